@@ -0,0 +1,167 @@
+//! Derive macros for [activitypub_federation](https://docs.rs/activitypub_federation). They are
+//! re-exported by that crate, so there is no need to depend on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields};
+
+/// Implements `ActivityHandler` for an enum whose variants each contain one activity, see the
+/// documentation of the trait in `activitypub_federation::traits`.
+#[proc_macro_derive(ActivityHandler)]
+pub fn derive_activity_handler(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_activity_handler(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand_activity_handler(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "ActivityHandler can only be derived for enums",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "ActivityHandler can't be derived for enums with generic parameters",
+        ));
+    }
+
+    let mut variants = vec![];
+    let mut types = vec![];
+    for variant in &data.variants {
+        match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                variants.push(&variant.ident);
+                types.push(&fields.unnamed[0].ty);
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    variant,
+                    "each variant must contain exactly one activity, for example `Follow(Follow)`",
+                ))
+            }
+        }
+    }
+    let Some(first) = types.first() else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "ActivityHandler can't be derived for enums without variants",
+        ));
+    };
+
+    // Calls of the variant's handler methods are spanned to its type, so that a mismatching
+    // `DataType` or `Error` is reported on the variant.
+    let verify = variants.iter().zip(&types).map(|(variant, ty)| {
+        quote_spanned! {ty.span()=>
+            Self::#variant(activity) => Ok(ActivityHandler::verify(activity, data).await?),
+        }
+    });
+    let receive = variants.iter().zip(&types).map(|(variant, ty)| {
+        quote_spanned! {ty.span()=>
+            Self::#variant(activity) => Ok(ActivityHandler::receive(activity, data).await?),
+        }
+    });
+    let receive_for = variants.iter().zip(&types).map(|(variant, ty)| {
+        quote_spanned! {ty.span()=>
+            Self::#variant(activity) => {
+                Ok(ActivityHandler::receive_for(activity, recipients, data).await?)
+            }
+        }
+    });
+
+    Ok(quote! {
+        const _: () = {
+            use ::activitypub_federation::{
+                __private::{async_trait, serde, serde_json, serde_path_to_error, Url},
+                config::Data,
+                traits::ActivityHandler,
+            };
+
+            #[automatically_derived]
+            #[allow(clippy::needless_question_mark)]
+            #[async_trait]
+            impl ActivityHandler for #name {
+                type DataType = <#first as ActivityHandler>::DataType;
+                type Error = <#first as ActivityHandler>::Error;
+
+                fn id(&self) -> &Url {
+                    match self {
+                        #(Self::#variants(activity) => ActivityHandler::id(activity),)*
+                    }
+                }
+
+                fn actor(&self) -> &Url {
+                    match self {
+                        #(Self::#variants(activity) => ActivityHandler::actor(activity),)*
+                    }
+                }
+
+                async fn verify(&self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+                    match self {
+                        #(#verify)*
+                    }
+                }
+
+                async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+                    match self {
+                        #(#receive)*
+                    }
+                }
+
+                async fn receive_for(
+                    self,
+                    recipients: Vec<Url>,
+                    data: &Data<Self::DataType>,
+                ) -> Result<(), Self::Error>
+                where
+                    Self: Sized + Send,
+                {
+                    match self {
+                        #(#receive_for)*
+                    }
+                }
+
+                fn handles_kind(kind: &str) -> bool {
+                    #(<#types as ActivityHandler>::handles_kind(kind))||*
+                }
+            }
+
+            #[automatically_derived]
+            impl<'de> serde::Deserialize<'de> for #name {
+                fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+                where
+                    D: serde::Deserializer<'de>,
+                {
+                    let value = <serde_json::Value as serde::Deserialize>::deserialize(deserializer)?;
+                    let mut errors = Vec::new();
+                    #(
+                        match serde_path_to_error::deserialize::<_, #types>(&value) {
+                            Ok(activity) => return Ok(Self::#variants(activity)),
+                            Err(e) => errors.push(format!("{}: {}", stringify!(#variants), e)),
+                        }
+                    )*
+                    Err(<D::Error as serde::de::Error>::custom(format!(
+                        "data did not match any variant of {}, {}",
+                        stringify!(#name),
+                        errors.join("; "),
+                    )))
+                }
+            }
+
+            #[automatically_derived]
+            impl #name {
+                /// Name of the variant which contains the activity
+                pub fn kind(&self) -> &'static str {
+                    match self {
+                        #(Self::#variants(_) => stringify!(#variants),)*
+                    }
+                }
+            }
+        };
+    })
+}
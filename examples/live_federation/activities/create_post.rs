@@ -1,13 +1,14 @@
 use crate::{
-    database::DatabaseHandle,
+    database::{local_user, DatabaseHandle},
     error::Error,
-    objects::{person::DbUser, post::Note},
+    objects::post::Note,
     utils::generate_object_id,
     DbPost,
 };
 use activitypub_federation::{
     activity_sending::SendActivityTask,
     config::Data,
+    example_storage::DbUser,
     fetch::object_id::ObjectId,
     kinds::activity::CreateType,
     protocol::{context::WithContext, helpers::deserialize_one_or_many},
@@ -40,7 +41,7 @@ impl CreatePost {
         };
         let create_with_context = WithContext::new_default(create);
         let sends =
-            SendActivityTask::prepare(&create_with_context, &data.local_user(), vec![inbox], data)
+            SendActivityTask::prepare(&create_with_context, &local_user(data)?, vec![inbox], data)
                 .await?;
         for send in sends {
             send.sign_and_send(data).await?;
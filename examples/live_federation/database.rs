@@ -1,26 +1,15 @@
-use crate::{objects::person::DbUser, Error};
+use crate::{Error, LOCAL_USER_NAME};
+use activitypub_federation::example_storage::{DbUser, InMemoryStorage};
 use anyhow::anyhow;
-use std::sync::{Arc, Mutex};
-
-pub type DatabaseHandle = Arc<Database>;
 
 /// Our "database" which contains all known users (local and federated)
-pub struct Database {
-    pub users: Mutex<Vec<DbUser>>,
-}
+pub type DatabaseHandle = InMemoryStorage;
 
-impl Database {
-    pub fn local_user(&self) -> DbUser {
-        let lock = self.users.lock().unwrap();
-        lock.first().unwrap().clone()
-    }
+pub fn local_user(data: &DatabaseHandle) -> Result<DbUser, Error> {
+    read_user(LOCAL_USER_NAME, data)
+}
 
-    pub fn read_user(&self, name: &str) -> Result<DbUser, Error> {
-        let db_user = self.local_user();
-        if name == db_user.name {
-            Ok(db_user)
-        } else {
-            Err(anyhow!("Invalid user {name}").into())
-        }
-    }
+pub fn read_user(name: &str, data: &DatabaseHandle) -> Result<DbUser, Error> {
+    data.read_local_user(name)
+        .ok_or_else(|| anyhow!("Invalid user {name}").into())
 }
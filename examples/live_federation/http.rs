@@ -1,7 +1,7 @@
 use crate::{
-    database::DatabaseHandle,
+    database::{read_user, DatabaseHandle},
     error::Error,
-    objects::person::{DbUser, Person, PersonAcceptedActivities},
+    objects::person::PersonAcceptedActivities,
 };
 use activitypub_federation::{
     axum::{
@@ -9,6 +9,7 @@ use activitypub_federation::{
         json::FederationJson,
     },
     config::Data,
+    example_storage::{DbUser, Person},
     fetch::webfinger::{build_webfinger_response, extract_webfinger_name, Webfinger},
     protocol::context::WithContext,
     traits::Object,
@@ -33,7 +34,7 @@ pub async fn http_get_user(
     Path(name): Path<String>,
     data: Data<DatabaseHandle>,
 ) -> Result<FederationJson<WithContext<Person>>, Error> {
-    let db_user = data.read_user(&name)?;
+    let db_user = read_user(&name, &data)?;
     let json_user = db_user.into_json(&data).await?;
     Ok(FederationJson(WithContext::new_default(json_user)))
 }
@@ -61,7 +62,7 @@ pub async fn webfinger(
     data: Data<DatabaseHandle>,
 ) -> Result<Json<Webfinger>, Error> {
     let name = extract_webfinger_name(&query.resource, &data)?;
-    let db_user = data.read_user(name)?;
+    let db_user = read_user(name, &data)?;
     Ok(Json(build_webfinger_response(
         query.resource,
         db_user.ap_id.into_inner(),
@@ -1,22 +1,22 @@
 #![allow(clippy::unwrap_used)]
 
 use crate::{
-    database::Database,
     http::{http_get_user, http_post_user_inbox, webfinger},
-    objects::{person::DbUser, post::DbPost},
+    objects::post::DbPost,
     utils::generate_object_id,
 };
-use activitypub_federation::config::{FederationConfig, FederationMiddleware};
+use activitypub_federation::{
+    config::{FederationConfig, FederationMiddleware},
+    example_storage::{DbUser, InMemoryStorage},
+};
 use axum::{
     routing::{get, post},
     Router,
 };
 use error::Error;
-use std::{
-    net::ToSocketAddrs,
-    sync::{Arc, Mutex},
-};
+use std::net::ToSocketAddrs;
 use tracing::log::{info, LevelFilter};
+use url::Url;
 
 mod activities;
 mod database;
@@ -40,10 +40,13 @@ async fn main() -> Result<(), Error> {
         .init();
 
     info!("Setup local user and database");
-    let local_user = DbUser::new(DOMAIN, LOCAL_USER_NAME)?;
-    let database = Arc::new(Database {
-        users: Mutex::new(vec![local_user]),
-    });
+    let local_user = DbUser::new(
+        LOCAL_USER_NAME,
+        Url::parse(&format!("https://{DOMAIN}/{LOCAL_USER_NAME}"))?,
+        Url::parse(&format!("https://{DOMAIN}/{LOCAL_USER_NAME}/inbox"))?,
+    )?;
+    let database = InMemoryStorage::default();
+    database.upsert_user(local_user);
 
     info!("Setup configuration");
     let config = FederationConfig::builder()
@@ -1,12 +1,12 @@
 use crate::{
     activities::create_post::CreatePost,
-    database::DatabaseHandle,
+    database::{local_user, DatabaseHandle},
     error::Error,
     generate_object_id,
-    objects::person::DbUser,
 };
 use activitypub_federation::{
     config::Data,
+    example_storage::DbUser,
     fetch::object_id::ObjectId,
     kinds::{object::NoteType, public},
     protocol::{helpers::deserialize_one_or_many, verification::verify_domains_match},
@@ -97,7 +97,7 @@ impl Object for DbPost {
         let note = Note {
             kind: Default::default(),
             id: generate_object_id(data.domain())?.into(),
-            attributed_to: data.local_user().ap_id,
+            attributed_to: local_user(data)?.ap_id,
             to: vec![public()],
             content: format!("Hello {}", creator.name),
             in_reply_to: Some(json.id.clone()),
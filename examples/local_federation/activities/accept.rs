@@ -1,6 +1,7 @@
-use crate::{activities::follow::Follow, instance::DatabaseHandle, objects::person::DbUser};
+use crate::{activities::follow::Follow, instance::DatabaseHandle};
 use activitypub_federation::{
     config::Data,
+    example_storage::DbUser,
     fetch::object_id::ObjectId,
     kinds::activity::AcceptType,
     traits::ActivityHandler,
@@ -1,10 +1,7 @@
-use crate::{
-    instance::DatabaseHandle,
-    objects::{person::DbUser, post::Note},
-    DbPost,
-};
+use crate::instance::DatabaseHandle;
 use activitypub_federation::{
     config::Data,
+    example_storage::{DbPost, DbUser, Note},
     fetch::object_id::ObjectId,
     kinds::activity::CreateType,
     protocol::helpers::deserialize_one_or_many,
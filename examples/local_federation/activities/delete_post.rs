@@ -0,0 +1,42 @@
+use crate::instance::DatabaseHandle;
+use activitypub_federation::{
+    config::Data,
+    protocol::{tombstone::Delete, verification::verify_domains_match},
+    traits::{ActivityHandler, Object},
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Deletion of a post, with a tombstone in place of the post
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(transparent)]
+pub struct DeletePost(pub Delete);
+
+#[async_trait::async_trait]
+impl ActivityHandler for DeletePost {
+    type DataType = DatabaseHandle;
+    type Error = crate::error::Error;
+
+    fn id(&self) -> &Url {
+        &self.0.id
+    }
+
+    fn actor(&self) -> &Url {
+        &self.0.actor
+    }
+
+    async fn verify(&self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        // Only the instance of the post can delete it
+        verify_domains_match(&self.0.actor, &self.0.object.id)?;
+        Ok(())
+    }
+
+    async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        if let Some(post) = data.read_post(&self.0.object.id) {
+            if post.creator.inner() == &self.0.actor {
+                post.delete(data).await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,10 +2,11 @@ use crate::{
     activities::accept::Accept,
     generate_object_id,
     instance::DatabaseHandle,
-    objects::person::DbUser,
+    objects::person::send,
 };
 use activitypub_federation::{
     config::Data,
+    example_storage::DbUser,
     fetch::object_id::ObjectId,
     kinds::activity::FollowType,
     traits::{ActivityHandler, Actor},
@@ -51,24 +52,23 @@ impl ActivityHandler for Follow {
         Ok(())
     }
 
-    // Ignore clippy false positive: https://github.com/rust-lang/rust-clippy/issues/6446
-    #[allow(clippy::await_holding_lock)]
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
         // add to followers
-        let local_user = {
-            let mut users = data.users.lock().unwrap();
-            let local_user = users.first_mut().unwrap();
-            local_user.followers.push(self.actor.inner().clone());
-            local_user.clone()
-        };
+        data.add_follower(self.object.inner(), self.actor.inner().clone());
+        let local_user = self.object.dereference_local(data).await?;
 
         // send back an accept
         let follower = self.actor.dereference(data).await?;
         let id = generate_object_id(data.domain())?;
         let accept = Accept::new(local_user.ap_id.clone(), self, id.clone());
-        local_user
-            .send(accept, vec![follower.shared_inbox_or_inbox()], false, data)
-            .await?;
+        send(
+            &local_user,
+            accept,
+            vec![follower.shared_inbox_or_inbox()],
+            false,
+            data,
+        )
+        .await?;
         Ok(())
     }
 }
@@ -1,3 +1,4 @@
 pub mod accept;
 pub mod create_post;
+pub mod delete_post;
 pub mod follow;
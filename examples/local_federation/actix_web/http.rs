@@ -1,17 +1,24 @@
 use crate::{
     error::Error,
-    instance::DatabaseHandle,
-    objects::person::{DbUser, PersonAcceptedActivities},
+    instance::{instance_actor, DatabaseHandle},
+    objects::person::{read_local_user, PersonAcceptedActivities},
+    utils::generate_post_url,
 };
 use activitypub_federation::{
-    actix_web::{inbox::receive_activity, signing_actor},
+    actix_web::{
+        inbox::{receive_shared_activity, VerifiedActivity},
+        json::{tombstone_response, FederationJson},
+        SignedActor,
+    },
     config::{Data, FederationConfig, FederationMiddleware},
+    example_storage::DbUser,
     fetch::webfinger::{build_webfinger_response, extract_webfinger_name},
-    protocol::context::WithContext,
+    instance_actor::{Application, InstanceActor},
+    protocol::{collections::OrderedCollection, context::WithContext},
     traits::{Actor, Object},
     FEDERATION_CONTENT_TYPE,
 };
-use actix_web::{web, web::Bytes, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{web, web::Bytes, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use anyhow::anyhow;
 use serde::Deserialize;
 use tracing::info;
@@ -23,7 +30,15 @@ pub fn listen(config: &FederationConfig<DatabaseHandle>) -> Result<(), Error> {
     let server = HttpServer::new(move || {
         App::new()
             .wrap(FederationMiddleware::new(config.clone()))
-            .route("/", web::get().to(http_get_system_user))
+            .route("/inbox", web::post().to(http_post_shared_inbox))
+            .route("/actor", web::get().to(http_get_instance_actor))
+            .route("/actor/inbox", web::get().to(http_get_instance_actor_inbox))
+            .route("/actor/inbox", web::post().to(http_post_shared_inbox))
+            .route(
+                "/actor/outbox",
+                web::get().to(http_get_instance_actor_outbox),
+            )
+            .route("/objects/{id}", web::get().to(http_get_post))
             .route("/{user}", web::get().to(http_get_user))
             .route("/{user}/inbox", web::post().to(http_post_user_inbox))
             .route("/.well-known/webfinger", web::get().to(webfinger))
@@ -34,46 +49,83 @@ pub fn listen(config: &FederationConfig<DatabaseHandle>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Handles requests to fetch system user json over HTTP
-pub async fn http_get_system_user(data: Data<DatabaseHandle>) -> Result<HttpResponse, Error> {
-    let json_user = data.system_user.clone().into_json(&data).await?;
-    Ok(HttpResponse::Ok()
-        .content_type(FEDERATION_CONTENT_TYPE)
-        .json(WithContext::new_default(json_user)))
+/// Handles requests to fetch the instance actor, which signs all fetch requests. It must be
+/// fetchable without a signature, otherwise verifying a signature of the other instance would
+/// end in a loop.
+pub async fn http_get_instance_actor(data: Data<DatabaseHandle>) -> Result<Application, Error> {
+    let actor = instance_actor(data.domain(), &data).await?;
+    Ok(actor.into_json(&data).await?)
+}
+
+pub async fn http_get_instance_actor_inbox(
+    data: Data<DatabaseHandle>,
+) -> Result<OrderedCollection, Error> {
+    Ok(instance_actor(data.domain(), &data)
+        .await?
+        .inbox_collection())
+}
+
+pub async fn http_get_instance_actor_outbox(
+    data: Data<DatabaseHandle>,
+) -> Result<OrderedCollection, Error> {
+    Ok(instance_actor(data.domain(), &data)
+        .await?
+        .outbox_collection())
+}
+
+/// Serves a local post, or its tombstone with status `410 Gone` if it was deleted
+pub async fn http_get_post(
+    request: HttpRequest,
+    id: web::Path<String>,
+    data: Data<DatabaseHandle>,
+) -> Result<HttpResponse, Error> {
+    let ap_id = generate_post_url(data.domain(), &id)?;
+    if let Some(tombstone) = data.read_tombstone(&ap_id) {
+        return Ok(tombstone_response(tombstone));
+    }
+    let post = data
+        .read_post(&ap_id)
+        .ok_or_else(|| anyhow!("Invalid post {ap_id}"))?;
+    let json_post = post.into_json(&data).await?;
+    Ok(FederationJson(WithContext::new_default(json_post)).respond_to(&request))
 }
 
 /// Handles requests to fetch user json over HTTP
 pub async fn http_get_user(
-    request: HttpRequest,
+    signed_by: SignedActor<InstanceActor<DatabaseHandle>>,
     user_name: web::Path<String>,
     data: Data<DatabaseHandle>,
 ) -> Result<HttpResponse, Error> {
-    let signed_by = signing_actor::<DbUser>(&request, None, &data).await?;
     // here, checks can be made on the actor or the domain to which
     // it belongs, to verify whether it is allowed to access this resource
     info!(
-        "Fetch user request is signed by system account {}",
+        "Fetch user request is signed by instance actor {}",
         signed_by.id()
     );
 
-    let db_user = data.local_user();
-    if user_name.into_inner() == db_user.name {
-        let json_user = db_user.into_json(&data).await?;
-        Ok(HttpResponse::Ok()
-            .content_type(FEDERATION_CONTENT_TYPE)
-            .json(WithContext::new_default(json_user)))
-    } else {
-        Err(anyhow!("Invalid user").into())
-    }
+    let db_user = read_local_user(&user_name, &data)?;
+    let json_user = db_user.into_json(&data).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new_default(json_user)))
 }
 
 /// Handles messages received in user inbox
 pub async fn http_post_user_inbox(
+    activity: VerifiedActivity<WithContext<PersonAcceptedActivities>, DbUser>,
+    data: Data<DatabaseHandle>,
+) -> Result<HttpResponse, Error> {
+    activity.receive(&data).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Handles messages received in the shared inbox, as advertised in `endpoints.sharedInbox`
+pub async fn http_post_shared_inbox(
     request: HttpRequest,
     body: Bytes,
     data: Data<DatabaseHandle>,
 ) -> Result<HttpResponse, Error> {
-    receive_activity::<WithContext<PersonAcceptedActivities>, DbUser, DatabaseHandle>(
+    receive_shared_activity::<WithContext<PersonAcceptedActivities>, DbUser, DatabaseHandle>(
         request, body, &data,
     )
     .await
@@ -89,7 +141,11 @@ pub async fn webfinger(
     data: Data<DatabaseHandle>,
 ) -> Result<HttpResponse, Error> {
     let name = extract_webfinger_name(&query.resource, &data)?;
-    let db_user = data.read_user(name)?;
+    let instance_actor = instance_actor(data.domain(), &data).await?;
+    if name == instance_actor.name() {
+        return Ok(HttpResponse::Ok().json(instance_actor.webfinger()));
+    }
+    let db_user = read_local_user(name, &data)?;
     Ok(HttpResponse::Ok().json(build_webfinger_response(
         query.resource.clone(),
         db_user.ap_id.into_inner(),
@@ -1,22 +1,26 @@
 use crate::{
     error::Error,
-    instance::DatabaseHandle,
-    objects::person::{DbUser, Person, PersonAcceptedActivities},
+    instance::{instance_actor, DatabaseHandle},
+    objects::person::{read_local_user, PersonAcceptedActivities},
+    utils::generate_post_url,
 };
 use activitypub_federation::{
     axum::{
-        inbox::{receive_activity, ActivityData},
-        json::FederationJson,
+        inbox::{receive_activity, receive_shared_activity, ActivityData},
+        json::{tombstone_response, FederationJson},
     },
     config::{Data, FederationConfig, FederationMiddleware},
+    example_storage::{DbUser, Person},
     fetch::webfinger::{build_webfinger_response, extract_webfinger_name, Webfinger},
-    protocol::context::WithContext,
+    instance_actor::Application,
+    protocol::{collections::OrderedCollection, context::WithContext},
     traits::Object,
 };
+use anyhow::anyhow;
 use axum::{
     debug_handler,
     extract::{Path, Query},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json,
     Router,
@@ -30,6 +34,14 @@ pub fn listen(config: &FederationConfig<DatabaseHandle>) -> Result<(), Error> {
     info!("Listening with axum on {hostname}");
     let config = config.clone();
     let app = Router::new()
+        .route("/inbox", post(http_post_shared_inbox))
+        .route("/actor", get(http_get_instance_actor))
+        .route(
+            "/actor/inbox",
+            get(http_get_instance_actor_inbox).post(http_post_shared_inbox),
+        )
+        .route("/actor/outbox", get(http_get_instance_actor_outbox))
+        .route("/objects/:id", get(http_get_post))
         .route("/:user/inbox", post(http_post_user_inbox))
         .route("/:user", get(http_get_user))
         .route("/.well-known/webfinger", get(webfinger))
@@ -55,11 +67,54 @@ async fn http_get_user(
     Path(name): Path<String>,
     data: Data<DatabaseHandle>,
 ) -> Result<FederationJson<WithContext<Person>>, Error> {
-    let db_user = data.read_user(&name)?;
+    let db_user = read_local_user(&name, &data)?;
     let json_user = db_user.into_json(&data).await?;
     Ok(FederationJson(WithContext::new_default(json_user)))
 }
 
+/// Serves a local post, or its tombstone with status `410 Gone` if it was deleted
+#[debug_handler]
+async fn http_get_post(
+    Path(id): Path<String>,
+    data: Data<DatabaseHandle>,
+) -> Result<Response, Error> {
+    let ap_id = generate_post_url(data.domain(), &id)?;
+    if let Some(tombstone) = data.read_tombstone(&ap_id) {
+        return Ok(tombstone_response(tombstone));
+    }
+    let post = data
+        .read_post(&ap_id)
+        .ok_or_else(|| anyhow!("Invalid post {ap_id}"))?;
+    let json_post = post.into_json(&data).await?;
+    Ok(FederationJson(WithContext::new_default(json_post)).into_response())
+}
+
+/// Serves the instance actor, which signs all fetch requests. It must be fetchable without a
+/// signature, otherwise verifying a signature of the other instance would end in a loop.
+#[debug_handler]
+async fn http_get_instance_actor(data: Data<DatabaseHandle>) -> Result<Application, Error> {
+    let actor = instance_actor(data.domain(), &data).await?;
+    Ok(actor.into_json(&data).await?)
+}
+
+#[debug_handler]
+async fn http_get_instance_actor_inbox(
+    data: Data<DatabaseHandle>,
+) -> Result<OrderedCollection, Error> {
+    Ok(instance_actor(data.domain(), &data)
+        .await?
+        .inbox_collection())
+}
+
+#[debug_handler]
+async fn http_get_instance_actor_outbox(
+    data: Data<DatabaseHandle>,
+) -> Result<OrderedCollection, Error> {
+    Ok(instance_actor(data.domain(), &data)
+        .await?
+        .outbox_collection())
+}
+
 #[debug_handler]
 async fn http_post_user_inbox(
     data: Data<DatabaseHandle>,
@@ -72,6 +127,19 @@ async fn http_post_user_inbox(
     .await
 }
 
+/// Receives activities for all local users, as advertised in `endpoints.sharedInbox`
+#[debug_handler]
+async fn http_post_shared_inbox(
+    data: Data<DatabaseHandle>,
+    activity_data: ActivityData,
+) -> impl IntoResponse {
+    receive_shared_activity::<WithContext<PersonAcceptedActivities>, DbUser, DatabaseHandle>(
+        activity_data,
+        &data,
+    )
+    .await
+}
+
 #[derive(Deserialize)]
 struct WebfingerQuery {
     resource: String,
@@ -83,7 +151,11 @@ async fn webfinger(
     data: Data<DatabaseHandle>,
 ) -> Result<Json<Webfinger>, Error> {
     let name = extract_webfinger_name(&query.resource, &data)?;
-    let db_user = data.read_user(name)?;
+    let instance_actor = instance_actor(data.domain(), &data).await?;
+    if name == instance_actor.name() {
+        return Ok(Json(instance_actor.webfinger()));
+    }
+    let db_user = read_local_user(name, &data)?;
     Ok(Json(build_webfinger_response(
         query.resource,
         db_user.ap_id.into_inner(),
@@ -1,32 +1,24 @@
-use crate::{
-    objects::{person::DbUser, post::DbPost},
-    Error,
+use crate::{objects::person::new_local_user, Error};
+use activitypub_federation::{
+    config::{FederationConfig, UrlVerifier},
+    example_storage::InMemoryStorage,
+    instance_actor::InstanceActor,
 };
-use activitypub_federation::config::{FederationConfig, UrlVerifier};
-use anyhow::anyhow;
 use async_trait::async_trait;
-use std::{
-    str::FromStr,
-    sync::{Arc, Mutex},
-};
+use std::str::FromStr;
 use url::Url;
 
 pub async fn new_instance(
     hostname: &str,
     name: String,
 ) -> Result<FederationConfig<DatabaseHandle>, Error> {
-    let mut system_user = DbUser::new(hostname, "system".into())?;
-    system_user.ap_id = Url::parse(&format!("http://{}/", hostname))?.into();
-
-    let local_user = DbUser::new(hostname, name)?;
-    let database = Arc::new(Database {
-        system_user: system_user.clone(),
-        users: Mutex::new(vec![local_user]),
-        posts: Mutex::new(vec![]),
-    });
+    let local_user = new_local_user(hostname, &name)?;
+    let database = InMemoryStorage::default();
+    database.upsert_user(local_user);
+    let instance_actor = instance_actor(hostname, &database).await?;
     let config = FederationConfig::builder()
         .domain(hostname)
-        .signed_fetch_actor(&system_user)
+        .signed_fetch_actor(&instance_actor)
         .app_data(database)
         .url_verifier(Box::new(MyUrlVerifier()))
         .debug(true)
@@ -35,14 +27,21 @@ pub async fn new_instance(
     Ok(config)
 }
 
-pub type DatabaseHandle = Arc<Database>;
+/// Actor which signs all fetch requests of the instance. The keypair is generated by the first
+/// call and then read from the database.
+pub async fn instance_actor(
+    hostname: &str,
+    database: &DatabaseHandle,
+) -> Result<InstanceActor<DatabaseHandle>, Error> {
+    let id = Url::parse(&format!("http://{}/actor", hostname))?;
+    let shared_inbox = Url::parse(&format!("http://{}/inbox", hostname))?;
+    Ok(InstanceActor::load_or_generate(id, database)
+        .await?
+        .with_shared_inbox(shared_inbox))
+}
 
 /// Our "database" which contains all known posts and users (local and federated)
-pub struct Database {
-    pub system_user: DbUser,
-    pub users: Mutex<Vec<DbUser>>,
-    pub posts: Mutex<Vec<DbPost>>,
-}
+pub type DatabaseHandle = InMemoryStorage;
 
 /// Use this to store your federation blocklist, or a database connection needed to retrieve it.
 #[derive(Clone)]
@@ -88,19 +87,3 @@ pub fn listen(
     }
     Ok(())
 }
-
-impl Database {
-    pub fn local_user(&self) -> DbUser {
-        let lock = self.users.lock().unwrap();
-        lock.first().unwrap().clone()
-    }
-
-    pub fn read_user(&self, name: &str) -> Result<DbUser, Error> {
-        let db_user = self.local_user();
-        if name == db_user.name {
-            Ok(db_user)
-        } else {
-            Err(anyhow!("Invalid user {name}").into())
-        }
-    }
-}
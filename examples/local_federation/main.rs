@@ -1,10 +1,17 @@
 #![allow(clippy::unwrap_used)]
 
 use crate::{
-    instance::{listen, new_instance, Webserver},
-    objects::post::DbPost,
+    instance::{instance_actor, listen, new_instance, DatabaseHandle, Webserver},
+    objects::person::{delete_post, follow, post, read_local_user},
     utils::generate_object_id,
 };
+use activitypub_federation::{
+    error::Error as FederationError,
+    example_storage::DbPost,
+    fetch::object_id::ObjectId,
+    instance_actor::InstanceActor,
+    traits::Actor,
+};
 use error::Error;
 use std::{env::args, str::FromStr};
 use tokio::try_join;
@@ -43,29 +50,61 @@ async fn main() -> Result<(), Error> {
     listen(&beta, &webserver)?;
     info!("Local instances started");
 
-    info!("Alpha user follows beta user via webfinger");
-    alpha
-        .local_user()
-        .follow("beta@localhost:8002", &alpha.to_request_data())
+    info!("Alpha fetches the instance actor of beta with a signed request");
+    let beta_instance_actor = instance_actor(beta.domain(), &beta).await?;
+    let fetched: InstanceActor<DatabaseHandle> = ObjectId::from(beta_instance_actor.id())
+        .dereference(&alpha.to_request_data())
         .await?;
     assert_eq!(
-        beta.local_user().followers(),
-        &vec![alpha.local_user().ap_id.inner().clone()]
+        fetched.public_key_pem(),
+        beta_instance_actor.public_key_pem()
+    );
+    assert_eq!(fetched.private_key_pem(), None);
+    info!("Fetched instance actor {}", fetched.id());
+
+    info!("Alpha user follows beta user via webfinger");
+    follow(
+        &read_local_user("alpha", &alpha)?,
+        "beta@localhost:8002",
+        &alpha.to_request_data(),
+    )
+    .await?;
+    assert_eq!(
+        read_local_user("beta", &beta)?.followers,
+        vec![read_local_user("alpha", &alpha)?.ap_id.into_inner()]
     );
     info!("Follow was successful");
 
     info!("Beta sends a post to its followers");
-    let sent_post = DbPost::new("Hello world!".to_string(), beta.local_user().ap_id)?;
-    beta.local_user()
-        .post(sent_post.clone(), &beta.to_request_data())
-        .await?;
-    let received_post = alpha.posts.lock().unwrap().first().cloned().unwrap();
+    let beta_user = read_local_user("beta", &beta)?;
+    let sent_post = DbPost::new(
+        "Hello world!".to_string(),
+        generate_object_id(beta.domain())?,
+        beta_user.ap_id.clone(),
+    );
+    beta.upsert_post(sent_post.clone());
+    post(&beta_user, sent_post.clone(), &beta.to_request_data()).await?;
+    let received_post = alpha.posts().first().cloned().unwrap();
     info!("Alpha received post: {}", received_post.text);
 
     // assert that alpha received the post
     assert_eq!(received_post.text, sent_post.text);
     assert_eq!(received_post.ap_id.inner(), sent_post.ap_id.inner());
     assert_eq!(received_post.creator.inner(), sent_post.creator.inner());
+
+    info!("Beta deletes the post");
+    delete_post(&beta_user, sent_post.clone(), &beta.to_request_data()).await?;
+    assert!(alpha.posts().is_empty());
+    let refetched = ObjectId::<DbPost>::from(sent_post.ap_id.into_inner())
+        .dereference_forced(&alpha.to_request_data())
+        .await;
+    match refetched {
+        Err(FederationError::ObjectDeleted(_, Some(tombstone))) => {
+            assert_eq!(tombstone.former_type.as_deref(), Some("Note"));
+            info!("Alpha received tombstone for deleted post {}", tombstone.id);
+        }
+        res => panic!("Expected deleted post, got {res:?}"),
+    }
     info!("Test completed");
     Ok(())
 }
@@ -1,2 +1 @@
 pub mod person;
-pub mod post;
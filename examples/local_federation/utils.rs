@@ -9,5 +9,10 @@ pub fn generate_object_id(domain: &str) -> Result<Url, ParseError> {
         .take(7)
         .map(char::from)
         .collect();
+    generate_post_url(domain, &id)
+}
+
+/// Url of the object with the given random id, as generated by [generate_object_id]
+pub fn generate_post_url(domain: &str, id: &str) -> Result<Url, ParseError> {
     Url::parse(&format!("http://{}/objects/{}", domain, id))
 }
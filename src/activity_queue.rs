@@ -3,29 +3,56 @@
 #![doc = include_str!("../docs/09_sending_activities.md")]
 
 use crate::{
-    activity_sending::{build_tasks, SendActivityTask},
-    config::Data,
+    activity_sending::{
+        build_tasks,
+        build_tasks_serialized,
+        HostLimiter,
+        HostPermit,
+        SendActivityTask,
+    },
+    config::{Data, FederationConfig},
     error::Error,
+    extract_id,
+    fetch::nodeinfo::PeerSoftwareCache,
+    http_signatures::SigningLimiter,
+    metrics::{FederationMetricsHook, NoMetrics},
     traits::{ActivityHandler, Actor},
 };
 
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::future::{select, Either};
 use futures_core::Future;
-
+use http::StatusCode;
+use itertools::Itertools;
 use reqwest_middleware::ClientWithMiddleware;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fmt::{Debug, Display},
+    pin::{pin, Pin},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
+        Mutex,
+        MutexGuard,
+        PoisonError,
     },
     time::Duration,
 };
 use tokio::{
-    sync::mpsc::{unbounded_channel, UnboundedSender},
+    sync::{
+        mpsc::{error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+        watch,
+        Notify,
+        OwnedSemaphorePermit,
+        Semaphore,
+    },
     task::{JoinHandle, JoinSet},
 };
-use tracing::{info, warn};
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
 use url::Url;
 
 /// Send a new activity to the given inboxes with automatic retry on failure. Alternatively you
@@ -33,10 +60,10 @@ use url::Url;
 ///
 /// - `activity`: The activity to be sent, gets converted to json
 /// - `private_key`: Private key belonging to the actor who sends the activity, for signing HTTP
-///                  signature. Generated with [crate::http_signatures::generate_actor_keypair].
+///   signature. Generated with [crate::http_signatures::generate_actor_keypair].
 /// - `inboxes`: List of remote actor inboxes that should receive the activity. Ignores local actor
-///              inboxes. Should be built by calling [crate::traits::Actor::shared_inbox_or_inbox]
-///              for each target actor.
+///   inboxes. Should be built by calling [crate::traits::Actor::shared_inbox_or_inbox]
+///   for each target actor.
 pub async fn queue_activity<Activity, Datatype, ActorType>(
     activity: &Activity,
     actor: &ActorType,
@@ -46,476 +73,3267 @@ pub async fn queue_activity<Activity, Datatype, ActorType>(
 where
     Activity: ActivityHandler + Serialize + Debug,
     Datatype: Clone,
-    ActorType: Actor,
+    ActorType: Actor<DataType = Datatype>,
 {
-    let config = &data.config;
-    let tasks = build_tasks(activity, actor, inboxes, data).await?;
-
-    for task in tasks {
-        // Don't use the activity queue if this is in debug mode, send and wait directly
-        if config.debug {
-            if let Err(err) = sign_and_send(
-                &task,
-                &config.client,
-                config.request_timeout,
-                Default::default(),
-            )
-            .await
-            {
-                warn!("{err}");
-            }
-        } else {
-            // This field is only optional to make builder work, its always present at this point
-            let activity_queue = config
-                .activity_queue
-                .as_ref()
-                .expect("Config has activity queue");
-            activity_queue.queue(task).await?;
-            let stats = activity_queue.get_stats();
-            let running = stats.running.load(Ordering::Relaxed);
-            if running == config.queue_worker_count && config.queue_worker_count != 0 {
-                warn!("Reached max number of send activity workers ({}). Consider increasing worker count to avoid federation delays", config.queue_worker_count);
-                warn!("{:?}", stats);
-            } else {
-                info!("{:?}", stats);
-            }
-        }
-    }
+    queue_activity_with_options(activity, actor, inboxes, data, SendOptions::default()).await?;
     Ok(())
 }
 
-async fn sign_and_send(
-    task: &SendActivityTask,
-    client: &ClientWithMiddleware,
-    timeout: Duration,
-    retry_strategy: RetryStrategy,
-) -> Result<(), Error> {
-    retry(
-        || task.sign_and_send_internal(client, timeout),
-        retry_strategy,
+/// Forwards a received activity to the given inboxes with automatic retry on failure, for example
+/// from a group actor to its followers.
+///
+/// Unlike [queue_activity] the body is sent exactly as it was received, so that signatures which
+/// are embedded in the activity remain valid. Only the HTTP signature is created with the key of
+/// `actor`. The body of an activity which is currently being received is available with
+/// [Data::received_activity_bytes].
+///
+/// <https://www.w3.org/TR/activitypub/#inbox-forwarding>
+pub async fn forward_activity<Datatype, ActorType>(
+    raw_body: Bytes,
+    actor: &ActorType,
+    inboxes: Vec<Url>,
+    data: &Data<Datatype>,
+) -> Result<(), Error>
+where
+    Datatype: Clone,
+    ActorType: Actor<DataType = Datatype>,
+{
+    let activity_id =
+        extract_id(&raw_body).map_err(|e| Error::ParseReceivedActivity(Box::new(e), None))?;
+    let tasks = build_tasks_serialized(
+        &activity_id,
+        raw_body,
+        &actor.id(),
+        actor,
+        inboxes,
+        data,
+        None,
     )
-    .await
+    .await?;
+    if !tasks.skipped.is_empty() {
+        info!(
+            "Not forwarding activity {} to {} inboxes which failed verification",
+            activity_id,
+            tasks.skipped.len()
+        );
+    }
+
+    send_or_schedule_tasks(tasks.into(), &data.config, None).await?;
+    Ok(())
 }
 
-/// A simple activity queue which spawns tokio workers to send out requests
-/// When creating a queue, it will spawn a task per worker thread
-/// Uses an unbounded mpsc queue for communication (i.e, all messages are in memory)
-pub(crate) struct ActivityQueue {
-    // Stats shared between the queue and workers
-    stats: Arc<Stats>,
-    sender: UnboundedSender<SendActivityTask>,
-    sender_task: JoinHandle<()>,
-    retry_sender_task: JoinHandle<()>,
+/// Determines how delivery of an activity is retried when the target inbox is unreachable.
+///
+/// Use [FederationConfigBuilder::retry_policy](crate::config::FederationConfigBuilder::retry_policy)
+/// to set a default for an activity type, or [SendOptions::retry_policy] for a single send.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetryPolicy {
+    /// Retry after one minute, then after one hour and after 60 hours
+    #[default]
+    Full,
+    /// Only retry once after one minute
+    FastOnly,
+    /// Single delivery attempt without any retry. Useful for ephemeral activities such as `Like`.
+    None,
 }
 
-/// Simple stat counter to show where we're up to with sending messages
-/// This is a lock-free way to share things between tasks
-/// When reading these values it's possible (but extremely unlikely) to get stale data if a worker task is in the middle of transitioning
-#[derive(Default)]
-pub(crate) struct Stats {
-    pending: AtomicUsize,
-    running: AtomicUsize,
-    retries: AtomicUsize,
-    dead_last_hour: AtomicUsize,
-    completed_last_hour: AtomicUsize,
+/// Options for [queue_activity_with_options].
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Retry policy for this send, overrides the one which is configured for the activity type
+    pub retry_policy: Option<RetryPolicy>,
+    /// Don't deliver the activity before this time, for example to publish a scheduled post.
+    /// Until then the send can be cancelled with the returned [ScheduledSendHandle].
+    ///
+    /// Scheduled sends are stored in the [QueueBackend] right away together with their due time,
+    /// so with a persistent backend they are also sent after a restart. They use the background
+    /// queue even in debug mode.
+    pub deliver_after: Option<DateTime<Utc>>,
 }
 
-impl Debug for Stats {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Activity queue stats: pending: {}, running: {}, retries: {}, dead: {}, complete: {}",
-            self.pending.load(Ordering::Relaxed),
-            self.running.load(Ordering::Relaxed),
-            self.retries.load(Ordering::Relaxed),
-            self.dead_last_hour.load(Ordering::Relaxed),
-            self.completed_last_hour.load(Ordering::Relaxed)
-        )
+/// Same as [queue_activity], but allows changing the delivery behaviour with `options`.
+///
+/// The returned handle can be used to cancel the send if [SendOptions::deliver_after] is set.
+pub async fn queue_activity_with_options<Activity, Datatype, ActorType>(
+    activity: &Activity,
+    actor: &ActorType,
+    inboxes: Vec<Url>,
+    data: &Data<Datatype>,
+    options: SendOptions,
+) -> Result<ScheduledSendHandle, Error>
+where
+    Activity: ActivityHandler + Serialize + Debug,
+    Datatype: Clone,
+    ActorType: Actor<DataType = Datatype>,
+{
+    let config = &data.config;
+    let tasks = build_tasks(activity, actor, inboxes, data, options.retry_policy).await?;
+    if !tasks.skipped.is_empty() {
+        info!(
+            "Not sending activity {} to {} inboxes which failed verification",
+            activity.id(),
+            tasks.skipped.len()
+        );
     }
+
+    send_or_schedule_tasks(tasks.into(), config, options.deliver_after).await
 }
 
-#[derive(Clone, Copy, Default)]
-struct RetryStrategy {
-    /// Amount of time in seconds to back off
-    backoff: usize,
-    /// Amount of times to retry
-    retries: usize,
-    /// If this particular request has already been retried, you can add an offset here to increment the count to start
-    offset: usize,
-    /// Number of seconds to sleep before trying
-    initial_sleep: usize,
+/// Same as [queue_activity], but returns the status of the delivery to each inbox, for example to
+/// show the federation status of a post.
+///
+/// In debug mode the activity is sent directly, and the report contains the final status of each
+/// inbox. Otherwise the activity is queued, and each [DeliveryStatus::Queued] entry receives the
+/// final status once the activity was delivered or won't be retried anymore.
+pub async fn send_activity_with_report<Activity, Datatype, ActorType>(
+    activity: &Activity,
+    actor: &ActorType,
+    inboxes: Vec<Url>,
+    data: &Data<Datatype>,
+) -> Result<DeliveryReport, Error>
+where
+    Activity: ActivityHandler + Serialize + Debug,
+    Datatype: Clone,
+    ActorType: Actor<DataType = Datatype>,
+{
+    let config = &data.config;
+    let prepared = build_tasks(activity, actor, inboxes.clone(), data, None).await?;
+    let mut tasks: HashMap<_, _> = prepared
+        .tasks
+        .into_iter()
+        .map(|task| (task.inbox.clone(), task))
+        .collect();
+    let mut skipped: HashMap<_, _> = prepared
+        .skipped
+        .into_iter()
+        .map(|skipped| (skipped.inbox, skipped.reason))
+        .collect();
+
+    let mut report = DeliveryReport {
+        activity_id: activity.id().clone(),
+        inboxes: vec![],
+    };
+    for inbox in inboxes.into_iter().unique() {
+        let status = if let Some(task) = tasks.remove(&inbox) {
+            if config.debug {
+                DeliveryStatus::from_outcome(send_directly(&task, config).await)
+            } else {
+                let activity_queue = config
+                    .activity_queue()
+                    .await
+                    .ok_or_else(|| Error::ActivityQueueError(task.activity_id.clone()))?;
+                let receiver = activity_queue.store.watch(&task);
+                let key = (task.activity_id.clone(), task.inbox.clone());
+                if let Err(err) = queue_task(task, config).await {
+                    activity_queue.store.unwatch(&key);
+                    return Err(err);
+                }
+                DeliveryStatus::Queued(receiver)
+            }
+        } else if let Some(reason) = skipped.remove(&inbox) {
+            DeliveryStatus::Skipped(reason)
+        } else {
+            DeliveryStatus::Local
+        };
+        report.inboxes.push((inbox, status));
+    }
+    Ok(report)
 }
 
-/// A tokio spawned worker which is responsible for submitting requests to federated servers
-/// This will retry up to one time with the same signature, and if it fails, will move it to the retry queue.
-/// We need to retry activity sending in case the target instances is temporarily unreachable.
-/// In this case, the task is stored and resent when the instance is hopefully back up. This
-/// list shows the retry intervals, and which events of the target instance can be covered:
-/// - 60s (one minute, service restart) -- happens in the worker w/ same signature
-/// - 60min (one hour, instance maintenance) --- happens in the retry worker
-/// - 60h (2.5 days, major incident with rebuild from backup) --- happens in the retry worker
-async fn worker(
-    client: ClientWithMiddleware,
-    timeout: Duration,
-    message: SendActivityTask,
-    retry_queue: UnboundedSender<SendActivityTask>,
-    stats: Arc<Stats>,
-    strategy: RetryStrategy,
-) {
-    stats.pending.fetch_sub(1, Ordering::Relaxed);
-    stats.running.fetch_add(1, Ordering::Relaxed);
+/// Status of an activity for each inbox, returned by [send_activity_with_report]
+#[derive(Debug)]
+pub struct DeliveryReport {
+    /// Id of the activity
+    pub activity_id: Url,
+    /// Each inbox with its status, in the order in which they were passed. Duplicate inboxes are
+    /// only listed once.
+    pub inboxes: Vec<(Url, DeliveryStatus)>,
+}
 
-    let outcome = sign_and_send(&message, &client, timeout, strategy).await;
+impl DeliveryReport {
+    /// Status of the given inbox, or `None` if the activity wasn't sent to it
+    pub fn status(&self, inbox: &Url) -> Option<&DeliveryStatus> {
+        self.inboxes
+            .iter()
+            .find(|(i, _)| i == inbox)
+            .map(|(_, status)| status)
+    }
+}
 
-    // "Running" has finished, check the outcome
-    stats.running.fetch_sub(1, Ordering::Relaxed);
+/// Status of the delivery of an activity to one inbox, see [DeliveryReport]
+#[derive(Debug)]
+pub enum DeliveryStatus {
+    /// Not sent, because the inbox belongs to the local instance
+    Local,
+    /// Not sent, because the inbox failed verification with the
+    /// [UrlVerifier](crate::config::UrlVerifier)
+    Skipped(Error),
+    /// Delivered to the inbox
+    Delivered,
+    /// Rejected by the inbox with a client error, the activity is not sent again
+    Rejected {
+        /// Status of the response
+        status: StatusCode,
+        /// Error with the response body
+        error: Error,
+    },
+    /// Sending failed, and the activity is not sent again
+    Failed {
+        /// Status of the last response, or `None` if the inbox couldn't be reached
+        status: Option<StatusCode>,
+        /// Error of the last attempt
+        error: Error,
+    },
+    /// Added to the activity queue, including retries after failed attempts. Receives
+    /// [DeliveryStatus::Delivered], [DeliveryStatus::Rejected] or [DeliveryStatus::Failed] once
+    /// the activity won't be sent again. Closed without a value if the queue is shut down before.
+    Queued(oneshot::Receiver<DeliveryStatus>),
+}
 
-    match outcome {
-        Ok(_) => {
-            stats.completed_last_hour.fetch_add(1, Ordering::Relaxed);
-        }
-        Err(_err) => {
-            stats.retries.fetch_add(1, Ordering::Relaxed);
-            warn!(
-                "Sending activity {} to {} to the retry queue to be tried again later",
-                message.activity_id, message.inbox
-            );
-            // Send to the retry queue.  Ignoring whether it succeeds or not
-            retry_queue.send(message).ok();
+impl DeliveryStatus {
+    fn from_outcome(outcome: Result<(), Error>) -> DeliveryStatus {
+        match outcome {
+            Ok(()) => DeliveryStatus::Delivered,
+            Err(error @ Error::DeliveryRejected { status, .. }) => {
+                DeliveryStatus::Rejected { status, error }
+            }
+            Err(error) => {
+                let status = match &error {
+                    Error::DeliveryFailed { status, .. } => Some(*status),
+                    _ => None,
+                };
+                DeliveryStatus::Failed { status, error }
+            }
         }
     }
 }
 
-async fn retry_worker(
-    client: ClientWithMiddleware,
-    timeout: Duration,
-    message: SendActivityTask,
-    stats: Arc<Stats>,
-    strategy: RetryStrategy,
-) {
-    // Because the times are pretty extravagant between retries, we have to re-sign each time
-    let outcome = retry(
-        || {
-            sign_and_send(
-                &message,
-                &client,
-                timeout,
-                RetryStrategy {
-                    backoff: 0,
-                    retries: 0,
-                    offset: 0,
-                    initial_sleep: 0,
-                },
-            )
-        },
-        strategy,
-    )
-    .await;
-
-    stats.retries.fetch_sub(1, Ordering::Relaxed);
-
-    match outcome {
-        Ok(_) => {
-            stats.completed_last_hour.fetch_add(1, Ordering::Relaxed);
-        }
-        Err(_err) => {
-            stats.dead_last_hour.fetch_add(1, Ordering::Relaxed);
-        }
+/// Same as [queue_activity], but the activity is only queued once [DeferredSend::commit] is called.
+///
+/// Use this when the activity describes a change which is not persisted yet, for example inside of
+/// a database transaction. The expensive preparation such as serializing the activity and loading
+/// the private key happens immediately. If the transaction fails, call [DeferredSend::abort] or
+/// simply drop the returned value, and the activity is never sent.
+pub async fn queue_activity_deferred<Activity, Datatype, ActorType>(
+    activity: &Activity,
+    actor: &ActorType,
+    inboxes: Vec<Url>,
+    data: &Data<Datatype>,
+    options: SendOptions,
+) -> Result<DeferredSend<Datatype>, Error>
+where
+    Activity: ActivityHandler + Serialize + Debug,
+    Datatype: Clone,
+    ActorType: Actor<DataType = Datatype>,
+{
+    let tasks = build_tasks(activity, actor, inboxes, data, options.retry_policy).await?;
+    if !tasks.skipped.is_empty() {
+        info!(
+            "Not sending activity {} to {} inboxes which failed verification",
+            activity.id(),
+            tasks.skipped.len()
+        );
     }
+    Ok(DeferredSend {
+        tasks: tasks.into(),
+        config: data.config.clone(),
+        deliver_after: options.deliver_after,
+        scheduled: Default::default(),
+    })
 }
 
-impl ActivityQueue {
-    fn new(
-        client: ClientWithMiddleware,
-        worker_count: usize,
-        retry_count: usize,
-        timeout: Duration,
-        backoff: usize, // This should be 60 seconds by default or 1 second in tests
-    ) -> Self {
-        let stats: Arc<Stats> = Default::default();
+/// Activity which is prepared for sending, but not queued yet. See [queue_activity_deferred].
+///
+/// Dropping this without calling [DeferredSend::commit] discards the activity. The copies of the
+/// private key which are held for signing are zeroized when dropped.
+#[must_use = "the activity is only sent after calling commit()"]
+pub struct DeferredSend<T: Clone> {
+    tasks: Vec<SendActivityTask>,
+    config: FederationConfig<T>,
+    deliver_after: Option<DateTime<Utc>>,
+    /// Sends which were scheduled by previous calls of [DeferredSend::commit]
+    scheduled: ScheduledSendHandle,
+}
 
-        // This task clears the dead/completed stats every hour
-        let hour_stats = stats.clone();
-        tokio::spawn(async move {
-            let duration = Duration::from_secs(3600);
-            loop {
-                tokio::time::sleep(duration).await;
-                hour_stats.completed_last_hour.store(0, Ordering::Relaxed);
-                hour_stats.dead_last_hour.store(0, Ordering::Relaxed);
+impl<T: Clone> DeferredSend<T> {
+    /// Queue the activity for sending. If this fails, the inboxes which were not queued yet are
+    /// kept, and calling it again retries them. Otherwise calling it more than once has no effect,
+    /// the activity is only sent once.
+    pub async fn commit(&mut self) -> Result<ScheduledSendHandle, Error> {
+        let mut sent = 0;
+        let mut result = Ok(());
+        for task in &self.tasks {
+            match send_or_schedule_tasks(vec![task.clone()], &self.config, self.deliver_after).await
+            {
+                Ok(handle) => self.scheduled.merge(handle),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             }
-        });
-
-        let (retry_sender, mut retry_receiver) = unbounded_channel();
-        let retry_stats = stats.clone();
-        let retry_client = client.clone();
+            sent += 1;
+        }
+        self.tasks.drain(..sent);
+        result.map(|()| self.scheduled.clone())
+    }
 
-        // The "fast path" retry
-        // The backoff should be < 5 mins for this to work otherwise signatures may expire
-        // This strategy is the one that is used with the *same* signature
-        let strategy = RetryStrategy {
-            backoff,
-            retries: 1,
-            offset: 0,
-            initial_sleep: 0,
-        };
+    /// Discard the activity without sending it. This is the same as dropping it.
+    pub fn abort(self) {}
 
-        // The "retry path" strategy
-        // After the fast path fails, a task will sleep up to backoff ^ 2 and then retry again
-        let retry_strategy = RetryStrategy {
-            backoff,
-            retries: 3,
-            offset: 2,
-            initial_sleep: backoff.pow(2), // wait 60 mins before even trying
-        };
+    /// Number of inboxes which the activity will be sent to on commit. Returns zero after commit.
+    pub fn inbox_count(&self) -> usize {
+        self.tasks.len()
+    }
+}
 
-        let retry_sender_task = tokio::spawn(async move {
-            let mut join_set = JoinSet::new();
+impl<T: Clone> Drop for DeferredSend<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.tasks.first() {
+            debug!("Discarding deferred activity {}", task.activity_id);
+        }
+    }
+}
 
-            while let Some(message) = retry_receiver.recv().await {
-                let retry_task = retry_worker(
-                    retry_client.clone(),
-                    timeout,
-                    message,
-                    retry_stats.clone(),
-                    retry_strategy,
-                );
+/// Handle for sends which are scheduled with [SendOptions::deliver_after], to cancel them before
+/// they are delivered, for example to undo sending of a direct message.
+#[derive(Clone, Default)]
+pub struct ScheduledSendHandle {
+    /// Activity id and inbox of each scheduled send
+    keys: Vec<(Url, Url)>,
+    scheduled: Option<Arc<ScheduledSends>>,
+}
 
-                if retry_count > 0 {
-                    // If we're over the limit of retries, wait for them to finish before spawning
-                    while join_set.len() >= retry_count {
-                        join_set.join_next().await;
-                    }
+impl ScheduledSendHandle {
+    fn merge(&mut self, other: ScheduledSendHandle) {
+        self.keys.extend(other.keys);
+        if self.scheduled.is_none() {
+            self.scheduled = other.scheduled;
+        }
+    }
 
-                    join_set.spawn(retry_task);
-                } else {
-                    // If the retry worker count is `0` then just spawn and don't use the join_set
-                    tokio::spawn(retry_task);
-                }
-            }
+    /// Cancel the sends which are not due yet. Returns the number of cancelled sends, which is
+    /// zero if the activity wasn't scheduled or was already added to the queue.
+    pub fn cancel(&self) -> usize {
+        let Some(scheduled) = &self.scheduled else {
+            return 0;
+        };
+        self.keys.iter().filter(|key| scheduled.cancel(key)).count()
+    }
 
-            while !join_set.is_empty() {
-                join_set.join_next().await;
-            }
-        });
+    /// Number of sends which are still waiting for their scheduled time
+    pub fn scheduled_count(&self) -> usize {
+        let Some(scheduled) = &self.scheduled else {
+            return 0;
+        };
+        self.keys
+            .iter()
+            .filter(|key| scheduled.is_waiting(key))
+            .count()
+    }
+}
 
-        let (sender, mut receiver) = unbounded_channel();
+/// Sends which are waiting for their [SendOptions::deliver_after] time, keyed by activity id and
+/// inbox. The tasks themselves are stored in the [QueueBackend] with their due time, so that
+/// they are sent after a restart.
+#[derive(Default)]
+pub(crate) struct ScheduledSends(Mutex<ScheduledState>);
 
-        let sender_stats = stats.clone();
+#[derive(Default)]
+struct ScheduledState {
+    sends: HashMap<(Url, Url), ScheduledSend>,
+    /// Set on shutdown, the waiting tasks are left in the backend
+    stopped: bool,
+}
 
-        let sender_task = tokio::spawn(async move {
-            let mut join_set = JoinSet::new();
+#[derive(Default)]
+struct ScheduledSend {
+    cancelled: bool,
+    /// Wakes up the waiting task when it is cancelled or the queue stops
+    wake: Arc<Notify>,
+}
 
-            while let Some(message) = receiver.recv().await {
-                let task = worker(
-                    client.clone(),
-                    timeout,
-                    message,
-                    retry_sender.clone(),
-                    sender_stats.clone(),
-                    strategy,
-                );
+/// What happened to a scheduled task while it was waiting, see [ScheduledSends::wait]
+enum ScheduledOutcome {
+    Due(SendActivityTask),
+    Cancelled(SendActivityTask),
+    Stopped,
+}
 
-                if worker_count > 0 {
-                    // If we're over the limit of workers, wait for them to finish before spawning
-                    while join_set.len() >= worker_count {
-                        join_set.join_next().await;
-                    }
+impl ScheduledSends {
+    fn lock(&self) -> MutexGuard<'_, ScheduledState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-                    join_set.spawn(task);
-                } else {
-                    // If the worker count is `0` then just spawn and don't use the join_set
-                    tokio::spawn(task);
-                }
-            }
+    fn insert(&self, key: (Url, Url)) {
+        self.lock().sends.insert(key, Default::default());
+    }
 
-            drop(retry_sender);
+    fn remove(&self, key: &(Url, Url)) {
+        self.lock().sends.remove(key);
+    }
 
-            while !join_set.is_empty() {
-                join_set.join_next().await;
+    /// Returns true if the send was still scheduled
+    fn cancel(&self, key: &(Url, Url)) -> bool {
+        match self.lock().sends.get_mut(key) {
+            Some(send) if !send.cancelled => {
+                send.cancelled = true;
+                send.wake.notify_one();
+                true
             }
-        });
-
-        Self {
-            stats,
-            sender,
-            sender_task,
-            retry_sender_task,
+            _ => false,
         }
     }
 
-    async fn queue(&self, message: SendActivityTask) -> Result<(), Error> {
-        self.stats.pending.fetch_add(1, Ordering::Relaxed);
-        self.sender
-            .send(message)
-            .map_err(|e| Error::ActivityQueueError(e.0.activity_id))?;
-
-        Ok(())
+    fn is_waiting(&self, key: &(Url, Url)) -> bool {
+        self.lock()
+            .sends
+            .get(key)
+            .is_some_and(|send| !send.cancelled)
     }
 
-    fn get_stats(&self) -> &Stats {
-        &self.stats
+    /// Number of sends which are waiting and not cancelled
+    fn count(&self) -> usize {
+        let state = self.lock();
+        state.sends.values().filter(|send| !send.cancelled).count()
     }
 
-    #[allow(unused)]
-    // Drops all the senders and shuts down the workers
-    pub(crate) async fn shutdown(self, wait_for_retries: bool) -> Result<Arc<Stats>, Error> {
-        drop(self.sender);
-
-        self.sender_task.await?;
-
-        if wait_for_retries {
-            self.retry_sender_task.await?;
+    /// Wakes up all waiting tasks, and leaves them in the backend
+    fn stop(&self) {
+        let mut state = self.lock();
+        state.stopped = true;
+        for send in state.sends.values() {
+            send.wake.notify_one();
         }
-
-        Ok(self.stats)
     }
-}
-
-/// Creates an activity queue using tokio spawned tasks
-/// Note: requires a tokio runtime
-pub(crate) fn create_activity_queue(
-    client: ClientWithMiddleware,
-    worker_count: usize,
-    retry_count: usize,
-    request_timeout: Duration,
-) -> ActivityQueue {
-    ActivityQueue::new(client, worker_count, retry_count, request_timeout, 60)
-}
 
-/// Retries a future action factory function up to `amount` times with an exponential backoff timer between tries
-async fn retry<T, E: Display + Debug, F: Future<Output = Result<T, E>>, A: FnMut() -> F>(
-    mut action: A,
-    strategy: RetryStrategy,
-) -> Result<T, E> {
-    let mut count = strategy.offset;
-
-    // Do an initial sleep if it's called for
-    if strategy.initial_sleep > 0 {
-        let sleep_dur = Duration::from_secs(strategy.initial_sleep as u64);
-        tokio::time::sleep(sleep_dur).await;
+    /// Waits until the task is due, or until it is cancelled or the queue stops. Tasks which were
+    /// scheduled before a restart are counted as pending by [ActivityQueue::recover], so they are
+    /// moved to the scheduled ones here.
+    async fn wait(&self, task: SendActivityTask, stats: &Stats) -> ScheduledOutcome {
+        let key = (task.activity_id.clone(), task.inbox.clone());
+        let wake = {
+            let mut state = self.lock();
+            if state.stopped {
+                return ScheduledOutcome::Stopped;
+            }
+            let send = state.sends.entry(key.clone()).or_insert_with(|| {
+                stats.pending.fetch_sub(1, Ordering::Relaxed);
+                Default::default()
+            });
+            send.wake.clone()
+        };
+        let deliver_after = task.deliver_after.unwrap_or_else(Utc::now);
+        let delay = (deliver_after - Utc::now()).to_std().unwrap_or_default();
+        let sleep = pin!(tokio::time::sleep(delay));
+        select(sleep, pin!(wake.notified())).await;
+
+        let mut state = self.lock();
+        let cancelled = state.sends.remove(&key).is_some_and(|send| send.cancelled);
+        if cancelled {
+            ScheduledOutcome::Cancelled(task)
+        } else if state.stopped {
+            ScheduledOutcome::Stopped
+        } else {
+            // Counted as pending again until a worker starts sending it
+            stats.pending.fetch_add(1, Ordering::Relaxed);
+            ScheduledOutcome::Due(task)
+        }
     }
+}
 
-    loop {
-        match action().await {
-            Ok(val) => return Ok(val),
-            Err(err) => {
-                if count < strategy.retries {
-                    count += 1;
-
-                    let sleep_amt = strategy.backoff.pow(count as u32) as u64;
-                    let sleep_dur = Duration::from_secs(sleep_amt);
-                    warn!("{err:?}.  Sleeping for {sleep_dur:?} and trying again");
-                    tokio::time::sleep(sleep_dur).await;
-                    continue;
-                } else {
-                    return Err(err);
-                }
-            }
+/// Sends the tasks with [send_tasks], or schedules them if `deliver_after` is in the future.
+async fn send_or_schedule_tasks<T: Clone>(
+    tasks: Vec<SendActivityTask>,
+    config: &FederationConfig<T>,
+    deliver_after: Option<DateTime<Utc>>,
+) -> Result<ScheduledSendHandle, Error> {
+    let deliver_after = match deliver_after {
+        Some(deliver_after) if deliver_after > Utc::now() => deliver_after,
+        _ => {
+            send_tasks(tasks, config).await?;
+            return Ok(ScheduledSendHandle::default());
         }
+    };
+    let Some(first) = tasks.first() else {
+        return Ok(ScheduledSendHandle::default());
+    };
+    let activity_queue = config
+        .activity_queue()
+        .await
+        .ok_or_else(|| Error::ActivityQueueError(first.activity_id.clone()))?;
+    let mut keys = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        keys.push(activity_queue.schedule(task, deliver_after).await?);
     }
+    Ok(ScheduledSendHandle {
+        keys,
+        scheduled: Some(activity_queue.scheduled.clone()),
+    })
+}
+
+/// Send the tasks directly in debug mode, otherwise add them to the activity queue.
+async fn send_tasks<T: Clone>(
+    tasks: impl IntoIterator<Item = SendActivityTask>,
+    config: &FederationConfig<T>,
+) -> Result<(), Error> {
+    for task in tasks {
+        // Don't use the activity queue if this is in debug mode, send and wait directly
+        if config.debug {
+            if let Err(err) = send_directly(&task, config).await {
+                warn!("{err}");
+            }
+        } else {
+            queue_task(task, config).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the task once without the activity queue, and reports the outcome to the delivery
+/// callbacks
+async fn send_directly<T: Clone>(
+    task: &SendActivityTask,
+    config: &FederationConfig<T>,
+) -> Result<(), Error> {
+    let mut attempts = DeliveryAttempts::default();
+    attempts.record();
+    let outcome = task
+        .send_attempt(&config.client, config.request_timeout, attempts.count, None)
+        .await;
+    config
+        .delivery_callbacks
+        .finished(task, &attempts, &outcome);
+    outcome
+}
+
+async fn queue_task<T: Clone>(
+    task: SendActivityTask,
+    config: &FederationConfig<T>,
+) -> Result<(), Error> {
+    let activity_queue = config
+        .activity_queue()
+        .await
+        .ok_or_else(|| Error::ActivityQueueError(task.activity_id.clone()))?;
+    activity_queue.queue(task).await?;
+    let stats = activity_queue.get_stats();
+    let running = stats.running.load(Ordering::Relaxed);
+    if running == config.queue_worker_count && config.queue_worker_count != 0 {
+        warn!("Reached max number of send activity workers ({}). Consider increasing worker count to avoid federation delays", config.queue_worker_count);
+        warn!("{:?}", stats);
+    } else {
+        info!("{:?}", stats);
+    }
+    Ok(())
+}
+
+/// Number and times of the attempts to send a task
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DeliveryAttempts {
+    count: usize,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+}
+
+impl DeliveryAttempts {
+    fn record(&mut self) {
+        let now = Utc::now();
+        self.count += 1;
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+}
+
+/// Activity which was delivered to an inbox, passed to
+/// [FederationConfigBuilder::on_delivery_success](crate::config::FederationConfigBuilder::on_delivery_success).
+#[derive(Clone, Debug)]
+pub struct DeliverySuccess {
+    /// Id of the activity
+    pub activity_id: Url,
+    /// Inbox which received the activity
+    pub inbox: Url,
+    /// Number of send attempts, including the successful one
+    pub attempts: usize,
+}
+
+/// Activity which could not be delivered to an inbox and is not retried anymore, passed to
+/// [FederationConfigBuilder::on_delivery_failure](crate::config::FederationConfigBuilder::on_delivery_failure).
+#[derive(Clone, Debug)]
+pub struct DeliveryFailure {
+    /// Id of the activity
+    pub activity_id: Url,
+    /// Inbox which the activity couldn't be delivered to
+    pub inbox: Url,
+    /// Number of send attempts
+    pub attempts: usize,
+    /// HTTP status of the last response, or `None` if the inbox couldn't be reached
+    pub last_status: Option<StatusCode>,
+    /// Error of the last attempt
+    pub last_error: String,
+    /// Time of the first send attempt
+    pub first_attempt: DateTime<Utc>,
+    /// Time of the last send attempt
+    pub last_attempt: DateTime<Utc>,
+}
+
+type DeliveryCallback<T> = Arc<dyn Fn(T) + Send + Sync>;
+
+/// Callbacks for finished deliveries, set with
+/// [FederationConfigBuilder::on_delivery_success](crate::config::FederationConfigBuilder::on_delivery_success)
+/// and [FederationConfigBuilder::on_delivery_failure](crate::config::FederationConfigBuilder::on_delivery_failure)
+#[derive(Clone, Default)]
+pub(crate) struct DeliveryCallbacks {
+    pub(crate) on_success: Option<DeliveryCallback<DeliverySuccess>>,
+    pub(crate) on_failure: Option<DeliveryCallback<DeliveryFailure>>,
+}
+
+impl DeliveryCallbacks {
+    /// Calls the matching callback for a task which won't be sent again
+    fn finished(
+        &self,
+        task: &SendActivityTask,
+        attempts: &DeliveryAttempts,
+        outcome: &Result<(), Error>,
+    ) {
+        match outcome {
+            Ok(()) => {
+                if let Some(on_success) = &self.on_success {
+                    on_success(DeliverySuccess {
+                        activity_id: task.activity_id.clone(),
+                        inbox: task.inbox.clone(),
+                        attempts: attempts.count,
+                    });
+                }
+            }
+            Err(err) => {
+                if let Some(on_failure) = &self.on_failure {
+                    let last_status = match err {
+                        Error::DeliveryFailed { status, .. }
+                        | Error::DeliveryRejected { status, .. } => Some(*status),
+                        _ => None,
+                    };
+                    let now = Utc::now();
+                    on_failure(DeliveryFailure {
+                        activity_id: task.activity_id.clone(),
+                        inbox: task.inbox.clone(),
+                        attempts: attempts.count,
+                        last_status,
+                        last_error: err.to_string(),
+                        first_attempt: attempts.first.unwrap_or(now),
+                        last_attempt: attempts.last.unwrap_or(now),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Storage for the tasks of the activity queue, set with
+/// [FederationConfigBuilder::queue_backend](crate::config::FederationConfigBuilder::queue_backend).
+///
+/// By default tasks are only kept in memory, so activities which are pending or waiting for a
+/// retry are lost when the process restarts. A persistent implementation, for example in the
+/// application database, keeps each task until it is acknowledged. After a restart the queue
+/// loads all stored tasks again and sends them. [SendActivityTask] can be stored with serde, eg
+/// as json. A task is identified by [SendActivityTask::activity_id] and [SendActivityTask::inbox].
+#[async_trait]
+pub trait QueueBackend: Send + Sync {
+    /// Store a new task which should be sent.
+    async fn push(&self, task: SendActivityTask) -> Result<(), Error>;
+    /// Returns the next stored task which should be sent, or `None` if there is none. The task
+    /// must stay stored until it is acknowledged, but must not be returned a second time by the
+    /// same backend instance.
+    async fn pop(&self) -> Result<Option<SendActivityTask>, Error>;
+    /// Remove a task which is finished, because it was delivered or won't be retried anymore.
+    async fn ack(&self, task: &SendActivityTask) -> Result<(), Error>;
+    /// Number of stored tasks which are not acknowledged yet.
+    async fn len(&self) -> Result<usize, Error>;
+    /// Returns true if there are no stored tasks.
+    async fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len().await? == 0)
+    }
+}
+
+/// Default [QueueBackend] which keeps all tasks in an unbounded channel, i.e. in memory.
+pub(crate) struct MemoryQueueBackend {
+    sender: UnboundedSender<SendActivityTask>,
+    receiver: Mutex<UnboundedReceiver<SendActivityTask>>,
+    len: AtomicUsize,
+}
+
+impl Default for MemoryQueueBackend {
+    fn default() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        MemoryQueueBackend {
+            sender,
+            receiver: Mutex::new(receiver),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl QueueBackend for MemoryQueueBackend {
+    async fn push(&self, task: SendActivityTask) -> Result<(), Error> {
+        self.sender
+            .send(task)
+            .map_err(|e| Error::ActivityQueueError(e.0.activity_id))?;
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn pop(&self) -> Result<Option<SendActivityTask>, Error> {
+        let mut receiver = self.receiver.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(receiver.try_recv().ok())
+    }
+
+    async fn ack(&self, _task: &SendActivityTask) -> Result<(), Error> {
+        self.len
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                len.checked_sub(1)
+            })
+            .ok();
+        Ok(())
+    }
+
+    async fn len(&self) -> Result<usize, Error> {
+        Ok(self.len.load(Ordering::Relaxed))
+    }
+}
+
+/// The [QueueBackend] used by a queue, which logs errors of the backend. Tasks loaded from the
+/// backend get the signing and host limiters of the config. Finished tasks are reported to the
+/// delivery callbacks.
+#[derive(Clone)]
+pub(crate) struct TaskStore {
+    backend: Arc<dyn QueueBackend>,
+    signing_limiter: Arc<SigningLimiter>,
+    host_limiter: Arc<HostLimiter>,
+    peer_software: Arc<PeerSoftwareCache>,
+    metrics_hook: Arc<dyn FederationMetricsHook>,
+    callbacks: DeliveryCallbacks,
+    /// Receivers of the final status of tasks, keyed by activity id and inbox, see
+    /// [send_activity_with_report]
+    watchers: Arc<Mutex<DeliveryWatchers>>,
+}
+
+impl TaskStore {
+    pub(crate) fn new(
+        backend: Arc<dyn QueueBackend>,
+        signing_limiter: Arc<SigningLimiter>,
+        host_limiter: Arc<HostLimiter>,
+        peer_software: Arc<PeerSoftwareCache>,
+        metrics_hook: Arc<dyn FederationMetricsHook>,
+        callbacks: DeliveryCallbacks,
+    ) -> Self {
+        TaskStore {
+            backend,
+            signing_limiter,
+            host_limiter,
+            peer_software,
+            metrics_hook,
+            callbacks,
+            watchers: Default::default(),
+        }
+    }
+
+    /// Returns a receiver for the final status of the task, once it is finished
+    fn watch(&self, task: &SendActivityTask) -> oneshot::Receiver<DeliveryStatus> {
+        let (sender, receiver) = oneshot::channel();
+        let key = (task.activity_id.clone(), task.inbox.clone());
+        self.watchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, sender);
+        receiver
+    }
+
+    /// Removes the receiver of the task, which is then closed without a value
+    fn unwatch(&self, key: &(Url, Url)) {
+        self.watchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key);
+    }
+
+    async fn pop(&self) -> Option<SendActivityTask> {
+        match self.backend.pop().await {
+            Ok(task) => task.map(|mut task| {
+                task.signing_limiter = self.signing_limiter.clone();
+                task.host_limiter = self.host_limiter.clone();
+                task.peer_software = self.peer_software.clone();
+                task.metrics_hook = self.metrics_hook.clone();
+                task
+            }),
+            Err(err) => {
+                warn!("Failed to load task from activity queue backend: {err}");
+                None
+            }
+        }
+    }
+
+    async fn ack(&self, task: &SendActivityTask) {
+        if let Err(err) = self.backend.ack(task).await {
+            warn!("Failed to remove task {task} from activity queue backend: {err}");
+        }
+    }
+
+    /// Reports a task which won't be sent again to the delivery callbacks, and removes it from
+    /// the backend
+    async fn finish(&self, finished: FinishedTask) {
+        self.callbacks
+            .finished(&finished.task, &finished.attempts, &finished.outcome);
+        self.ack(&finished.task).await;
+        let key = (
+            finished.task.activity_id.clone(),
+            finished.task.inbox.clone(),
+        );
+        let watcher = self
+            .watchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key);
+        if let Some(watcher) = watcher {
+            watcher
+                .send(DeliveryStatus::from_outcome(finished.outcome))
+                .ok();
+        }
+    }
+}
+
+type DeliveryWatchers = HashMap<(Url, Url), oneshot::Sender<DeliveryStatus>>;
+
+/// Task which won't be sent again, with the outcome of the last attempt
+struct FinishedTask {
+    task: SendActivityTask,
+    attempts: DeliveryAttempts,
+    outcome: Result<(), Error>,
+}
+
+impl Default for TaskStore {
+    fn default() -> Self {
+        TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Arc::new(NoMetrics),
+            Default::default(),
+        )
+    }
+}
+
+/// A simple activity queue which spawns tokio workers to send out requests
+/// When creating a queue, it will spawn a task per worker thread
+/// Tasks are stored in a [QueueBackend] until they are finished, and the workers are woken up
+/// through an unbounded mpsc channel.
+pub(crate) struct ActivityQueue {
+    // Stats shared between the queue and workers
+    stats: Arc<Stats>,
+    store: TaskStore,
+    scheduled: Arc<ScheduledSends>,
+    /// Taken out by [ActivityQueue::shutdown], after that no new tasks are accepted
+    workers: Mutex<Option<Workers>>,
+}
+
+/// Channels and tasks which keep the workers of an [ActivityQueue] running
+struct Workers {
+    sender: UnboundedSender<Dispatch>,
+    retry_sender: UnboundedSender<RetryTask>,
+    sender_task: JoinHandle<()>,
+    retry_sender_task: JoinHandle<()>,
+    /// Set when shutting down without waiting for retries, see [PoolMode::Ordered]
+    stopping: watch::Sender<bool>,
+}
+
+/// Message to the dispatcher of the activity queue
+enum Dispatch {
+    /// There are new tasks in the backend
+    Pop,
+    /// A scheduled task is due now, see [ScheduledSends]
+    Due(Box<SendActivityTask>),
+}
+
+/// How the concurrency of workers is limited, see
+/// [FederationConfigBuilder::unified_worker_pool](crate::config::FederationConfigBuilder::unified_worker_pool)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum PoolMode {
+    /// Fresh sends and retries have separate pools with `worker_count` and `retry_count` workers
+    #[default]
+    Separate,
+    /// Fresh sends and retries share `worker_count` slots, with at most `retry_percent` of them
+    /// used by retries
+    Unified { retry_percent: u8 },
+    /// Fresh sends are grouped by inbox host, and the sends to each host run one after another
+    /// in the order they were queued. Up to `worker_count` hosts are sent to at the same time.
+    /// Retries have a separate pool with `retry_count` workers.
+    PerHost,
+    /// Fresh sends are grouped by inbox, and the sends to each inbox run one after another in
+    /// the order they were queued. A task which fails is moved to the retry queue like in the
+    /// other modes, and the next task for the inbox waits until it succeeds or is dropped. The
+    /// waiting sub-queue doesn't hold a worker slot. When `limit` tasks are waiting for an
+    /// inbox, further tasks are moved directly to the retry queue, and are not ordered anymore.
+    /// Up to `worker_count` inboxes are sent to at the same time.
+    Ordered { limit: usize },
+}
+
+/// A future which sends a task and removes it from the store once it is finished
+type SendFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Closed once a task which was moved to the retry queue is finished, see [PoolMode::Ordered]
+type RetryDone = oneshot::Receiver<()>;
+
+/// How long the worker of a sub-queue waits for new tasks before it stops, see [SubQueues]
+const SUB_QUEUE_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sub-queues of [PoolMode::PerHost] and [PoolMode::Ordered], keyed by inbox host or inbox.
+/// Each sub-queue has a worker which runs its sends in order. The worker is started with the
+/// first task for the key, and stops once it didn't receive any new tasks for
+/// [SUB_QUEUE_IDLE_TIMEOUT].
+struct SubQueues {
+    queues: HashMap<String, SubQueue>,
+    /// Maximum number of sends waiting in a sub-queue, `0` means no limit
+    limit: usize,
+    /// Set when the activity queue shuts down without waiting for retries
+    stopping: watch::Receiver<bool>,
+}
+
+struct SubQueue {
+    sender: UnboundedSender<(SendFuture, Option<RetryDone>)>,
+    /// Number of sends which are waiting or running
+    pending: Arc<AtomicUsize>,
+}
+
+impl SubQueues {
+    fn new(limit: usize, stopping: watch::Receiver<bool>) -> Self {
+        SubQueues {
+            queues: HashMap::new(),
+            limit,
+            stopping,
+        }
+    }
+
+    /// Returns true if the sub-queue for the key can't take any more sends
+    fn is_full(&self, key: &str) -> bool {
+        self.limit > 0
+            && self.queues.get(key).is_some_and(|queue| {
+                !queue.sender.is_closed() && queue.pending.load(Ordering::Relaxed) >= self.limit
+            })
+    }
+
+    /// Adds the send to the sub-queue for the key, and starts a worker for it if it has none. The
+    /// next send of the sub-queue waits until `done` is closed.
+    fn push(
+        &mut self,
+        key: String,
+        send: SendFuture,
+        done: Option<RetryDone>,
+        join_set: &mut JoinSet<()>,
+    ) {
+        let send = match self.queues.get(&key) {
+            Some(queue) => {
+                queue.pending.fetch_add(1, Ordering::Relaxed);
+                match queue.sender.send((send, done)) {
+                    Ok(()) => return,
+                    // The worker of the sub-queue stopped because it was idle
+                    Err(SendError(send)) => send,
+                }
+            }
+            None => (send, done),
+        };
+        self.queues.retain(|_, queue| !queue.sender.is_closed());
+        let (sender, receiver) = unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(1));
+        sender.send(send).ok();
+        join_set.spawn(sub_queue_worker(
+            receiver,
+            pending.clone(),
+            self.stopping.clone(),
+        ));
+        self.queues.insert(key, SubQueue { sender, pending });
+    }
+}
+
+/// Runs the sends of one sub-queue in order, see [SubQueues]. The sends acquire a slot of the
+/// [WorkerPool] themselves.
+///
+/// When a send was moved to the retry queue, the next one waits until it is finished, without
+/// holding a slot. When the activity queue shuts down without waiting for retries, the worker
+/// stops waiting, and the remaining sends are left in the [QueueBackend] so that they are not
+/// sent out of order.
+async fn sub_queue_worker(
+    mut receiver: UnboundedReceiver<(SendFuture, Option<RetryDone>)>,
+    pending: Arc<AtomicUsize>,
+    mut stopping: watch::Receiver<bool>,
+) {
+    loop {
+        let (send, done) = match tokio::time::timeout(SUB_QUEUE_IDLE_TIMEOUT, receiver.recv()).await
+        {
+            Ok(Some(send)) => send,
+            Ok(None) => break,
+            Err(_) => {
+                // Stop accepting new sends, and finish the ones which arrived in the meantime
+                receiver.close();
+                continue;
+            }
+        };
+        send.await;
+        pending.fetch_sub(1, Ordering::Relaxed);
+        if let Some(done) = done {
+            let stopped = pin!(stopping.wait_for(|stopping| *stopping));
+            if let Either::Right(_) = select(done, stopped).await {
+                debug!("Activity queue stopped while waiting for a retry, leaving the other sends");
+                break;
+            }
+        }
+    }
+}
+
+/// Slots of the workers, which limit the number of send attempts at the same time. Fresh sends
+/// need a permit of `slots`, and retries a permit of `retry_slots`. In the unified pool retries
+/// need both. There is no limit if a semaphore is `None`.
+#[derive(Clone, Default)]
+struct WorkerPool {
+    slots: Option<Arc<Semaphore>>,
+    retry_slots: Option<Arc<Semaphore>>,
+    unified: bool,
+}
+
+impl WorkerPool {
+    fn new(mode: PoolMode, worker_count: usize, retry_count: usize) -> Self {
+        let semaphore = |count: usize| (count > 0).then(|| Arc::new(Semaphore::new(count)));
+        match mode {
+            PoolMode::Unified { retry_percent } if worker_count > 0 => {
+                let retry_slots = (worker_count * usize::from(retry_percent.min(100)) / 100).max(1);
+                WorkerPool {
+                    slots: semaphore(worker_count),
+                    retry_slots: semaphore(retry_slots),
+                    unified: true,
+                }
+            }
+            PoolMode::Unified { .. } => WorkerPool::default(),
+            PoolMode::Separate | PoolMode::PerHost | PoolMode::Ordered { .. } => WorkerPool {
+                slots: semaphore(worker_count),
+                retry_slots: semaphore(retry_count),
+                unified: false,
+            },
+        }
+    }
+
+    /// Waits for a free slot. Retry slots are acquired first, so that retries waiting for their
+    /// share don't block slots which fresh sends could use.
+    async fn acquire(&self, is_retry: bool) -> Vec<OwnedSemaphorePermit> {
+        let mut permits = Vec::with_capacity(2);
+        if let (true, Some(retry_slots)) = (is_retry, &self.retry_slots) {
+            permits.push(acquire_permit(retry_slots).await);
+        }
+        let needs_slot = !is_retry || self.unified;
+        if let (true, Some(slots)) = (needs_slot, &self.slots) {
+            permits.push(acquire_permit(slots).await);
+        }
+        permits
+    }
+}
+
+/// Everything which the workers of an [ActivityQueue] need to send tasks
+#[derive(Clone)]
+struct SendContext {
+    client: ClientWithMiddleware,
+    timeout: Duration,
+    /// Base of the exponential backoff between attempts in seconds, see [retry_delay]
+    backoff: usize,
+    stats: Arc<Stats>,
+    pool: WorkerPool,
+}
+
+impl SendContext {
+    /// Waits for a free slot and until the host limiter allows sending the task. While the host
+    /// is at its limit, or asked to wait with `Retry-After`, the task waits without a slot so that
+    /// sends to other hosts are not blocked.
+    async fn acquire(
+        &self,
+        task: &SendActivityTask,
+        is_retry: bool,
+    ) -> (Vec<OwnedSemaphorePermit>, HostPermit) {
+        let host_permit = task.host_limiter.acquire_permit(&task.inbox).await;
+        loop {
+            let permits = self.pool.acquire(is_retry).await;
+            match task.host_limiter.try_reserve(&task.inbox).await {
+                Ok(()) => return (permits, host_permit),
+                Err(wait) => {
+                    drop(permits);
+                    debug!("Waiting {wait:?} before sending {task}");
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+}
+
+async fn acquire_permit(semaphore: &Arc<Semaphore>) -> OwnedSemaphorePermit {
+    semaphore
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("worker pool semaphore is never closed")
+}
+
+/// Snapshot of the activity queue counters, see [FederationConfig::queue_stats].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ActivityQueueStats {
+    /// Tasks which are waiting for their [SendOptions::deliver_after] time
+    pub scheduled: usize,
+    /// Tasks which are queued, but not being sent yet
+    pub pending: usize,
+    /// Tasks which are being sent right now for the first time
+    pub running: usize,
+    /// Tasks in the retry queue, which are waiting for the next attempt or being sent
+    pub retries: usize,
+    /// Tasks in the retry queue which are being sent right now
+    pub running_retries: usize,
+    /// Tasks which failed permanently in the last hour
+    pub dead_last_hour: usize,
+    /// Tasks which were delivered in the last hour
+    pub completed_last_hour: usize,
+    /// Hosts to which sends currently fail immediately after repeated failures, see
+    /// [FederationConfigBuilder::circuit_breaker](crate::config::FederationConfigBuilder::circuit_breaker)
+    pub unavailable_hosts: usize,
+}
+
+impl ActivityQueueStats {
+    /// Number of tasks which are not finished yet
+    pub fn len(&self) -> usize {
+        self.scheduled + self.pending + self.running + self.retries
+    }
+
+    /// Returns true if there are no unfinished tasks
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Simple stat counter to show where we're up to with sending messages
+/// This is a lock-free way to share things between tasks
+/// When reading these values it's possible (but extremely unlikely) to get stale data if a worker task is in the middle of transitioning
+#[derive(Default)]
+pub(crate) struct Stats {
+    pending: AtomicUsize,
+    running: AtomicUsize,
+    retries: AtomicUsize,
+    running_retries: AtomicUsize,
+    dead_last_hour: AtomicUsize,
+    completed_last_hour: AtomicUsize,
+    full: PolicyStats,
+    fast_only: PolicyStats,
+    no_retry: PolicyStats,
+}
+
+/// Outcomes in the last hour for tasks with one [RetryPolicy]
+#[derive(Default)]
+struct PolicyStats {
+    dead: AtomicUsize,
+    completed: AtomicUsize,
+}
+
+impl Stats {
+    fn policy(&self, policy: RetryPolicy) -> &PolicyStats {
+        match policy {
+            RetryPolicy::Full => &self.full,
+            RetryPolicy::FastOnly => &self.fast_only,
+            RetryPolicy::None => &self.no_retry,
+        }
+    }
+
+    fn record_completed(&self, policy: RetryPolicy) {
+        self.completed_last_hour.fetch_add(1, Ordering::Relaxed);
+        self.policy(policy)
+            .completed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dead(&self, policy: RetryPolicy) {
+        self.dead_last_hour.fetch_add(1, Ordering::Relaxed);
+        self.policy(policy).dead.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ActivityQueueStats {
+        ActivityQueueStats {
+            scheduled: 0,
+            pending: self.pending.load(Ordering::Relaxed),
+            running: self.running.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            running_retries: self.running_retries.load(Ordering::Relaxed),
+            dead_last_hour: self.dead_last_hour.load(Ordering::Relaxed),
+            completed_last_hour: self.completed_last_hour.load(Ordering::Relaxed),
+            unavailable_hosts: 0,
+        }
+    }
+
+    fn reset_hourly(&self) {
+        self.completed_last_hour.store(0, Ordering::Relaxed);
+        self.dead_last_hour.store(0, Ordering::Relaxed);
+        for stats in [&self.full, &self.fast_only, &self.no_retry] {
+            stats.completed.store(0, Ordering::Relaxed);
+            stats.dead.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Debug for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Activity queue stats: pending: {}, running: {}, retries: {}, running retries: {}, \
+            dead: {}, complete: {}, by policy (complete/dead): full {}, fast only {}, none {}",
+            self.pending.load(Ordering::Relaxed),
+            self.running.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+            self.running_retries.load(Ordering::Relaxed),
+            self.dead_last_hour.load(Ordering::Relaxed),
+            self.completed_last_hour.load(Ordering::Relaxed),
+            self.full,
+            self.fast_only,
+            self.no_retry,
+        )
+    }
+}
+
+impl Display for PolicyStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.completed.load(Ordering::Relaxed),
+            self.dead.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Task which failed and waits in the retry queue for its next attempt, see [retry_worker]
+struct RetryTask {
+    task: SendActivityTask,
+    attempts: DeliveryAttempts,
+    /// Time to wait before the next attempt
+    delay: Duration,
+    /// Dropped once the task is finished, see [PoolMode::Ordered]
+    done: Option<oneshot::Sender<()>>,
+}
+
+/// A tokio spawned worker which is responsible for submitting requests to federated servers
+/// This makes a single attempt, and if it fails, moves the task to the retry queue.
+/// We need to retry activity sending in case the target instances is temporarily unreachable.
+/// In this case, the task is stored and resent when the instance is hopefully back up. This
+/// list shows the retry intervals, and which events of the target instance can be covered:
+/// - 60s (one minute, service restart)
+/// - 60min (one hour, instance maintenance)
+/// - 60h (2.5 days, major incident with rebuild from backup)
+///
+/// Tasks with [RetryPolicy::FastOnly] are only retried after one minute, and tasks with
+/// [RetryPolicy::None] are attempted once.
+///
+/// Returns the task if it is finished, and `None` if it was moved to the retry queue.
+#[instrument(
+    name = "activity_queue_worker",
+    skip_all,
+    fields(activity_id = %message.activity_id, inbox = %message.inbox)
+)]
+async fn worker(
+    context: SendContext,
+    message: SendActivityTask,
+    retry_queue: UnboundedSender<RetryTask>,
+    done: Option<oneshot::Sender<()>>,
+) -> Option<FinishedTask> {
+    let stats = &context.stats;
+    let (permits, host_permit) = context.acquire(&message, false).await;
+    stats.pending.fetch_sub(1, Ordering::Relaxed);
+    stats.running.fetch_add(1, Ordering::Relaxed);
+
+    let policy = message.retry_policy;
+    let mut attempts = DeliveryAttempts::default();
+    attempts.record();
+    let outcome = message
+        .send_attempt(
+            &context.client,
+            context.timeout,
+            attempts.count,
+            Some(host_permit),
+        )
+        .await;
+    drop(permits);
+
+    // "Running" has finished, check the outcome
+    stats.running.fetch_sub(1, Ordering::Relaxed);
+
+    let err = match outcome {
+        Ok(_) => {
+            stats.record_completed(policy);
+            return Some(FinishedTask {
+                task: message,
+                attempts,
+                outcome,
+            });
+        }
+        Err(ref err) => err,
+    };
+    match retry_delay(context.backoff, &message, &attempts, err) {
+        Some(delay) => {
+            stats.retries.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Sending activity {} to {} to the retry queue to be tried again in {delay:?}",
+                message.activity_id, message.inbox
+            );
+            // Send to the retry queue.  Ignoring whether it succeeds or not
+            retry_queue
+                .send(RetryTask {
+                    task: message,
+                    attempts,
+                    delay,
+                    done,
+                })
+                .ok();
+            None
+        }
+        None => {
+            if err.is_retryable() {
+                warn!(
+                    "Sending activity {} to {} failed, not retrying due to {:?} retry policy",
+                    message.activity_id, message.inbox, policy
+                );
+            } else {
+                warn!(
+                    "Sending activity {} to {} failed, not retrying: {err}",
+                    message.activity_id, message.inbox
+                );
+            }
+            stats.record_dead(policy);
+            Some(FinishedTask {
+                task: message,
+                attempts,
+                outcome,
+            })
+        }
+    }
+}
+
+/// Retries a task which failed in the [worker]. A slot is only held during each send attempt and
+/// not while waiting for the next one, so that waiting retries don't block other sends.
+///
+/// Returns the task once it is finished.
+#[instrument(
+    name = "activity_queue_retry_worker",
+    skip_all,
+    fields(activity_id = %retry.task.activity_id, inbox = %retry.task.inbox)
+)]
+async fn retry_worker(context: SendContext, retry: RetryTask) -> FinishedTask {
+    let RetryTask {
+        task,
+        mut attempts,
+        mut delay,
+        done: _,
+    } = retry;
+    let stats = &context.stats;
+    let outcome = loop {
+        tokio::time::sleep(delay).await;
+        let (_permits, host_permit) = context.acquire(&task, true).await;
+        stats.running_retries.fetch_add(1, Ordering::Relaxed);
+        attempts.record();
+        // Because the times are pretty extravagant between retries, we have to re-sign each time
+        let outcome = task
+            .send_attempt(
+                &context.client,
+                context.timeout,
+                attempts.count,
+                Some(host_permit),
+            )
+            .await;
+        stats.running_retries.fetch_sub(1, Ordering::Relaxed);
+        let next = match &outcome {
+            Ok(()) => None,
+            Err(err) => retry_delay(context.backoff, &task, &attempts, err),
+        };
+        match next {
+            Some(next) => delay = next,
+            None => break outcome,
+        }
+    };
+
+    stats.retries.fetch_sub(1, Ordering::Relaxed);
+
+    match outcome {
+        Ok(_) => {
+            stats.record_completed(task.retry_policy);
+        }
+        Err(_) => {
+            stats.record_dead(task.retry_policy);
+        }
+    }
+    FinishedTask {
+        task,
+        attempts,
+        outcome,
+    }
+}
+
+impl ActivityQueue {
+    fn new(
+        client: ClientWithMiddleware,
+        worker_count: usize,
+        retry_count: usize,
+        timeout: Duration,
+        backoff: usize, // This should be 60 seconds by default or 1 second in tests
+        mode: PoolMode,
+        store: TaskStore,
+    ) -> Self {
+        let stats: Arc<Stats> = Default::default();
+
+        // All tasks are spawned directly, and wait for a free slot of the pool for each attempt
+        let context = SendContext {
+            client,
+            timeout,
+            backoff,
+            stats: stats.clone(),
+            pool: WorkerPool::new(mode, worker_count, retry_count),
+        };
+
+        // This task clears the dead/completed stats every hour
+        let hour_stats = stats.clone();
+        tokio::spawn(async move {
+            let duration = Duration::from_secs(3600);
+            loop {
+                tokio::time::sleep(duration).await;
+                hour_stats.reset_hourly();
+            }
+        });
+
+        let (retry_sender, mut retry_receiver) = unbounded_channel::<RetryTask>();
+        let retry_context = context.clone();
+        let retry_store = store.clone();
+
+        let retry_sender_fut = async move {
+            let mut join_set = JoinSet::new();
+
+            while let Some(mut retry) = retry_receiver.recv().await {
+                // Held until the task is finished, so that the next task of an ordered sub-queue
+                // waits for it
+                let done = retry.done.take();
+                let retry_task = retry_worker(retry_context.clone(), retry);
+                let store = retry_store.clone();
+                let retry_task = async move {
+                    store.finish(retry_task.await).await;
+                    drop(done);
+                };
+
+                // Retries wait for their next attempt without a slot, and the pool limits how many
+                // of them are sent at the same time. Only clean up finished tasks, so that
+                // shutdown can wait for the others.
+                while join_set.try_join_next().is_some() {}
+                join_set.spawn(retry_task);
+            }
+
+            while !join_set.is_empty() {
+                join_set.join_next().await;
+            }
+            debug!("Retry queue stopped");
+        };
+        let retry_sender_task =
+            tokio::spawn(retry_sender_fut.instrument(info_span!("activity_queue_retries")));
+
+        let (sender, mut receiver) = unbounded_channel();
+        let (stopping, stopping_receiver) = watch::channel(false);
+        let scheduled: Arc<ScheduledSends> = Default::default();
+
+        let sender_stats = stats.clone();
+        let worker_retry_sender = retry_sender.clone();
+        let sender_store = store.clone();
+        let sender_scheduled = scheduled.clone();
+        // Weak, so that the dispatcher stops once the queue drops its sender
+        let due_sender = sender.downgrade();
+
+        let sender_fut = async move {
+            let mut join_set = JoinSet::new();
+            let mut sub_queues = match mode {
+                PoolMode::PerHost => Some(SubQueues::new(0, stopping_receiver)),
+                PoolMode::Ordered { limit } => Some(SubQueues::new(limit, stopping_receiver)),
+                PoolMode::Separate | PoolMode::Unified { .. } => None,
+            };
+
+            // Each message signals that there are new tasks in the store, or that a scheduled
+            // task is due
+            while let Some(dispatch) = receiver.recv().await {
+                let mut due = match dispatch {
+                    Dispatch::Pop => None,
+                    Dispatch::Due(task) => Some(*task),
+                };
+                loop {
+                    // Tasks which are due don't wait again, their stored copy still has the
+                    // due time
+                    let (message, scheduled) = match due.take() {
+                        Some(task) => (task, false),
+                        None => match sender_store.pop().await {
+                            Some(task) => {
+                                let scheduled = task.deliver_after.is_some();
+                                (task, scheduled)
+                            }
+                            None => break,
+                        },
+                    };
+                    // Scheduled tasks wait without a slot, and come back here once they are due
+                    if scheduled {
+                        let Some(due_sender) = due_sender.upgrade() else {
+                            continue;
+                        };
+                        let scheduled = sender_scheduled.clone();
+                        let stats = sender_stats.clone();
+                        let store = sender_store.clone();
+                        while join_set.try_join_next().is_some() {}
+                        join_set.spawn(async move {
+                            match scheduled.wait(message, &stats).await {
+                                ScheduledOutcome::Due(task) => {
+                                    due_sender.send(Dispatch::Due(Box::new(task))).ok();
+                                }
+                                ScheduledOutcome::Cancelled(task) => {
+                                    store.ack(&task).await;
+                                    store.unwatch(&(task.activity_id, task.inbox));
+                                }
+                                ScheduledOutcome::Stopped => {}
+                            }
+                        });
+                        continue;
+                    }
+                    let key = match mode {
+                        PoolMode::Ordered { .. } => message.inbox.to_string(),
+                        _ => format!(
+                            "{}:{}",
+                            message.inbox.host_str().unwrap_or_default(),
+                            message.inbox.port_or_known_default().unwrap_or_default()
+                        ),
+                    };
+                    if sub_queues
+                        .as_ref()
+                        .is_some_and(|sub_queues| sub_queues.is_full(&key))
+                    {
+                        warn!(
+                            "Too many activities waiting for {}, moving {} to the retry queue",
+                            message.inbox, message.activity_id
+                        );
+                        sender_stats.pending.fetch_sub(1, Ordering::Relaxed);
+                        sender_stats.retries.fetch_add(1, Ordering::Relaxed);
+                        let retry = RetryTask {
+                            task: message,
+                            attempts: Default::default(),
+                            delay: Duration::ZERO,
+                            done: None,
+                        };
+                        worker_retry_sender.send(retry).ok();
+                        continue;
+                    }
+
+                    // In ordered mode the next task for the inbox waits until a task which was
+                    // moved to the retry queue is finished
+                    let (done, wait_for_retry) = match mode {
+                        PoolMode::Ordered { .. } => {
+                            let (sender, receiver) = oneshot::channel();
+                            (Some(sender), Some(receiver))
+                        }
+                        _ => (None, None),
+                    };
+                    let task = worker(context.clone(), message, worker_retry_sender.clone(), done);
+                    let store = sender_store.clone();
+                    let task = async move {
+                        // Otherwise the task was moved to the retry queue
+                        if let Some(finished) = task.await {
+                            store.finish(finished).await;
+                        }
+                    };
+
+                    // The pool limits concurrency. Only clean up finished tasks, so that shutdown
+                    // can wait for the others.
+                    while join_set.try_join_next().is_some() {}
+                    if let Some(sub_queues) = &mut sub_queues {
+                        sub_queues.push(key, Box::pin(task), wait_for_retry, &mut join_set);
+                    } else {
+                        join_set.spawn(task);
+                    }
+                }
+            }
+
+            // Closes the sub-queues, so that their workers stop once they are empty
+            drop(sub_queues);
+            while !join_set.is_empty() {
+                join_set.join_next().await;
+            }
+            debug!("Activity queue stopped");
+        };
+        let sender_task = tokio::spawn(sender_fut.instrument(info_span!("activity_queue")));
+
+        Self {
+            stats,
+            store,
+            scheduled,
+            workers: Mutex::new(Some(Workers {
+                sender,
+                retry_sender,
+                sender_task,
+                retry_sender_task,
+                stopping,
+            })),
+        }
+    }
+
+    /// Sender to wake up the workers, or an error if the queue was shut down
+    fn sender(&self, activity_id: &Url) -> Result<UnboundedSender<Dispatch>, Error> {
+        let workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        workers
+            .as_ref()
+            .map(|workers| workers.sender.clone())
+            .ok_or_else(|| Error::ActivityQueueError(activity_id.clone()))
+    }
+
+    async fn queue(&self, message: SendActivityTask) -> Result<(), Error> {
+        let sender = self.sender(&message.activity_id)?;
+        enqueue(&self.store, &self.stats, &sender, message).await
+    }
+
+    /// Stores the task in the backend, to be sent once `deliver_after` is reached. Returns the key
+    /// for cancelling it.
+    async fn schedule(
+        &self,
+        mut message: SendActivityTask,
+        deliver_after: DateTime<Utc>,
+    ) -> Result<(Url, Url), Error> {
+        let key = (message.activity_id.clone(), message.inbox.clone());
+        let sender = self.sender(&message.activity_id)?;
+        message.deliver_after = Some(deliver_after);
+
+        // Registered before the task is stored, so that it can be cancelled right away
+        self.scheduled.insert(key.clone());
+        if let Err(err) = enqueue(&self.store, &self.stats, &sender, message).await {
+            self.scheduled.remove(&key);
+            return Err(err);
+        }
+        Ok(key)
+    }
+
+    /// Sends the tasks which are left in the backend from a previous run. Needs to be called
+    /// before any new tasks are queued.
+    async fn recover(&self) {
+        let count = match self.store.backend.len().await {
+            Ok(count) => count,
+            Err(err) => {
+                warn!("Failed to read length of activity queue backend: {err}");
+                return;
+            }
+        };
+        if count > 0 {
+            info!("Loaded {count} unfinished tasks from activity queue backend");
+            self.stats.pending.fetch_add(count, Ordering::Relaxed);
+            let workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(workers) = workers.as_ref() {
+                workers.sender.send(Dispatch::Pop).ok();
+            }
+        }
+    }
+
+    fn get_stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub(crate) fn stats_snapshot(&self) -> ActivityQueueStats {
+        ActivityQueueStats {
+            scheduled: self.scheduled.count(),
+            ..self.stats.snapshot()
+        }
+    }
+
+    /// Drops all the senders and waits until the workers are finished. Scheduled sends are left
+    /// in the backend, and no new tasks are accepted afterwards. Calling it again has no effect.
+    pub(crate) async fn shutdown(&self, wait_for_retries: bool) -> Result<Arc<Stats>, Error> {
+        let workers = self
+            .workers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(workers) = workers {
+            self.scheduled.stop();
+            drop(workers.sender);
+            if !wait_for_retries {
+                // Ordered sub-queues don't wait for their retries anymore
+                workers.stopping.send_replace(true);
+            }
+
+            workers.sender_task.await?;
+            drop(workers.retry_sender);
+
+            if wait_for_retries {
+                workers.retry_sender_task.await?;
+            }
+            // Closes the receivers of tasks which are not finished yet
+            self.store
+                .watchers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+
+        Ok(self.stats.clone())
+    }
+}
+
+/// Adds a task to the backend and wakes up the workers. Scheduled tasks are not counted as
+/// pending, see [ScheduledSends].
+async fn enqueue(
+    store: &TaskStore,
+    stats: &Stats,
+    sender: &UnboundedSender<Dispatch>,
+    message: SendActivityTask,
+) -> Result<(), Error> {
+    let activity_id = message.activity_id.clone();
+    let scheduled = message.deliver_after.is_some();
+    store.backend.push(message).await?;
+    if !scheduled {
+        stats.pending.fetch_add(1, Ordering::Relaxed);
+    }
+    sender
+        .send(Dispatch::Pop)
+        .map_err(|_| Error::ActivityQueueError(activity_id))?;
+
+    Ok(())
+}
+
+/// Creates an activity queue using tokio spawned tasks, and starts sending the tasks which are
+/// left in the backend.
+/// Note: requires a tokio runtime
+pub(crate) async fn create_activity_queue(
+    client: ClientWithMiddleware,
+    worker_count: usize,
+    retry_count: usize,
+    request_timeout: Duration,
+    mode: PoolMode,
+    store: TaskStore,
+) -> ActivityQueue {
+    let queue = ActivityQueue::new(
+        client,
+        worker_count,
+        retry_count,
+        request_timeout,
+        60,
+        mode,
+        store,
+    );
+    queue.recover().await;
+    queue
+}
+
+/// Upper limit for the time which an inbox can request to wait with `Retry-After`, so that
+/// workers are not blocked indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// Time to wait before sending the task again after the last of `attempts` failed with `err`,
+/// or `None` if it shouldn't be sent again. Errors which are not
+/// [retryable](Error::is_retryable) are never retried. Otherwise the task is sent up to four
+/// times with [RetryPolicy::Full], and the time between attempts grows exponentially.
+fn retry_delay(
+    backoff: usize,
+    task: &SendActivityTask,
+    attempts: &DeliveryAttempts,
+    err: &Error,
+) -> Option<Duration> {
+    let max_attempts = match task.retry_policy {
+        RetryPolicy::Full => 4,
+        RetryPolicy::FastOnly => 2,
+        RetryPolicy::None => 1,
+    };
+    if !err.is_retryable() || attempts.count >= max_attempts {
+        return None;
+    }
+    let mut delay = Duration::from_secs(backoff.pow(attempts.count as u32) as u64);
+    // Wait at least as long as the inbox asked for with `Retry-After`, or until the circuit
+    // breaker of the host is closed again
+    if let Error::RateLimited(_, Some(retry_after)) | Error::HostUnavailable(_, retry_after) = err {
+        delay = delay.max((*retry_after).min(MAX_RETRY_AFTER));
+    }
+    Some(delay)
 }
 
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
     use super::*;
-    use crate::http_signatures::generate_actor_keypair;
+    use crate::{
+        activity_sending::tests::{test_task, BlockingVerifier},
+        fetch::object_id::ObjectId,
+        traits::tests::{DbConnection, Follow, DB_USER},
+    };
     use axum::extract::State;
-    use bytes::Bytes;
     use http::{HeaderMap, StatusCode};
+    use rand::Rng;
     use std::time::Instant;
-    use tracing::debug;
 
-    // This will periodically send back internal errors to test the retry
-    async fn dodgy_handler(
-        State(state): State<Arc<AtomicUsize>>,
-        headers: HeaderMap,
-        body: Bytes,
-    ) -> Result<(), StatusCode> {
-        debug!("Headers:{:?}", headers);
-        debug!("Body len:{}", body.len());
+    // This will periodically send back internal errors to test the retry
+    async fn dodgy_handler(
+        State(state): State<Arc<AtomicUsize>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<(), StatusCode> {
+        debug!("Headers:{:?}", headers);
+        debug!("Body len:{}", body.len());
+
+        if state.fetch_add(1, Ordering::Relaxed) % 20 == 0 {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Ok(())
+    }
+
+    async fn test_server() {
+        use axum::{routing::post, Router};
+
+        // We should break every now and then ;)
+        let state = Arc::new(AtomicUsize::new(0));
+
+        let app = Router::new()
+            .route("/", post(dodgy_handler))
+            .with_state(state);
+
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:8002").await.unwrap();
+        axum::serve(listener, app.into_make_service())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    // Queues 100 messages and then asserts that the worker runs them
+    async fn test_activity_queue_workers() {
+        let num_workers = 64;
+        let num_messages: usize = 100;
+
+        tokio::spawn(test_server());
+
+        /*
+        // uncomment for debug logs & stats
+        use tracing::log::LevelFilter;
+
+        env_logger::builder()
+            .filter_level(LevelFilter::Warn)
+            .filter_module("activitypub_federation", LevelFilter::Info)
+            .format_timestamp(None)
+            .init();
+
+        */
+
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            num_workers,
+            num_workers,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            Default::default(),
+        );
+
+        let inbox: Url = "http://localhost:8002".parse().unwrap();
+        let message = test_task(&inbox);
+
+        let start = Instant::now();
+
+        for _ in 0..num_messages {
+            activity_queue.queue(message.clone()).await.unwrap();
+        }
+
+        info!("Queue Sent: {:?}", start.elapsed());
+
+        let stats = activity_queue.shutdown(true).await.unwrap();
+
+        info!(
+            "Queue Finished.  Num msgs: {}, Time {:?}, msg/s: {:0.0}",
+            num_messages,
+            start.elapsed(),
+            num_messages as f64 / start.elapsed().as_secs_f64()
+        );
+
+        assert_eq!(
+            stats.completed_last_hour.load(Ordering::Relaxed),
+            num_messages
+        );
+    }
+
+    async fn always_failing_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        state.fetch_add(1, Ordering::Relaxed);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    /// Starts a server which rejects all requests, returns its url and the number of requests
+    async fn failing_server() -> (Url, Arc<AtomicUsize>) {
+        use axum::{routing::post, Router};
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/", post(always_failing_handler))
+            .with_state(attempts.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url.parse().unwrap(), attempts)
+    }
+
+    async fn ok_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        state.fetch_add(1, Ordering::Relaxed);
+        StatusCode::OK
+    }
+
+    /// Starts a server which accepts all requests, returns its url and the number of requests
+    async fn counting_server() -> (Url, Arc<AtomicUsize>) {
+        use axum::{routing::post, Router};
+
+        let deliveries = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/inbox", post(ok_handler))
+            .with_state(deliveries.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://localhost:{}/inbox",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url.parse().unwrap(), deliveries)
+    }
+
+    /// Headers and body of each request which was received
+    type Captured = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    async fn capture_handler(
+        State(state): State<Captured>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        state.lock().unwrap().push((headers, body));
+        StatusCode::OK
+    }
+
+    #[tokio::test]
+    async fn test_forward_activity() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let received: Captured = Default::default();
+        let app = Router::new()
+            .route("/inbox", post(capture_handler))
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url =
+            format!("http://localhost:{}/inbox", listener.local_addr()?.port()).parse()?;
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        // unusual formatting, unknown fields and an embedded signature must be preserved
+        let body = Bytes::from_static(
+            br#"{ "type":"Create",  "id":"https://remote.example/activity/1",
+                "actor":"https://remote.example/u/alice", "object":{"type":"Note","x-unknown":[1, 2.50]},
+                "signature":{"type":"RsaSignature2017","signatureValue":"abc=="} }"#,
+        );
+        let local_inbox: Url = "https://example.com/inbox".parse()?;
+
+        forward_activity(body.clone(), &*DB_USER, vec![inbox, local_inbox], &data).await?;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, received_body) = &received[0];
+        assert_eq!(received_body, &body);
+        let signature = headers.get("signature").unwrap().to_str().unwrap();
+        let key_id = format!("keyId=\"{}#main-key\"", DB_USER.federation_id);
+        assert!(signature.contains(&key_id));
+        Ok(())
+    }
+
+    async fn deferred_send(inbox: Url) -> DeferredSend<DbConnection> {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob").unwrap(),
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse().unwrap(),
+        };
+        queue_activity_deferred(&activity, &*DB_USER, vec![inbox], &data, Default::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deferred_send_dropped() {
+        let (inbox, deliveries) = counting_server().await;
+        let deferred = deferred_send(inbox.clone()).await;
+        assert_eq!(deferred.inbox_count(), 1);
+        drop(deferred);
+        deferred_send(inbox).await.abort();
+        assert_eq!(deliveries.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_deferred_send_commit() -> Result<(), Error> {
+        let (inbox, deliveries) = counting_server().await;
+        let mut deferred = deferred_send(inbox).await;
+        deferred.commit().await?;
+        assert_eq!(deliveries.load(Ordering::Relaxed), 1);
+
+        // Committing again doesn't send the activity a second time
+        deferred.commit().await?;
+        assert_eq!(deliveries.load(Ordering::Relaxed), 1);
+        assert_eq!(deferred.inbox_count(), 0);
+        Ok(())
+    }
+
+    /// Backend which fails to store tasks once `pushes_left` is zero
+    #[derive(Default)]
+    struct FailingBackend {
+        inner: MemoryQueueBackend,
+        pushes_left: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl QueueBackend for FailingBackend {
+        async fn push(&self, task: SendActivityTask) -> Result<(), Error> {
+            self.pushes_left
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                    left.checked_sub(1)
+                })
+                .map_err(|_| Error::ActivityQueueError(task.activity_id.clone()))?;
+            self.inner.push(task).await
+        }
+
+        async fn pop(&self) -> Result<Option<SendActivityTask>, Error> {
+            self.inner.pop().await
+        }
+
+        async fn ack(&self, task: &SendActivityTask) -> Result<(), Error> {
+            self.inner.ack(task).await
+        }
+
+        async fn len(&self) -> Result<usize, Error> {
+            self.inner.len().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deferred_send_commit_failure() -> Result<(), Error> {
+        let backend = Arc::new(FailingBackend {
+            pushes_left: AtomicUsize::new(1),
+            ..Default::default()
+        });
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .debug(true)
+            .queue_backend(backend.clone())
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob").unwrap(),
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse().unwrap(),
+        };
+        let inboxes = ["a", "b", "c"]
+            .map(|name| {
+                format!("http://localhost:8001/{name}/inbox")
+                    .parse()
+                    .unwrap()
+            })
+            .into();
+        // Scheduled sends use the backend also in debug mode
+        let options = SendOptions {
+            deliver_after: Some(Utc::now() + chrono::Duration::seconds(60)),
+            ..Default::default()
+        };
+        let mut deferred =
+            queue_activity_deferred(&activity, &*DB_USER, inboxes, &data, options).await?;
+
+        // Only the first inbox is queued, the others are kept for the next commit
+        assert!(deferred.commit().await.is_err());
+        assert_eq!(deferred.inbox_count(), 2);
+
+        backend.pushes_left.store(usize::MAX, Ordering::Relaxed);
+        let handle = deferred.commit().await?;
+        assert_eq!(deferred.inbox_count(), 0);
+        assert_eq!(backend.len().await?, 3);
+        assert_eq!(handle.cancel(), 3);
+        Ok(())
+    }
+
+    /// Backend which stores tasks as json in `stored`, which outlives the backend like a database
+    struct JsonBackend {
+        stored: Arc<Mutex<Vec<String>>>,
+        popped: Mutex<Vec<String>>,
+    }
+
+    impl JsonBackend {
+        fn new(stored: Arc<Mutex<Vec<String>>>) -> Self {
+            JsonBackend {
+                stored,
+                popped: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl QueueBackend for JsonBackend {
+        async fn push(&self, task: SendActivityTask) -> Result<(), Error> {
+            let json = serde_json::to_string(&task).unwrap();
+            self.stored.lock().unwrap().push(json);
+            Ok(())
+        }
+
+        async fn pop(&self) -> Result<Option<SendActivityTask>, Error> {
+            let stored = self.stored.lock().unwrap();
+            let mut popped = self.popped.lock().unwrap();
+            let Some(json) = stored.iter().find(|json| !popped.contains(json)) else {
+                return Ok(None);
+            };
+            popped.push(json.clone());
+            Ok(Some(serde_json::from_str(json).unwrap()))
+        }
+
+        async fn ack(&self, task: &SendActivityTask) -> Result<(), Error> {
+            let json = serde_json::to_string(task).unwrap();
+            self.stored.lock().unwrap().retain(|j| j != &json);
+            self.popped.lock().unwrap().retain(|j| j != &json);
+            Ok(())
+        }
+
+        async fn len(&self) -> Result<usize, Error> {
+            Ok(self.stored.lock().unwrap().len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_backend_restart() -> Result<(), Error> {
+        let (inbox, deliveries) = counting_server().await;
+        let task = test_task(&inbox);
+
+        // Task which was queued before the restart, but not sent yet
+        let stored: Arc<Mutex<Vec<String>>> = Default::default();
+        JsonBackend::new(stored.clone()).push(task).await?;
+        assert_eq!(stored.lock().unwrap().len(), 1);
+
+        let store = TaskStore::new(
+            Arc::new(JsonBackend::new(stored.clone())),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Arc::new(NoMetrics),
+            Default::default(),
+        );
+        let activity_queue = create_activity_queue(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            PoolMode::Separate,
+            store,
+        )
+        .await;
+        let stats = activity_queue.shutdown(true).await?;
+
+        assert_eq!(deliveries.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.completed_last_hour.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 0);
+        assert!(stored.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_queue_stats() -> Result<(), Error> {
+        let (inbox, delivered) = start_server(healthy_handler).await;
+        let config = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(config.queue_stats(), ActivityQueueStats::default());
+
+        let tasks = (0..3).map(|i| SendActivityTask {
+            activity_id: inbox.join(&format!("activity/{i}")).unwrap(),
+            ..test_task(&inbox)
+        });
+        send_tasks(tasks, &config).await?;
+
+        // Wait until the queue is idle before looking at the counters
+        while delivered.load(Ordering::Relaxed) < 3 || config.queue_len() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(config.queue_len(), 0);
+        let stats = config.queue_stats();
+        assert_eq!(stats.completed_last_hour, 3);
+        assert_eq!(stats.dead_last_hour, 0);
+        assert!(stats.is_empty());
+        Ok(())
+    }
+
+    async fn slow_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        state.fetch_add(1, Ordering::Relaxed);
+        StatusCode::OK
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown() -> Result<(), Error> {
+        let (inbox, delivered) = start_server(slow_handler).await;
+        let config = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .build()
+            .await
+            .unwrap();
+        let task = |i: usize| SendActivityTask {
+            activity_id: inbox.join(&format!("activity/{i}")).unwrap(),
+            ..test_task(&inbox)
+        };
+        send_tasks((0..5).map(task), &config).await?;
+        assert_eq!(delivered.load(Ordering::Relaxed), 0);
+
+        // Shutdown through a clone waits for all sends to finish
+        let stats = config
+            .clone()
+            .shutdown(false, Duration::from_secs(10))
+            .await?;
+        assert_eq!(delivered.load(Ordering::Relaxed), 5);
+        assert_eq!(stats.completed_last_hour, 5);
+        assert!(stats.is_empty());
+
+        // No new sends are accepted, and shutting down again only returns the stats
+        let res = send_tasks([task(5)], &config).await;
+        assert!(matches!(res, Err(Error::ActivityQueueError(_))));
+        let stats = config.shutdown(true, Duration::from_secs(10)).await?;
+        assert_eq!(stats.completed_last_hour, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_before_start() -> Result<(), Error> {
+        let (inbox, delivered) = counting_server().await;
+        let config = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .build_lazy()
+            .unwrap();
+        let stats = config
+            .clone()
+            .shutdown(false, Duration::from_secs(10))
+            .await?;
+        assert!(stats.is_empty());
+
+        // Sending doesn't start the queue anymore
+        let res = send_tasks([test_task(&inbox)], &config).await;
+        assert!(matches!(res, Err(Error::ActivityQueueError(_))));
+        config.start_queue().await;
+        assert!(config.activity_queue.get().is_none());
+        assert_eq!(delivered.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_lazy_outside_runtime() -> Result<(), Error> {
+        let build = || {
+            FederationConfig::builder()
+                .app_data(DbConnection)
+                .domain("example.com")
+                .build_lazy()
+                .unwrap()
+        };
+        // Dropping a config whose queue was never started is fine
+        drop(build());
+
+        let config = build();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            assert!(config.activity_queue.get().is_none());
+            let (inbox, delivered) = start_server(healthy_handler).await;
+            let task = SendActivityTask {
+                activity_id: inbox.join("activity/1").unwrap(),
+                ..test_task(&inbox)
+            };
+            send_tasks([task], &config).await?;
+            assert!(config.activity_queue.get().is_some());
+
+            while delivered.load(Ordering::Relaxed) < 1 || config.queue_len() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(config.queue_stats().completed_last_hour, 1);
+            Ok(())
+        })
+    }
+
+    async fn scheduled_send(
+        inbox: Url,
+        delay: chrono::Duration,
+    ) -> (ScheduledSendHandle, Data<DbConnection>) {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob").unwrap(),
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse().unwrap(),
+        };
+        let options = SendOptions {
+            deliver_after: Some(Utc::now() + delay),
+            ..Default::default()
+        };
+        let handle = queue_activity_with_options(&activity, &*DB_USER, vec![inbox], &data, options)
+            .await
+            .unwrap();
+        (handle, data)
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_send() {
+        let (inbox, deliveries) = counting_server().await;
+        let start = Instant::now();
+        let (handle, data) = scheduled_send(inbox, chrono::Duration::seconds(2)).await;
+        assert_eq!(handle.scheduled_count(), 1);
+        assert_eq!(data.queue_stats().scheduled, 1);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(deliveries.load(Ordering::Relaxed), 0);
+
+        while deliveries.load(Ordering::Relaxed) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(1900));
+        assert_eq!(data.queue_stats().scheduled, 0);
+        // Too late to cancel
+        assert_eq!(handle.cancel(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_send_cancel() {
+        let (inbox, deliveries) = counting_server().await;
+        let (handle, data) = scheduled_send(inbox, chrono::Duration::seconds(1)).await;
+        assert_eq!(handle.cancel(), 1);
+        assert_eq!(handle.scheduled_count(), 0);
+        assert_eq!(data.queue_stats().scheduled, 0);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(deliveries.load(Ordering::Relaxed), 0);
+        assert_eq!(handle.cancel(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_send_restart() -> Result<(), Error> {
+        let (inbox, deliveries) = counting_server().await;
+        let stored: Arc<Mutex<Vec<String>>> = Default::default();
+        let start_queue = || {
+            let store = TaskStore::new(
+                Arc::new(JsonBackend::new(stored.clone())),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                Arc::new(NoMetrics),
+                Default::default(),
+            );
+            create_activity_queue(
+                reqwest::Client::default().into(),
+                1,
+                1,
+                Duration::from_secs(10),
+                PoolMode::Separate,
+                store,
+            )
+        };
+
+        // The scheduled task is stored right away, and left in the backend on shutdown
+        let activity_queue = start_queue().await;
+        let deliver_after = Utc::now() + chrono::Duration::seconds(1);
+        activity_queue
+            .schedule(test_task(&inbox), deliver_after)
+            .await?;
+        assert_eq!(activity_queue.stats_snapshot().scheduled, 1);
+        assert_eq!(activity_queue.stats_snapshot().pending, 0);
+        let stats = activity_queue.shutdown(false).await?;
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 0);
+        assert_eq!(stored.lock().unwrap().len(), 1);
+
+        // After the restart it is sent once it is due
+        let activity_queue = start_queue().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(activity_queue.stats_snapshot().scheduled, 1);
+        assert_eq!(deliveries.load(Ordering::Relaxed), 0);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let stats = activity_queue.shutdown(true).await?;
+        assert_eq!(deliveries.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.pending.load(Ordering::Relaxed), 0);
+        assert!(stored.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    /// Number of requests which are handled right now, and the maximum of that
+    #[derive(Default)]
+    struct Concurrency {
+        current: AtomicUsize,
+        max: AtomicUsize,
+        total: AtomicUsize,
+    }
+
+    async fn concurrency_handler(State(state): State<Arc<Concurrency>>) -> StatusCode {
+        let current = state.current.fetch_add(1, Ordering::SeqCst) + 1;
+        state.max.fetch_max(current, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state.current.fetch_sub(1, Ordering::SeqCst);
+        state.total.fetch_add(1, Ordering::SeqCst);
+        StatusCode::OK
+    }
+
+    /// Sends `count` activities to a single host with the given limiter, and returns the
+    /// concurrency of requests on the host
+    async fn send_with_host_limiter(count: usize, host_limiter: HostLimiter) -> Arc<Concurrency> {
+        use axum::{routing::post, Router};
+
+        let concurrency: Arc<Concurrency> = Default::default();
+        let app = Router::new()
+            .route("/", post(concurrency_handler))
+            .with_state(concurrency.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let inbox: Url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // Tasks get the host limiter of the store when they are queued
+        let host_limiter = Arc::new(host_limiter);
+        let store = TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            host_limiter.clone(),
+            Default::default(),
+            Arc::new(NoMetrics),
+            Default::default(),
+        );
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            0,
+            0,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store,
+        );
+        for i in 0..count {
+            let task = SendActivityTask {
+                activity_id: inbox.join(&format!("activity/{i}")).unwrap(),
+                retry_policy: RetryPolicy::None,
+                ..test_task(&inbox)
+            };
+            activity_queue.queue(task).await.unwrap();
+        }
+        activity_queue.shutdown(true).await.unwrap();
+        while concurrency.total.load(Ordering::SeqCst) < count {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        concurrency
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_concurrent_sends_per_host() {
+        let concurrency =
+            send_with_host_limiter(20, HostLimiter::new(2, Duration::ZERO, None)).await;
+        assert_eq!(concurrency.max.load(Ordering::SeqCst), 2);
+
+        let concurrency = send_with_host_limiter(20, HostLimiter::default()).await;
+        assert!(concurrency.max.load(Ordering::SeqCst) > 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_min_send_interval_per_host() {
+        let start = Instant::now();
+        let limiter = HostLimiter::new(0, Duration::from_millis(100), None);
+        let concurrency = send_with_host_limiter(5, limiter).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(concurrency.max.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_busy_host_does_not_block_workers() -> Result<(), Error> {
+        let (busy, busy_delivered) = start_server(slow_handler).await;
+        let (healthy, delivered) = start_server(ok_handler).await;
+        let store = TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            Arc::new(HostLimiter::new(1, Duration::ZERO, None)),
+            Default::default(),
+            Arc::new(NoMetrics),
+            Default::default(),
+        );
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            2,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store,
+        );
+        for i in 0..3 {
+            let task = SendActivityTask {
+                activity_id: busy.join(&format!("activity/{i}"))?,
+                ..test_task(&busy)
+            };
+            activity_queue.queue(task).await?;
+        }
+        activity_queue.queue(test_task(&healthy)).await?;
 
-        if state.fetch_add(1, Ordering::Relaxed) % 20 == 0 {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        // Tasks waiting for the busy host don't take the second worker slot
+        while delivered.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(busy_delivered.load(Ordering::Relaxed), 0);
+
+        let stats = activity_queue.shutdown(true).await?;
+        assert_eq!(busy_delivered.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.completed_last_hour.load(Ordering::Relaxed), 4);
         Ok(())
     }
 
-    async fn test_server() {
-        use axum::{routing::post, Router};
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_paused_host_does_not_block_worker() -> Result<(), Error> {
+        let (paused, paused_requests) = start_server(ok_handler).await;
+        let (healthy, delivered) = start_server(ok_handler).await;
+        // Same as after a response with `Retry-After`
+        let host_limiter = Arc::new(HostLimiter::default());
+        host_limiter.pause(&paused, Duration::from_secs(2)).await;
+        let store = TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            host_limiter,
+            Default::default(),
+            Arc::new(NoMetrics),
+            Default::default(),
+        );
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store,
+        );
+        activity_queue.queue(test_task(&paused)).await?;
+        activity_queue.queue(test_task(&healthy)).await?;
 
-        // We should break every now and then ;)
-        let state = Arc::new(AtomicUsize::new(0));
+        // The only worker slot is used for the other host in the meantime
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while delivered.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(paused_requests.load(Ordering::Relaxed), 0);
+
+        // Waiting for the host is not counted as a failed attempt
+        let stats = activity_queue.shutdown(true).await?;
+        assert_eq!(paused_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.completed_last_hour.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.dead_last_hour.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_delay_waits_for_retry_after() {
+        let inbox: Url = "http://example.com/inbox".parse().unwrap();
+        let task = test_task(&inbox);
+        let attempts = |count| DeliveryAttempts {
+            count,
+            ..Default::default()
+        };
+        let failed = Error::DeliveryFailed {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            inbox: inbox.clone(),
+            body: String::new(),
+        };
+        let rate_limited = |retry_after| Error::RateLimited(inbox.clone(), Some(retry_after));
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+
+        assert_eq!(
+            retry_delay(60, &task, &attempts(1), &failed),
+            Some(minutes(1))
+        );
+        assert_eq!(
+            retry_delay(60, &task, &attempts(2), &failed),
+            Some(minutes(60))
+        );
+        assert_eq!(retry_delay(60, &task, &attempts(4), &failed), None);
+        assert_eq!(
+            retry_delay(60, &task, &attempts(1), &rate_limited(minutes(10))),
+            Some(minutes(10))
+        );
+        // The backoff is used if it is longer, and the wait is limited
+        assert_eq!(
+            retry_delay(60, &task, &attempts(2), &rate_limited(minutes(10))),
+            Some(minutes(60))
+        );
+        assert_eq!(
+            retry_delay(1, &task, &attempts(1), &rate_limited(minutes(600))),
+            Some(MAX_RETRY_AFTER)
+        );
+        // Tasks for a host which is skipped by the circuit breaker wait in the retry queue
+        let unavailable = Error::HostUnavailable(inbox.clone(), minutes(10));
+        assert_eq!(
+            retry_delay(1, &task, &attempts(1), &unavailable),
+            Some(minutes(10))
+        );
+
+        let fast_only = SendActivityTask {
+            retry_policy: RetryPolicy::FastOnly,
+            ..test_task(&inbox)
+        };
+        assert_eq!(
+            retry_delay(60, &fast_only, &attempts(1), &failed),
+            Some(minutes(1))
+        );
+        assert_eq!(retry_delay(60, &fast_only, &attempts(2), &failed), None);
+        let rejected = Error::DeliveryRejected {
+            status: StatusCode::FORBIDDEN,
+            inbox: inbox.clone(),
+        };
+        assert_eq!(retry_delay(60, &task, &attempts(1), &rejected), None);
+    }
+
+    async fn send_to_failing_server(policy: RetryPolicy) -> (Arc<Stats>, usize) {
+        let (inbox, attempts) = failing_server().await;
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            Default::default(),
+        );
+        let message = SendActivityTask {
+            retry_policy: policy,
+            ..test_task(&inbox)
+        };
+        activity_queue.queue(message).await.unwrap();
+        let stats = activity_queue.shutdown(true).await.unwrap();
+        (stats, attempts.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_retry_policy_none() {
+        let (stats, attempts) = send_to_failing_server(RetryPolicy::None).await;
+        assert_eq!(attempts, 1);
+        assert_eq!(stats.retries.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.dead_last_hour.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.no_retry.dead.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.full.dead.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_retry_policy_fast_only() {
+        let (stats, attempts) = send_to_failing_server(RetryPolicy::FastOnly).await;
+        assert_eq!(attempts, 2);
+        assert_eq!(stats.dead_last_hour.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.fast_only.dead.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_retry_policy_full() {
+        let (stats, attempts) = send_to_failing_server(RetryPolicy::Full).await;
+        // The first attempt in the worker, then three more in the retry worker
+        assert_eq!(attempts, 4);
+        assert_eq!(stats.retries.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.dead_last_hour.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.full.dead.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delivery_callbacks() -> Result<(), Error> {
+        // Nothing listens on the port once the listener is dropped, so connections are refused
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let refused_inbox = Url::parse(&format!("http://localhost:{port}/inbox"))?;
+        let (healthy_inbox, _) = counting_server().await;
+
+        let failures = Arc::new(Mutex::new(vec![]));
+        let successes = Arc::new(Mutex::new(vec![]));
+        let callbacks = DeliveryCallbacks {
+            on_failure: Some({
+                let failures = failures.clone();
+                Arc::new(move |failure| failures.lock().unwrap().push(failure))
+            }),
+            on_success: Some({
+                let successes = successes.clone();
+                Arc::new(move |success| successes.lock().unwrap().push(success))
+            }),
+        };
+        let store = TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Arc::new(NoMetrics),
+            callbacks,
+        );
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store,
+        );
+        let task = |inbox: &Url| SendActivityTask {
+            retry_policy: RetryPolicy::None,
+            ..test_task(inbox)
+        };
+        activity_queue.queue(task(&refused_inbox)).await?;
+        activity_queue.queue(task(&healthy_inbox)).await?;
+        activity_queue.shutdown(true).await?;
+
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        let failure = &failures[0];
+        assert_eq!(failure.inbox, refused_inbox);
+        assert_eq!(failure.activity_id, refused_inbox.join("activity")?);
+        assert_eq!(failure.attempts, 1);
+        assert_eq!(failure.last_status, None);
+        assert!(!failure.last_error.is_empty());
+        assert!(failure.first_attempt <= failure.last_attempt);
+
+        let successes = successes.lock().unwrap();
+        assert_eq!(successes.len(), 1);
+        assert_eq!(successes[0].inbox, healthy_inbox);
+        assert_eq!(successes[0].attempts, 1);
+        Ok(())
+    }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delivery_failure_status() {
+        let (inbox, _) = failing_server().await;
+        let failures = Arc::new(Mutex::new(vec![]));
+        let store = TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Arc::new(NoMetrics),
+            DeliveryCallbacks {
+                on_failure: Some({
+                    let failures = failures.clone();
+                    Arc::new(move |failure| failures.lock().unwrap().push(failure))
+                }),
+                on_success: None,
+            },
+        );
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store,
+        );
+        let message = test_task(&inbox);
+        activity_queue.queue(message).await.unwrap();
+        activity_queue.shutdown(true).await.unwrap();
+
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        // Attempts of the worker and the retry worker are counted together
+        assert_eq!(failures[0].attempts, 4);
+        assert_eq!(
+            failures[0].last_status,
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+    }
+
+    async fn forbidden_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        state.fetch_add(1, Ordering::Relaxed);
+        StatusCode::FORBIDDEN
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delivery_rejected_not_retried() -> Result<(), Error> {
+        use axum::{routing::post, Router};
+
+        let requests = Arc::new(AtomicUsize::new(0));
         let app = Router::new()
-            .route("/", post(dodgy_handler))
-            .with_state(state);
+            .route("/", post(forbidden_handler))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url = format!("http://localhost:{}/", listener.local_addr()?.port()).parse()?;
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let failures = Arc::new(Mutex::new(vec![]));
+        let store = TaskStore::new(
+            Arc::new(MemoryQueueBackend::default()),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Arc::new(NoMetrics),
+            DeliveryCallbacks {
+                on_failure: Some({
+                    let failures = failures.clone();
+                    Arc::new(move |failure| failures.lock().unwrap().push(failure))
+                }),
+                on_success: None,
+            },
+        );
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store,
+        );
+        let message = test_task(&inbox);
+        activity_queue.queue(message).await?;
+        let stats = activity_queue.shutdown(true).await?;
+
+        // Sending again wouldn't change the outcome, so there is only a single attempt
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.dead_last_hour.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.retries.load(Ordering::Relaxed), 0);
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].attempts, 1);
+        assert_eq!(failures[0].last_status, Some(StatusCode::FORBIDDEN));
+        Ok(())
+    }
 
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:8002").await.unwrap();
-        axum::serve(listener, app.into_make_service())
+    #[tokio::test]
+    async fn test_send_activity_with_report() -> anyhow::Result<()> {
+        let (delivered, _) = start_server(ok_handler).await;
+        let (rejected, _) = start_server(forbidden_handler).await;
+        let (failed, _) = start_server(always_failing_handler).await;
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .url_verifier(Box::new(BlockingVerifier))
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob")?,
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse()?,
+        };
+        let local: Url = "https://example.com/inbox".parse()?;
+        let blocked: Url = "http://blocked.com/inbox".parse()?;
+        let inboxes = vec![
+            delivered.clone(),
+            local.clone(),
+            rejected.clone(),
+            blocked.clone(),
+            failed.clone(),
+            delivered.clone(),
+        ];
+
+        let report = send_activity_with_report(&activity, &*DB_USER, inboxes, &data).await?;
+        assert_eq!(report.activity_id, activity.id);
+        let inboxes: Vec<_> = report.inboxes.iter().map(|(inbox, _)| inbox).collect();
+        assert_eq!(
+            inboxes,
+            vec![&delivered, &local, &rejected, &blocked, &failed]
+        );
+        assert!(matches!(
+            report.status(&delivered),
+            Some(DeliveryStatus::Delivered)
+        ));
+        assert!(matches!(report.status(&local), Some(DeliveryStatus::Local)));
+        assert!(matches!(
+            report.status(&rejected),
+            Some(DeliveryStatus::Rejected {
+                status: StatusCode::FORBIDDEN,
+                ..
+            })
+        ));
+        let Some(DeliveryStatus::Skipped(reason)) = report.status(&blocked) else {
+            panic!("expected skipped inbox");
+        };
+        assert_eq!(reason.to_string(), "domain is blocked");
+        assert!(matches!(
+            report.status(&failed),
+            Some(DeliveryStatus::Failed {
+                status: Some(StatusCode::INTERNAL_SERVER_ERROR),
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch_queued_task() -> Result<(), Error> {
+        let (delivered, _) = start_server(ok_handler).await;
+        let (rejected, _) = start_server(forbidden_handler).await;
+        let store = TaskStore::default();
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Separate,
+            store.clone(),
+        );
+        let mut receivers = vec![];
+        for inbox in [&delivered, &rejected] {
+            let task = test_task(inbox);
+            receivers.push(store.watch(&task));
+            activity_queue.queue(task).await?;
+        }
+        let delivered = tokio::time::timeout(Duration::from_secs(10), receivers.remove(0))
+            .await
+            .unwrap();
+        assert!(matches!(delivered, Ok(DeliveryStatus::Delivered)));
+        let rejected = tokio::time::timeout(Duration::from_secs(10), receivers.remove(0))
             .await
             .unwrap();
+        assert!(matches!(
+            rejected,
+            Ok(DeliveryStatus::Rejected {
+                status: StatusCode::FORBIDDEN,
+                ..
+            })
+        ));
+        assert!(store.watchers.lock().unwrap().is_empty());
+        activity_queue.shutdown(true).await?;
+        Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    // Queues 100 messages and then asserts that the worker runs them
-    async fn test_activity_queue_workers() {
-        let num_workers = 64;
-        let num_messages: usize = 100;
+    async fn test_watch_closed_on_shutdown() -> Result<(), Error> {
+        let (failed, requests) = start_server(always_failing_handler).await;
+        let store = TaskStore::default();
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            1,
+            Duration::from_secs(10),
+            10,
+            PoolMode::Separate,
+            store.clone(),
+        );
+        let task = test_task(&failed);
+        let receiver = store.watch(&task);
+        activity_queue.queue(task).await?;
+        while requests.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
 
-        tokio::spawn(test_server());
+        // The task waits for its retry, and the receiver is closed on shutdown
+        activity_queue.shutdown(false).await?;
+        let status = tokio::time::timeout(Duration::from_secs(1), receiver)
+            .await
+            .unwrap();
+        assert!(status.is_err());
+        assert!(store.watchers.lock().unwrap().is_empty());
+        Ok(())
+    }
 
-        /*
-        // uncomment for debug logs & stats
-        use tracing::log::LevelFilter;
+    async fn slow_failing_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        state.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
 
-        env_logger::builder()
-            .filter_level(LevelFilter::Warn)
-            .filter_module("activitypub_federation", LevelFilter::Info)
-            .format_timestamp(None)
-            .init();
+    async fn healthy_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state.fetch_add(1, Ordering::Relaxed);
+        StatusCode::OK
+    }
 
-        */
+    /// Starts a server with the given handler, returns its url and the number of requests
+    async fn start_server<H, T>(handler: H) -> (Url, Arc<AtomicUsize>)
+    where
+        H: axum::handler::Handler<T, Arc<AtomicUsize>>,
+        T: 'static,
+    {
+        use axum::{routing::post, Router};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/", post(handler))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url.parse().unwrap(), requests)
+    }
+
+    type OrderedState = (Arc<Mutex<Vec<String>>>, Arc<Concurrency>);
+
+    /// Stores the received bodies of one host, and counts the concurrency across all hosts
+    async fn ordered_handler(
+        State((received, all)): State<OrderedState>,
+        body: Bytes,
+    ) -> StatusCode {
+        received
+            .lock()
+            .unwrap()
+            .push(String::from_utf8_lossy(&body).into_owned());
+        concurrency_handler(State(all)).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_group_sends_by_host() {
+        use axum::{routing::post, Router};
+
+        let all: Arc<Concurrency> = Default::default();
+        let mut hosts = vec![];
+        for _ in 0..2 {
+            let received: Arc<Mutex<Vec<String>>> = Default::default();
+            let app = Router::new()
+                .route("/", post(ordered_handler))
+                .with_state((received.clone(), all.clone()));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let inbox: Url = format!(
+                "http://localhost:{}/",
+                listener.local_addr().unwrap().port()
+            )
+            .parse()
+            .unwrap();
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            hosts.push((inbox, received));
+        }
 
         let activity_queue = ActivityQueue::new(
             reqwest::Client::default().into(),
-            num_workers,
-            num_workers,
+            0,
+            0,
             Duration::from_secs(10),
             1,
+            PoolMode::PerHost,
+            Default::default(),
         );
+        for i in 0..5 {
+            for (inbox, _) in &hosts {
+                let task = SendActivityTask {
+                    activity_id: inbox.join(&format!("activity/{i}")).unwrap(),
+                    activity: i.to_string().into(),
+                    retry_policy: RetryPolicy::None,
+                    ..test_task(inbox)
+                };
+                activity_queue.queue(task).await.unwrap();
+            }
+        }
+        activity_queue.shutdown(true).await.unwrap();
 
-        let keypair = generate_actor_keypair().unwrap();
+        let expected: Vec<_> = (0..5).map(|i| i.to_string()).collect();
+        for (_, received) in &hosts {
+            assert_eq!(*received.lock().unwrap(), expected);
+        }
+        // One send at a time per host, but both hosts in parallel
+        assert_eq!(all.max.load(Ordering::SeqCst), 2);
+        assert_eq!(all.total.load(Ordering::SeqCst), 10);
+    }
 
-        let message = SendActivityTask {
-            actor_id: "http://localhost:8002".parse().unwrap(),
-            activity_id: "http://localhost:8002/activity".parse().unwrap(),
-            activity: "{}".into(),
-            inbox: "http://localhost:8002".parse().unwrap(),
-            private_key: keypair.private_key().unwrap(),
-            http_signature_compat: true,
+    /// Stores the received bodies, responds after a random delay and fails the first request
+    async fn random_delay_handler(
+        State(received): State<Arc<Mutex<Vec<String>>>>,
+        body: Bytes,
+    ) -> StatusCode {
+        let first = {
+            let mut received = received.lock().unwrap();
+            received.push(String::from_utf8_lossy(&body).into_owned());
+            received.len() == 1
         };
+        let delay = rand::thread_rng().gen_range(0..30);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        if first {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::OK
+        }
+    }
 
-        let start = Instant::now();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ordered_delivery() {
+        use axum::{routing::post, Router};
 
-        for _ in 0..num_messages {
-            activity_queue.queue(message.clone()).await.unwrap();
+        let received: Arc<Mutex<Vec<String>>> = Default::default();
+        let app = Router::new()
+            .route("/", post(random_delay_handler))
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let inbox: Url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            0,
+            0,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Ordered { limit: 0 },
+            Default::default(),
+        );
+        for i in 0..10 {
+            let task = SendActivityTask {
+                activity_id: inbox.join(&format!("activity/{i}")).unwrap(),
+                activity: i.to_string().into(),
+                ..test_task(&inbox)
+            };
+            activity_queue.queue(task).await.unwrap();
         }
+        let stats = activity_queue.shutdown(true).await.unwrap();
 
-        info!("Queue Sent: {:?}", start.elapsed());
+        // The first activity fails once, and the others wait until it is delivered
+        let mut expected = vec!["0".to_string()];
+        expected.extend((0..10).map(|i| i.to_string()));
+        assert_eq!(*received.lock().unwrap(), expected);
+        assert_eq!(stats.completed_last_hour.load(Ordering::Relaxed), 10);
+    }
 
-        let stats = activity_queue.shutdown(true).await.unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ordered_retry_releases_slot() {
+        use axum::{routing::post, Router};
 
-        info!(
-            "Queue Finished.  Num msgs: {}, Time {:?}, msg/s: {:0.0}",
-            num_messages,
-            start.elapsed(),
-            num_messages as f64 / start.elapsed().as_secs_f64()
+        let received: Arc<Mutex<Vec<String>>> = Default::default();
+        let app = Router::new()
+            .route("/", post(random_delay_handler))
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failing_inbox: Url = format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (healthy_inbox, delivered) = start_server(healthy_handler).await;
+
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            1,
+            0,
+            Duration::from_secs(10),
+            1,
+            PoolMode::Ordered { limit: 0 },
+            Default::default(),
+        );
+        for i in 0..2 {
+            let task = SendActivityTask {
+                activity_id: failing_inbox.join(&format!("activity/{i}")).unwrap(),
+                activity: i.to_string().into(),
+                ..test_task(&failing_inbox)
+            };
+            activity_queue.queue(task).await.unwrap();
+        }
+        activity_queue
+            .queue(test_task(&healthy_inbox))
+            .await
+            .unwrap();
+
+        // The only worker slot is free while the first activity waits for its retry
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(delivered.load(Ordering::Relaxed), 1);
+        assert_eq!(*received.lock().unwrap(), vec!["0".to_string()]);
+
+        activity_queue.shutdown(true).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec!["0", "0", "1"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unified_pool_fresh_sends_not_starved() {
+        let (failing_inbox, _) = start_server(slow_failing_handler).await;
+        let (healthy_inbox, delivered) = start_server(healthy_handler).await;
+        let activity_queue = ActivityQueue::new(
+            reqwest::Client::default().into(),
+            4,
+            0,
+            Duration::from_secs(10),
+            0,
+            PoolMode::Unified { retry_percent: 50 },
+            Default::default(),
         );
 
+        // Each of these takes two attempts of 300ms, so 6s in total with two retry slots
+        let num_retries = 20;
+        for _ in 0..num_retries {
+            activity_queue.stats.retries.fetch_add(1, Ordering::Relaxed);
+            let workers = activity_queue.workers.lock().unwrap();
+            let retry_sender = &workers.as_ref().unwrap().retry_sender;
+            let retry = RetryTask {
+                task: test_task(&failing_inbox),
+                attempts: DeliveryAttempts {
+                    count: 2,
+                    ..Default::default()
+                },
+                delay: Duration::ZERO,
+                done: None,
+            };
+            retry_sender.send(retry).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
         assert_eq!(
-            stats.completed_last_hour.load(Ordering::Relaxed),
-            num_messages
+            activity_queue.stats.running_retries.load(Ordering::Relaxed),
+            2
         );
+
+        let num_fresh = 5;
+        let start = Instant::now();
+        for _ in 0..num_fresh {
+            activity_queue
+                .queue(test_task(&healthy_inbox))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        while delivered.load(Ordering::Relaxed) < num_fresh {
+            assert!(activity_queue.stats.running_retries.load(Ordering::Relaxed) <= 2);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(activity_queue.stats.retries.load(Ordering::Relaxed) > 0);
+
+        let stats = activity_queue.shutdown(true).await.unwrap();
+        assert_eq!(stats.completed_last_hour.load(Ordering::Relaxed), num_fresh);
+        assert_eq!(stats.dead_last_hour.load(Ordering::Relaxed), num_retries);
+        assert_eq!(stats.running.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.running_retries.load(Ordering::Relaxed), 0);
     }
 }
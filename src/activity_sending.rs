@@ -3,42 +3,74 @@
 #![doc = include_str!("../docs/09_sending_activities.md")]
 
 use crate::{
-    config::Data,
+    activity_queue::RetryPolicy,
+    config::{Data, VerifyContext},
     error::Error,
-    http_signatures::sign_request,
+    extract_kind,
+    fetch::nodeinfo::PeerSoftwareCache,
+    http_signatures::{sign_request, SigningLimiter},
+    metrics::{FederationMetricsHook, NoMetrics},
+    protocol::{
+        collection_synchronization::{
+            parse_collection_synchronization,
+            COLLECTION_SYNCHRONIZATION_HEADER,
+        },
+        helpers::strip_private_addressing,
+        ld_signature::create_ld_signature,
+    },
     reqwest_shim::ResponseExt,
     traits::{ActivityHandler, Actor},
     FEDERATION_CONTENT_TYPE,
 };
 use bytes::Bytes;
-use futures::StreamExt;
-use http::StatusCode;
-use httpdate::fmt_http_date;
+use chrono::{DateTime, Utc};
+use http::{header::RETRY_AFTER, StatusCode};
+use httpdate::{fmt_http_date, parse_http_date};
 use itertools::Itertools;
+use moka::future::Cache;
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
     Response,
 };
 use reqwest_middleware::ClientWithMiddleware;
-use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey};
-use serde::Serialize;
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    RsaPrivateKey,
+};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::{
+    collections::HashSet,
     fmt::{Debug, Display},
+    sync::{Arc, Mutex, PoisonError},
     time::{Duration, Instant, SystemTime},
 };
-use tracing::{debug, warn};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info_span, warn, Instrument};
 use url::Url;
 
 #[derive(Clone, Debug)]
 /// All info needed to sign and send one activity to one inbox. You should generally use
 /// [[crate::activity_queue::queue_activity]] unless you want implement your own queue.
 pub struct SendActivityTask {
-    pub(crate) actor_id: Url,
+    pub(crate) key_id: String,
     pub(crate) activity_id: Url,
     pub(crate) activity: Bytes,
     pub(crate) inbox: Url,
     pub(crate) private_key: RsaPrivateKey,
     pub(crate) http_signature_compat: bool,
+    pub(crate) rfc9421_signatures: bool,
+    pub(crate) both_digest_headers: bool,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) signing_limiter: Arc<SigningLimiter>,
+    pub(crate) host_limiter: Arc<HostLimiter>,
+    pub(crate) peer_software: Arc<PeerSoftwareCache>,
+    pub(crate) metrics_hook: Arc<dyn FederationMetricsHook>,
+    /// Value of the `Collection-Synchronization` header, see [Actor::collection_synchronization]
+    pub(crate) collection_synchronization: Option<HeaderValue>,
+    /// Time before which the task is not sent, see
+    /// [SendOptions::deliver_after](crate::activity_queue::SendOptions::deliver_after)
+    pub(crate) deliver_after: Option<DateTime<Utc>>,
 }
 
 impl Display for SendActivityTask {
@@ -47,55 +79,241 @@ impl Display for SendActivityTask {
     }
 }
 
+/// Serialized form of [SendActivityTask], for storage in a
+/// [QueueBackend](crate::activity_queue::QueueBackend).
+#[derive(Serialize, Deserialize)]
+struct SerializedTask {
+    key_id: String,
+    activity_id: Url,
+    activity: String,
+    inbox: Url,
+    /// Private key in PEM format
+    private_key: String,
+    http_signature_compat: bool,
+    #[serde(default)]
+    rfc9421_signatures: bool,
+    #[serde(default)]
+    both_digest_headers: bool,
+    retry_policy: RetryPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    collection_synchronization: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deliver_after: Option<DateTime<Utc>>,
+}
+
+impl Serialize for SendActivityTask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let private_key = self
+            .private_key
+            .to_pkcs8_pem(LineEnding::default())
+            .map_err(ser::Error::custom)?;
+        SerializedTask {
+            key_id: self.key_id.clone(),
+            activity_id: self.activity_id.clone(),
+            activity: String::from_utf8(self.activity.to_vec()).map_err(ser::Error::custom)?,
+            inbox: self.inbox.clone(),
+            private_key: private_key.to_string(),
+            http_signature_compat: self.http_signature_compat,
+            rfc9421_signatures: self.rfc9421_signatures,
+            both_digest_headers: self.both_digest_headers,
+            retry_policy: self.retry_policy,
+            collection_synchronization: self
+                .collection_synchronization
+                .as_ref()
+                .and_then(|header| header.to_str().ok())
+                .map(str::to_string),
+            deliver_after: self.deliver_after,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SendActivityTask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let task = SerializedTask::deserialize(deserializer)?;
+        let private_key =
+            RsaPrivateKey::from_pkcs8_pem(&task.private_key).map_err(de::Error::custom)?;
+        Ok(SendActivityTask {
+            key_id: task.key_id,
+            activity_id: task.activity_id,
+            activity: task.activity.into(),
+            inbox: task.inbox,
+            private_key,
+            http_signature_compat: task.http_signature_compat,
+            rfc9421_signatures: task.rfc9421_signatures,
+            both_digest_headers: task.both_digest_headers,
+            retry_policy: task.retry_policy,
+            signing_limiter: Default::default(),
+            host_limiter: Default::default(),
+            peer_software: Default::default(),
+            metrics_hook: Arc::new(NoMetrics),
+            collection_synchronization: task
+                .collection_synchronization
+                .and_then(|header| HeaderValue::from_str(&header).ok()),
+            deliver_after: task.deliver_after,
+        })
+    }
+}
+
 impl SendActivityTask {
     /// Prepare an activity for sending
     ///
     /// - `activity`: The activity to be sent, gets converted to json
     /// - `inboxes`: List of remote actor inboxes that should receive the activity. Ignores local actor
-    ///              inboxes. Should be built by calling [crate::traits::Actor::shared_inbox_or_inbox]
-    ///              for each target actor.
+    ///   inboxes. Should be built by calling [crate::traits::Actor::shared_inbox_or_inbox]
+    ///   for each target actor.
     pub async fn prepare<Activity, Datatype, ActorType>(
         activity: &Activity,
         actor: &ActorType,
         inboxes: Vec<Url>,
         data: &Data<Datatype>,
-    ) -> Result<Vec<SendActivityTask>, Error>
+    ) -> Result<PreparedTasks, Error>
     where
         Activity: ActivityHandler + Serialize + Debug,
         Datatype: Clone,
-        ActorType: Actor,
+        ActorType: Actor<DataType = Datatype>,
     {
-        build_tasks(activity, actor, inboxes, data).await
+        build_tasks(activity, actor, inboxes, data, None).await
+    }
+
+    /// Id of the activity which is sent
+    pub fn activity_id(&self) -> &Url {
+        &self.activity_id
+    }
+
+    /// Inbox which the activity is sent to
+    pub fn inbox(&self) -> &Url {
+        &self.inbox
+    }
+
+    /// Retry policy which is applied when this task is sent with the activity queue.
+    ///
+    /// Determined by [FederationConfigBuilder::retry_policy](crate::config::FederationConfigBuilder::retry_policy)
+    /// for the activity type, or by [SendOptions::retry_policy](crate::activity_queue::SendOptions::retry_policy).
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
     }
 
     /// convert a sendactivitydata to a request, signing and sending it
     pub async fn sign_and_send<Datatype: Clone>(&self, data: &Data<Datatype>) -> Result<(), Error> {
-        self.sign_and_send_internal(&data.config.client, data.config.request_timeout)
+        self.send_attempt(&data.config.client, data.config.request_timeout, 1, None)
             .await
     }
 
+    /// Signs and sends the task once in a `send_activity` span, and reports the result to the
+    /// metrics hook. `attempt` is the number of this attempt, starting at 1. Without a `permit`
+    /// from [HostLimiter::acquire_permit], this waits until the host limiter allows the send.
+    pub(crate) async fn send_attempt(
+        &self,
+        client: &ClientWithMiddleware,
+        timeout: Duration,
+        attempt: usize,
+        permit: Option<HostPermit>,
+    ) -> Result<(), Error> {
+        let span = info_span!(
+            "send_activity",
+            activity_id = %self.activity_id,
+            inbox = %self.inbox,
+            attempt,
+        );
+        let start = Instant::now();
+        let res = self
+            .sign_and_send_internal(client, timeout, permit)
+            .instrument(span)
+            .await;
+        match &res {
+            Ok(()) => self.metrics_hook.on_send_success(
+                &self.activity_id,
+                &self.inbox,
+                attempt,
+                start.elapsed(),
+            ),
+            Err(err) => {
+                self.metrics_hook
+                    .on_send_failure(&self.activity_id, &self.inbox, attempt, err)
+            }
+        }
+        res
+    }
+
+    /// Signs and sends the task with the given permit of the host limiter, or waits for one if it
+    /// is `None`.
     pub(crate) async fn sign_and_send_internal(
         &self,
         client: &ClientWithMiddleware,
         timeout: Duration,
+        permit: Option<HostPermit>,
+    ) -> Result<(), Error> {
+        let _permit = match permit {
+            Some(permit) => {
+                self.host_limiter.check_circuit(&self.inbox).await?;
+                permit
+            }
+            None => self.host_limiter.acquire(&self.inbox).await?,
+        };
+        let res = self.sign_and_send_to_host(client, timeout).await;
+        self.host_limiter.record_result(&self.inbox, &res).await;
+        res
+    }
+
+    async fn sign_and_send_to_host(
+        &self,
+        client: &ClientWithMiddleware,
+        timeout: Duration,
     ) -> Result<(), Error> {
         debug!("Sending {} to {}", self.activity_id, self.inbox,);
-        let request_builder = client
-            .post(self.inbox.to_string())
-            .timeout(timeout)
-            .headers(generate_request_headers(&self.inbox));
-        let request = sign_request(
-            request_builder,
-            &self.actor_id,
-            self.activity.clone(),
-            self.private_key.clone(),
-            self.http_signature_compat,
-        )
-        .await?;
+        let http_signature_compat = self.http_signature_compat
+            || self
+                .peer_software
+                .quirks(&self.inbox)
+                .await
+                .http_signature_compat;
+        let sign = |http_signature_compat, rfc9421| {
+            let mut request_builder = client
+                .post(self.inbox.to_string())
+                .timeout(timeout)
+                .headers(generate_request_headers(&self.inbox));
+            if let Some(header) = &self.collection_synchronization {
+                request_builder =
+                    request_builder.header(COLLECTION_SYNCHRONIZATION_HEADER, header.clone());
+            }
+            sign_request(
+                request_builder,
+                self.key_id.clone(),
+                self.activity.clone(),
+                self.private_key.clone(),
+                http_signature_compat,
+                rfc9421,
+                self.both_digest_headers,
+                &self.signing_limiter,
+            )
+        };
+        let request = sign(http_signature_compat, self.rfc9421_signatures).await?;
 
         // Send the activity, and log a warning if its too slow.
         let now = Instant::now();
-        let response = client.execute(request).await?;
+        let mut response = client.execute(request).await?;
+        if self.rfc9421_signatures && response.status() == StatusCode::UNAUTHORIZED {
+            debug!("Inbox rejected RFC 9421 signature for {self}, retrying with draft-cavage");
+            let request = sign(http_signature_compat, false).await?;
+            response = client.execute(request).await?;
+        }
+        if !http_signature_compat
+            && self.peer_software.auto_signature_compat()
+            && matches!(
+                response.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::BAD_REQUEST
+            )
+        {
+            debug!("Inbox rejected signature for {self}, retrying with draft 10 signature");
+            let request = sign(true, false).await?;
+            response = client.execute(request).await?;
+            if response.status().is_success() {
+                self.peer_software
+                    .remember_signature_compat(&self.inbox)
+                    .await;
+            }
+        }
         let elapsed = now.elapsed().as_secs();
         if elapsed > 10 {
             warn!(
@@ -103,14 +321,28 @@ impl SendActivityTask {
                 self.activity_id, self.inbox, elapsed
             );
         }
-        self.handle_response(response).await
+        let res = self.handle_response(response).await;
+        if res.is_ok() {
+            self.peer_software.discover(&self.inbox);
+        }
+        if let Err(Error::RateLimited(_, Some(retry_after))) = &res {
+            self.host_limiter.pause(&self.inbox, *retry_after).await;
+        }
+        res
     }
 
     /// Based on the HTTP status code determines if an activity was delivered successfully. In that case
-    /// Ok is returned. Otherwise it returns Err and the activity send should be retried later.
+    /// Ok is returned. Client errors return [Error::DeliveryRejected], which is not retried.
+    /// Otherwise it returns [Error::DeliveryFailed] and the activity send should be retried later.
+    /// For status 429, and for status 503 with `Retry-After` header, [Error::RateLimited] is
+    /// returned with the time to wait.
     ///
     /// Equivalent code in mastodon: https://github.com/mastodon/mastodon/blob/v4.2.8/app/helpers/jsonld_helper.rb#L215-L217
     async fn handle_response(&self, response: Response) -> Result<(), Error> {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(parse_retry_after);
         match response.status() {
             status if status.is_success() => {
                 debug!("Activity {self} delivered successfully");
@@ -123,16 +355,298 @@ impl SendActivityTask {
             {
                 let text = response.text_limited().await?;
                 debug!("Activity {self} was rejected, aborting: {text}");
-                Ok(())
+                Err(Error::DeliveryRejected {
+                    status,
+                    inbox: self.inbox.clone(),
+                })
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                debug!("Activity {self} was rate limited, retry after {retry_after:?}");
+                Err(Error::RateLimited(self.inbox.clone(), retry_after))
+            }
+            StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {
+                debug!("Inbox for activity {self} is unavailable, retry after {retry_after:?}");
+                Err(Error::RateLimited(self.inbox.clone(), retry_after))
             }
             status => {
-                let text = response.text_limited().await?;
+                let body = response.text_limited().await?;
+                Err(Error::DeliveryFailed {
+                    status,
+                    inbox: self.inbox.clone(),
+                    body,
+                })
+            }
+        }
+    }
+}
 
-                Err(Error::Other(format!(
-                    "Activity {self} failure with status {status}: {text}",
-                )))
+/// Parses the value of a `Retry-After` header, which is either a number of seconds or a date.
+fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Sends to a host which asked to wait longer than this with `Retry-After` fail immediately, so
+/// that they don't block a worker and are retried later instead.
+const MAX_HOST_WAIT: Duration = Duration::from_secs(60);
+
+/// Permit of the [HostLimiter] for one send, if the number of concurrent sends per host is limited
+pub(crate) type HostPermit = Option<OwnedSemaphorePermit>;
+
+/// Limits the concurrency and rate of outgoing sends per inbox host, see
+/// [FederationConfigBuilder::max_concurrent_sends_per_host](crate::config::FederationConfigBuilder::max_concurrent_sends_per_host).
+/// Also skips hosts which failed repeatedly, see
+/// [FederationConfigBuilder::circuit_breaker](crate::config::FederationConfigBuilder::circuit_breaker).
+pub(crate) struct HostLimiter {
+    max_concurrent: usize,
+    min_interval: Duration,
+    /// Number of consecutive failures after which sends fail immediately, and for how long
+    circuit_breaker: Option<(u32, Duration)>,
+    hosts: Cache<String, Arc<HostState>>,
+}
+
+struct HostState {
+    semaphore: Option<Arc<Semaphore>>,
+    /// Earliest time when the next send to this host may start
+    next_send: Mutex<Instant>,
+    circuit: Mutex<CircuitState>,
+}
+
+/// Failures of sends to a host, for the circuit breaker
+#[derive(Default)]
+struct CircuitState {
+    /// Number of sends in a row which failed
+    failures: u32,
+    /// Sends fail immediately until this time
+    open_until: Option<Instant>,
+}
+
+impl CircuitState {
+    /// Remaining time during which sends fail immediately
+    fn remaining(&self) -> Option<Duration> {
+        let remaining = self.open_until?.checked_duration_since(Instant::now())?;
+        (!remaining.is_zero()).then_some(remaining)
+    }
+}
+
+impl HostLimiter {
+    pub(crate) fn new(
+        max_concurrent: usize,
+        min_interval: Duration,
+        circuit_breaker: Option<(u32, Duration)>,
+    ) -> Self {
+        HostLimiter {
+            max_concurrent,
+            min_interval,
+            circuit_breaker,
+            hosts: Cache::builder()
+                .max_capacity(10000)
+                .time_to_idle(Duration::from_secs(3600))
+                .build(),
+        }
+    }
+
+    async fn host(&self, inbox: &Url) -> Arc<HostState> {
+        let host = format!(
+            "{}:{}",
+            inbox.host_str().unwrap_or_default(),
+            inbox.port_or_known_default().unwrap_or_default()
+        );
+        self.hosts
+            .get_with(host, async {
+                Arc::new(HostState {
+                    semaphore: (self.max_concurrent > 0)
+                        .then(|| Arc::new(Semaphore::new(self.max_concurrent))),
+                    next_send: Mutex::new(Instant::now()),
+                    circuit: Default::default(),
+                })
+            })
+            .await
+    }
+
+    /// Returns an error if sends to the inbox currently fail immediately because of the circuit
+    /// breaker.
+    async fn check_circuit(&self, inbox: &Url) -> Result<Arc<HostState>, Error> {
+        let state = self.host(inbox).await;
+        let circuit_open = state
+            .circuit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remaining();
+        match circuit_open {
+            Some(remaining) => Err(Error::HostUnavailable(inbox.clone(), remaining)),
+            None => Ok(state),
+        }
+    }
+
+    /// Waits until a send to the inbox is allowed. The returned permit needs to be held until the
+    /// send is finished.
+    pub(crate) async fn acquire(&self, inbox: &Url) -> Result<HostPermit, Error> {
+        let state = self.check_circuit(inbox).await?;
+        let permit = self.acquire_permit(inbox).await;
+        let wait = {
+            let mut next_send = state
+                .next_send
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            let start = (*next_send).max(now);
+            if start - now > MAX_HOST_WAIT {
+                return Err(Error::RateLimited(inbox.clone(), Some(start - now)));
+            }
+            *next_send = start + self.min_interval;
+            start - now
+        };
+        tokio::time::sleep(wait).await;
+        Ok(permit)
+    }
+
+    /// Waits until fewer than the maximum number of sends to the host of the inbox are running.
+    /// The returned permit needs to be held until the send is finished.
+    pub(crate) async fn acquire_permit(&self, inbox: &Url) -> HostPermit {
+        let state = self.host(inbox).await;
+        match &state.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("host semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+
+    /// Reserves the start of a send to the inbox if it may start right away, and otherwise returns
+    /// the time after which it should be tried again, for example because the host asked to wait
+    /// with `Retry-After`. Unlike [HostLimiter::acquire] this doesn't sleep, so that the activity
+    /// queue can use the worker slot for other sends in the meantime. The circuit breaker is
+    /// checked when sending.
+    pub(crate) async fn try_reserve(&self, inbox: &Url) -> Result<(), Duration> {
+        let state = self.host(inbox).await;
+        let mut next_send = state
+            .next_send
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if *next_send > now {
+            return Err(*next_send - now);
+        }
+        *next_send = now + self.min_interval;
+        Ok(())
+    }
+
+    /// Delays further sends to the host of the inbox, after it responded with `Retry-After`.
+    pub(crate) async fn pause(&self, inbox: &Url, duration: Duration) {
+        let state = self.host(inbox).await;
+        let mut next_send = state
+            .next_send
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *next_send = (*next_send).max(Instant::now() + duration);
+    }
+
+    /// Counts consecutive failed sends to the host of the inbox for the circuit breaker. Sends
+    /// fail if the host can't be reached or responds with a server error. Other errors, such as
+    /// failed signing, don't change the count.
+    pub(crate) async fn record_result(&self, inbox: &Url, res: &Result<(), Error>) {
+        let Some((max_failures, cooldown)) = self.circuit_breaker else {
+            return;
+        };
+        let state = self.host(inbox).await;
+        let mut circuit = state.circuit.lock().unwrap_or_else(PoisonError::into_inner);
+        match res {
+            Err(
+                Error::DeliveryFailed { .. }
+                | Error::Reqwest(_)
+                | Error::ReqwestMiddleware(_)
+                | Error::IoError(_),
+            ) => {
+                circuit.failures = circuit.failures.saturating_add(1);
+                if circuit.failures >= max_failures {
+                    debug!("Pausing sends to {inbox} for {cooldown:?} after repeated failures");
+                    circuit.open_until = Some(Instant::now() + cooldown);
+                }
+            }
+            Ok(()) | Err(Error::DeliveryRejected { .. } | Error::RateLimited(..)) => {
+                *circuit = CircuitState::default();
             }
+            Err(_) => {}
+        }
+    }
+
+    /// Number of hosts to which sends currently fail immediately because of the circuit breaker
+    pub(crate) fn unavailable_hosts(&self) -> usize {
+        if self.circuit_breaker.is_none() {
+            return 0;
         }
+        self.hosts
+            .iter()
+            .filter(|(_, state)| {
+                state
+                    .circuit
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remaining()
+                    .is_some()
+            })
+            .count()
+    }
+}
+
+impl Default for HostLimiter {
+    fn default() -> Self {
+        HostLimiter::new(0, Duration::ZERO, None)
+    }
+}
+
+impl Debug for HostLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostLimiter")
+            .field("max_concurrent", &self.max_concurrent)
+            .field("min_interval", &self.min_interval)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("hosts", &self.hosts.entry_count())
+            .finish()
+    }
+}
+
+/// Result of [SendActivityTask::prepare], with one task per inbox which should receive the activity.
+///
+/// Iterating over this yields the tasks, so it can be used like a `Vec<SendActivityTask>`.
+#[derive(Debug, Default)]
+pub struct PreparedTasks {
+    /// Tasks which are ready to be signed and sent
+    pub tasks: Vec<SendActivityTask>,
+    /// Inboxes which were excluded from delivery because they failed verification
+    pub skipped: Vec<SkippedInbox>,
+}
+
+/// An inbox which was excluded from delivery, see [PreparedTasks].
+#[derive(Debug)]
+pub struct SkippedInbox {
+    /// The inbox url
+    pub inbox: Url,
+    /// Why the inbox was excluded, eg the error returned by [crate::config::UrlVerifier]
+    pub reason: Error,
+}
+
+impl IntoIterator for PreparedTasks {
+    type Item = SendActivityTask;
+    type IntoIter = std::vec::IntoIter<SendActivityTask>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tasks.into_iter()
+    }
+}
+
+impl From<PreparedTasks> for Vec<SendActivityTask> {
+    fn from(value: PreparedTasks) -> Self {
+        value.tasks
     }
 }
 
@@ -141,42 +655,153 @@ pub(crate) async fn build_tasks<Activity, Datatype, ActorType>(
     actor: &ActorType,
     inboxes: Vec<Url>,
     data: &Data<Datatype>,
-) -> Result<Vec<SendActivityTask>, Error>
+    retry_policy: Option<RetryPolicy>,
+) -> Result<PreparedTasks, Error>
 where
     Activity: ActivityHandler + Serialize + Debug,
     Datatype: Clone,
-    ActorType: Actor,
+    ActorType: Actor<DataType = Datatype>,
+{
+    let serialize_error =
+        |e| Error::SerializeOutgoingActivity(Arc::new(e), format!("{:?}", activity));
+    let activity_serialized = if data.config.strip_private_addressing {
+        let mut value = serde_json::to_value(activity).map_err(serialize_error)?;
+        strip_private_addressing(&mut value);
+        serde_json::to_vec(&value)
+    } else {
+        serde_json::to_vec(activity)
+    };
+    let activity_serialized: Bytes = activity_serialized.map_err(serialize_error)?.into();
+    build_tasks_serialized(
+        activity.id(),
+        activity_serialized,
+        activity.actor(),
+        actor,
+        inboxes,
+        data,
+        retry_policy,
+    )
+    .await
+}
+
+/// Same as [build_tasks], but with an activity which is already serialized. Requests are signed
+/// with the key of `actor`, using the key id of `key_owner`.
+pub(crate) async fn build_tasks_serialized<Datatype, ActorType>(
+    activity_id: &Url,
+    activity_serialized: Bytes,
+    key_owner: &Url,
+    actor: &ActorType,
+    inboxes: Vec<Url>,
+    data: &Data<Datatype>,
+    retry_policy: Option<RetryPolicy>,
+) -> Result<PreparedTasks, Error>
+where
+    Datatype: Clone,
+    ActorType: Actor<DataType = Datatype>,
 {
     let config = &data.config;
-    let actor_id = activity.actor();
-    let activity_id = activity.id();
-    let activity_serialized: Bytes = serde_json::to_vec(activity)
-        .map_err(|e| Error::SerializeOutgoingActivity(e, format!("{:?}", activity)))?
-        .into();
     let private_key = get_pkey_cached(data, actor).await?;
+    let key_id = config.key_id_strategy.key_id(key_owner);
+    let retry_policy = retry_policy.unwrap_or_else(|| {
+        extract_kind(&activity_serialized)
+            .ok()
+            .and_then(|kind| config.retry_policies.get(&kind).copied())
+            .unwrap_or_default()
+    });
 
-    Ok(futures::stream::iter(
-        inboxes
-            .into_iter()
-            .unique()
-            .filter(|i| !config.is_local_url(i)),
-    )
-    .filter_map(|inbox| async {
-        if let Err(err) = config.verify_url_valid(&inbox).await {
+    let followers = actor
+        .followers()
+        .filter(|followers| extract_recipients(&activity_serialized).contains(followers.as_str()));
+
+    let mut prepared = PreparedTasks::default();
+    for inbox in inboxes
+        .into_iter()
+        .unique()
+        .filter(|i| !config.is_local_url(i))
+    {
+        if let Err(err) = config
+            .verify_url_valid(&inbox, VerifyContext::OutgoingDelivery)
+            .await
+        {
             debug!("inbox url invalid, skipping: {inbox}: {err}");
-            return None;
+            prepared.skipped.push(SkippedInbox { inbox, reason: err });
+            continue;
         };
-        Some(SendActivityTask {
-            actor_id: actor_id.clone(),
+        let collection_synchronization = match &followers {
+            Some(followers) => {
+                actor
+                    .collection_synchronization(&inbox, data)
+                    .await
+                    .filter(|header| {
+                        parse_collection_synchronization(header)
+                            .is_some_and(|sync| &sync.collection_id == followers)
+                    })
+            }
+            None => None,
+        };
+        prepared.tasks.push(SendActivityTask {
+            key_id: key_id.clone(),
             activity_id: activity_id.clone(),
             inbox,
             activity: activity_serialized.clone(),
             private_key: private_key.clone(),
             http_signature_compat: config.http_signature_compat,
+            rfc9421_signatures: config.use_rfc9421_signatures,
+            both_digest_headers: config.both_digest_headers,
+            retry_policy,
+            signing_limiter: config.signing_limiter.clone(),
+            host_limiter: config.host_limiter.clone(),
+            peer_software: config.peer_software.clone(),
+            metrics_hook: config.metrics_hook.clone(),
+            collection_synchronization,
+            deliver_after: None,
+        });
+    }
+    Ok(prepared)
+}
+
+/// Ids in the addressing fields of a serialized activity
+fn extract_recipients(activity: &[u8]) -> HashSet<String> {
+    let Ok(Value::Object(activity)) = serde_json::from_slice(activity) else {
+        return HashSet::new();
+    };
+    ["to", "cc", "bto", "bcc", "audience"]
+        .iter()
+        .filter_map(|field| activity.get(*field))
+        .flat_map(|value| match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
         })
-    })
-    .collect()
-    .await)
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Adds a Linked Data Signature of type `RsaSignature2017` to the activity, and returns it as
+/// json with the additional `signature` field.
+///
+/// This is needed for relays which only accept activities with an embedded signature, because
+/// they forward activities to other instances where the HTTP signature can't be verified. The
+/// activity must include `@context`, for example by wrapping it in
+/// [WithContext](crate::protocol::context::WithContext). See
+/// [ld_signature](crate::protocol::ld_signature) for the supported subset of JSON-LD.
+///
+/// To send the signed activity, deserialize it into a struct which has an additional
+/// `signature: Option<serde_json::Value>` field.
+pub async fn sign_activity_ld<Activity, Datatype, ActorType>(
+    activity: &Activity,
+    actor: &ActorType,
+    data: &Data<Datatype>,
+) -> Result<serde_json::Value, Error>
+where
+    Activity: Serialize + Debug,
+    Datatype: Clone,
+    ActorType: Actor,
+{
+    let activity_json = serde_json::to_value(activity)
+        .map_err(|e| Error::SerializeOutgoingActivity(Arc::new(e), format!("{:?}", activity)))?;
+    let private_key = get_pkey_cached(data, actor).await?;
+    let key_id = data.config.key_id_strategy.key_id(&actor.id());
+    create_ld_signature(activity_json, &key_id, &private_key, Utc::now())
 }
 
 pub(crate) async fn get_pkey_cached<ActorType>(
@@ -191,24 +816,21 @@ where
     data.config
         .actor_pkey_cache
         .try_get_with_by_ref(&actor_id, async {
-            let private_key_pem = actor.private_key_pem().ok_or_else(|| {
-                Error::Other(format!(
-                    "Actor {actor_id} does not contain a private key for signing"
-                ))
-            })?;
-
-            // This is a mostly expensive blocking call, we don't want to tie up other tasks while this is happening
-            let pkey = tokio::task::spawn_blocking(move || {
-                RsaPrivateKey::from_pkcs8_pem(&private_key_pem).map_err(|err| {
-                    Error::Other(format!("Could not create private key from PEM data:{err}"))
-                })
-            })
-            .await
-            .map_err(|err| Error::Other(format!("Error joining: {err}")))??;
-            std::result::Result::<RsaPrivateKey, Error>::Ok(pkey)
+            let private_key_pem = actor
+                .private_key_pem()
+                .ok_or_else(|| Error::MissingPrivateKey(actor_id.clone()))?;
+
+            data.config
+                .signing_limiter
+                .parse_private_key(private_key_pem)
+                .await
         })
         .await
-        .map_err(|e| Error::Other(format!("cloned error: {e}")))
+        .map_err(|e| match &*e {
+            Error::MissingPrivateKey(actor_id) => Error::MissingPrivateKey(actor_id.clone()),
+            Error::KeyParse(err) => Error::KeyParse(err.clone()),
+            e => Error::Other(format!("cloned error: {e}")),
+        })
 }
 
 pub(crate) fn generate_request_headers(inbox_url: &Url) -> HeaderMap {
@@ -235,15 +857,58 @@ pub(crate) fn generate_request_headers(inbox_url: &Url) -> HeaderMap {
 
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::{config::FederationConfig, http_signatures::generate_actor_keypair};
+    use crate::{
+        config::{FederationConfig, UrlVerifier},
+        fetch::object_id::ObjectId,
+        http_signatures::test::test_keypair,
+        protocol::{
+            context::WithContext,
+            ld_signature::verify_activity_ld_signature,
+            public_key::KeyIdStrategy,
+        },
+        traits::{
+            tests::{serve_router, DbConnection, DbUser, Follow, DB_USER, DB_USER_KEYPAIR},
+            Object,
+        },
+    };
+    use activitystreams_kinds::public;
+    use axum::extract::State;
+    use http::Response;
+    use serde_json::{json, Value};
     use std::{
-        sync::{atomic::AtomicUsize, Arc},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+            Mutex,
+        },
         time::Instant,
     };
     use tracing::info;
 
+    /// Task which sends an empty activity to `inbox`, signed with the test keypair. Tests can
+    /// change single fields with struct update syntax.
+    pub(crate) fn test_task(inbox: &Url) -> SendActivityTask {
+        SendActivityTask {
+            key_id: format!("{inbox}#main-key"),
+            activity_id: inbox.join("activity").unwrap(),
+            activity: "{}".into(),
+            inbox: inbox.clone(),
+            private_key: test_keypair().private_key().unwrap(),
+            http_signature_compat: true,
+            rfc9421_signatures: false,
+            both_digest_headers: false,
+            retry_policy: Default::default(),
+            signing_limiter: Default::default(),
+            host_limiter: Default::default(),
+            peer_software: Default::default(),
+            metrics_hook: Arc::new(NoMetrics),
+            collection_synchronization: None,
+            deliver_after: None,
+        }
+    }
+
     // This will periodically send back internal errors to test the retry
     async fn dodgy_handler(headers: HeaderMap, body: Bytes) -> Result<(), StatusCode> {
         debug!("Headers:{:?}", headers);
@@ -285,16 +950,9 @@ mod tests {
             .init();
 
         */
-        let keypair = generate_actor_keypair().unwrap();
 
-        let message = SendActivityTask {
-            actor_id: "http://localhost:8001".parse().unwrap(),
-            activity_id: "http://localhost:8001/activity".parse().unwrap(),
-            activity: "{}".into(),
-            inbox: "http://localhost:8001".parse().unwrap(),
-            private_key: keypair.private_key().unwrap(),
-            http_signature_compat: true,
-        };
+        let inbox: Url = "http://localhost:8001".parse().unwrap();
+        let message = test_task(&inbox);
         let data = FederationConfig::builder()
             .app_data(())
             .domain("localhost")
@@ -312,17 +970,213 @@ mod tests {
         Ok(())
     }
 
+    /// Rejects all urls on the domain `blocked.com`
+    #[derive(Clone)]
+    pub(crate) struct BlockingVerifier;
+
+    #[async_trait::async_trait]
+    impl UrlVerifier for BlockingVerifier {
+        async fn verify(&self, url: &Url) -> Result<(), Error> {
+            if url.domain() == Some("blocked.com") {
+                Err(Error::Other("domain is blocked".to_string()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
     #[tokio::test]
-    async fn test_handle_response() {
-        let keypair = generate_actor_keypair().unwrap();
-        let message = SendActivityTask {
-            actor_id: "http://localhost:8001".parse().unwrap(),
-            activity_id: "http://localhost:8001/activity".parse().unwrap(),
-            activity: "{}".into(),
-            inbox: "http://localhost:8001".parse().unwrap(),
-            private_key: keypair.private_key().unwrap(),
-            http_signature_compat: true,
+    async fn test_prepare_skipped_inbox() -> anyhow::Result<()> {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .url_verifier(Box::new(BlockingVerifier))
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob")?,
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse()?,
         };
+        let valid: Url = "http://localhost:8001/inbox".parse()?;
+        let blocked: Url = "http://blocked.com/inbox".parse()?;
+
+        let prepared = SendActivityTask::prepare(
+            &activity,
+            &*DB_USER,
+            vec![valid.clone(), blocked.clone()],
+            &data,
+        )
+        .await?;
+        assert_eq!(prepared.tasks.len(), 1);
+        assert_eq!(prepared.tasks[0].inbox, valid);
+        assert_eq!(prepared.skipped.len(), 1);
+        assert_eq!(prepared.skipped[0].inbox, blocked);
+        assert_eq!(prepared.skipped[0].reason.to_string(), "domain is blocked");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_private_key() -> anyhow::Result<()> {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        // Actors which were received from another instance don't have a private key
+        let remote_user = DbUser::from_json(DB_USER.clone().into_json(&data).await?, &data).await?;
+        let err = get_pkey_cached(&data, &remote_user).await.unwrap_err();
+        assert!(matches!(&err, Error::MissingPrivateKey(id) if id == &DB_USER.federation_id));
+        assert!(!err.is_retryable());
+
+        let err = data
+            .config
+            .signing_limiter
+            .parse_private_key("invalid".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::KeyParse(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_key_id_strategy() -> anyhow::Result<()> {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .key_id_strategy(KeyIdStrategy::PathSuffix("main-key".to_string()))
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob")?,
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse()?,
+        };
+        let inbox: Url = "http://localhost:8001/inbox".parse()?;
+
+        let prepared = SendActivityTask::prepare(&activity, &*DB_USER, vec![inbox], &data).await?;
+        assert_eq!(prepared.tasks.len(), 1);
+        assert_eq!(
+            prepared.tasks[0].key_id,
+            format!("{}/main-key", DB_USER.federation_id)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sign_activity_ld() -> anyhow::Result<()> {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .build()
+            .await?
+            .to_request_data();
+        let activity = WithContext::new_default(Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob")?,
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse()?,
+        });
+
+        let signed = sign_activity_ld(&activity, &*DB_USER, &data).await?;
+        assert_eq!(
+            signed["signature"]["creator"],
+            format!("{}#main-key", DB_USER.federation_id)
+        );
+        verify_activity_ld_signature(&signed, &DB_USER_KEYPAIR.public_key)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signing_limit() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let app = Router::new().route("/inbox/:id", post(|| async {}));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .max_concurrent_signatures(4)
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob")?,
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse()?,
+        };
+        let inboxes = (0..500)
+            .map(|i| format!("http://localhost:{port}/inbox/{i}").parse())
+            .collect::<Result<_, _>>()?;
+
+        let prepared = SendActivityTask::prepare(&activity, &*DB_USER, inboxes, &data).await?;
+        assert_eq!(prepared.tasks.len(), 500);
+        let results =
+            futures::future::join_all(prepared.tasks.iter().map(|t| t.sign_and_send(&data))).await;
+        assert!(results.iter().all(Result::is_ok));
+
+        let limiter = &data.config.signing_limiter;
+        assert!(limiter.max_in_flight() <= 4);
+        assert!(limiter.max_in_flight() > 1);
+        let metrics = data.signing_metrics();
+        assert_eq!(metrics.sign_count, 500);
+        assert_eq!(metrics.key_parse_count, 1);
+        assert!(metrics.avg_sign_time > Duration::ZERO);
+        assert!(metrics.p99_sign_time >= metrics.p50_sign_time);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_retry_policy() -> anyhow::Result<()> {
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .retry_policy("Follow", RetryPolicy::None)
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        let activity = Follow {
+            actor: DB_USER.federation_id.clone().into(),
+            object: ObjectId::parse("http://localhost:8001/u/bob")?,
+            kind: Default::default(),
+            id: "http://localhost:123/activity/1".parse()?,
+        };
+        let inboxes = vec!["http://localhost:8001/inbox".parse()?];
+
+        let prepared =
+            SendActivityTask::prepare(&activity, &*DB_USER, inboxes.clone(), &data).await?;
+        assert_eq!(prepared.tasks[0].retry_policy(), RetryPolicy::None);
+
+        let prepared = build_tasks(
+            &activity,
+            &*DB_USER,
+            inboxes,
+            &data,
+            Some(RetryPolicy::Full),
+        )
+        .await?;
+        assert_eq!(prepared.tasks[0].retry_policy(), RetryPolicy::Full);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handle_response() {
+        let inbox: Url = "http://localhost:8001".parse().unwrap();
+        let message = test_task(&inbox);
 
         let res = |status| {
             http::Response::builder()
@@ -333,10 +1187,18 @@ mod tests {
         };
 
         assert!(message.handle_response(res(StatusCode::OK)).await.is_ok());
-        assert!(message
+        let rejected = message
             .handle_response(res(StatusCode::BAD_REQUEST))
             .await
-            .is_ok());
+            .unwrap_err();
+        assert!(matches!(
+            rejected,
+            Error::DeliveryRejected {
+                status: StatusCode::BAD_REQUEST,
+                ..
+            }
+        ));
+        assert!(!rejected.is_retryable());
 
         assert!(message
             .handle_response(res(StatusCode::MOVED_PERMANENTLY))
@@ -350,9 +1212,552 @@ mod tests {
             .handle_response(res(StatusCode::TOO_MANY_REQUESTS))
             .await
             .is_err());
-        assert!(message
+        let failed = message
             .handle_response(res(StatusCode::INTERNAL_SERVER_ERROR))
             .await
-            .is_err());
+            .unwrap_err();
+        assert!(matches!(
+            failed,
+            Error::DeliveryFailed {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                ..
+            }
+        ));
+        assert!(failed.is_retryable());
+    }
+
+    async fn rate_limiting_handler(State(state): State<Arc<AtomicUsize>>) -> Response<String> {
+        let mut response = Response::new(String::new());
+        if state.fetch_add(1, Ordering::Relaxed) == 0 {
+            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() -> Result<(), Error> {
+        use axum::{routing::post, Router};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/inbox", post(rate_limiting_handler))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url =
+            format!("http://localhost:{}/inbox", listener.local_addr()?.port()).parse()?;
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let task = test_task(&inbox);
+        let client = reqwest::Client::default().into();
+        let timeout = Duration::from_secs(10);
+        let res = task.sign_and_send_internal(&client, timeout, None).await;
+        assert_eq!(
+            res,
+            Err(Error::RateLimited(inbox, Some(Duration::from_secs(1))))
+        );
+
+        // The next send to the same host waits until the requested time
+        let start = Instant::now();
+        task.sign_and_send_internal(&client, timeout, None).await?;
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    struct PleromaServer {
+        url: Url,
+        signatures: Mutex<Vec<String>>,
+    }
+
+    async fn pleroma_well_known(State(state): State<Arc<PleromaServer>>) -> axum::Json<Value> {
+        axum::Json(json!({
+            "links": [{
+                "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                "href": state.url.join("/nodeinfo/2.0").unwrap(),
+            }]
+        }))
+    }
+
+    async fn pleroma_nodeinfo() -> axum::Json<Value> {
+        axum::Json(json!({
+            "version": "2.0",
+            "software": { "name": "pleroma", "version": "2.4.5" },
+        }))
+    }
+
+    async fn pleroma_inbox(State(state): State<Arc<PleromaServer>>, headers: HeaderMap) {
+        let signature = headers.get("Signature").unwrap().to_str().unwrap();
+        state.signatures.lock().unwrap().push(signature.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_peer_software_signature_compat() -> anyhow::Result<()> {
+        use axum::{
+            routing::{get, post},
+            Router,
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url: Url = format!("http://localhost:{}/", listener.local_addr()?.port()).parse()?;
+        let server = Arc::new(PleromaServer {
+            url: url.clone(),
+            signatures: Default::default(),
+        });
+        let app = Router::new()
+            .route("/.well-known/nodeinfo", get(pleroma_well_known))
+            .route("/nodeinfo/2.0", get(pleroma_nodeinfo))
+            .route("/inbox", post(pleroma_inbox))
+            .with_state(server.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = FederationConfig::builder()
+            .app_data(())
+            .domain("example.com")
+            .detect_peer_software(true)
+            .debug(true)
+            .build()
+            .await?;
+        let data = config.to_request_data();
+        let inbox = url.join("inbox")?;
+        let task = SendActivityTask {
+            http_signature_compat: false,
+            peer_software: config.peer_software.clone(),
+            metrics_hook: config.metrics_hook.clone(),
+            ..test_task(&inbox)
+        };
+
+        // Software is unknown at first, so the default signature is used
+        task.sign_and_send(&data).await?;
+        let start = Instant::now();
+        while data.peer_software(&inbox).await.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            data.peer_software(&inbox).await.map(|s| s.name),
+            Some("pleroma".to_string())
+        );
+
+        // Once the software is known, draft 10 signatures are used
+        task.sign_and_send(&data).await?;
+        let signatures = server.signatures.lock().unwrap().clone();
+        assert_eq!(signatures.len(), 2);
+        assert!(signatures[0].contains("(created)"));
+        assert!(!signatures[1].contains("(created)"));
+        Ok(())
+    }
+
+    /// Inbox which only accepts draft 10 signatures, and records the signature of each request
+    async fn compat_inbox(
+        State(signatures): State<Arc<Mutex<Vec<String>>>>,
+        headers: HeaderMap,
+    ) -> StatusCode {
+        let signature = headers.get("Signature").unwrap().to_str().unwrap();
+        signatures.lock().unwrap().push(signature.to_string());
+        match signature.contains("(created)") {
+            true => StatusCode::UNAUTHORIZED,
+            false => StatusCode::OK,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signature_auto_compat() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url =
+            format!("http://localhost:{}/inbox", listener.local_addr()?.port()).parse()?;
+        let signatures = Arc::new(Mutex::new(vec![]));
+        let app = Router::new()
+            .route("/inbox", post(compat_inbox))
+            .with_state(signatures.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let task = |config: &FederationConfig<()>| SendActivityTask {
+            http_signature_compat: config.http_signature_compat,
+            peer_software: config.peer_software.clone(),
+            metrics_hook: config.metrics_hook.clone(),
+            ..test_task(&inbox)
+        };
+        let config = |auto_compat| {
+            FederationConfig::builder()
+                .app_data(())
+                .domain("example.com")
+                .http_signature_auto_compat(auto_compat)
+                .debug(true)
+                .build_lazy()
+        };
+
+        // Without auto compat, the rejected activity is not retried
+        let disabled = config(false)?;
+        let res = task(&disabled)
+            .sign_and_send(&disabled.to_request_data())
+            .await;
+        assert!(matches!(res, Err(Error::DeliveryRejected { .. })));
+        assert_eq!(signatures.lock().unwrap().len(), 1);
+        signatures.lock().unwrap().clear();
+
+        // The first activity is sent again with a compat signature
+        let config = config(true)?;
+        let data = config.to_request_data();
+        task(&config).sign_and_send(&data).await?;
+        {
+            let signatures = signatures.lock().unwrap();
+            assert_eq!(signatures.len(), 2);
+            assert!(signatures[0].contains("(created)"));
+            assert!(!signatures[1].contains("(created)"));
+        }
+
+        // Further activities are sent with a compat signature directly
+        task(&config).sign_and_send(&data).await?;
+        let signatures = signatures.lock().unwrap().clone();
+        assert_eq!(signatures.len(), 3);
+        assert!(!signatures[2].contains("(created)"));
+        Ok(())
+    }
+
+    /// Inbox which only accepts draft-cavage signatures, and records the signature headers of
+    /// each request
+    async fn cavage_inbox(
+        State(requests): State<Arc<Mutex<Vec<HeaderMap>>>>,
+        headers: HeaderMap,
+    ) -> StatusCode {
+        let rfc9421 = headers.contains_key("signature-input");
+        requests.lock().unwrap().push(headers);
+        match rfc9421 {
+            true => StatusCode::UNAUTHORIZED,
+            false => StatusCode::OK,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collection_synchronization() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url =
+            format!("http://localhost:{}/inbox", listener.local_addr()?.port()).parse()?;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let app = Router::new()
+            .route("/inbox", post(cavage_inbox))
+            .with_state(requests.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("example.com")
+            .debug(true)
+            .build()
+            .await?;
+        let followers = format!("{}/followers", DB_USER.federation_id);
+        let prepare = |activity: Value| {
+            let activity_id = Url::parse("http://example.com/activity").unwrap();
+            let inbox = inbox.clone();
+            let data = config.to_request_data();
+            async move {
+                build_tasks_serialized(
+                    &activity_id,
+                    serde_json::to_vec(&activity).unwrap().into(),
+                    &DB_USER.federation_id,
+                    &*DB_USER,
+                    vec![inbox],
+                    &data,
+                    None,
+                )
+                .await
+                .unwrap()
+                .tasks
+                .remove(0)
+            }
+        };
+
+        // Only sent if the activity is addressed to the followers collection
+        let addressed = prepare(json!({ "to": [public()], "cc": followers })).await;
+        let sync = parse_collection_synchronization(
+            addressed.collection_synchronization.as_ref().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sync.collection_id.as_str(), followers);
+        assert!(sync.matches(&[]));
+        let not_addressed = prepare(json!({ "to": [public()] })).await;
+        assert!(not_addressed.collection_synchronization.is_none());
+
+        let client = reqwest::Client::default().into();
+        addressed
+            .sign_and_send_internal(&client, Duration::from_secs(10), None)
+            .await?;
+        not_addressed
+            .sign_and_send_internal(&client, Duration::from_secs(10), None)
+            .await?;
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(
+            requests[0].get(COLLECTION_SYNCHRONIZATION_HEADER),
+            addressed.collection_synchronization.as_ref()
+        );
+        assert!(!requests[1].contains_key(COLLECTION_SYNCHRONIZATION_HEADER));
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize)]
+    struct PrivateCreate {
+        id: Url,
+        actor: Url,
+        to: Vec<Url>,
+        cc: Vec<Url>,
+        bto: Vec<Url>,
+        bcc: Vec<Url>,
+        object: Value,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for PrivateCreate {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strip_private_addressing() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let state = bodies.clone();
+        let inbox = serve_router(|_| {
+            Router::new()
+                .route(
+                    "/inbox",
+                    post(
+                        |State(bodies): State<Arc<Mutex<Vec<Bytes>>>>, body: Bytes| async move {
+                            bodies.lock().unwrap().push(body);
+                        },
+                    ),
+                )
+                .with_state(state)
+        })
+        .await
+        .join("/inbox")?;
+
+        let alice: Url = "http://example.net/u/alice".parse()?;
+        let bob: Url = "http://example.net/u/bob".parse()?;
+        let activity = PrivateCreate {
+            id: "http://localhost:8001/activity/1".parse()?,
+            actor: DB_USER.federation_id.clone(),
+            to: vec![public()],
+            cc: vec![alice.clone()],
+            bto: vec![bob.clone()],
+            bcc: vec![bob.clone()],
+            object: json!({
+                "type": "Note",
+                "to": [public()],
+                "bto": [bob],
+                "bcc": [bob],
+            }),
+        };
+        let builder = || {
+            let mut builder = FederationConfig::builder();
+            builder
+                .app_data(DbConnection)
+                .domain("localhost:8001")
+                .debug(true);
+            builder
+        };
+
+        let data = builder().build().await?.to_request_data();
+        let prepared =
+            SendActivityTask::prepare(&activity, &*DB_USER, vec![inbox.clone()], &data).await?;
+        prepared.tasks[0].sign_and_send(&data).await?;
+        let body: Value = serde_json::from_slice(&bodies.lock().unwrap()[0])?;
+        assert_eq!(body["to"], json!([public()]));
+        assert_eq!(body["cc"], json!([alice]));
+        assert_eq!(body["object"]["to"], json!([public()]));
+        for value in [&body, &body["object"]] {
+            let value = value.as_object().unwrap();
+            assert!(!value.contains_key("bto"));
+            assert!(!value.contains_key("bcc"));
+        }
+
+        // Sent unchanged if disabled
+        let data = builder()
+            .strip_private_addressing(false)
+            .build()
+            .await?
+            .to_request_data();
+        let prepared = SendActivityTask::prepare(&activity, &*DB_USER, vec![inbox], &data).await?;
+        let body: Value = serde_json::from_slice(&prepared.tasks[0].activity)?;
+        assert_eq!(body, serde_json::to_value(&activity)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rfc9421_fallback_to_cavage() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url =
+            format!("http://localhost:{}/inbox", listener.local_addr()?.port()).parse()?;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let app = Router::new()
+            .route("/inbox", post(cavage_inbox))
+            .with_state(requests.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let task = SendActivityTask {
+            http_signature_compat: false,
+            rfc9421_signatures: true,
+            ..test_task(&inbox)
+        };
+        task.sign_and_send_internal(
+            &reqwest::Client::default().into(),
+            Duration::from_secs(10),
+            None,
+        )
+        .await?;
+
+        // Rejected RFC 9421 signature, then accepted draft-cavage signature
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains_key("content-digest"));
+        assert!(!requests[1].contains_key("signature-input"));
+        assert!(requests[1].contains_key("digest"));
+        Ok(())
+    }
+
+    async fn response_status(status: StatusCode, retry_after: Option<&str>) -> Result<(), Error> {
+        let inbox: Url = "http://example.com/inbox".parse()?;
+        let task = test_task(&inbox);
+        let mut response = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header(RETRY_AFTER, retry_after);
+        }
+        let response = response.body(String::new()).unwrap();
+        task.handle_response(response.into()).await
+    }
+
+    #[tokio::test]
+    async fn test_handle_response_retry_after() -> Result<(), Error> {
+        let inbox: Url = "http://example.com/inbox".parse()?;
+        let res = response_status(StatusCode::TOO_MANY_REQUESTS, Some("30")).await;
+        assert_eq!(
+            res,
+            Err(Error::RateLimited(
+                inbox.clone(),
+                Some(Duration::from_secs(30))
+            ))
+        );
+        let Err(Error::RateLimited(_, retry_after)) =
+            response_status(StatusCode::TOO_MANY_REQUESTS, None).await
+        else {
+            panic!("expected rate limit error");
+        };
+        assert_eq!(retry_after, None);
+
+        let date = fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let Err(Error::RateLimited(_, Some(retry_after))) =
+            response_status(StatusCode::SERVICE_UNAVAILABLE, Some(&date)).await
+        else {
+            panic!("expected rate limit error");
+        };
+        assert!(retry_after > Duration::from_secs(50));
+
+        // Without header, 503 is a normal error which is retried on the usual schedule
+        let Err(Error::DeliveryFailed { status, .. }) =
+            response_status(StatusCode::SERVICE_UNAVAILABLE, None).await
+        else {
+            panic!("expected delivery error");
+        };
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+
+    async fn failing_handler(State(state): State<Arc<AtomicUsize>>) -> StatusCode {
+        state.fetch_add(1, Ordering::Relaxed);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() -> anyhow::Result<()> {
+        use axum::{routing::post, Router};
+
+        let data = FederationConfig::builder()
+            .app_data(DbConnection)
+            .domain("localhost:8001")
+            .circuit_breaker(3, Duration::from_millis(500))
+            .debug(true)
+            .build()
+            .await?
+            .to_request_data();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/inbox", post(failing_handler))
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let inbox: Url =
+            format!("http://localhost:{}/inbox", listener.local_addr()?.port()).parse()?;
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut tasks = vec![];
+        for i in 0..20 {
+            let activity = Follow {
+                actor: DB_USER.federation_id.clone().into(),
+                object: ObjectId::parse("http://localhost:8001/u/bob")?,
+                kind: Default::default(),
+                id: format!("http://localhost:8001/activity/{i}").parse()?,
+            };
+            let prepared =
+                SendActivityTask::prepare(&activity, &*DB_USER, vec![inbox.clone()], &data).await?;
+            tasks.extend(prepared.tasks);
+        }
+
+        for task in &tasks[..3] {
+            let res = task.sign_and_send(&data).await;
+            assert!(matches!(res, Err(Error::DeliveryFailed { .. })));
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+        assert_eq!(data.queue_stats().unavailable_hosts, 1);
+
+        // After the threshold, sends fail without connecting
+        for task in &tasks[3..] {
+            let res = task.sign_and_send(&data).await;
+            assert!(matches!(res, Err(Error::HostUnavailable(ref url, _)) if url == &inbox));
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        // After the cool-down one send is attempted, and fails again
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(data.queue_stats().unavailable_hosts, 0);
+        let res = tasks[0].sign_and_send(&data).await;
+        assert!(matches!(res, Err(Error::DeliveryFailed { .. })));
+        let res = tasks[1].sign_and_send(&data).await;
+        assert!(matches!(res, Err(Error::HostUnavailable(..))));
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let seconds = HeaderValue::from_static("120");
+        assert_eq!(parse_retry_after(&seconds), Some(Duration::from_secs(120)));
+        let date = fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        let date = parse_retry_after(&HeaderValue::from_str(&date).unwrap()).unwrap();
+        assert!(date > Duration::from_secs(3500) && date <= Duration::from_secs(3600));
+        assert_eq!(parse_retry_after(&HeaderValue::from_static("soon")), None);
     }
 }
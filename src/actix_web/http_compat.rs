@@ -12,7 +12,7 @@ where
 {
     let mut new_map = http::HeaderMap::new();
     for (n, v) in m {
-        new_map.insert(
+        new_map.append(
             http::HeaderName::from_lowercase(n.as_str().as_bytes())
                 .expect("can convert http types"),
             header_value(v),
@@ -28,3 +28,11 @@ pub fn method(m: &http02::Method) -> http::Method {
 pub fn uri(m: &http02::Uri) -> http::Uri {
     http::Uri::from_str(&m.to_string()).expect("can convert http types")
 }
+
+pub fn header_value_02(v: &http::HeaderValue) -> http02::HeaderValue {
+    http02::HeaderValue::from_bytes(v.as_bytes()).expect("can convert http types")
+}
+
+pub fn status_code_02(s: http::StatusCode) -> http02::StatusCode {
+    http02::StatusCode::from_u16(s.as_u16()).expect("can convert http types")
+}
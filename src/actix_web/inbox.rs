@@ -1,16 +1,30 @@
 //! Handles incoming activities, verifying HTTP signatures and other checks
 
-use super::http_compat;
+use super::{http_compat, ExtractorConfig};
 use crate::{
-    config::Data,
+    config::{Data, IncomingBodyLimit, DEFAULT_MAX_INCOMING_BODY_SIZE},
     error::Error,
-    http_signatures::{verify_body_hash, verify_signature},
-    parse_received_activity,
+    extract_local_recipients,
+    ignore_unknown_kind,
+    inbox,
+    process_received_activity,
     traits::{ActivityHandler, Actor, Object},
+    ReceiveOutcome,
+    FEDERATION_RESULT_HEADER,
 };
-use actix_web::{web::Bytes, HttpRequest, HttpResponse};
-use serde::de::DeserializeOwned;
-use tracing::debug;
+use actix_web::{
+    dev::Payload,
+    http::header::CONTENT_LENGTH,
+    web::{Bytes, BytesMut},
+    FromRequest,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    ResponseError,
+};
+use futures::{future::LocalBoxFuture, StreamExt};
+use http::{HeaderMap, Method, Uri};
+use serde::{de::DeserializeOwned, Deserialize};
 
 /// Handles incoming activities, verifying HTTP signatures and other checks
 ///
@@ -28,23 +42,394 @@ where
     <ActorT as Object>::Error: From<Error>,
     Datatype: Clone,
 {
-    let digest_header = request
-        .headers()
-        .get("Digest")
-        .map(http_compat::header_value);
-    verify_body_hash(digest_header.as_ref(), &body)?;
+    receive_activity_borrowed::<Activity, ActorT, Datatype>(&request, &body, data).await
+}
+
+/// Same as [receive_activity], but the activity may borrow from the request body instead of
+/// copying it, for example using `&str` fields with `#[serde(borrow)]`.
+///
+/// This avoids allocations for large incoming activities. The activity is fully processed before
+/// this function returns, so it can't outlive the body.
+pub async fn receive_activity_borrowed<'a, Activity, ActorT, Datatype>(
+    request: &HttpRequest,
+    body: &'a Bytes,
+    data: &Data<Datatype>,
+) -> Result<HttpResponse, <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    let (response, _) =
+        receive_activity_outcome_borrowed::<Activity, ActorT, Datatype>(request, body, data)
+            .await?;
+    Ok(response)
+}
+
+/// Same as [receive_activity], but additionally returns how the activity was handled. This
+/// allows the application to log or count ignored activities, and helps with testing.
+pub async fn receive_activity_outcome<Activity, ActorT, Datatype>(
+    request: HttpRequest,
+    body: Bytes,
+    data: &Data<Datatype>,
+) -> Result<(HttpResponse, ReceiveOutcome), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    receive_activity_outcome_borrowed::<Activity, ActorT, Datatype>(&request, &body, data).await
+}
 
-    let (activity, actor) = parse_received_activity::<Activity, ActorT, _>(&body, data).await?;
+async fn receive_activity_outcome_borrowed<'a, Activity, ActorT, Datatype>(
+    request: &HttpRequest,
+    body: &'a Bytes,
+    data: &Data<Datatype>,
+) -> Result<(HttpResponse, ReceiveOutcome), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    if let Some(outcome) = ignore_unknown_kind::<Activity, _>(body, data) {
+        return Ok((outcome_response(&outcome, data), outcome));
+    }
+    let (activity, _actor) = verify_activity::<Activity, ActorT, _>(request, body, data).await?;
+    let outcome =
+        process_received_activity(activity, body.clone(), data, |a| a.receive(data)).await?;
+    Ok((outcome_response(&outcome, data), outcome))
+}
+
+/// Same as [receive_activity], but takes the request data from the [ActivityData] extractor
+/// instead of `HttpRequest` and `Bytes`.
+///
+/// ```
+/// # use activitypub_federation::actix_web::inbox::{receive_activity_data, ActivityData};
+/// # use activitypub_federation::config::Data;
+/// # use activitypub_federation::error::Error;
+/// # use activitypub_federation::traits::tests::{DbConnection, DbUser, Follow};
+/// # use actix_web::HttpResponse;
+/// async fn inbox(
+///     activity_data: ActivityData,
+///     data: Data<DbConnection>,
+/// ) -> Result<HttpResponse, Error> {
+///     receive_activity_data::<Follow, DbUser, DbConnection>(activity_data, &data).await
+/// }
+/// ```
+pub async fn receive_activity_data<Activity, ActorT, Datatype>(
+    activity_data: ActivityData,
+    data: &Data<Datatype>,
+) -> Result<HttpResponse, <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    if let Some(outcome) = ignore_unknown_kind::<Activity, _>(&activity_data.body, data) {
+        return Ok(outcome_response(&outcome, data));
+    }
+    let (activity, _actor) = inbox::receive_activity_parts::<Activity, ActorT, _>(
+        &activity_data.headers,
+        &activity_data.method,
+        &activity_data.uri,
+        &activity_data.body,
+        data,
+    )
+    .await?;
+    let outcome =
+        process_received_activity(activity, activity_data.body, data, |a| a.receive(data))
+            .await?;
+    Ok(outcome_response(&outcome, data))
+}
 
+/// Response for a received activity, with [FEDERATION_RESULT_HEADER] if enabled
+fn outcome_response<T: Clone>(outcome: &ReceiveOutcome, data: &Data<T>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if data.config.federation_result_header {
+        response.insert_header((FEDERATION_RESULT_HEADER, outcome.header_value()));
+    }
+    response.finish()
+}
+
+/// Handles activities which are delivered to a shared inbox, such as `/inbox`.
+///
+/// Works like [receive_activity], but after verification the local actors which are addressed
+/// in the `to`, `cc`, `bto`, `bcc` or `audience` fields are passed to
+/// [ActivityHandler::receive_for]. This way the application can handle the activity for each
+/// local recipient, for example to add a post to the timeline of every follower.
+pub async fn receive_shared_activity<Activity, ActorT, Datatype>(
+    request: HttpRequest,
+    body: Bytes,
+    data: &Data<Datatype>,
+) -> Result<HttpResponse, <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    if let Some(outcome) = ignore_unknown_kind::<Activity, _>(&body, data) {
+        return Ok(outcome_response(&outcome, data));
+    }
+    let (activity, _actor) = verify_activity::<Activity, ActorT, _>(&request, &body, data).await?;
+    let recipients = extract_local_recipients(&body, data);
+    let outcome =
+        process_received_activity(activity, body, data, |a| a.receive_for(recipients, data))
+            .await?;
+    Ok(outcome_response(&outcome, data))
+}
+
+/// Verifies the body digest and HTTP signature of an incoming activity, and returns it together
+/// with the signing actor without processing it.
+///
+/// This allows responding with `202 Accepted` immediately and processing the activity later, so
+/// that a slow [ActivityHandler::receive] doesn't hold the connection of the sending server open
+/// until it times out and retries. The caller is responsible for calling [ActivityHandler::verify]
+/// and [ActivityHandler::receive], for example in a background task:
+///
+/// ```
+/// # use activitypub_federation::actix_web::inbox::receive_activity_parts;
+/// # use activitypub_federation::config::Data;
+/// # use activitypub_federation::error::Error;
+/// # use activitypub_federation::traits::ActivityHandler;
+/// # use activitypub_federation::traits::tests::{DbConnection, DbUser, Follow};
+/// # use actix_web::{web::Bytes, HttpRequest, HttpResponse};
+/// async fn inbox(
+///     request: HttpRequest,
+///     body: Bytes,
+///     data: Data<DbConnection>,
+/// ) -> Result<HttpResponse, Error> {
+///     let (activity, _actor) =
+///         receive_activity_parts::<Follow, DbUser, DbConnection>(&request, &body, &data).await?;
+///     let data = data.reset_request_count();
+///     tokio::spawn(async move {
+///         activity.verify(&data).await?;
+///         activity.receive(&data).await
+///     });
+///     Ok(HttpResponse::Accepted().finish())
+/// }
+/// ```
+pub async fn receive_activity_parts<Activity, ActorT, Datatype>(
+    request: &HttpRequest,
+    body: &Bytes,
+    data: &Data<Datatype>,
+) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    verify_activity::<Activity, ActorT, Datatype>(request, body, data).await
+}
+
+/// Checks the body digest, parses the activity, fetches the actor and verifies the signature.
+async fn verify_activity<'a, Activity, ActorT, Datatype>(
+    request: &HttpRequest,
+    body: &'a Bytes,
+    data: &Data<Datatype>,
+) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
     let headers = http_compat::header_map(request.headers());
     let method = http_compat::method(request.method());
     let uri = http_compat::uri(request.uri());
-    verify_signature(&headers, &method, &uri, actor.public_key_pem())?;
+    inbox::receive_activity_parts::<Activity, ActorT, _>(&headers, &method, &uri, body, data).await
+}
 
-    debug!("Receiving activity {}", activity.id().to_string());
-    activity.verify(data).await?;
-    activity.receive(data).await?;
-    Ok(HttpResponse::Ok().finish())
+/// Contains all data that is necessary to receive an activity from an HTTP request, for use with
+/// [receive_activity_data]. The body is read up to the size limit configured with
+/// [FederationConfigBuilder::max_incoming_body_size](crate::config::FederationConfigBuilder::max_incoming_body_size).
+#[derive(Debug)]
+pub struct ActivityData {
+    headers: HeaderMap,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+}
+
+impl ActivityData {
+    /// Raw body of the request
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+}
+
+impl FromRequest for ActivityData {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let limit = req
+            .extensions()
+            .get::<IncomingBodyLimit>()
+            .map(|l| l.0)
+            .unwrap_or(DEFAULT_MAX_INCOMING_BODY_SIZE);
+        let mut payload = payload.take();
+        let request = req.clone();
+        Box::pin(async move {
+            let body = read_body_limited(&request, &mut payload, limit)
+                .await
+                .map_err(|err| ExtractorConfig::map_error(&request, err))?;
+            Ok(ActivityData {
+                headers: http_compat::header_map(request.headers()),
+                method: http_compat::method(request.method()),
+                uri: http_compat::uri(request.uri()),
+                body,
+            })
+        })
+    }
+}
+
+/// Extractor for an incoming activity, as alternative to [receive_activity]. The body digest and
+/// HTTP signature are verified during extraction, so the handler only needs to call
+/// [VerifiedActivity::receive]. This allows combining it with other extractors, for example for
+/// rate limiting.
+///
+/// The request body is consumed by this extractor, so it can't be combined with `web::Bytes`.
+/// Use [VerifiedActivity::body] instead. Errors are converted to responses as configured with
+/// [ExtractorConfig].
+///
+/// ```
+/// # use activitypub_federation::actix_web::inbox::VerifiedActivity;
+/// # use activitypub_federation::config::Data;
+/// # use activitypub_federation::error::Error;
+/// # use activitypub_federation::traits::tests::{DbConnection, DbUser, Follow};
+/// # use actix_web::HttpResponse;
+/// async fn inbox(
+///     activity: VerifiedActivity<Follow, DbUser>,
+///     data: Data<DbConnection>,
+/// ) -> Result<HttpResponse, Error> {
+///     activity.receive(&data).await?;
+///     Ok(HttpResponse::Ok().finish())
+/// }
+/// ```
+pub struct VerifiedActivity<Activity, ActorT> {
+    activity: Activity,
+    actor: ActorT,
+    body: Bytes,
+}
+
+impl<Activity, ActorT> VerifiedActivity<Activity, ActorT>
+where
+    Activity: ActivityHandler,
+{
+    /// The activity, which is not verified with [ActivityHandler::verify] yet
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    /// The actor who sent the activity, and whose signature was verified
+    pub fn actor(&self) -> &ActorT {
+        &self.actor
+    }
+
+    /// Raw body of the request
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Returns the activity and the actor who sent it
+    pub fn into_inner(self) -> (Activity, ActorT) {
+        (self.activity, self.actor)
+    }
+
+    /// Calls [ActivityHandler::verify] and [ActivityHandler::receive] for the activity, unless it
+    /// was already received before
+    pub async fn receive(
+        self,
+        data: &Data<<Activity as ActivityHandler>::DataType>,
+    ) -> Result<(), <Activity as ActivityHandler>::Error> {
+        process_received_activity(self.activity, self.body, data, |a| a.receive(data)).await?;
+        Ok(())
+    }
+}
+
+impl<Activity, ActorT> FromRequest for VerifiedActivity<Activity, ActorT>
+where
+    Activity: ActivityHandler + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = <Activity as ActivityHandler>::DataType> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error:
+        From<Error> + From<<ActorT as Object>::Error> + ResponseError + 'static,
+    <ActorT as Object>::Error: From<Error>,
+    <Activity as ActivityHandler>::DataType: 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let data = Data::<<Activity as ActivityHandler>::DataType>::from_request(req, payload)
+            .into_inner();
+        let limit = data
+            .as_ref()
+            .map(|data| data.config.max_incoming_body_size)
+            .unwrap_or(DEFAULT_MAX_INCOMING_BODY_SIZE);
+        let mut payload = payload.take();
+        let request = req.clone();
+        Box::pin(async move {
+            let data = data.map_err(|err| ExtractorConfig::map_error(&request, err))?;
+            let body = read_body_limited(&request, &mut payload, limit)
+                .await
+                .map_err(|err| ExtractorConfig::map_error(&request, err))?;
+            let (activity, actor) = verify_activity::<Activity, ActorT, _>(&request, &body, &data)
+                .await
+                .map_err(|err| ExtractorConfig::map_error(&request, err))?;
+            Ok(VerifiedActivity {
+                activity,
+                actor,
+                body,
+            })
+        })
+    }
+}
+
+/// Reads the request body, and rejects it with [Error::RequestBodyLimit] as soon as it exceeds
+/// `limit`.
+async fn read_body_limited(
+    request: &HttpRequest,
+    payload: &mut Payload,
+    limit: usize,
+) -> Result<Bytes, actix_web::Error> {
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Err(Error::RequestBodyLimit.into());
+    }
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(Error::RequestBodyLimit.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
 }
 
 #[cfg(test)]
@@ -53,15 +438,30 @@ mod test {
     use super::*;
     use crate::{
         activity_sending::generate_request_headers,
-        config::FederationConfig,
+        config::{ActivityFilter, FederationConfig},
         fetch::object_id::ObjectId,
-        http_signatures::sign_request,
-        traits::tests::{DbConnection, DbUser, Follow, DB_USER_KEYPAIR},
+        http_signatures::{generate_actor_keypair, sign_request, test::test_keypair},
+        protocol::public_key::main_key_id,
+        parse_received_activity_borrowed,
+        traits::tests::{DbConnection, DbUser, Follow, DB_USER, DB_USER_KEYPAIR},
+        FEDERATION_CONTENT_TYPE,
     };
     use actix_web::test::TestRequest;
+    use axum::{routing::get, Router};
+    use moka::future::Cache;
     use reqwest::Client;
     use reqwest_middleware::ClientWithMiddleware;
+    use rsa::RsaPrivateKey;
+    use serde::Serialize;
     use serde_json::json;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    };
     use url::Url;
 
     /// Remove this conversion helper after actix-web upgrades to http 1.0
@@ -144,14 +544,666 @@ mod test {
         .await;
 
         match res {
-            Err(Error::ParseReceivedActivity(_, url)) => {
+            Err(Error::ParseReceivedActivity(e, url)) => {
                 assert_eq!(id, url.expect("has url").as_str());
+                assert_eq!(e.path, "type");
+                assert_eq!(e.kind.as_deref(), Some("Delete"));
+                assert_eq!(e.value.as_deref(), Some(r#""Delete""#));
             }
             _ => unreachable!(),
         }
     }
 
+    #[derive(Deserialize, Serialize, Debug)]
+    struct LargeNote<'a> {
+        actor: Url,
+        id: Url,
+        #[serde(borrow)]
+        content: &'a str,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct OwnedLargeNote {
+        content: String,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for LargeNote<'_> {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_borrowed() {
+        let (_, _, config) = setup_receive_test().await;
+        let data = config.to_request_data();
+        let content = "a".repeat(100 * 1024);
+        let activity = LargeNote {
+            actor: "http://localhost:123".parse().unwrap(),
+            id: "http://localhost:123/1".parse().unwrap(),
+            content: &content,
+        };
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let incoming_request = construct_request(&body, &activity.actor).await;
+
+        let (borrowed, _) = parse_received_activity_borrowed::<LargeNote, DbUser, _>(&body, &data)
+            .await
+            .unwrap();
+        let owned: OwnedLargeNote = serde_json::from_slice(&body).unwrap();
+        assert_eq!(borrowed.content, owned.content);
+        // content is not copied but points into the request body
+        assert!(body.as_ptr_range().contains(&borrowed.content.as_ptr()));
+
+        receive_activity_borrowed::<LargeNote, DbUser, DbConnection>(
+            &incoming_request.to_http_request(),
+            &body,
+            &data,
+        )
+        .await
+        .unwrap();
+    }
+
+    static SLOW_NOTE_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    #[derive(Deserialize, Serialize, Debug)]
+    struct SlowNote {
+        actor: Url,
+        id: Url,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for SlowNote {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            SLOW_NOTE_RECEIVED.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    async fn deferred_inbox(
+        request: HttpRequest,
+        body: Bytes,
+        data: Data<DbConnection>,
+    ) -> Result<HttpResponse, Error> {
+        let (activity, _) =
+            receive_activity_parts::<SlowNote, DbUser, DbConnection>(&request, &body, &data)
+                .await?;
+        let data = data.reset_request_count();
+        tokio::spawn(async move {
+            activity.verify(&data).await?;
+            activity.receive(&data).await
+        });
+        Ok(HttpResponse::Accepted().finish())
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_parts_deferred() {
+        let (_, _, config) = setup_receive_test().await;
+        let activity = SlowNote {
+            actor: "http://localhost:123".parse().unwrap(),
+            id: "http://localhost:123/1".parse().unwrap(),
+        };
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let incoming_request = construct_request(&body, &activity.actor).await;
+
+        let response = deferred_inbox(
+            incoming_request.to_http_request(),
+            body,
+            config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 202);
+        // response is sent before the activity is processed
+        assert!(!SLOW_NOTE_RECEIVED.load(Ordering::SeqCst));
+
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert!(SLOW_NOTE_RECEIVED.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_parts_invalid_signature() {
+        let (body, incoming_request, config) = setup_receive_test().await;
+        let incoming_request = incoming_request.uri("/wrong");
+        let err = receive_activity_parts::<Follow, DbUser, DbConnection>(
+            &incoming_request.to_http_request(),
+            &body,
+            &config.to_request_data(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err, Error::ActivitySignatureInvalid);
+    }
+
+    static COUNTED_NOTE_RECEIVES: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Deserialize, Serialize, Debug)]
+    struct CountedNote {
+        actor: Url,
+        id: Url,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for CountedNote {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            COUNTED_NOTE_RECEIVES.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_duplicate() {
+        let (_, _, mut config) = setup_receive_test().await;
+        config.received_activity_cache = Some(
+            Cache::builder()
+                .max_capacity(100)
+                .time_to_live(Duration::from_secs(60))
+                .build(),
+        );
+        let activity = CountedNote {
+            actor: "http://localhost:123".parse().unwrap(),
+            id: "http://localhost:123/1".parse().unwrap(),
+        };
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let incoming_request = construct_request(&body, &activity.actor).await;
+        let request = incoming_request.to_http_request();
+
+        let mut outcomes = vec![];
+        for _ in 0..2 {
+            let (response, outcome) =
+                receive_activity_outcome::<CountedNote, DbUser, DbConnection>(
+                    request.clone(),
+                    body.clone(),
+                    &config.to_request_data(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            outcomes.push(outcome);
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                ReceiveOutcome::Processed {
+                    activity_id: activity.id.clone()
+                },
+                ReceiveOutcome::DuplicateIgnored {
+                    activity_id: activity.id
+                }
+            ]
+        );
+        assert_eq!(COUNTED_NOTE_RECEIVES.load(Ordering::SeqCst), 1);
+    }
+
+    static RAW_NOTE_BYTES: Mutex<Option<Bytes>> = Mutex::new(None);
+
+    #[derive(Deserialize, Debug)]
+    struct RawNote {
+        actor: Url,
+        id: Url,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for RawNote {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            *RAW_NOTE_BYTES.lock().unwrap() = data.received_activity_bytes();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_received_activity_bytes() {
+        let (_, _, config) = setup_receive_test().await;
+        let actor: Url = "http://localhost:123".parse().unwrap();
+        let activity = json!({
+          "actor": actor,
+          "id": "http://localhost:123/1",
+          "content": "unknown field",
+          "tag": [{ "type": "Hashtag", "name": "#test" }]
+        });
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let incoming_request = construct_request(&body, &actor).await;
+        let data = config.to_request_data();
+
+        receive_activity::<RawNote, DbUser, DbConnection>(
+            incoming_request.to_http_request(),
+            body.clone(),
+            &data,
+        )
+        .await
+        .unwrap();
+        assert_eq!(RAW_NOTE_BYTES.lock().unwrap().as_ref(), Some(&body));
+        // only available while the activity is processed
+        assert!(data.received_activity_bytes().is_none());
+    }
+
+    static SHARED_NOTE_RECIPIENTS: Mutex<Vec<Url>> = Mutex::new(vec![]);
+
+    #[derive(Deserialize, Debug)]
+    struct SharedNote {
+        actor: Url,
+        id: Url,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for SharedNote {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            unreachable!("shared inbox calls receive_for")
+        }
+
+        async fn receive_for(
+            self,
+            recipients: Vec<Url>,
+            _data: &Data<Self::DataType>,
+        ) -> Result<(), Self::Error> {
+            *SHARED_NOTE_RECIPIENTS.lock().unwrap() = recipients;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_shared_activity() {
+        let (_, _, config) = setup_receive_test().await;
+        let actor: Url = "http://localhost:123".parse().unwrap();
+        let activity = json!({
+          "actor": actor,
+          "id": "http://localhost:123/1",
+          "to": "http://localhost:8002/u/alice",
+          "cc": [
+            "https://www.w3.org/ns/activitystreams#Public",
+            "https://other.example/u/carol",
+            "http://localhost:8002/u/bob",
+            "http://localhost:8002/u/alice",
+            { "type": "Person", "id": "http://localhost:8002/u/dave" }
+          ]
+        });
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let incoming_request = construct_request(&body, &actor).await;
+
+        receive_shared_activity::<SharedNote, DbUser, DbConnection>(
+            incoming_request.to_http_request(),
+            body,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        let expected: Vec<Url> = vec![
+            "http://localhost:8002/u/alice".parse().unwrap(),
+            "http://localhost:8002/u/bob".parse().unwrap(),
+        ];
+        assert_eq!(*SHARED_NOTE_RECIPIENTS.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_outcome() {
+        let (body, incoming_request, config) = setup_receive_test().await;
+        let (response, outcome) = receive_activity_outcome::<Follow, DbUser, DbConnection>(
+            incoming_request.to_http_request(),
+            body,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            ReceiveOutcome::Processed {
+                activity_id: "http://localhost:123/1".parse().unwrap()
+            }
+        );
+        // header is disabled by default
+        assert!(response.headers().get(FEDERATION_RESULT_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_outcome_header() {
+        let (body, incoming_request, mut config) = setup_receive_test().await;
+        config.federation_result_header = true;
+        let response = receive_activity::<Follow, DbUser, DbConnection>(
+            incoming_request.to_http_request(),
+            body,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        let header = response.headers().get(FEDERATION_RESULT_HEADER).unwrap();
+        assert_eq!(header, "processed");
+    }
+
+    struct RejectFollowFilter;
+
+    #[async_trait::async_trait]
+    impl ActivityFilter for RejectFollowFilter {
+        async fn accept(&self, _activity_id: &Url, actor: &Url, kind: Option<&str>) -> bool {
+            assert_eq!(actor.as_str(), "http://localhost:123/");
+            kind != Some("Follow")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_filtered() {
+        let (body, incoming_request, mut config) = setup_receive_test().await;
+        config.activity_filter = Some(Arc::new(RejectFollowFilter));
+        config.federation_result_header = true;
+        let (response, outcome) = receive_activity_outcome::<Follow, DbUser, DbConnection>(
+            incoming_request.to_http_request(),
+            body,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, ReceiveOutcome::FilteredByHook);
+        let header = response.headers().get(FEDERATION_RESULT_HEADER).unwrap();
+        assert_eq!(header, "filtered");
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct OnlyNote {
+        actor: Url,
+        id: Url,
+    }
+
+    #[async_trait::async_trait]
+    impl ActivityHandler for OnlyNote {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            unreachable!("activities with unknown type are not verified")
+        }
+
+        async fn receive(self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            unreachable!("activities with unknown type are not received")
+        }
+
+        fn handles_kind(kind: &str) -> bool {
+            kind == "Note"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_unknown_type() {
+        let (_, _, mut config) = setup_receive_test().await;
+        config.federation_result_header = true;
+        let actor: Url = "http://localhost:123".parse().unwrap();
+        let activity = json!({
+          "actor": actor,
+          "id": "http://localhost:123/1",
+          "type": "Flag",
+        });
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let request = construct_request(&body, &actor).await.to_http_request();
+        let data = config.to_request_data();
+
+        let (response, outcome) = receive_activity_outcome::<OnlyNote, DbUser, DbConnection>(
+            request.clone(),
+            body.clone(),
+            &data,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome,
+            ReceiveOutcome::UnknownTypeIgnored {
+                kind: "Flag".to_string()
+            }
+        );
+        let header = response.headers().get(FEDERATION_RESULT_HEADER).unwrap();
+        assert_eq!(header, "unknown-type");
+
+        let response =
+            receive_shared_activity::<OnlyNote, DbUser, DbConnection>(request, body, &data)
+                .await
+                .unwrap();
+        let header = response.headers().get(FEDERATION_RESULT_HEADER).unwrap();
+        assert_eq!(header, "unknown-type");
+    }
+
+    #[test]
+    fn test_receive_outcome_header_value() {
+        let activity_id: Url = "http://localhost:123/1".parse().unwrap();
+        let outcomes = [
+            (
+                ReceiveOutcome::Processed {
+                    activity_id: activity_id.clone(),
+                },
+                "processed",
+            ),
+            (
+                ReceiveOutcome::DuplicateIgnored { activity_id },
+                "duplicate",
+            ),
+            (ReceiveOutcome::FilteredByHook, "filtered"),
+            (
+                ReceiveOutcome::UnknownTypeIgnored {
+                    kind: "Flag".to_string(),
+                },
+                "unknown-type",
+            ),
+        ];
+        for (outcome, value) in outcomes {
+            assert_eq!(outcome.header_value(), value);
+        }
+    }
+
+    async fn extract_activity(
+        request: TestRequest,
+        body: Bytes,
+        config: &FederationConfig<DbConnection>,
+    ) -> Result<VerifiedActivity<Follow, DbUser>, actix_web::Error> {
+        let (request, mut payload) = request.set_payload(body).to_http_parts();
+        request.extensions_mut().insert(config.clone());
+        VerifiedActivity::from_request(&request, &mut payload).await
+    }
+
+    #[tokio::test]
+    async fn test_verified_activity() {
+        let (body, incoming_request, config) = setup_receive_test().await;
+        let activity = extract_activity(incoming_request, body.clone(), &config)
+            .await
+            .unwrap();
+        assert_eq!(activity.actor().federation_id, DB_USER.federation_id);
+        assert_eq!(activity.activity().id.as_str(), "http://localhost:123/1");
+        assert_eq!(activity.body(), &body);
+        activity.receive(&config.to_request_data()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_data() {
+        let (body, incoming_request, config) = setup_receive_test().await;
+        let (request, mut payload) = incoming_request
+            .set_payload(body.clone())
+            .to_http_parts();
+        let activity_data = ActivityData::from_request(&request, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(activity_data.body(), &body);
+        let res = receive_activity_data::<Follow, DbUser, DbConnection>(
+            activity_data,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), 200);
+
+        // body size limit from middleware is applied
+        let (_, incoming_request, _) = setup_receive_test().await;
+        let (request, mut payload) = incoming_request.set_payload(body).to_http_parts();
+        request.extensions_mut().insert(IncomingBodyLimit(10));
+        let err = ActivityData::from_request(&request, &mut payload)
+            .await
+            .unwrap_err();
+        assert_eq!(err.as_error::<Error>(), Some(&Error::RequestBodyLimit));
+    }
+
+    #[tokio::test]
+    async fn test_verified_activity_invalid_digest() {
+        let (_, incoming_request, config) = setup_receive_test().await;
+        let err = extract_activity(incoming_request, "invalid".into(), &config)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.as_error::<Error>(),
+            Some(&Error::ActivityBodyDigestInvalid)
+        );
+        assert_eq!(err.as_response_error().status_code(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_verified_activity_invalid_signature() {
+        let (body, incoming_request, config) = setup_receive_test().await;
+        let incoming_request = incoming_request.uri("/wrong");
+        let err = extract_activity(incoming_request, body, &config)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.as_error::<Error>(),
+            Some(&Error::ActivitySignatureInvalid)
+        );
+        assert_eq!(err.as_response_error().status_code(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_body_limit() {
+        let (_, _, mut config) = setup_receive_test().await;
+        config.max_incoming_body_size = 1024;
+        let activity = LargeNote {
+            actor: "http://localhost:123".parse().unwrap(),
+            id: "http://localhost:123/1".parse().unwrap(),
+            content: &"a".repeat(2048),
+        };
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let incoming_request = construct_request(&body, &activity.actor).await;
+
+        let err = receive_activity::<Follow, DbUser, DbConnection>(
+            incoming_request.to_http_request(),
+            body.clone(),
+            &config.to_request_data(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err, Error::RequestBodyLimit);
+        assert_eq!(err.status_code(), 413);
+
+        // The extractor rejects it before verifying the signature
+        let incoming_request = construct_request(&body, &activity.actor).await;
+        let err = extract_activity(incoming_request, body, &config)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.as_error::<Error>(), Some(&Error::RequestBodyLimit));
+        assert_eq!(err.as_response_error().status_code(), 413);
+    }
+
+    #[tokio::test]
+    async fn test_verified_activity_error_handler() {
+        let (_, incoming_request, config) = setup_receive_test().await;
+        let extractor_config = ExtractorConfig::default()
+            .error_handler(|err, _| actix_web::error::ErrorForbidden(err.to_string()));
+        let incoming_request = incoming_request.app_data(extractor_config);
+        let err = extract_activity(incoming_request, "invalid".into(), &config)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.as_response_error().status_code(), 403);
+    }
+
     async fn construct_request(body: &Bytes, actor: &Url) -> TestRequest {
+        construct_request_with_key(body, actor, DB_USER_KEYPAIR.private_key().unwrap()).await
+    }
+
+    async fn construct_request_with_key(
+        body: &Bytes,
+        actor: &Url,
+        private_key: RsaPrivateKey,
+    ) -> TestRequest {
         let inbox = "https://example.com/inbox";
         let headers = generate_request_headers(&Url::parse(inbox).unwrap());
         let request_builder = ClientWithMiddleware::from(Client::default())
@@ -159,10 +1211,13 @@ mod test {
             .headers(headers);
         let outgoing_request = sign_request(
             request_builder,
-            actor,
+            main_key_id(actor),
             body.clone(),
-            DB_USER_KEYPAIR.private_key().unwrap(),
+            private_key,
             false,
+            false,
+            false,
+            &Default::default(),
         )
         .await
         .unwrap();
@@ -173,6 +1228,110 @@ mod test {
         incoming_request
     }
 
+    /// Serves an actor with the public key of [test_keypair] on a random port, and counts how often
+    /// it was fetched. The stored version of the actor has the key of [DB_USER_KEYPAIR].
+    async fn serve_rotated_actor() -> (Url, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let actor_id = Url::parse(&format!("http://localhost:{port}/u/alice")).unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        let mut actor = DB_USER.clone();
+        actor.federation_id = actor_id.clone();
+        actor.public_key = test_keypair().public_key;
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let json = serde_json::to_string(&actor.into_json(&data).await.unwrap()).unwrap();
+        let counter = fetches.clone();
+        let app = Router::new().route(
+            "/u/alice",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                ([("Content-Type", FEDERATION_CONTENT_TYPE)], json)
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (actor_id, fetches)
+    }
+
+    async fn follow_from(actor_id: &Url, private_key: RsaPrivateKey) -> (Bytes, TestRequest) {
+        let activity = Follow {
+            actor: actor_id.clone().into(),
+            object: ObjectId::parse("http://localhost:124").unwrap(),
+            kind: Default::default(),
+            id: actor_id.join("/activity/1").unwrap(),
+        };
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let request = construct_request_with_key(&body, actor_id, private_key).await;
+        (body, request)
+    }
+
+    async fn rotation_config() -> Data<DbConnection> {
+        FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data()
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_rotated_key() {
+        let (actor_id, fetches) = serve_rotated_actor().await;
+        let data = rotation_config().await;
+
+        // Signed with the new key, so the actor is refetched once and the signature verified
+        let (body, request) = follow_from(&actor_id, test_keypair().private_key().unwrap()).await;
+        receive_activity::<Follow, DbUser, DbConnection>(request.to_http_request(), body, &data)
+            .await
+            .unwrap();
+        assert_eq!(1, fetches.load(Ordering::SeqCst));
+
+        // Actor was refetched recently, so another mismatch doesn't cause a new fetch
+        let (body, request) = follow_from(
+            &actor_id,
+            generate_actor_keypair().unwrap().private_key().unwrap(),
+        )
+        .await;
+        let err = receive_activity::<Follow, DbUser, DbConnection>(
+            request.to_http_request(),
+            body,
+            &data,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(Error::ActivitySignatureInvalid, err);
+        assert_eq!(1, fetches.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_invalid_key_refetch_limit() {
+        let (actor_id, fetches) = serve_rotated_actor().await;
+        let data = rotation_config().await;
+
+        // Neither the stored nor the fetched key match, the actor is only refetched once
+        let private_key = generate_actor_keypair().unwrap().private_key().unwrap();
+        for _ in 0..3 {
+            let (body, request) = follow_from(&actor_id, private_key.clone()).await;
+            let err = receive_activity::<Follow, DbUser, DbConnection>(
+                request.to_http_request(),
+                body,
+                &data,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(Error::ActivitySignatureInvalid, err);
+        }
+        assert_eq!(1, fetches.load(Ordering::SeqCst));
+    }
+
     async fn setup_receive_test() -> (Bytes, TestRequest, FederationConfig<DbConnection>) {
         let activity = Follow {
             actor: ObjectId::parse("http://localhost:123").unwrap(),
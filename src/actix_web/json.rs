@@ -0,0 +1,162 @@
+//! Wrapper struct to respond with `application/activity+json` in actix-web handlers
+//!
+//! ```
+//! # use anyhow::Error;
+//! # use actix_web::web::Path;
+//! # use activitypub_federation::actix_web::json::FederationJson;
+//! # use activitypub_federation::protocol::context::WithContext;
+//! # use activitypub_federation::config::Data;
+//! # use activitypub_federation::traits::Object;
+//! # use activitypub_federation::traits::tests::{DbConnection, DbUser, Person};
+//! async fn http_get_user(name: Path<String>, data: Data<DbConnection>) -> Result<FederationJson<WithContext<Person>>, Error> {
+//!     let user: DbUser = data.read_local_user(&name).await?;
+//!     let person = user.into_json(&data).await?;
+//!
+//!     Ok(FederationJson(WithContext::new_default(person)))
+//! }
+//! ```
+
+use crate::{
+    fetch::nodeinfo::{NodeInfo, NodeInfoWellKnown},
+    instance_actor::Application,
+    protocol::{
+        collections::{OrderedCollection, OrderedCollectionPage},
+        context::WithContext,
+        tombstone::Tombstone,
+    },
+    FEDERATION_CONTENT_TYPE,
+};
+use actix_web::{body::BoxBody, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+/// Wrapper struct to respond with `application/activity+json` in actix-web handlers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FederationJson<Json: Serialize>(pub Json);
+
+impl<Json: Serialize> Responder for FederationJson<Json> {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type(FEDERATION_CONTENT_TYPE)
+            .json(self.0)
+    }
+}
+
+/// Responds with the collection and the default context
+impl Responder for OrderedCollection {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        FederationJson(WithContext::new_default(self)).respond_to(req)
+    }
+}
+
+/// Responds with the collection page and the default context
+impl Responder for OrderedCollectionPage {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        FederationJson(WithContext::new_default(self)).respond_to(req)
+    }
+}
+
+/// Responds with the instance actor and the default context
+impl Responder for Application {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        FederationJson(WithContext::new_default(self)).respond_to(req)
+    }
+}
+
+/// Responds with the tombstone of a deleted object and status `410 Gone`, so that
+/// [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference) on other instances
+/// fails with [Error::ObjectDeleted](crate::error::Error::ObjectDeleted).
+pub fn tombstone_response(tombstone: Tombstone) -> HttpResponse {
+    HttpResponse::Gone()
+        .content_type(FEDERATION_CONTENT_TYPE)
+        .json(WithContext::new_default(tombstone))
+}
+
+/// Responds with `application/json`
+impl Responder for NodeInfoWellKnown {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().json(self)
+    }
+}
+
+/// Responds with `application/json` and the schema version as profile
+impl Responder for NodeInfo {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type(self.content_type())
+            .json(self)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::protocol::collections::OrderedCollectionPager;
+    use actix_web::{body::MessageBody, http::header, test::TestRequest};
+    use serde_json::Value;
+    use url::Url;
+
+    #[test]
+    fn test_collection_response() {
+        let id = Url::parse("https://example.com/outbox").unwrap();
+        let collection = OrderedCollectionPager::new(id, 3, 10).collection();
+        let request = TestRequest::default().to_http_request();
+        let response = collection.respond_to(&request);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            FEDERATION_CONTENT_TYPE
+        );
+        let body = response.into_body().try_into_bytes().unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["@context"], "https://www.w3.org/ns/activitystreams");
+        assert_eq!(json["type"], "OrderedCollection");
+        assert_eq!(json["totalItems"], 3);
+    }
+
+    #[test]
+    fn test_nodeinfo_response() {
+        let request = TestRequest::default().to_http_request();
+        let response = NodeInfo::new("lemmy", "0.19.5").respond_to(&request);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json; profile=\"http://nodeinfo.diaspora.software/ns/schema/2.1#\""
+        );
+        let body = response.into_body().try_into_bytes().unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["software"]["name"], "lemmy");
+
+        let href = Url::parse("https://example.com/nodeinfo/2.1").unwrap();
+        let response = NodeInfoWellKnown::new(href, "2.1").respond_to(&request);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_tombstone_response() {
+        let id = Url::parse("https://example.com/post/1").unwrap();
+        let response = tombstone_response(Tombstone::new(id).with_former_type("Note"));
+        assert_eq!(response.status(), actix_web::http::StatusCode::GONE);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            FEDERATION_CONTENT_TYPE
+        );
+        let body = response.into_body().try_into_bytes().unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["type"], "Tombstone");
+        assert_eq!(json["formerType"], "Note");
+    }
+}
@@ -1,6 +1,7 @@
-use crate::config::{Data, FederationConfig, FederationMiddleware};
+use crate::config::{Data, FederationConfig, FederationMiddleware, IncomingBodyLimit};
 use actix_web::{
     dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::HOST, uri::Authority},
     Error,
     FromRequest,
     HttpMessage,
@@ -24,7 +25,7 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(FederationService {
             service,
-            config: self.0.clone(),
+            middleware: self.clone(),
         }))
     }
 }
@@ -38,7 +39,7 @@ where
     T: Sync,
 {
     service: S,
-    config: FederationConfig<T>,
+    middleware: FederationMiddleware<T>,
 }
 
 impl<S, B, T> Service<ServiceRequest> for FederationService<S, T>
@@ -55,7 +56,15 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        req.extensions_mut().insert(self.config.clone());
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or(req.uri().authority().map(Authority::as_str));
+        let config = self.middleware.config_for_host(host).clone();
+        req.extensions_mut()
+            .insert(IncomingBodyLimit(config.max_incoming_body_size));
+        req.extensions_mut().insert(config);
 
         self.service.call(req)
     }
@@ -74,3 +83,96 @@ impl<T: Clone + 'static> FromRequest for Data<T> {
         })
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        extract_local_recipients,
+        fetch::webfinger::extract_webfinger_name,
+        traits::tests::DbConnection,
+    };
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web,
+        App,
+        HttpResponse,
+    };
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    async fn config(domain: &str) -> FederationConfig<DbConnection> {
+        FederationConfig::builder()
+            .domain(domain)
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    async fn webfinger(
+        query: web::Query<HashMap<String, String>>,
+        data: Data<DbConnection>,
+    ) -> HttpResponse {
+        match extract_webfinger_name(&query["resource"], &data) {
+            Ok(name) => HttpResponse::Ok().body(name.to_string()),
+            Err(_) => HttpResponse::NotFound().body(data.domain().to_string()),
+        }
+    }
+
+    async fn inbox(body: web::Bytes, data: Data<DbConnection>) -> String {
+        extract_local_recipients(&body, &data).len().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_multiple_domains() {
+        let alpha = config("alpha.example").await;
+        let configs = HashMap::from([
+            ("alpha.example".to_string(), alpha.clone()),
+            ("Beta.Example".to_string(), config("beta.example").await),
+        ]);
+        let app = init_service(
+            App::new()
+                .route("/.well-known/webfinger", web::get().to(webfinger))
+                .route("/inbox", web::post().to(inbox))
+                .wrap(FederationMiddleware::new_multi(configs, alpha)),
+        )
+        .await;
+        let call = |request: TestRequest| {
+            let app = &app;
+            async move {
+                let response = call_service(app, request.to_request()).await;
+                let status = response.status().as_u16();
+                let body = read_body(response).await;
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let webfinger = |host: &str| {
+            TestRequest::get()
+                .uri("/.well-known/webfinger?resource=acct:alice@beta.example")
+                .insert_header((HOST, host))
+        };
+        let ok = (200, "alice".to_string());
+        assert_eq!(call(webfinger("beta.example")).await, ok);
+        assert_eq!(call(webfinger("BETA.example:8080")).await, ok);
+        let not_found = (404, "alpha.example".to_string());
+        assert_eq!(call(webfinger("alpha.example")).await, not_found);
+        // Unknown hosts use the default config
+        assert_eq!(call(webfinger("other.example")).await, not_found);
+
+        let activity = json!({
+            "to": ["https://alpha.example/u/alice", "https://beta.example/u/bob"],
+            "cc": ["https://beta.example/u/carol"],
+        });
+        let inbox = |host: &str| {
+            TestRequest::post()
+                .uri("/inbox")
+                .insert_header((HOST, host))
+                .set_payload(activity.to_string())
+        };
+        assert_eq!(call(inbox("alpha.example")).await.1, "1");
+        assert_eq!(call(inbox("beta.example")).await.1, "2");
+    }
+}
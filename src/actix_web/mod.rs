@@ -2,8 +2,10 @@
 
 mod http_compat;
 pub mod inbox;
+pub mod json;
 #[doc(hidden)]
 pub mod middleware;
+pub mod sign_responses;
 
 use crate::{
     config::Data,
@@ -11,8 +13,17 @@ use crate::{
     http_signatures::{self, verify_body_hash},
     traits::{Actor, Object},
 };
-use actix_web::{web::Bytes, HttpRequest};
+use actix_web::{
+    dev::Payload,
+    http::StatusCode,
+    web::Bytes,
+    FromRequest,
+    HttpRequest,
+    ResponseError,
+};
+use futures::future::LocalBoxFuture;
 use serde::Deserialize;
+use std::{ops::Deref, sync::Arc};
 
 /// Checks whether the request is signed by an actor of type A, and returns
 /// the actor in question if a valid signature is found.
@@ -26,14 +37,99 @@ where
     <A as Object>::Error: From<Error>,
     for<'de2> <A as Object>::Kind: Deserialize<'de2>,
 {
-    let digest_header = request
-        .headers()
-        .get("Digest")
-        .map(http_compat::header_value);
-    verify_body_hash(digest_header.as_ref(), &body.unwrap_or_default())?;
-
     let headers = http_compat::header_map(request.headers());
+    verify_body_hash(&headers, &body.unwrap_or_default())?;
+
     let method = http_compat::method(request.method());
     let uri = http_compat::uri(request.uri());
     http_signatures::signing_actor(&headers, &method, &uri, data).await
 }
+
+/// Extractor which checks that the request is signed by an actor of type A, using
+/// [signing_actor]. Meant for requests without body such as GET, the body is not verified.
+///
+/// Errors are converted to responses as configured with [ExtractorConfig].
+pub struct SignedActor<A>(pub A);
+
+impl<A> SignedActor<A> {
+    /// Returns the actor which signed the request
+    pub fn into_inner(self) -> A {
+        self.0
+    }
+}
+
+impl<A> Deref for SignedActor<A> {
+    type Target = A;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<A> FromRequest for SignedActor<A>
+where
+    A: Object + Actor + 'static,
+    <A as Object>::DataType: 'static,
+    <A as Object>::Error: From<Error> + ResponseError + 'static,
+    for<'de2> <A as Object>::Kind: Deserialize<'de2>,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let data = Data::<<A as Object>::DataType>::from_request(req, payload).into_inner();
+        let request = req.clone();
+        Box::pin(async move {
+            let data = data.map_err(|err| ExtractorConfig::map_error(&request, err))?;
+            signing_actor::<A>(&request, None, &data)
+                .await
+                .map(SignedActor)
+                .map_err(|err| ExtractorConfig::map_error(&request, err))
+        })
+    }
+}
+
+type ErrorHandler = Arc<dyn Fn(actix_web::Error, &HttpRequest) -> actix_web::Error + Send + Sync>;
+
+/// Configuration for the extractors [SignedActor], [inbox::VerifiedActivity] and
+/// [inbox::ActivityData], which is
+/// registered with [App::app_data](actix_web::App::app_data).
+///
+/// By default errors are converted to responses with their [ResponseError] implementation. For
+/// errors of this library the status code is given by [Error::status_code].
+#[derive(Clone, Default)]
+pub struct ExtractorConfig {
+    error_handler: Option<ErrorHandler>,
+}
+
+impl ExtractorConfig {
+    /// Set a function which converts errors of the extractors into responses. The original
+    /// error can be accessed with [actix_web::Error::as_error].
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(actix_web::Error, &HttpRequest) -> actix_web::Error + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    pub(crate) fn map_error(
+        request: &HttpRequest,
+        err: impl Into<actix_web::Error>,
+    ) -> actix_web::Error {
+        let err = err.into();
+        match request
+            .app_data::<Self>()
+            .and_then(|config| config.error_handler.as_ref())
+        {
+            Some(handler) => handler(err, request),
+            None => err,
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        http_compat::status_code_02(Error::status_code(self))
+    }
+}
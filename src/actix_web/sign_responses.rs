@@ -0,0 +1,187 @@
+//! Middleware which adds HTTP signatures to Activitypub responses
+//!
+//! ```
+//! # use activitypub_federation::actix_web::sign_responses::SignResponses;
+//! # use activitypub_federation::traits::tests::DB_USER;
+//! # use actix_web::{web, App, HttpResponse};
+//! let app = App::new()
+//!     .route("/u/alice", web::get().to(HttpResponse::Ok))
+//!     .wrap(SignResponses::new(&*DB_USER)?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use super::http_compat;
+use crate::{
+    error::Error,
+    http_signatures::{is_activitypub_response, ResponseSigner},
+    protocol::public_key::KeyIdStrategy,
+    traits::Actor,
+};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorInternalServerError,
+    http::header::{HeaderName, DATE},
+};
+use futures::future::LocalBoxFuture;
+use std::{
+    future::{ready, Ready},
+    sync::Arc,
+};
+use tracing::warn;
+
+/// Signs all responses with content type `application/activity+json` or `application/ld+json`
+/// using the private key of the given actor. Other responses are passed through unchanged.
+///
+/// The signature covers the request method and path. Signatures can be checked with
+/// [FetchObjectResponse::verify_response_signature](crate::fetch::FetchObjectResponse::verify_response_signature).
+#[derive(Clone)]
+pub struct SignResponses(Arc<ResponseSigner>);
+
+impl SignResponses {
+    /// Sign responses with the private key of `actor`, which must be present. The key id is
+    /// `{actor_id}#main-key`.
+    pub fn new<A: Actor>(actor: &A) -> Result<Self, Error> {
+        Ok(SignResponses(Arc::new(ResponseSigner::new(actor)?)))
+    }
+
+    /// Use a different key id strategy, see
+    /// [FederationConfigBuilder::key_id_strategy](crate::config::FederationConfigBuilder::key_id_strategy).
+    pub fn key_id_strategy(mut self, strategy: &KeyIdStrategy) -> Self {
+        Arc::make_mut(&mut self.0).set_key_id_strategy(strategy);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SignResponses
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = SignResponsesService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SignResponsesService {
+            service,
+            signer: self.0.clone(),
+        }))
+    }
+}
+
+/// Service created by [SignResponses]
+#[doc(hidden)]
+pub struct SignResponsesService<S> {
+    service: S,
+    signer: Arc<ResponseSigner>,
+}
+
+impl<S, B> Service<ServiceRequest> for SignResponsesService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let method = http_compat::method(req.method());
+        let uri = http_compat::uri(req.uri());
+        let signer = self.signer.clone();
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let response = future.await?;
+            let mut headers = http_compat::header_map(response.headers());
+            if !is_activitypub_response(&headers) {
+                return Ok(response.map_into_boxed_body());
+            }
+            let (request, response) = response.into_parts();
+            let (response, body) = response.into_parts();
+            let body = actix_web::body::to_bytes(body).await.map_err(|e| {
+                let e: Box<dyn std::error::Error> = e.into();
+                ErrorInternalServerError(e.to_string())
+            })?;
+            let mut response = response.set_body(body.clone()).map_into_boxed_body();
+            match signer.sign(&method, &uri, &mut headers, &body) {
+                Ok(()) => {
+                    for name in ["digest", "signature", DATE.as_str()] {
+                        if let Some(value) = headers.get(name) {
+                            response.headers_mut().insert(
+                                HeaderName::from_static(name),
+                                http_compat::header_value_02(value),
+                            );
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to sign response: {e}"),
+            }
+            Ok(ServiceResponse::new(request, response))
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_signatures::verify_response_signature,
+        traits::tests::{DB_USER, DB_USER_KEYPAIR},
+        FEDERATION_CONTENT_TYPE,
+    };
+    use actix_web::{
+        http::header::CONTENT_TYPE,
+        test::{call_service, init_service, read_body, TestRequest},
+        web,
+        App,
+        HttpResponse,
+    };
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_sign_responses() -> Result<(), Error> {
+        let app = init_service(
+            App::new()
+                .route(
+                    "/objects/1",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .content_type(FEDERATION_CONTENT_TYPE)
+                            .body(r#"{"type":"Note"}"#)
+                    }),
+                )
+                .route("/html", web::get().to(|| async { "not activitypub" }))
+                .wrap(SignResponses::new(&*DB_USER)?),
+        )
+        .await;
+
+        let request = TestRequest::get().uri("/objects/1").to_request();
+        let response = call_service(&app, request).await;
+        let headers = http_compat::header_map(response.headers());
+        let body = read_body(response).await;
+        let url = Url::parse("http://example.com/objects/1")?;
+        verify_response_signature(
+            &http::Method::GET,
+            &url,
+            &headers,
+            &body,
+            &DB_USER_KEYPAIR.public_key,
+        )?;
+        assert!(headers
+            .get(CONTENT_TYPE.as_str())
+            .is_some_and(|c| c == FEDERATION_CONTENT_TYPE));
+
+        let request = TestRequest::get().uri("/html").to_request();
+        let response = call_service(&app, request).await;
+        assert!(!response.headers().contains_key("signature"));
+        Ok(())
+    }
+}
@@ -3,11 +3,15 @@
 #![doc = include_str!("../../docs/08_receiving_activities.md")]
 
 use crate::{
-    config::Data,
+    config::{Data, IncomingBodyLimit, DEFAULT_MAX_INCOMING_BODY_SIZE},
     error::Error,
-    http_signatures::verify_signature,
-    parse_received_activity,
+    extract_local_recipients,
+    ignore_unknown_kind,
+    inbox,
+    process_received_activity,
     traits::{ActivityHandler, Actor, Object},
+    ReceiveOutcome,
+    FEDERATION_RESULT_HEADER,
 };
 use axum::{
     async_trait,
@@ -16,9 +20,11 @@ use axum::{
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
 };
-use http::{HeaderMap, Method, Uri};
-use serde::de::DeserializeOwned;
-use tracing::debug;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use http::{header::CONTENT_LENGTH, HeaderMap, Method, Uri};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
 
 /// Handles incoming activities, verifying HTTP signatures and other checks
 pub async fn receive_activity<Activity, ActorT, Datatype>(
@@ -33,20 +39,182 @@ where
     <ActorT as Object>::Error: From<Error>,
     Datatype: Clone,
 {
-    let (activity, actor) =
-        parse_received_activity::<Activity, ActorT, _>(&activity_data.body, data).await?;
+    receive_activity_borrowed::<Activity, ActorT, Datatype>(&activity_data, data).await
+}
+
+/// Same as [receive_activity], but the activity may borrow from the request body instead of
+/// copying it, for example using `&str` fields with `#[serde(borrow)]`.
+///
+/// This avoids allocations for large incoming activities. The activity is fully processed before
+/// this function returns, so it can't outlive the body.
+pub async fn receive_activity_borrowed<'a, Activity, ActorT, Datatype>(
+    activity_data: &'a ActivityData,
+    data: &Data<Datatype>,
+) -> Result<(), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    receive_activity_outcome_borrowed::<Activity, ActorT, Datatype>(activity_data, data).await?;
+    Ok(())
+}
+
+/// Same as [receive_activity], but additionally returns how the activity was handled. This
+/// allows the application to log or count ignored activities, and helps with testing.
+///
+/// The returned response contains the [FEDERATION_RESULT_HEADER] if enabled with
+/// [FederationConfigBuilder::federation_result_header](crate::config::FederationConfigBuilder::federation_result_header).
+pub async fn receive_activity_outcome<Activity, ActorT, Datatype>(
+    activity_data: ActivityData,
+    data: &Data<Datatype>,
+) -> Result<(Response, ReceiveOutcome), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    let outcome =
+        receive_activity_outcome_borrowed::<Activity, ActorT, Datatype>(&activity_data, data)
+            .await?;
+    Ok((outcome_response(&outcome, data), outcome))
+}
+
+/// Response for a received activity, with [FEDERATION_RESULT_HEADER] if enabled
+fn outcome_response<T: Clone>(outcome: &ReceiveOutcome, data: &Data<T>) -> Response {
+    if data.config.federation_result_header {
+        [(FEDERATION_RESULT_HEADER, outcome.header_value())].into_response()
+    } else {
+        StatusCode::OK.into_response()
+    }
+}
+
+async fn receive_activity_outcome_borrowed<'a, Activity, ActorT, Datatype>(
+    activity_data: &'a ActivityData,
+    data: &Data<Datatype>,
+) -> Result<ReceiveOutcome, <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    if let Some(outcome) = ignore_unknown_kind::<Activity, _>(&activity_data.body, data) {
+        return Ok(outcome);
+    }
+    let (activity, _actor) =
+        verify_activity_data::<Activity, ActorT, Datatype>(activity_data, data).await?;
+    process_received_activity(activity, activity_data.body.clone(), data, |a| {
+        a.receive(data)
+    })
+    .await
+}
+
+/// Handles activities which are delivered to a shared inbox, such as `/inbox`.
+///
+/// Works like [receive_activity], but after verification the local actors which are addressed
+/// in the `to`, `cc`, `bto`, `bcc` or `audience` fields are passed to
+/// [ActivityHandler::receive_for]. This way the application can handle the activity for each
+/// local recipient, for example to add a post to the timeline of every follower.
+///
+/// The returned response contains the [FEDERATION_RESULT_HEADER] if enabled with
+/// [FederationConfigBuilder::federation_result_header](crate::config::FederationConfigBuilder::federation_result_header).
+pub async fn receive_shared_activity<Activity, ActorT, Datatype>(
+    activity_data: ActivityData,
+    data: &Data<Datatype>,
+) -> Result<Response, <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    if let Some(outcome) = ignore_unknown_kind::<Activity, _>(&activity_data.body, data) {
+        return Ok(outcome_response(&outcome, data));
+    }
+    let (activity, _actor) =
+        verify_activity_data::<Activity, ActorT, Datatype>(&activity_data, data).await?;
+    let recipients = extract_local_recipients(&activity_data.body, data);
+    let outcome = process_received_activity(activity, activity_data.body, data, |a| {
+        a.receive_for(recipients, data)
+    })
+    .await?;
+    Ok(outcome_response(&outcome, data))
+}
+
+/// Verifies the HTTP signature of an incoming activity, and returns it together with the signing
+/// actor without processing it.
+///
+/// This allows responding with `202 Accepted` immediately and processing the activity later, so
+/// that a slow [ActivityHandler::receive] doesn't hold the connection of the sending server open
+/// until it times out and retries. The caller is responsible for calling [ActivityHandler::verify]
+/// and [ActivityHandler::receive], for example in a background task:
+///
+/// ```
+/// # use activitypub_federation::axum::inbox::{receive_activity_parts, ActivityData};
+/// # use activitypub_federation::config::Data;
+/// # use activitypub_federation::error::Error;
+/// # use activitypub_federation::traits::ActivityHandler;
+/// # use activitypub_federation::traits::tests::{DbConnection, DbUser, Follow};
+/// # use axum::http::StatusCode;
+/// async fn inbox(data: Data<DbConnection>, activity_data: ActivityData) -> Result<StatusCode, Error> {
+///     let (activity, _actor) =
+///         receive_activity_parts::<Follow, DbUser, DbConnection>(activity_data, &data).await?;
+///     let data = data.reset_request_count();
+///     tokio::spawn(async move {
+///         activity.verify(&data).await?;
+///         activity.receive(&data).await
+///     });
+///     Ok(StatusCode::ACCEPTED)
+/// }
+/// ```
+pub async fn receive_activity_parts<Activity, ActorT, Datatype>(
+    activity_data: ActivityData,
+    data: &Data<Datatype>,
+) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    verify_activity_data::<Activity, ActorT, Datatype>(&activity_data, data).await
+}
 
-    verify_signature(
+/// Checks the body digest, parses the activity, fetches the actor and verifies the signature.
+async fn verify_activity_data<'a, Activity, ActorT, Datatype>(
+    activity_data: &'a ActivityData,
+    data: &Data<Datatype>,
+) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    inbox::verify_activity::<Activity, ActorT, _>(
         &activity_data.headers,
         &activity_data.method,
         &activity_data.uri,
-        actor.public_key_pem(),
-    )?;
-
-    debug!("Receiving activity {}", activity.id().to_string());
-    activity.verify(data).await?;
-    activity.receive(data).await?;
-    Ok(())
+        &activity_data.body,
+        &activity_data.body_digest,
+        data,
+    )
+    .await
 }
 
 /// Contains all data that is necessary to receive an activity from an HTTP request
@@ -55,7 +223,16 @@ pub struct ActivityData {
     headers: HeaderMap,
     method: Method,
     uri: Uri,
-    body: Vec<u8>,
+    body: Bytes,
+    body_digest: [u8; 32],
+}
+
+impl ActivityData {
+    /// SHA-256 hash of the request body. It is computed while reading the body, and compared
+    /// against the `Digest` header when the activity is received.
+    pub fn body_digest(&self) -> &[u8] {
+        &self.body_digest
+    }
 }
 
 #[async_trait]
@@ -67,19 +244,233 @@ where
 
     async fn from_request(req: Request<Body>, _state: &S) -> Result<Self, Self::Rejection> {
         let (parts, body) = req.into_parts();
+        let limit = parts
+            .extensions
+            .get::<IncomingBodyLimit>()
+            .map(|l| l.0)
+            .unwrap_or(DEFAULT_MAX_INCOMING_BODY_SIZE);
+        let too_large = || {
+            let err = Error::RequestBodyLimit;
+            (err.status_code(), err.to_string()).into_response()
+        };
 
-        // this wont work if the body is an long running stream
-        let bytes = axum::body::to_bytes(body, usize::MAX)
-            .await
-            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())?;
+        let content_length = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        if content_length.is_some_and(|len| len > limit) {
+            return Err(too_large());
+        }
+
+        // Read the body in chunks, so that oversized requests are rejected without buffering
+        // them completely. The digest is computed at the same time to avoid another pass.
+        let mut stream = body.into_data_stream();
+        let mut bytes = BytesMut::with_capacity(content_length.unwrap_or_default());
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|err| {
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            })?;
+            if bytes.len() + chunk.len() > limit {
+                return Err(too_large());
+            }
+            hasher.update(&chunk);
+            bytes.extend_from_slice(&chunk);
+        }
+        let bytes = bytes.freeze();
 
         Ok(Self {
             headers: parts.headers,
             method: parts.method,
             uri: parts.uri,
-            body: bytes.to_vec(),
+            body: bytes,
+            body_digest: hasher.finalize().into(),
         })
     }
 }
 
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        activity_sending::generate_request_headers,
+        config::FederationConfig,
+        http_signatures::{sign_request, verify_body_digest, verify_body_hash},
+        protocol::public_key::main_key_id,
+        traits::tests::{DbConnection, DbUser, DB_USER_KEYPAIR},
+    };
+    use base64::{engine::general_purpose::STANDARD as Base64, Engine};
+    use http::HeaderValue;
+    use reqwest::Client;
+    use reqwest_middleware::ClientWithMiddleware;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use url::Url;
+
+    async fn extract(body: Vec<u8>, content_length: bool) -> Result<ActivityData, Response> {
+        let mut request = Request::post("/inbox");
+        if content_length {
+            request = request.header(CONTENT_LENGTH, body.len());
+        }
+        let mut request = request.body(Body::from(body)).unwrap();
+        request.extensions_mut().insert(IncomingBodyLimit(1024));
+        ActivityData::from_request(request, &()).await
+    }
+
+    #[tokio::test]
+    async fn test_activity_data_body_limit() {
+        let data = extract(vec![b'a'; 1024], true).await.unwrap();
+        assert_eq!(data.body.len(), 1024);
+
+        for content_length in [true, false] {
+            let res = extract(vec![b'a'; 1025], content_length).await;
+            assert_eq!(res.err().unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_activity_data_body_digest() {
+        let chunks = ["my ", "activity"].map(|c| Ok::<_, std::io::Error>(Bytes::from(c)));
+        let request = Request::post("/inbox")
+            .body(Body::from_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let data = ActivityData::from_request(request, &()).await.unwrap();
+        assert_eq!(data.body.as_ref(), b"my activity");
+        assert_eq!(data.body_digest(), Sha256::digest(b"my activity").as_slice());
+
+        // Same result as hashing the complete body, for valid and tampered bodies
+        let digest = format!("SHA-256={}", Base64.encode(Sha256::digest(b"my activity")));
+        let mut headers = HeaderMap::new();
+        headers.insert("digest", HeaderValue::from_str(&digest).unwrap());
+        for body in [&b"my activity"[..], b"other activity"] {
+            let precomputed = verify_body_digest(&headers, &Sha256::digest(body));
+            assert_eq!(precomputed, verify_body_hash(&headers, body));
+        }
+        assert_eq!(verify_body_digest(&headers, data.body_digest()), Ok(()));
+        assert_eq!(
+            verify_body_hash(&headers, b"other activity"),
+            Err(Error::ActivityBodyDigestInvalid)
+        );
+    }
+
+    static SHARED_NOTE_RECIPIENTS: Mutex<Vec<Url>> = Mutex::new(vec![]);
+
+    #[derive(Deserialize, Debug)]
+    struct SharedNote {
+        actor: Url,
+        id: Url,
+    }
+
+    #[async_trait]
+    impl ActivityHandler for SharedNote {
+        type DataType = DbConnection;
+        type Error = Error;
+
+        fn id(&self) -> &Url {
+            &self.id
+        }
+
+        fn actor(&self) -> &Url {
+            &self.actor
+        }
+
+        async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn receive(self, _data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+            unreachable!("shared inbox calls receive_for")
+        }
+
+        async fn receive_for(
+            self,
+            recipients: Vec<Url>,
+            _data: &Data<Self::DataType>,
+        ) -> Result<(), Self::Error> {
+            *SHARED_NOTE_RECIPIENTS.lock().unwrap() = recipients;
+            Ok(())
+        }
+    }
+
+    /// Activity data of a request to the shared inbox, signed by `actor`
+    async fn signed_activity_data(body: &Bytes, actor: &Url) -> ActivityData {
+        let inbox = Url::parse("https://example.com/inbox").unwrap();
+        let request_builder = ClientWithMiddleware::from(Client::default())
+            .post(inbox.clone())
+            .headers(generate_request_headers(&inbox));
+        let outgoing_request = sign_request(
+            request_builder,
+            main_key_id(actor),
+            body.clone(),
+            DB_USER_KEYPAIR.private_key().unwrap(),
+            false,
+            false,
+            false,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        let mut request = Request::post(inbox.path())
+            .body(Body::from(body.clone()))
+            .unwrap();
+        *request.headers_mut() = outgoing_request.headers().clone();
+        ActivityData::from_request(request, &()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_receive_shared_activity() {
+        let actor: Url = "http://localhost:123".parse().unwrap();
+        let activity = json!({
+          "actor": actor,
+          "id": "http://localhost:123/1",
+          "to": "http://localhost:8002/u/alice",
+          "cc": [
+            "https://www.w3.org/ns/activitystreams#Public",
+            "https://other.example/u/carol",
+            "http://localhost:8002/u/bob",
+            "http://localhost:8002/u/alice",
+            { "type": "Person", "id": "http://localhost:8002/u/dave" }
+          ]
+        });
+        let body: Bytes = serde_json::to_vec(&activity).unwrap().into();
+        let mut config = FederationConfig::builder()
+            .domain("localhost:8002")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap();
+
+        let activity_data = signed_activity_data(&body, &actor).await;
+        let response = receive_shared_activity::<SharedNote, DbUser, DbConnection>(
+            activity_data,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        let expected: Vec<Url> = vec![
+            "http://localhost:8002/u/alice".parse().unwrap(),
+            "http://localhost:8002/u/bob".parse().unwrap(),
+        ];
+        assert_eq!(*SHARED_NOTE_RECIPIENTS.lock().unwrap(), expected);
+        // header is disabled by default
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(FEDERATION_RESULT_HEADER).is_none());
+
+        config.federation_result_header = true;
+        let activity_data = signed_activity_data(&body, &actor).await;
+        let response = receive_shared_activity::<SharedNote, DbUser, DbConnection>(
+            activity_data,
+            &config.to_request_data(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.headers().get(FEDERATION_RESULT_HEADER).unwrap(),
+            "processed"
+        );
+    }
+}
+
 // TODO: copy tests from actix-web inbox and implement for axum as well
@@ -16,9 +16,18 @@
 //! }
 //! ```
 
-use crate::FEDERATION_CONTENT_TYPE;
-use axum::response::IntoResponse;
-use http::header;
+use crate::{
+    fetch::nodeinfo::{NodeInfo, NodeInfoWellKnown},
+    instance_actor::Application,
+    protocol::{
+        collections::{OrderedCollection, OrderedCollectionPage},
+        context::WithContext,
+        tombstone::Tombstone,
+    },
+    FEDERATION_CONTENT_TYPE,
+};
+use axum::response::{IntoResponse, Response};
+use http::{header, StatusCode};
 use serde::Serialize;
 
 /// Wrapper struct to respond with `application/activity+json` in axum handlers
@@ -37,3 +46,56 @@ impl<Json: Serialize> IntoResponse for FederationJson<Json> {
         response
     }
 }
+
+/// Responds with the collection and the default context
+impl IntoResponse for OrderedCollection {
+    fn into_response(self) -> axum::response::Response {
+        FederationJson(WithContext::new_default(self)).into_response()
+    }
+}
+
+/// Responds with the collection page and the default context
+impl IntoResponse for OrderedCollectionPage {
+    fn into_response(self) -> axum::response::Response {
+        FederationJson(WithContext::new_default(self)).into_response()
+    }
+}
+
+/// Responds with the instance actor and the default context
+impl IntoResponse for Application {
+    fn into_response(self) -> axum::response::Response {
+        FederationJson(WithContext::new_default(self)).into_response()
+    }
+}
+
+/// Responds with the tombstone of a deleted object and status `410 Gone`, so that
+/// [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference) on other instances
+/// fails with [Error::ObjectDeleted](crate::error::Error::ObjectDeleted).
+pub fn tombstone_response(tombstone: Tombstone) -> Response {
+    (
+        StatusCode::GONE,
+        FederationJson(WithContext::new_default(tombstone)),
+    )
+        .into_response()
+}
+
+/// Responds with `application/json`
+impl IntoResponse for NodeInfoWellKnown {
+    fn into_response(self) -> axum::response::Response {
+        axum::response::Json(self).into_response()
+    }
+}
+
+/// Responds with `application/json` and the schema version as profile
+impl IntoResponse for NodeInfo {
+    fn into_response(self) -> axum::response::Response {
+        let content_type = self.content_type();
+        let mut response = axum::response::Json(self).into_response();
+        if let Ok(content_type) = content_type.parse() {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, content_type);
+        }
+        response
+    }
+}
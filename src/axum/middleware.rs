@@ -1,6 +1,6 @@
-use crate::config::{Data, FederationConfig, FederationMiddleware};
+use crate::config::{Data, FederationConfig, FederationMiddleware, IncomingBodyLimit};
 use axum::{async_trait, body::Body, extract::FromRequestParts, http::Request, response::Response};
-use http::{request::Parts, StatusCode};
+use http::{header::HOST, request::Parts, uri::Authority, StatusCode};
 use std::task::{Context, Poll};
 use tower::{Layer, Service};
 
@@ -10,7 +10,7 @@ impl<S, T: Clone> Layer<S> for FederationMiddleware<T> {
     fn layer(&self, inner: S) -> Self::Service {
         FederationService {
             inner,
-            config: self.0.clone(),
+            middleware: self.clone(),
         }
     }
 }
@@ -20,7 +20,7 @@ impl<S, T: Clone> Layer<S> for FederationMiddleware<T> {
 #[derive(Clone)]
 pub struct FederationService<S, T: Clone> {
     inner: S,
-    config: FederationConfig<T>,
+    middleware: FederationMiddleware<T>,
 }
 
 impl<S, T> Service<Request<Body>> for FederationService<S, T>
@@ -38,7 +38,16 @@ where
     }
 
     fn call(&mut self, mut request: Request<Body>) -> Self::Future {
-        request.extensions_mut().insert(self.config.clone());
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or(request.uri().authority().map(Authority::as_str));
+        let config = self.middleware.config_for_host(host).clone();
+        request
+            .extensions_mut()
+            .insert(IncomingBodyLimit(config.max_incoming_body_size));
+        request.extensions_mut().insert(config);
         self.inner.call(request)
     }
 }
@@ -61,3 +70,97 @@ where
         }
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        extract_local_recipients,
+        fetch::webfinger::extract_webfinger_name,
+        traits::tests::DbConnection,
+    };
+    use axum::{
+        body::Bytes,
+        extract::Query,
+        routing::{get, post},
+        Router,
+    };
+    use reqwest::RequestBuilder;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    async fn config(domain: &str) -> FederationConfig<DbConnection> {
+        FederationConfig::builder()
+            .domain(domain)
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    async fn call(request: RequestBuilder) -> (StatusCode, String) {
+        let response = request.send().await.unwrap();
+        (response.status(), response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_domains() {
+        let alpha = config("alpha.example").await;
+        let configs = HashMap::from([
+            ("alpha.example".to_string(), alpha.clone()),
+            ("Beta.Example".to_string(), config("beta.example").await),
+        ]);
+        let app = Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(
+                    |Query(query): Query<HashMap<String, String>>, data: Data<DbConnection>| async move {
+                        match extract_webfinger_name(&query["resource"], &data) {
+                            Ok(name) => (StatusCode::OK, name.to_string()),
+                            Err(_) => (StatusCode::NOT_FOUND, data.domain().to_string()),
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/inbox",
+                post(|data: Data<DbConnection>, body: Bytes| async move {
+                    extract_local_recipients(&body, &data).len().to_string()
+                }),
+            )
+            .layer(FederationMiddleware::new_multi(configs, alpha));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+
+        let webfinger = |host: &str| {
+            client
+                .get(format!(
+                    "{base}/.well-known/webfinger?resource=acct:alice@beta.example"
+                ))
+                .header(HOST, host)
+        };
+        let ok = (StatusCode::OK, "alice".to_string());
+        assert_eq!(call(webfinger("beta.example")).await, ok);
+        assert_eq!(call(webfinger("BETA.example:8080")).await, ok);
+        let not_found = (StatusCode::NOT_FOUND, "alpha.example".to_string());
+        assert_eq!(call(webfinger("alpha.example")).await, not_found);
+        // Unknown hosts use the default config
+        assert_eq!(call(webfinger("other.example")).await, not_found);
+
+        let activity = json!({
+            "to": ["https://alpha.example/u/alice", "https://beta.example/u/bob"],
+            "cc": ["https://beta.example/u/carol"],
+        });
+        let inbox = |host: &str| {
+            client
+                .post(format!("{base}/inbox"))
+                .header(HOST, host)
+                .body(activity.to_string())
+        };
+        assert_eq!(call(inbox("alpha.example")).await.1, "1");
+        assert_eq!(call(inbox("beta.example")).await.1, "2");
+    }
+}
@@ -0,0 +1,173 @@
+//! Middleware which adds HTTP signatures to Activitypub responses
+//!
+//! ```
+//! # use activitypub_federation::axum::{json::FederationJson, sign_responses::SignResponses};
+//! # use activitypub_federation::traits::tests::DB_USER;
+//! # use axum::{routing::get, Router};
+//! let app: Router = Router::new()
+//!     .route("/u/alice", get(|| async { FederationJson("...") }))
+//!     .layer(SignResponses::new(&*DB_USER)?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use crate::{
+    error::Error,
+    http_signatures::{is_activitypub_response, ResponseSigner},
+    protocol::public_key::KeyIdStrategy,
+    traits::Actor,
+};
+use axum::{
+    body::Body,
+    http::Request,
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use http::StatusCode;
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// Signs all responses with content type `application/activity+json` or `application/ld+json`
+/// using the private key of the given actor. Other responses are passed through unchanged.
+///
+/// The signature covers the request method and path, so the layer should be applied to a router
+/// which is not nested under a path prefix. Signatures can be checked with
+/// [FetchObjectResponse::verify_response_signature](crate::fetch::FetchObjectResponse::verify_response_signature).
+#[derive(Clone)]
+pub struct SignResponses(Arc<ResponseSigner>);
+
+impl SignResponses {
+    /// Sign responses with the private key of `actor`, which must be present. The key id is
+    /// `{actor_id}#main-key`.
+    pub fn new<A: Actor>(actor: &A) -> Result<Self, Error> {
+        Ok(SignResponses(Arc::new(ResponseSigner::new(actor)?)))
+    }
+
+    /// Use a different key id strategy, see
+    /// [FederationConfigBuilder::key_id_strategy](crate::config::FederationConfigBuilder::key_id_strategy).
+    pub fn key_id_strategy(mut self, strategy: &KeyIdStrategy) -> Self {
+        Arc::make_mut(&mut self.0).set_key_id_strategy(strategy);
+        self
+    }
+}
+
+impl<S> Layer<S> for SignResponses {
+    type Service = SignResponsesService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SignResponsesService {
+            inner,
+            signer: self.0.clone(),
+        }
+    }
+}
+
+/// Service created by [SignResponses]
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct SignResponsesService<S> {
+    inner: S,
+    signer: Arc<ResponseSigner>,
+}
+
+impl<S> Service<Request<Body>> for SignResponsesService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+        let signer = self.signer.clone();
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let response = future.await?;
+            if !is_activitypub_response(response.headers()) {
+                return Ok(response);
+            }
+            let (mut parts, body) = response.into_parts();
+            let body = match axum::body::to_bytes(body, usize::MAX).await {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("Failed to read response body for signing: {e}");
+                    return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                }
+            };
+            if let Err(e) = signer.sign(&method, &uri, &mut parts.headers, &body) {
+                warn!("Failed to sign response: {e}");
+            }
+            Ok(Response::from_parts(parts, Body::from(body)))
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        axum::json::FederationJson,
+        config::FederationConfig,
+        fetch::{fetch_object_http, FetchObjectResponse},
+        http_signatures::generate_actor_keypair,
+        traits::tests::{DbConnection, DB_USER, DB_USER_KEYPAIR},
+    };
+    use axum::{routing::get, Router};
+    use serde_json::{json, Value};
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_sign_responses() -> Result<(), Error> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+        let object = json!({ "id": format!("{base}/objects/1"), "type": "Note" });
+        let app = Router::new()
+            .route(
+                "/objects/1",
+                get(move || {
+                    let object = object.clone();
+                    async move { FederationJson(object) }
+                }),
+            )
+            .route("/html", get(|| async { "not activitypub" }))
+            .layer(SignResponses::new(&*DB_USER)?);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let url = Url::parse(&format!("{base}/objects/1"))?;
+        let res: FetchObjectResponse<Value> = fetch_object_http(&url, &data).await?;
+        assert_eq!(
+            Some("https://localhost/123#main-key"),
+            res.headers
+                .get("signature")
+                .and_then(|s| s.to_str().ok())
+                .and_then(|s| s.split('"').nth(1))
+        );
+        res.verify_response_signature(&DB_USER_KEYPAIR.public_key)?;
+
+        let other_key = generate_actor_keypair()?.public_key;
+        assert!(res.verify_response_signature(&other_key).is_err());
+
+        let html = reqwest::get(format!("{base}/html")).await?;
+        assert!(!html.headers().contains_key("signature"));
+        Ok(())
+    }
+}
@@ -15,11 +15,37 @@
 //! ```
 
 use crate::{
-    activity_queue::{create_activity_queue, ActivityQueue},
+    activity_queue::{
+        create_activity_queue,
+        ActivityQueue,
+        ActivityQueueStats,
+        DeliveryCallbacks,
+        DeliveryFailure,
+        DeliverySuccess,
+        MemoryQueueBackend,
+        PoolMode,
+        QueueBackend,
+        RetryPolicy,
+        TaskStore,
+    },
+    activity_sending::HostLimiter,
     error::Error,
-    http_signatures::sign_request,
-    protocol::verification::verify_domains_match,
-    traits::{ActivityHandler, Actor},
+    fetch::{
+        nodeinfo::{DefaultQuirksTable, PeerSoftware, PeerSoftwareCache, QuirksTable},
+        object_id::BackgroundRefresher,
+        webfinger::{WebfingerLink, WEBFINGER_CACHE_TTL},
+        FetchObjectResponse,
+        InFlightFetches,
+    },
+    http_signatures::{sign_request, SigningLimiter, SigningMetrics, KEY_REFETCH_INTERVAL},
+    metrics::{FederationMetricsHook, NoMetrics},
+    protocol::{
+        public_key::KeyIdStrategy,
+        verification::{normalize_domain, verify_domains_match_with},
+    },
+    reqwest_shim::MAX_BODY_SIZE,
+    traits::{ActivityHandler, Actor, Object},
+    url::normalize,
 };
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -28,20 +54,32 @@ use dyn_clone::{clone_trait_object, DynClone};
 use moka::future::Cache;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::{redirect::Policy, Client, Request};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Client,
+    ClientBuilder,
+    Request,
+};
 use reqwest_middleware::{ClientWithMiddleware, RequestBuilder};
-use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey};
-use serde::de::DeserializeOwned;
+use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
 use std::{
-    net::IpAddr,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
     ops::Deref,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         Arc,
+        Mutex,
+        MutexGuard,
+        PoisonError,
+        RwLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tokio::net::lookup_host;
+use tokio::{net::lookup_host, sync::OnceCell};
+use tracing::warn;
 use url::Url;
 
 /// Configuration for this library, with various federation related settings
@@ -79,6 +117,28 @@ pub struct FederationConfig<T: Clone> {
     /// use the same as timeout when sending
     #[builder(default = "Duration::from_secs(10)")]
     pub(crate) request_timeout: Duration,
+    /// Total time which all outgoing fetches made with one [Data] may take, for example while
+    /// handling one incoming activity. Each fetch uses the remaining time as timeout if it is
+    /// shorter than [FederationConfigBuilder::request_timeout]. Once the time is used up, fetches
+    /// fail with [Error::RequestDeadlineExceeded]. Unlimited by default, so that only
+    /// [FederationConfigBuilder::http_fetch_limit] applies.
+    #[builder(default, setter(strip_option))]
+    pub(crate) request_deadline: Option<Duration>,
+    /// Maximum size in bytes of incoming activities. Larger requests are rejected by the inbox
+    /// with `413 Payload Too Large`, without reading the rest of the body. Defaults to 1 MiB.
+    #[builder(default = "DEFAULT_MAX_INCOMING_BODY_SIZE")]
+    pub(crate) max_incoming_body_size: usize,
+    /// Maximum size in bytes of objects fetched from other instances. Downloads are aborted as
+    /// soon as they exceed this size, with [Error::ResponseBodyLimit]. Defaults to 200 KB.
+    #[builder(default = "MAX_BODY_SIZE")]
+    pub(crate) max_fetch_body_size: usize,
+    /// Maximum nesting depth of JSON arrays and objects in received activities and fetched
+    /// objects. Deeper bodies are rejected with [Error::JsonDepthLimit] before they are
+    /// deserialized, see [verify_json_depth](crate::protocol::verification::verify_json_depth).
+    /// Defaults to 128, which is also the maximum. serde_json rejects deeper input on its own, so
+    /// building the config fails for larger values.
+    #[builder(default = "DEFAULT_MAX_JSON_DEPTH")]
+    pub(crate) max_json_depth: usize,
     /// Function used to verify that urls are valid, See [UrlVerifier] for details.
     #[builder(default = "Box::new(DefaultUrlVerifier())")]
     pub(crate) url_verifier: Box<dyn UrlVerifier + Sync>,
@@ -88,32 +148,311 @@ pub struct FederationConfig<T: Clone> {
     /// <https://git.pleroma.social/pleroma/pleroma/-/issues/2939>
     #[builder(default = "false")]
     pub(crate) http_signature_compat: bool,
+    /// Learn per host whether draft 10 signatures are needed, instead of using them for all hosts
+    /// with `http_signature_compat`. If an inbox rejects an activity with status 401 or 400, it
+    /// is sent once more with a draft 10 signature. If that succeeds, further activities and
+    /// signed fetches for the host use draft 10 signatures for the next 24 hours. Enabling
+    /// `http_signature_compat` overrides this setting.
+    #[builder(default = "false")]
+    pub(crate) http_signature_auto_compat: bool,
+    /// Sign outgoing requests with HTTP Message Signatures according to RFC 9421, instead of
+    /// draft-cavage signatures. If an inbox rejects such a request with status 401, the activity
+    /// is sent again with a draft-cavage signature. Incoming requests are always accepted with
+    /// either format.
+    /// <https://www.rfc-editor.org/rfc/rfc9421>
+    #[builder(default = "false")]
+    pub(crate) use_rfc9421_signatures: bool,
+    /// Add both the `Digest` header and the `Content-Digest` header from RFC 9530 to outgoing
+    /// activities. Older software only checks `Digest`, while newer versions of GoToSocial and
+    /// Mastodon move to `Content-Digest`. If disabled, only the header which belongs to the
+    /// signature format is sent: `Digest` for draft-cavage signatures and `Content-Digest` with
+    /// [FederationConfigBuilder::use_rfc9421_signatures].
+    #[builder(default = "true")]
+    pub(crate) both_digest_headers: bool,
+    /// Remove `bto` and `bcc` from outgoing activities and their objects before they are signed
+    /// and sent, as required by the spec. See
+    /// [strip_private_addressing](crate::protocol::helpers::strip_private_addressing). Forwarded
+    /// activities are always sent unchanged. Enabled by default.
+    #[builder(default = "true")]
+    pub(crate) strip_private_addressing: bool,
+    /// Determines the key id which is used in HTTP signatures of outgoing requests. The actor json
+    /// should be generated with [Actor::public_key_with_strategy] using the same value.
+    #[builder(default)]
+    pub(crate) key_id_strategy: KeyIdStrategy,
     /// Actor Id and private key to use to sign all federated fetch requests.
-    /// This can be used to implement secure mode federation.
+    /// This can be used to implement secure mode federation. It is shared between all clones of
+    /// the config, so that it can be changed later with [FederationConfig::set_signed_fetch_actor].
     /// <https://docs.joinmastodon.org/spec/activitypub/#secure-mode>
-    #[builder(default = "None", setter(custom))]
-    pub(crate) signed_fetch_actor: Option<Arc<(Url, RsaPrivateKey)>>,
+    #[builder(default, setter(custom))]
+    pub(crate) signed_fetch_actor: Arc<RwLock<Option<SignedFetchActor>>>,
     #[builder(
         default = "Cache::builder().max_capacity(10000).build()",
         setter(custom)
     )]
     pub(crate) actor_pkey_cache: Cache<Url, RsaPrivateKey>,
-    /// Queue for sending outgoing activities. Only optional to make builder work, its always
-    /// present once constructed.
+    /// Parsed public keys of remote actors, keyed by PEM, so that incoming signatures can be
+    /// verified without parsing the key every time. Change the size with
+    /// [FederationConfigBuilder::actor_public_key_cache].
+    #[builder(
+        default = "Cache::builder().max_capacity(10000).build()",
+        setter(custom)
+    )]
+    pub(crate) actor_public_key_cache: Cache<String, RsaPublicKey>,
+    /// Default [RetryPolicy] for outgoing activities, keyed by activity type (eg `Like`). Activities
+    /// of other types use [RetryPolicy::Full].
+    #[builder(default, setter(custom))]
+    pub(crate) retry_policies: HashMap<String, RetryPolicy>,
+    /// Queue for sending outgoing activities. It is created by [FederationConfig::start_queue],
+    /// or when the first activity is sent.
+    #[builder(setter(skip))]
+    pub(crate) activity_queue: Arc<OnceCell<ActivityQueue>>,
+    /// Set by [FederationConfig::shutdown], so that the activity queue isn't started afterwards
     #[builder(setter(skip))]
-    pub(crate) activity_queue: Option<Arc<ActivityQueue>>,
+    pub(crate) queue_shut_down: Arc<AtomicBool>,
     /// When sending with activity queue: Number of tasks that can be in-flight concurrently.
-    /// Tasks are retried once after a minute, then put into the retry queue.
+    /// Tasks which fail are put into the retry queue.
     /// Setting this count to `0` means that there is no limit to concurrency
     #[builder(default = "0")]
     pub(crate) queue_worker_count: usize,
     /// When sending with activity queue: Number of concurrent tasks that are being retried
-    /// in-flight concurrently. Tasks are retried after an hour, then again in 60 hours.
+    /// in-flight concurrently. Tasks are retried after a minute, after an hour, then again in
+    /// 60 hours. Tasks which wait for their next attempt don't count towards this limit.
     /// Setting this count to `0` means that there is no limit to concurrency
     #[builder(default = "0")]
     pub(crate) queue_retry_count: usize,
+    /// When sending with activity queue: Use a single pool of `queue_worker_count` workers for
+    /// both fresh sends and retries, instead of separate pools. This way idle capacity of one
+    /// kind can be used by the other. `queue_retry_count` is ignored in this mode.
+    #[builder(default = "false")]
+    pub(crate) unified_worker_pool: bool,
+    /// When using [FederationConfigBuilder::unified_worker_pool]: Maximum percentage of workers
+    /// which can be used for retries at the same time, so that fresh sends are not starved
+    /// during a storm of retries.
+    #[builder(default = "50")]
+    pub(crate) unified_pool_retry_percent: u8,
+    /// When sending with activity queue: Group outgoing activities by inbox host, and send the
+    /// activities for each host one after another on a single worker. This preserves the order
+    /// of activities per host, so that for example an `Undo` doesn't arrive before the `Follow`
+    /// it reverts, and reuses the connection to the host. Different hosts are still sent to in
+    /// parallel, with up to `queue_worker_count` hosts at the same time. Activities which are
+    /// moved to the retry queue are not ordered anymore. Takes precedence over
+    /// `unified_worker_pool`.
+    #[builder(default = "false")]
+    pub(crate) group_sends_by_host: bool,
+    /// When sending with activity queue: Deliver the activities for each inbox strictly in the
+    /// order they were queued. If sending an activity fails, the following activities for the
+    /// same inbox wait until it was delivered or dropped after all retries, so that for example
+    /// a `Delete` never overtakes the `Create` of the same object. Up to `queue_worker_count`
+    /// inboxes are sent to at the same time. Takes precedence over `group_sends_by_host` and
+    /// `unified_worker_pool`.
+    #[builder(default = "false")]
+    pub(crate) ordered_delivery: bool,
+    /// When using [FederationConfigBuilder::ordered_delivery]: Maximum number of activities
+    /// which wait for the same inbox. Further activities are moved to the retry queue and not
+    /// ordered anymore, so that an unreachable inbox doesn't use up an unlimited amount of
+    /// memory. Setting this to `0` means that there is no limit.
+    #[builder(default = "1000")]
+    pub(crate) ordered_delivery_limit: usize,
+    /// Storage for the tasks of the activity queue, see [QueueBackend]. By default tasks are
+    /// only kept in memory, and are lost when the process restarts.
+    #[builder(default = "Arc::new(MemoryQueueBackend::default())")]
+    pub(crate) queue_backend: Arc<dyn QueueBackend>,
+    /// Callbacks which are called when the activity queue is finished with a task, see
+    /// [FederationConfigBuilder::on_delivery_success] and
+    /// [FederationConfigBuilder::on_delivery_failure].
+    #[builder(default, setter(custom))]
+    pub(crate) delivery_callbacks: DeliveryCallbacks,
+    /// Hook which is called when objects are fetched and activities are sent or received, so
+    /// that applications can collect metrics, see [FederationMetricsHook]. By default these
+    /// events are only visible as tracing spans.
+    #[builder(default = "Arc::new(NoMetrics)")]
+    pub(crate) metrics_hook: Arc<dyn FederationMetricsHook>,
+    /// Disable automatic refetching of outdated remote objects in [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference).
+    /// Objects which are already stored are then always returned from the database, which saves
+    /// a lot of requests for applications that don't need up-to-date profiles, such as bridges or
+    /// archives. The downside is that changes like a new public key are not noticed automatically,
+    /// use [ObjectId::dereference_forced](crate::fetch::object_id::ObjectId::dereference_forced)
+    /// to refresh objects explicitly.
+    #[builder(default = "false")]
+    pub(crate) disable_automatic_refetch: bool,
+    /// Time after which stored remote objects are refetched by
+    /// [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference). Types can
+    /// override it with [Object::refetch_interval](crate::traits::Object::refetch_interval).
+    /// Defaults to 24 hours, or 20 seconds in debug builds.
+    #[builder(default, setter(strip_option))]
+    pub(crate) object_refetch_interval: Option<Duration>,
+    /// Return outdated objects from [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference)
+    /// immediately, and refetch them in the background. This way the refetch doesn't delay
+    /// the handling of the current request, or cause it to time out if the remote instance is
+    /// down. The refetch has its own request counter. Only a few refetches run at the same time,
+    /// and if too many are waiting, further ones are skipped until a later dereference.
+    #[builder(default = "false")]
+    pub(crate) background_object_refresh: bool,
+    /// Worker for background refreshes, used if `background_object_refresh` is enabled
+    #[builder(setter(skip))]
+    pub(crate) background_refresher: Arc<BackgroundRefresher>,
+    /// Use [SafeDnsResolver] in the default client, so that outgoing requests can't be directed
+    /// to private IP addresses with DNS rebinding. This has no effect in debug mode, or when a
+    /// custom [FederationConfigBuilder::client] is set. In that case install the resolver in the
+    /// custom client instead. If the client with the resolver can't be created, building the
+    /// config fails.
+    #[builder(default = "false")]
+    pub(crate) safe_dns_resolver: bool,
+    /// Additional content types which are accepted when fetching objects, for example
+    /// `application/json` for servers which don't use the ActivityPub media types. Parameters of
+    /// the content type are ignored when comparing. By default only `application/activity+json`
+    /// and `application/ld+json` with the ActivityStreams profile are accepted.
+    #[builder(default)]
+    pub(crate) extra_accepted_content_types: Vec<String>,
+    /// Accept fetched objects whose `id` is on the same host as the url they were fetched from,
+    /// but doesn't match it exactly. Some software serves the same object under multiple urls,
+    /// for example GoToSocial under `/users/alice` and `/@alice`. The url from the `id` is still
+    /// fetched once, and only if that also returns a different `id` the object is accepted with
+    /// its `id` as [FetchObjectResponse::url](crate::fetch::FetchObjectResponse::url). By default
+    /// such objects are rejected with [Error::FetchWrongId].
+    #[builder(default = "false")]
+    pub(crate) allow_fetch_id_mismatch_same_domain: bool,
+    /// Accept `Announce` activities which are signed by a different actor on the same domain as
+    /// their `actor`, for example by the instance actor on behalf of a group. The signature is
+    /// then verified with the key of the signing actor. By default an activity must be signed with
+    /// a key of its `actor`, otherwise it is rejected with [Error::SignatureActorMismatch].
+    #[builder(default = "false")]
+    pub(crate) allow_same_origin_announce_forwarding: bool,
+    /// Maximum number of signing operations (HTTP signatures and private key parsing) which can
+    /// run at the same time on the blocking thread pool. This prevents a large fan-out of
+    /// activities from using up the blocking threads which the application needs for other work.
+    /// Verification of the signatures of incoming requests uses the same limit.
+    /// Setting this count to `0` means that there is no limit.
+    #[builder(default = "0")]
+    pub(crate) max_concurrent_signatures: usize,
+    /// Limiter for signing operations, created from `max_concurrent_signatures`.
+    #[builder(setter(skip))]
+    pub(crate) signing_limiter: Arc<SigningLimiter>,
+    /// Maximum number of activities which are sent to the same host at the same time, to avoid
+    /// overloading small instances when sending to many inboxes. Additional sends wait until
+    /// one of them is finished. Setting this count to `0` means that there is no limit.
+    #[builder(default = "0")]
+    pub(crate) max_concurrent_sends_per_host: usize,
+    /// Minimum time between the start of two sends to the same host. Sends to a host which
+    /// responds with `429 Too Many Requests` and a `Retry-After` header are additionally delayed
+    /// for the requested time.
+    #[builder(default = "Duration::ZERO")]
+    pub(crate) min_send_interval_per_host: Duration,
+    /// Consecutive failed sends to a host after which further sends fail immediately, and for how
+    /// long. See [FederationConfigBuilder::circuit_breaker].
+    #[builder(default = "None", setter(custom))]
+    pub(crate) circuit_breaker: Option<(u32, Duration)>,
+    /// Limiter for sends per host, created from `max_concurrent_sends_per_host`,
+    /// `min_send_interval_per_host` and `circuit_breaker`.
+    #[builder(setter(skip))]
+    pub(crate) host_limiter: Arc<HostLimiter>,
+    /// Add the [FEDERATION_RESULT_HEADER](crate::FEDERATION_RESULT_HEADER) to inbox responses,
+    /// which tells the sending instance if the activity was processed or ignored. This helps with
+    /// debugging federation between cooperating instances, but reveals details about how the
+    /// inbox handles activities, so it is disabled by default.
+    #[builder(default = "false")]
+    pub(crate) federation_result_header: bool,
+    /// Decides which incoming activities are processed, see [ActivityFilter]. Activities which
+    /// are rejected by it are not verified or received, and reported as
+    /// [ReceiveOutcome::FilteredByHook](crate::ReceiveOutcome::FilteredByHook).
+    #[builder(default, setter(strip_option))]
+    pub(crate) activity_filter: Option<Arc<dyn ActivityFilter>>,
+    /// Fetch the nodeinfo of remote hosts in the background after the first successful fetch or
+    /// delivery, to find out which software they are running. The software is then used to look
+    /// up adjustments in the `quirks_table`, and is available with [Data::peer_software].
+    #[builder(default = "false")]
+    pub(crate) detect_peer_software: bool,
+    /// Rules for adjusting outgoing requests based on the software of the remote host, see
+    /// [QuirksTable] for details. Only used if `detect_peer_software` is enabled.
+    #[builder(default = "Box::new(DefaultQuirksTable)")]
+    pub(crate) quirks_table: Box<dyn QuirksTable>,
+    /// Software of remote hosts, created from `detect_peer_software` and `quirks_table`.
+    #[builder(setter(skip))]
+    pub(crate) peer_software: Arc<PeerSoftwareCache>,
+    /// Actors which were recently refetched because an incoming signature couldn't be verified
+    /// with their stored key
+    #[builder(
+        default = "Cache::builder().max_capacity(10000).time_to_live(KEY_REFETCH_INTERVAL).build()",
+        setter(skip)
+    )]
+    pub(crate) key_refetches: Cache<Url, ()>,
+    /// Ids of recently received activities, so that repeated deliveries of the same activity are
+    /// not processed again. Disabled by default, enable with
+    /// [FederationConfigBuilder::received_activity_cache].
+    #[builder(default = "None", setter(custom))]
+    pub(crate) received_activity_cache: Option<Cache<Url, ()>>,
+    /// Pairs of domains which belong to the same instance, see
+    /// [FederationConfigBuilder::domain_aliases].
+    #[builder(default, setter(custom))]
+    pub(crate) domain_aliases: Vec<(String, String)>,
+    /// Links to actors which were recently resolved with
+    /// [webfinger_resolve_actor](crate::fetch::webfinger::webfinger_resolve_actor), keyed by
+    /// identifier and [Actor::actor_type](crate::traits::Actor::actor_type). Change the expiration with [FederationConfigBuilder::webfinger_cache_ttl].
+    #[builder(
+        default = "Cache::builder().max_capacity(1000).time_to_live(WEBFINGER_CACHE_TTL).build()",
+        setter(custom)
+    )]
+    pub(crate) webfinger_cache: Cache<(String, Option<&'static str>), WebfingerLink>,
+    /// Fetches of remote objects which are currently running, so that concurrent dereferences
+    /// of the same url share a single request
+    #[builder(setter(skip))]
+    pub(crate) in_flight_fetches: Arc<InFlightFetches>,
 }
 
+/// Returns true if the ip address is private, loopback or similar, so that it must not be
+/// requested outside of debug mode.
+// TODO: Use is_global() once stabilized
+//       https://doc.rust-lang.org/std/net/enum.IpAddr.html#method.is_global
+pub(crate) fn is_invalid_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(addr) => {
+            addr.is_private()
+                || addr.is_link_local()
+                || addr.is_loopback()
+                || addr.is_multicast()
+                || addr.is_broadcast()
+                || addr.octets()[0] == 0 // "this network", including 0.0.0.0
+        }
+        IpAddr::V6(addr) => {
+            if let Some(v4) = addr.to_ipv4_mapped() {
+                return is_invalid_ip(IpAddr::V4(v4));
+            }
+            addr.is_loopback()
+                || addr.is_unspecified()
+                || addr.is_multicast()
+                || ((addr.segments()[0] & 0xfe00) == 0xfc00) // is_unique_local
+                || ((addr.segments()[0] & 0xffc0) == 0xfe80) // is_unicast_link_local
+        }
+    }
+}
+
+/// Actor id and private key which are used to sign fetch requests
+pub(crate) type SignedFetchActor = Arc<(Url, RsaPrivateKey)>;
+
+/// Reads the id and private key of an actor for signing fetch requests.
+fn parse_signed_fetch_actor<A: Actor>(actor: &A) -> Result<SignedFetchActor, Error> {
+    let private_key_pem = actor
+        .private_key_pem()
+        .ok_or_else(|| Error::MissingPrivateKey(actor.id()))?;
+    let private_key = RsaPrivateKey::from_pkcs8_pem(&private_key_pem)
+        .map_err(|e| Error::KeyParse(e.to_string()))?;
+    Ok(Arc::new((actor.id(), private_key)))
+}
+
+/// Default for [FederationConfigBuilder::max_incoming_body_size], 1 MiB
+pub(crate) const DEFAULT_MAX_INCOMING_BODY_SIZE: usize = 1024 * 1024;
+
+/// Default and maximum for [FederationConfigBuilder::max_json_depth], which is the recursion limit
+/// of serde_json
+pub(crate) const DEFAULT_MAX_JSON_DEPTH: usize = 128;
+
+/// Size limit for incoming activities, which is inserted into request extensions by the
+/// middleware. This way it is available to extractors without knowing the type of
+/// [FederationConfig].
+#[cfg(any(feature = "actix-web", feature = "axum"))]
+#[derive(Clone, Copy)]
+pub(crate) struct IncomingBodyLimit(pub(crate) usize);
+
 pub(crate) static DOMAIN_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^[a-zA-Z0-9.-]*$").expect("compile regex"));
 
@@ -128,10 +467,11 @@ impl<T: Clone> FederationConfig<T> {
         activity: &Activity,
     ) -> Result<(), Error>
     where
-        Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+        Activity: ActivityHandler<DataType = Datatype>,
     {
-        verify_domains_match(activity.id(), activity.actor())?;
-        self.verify_url_valid(activity.id()).await?;
+        verify_domains_match_with(self, activity.id(), activity.actor())?;
+        self.verify_url_valid(activity.id(), VerifyContext::IncomingActivity)
+            .await?;
         if self.is_local_url(activity.id()) {
             return Err(Error::UrlVerificationError(
                 "Activity was sent from local instance",
@@ -145,15 +485,40 @@ impl<T: Clone> FederationConfig<T> {
     pub fn to_request_data(&self) -> Data<T> {
         Data {
             config: self.clone(),
+            deadline: self.request_deadline.map(|d| Instant::now() + d),
             request_counter: Default::default(),
+            fetched_objects: Default::default(),
+            prefetched_objects: Default::default(),
+            received_activity: Default::default(),
+            signed_fetch_actor: Default::default(),
         }
     }
 
+    /// Sets the actor which signs all federated fetch requests, replacing the value of
+    /// [FederationConfigBuilder::signed_fetch_actor]. The change applies to all clones of this
+    /// config, including those in the middleware.
+    ///
+    /// This is useful when the actor is only created after the config, for example an instance
+    /// actor which is stored in the database.
+    pub fn set_signed_fetch_actor<A: Actor>(&self, actor: &A) -> Result<(), Error> {
+        let signed_fetch_actor = parse_signed_fetch_actor(actor)?;
+        let mut lock = self
+            .signed_fetch_actor
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        *lock = Some(signed_fetch_actor);
+        Ok(())
+    }
+
     /// Perform some security checks on URLs as mentioned in activitypub spec, and call user-supplied
     /// [`InstanceSettings.verify_url_function`].
     ///
     /// https://www.w3.org/TR/activitypub/#security-considerations
-    pub(crate) async fn verify_url_valid(&self, url: &Url) -> Result<(), Error> {
+    pub(crate) async fn verify_url_valid(
+        &self,
+        url: &Url,
+        context: VerifyContext,
+    ) -> Result<(), Error> {
         match url.scheme() {
             "https" => {}
             "http" => {
@@ -185,25 +550,9 @@ impl<T: Clone> FederationConfig<T> {
             }
 
             // Resolve domain and see if it points to private IP
-            // TODO: Use is_global() once stabilized
-            //       https://doc.rust-lang.org/std/net/enum.IpAddr.html#method.is_global
-            let invalid_ip =
-                lookup_host((domain.to_owned(), 80))
-                    .await?
-                    .any(|addr| match addr.ip() {
-                        IpAddr::V4(addr) => {
-                            addr.is_private()
-                                || addr.is_link_local()
-                                || addr.is_loopback()
-                                || addr.is_multicast()
-                        }
-                        IpAddr::V6(addr) => {
-                            addr.is_loopback()
-                        || addr.is_multicast()
-                        || ((addr.segments()[0] & 0xfe00) == 0xfc00) // is_unique_local
-                        || ((addr.segments()[0] & 0xffc0) == 0xfe80) // is_unicast_link_local
-                        }
-                    });
+            let invalid_ip = lookup_host((domain.to_owned(), 80))
+                .await?
+                .any(|addr| is_invalid_ip(addr.ip()));
             if invalid_ip {
                 return Err(Error::UrlVerificationError(
                     "Localhost is only allowed in debug mode",
@@ -217,17 +566,18 @@ impl<T: Clone> FederationConfig<T> {
             let mut url = url.clone();
             let domain = &domain[0..domain.len() - 1];
             url.set_host(Some(domain))?;
-            self.url_verifier.verify(&url).await?;
+            self.url_verifier.verify_with_context(&url, context).await?;
         } else {
-            self.url_verifier.verify(url).await?;
+            self.url_verifier.verify_with_context(url, context).await?;
         }
 
         Ok(())
     }
 
     /// Returns true if the url refers to this instance. Handles hostnames like `localhost:8540` for
-    /// local debugging.
+    /// local debugging, and internationalized domains in either unicode or punycode form.
     pub(crate) fn is_local_url(&self, url: &Url) -> bool {
+        let url = normalize(url.clone());
         match url.host_str() {
             Some(domain) => {
                 let domain = if let Some(port) = url.port() {
@@ -235,7 +585,7 @@ impl<T: Clone> FederationConfig<T> {
                 } else {
                     domain.to_string()
                 };
-                domain == self.domain
+                normalize_domain(&domain) == normalize_domain(&self.domain)
             }
             None => false,
         }
@@ -245,10 +595,126 @@ impl<T: Clone> FederationConfig<T> {
     pub fn domain(&self) -> &str {
         &self.domain
     }
+
+    /// Current counters of the activity queue, for example to export them for monitoring. Apart
+    /// from the number of unavailable hosts, only consists of atomic loads, so it can be called
+    /// frequently.
+    ///
+    /// In debug mode activities are sent directly, and the queue counters stay at zero.
+    pub fn queue_stats(&self) -> ActivityQueueStats {
+        let stats = self
+            .activity_queue
+            .get()
+            .map(ActivityQueue::stats_snapshot)
+            .unwrap_or_default();
+        ActivityQueueStats {
+            unavailable_hosts: self.host_limiter.unavailable_hosts(),
+            ..stats
+        }
+    }
+
+    /// Starts the background tasks of the activity queue, and resumes sending the tasks which are
+    /// left in the [QueueBackend]. Requires a tokio runtime.
+    ///
+    /// This is done automatically by [FederationConfigBuilder::build] and before the first
+    /// activity is sent. After [FederationConfigBuilder::build_lazy] it only needs to be called
+    /// to resume sending tasks of a persistent backend right away. Calling it again, or after
+    /// [FederationConfig::shutdown], has no effect.
+    pub async fn start_queue(&self) {
+        self.activity_queue().await;
+    }
+
+    /// Stops the activity queue, for example before the application exits. Sends which are
+    /// queued or running are finished first, and optionally also the retry queue. Scheduled sends
+    /// which are not due yet are left in the [QueueBackend], and new activities can't be sent with
+    /// this config or any of its clones afterwards. With [FederationConfigBuilder::ordered_delivery]
+    /// and without waiting for retries, activities which wait for a retry of the same inbox are
+    /// left in the backend. Background refreshes of
+    /// [FederationConfigBuilder::background_object_refresh] which were already started are
+    /// finished first, and no new ones are started.
+    ///
+    /// Waits at most for `timeout`, then returns the final stats. If [ActivityQueueStats::len] is
+    /// not zero, some tasks were not finished in time. They are lost unless a persistent
+    /// [QueueBackend] is used. Calling this again only returns the stats. If the queue wasn't
+    /// started yet, it isn't started anymore afterwards.
+    pub async fn shutdown(
+        &self,
+        wait_for_retries: bool,
+        timeout: Duration,
+    ) -> Result<ActivityQueueStats, Error> {
+        self.queue_shut_down.store(true, Ordering::Release);
+        let deadline = tokio::time::Instant::now() + timeout;
+        if tokio::time::timeout_at(deadline, self.background_refresher.shutdown())
+            .await
+            .is_err()
+        {
+            warn!("Timeout while waiting for background refreshes to finish");
+        }
+        let Some(queue) = self.activity_queue.get() else {
+            return Ok(ActivityQueueStats::default());
+        };
+        match tokio::time::timeout_at(deadline, queue.shutdown(wait_for_retries)).await {
+            Ok(res) => {
+                res?;
+            }
+            Err(_) => warn!("Timeout while waiting for activity queue to shut down"),
+        }
+        Ok(queue.stats_snapshot())
+    }
+
+    /// Returns the activity queue, and creates it if it is not running yet. Returns `None` if
+    /// it wasn't started before [FederationConfig::shutdown].
+    pub(crate) async fn activity_queue(&self) -> Option<&ActivityQueue> {
+        if self.queue_shut_down.load(Ordering::Acquire) {
+            return self.activity_queue.get();
+        }
+        let queue = self
+            .activity_queue
+            .get_or_init(|| async {
+                let mode = if self.ordered_delivery {
+                    PoolMode::Ordered {
+                        limit: self.ordered_delivery_limit,
+                    }
+                } else if self.group_sends_by_host {
+                    PoolMode::PerHost
+                } else if self.unified_worker_pool {
+                    PoolMode::Unified {
+                        retry_percent: self.unified_pool_retry_percent,
+                    }
+                } else {
+                    PoolMode::Separate
+                };
+                let store = TaskStore::new(
+                    self.queue_backend.clone(),
+                    self.signing_limiter.clone(),
+                    self.host_limiter.clone(),
+                    self.peer_software.clone(),
+                    self.metrics_hook.clone(),
+                    self.delivery_callbacks.clone(),
+                );
+                create_activity_queue(
+                    self.client.clone(),
+                    self.queue_worker_count,
+                    self.queue_retry_count,
+                    self.request_timeout,
+                    mode,
+                    store,
+                )
+                .await
+            })
+            .await;
+        Some(queue)
+    }
+
+    /// Number of activity sends which are queued, running or waiting for retry.
+    pub fn queue_len(&self) -> usize {
+        self.queue_stats().len()
+    }
 }
 
 impl<T: Clone> FederationConfigBuilder<T> {
-    /// Sets an actor to use to sign all federated fetch requests
+    /// Sets an actor to use to sign all federated fetch requests, usually an
+    /// [InstanceActor](crate::instance_actor::InstanceActor)
     pub fn signed_fetch_actor<A: Actor>(&mut self, actor: &A) -> &mut Self {
         let private_key_pem = actor
             .private_key_pem()
@@ -256,7 +722,10 @@ impl<T: Clone> FederationConfigBuilder<T> {
 
         let private_key =
             RsaPrivateKey::from_pkcs8_pem(&private_key_pem).expect("Could not decode PEM data");
-        self.signed_fetch_actor = Some(Some(Arc::new((actor.id(), private_key))));
+        self.signed_fetch_actor = Some(Arc::new(RwLock::new(Some(Arc::new((
+            actor.id(),
+            private_key,
+        ))))));
         self
     }
 
@@ -266,20 +735,165 @@ impl<T: Clone> FederationConfigBuilder<T> {
         self
     }
 
+    /// sets the number of parsed public keys of remote actors to keep in memory, for verifying
+    /// signatures of incoming requests
+    pub fn actor_public_key_cache(&mut self, cache_size: u64) -> &mut Self {
+        self.actor_public_key_cache = Some(Cache::builder().max_capacity(cache_size).build());
+        self
+    }
+
+    /// After `failures` sends in a row to the same host failed, because the host couldn't be
+    /// reached or responded with a server error, further sends to it fail immediately with
+    /// [Error::HostUnavailable] until `cooldown` is over. These sends are retried later like other
+    /// failed sends, so that an instance which is down doesn't keep workers waiting for the
+    /// request timeout with every activity. If the first send after the cool-down fails again,
+    /// the next cool-down starts right away.
+    ///
+    /// Disabled by default. Hosts which are currently skipped are counted in
+    /// [ActivityQueueStats::unavailable_hosts].
+    pub fn circuit_breaker(&mut self, failures: u32, cooldown: Duration) -> &mut Self {
+        self.circuit_breaker = Some(Some((failures.max(1), cooldown)));
+        self
+    }
+
+    /// Remembers the ids of up to `capacity` received activities for the duration of `ttl`. When
+    /// an activity with the same id is delivered again during this time, the inbox responds with
+    /// `200 OK` without calling [ActivityHandler::verify](crate::traits::ActivityHandler::verify)
+    /// or [ActivityHandler::receive](crate::traits::ActivityHandler::receive).
+    ///
+    /// Remote servers often deliver the same activity multiple times because of retries. Without
+    /// this setting the application is responsible for ignoring duplicates, for example by
+    /// storing the ids of received activities in its database.
+    pub fn received_activity_cache(&mut self, capacity: u64, ttl: Duration) -> &mut Self {
+        self.received_activity_cache = Some(Some(
+            Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .build(),
+        ));
+        self
+    }
+
+    /// How long successful webfinger lookups are remembered, so that repeated lookups of the same
+    /// `user@host` identifier don't need a request to the remote instance. The actor itself is
+    /// still dereferenced as usual. Defaults to one hour, pass `Duration::ZERO` to disable.
+    pub fn webfinger_cache_ttl(&mut self, ttl: Duration) -> &mut Self {
+        let capacity = if ttl.is_zero() { 0 } else { 1000 };
+        self.webfinger_cache = Some(
+            Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .build(),
+        );
+        self
+    }
+
+    /// Pairs of domains which are controlled by the same instance, and which are considered equal
+    /// by [verify_domains_match_with](crate::protocol::verification::verify_domains_match_with).
+    ///
+    /// This is needed for instances which serve actors under a different domain than the one
+    /// used in their activity ids, for example Mastodon with separate `WEB_DOMAIN` and
+    /// `LOCAL_DOMAIN`. Incoming activities from such instances are otherwise rejected because
+    /// the domains of activity id and actor don't match.
+    pub fn domain_aliases(&mut self, aliases: Vec<(String, String)>) -> &mut Self {
+        self.domain_aliases = Some(
+            aliases
+                .into_iter()
+                .map(|(a, b)| (normalize_domain(&a), normalize_domain(&b)))
+                .collect(),
+        );
+        self
+    }
+
+    /// Sets the default retry policy for outgoing activities with the given type, eg `Like`.
+    /// Can be called multiple times for different types.
+    pub fn retry_policy(&mut self, kind: impl Into<String>, policy: RetryPolicy) -> &mut Self {
+        self.retry_policies
+            .get_or_insert_with(Default::default)
+            .insert(kind.into(), policy);
+        self
+    }
+
+    /// Called when an outgoing activity was delivered to an inbox, with the number of attempts
+    /// which were needed. Can be used to track the health of remote instances.
+    ///
+    /// The callback runs on the worker which sent the activity, so it should not block.
+    pub fn on_delivery_success<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(DeliverySuccess) + Send + Sync + 'static,
+    {
+        self.delivery_callbacks
+            .get_or_insert_with(Default::default)
+            .on_success = Some(Arc::new(callback));
+        self
+    }
+
+    /// Called when an outgoing activity could not be delivered to an inbox and is dropped, after
+    /// all retries allowed by its [RetryPolicy] failed. Without this callback such activities
+    /// are only logged, this way the application can store them for manual inspection or
+    /// mark the inbox as unreachable.
+    ///
+    /// The callback runs on the worker which sent the activity, so it should not block.
+    pub fn on_delivery_failure<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(DeliveryFailure) + Send + Sync + 'static,
+    {
+        self.delivery_callbacks
+            .get_or_insert_with(Default::default)
+            .on_failure = Some(Arc::new(callback));
+        self
+    }
+
     /// Constructs a new config instance with the values supplied to builder.
     ///
     /// Values which are not explicitly specified use the defaults. Also initializes the
     /// queue for outgoing activities, which is stored internally in the config struct.
     /// Requires a tokio runtime for the background queue.
     pub async fn build(&mut self) -> Result<FederationConfig<T>, FederationConfigBuilderError> {
+        let config = self.build_lazy()?;
+        config.start_queue().await;
+        Ok(config)
+    }
+
+    /// Same as [FederationConfigBuilder::build], but doesn't start the queue for outgoing
+    /// activities. This way the config can be constructed outside of a tokio runtime, for example
+    /// in synchronous setup code before the runtime of the application is created.
+    ///
+    /// The queue is started when the first activity is sent, or with
+    /// [FederationConfig::start_queue].
+    pub fn build_lazy(&mut self) -> Result<FederationConfig<T>, FederationConfigBuilderError> {
         let mut config = self.partial_build()?;
-        let queue = create_activity_queue(
+        if config.max_json_depth > DEFAULT_MAX_JSON_DEPTH {
+            return Err(FederationConfigBuilderError::ValidationError(format!(
+                "max_json_depth can be at most {DEFAULT_MAX_JSON_DEPTH}, the recursion limit of serde_json"
+            )));
+        }
+        if config.safe_dns_resolver && self.client.is_none() && !config.debug {
+            let resolver = SafeDnsResolver::new(&config.domain);
+            // Don't fall back to a client without the protection
+            let client = default_client_builder()
+                .dns_resolver(Arc::new(resolver))
+                .build()
+                .map_err(|err| {
+                    FederationConfigBuilderError::ValidationError(format!(
+                        "Failed to build client with safe DNS resolver: {err}"
+                    ))
+                })?;
+            config.client = client.into();
+        }
+        config.signing_limiter = Arc::new(SigningLimiter::new(config.max_concurrent_signatures));
+        config.host_limiter = Arc::new(HostLimiter::new(
+            config.max_concurrent_sends_per_host,
+            config.min_send_interval_per_host,
+            config.circuit_breaker,
+        ));
+        config.peer_software = Arc::new(PeerSoftwareCache::new(
+            config.detect_peer_software,
             config.client.clone(),
-            config.queue_worker_count,
-            config.queue_retry_count,
             config.request_timeout,
-        );
-        config.activity_queue = Some(Arc::new(queue));
+            config.quirks_table.clone(),
+            config.http_signature_auto_compat,
+        ));
         Ok(config)
     }
 }
@@ -327,10 +941,56 @@ impl<T: Clone> Deref for FederationConfig<T> {
 ///     }
 /// }
 /// ```
+///
+/// To apply different rules depending on why the url is used, for example to still accept
+/// activities from a domain but never fetch from it, implement [UrlVerifier::verify_with_context]
+/// instead:
+///
+/// ```
+/// # use async_trait::async_trait;
+/// # use url::Url;
+/// # use activitypub_federation::config::{UrlVerifier, VerifyContext};
+/// # use activitypub_federation::error::Error;
+/// #[derive(Clone)]
+/// struct NoFetchVerifier;
+///
+/// #[async_trait]
+/// impl UrlVerifier for NoFetchVerifier {
+///     async fn verify_with_context(&self, url: &Url, context: VerifyContext) -> Result<(), Error> {
+///         if context == VerifyContext::Fetch && url.domain() == Some("slow.example") {
+///             return Err(Error::Other("Fetching from this domain is disabled".into()));
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
 #[async_trait]
 pub trait UrlVerifier: DynClone + Send {
     /// Should return Ok iff the given url is valid for processing.
-    async fn verify(&self, url: &Url) -> Result<(), Error>;
+    async fn verify(&self, _url: &Url) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Same as [UrlVerifier::verify], but also gets the reason why the url is verified. This is
+    /// the method called by the library. By default it calls [UrlVerifier::verify], ignoring the
+    /// context.
+    async fn verify_with_context(&self, url: &Url, _context: VerifyContext) -> Result<(), Error> {
+        self.verify(url).await
+    }
+}
+
+/// Reason why a url is passed to [UrlVerifier::verify_with_context]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyContext {
+    /// Id of an activity which was received in the inbox
+    IncomingActivity,
+    /// Inbox to which an activity is about to be delivered
+    OutgoingDelivery,
+    /// Url of an object, collection or other resource which is fetched, including redirect
+    /// targets and ids of fetched objects
+    Fetch,
+    /// Url of a webfinger lookup, including redirect targets
+    Webfinger,
 }
 
 /// Default URL verifier which does nothing.
@@ -346,6 +1006,30 @@ impl UrlVerifier for DefaultUrlVerifier {
 
 clone_trait_object!(UrlVerifier);
 
+/// Filter for incoming activities, for example to ignore activities from blocked instances or
+/// users without fetching anything. It is called after the signature of the activity was
+/// verified, but before [ActivityHandler::verify](crate::traits::ActivityHandler::verify).
+///
+/// ```
+/// # use async_trait::async_trait;
+/// # use url::Url;
+/// # use activitypub_federation::config::ActivityFilter;
+/// struct BlockedDomainFilter;
+///
+/// #[async_trait]
+/// impl ActivityFilter for BlockedDomainFilter {
+///     async fn accept(&self, _activity_id: &Url, actor: &Url, _kind: Option<&str>) -> bool {
+///         actor.domain() != Some("spam.example")
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait ActivityFilter: Send + Sync {
+    /// Should return false if the activity should be ignored. `kind` is the value of its `type`
+    /// field.
+    async fn accept(&self, activity_id: &Url, actor: &Url, kind: Option<&str>) -> bool;
+}
+
 /// Stores data for handling one specific HTTP request.
 ///
 /// It gives acess to the `app_data` which was passed to [FederationConfig::builder].
@@ -356,7 +1040,209 @@ clone_trait_object!(UrlVerifier);
 /// <https://www.w3.org/TR/activitypub/#security-recursive-objects>
 pub struct Data<T: Clone> {
     pub(crate) config: FederationConfig<T>,
-    pub(crate) request_counter: AtomicU32,
+    /// Point in time after which no more fetches are made, see
+    /// [FederationConfigBuilder::request_deadline]
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) request_counter: RequestCounter,
+    pub(crate) fetched_objects: FetchedObjects,
+    pub(crate) prefetched_objects: PrefetchedObjects,
+    pub(crate) received_activity: ReceivedActivity,
+    /// Overrides the signed fetch actor of the config for this request, see
+    /// [Data::set_signed_fetch_actor].
+    pub(crate) signed_fetch_actor: Mutex<Option<SignedFetchActor>>,
+}
+
+/// Category of an outgoing HTTP request, used for the per-category counters in [Data].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RequestKind {
+    /// Fetch of an object or actor
+    Object,
+    /// Webfinger lookup
+    Webfinger,
+    /// Fetch of a collection
+    Collection,
+    /// Fetch of a non-ActivityPub resource with [Data::safe_get]
+    SafeGet,
+}
+
+/// Counters for outgoing HTTP requests made with one [Data].
+#[derive(Default)]
+pub(crate) struct RequestCounter {
+    total: AtomicU32,
+    object: AtomicU32,
+    webfinger: AtomicU32,
+    collection: AtomicU32,
+    safe_get: AtomicU32,
+    reservations: Reservations,
+}
+
+impl RequestCounter {
+    /// Count a new request of the given kind, and return the new total of all requests.
+    pub(crate) fn increment(&self, kind: RequestKind) -> u32 {
+        let category = match kind {
+            RequestKind::Object => &self.object,
+            RequestKind::Webfinger => &self.webfinger,
+            RequestKind::Collection => &self.collection,
+            RequestKind::SafeGet => &self.safe_get,
+        };
+        category.fetch_add(1, Ordering::SeqCst);
+        self.reservations.consume();
+        // fetch_add returns old value so we need to increment manually here
+        self.total.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+/// Requests which were reserved with [Data::try_reserve_requests] and not made yet, for each
+/// [RequestBudgetGuard] which is alive, keyed by an id of the guard.
+#[derive(Debug, Default)]
+struct Reservations {
+    remaining: Mutex<Vec<(u64, u32)>>,
+    next_id: AtomicU64,
+}
+
+impl Reservations {
+    fn lock(&self) -> MutexGuard<'_, Vec<(u64, u32)>> {
+        self.remaining
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Number of reserved requests which were not made yet
+    fn total(&self) -> u32 {
+        self.lock().iter().map(|(_, remaining)| remaining).sum()
+    }
+
+    /// A request is made, which uses up one request of the latest reservation. This way it is
+    /// not counted again as reserved.
+    fn consume(&self) {
+        if let Some((_, remaining)) = self.lock().iter_mut().rev().find(|(_, r)| *r > 0) {
+            *remaining -= 1;
+        }
+    }
+}
+
+/// Raw body of the activity which is currently being received with one [Data].
+#[derive(Default)]
+pub(crate) struct ReceivedActivity(Mutex<Option<Bytes>>);
+
+impl ReceivedActivity {
+    /// Stores the body until the returned guard is dropped. Then the body of the outer activity
+    /// is restored if activities are received in a nested way.
+    pub(crate) fn enter(&self, body: Bytes) -> ReceivedActivityGuard<'_> {
+        let mut received = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = received.replace(body);
+        ReceivedActivityGuard {
+            received: self,
+            previous,
+        }
+    }
+
+    fn get(&self) -> Option<Bytes> {
+        let received = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        received.clone()
+    }
+}
+
+/// Restores the previous body of [ReceivedActivity] once the activity is processed.
+pub(crate) struct ReceivedActivityGuard<'a> {
+    received: &'a ReceivedActivity,
+    previous: Option<Bytes>,
+}
+
+impl Drop for ReceivedActivityGuard<'_> {
+    fn drop(&mut self) {
+        let mut received = self
+            .received
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *received = self.previous.take();
+    }
+}
+
+/// Maximum number of responses which are kept in [FetchedObjects]
+pub(crate) const MAX_FETCHED_OBJECTS: usize = 100;
+
+/// Responses of objects which were fetched over HTTP with one [Data]. When the same url is
+/// dereferenced again, for example in a reply tree where several posts reply to the same parent,
+/// the object is converted from the stored response instead of making another request.
+///
+/// At most [MAX_FETCHED_OBJECTS] responses are kept, after that the oldest one is removed for
+/// each new response.
+#[derive(Default)]
+pub(crate) struct FetchedObjects(Mutex<FetchedResponses>);
+
+/// Stored responses, and their urls in the order in which they were inserted
+#[derive(Default)]
+struct FetchedResponses {
+    responses: HashMap<Url, FetchObjectResponse<()>>,
+    order: VecDeque<Url>,
+}
+
+impl FetchedObjects {
+    pub(crate) fn get(&self, url: &Url) -> Option<FetchObjectResponse<()>> {
+        let fetched = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        fetched.responses.get(url).cloned()
+    }
+
+    pub(crate) fn insert(&self, url: Url, response: FetchObjectResponse<()>) {
+        let mut fetched = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if fetched.responses.insert(url.clone(), response).is_some() {
+            return;
+        }
+        fetched.order.push_back(url);
+        if fetched.order.len() > MAX_FETCHED_OBJECTS {
+            if let Some(oldest) = fetched.order.pop_front() {
+                fetched.responses.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, url: &Url) -> bool {
+        let fetched = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        fetched.responses.contains_key(url)
+    }
+}
+
+/// Json of objects which are embedded in the received activity, added with
+/// [Data::add_prefetched].
+#[derive(Default)]
+pub(crate) struct PrefetchedObjects(Mutex<HashMap<Url, Bytes>>);
+
+impl PrefetchedObjects {
+    /// Removes the json of the object, so that later dereferences read it from the database.
+    pub(crate) fn take(&self, url: &Url) -> Option<Bytes> {
+        let mut prefetched = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        prefetched.remove(url)
+    }
+
+    fn insert(&self, url: Url, json: Bytes) {
+        let mut prefetched = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        prefetched.insert(url, json);
+    }
+}
+
+/// Budget of outgoing HTTP requests which was reserved with [Data::try_reserve_requests].
+///
+/// The reservation is released when the guard is dropped.
+#[derive(Debug)]
+pub struct RequestBudgetGuard<'a> {
+    reservations: &'a Reservations,
+    id: u64,
+    amount: u32,
+}
+
+impl RequestBudgetGuard<'_> {
+    /// Number of requests which are reserved by this guard
+    pub fn amount(&self) -> u32 {
+        self.amount
+    }
+}
+
+impl Drop for RequestBudgetGuard<'_> {
+    fn drop(&mut self) {
+        self.reservations.lock().retain(|(id, _)| *id != self.id);
+    }
 }
 
 impl<T: Clone> Data<T> {
@@ -370,36 +1256,252 @@ impl<T: Clone> Data<T> {
         &self.config.domain
     }
 
-    /// Returns a new instance of `Data` with request counter set to 0.
+    /// The key id strategy that was configured in [FederationConfig].
+    pub fn key_id_strategy(&self) -> &KeyIdStrategy {
+        &self.config.key_id_strategy
+    }
+
+    /// Returns a new instance of `Data` with request counter set to 0, without previously
+    /// fetched objects and with a new [FederationConfigBuilder::request_deadline].
     pub fn reset_request_count(&self) -> Self {
+        let signed_fetch_actor = self
+            .signed_fetch_actor
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
         Data {
             config: self.config.clone(),
+            deadline: self.config.request_deadline.map(|d| Instant::now() + d),
             request_counter: Default::default(),
+            fetched_objects: Default::default(),
+            prefetched_objects: Default::default(),
+            received_activity: Default::default(),
+            signed_fetch_actor: Mutex::new(signed_fetch_actor),
+        }
+    }
+
+    /// Returns `timeout`, shortened to the time which is left until the
+    /// [FederationConfigBuilder::request_deadline], or [Error::RequestDeadlineExceeded] if no
+    /// time is left.
+    pub(crate) fn timeout_within_deadline(&self, timeout: Duration) -> Result<Duration, Error> {
+        let Some(deadline) = self.deadline else {
+            return Ok(timeout);
+        };
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining.min(timeout)),
+            _ => Err(Error::RequestDeadlineExceeded),
         }
     }
+
+    /// Returns [Error::RequestDeadlineExceeded] instead of `error` if the deadline has passed,
+    /// because then the request most likely failed due to the shortened timeout.
+    pub(crate) fn deadline_error(&self, error: impl Into<Error>) -> Error {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Error::RequestDeadlineExceeded,
+            _ => error.into(),
+        }
+    }
+
+    /// Sign fetch requests which are made with this data using the key of `actor`, instead of
+    /// the actor from [FederationConfigBuilder::signed_fetch_actor]. Other requests are not
+    /// affected.
+    ///
+    /// This can be used if there are multiple local actors, so that fetches made in the context
+    /// of one actor are attributed to it, for example a community on a multi-tenant instance.
+    pub fn set_signed_fetch_actor<A: Actor>(&self, actor: &A) -> Result<(), Error> {
+        let signed_fetch_actor = parse_signed_fetch_actor(actor)?;
+        let mut lock = self
+            .signed_fetch_actor
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *lock = Some(signed_fetch_actor);
+        Ok(())
+    }
+
+    /// Actor which signs fetch requests made with this data, either set with
+    /// [Data::set_signed_fetch_actor] or from the config.
+    pub(crate) fn signed_fetch_actor(&self) -> Option<SignedFetchActor> {
+        let signed_fetch_actor = self
+            .signed_fetch_actor
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        signed_fetch_actor.or_else(|| {
+            self.config
+                .signed_fetch_actor
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone()
+        })
+    }
+    /// Returns true if the object with this url was already fetched over HTTP with this data. Further
+    /// dereferences of the url reuse the response, see [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference).
+    pub fn was_fetched(&self, url: &Url) -> bool {
+        self.fetched_objects.contains(url)
+    }
+
+    /// Response of the object with this url, if it was already fetched over HTTP with this data.
+    /// This can be used in [Object::from_json](crate::traits::Object::from_json) to store the
+    /// [FetchObjectResponse::etag] of the object, see [Object::cached_etag](crate::traits::Object::cached_etag).
+    pub fn fetched_response(&self, url: &Url) -> Option<FetchObjectResponse<()>> {
+        self.fetched_objects.get(url)
+    }
+
+    /// Raw body of the activity which is currently being received by the inbox, exactly as it
+    /// was sent. Only available in [ActivityHandler::verify] and [ActivityHandler::receive]
+    /// during inbox processing, otherwise returns `None`.
+    ///
+    /// This can be used to store the original activity, or to forward it to other instances
+    /// without losing fields which are not part of the activity struct.
+    pub fn received_activity_bytes(&self) -> Option<Bytes> {
+        self.received_activity.get()
+    }
+
+    /// Stores an object which is embedded in the activity that is currently being received, for
+    /// example the note of a `Create` or its `attributedTo` actor. The next
+    /// [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference) of its id with this
+    /// data passes the embedded json to [Object::verify](crate::traits::Object::verify) and
+    /// [Object::from_json](crate::traits::Object::from_json), as if it was fetched, instead of
+    /// reading the database or making an HTTP request. Further dereferences read the database
+    /// again.
+    ///
+    /// Like Mastodon, embedded objects are only trusted if they are on the same domain as the
+    /// `actor` of the activity, whose signature was verified by the inbox. Otherwise, or if no
+    /// activity is being received, [Error::UrlVerificationError] is returned and the object has
+    /// to be fetched from its origin as usual.
+    pub fn add_prefetched<Kind: Object>(&self, json: Kind::Kind) -> Result<(), Error>
+    where
+        Kind::Kind: Serialize,
+    {
+        #[derive(Deserialize)]
+        struct Embedded {
+            id: Url,
+        }
+        #[derive(Deserialize)]
+        struct Activity {
+            actor: Url,
+        }
+        let json = serde_json::to_vec(&json).map_err(|e| Error::Other(e.to_string()))?;
+        let id = serde_json::from_slice::<Embedded>(&json)
+            .map_err(|_| Error::UrlVerificationError("Embedded object has no id"))?
+            .id;
+        let actor = self
+            .received_activity_bytes()
+            .and_then(|body| serde_json::from_slice::<Activity>(&body).ok())
+            .ok_or(Error::UrlVerificationError(
+                "Embedded objects can only be added while receiving an activity",
+            ))?
+            .actor;
+        verify_domains_match_with(&self.config, &id, &actor)?;
+        if self.config.is_local_url(&id) {
+            return Err(Error::UrlVerificationError("Object is not remote"));
+        }
+        self.prefetched_objects.insert(normalize(id), json.into());
+        Ok(())
+    }
+
     /// Total number of outgoing HTTP requests made with this data.
     pub fn request_count(&self) -> u32 {
-        self.request_counter.load(Ordering::Relaxed)
+        self.request_counter.total.load(Ordering::Relaxed)
+    }
+
+    /// Number of objects and actors fetched over HTTP with this data.
+    pub fn object_fetch_count(&self) -> u32 {
+        self.request_counter.object.load(Ordering::Relaxed)
+    }
+
+    /// Number of webfinger lookups made with this data.
+    pub fn webfinger_count(&self) -> u32 {
+        self.request_counter.webfinger.load(Ordering::Relaxed)
+    }
+
+    /// Number of collections fetched over HTTP with this data.
+    pub fn collection_fetch_count(&self) -> u32 {
+        self.request_counter.collection.load(Ordering::Relaxed)
+    }
+
+    /// Number of non-ActivityPub resources fetched with [Data::safe_get] using this data.
+    pub fn safe_get_count(&self) -> u32 {
+        self.request_counter.safe_get.load(Ordering::Relaxed)
+    }
+
+    /// Number of outgoing HTTP requests which can still be made before
+    /// [FederationConfigBuilder::http_fetch_limit] is reached. Requests reserved with
+    /// [Data::try_reserve_requests] are not included, unless they were already made.
+    pub fn remaining_requests(&self) -> u32 {
+        self.config
+            .http_fetch_limit
+            .saturating_sub(self.request_count())
+            .saturating_sub(self.request_counter.reservations.total())
+    }
+
+    /// Reserve budget for `n` outgoing HTTP requests.
+    ///
+    /// Use this before starting an operation which needs multiple fetches, to fail early with
+    /// [Error::RequestLimit] instead of halfway through. The reservation is only taken into
+    /// account by other calls to this method, it doesn't change the limit for regular fetches.
+    /// Each request which is made afterwards uses up one reserved request. The rest is released
+    /// when the returned guard is dropped.
+    pub fn try_reserve_requests(&self, n: u32) -> Result<RequestBudgetGuard<'_>, Error> {
+        let reservations = &self.request_counter.reservations;
+        let mut remaining = reservations.lock();
+        let reserved: u32 = remaining.iter().map(|(_, r)| r).sum();
+        let limit = self
+            .config
+            .http_fetch_limit
+            .saturating_sub(self.request_count());
+        if reserved.checked_add(n).is_none_or(|total| total > limit) {
+            return Err(Error::RequestLimit);
+        }
+        let id = reservations.next_id.fetch_add(1, Ordering::Relaxed);
+        remaining.push((id, n));
+        Ok(RequestBudgetGuard {
+            reservations,
+            id,
+            amount: n,
+        })
     }
 
     /// Add HTTP signature to arbitrary request
     pub async fn sign_request(&self, req: RequestBuilder, body: Bytes) -> Result<Request, Error> {
-        let (actor_id, private_key_pem) =
-            self.config
-                .signed_fetch_actor
-                .as_deref()
-                .ok_or(Error::Other(
-                    "config value signed_fetch_actor is none".to_string(),
-                ))?;
+        let signed_fetch_actor = self.signed_fetch_actor().ok_or(Error::Other(
+            "config value signed_fetch_actor is none".to_string(),
+        ))?;
+        let (actor_id, private_key_pem) = signed_fetch_actor.as_ref();
         sign_request(
             req,
-            actor_id,
+            self.config.key_id_strategy.key_id(actor_id),
             body,
             private_key_pem.clone(),
             self.config.http_signature_compat,
+            self.config.use_rfc9421_signatures,
+            self.config.both_digest_headers,
+            &self.config.signing_limiter,
         )
         .await
     }
+
+    /// Timing of signing operations made with this config, across all requests. Can be used to
+    /// monitor how much CPU time is spent on HTTP signatures.
+    pub fn signing_metrics(&self) -> SigningMetrics {
+        self.config.signing_limiter.metrics()
+    }
+
+    /// Current counters of the activity queue, see [FederationConfig::queue_stats].
+    pub fn queue_stats(&self) -> ActivityQueueStats {
+        self.config.queue_stats()
+    }
+
+    /// Number of activity sends which are not finished yet, see [FederationConfig::queue_len].
+    pub fn queue_len(&self) -> usize {
+        self.config.queue_len()
+    }
+
+    /// Software which is running on the host of `url`, if it was already detected. Requires
+    /// [FederationConfigBuilder::detect_peer_software].
+    pub async fn peer_software(&self, url: &Url) -> Option<PeerSoftware> {
+        self.config.peer_software.get(url).await
+    }
 }
 
 impl<T: Clone> Deref for Data<T> {
@@ -411,25 +1513,129 @@ impl<T: Clone> Deref for Data<T> {
 }
 
 /// Middleware for HTTP handlers which provides access to [Data]
+///
+/// Usually the whole application uses a single [FederationConfig]. To serve multiple domains
+/// from one process, each with its own config, use [FederationMiddleware::new_multi].
 #[derive(Clone)]
-pub struct FederationMiddleware<T: Clone>(pub(crate) FederationConfig<T>);
+pub struct FederationMiddleware<T: Clone> {
+    default: FederationConfig<T>,
+    by_host: Arc<HashMap<String, FederationConfig<T>>>,
+}
 
 impl<T: Clone> FederationMiddleware<T> {
     /// Construct a new middleware instance
     pub fn new(config: FederationConfig<T>) -> Self {
-        FederationMiddleware(config)
+        FederationMiddleware {
+            default: config,
+            by_host: Default::default(),
+        }
+    }
+
+    /// Construct a middleware which selects the config by the `Host` header of each request, so
+    /// that [Data] in handlers uses the matching domain, for example to check local urls, to
+    /// answer webfinger requests and to sign fetches. Requests for other hosts use `default`.
+    ///
+    /// Keys are hosts like `example.com`, optionally with port like `localhost:8001`. They are
+    /// compared case-insensitively, and a key without port also matches requests with a port.
+    pub fn new_multi(
+        configs: HashMap<String, FederationConfig<T>>,
+        default: FederationConfig<T>,
+    ) -> Self {
+        let by_host = configs
+            .into_iter()
+            .map(|(host, config)| (host.to_ascii_lowercase(), config))
+            .collect();
+        FederationMiddleware {
+            default,
+            by_host: Arc::new(by_host),
+        }
+    }
+
+    /// Returns the config for a request with the given `Host` header
+    pub(crate) fn config_for_host(&self, host: Option<&str>) -> &FederationConfig<T> {
+        let Some(host) = host.filter(|_| !self.by_host.is_empty()) else {
+            return &self.default;
+        };
+        let host = host.to_ascii_lowercase();
+        let without_port = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => &host,
+        };
+        self.by_host
+            .get(&host)
+            .or_else(|| self.by_host.get(without_port))
+            .unwrap_or(&self.default)
     }
 }
 
 fn default_client() -> ClientWithMiddleware {
+    default_client_builder()
+        .build()
+        .unwrap_or_else(|_| Client::default())
+        .into()
+}
+
+fn default_client_builder() -> ClientBuilder {
     let timeout = Duration::from_secs(10);
     Client::builder()
         .redirect(Policy::none())
         .timeout(timeout)
         .connect_timeout(timeout)
-        .build()
-        .unwrap_or_else(|_| Client::default())
-        .into()
+}
+
+/// DNS resolver which doesn't resolve domains to private or loopback IP addresses, except for
+/// the local domain.
+///
+/// The url of each outgoing request is verified before sending, which includes a DNS lookup to
+/// reject private addresses. However the HTTP client resolves the domain again when connecting.
+/// A malicious DNS server can return a public address for the first lookup and a private address
+/// for the second one (DNS rebinding), so that requests reach internal services. With this
+/// resolver the addresses are checked during the lookup which is used for connecting.
+///
+/// It is used by the default client with [FederationConfigBuilder::safe_dns_resolver]. When
+/// passing a custom client to [FederationConfigBuilder::client], install it with
+/// [ClientBuilder::dns_resolver](reqwest::ClientBuilder::dns_resolver):
+///
+/// ```
+/// # use activitypub_federation::config::SafeDnsResolver;
+/// # use std::sync::Arc;
+/// let client = reqwest::Client::builder()
+///     .dns_resolver(Arc::new(SafeDnsResolver::new("example.com")))
+///     .build()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct SafeDnsResolver {
+    local_domain: String,
+}
+
+impl SafeDnsResolver {
+    /// Create a new resolver. `local_domain` is the same as [FederationConfigBuilder::domain],
+    /// and is allowed to resolve to private addresses, for example when the instance fetches
+    /// its own objects through a reverse proxy.
+    pub fn new(local_domain: &str) -> Self {
+        let local_domain = normalize_domain(local_domain);
+        let local_domain = match local_domain.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host.to_string(),
+            _ => local_domain,
+        };
+        SafeDnsResolver { local_domain }
+    }
+}
+
+impl Resolve for SafeDnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let is_local = name.as_str().eq_ignore_ascii_case(&self.local_domain);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0)).await?.collect();
+            if !is_local && addrs.iter().any(|addr| is_invalid_ip(addr.ip())) {
+                return Err(Box::new(Error::UrlVerificationError(
+                    "Domain resolves to a private IP address",
+                )) as _);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
 }
 
 #[cfg(test)]
@@ -453,6 +1659,163 @@ mod test {
         assert!(!config.is_local_url(&Url::parse("http://other.com")?));
         // ensure that missing domain doesnt cause crash
         assert!(!config.is_local_url(&Url::parse("http://127.0.0.1")?));
+        // Different spellings of the local domain
+        assert!(config.is_local_url(&Url::parse("https://Example.COM/u/Alice")?));
+        assert!(config.is_local_url(&Url::parse("https://example.com:443/u/alice#main-key")?));
+        assert!(!config.is_local_url(&Url::parse("https://example.com:8443/u/alice")?));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_url_is_local_idn() -> Result<(), Error> {
+        let config = FederationConfig::builder()
+            .domain("bücher.example")
+            .app_data(1)
+            .build()
+            .await
+            .unwrap();
+        assert!(config.is_local_url(&Url::parse("https://bücher.example/u/alice")?));
+        assert!(config.is_local_url(&Url::parse("https://xn--bcher-kva.example/u/alice")?));
+        assert!(!config.is_local_url(&Url::parse("https://bucher.example/u/alice")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_json_depth_limit() {
+        let builder = || {
+            let mut builder = FederationConfig::builder();
+            builder.domain("example.com").app_data(1);
+            builder
+        };
+        assert!(builder().max_json_depth(128).build_lazy().is_ok());
+        assert!(matches!(
+            builder().max_json_depth(129).build_lazy(),
+            Err(FederationConfigBuilderError::ValidationError(_))
+        ));
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingVerifier(Arc<std::sync::Mutex<Vec<VerifyContext>>>);
+
+    #[async_trait]
+    impl UrlVerifier for RecordingVerifier {
+        async fn verify_with_context(&self, _: &Url, context: VerifyContext) -> Result<(), Error> {
+            self.0.lock().unwrap().push(context);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_url_verifier_context() -> Result<(), Error> {
+        use crate::{
+            activity_sending::SendActivityTask,
+            fetch::{object_id::ObjectId, webfinger::webfinger_resolve_actor},
+            traits::tests::{DbConnection, DbUser, Follow, DB_USER},
+        };
+
+        let verifier = RecordingVerifier::default();
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .url_verifier(Box::new(verifier.clone()))
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+
+        let activity = Follow {
+            actor: ObjectId::parse("http://localhost:123")?,
+            object: ObjectId::parse("http://localhost:124")?,
+            kind: Default::default(),
+            id: "http://localhost:123/1".parse()?,
+        };
+        data.config.verify_url_and_domain(&activity).await?;
+        let inbox = Url::parse("http://localhost:124/inbox")?;
+        SendActivityTask::prepare(&activity, &*DB_USER, vec![inbox], &data).await?;
+        // Nothing listens on port 1, so these fail after verifying the url
+        let user = ObjectId::<DbUser>::parse("http://localhost:1/u/alice")?;
+        assert!(user.dereference_forced(&data).await.is_err());
+        assert!(
+            webfinger_resolve_actor::<_, DbUser>("alice@localhost:1", &data)
+                .await
+                .is_err()
+        );
+
+        assert_eq!(
+            *verifier.0.lock().unwrap(),
+            vec![
+                VerifyContext::IncomingActivity,
+                VerifyContext::OutgoingDelivery,
+                VerifyContext::Fetch,
+                VerifyContext::Webfinger,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_invalid_ip() {
+        let invalid = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "0.0.0.0",
+            "0.1.2.3",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "::ffff:192.168.1.1",
+            "fd00::1",
+        ];
+        for ip in invalid {
+            assert!(is_invalid_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "::ffff:1.1.1.1", "2606:4700::1111"] {
+            assert!(!is_invalid_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_safe_dns_resolver() -> Result<(), Error> {
+        use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+        let client = |domain: String, debug: bool| async move {
+            FederationConfig::builder()
+                .domain(domain)
+                .app_data(1)
+                .debug(debug)
+                .safe_dns_resolver(true)
+                .build()
+                .await
+                .unwrap()
+                .client
+                .clone()
+        };
+        // Simulates a domain which resolved to a public address during verification, but to
+        // a private address when connecting
+        let url = format!("http://localhost:{port}/");
+        let res = client("example.com".to_string(), false)
+            .await
+            .get(&url)
+            .send()
+            .await;
+        assert!(res.is_err());
+
+        // The local domain and debug mode are not restricted
+        let local = client(format!("Localhost:{port}"), false).await;
+        assert!(local.get(&url).send().await?.status().is_success());
+        let debug = client("example.com".to_string(), true).await;
+        assert!(debug.get(&url).send().await?.status().is_success());
         Ok(())
     }
 
@@ -461,4 +1824,63 @@ mod test {
         let config = config().await;
         assert_eq!("example.com", config.domain());
     }
+
+    #[tokio::test]
+    async fn test_reserve_requests() -> Result<(), Error> {
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(1)
+            .http_fetch_limit(10)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        for _ in 0..7 {
+            data.request_counter.increment(RequestKind::Object);
+        }
+        assert_eq!(data.request_count(), 7);
+        assert_eq!(data.object_fetch_count(), 7);
+        assert_eq!(data.remaining_requests(), 3);
+
+        let guard = data.try_reserve_requests(2)?;
+        assert_eq!(guard.amount(), 2);
+        assert_eq!(data.remaining_requests(), 1);
+        assert_eq!(
+            data.try_reserve_requests(2).err(),
+            Some(Error::RequestLimit)
+        );
+        drop(guard);
+
+        assert_eq!(data.try_reserve_requests(3)?.amount(), 3);
+        assert_eq!(
+            data.try_reserve_requests(4).err(),
+            Some(Error::RequestLimit)
+        );
+
+        // requests made under a reservation are only counted once
+        let guard = data.try_reserve_requests(2)?;
+        assert_eq!(data.remaining_requests(), 1);
+        data.request_counter.increment(RequestKind::Object);
+        assert_eq!(data.remaining_requests(), 1);
+        data.request_counter.increment(RequestKind::Object);
+        assert_eq!(data.remaining_requests(), 1);
+        data.request_counter.increment(RequestKind::Object);
+        assert_eq!(data.remaining_requests(), 0);
+        drop(guard);
+        assert_eq!(data.remaining_requests(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_received_activity_nested() {
+        let data = config().await.to_request_data();
+        let outer = data.received_activity.enter(Bytes::from("outer"));
+        {
+            let _inner = data.received_activity.enter(Bytes::from("inner"));
+            assert_eq!(data.received_activity_bytes(), Some(Bytes::from("inner")));
+        }
+        assert_eq!(data.received_activity_bytes(), Some(Bytes::from("outer")));
+        drop(outer);
+        assert_eq!(data.received_activity_bytes(), None);
+    }
 }
@@ -1,17 +1,29 @@
 //! Error messages returned by this library
 
-use crate::fetch::webfinger::WebFingerError;
+use crate::{fetch::webfinger::WebFingerError, protocol::tombstone::Tombstone};
+use http::StatusCode;
 use http_signature_normalization_reqwest::SignError;
 use rsa::{
     errors::Error as RsaError,
     pkcs8::{spki::Error as SpkiError, Error as Pkcs8Error},
 };
-use std::string::FromUtf8Error;
+use serde::Deserialize;
+use serde_json::Value;
+use serde_path_to_error::{Path, Segment};
+use std::{
+    fmt::{Display, Formatter},
+    string::FromUtf8Error,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::task::JoinError;
 use url::Url;
 
 /// Error messages returned by this library
-#[derive(thiserror::Error, Debug)]
+///
+/// Errors from other crates which don't implement [Clone] are wrapped in [Arc], so that results
+/// containing this error can be cached.
+#[derive(thiserror::Error, Debug, Clone)]
 pub enum Error {
     /// Object was not found in local database
     #[error("Object was not found in local database")]
@@ -19,42 +31,70 @@ pub enum Error {
     /// Request limit was reached during fetch
     #[error("Request limit was reached during fetch")]
     RequestLimit,
+    /// The time for fetches set with
+    /// [FederationConfigBuilder::request_deadline](crate::config::FederationConfigBuilder::request_deadline)
+    /// is used up
+    #[error("Request deadline was exceeded during fetch")]
+    RequestDeadlineExceeded,
     /// Response body limit was reached during fetch
     #[error("Response body limit was reached during fetch")]
     ResponseBodyLimit,
-    /// Object to be fetched was deleted
+    /// Incoming request body is larger than
+    /// [FederationConfigBuilder::max_incoming_body_size](crate::config::FederationConfigBuilder::max_incoming_body_size)
+    #[error("Incoming request body exceeds the size limit")]
+    RequestBodyLimit,
+    /// JSON arrays and objects of a received activity or fetched object are nested deeper than
+    /// [FederationConfigBuilder::max_json_depth](crate::config::FederationConfigBuilder::max_json_depth)
+    #[error("JSON data exceeds the nesting depth limit")]
+    JsonDepthLimit,
+    /// Object to be fetched was deleted. Contains the tombstone if the server returned one
+    /// instead of the object.
     #[error("Fetched remote object {0} which was deleted")]
-    ObjectDeleted(Url),
+    ObjectDeleted(Url, Option<Box<Tombstone>>),
     /// url verification error
     #[error("URL failed verification: {0}")]
     UrlVerificationError(&'static str),
     /// Incoming activity has invalid digest for body
     #[error("Incoming activity has invalid digest for body")]
     ActivityBodyDigestInvalid,
+    /// The `Digest` or `Content-Digest` header of an incoming activity contains no SHA-256 hash,
+    /// only hashes with the given other algorithm
+    #[error("Incoming activity has digest with unsupported algorithm {0}")]
+    UnsupportedDigestAlgorithm(String),
     /// Incoming activity has invalid signature
     #[error("Incoming activity has invalid signature")]
     ActivitySignatureInvalid,
+    /// Incoming activity is signed with the key of a different actor than its `actor` field. See
+    /// [FederationConfigBuilder::allow_same_origin_announce_forwarding](crate::config::FederationConfigBuilder::allow_same_origin_announce_forwarding)
+    /// for an exception.
+    #[error("Incoming activity claims to be by {claimed}, but is signed by {signer}")]
+    SignatureActorMismatch {
+        /// Actor of the signature key
+        signer: Box<Url>,
+        /// Actor of the activity
+        claimed: Box<Url>,
+    },
     /// Failed to resolve actor via webfinger
     #[error("Failed to resolve actor via webfinger")]
     WebfingerResolveFailed(#[from] WebFingerError),
     /// Failed to serialize outgoing activity
     #[error("Failed to serialize outgoing activity {1}: {0}")]
-    SerializeOutgoingActivity(serde_json::Error, String),
+    SerializeOutgoingActivity(Arc<serde_json::Error>, String),
     /// Failed to parse an object fetched from url
     #[error("Failed to parse object {1} with content {2}: {0}")]
-    ParseFetchedObject(serde_json::Error, Url, String),
+    ParseFetchedObject(Box<JsonError>, Url, String),
     /// Failed to parse an activity received from another instance
     #[error("Failed to parse incoming activity {}: {0}", match .1 {
         Some(t) => format!("with id {t}"),
         None => String::new(),
     })]
-    ParseReceivedActivity(serde_json::Error, Option<Url>),
+    ParseReceivedActivity(Box<JsonError>, Option<Url>),
     /// Reqwest Middleware Error
     #[error(transparent)]
-    ReqwestMiddleware(#[from] reqwest_middleware::Error),
+    ReqwestMiddleware(Arc<reqwest_middleware::Error>),
     /// Reqwest Error
     #[error(transparent)]
-    Reqwest(#[from] reqwest::Error),
+    Reqwest(Arc<reqwest::Error>),
     /// UTF-8 error
     #[error(transparent)]
     Utf8(#[from] FromUtf8Error),
@@ -63,13 +103,48 @@ pub enum Error {
     UrlParse(#[from] url::ParseError),
     /// Signing errors
     #[error(transparent)]
-    SignError(#[from] SignError),
+    SignError(Arc<SignError>),
     /// Failed to queue activity for sending
     #[error("Failed to queue activity {0} for sending")]
     ActivityQueueError(Url),
+    /// The inbox host responded with status 429, or asked to wait with `Retry-After` before
+    /// sending more activities. Contains the time to wait, if known.
+    #[error("Sending to inbox {0} is rate limited")]
+    RateLimited(Url, Option<Duration>),
+    /// Sending to the inbox host failed too often in a row, so that further sends fail
+    /// immediately until the cool-down is over. Contains the remaining time of the cool-down. See
+    /// [FederationConfigBuilder::circuit_breaker](crate::config::FederationConfigBuilder::circuit_breaker).
+    #[error("Sending to inbox {0} is paused after repeated failures")]
+    HostUnavailable(Url, Duration),
+    /// The inbox responded to an outgoing activity with a server error or another status which
+    /// is not final, so that sending should be retried later
+    #[error("Sending activity to {inbox} failed with status {status}: {body}")]
+    DeliveryFailed {
+        /// Status of the response
+        status: StatusCode,
+        /// Inbox which the activity was sent to
+        inbox: Url,
+        /// Response body, truncated to a limited size
+        body: String,
+    },
+    /// The inbox rejected an outgoing activity with a client error such as `403 Forbidden`.
+    /// Sending it again wouldn't change the outcome, so it is not retried.
+    #[error("Inbox {inbox} rejected activity with status {status}")]
+    DeliveryRejected {
+        /// Status of the response
+        status: StatusCode,
+        /// Inbox which the activity was sent to
+        inbox: Url,
+    },
+    /// Actor has no private key, so that activities can't be signed
+    #[error("Actor {0} does not contain a private key for signing")]
+    MissingPrivateKey(Url),
+    /// Private key of an actor is not valid PEM data
+    #[error("Could not create private key from PEM data: {0}")]
+    KeyParse(String),
     /// Stop activity queue
     #[error(transparent)]
-    StopActivityQueue(#[from] JoinError),
+    StopActivityQueue(Arc<JoinError>),
     /// Attempted to fetch object which doesn't have valid ActivityPub Content-Type
     #[error(
         "Attempted to fetch object from {0} which doesn't have valid ActivityPub Content-Type"
@@ -78,14 +153,205 @@ pub enum Error {
     /// Attempted to fetch object but the response's id field doesn't match
     #[error("Attempted to fetch object from {0} but the response's id field doesn't match")]
     FetchWrongId(Url),
+    /// Fetched object body is not valid in any supported charset
+    #[error("Attempted to fetch object from {0} but the response body is not valid UTF-8, ISO-8859-1 or Windows-1252")]
+    FetchInvalidEncoding(Url),
+    /// Object attempted to dereference itself while being dereferenced
+    #[error(
+        "Object {0} attempted to dereference itself, this is likely a bug in Object::verify or Object::from_json which should use the received json instead"
+    )]
+    SelfReferentialFetch(Url),
     /// I/O error from OS
     #[error(transparent)]
-    IoError(#[from] std::io::Error),
+    IoError(Arc<std::io::Error>),
     /// Other generic errors
     #[error("{0}")]
     Other(String),
 }
 
+/// Error from parsing JSON data, with the location of the invalid field.
+///
+/// Used by [Error::ParseFetchedObject] and [Error::ParseReceivedActivity], so that it is possible
+/// to find out why data from another instance couldn't be parsed.
+#[derive(Debug, Clone)]
+pub struct JsonError {
+    /// Error returned by serde_json
+    pub error: Arc<serde_json::Error>,
+    /// Path of the field which failed to parse, for example `object.tag[0].href`. Empty if the
+    /// error is not inside a field.
+    pub path: String,
+    /// The `type` field of the JSON data, if present
+    pub kind: Option<String>,
+    /// JSON value at `path`, truncated to [JsonError::MAX_VALUE_LENGTH] characters
+    pub value: Option<String>,
+}
+
+impl JsonError {
+    /// Maximum number of characters from the invalid value which are included in the error
+    pub const MAX_VALUE_LENGTH: usize = 100;
+
+    /// Parses `json`, returning the path of the invalid field on error
+    pub(crate) fn parse<'de, T: Deserialize<'de>>(json: &'de [u8]) -> Result<T, JsonError> {
+        let mut deserializer = serde_json::Deserializer::from_slice(json);
+        let parsed = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+            let path = e.path().clone();
+            JsonError::new(
+                e.into_inner(),
+                Some(&path),
+                serde_json::from_slice(json).ok(),
+            )
+        })?;
+        deserializer
+            .end()
+            .map_err(|e| JsonError::new(e, None, None))?;
+        Ok(parsed)
+    }
+
+    /// Same as [JsonError::parse], but for data which was already parsed as [Value]
+    pub(crate) fn from_value<T: for<'de> Deserialize<'de>>(json: Value) -> Result<T, JsonError> {
+        serde_path_to_error::deserialize(&json).map_err(|e| {
+            let path = e.path().clone();
+            JsonError::new(e.into_inner(), Some(&path), Some(json.clone()))
+        })
+    }
+
+    fn new(error: serde_json::Error, path: Option<&Path>, json: Option<Value>) -> JsonError {
+        let kind = json
+            .as_ref()
+            .and_then(|json| json.get("type"))
+            .and_then(Value::as_str)
+            .map(ToString::to_string);
+        let path = path.filter(|path| path.iter().next().is_some());
+        // Look up the invalid value by following the path
+        let value = path.zip(json.as_ref()).and_then(|(path, json)| {
+            path.iter().try_fold(json, |value, segment| match segment {
+                Segment::Seq { index } => value.get(index),
+                Segment::Map { key } => value.get(key),
+                Segment::Enum { .. } | Segment::Unknown => None,
+            })
+        });
+        JsonError {
+            error: Arc::new(error),
+            path: path.map(ToString::to_string).unwrap_or_default(),
+            kind,
+            value: value.map(|value| truncate(&value.to_string(), Self::MAX_VALUE_LENGTH)),
+        }
+    }
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(kind) = &self.kind {
+            write!(f, "type {kind}, ")?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "field {}", self.path)?;
+            if let Some(value) = &self.value {
+                write!(f, " = {value}")?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// Start of a request or response body for debug logs, at most 500 bytes
+pub(crate) fn body_snippet(body: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(&body[..body.len().min(500)])
+}
+
+/// Returns the first `max` characters of `text`, followed by `...` if anything was removed
+pub(crate) fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+impl Error {
+    /// HTTP status code for responding to an incoming request which failed with this error, for
+    /// example because of an invalid signature or an actor that can't be fetched.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::ObjectDeleted(..) => StatusCode::GONE,
+            Error::UrlVerificationError(_) => StatusCode::FORBIDDEN,
+            Error::RequestBodyLimit => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::ActivityBodyDigestInvalid
+            | Error::UnsupportedDigestAlgorithm(_)
+            | Error::ActivitySignatureInvalid
+            | Error::SignatureActorMismatch { .. } => StatusCode::UNAUTHORIZED,
+            Error::RequestLimit
+            | Error::RequestDeadlineExceeded
+            | Error::ResponseBodyLimit
+            | Error::WebfingerResolveFailed(_)
+            | Error::ParseFetchedObject(..)
+            | Error::ParseReceivedActivity(..)
+            | Error::JsonDepthLimit
+            | Error::ReqwestMiddleware(_)
+            | Error::Reqwest(_)
+            | Error::UrlParse(_)
+            | Error::FetchInvalidContentType(_)
+            | Error::FetchWrongId(_)
+            | Error::FetchInvalidEncoding(_)
+            | Error::SelfReferentialFetch(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Returns true if sending an activity which failed with this error should be retried later,
+    /// because the inbox was unreachable or returned a temporary error. Errors which would occur
+    /// again with every attempt, such as [Error::DeliveryRejected] or [Error::MissingPrivateKey],
+    /// are not retryable.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::DeliveryFailed { .. }
+                | Error::RateLimited(..)
+                | Error::HostUnavailable(..)
+                | Error::ReqwestMiddleware(_)
+                | Error::Reqwest(_)
+                | Error::IoError(_)
+        )
+    }
+}
+
+impl From<reqwest_middleware::Error> for Error {
+    fn from(value: reqwest_middleware::Error) -> Self {
+        Error::ReqwestMiddleware(Arc::new(value))
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Error::Reqwest(Arc::new(value))
+    }
+}
+
+impl From<SignError> for Error {
+    fn from(value: SignError) -> Self {
+        Error::SignError(Arc::new(value))
+    }
+}
+
+impl From<JoinError> for Error {
+    fn from(value: JoinError) -> Self {
+        Error::StopActivityQueue(Arc::new(value))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::IoError(Arc::new(value))
+    }
+}
+
 impl From<RsaError> for Error {
     fn from(value: RsaError) -> Self {
         Error::Other(value.to_string())
@@ -109,3 +375,122 @@ impl PartialEq for Error {
         std::mem::discriminant(self) == std::mem::discriminant(other)
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_json_error_path() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Tag {
+            href: Url,
+        }
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Note {
+            tag: Vec<Tag>,
+        }
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Create {
+            object: Note,
+        }
+
+        let json =
+            br#"{"type":"Create","object":{"tag":[{"href":"https://example.com/"},{"href":42}]}}"#;
+        let error = JsonError::parse::<Create>(json).unwrap_err();
+        assert_eq!(error.path, "object.tag[1].href");
+        assert_eq!(error.kind.as_deref(), Some("Create"));
+        assert_eq!(error.value.as_deref(), Some("42"));
+        assert!(error
+            .to_string()
+            .starts_with("type Create, field object.tag[1].href = 42: invalid type: integer `42`"));
+
+        let json = serde_json::json!({ "object": { "tag": "x".repeat(200) } });
+        let error = JsonError::from_value::<Create>(json).unwrap_err();
+        assert_eq!(error.path, "object.tag");
+        assert_eq!(error.kind, None);
+        let value = error.value.unwrap();
+        assert_eq!(value, format!("\"{}...", "x".repeat(99)));
+
+        // Errors which are not inside a field have no path
+        let error = JsonError::parse::<Create>(b"[]").unwrap_err();
+        assert_eq!(error.path, "");
+        assert_eq!(error.value, None);
+    }
+
+    #[tokio::test]
+    async fn test_clone_error() {
+        let url = Url::parse("https://example.com/object").unwrap();
+        let json_error = || Arc::new(serde_json::from_str::<u8>("{").unwrap_err());
+        let reqwest_error = || reqwest::Client::new().get("invalid").build().unwrap_err();
+        let join_error = {
+            let handle = tokio::spawn(std::future::pending::<()>());
+            handle.abort();
+            handle.await.unwrap_err()
+        };
+        let errors = vec![
+            Error::NotFound,
+            Error::RequestLimit,
+            Error::RequestDeadlineExceeded,
+            Error::ResponseBodyLimit,
+            Error::RequestBodyLimit,
+            Error::JsonDepthLimit,
+            Error::ObjectDeleted(url.clone(), None),
+            Error::UrlVerificationError("Domains do not match"),
+            Error::ActivityBodyDigestInvalid,
+            Error::UnsupportedDigestAlgorithm("SHA-512".to_string()),
+            Error::ActivitySignatureInvalid,
+            WebFingerError::NotFound.into(),
+            Error::SerializeOutgoingActivity(json_error(), "activity".to_string()),
+            Error::ParseFetchedObject(
+                Box::new(JsonError::parse::<u8>(b"{").unwrap_err()),
+                url.clone(),
+                "{".to_string(),
+            ),
+            Error::ParseReceivedActivity(
+                Box::new(JsonError::parse::<u8>(b"{").unwrap_err()),
+                Some(url.clone()),
+            ),
+            reqwest_middleware::Error::from(reqwest_error()).into(),
+            reqwest_error().into(),
+            String::from_utf8(vec![0xff]).unwrap_err().into(),
+            Url::parse("invalid").unwrap_err().into(),
+            SignError::BodyPresent.into(),
+            Error::ActivityQueueError(url.clone()),
+            Error::RateLimited(url.clone(), Some(Duration::from_secs(1))),
+            Error::HostUnavailable(url.clone(), Duration::from_secs(1)),
+            Error::DeliveryFailed {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                inbox: url.clone(),
+                body: "error".to_string(),
+            },
+            Error::DeliveryRejected {
+                status: StatusCode::FORBIDDEN,
+                inbox: url.clone(),
+            },
+            Error::MissingPrivateKey(url.clone()),
+            Error::KeyParse("invalid".to_string()),
+            join_error.into(),
+            Error::FetchInvalidContentType(url.clone()),
+            Error::FetchWrongId(url.clone()),
+            Error::FetchInvalidEncoding(url.clone()),
+            Error::SelfReferentialFetch(url.clone()),
+            std::io::Error::other("io").into(),
+            Error::Other("other".to_string()),
+        ];
+        for error in errors {
+            let cloned = error.clone();
+            assert_eq!(cloned, error);
+            assert_eq!(cloned.to_string(), error.to_string());
+            assert_eq!(
+                cloned.source().map(ToString::to_string),
+                error.source().map(ToString::to_string)
+            );
+        }
+    }
+}
@@ -0,0 +1,571 @@
+//! In-memory storage with complete trait implementations, for quick-starts and tests
+//!
+//! Implementing [Object], [Actor] and [Collection] requires a database. This module provides a
+//! minimal in-memory replacement, so that federation can be tried out without setting up any
+//! persistence. It is also useful as reference implementation and for integration tests of
+//! downstream crates. Data is lost when the process exits, so don't use this in production.
+//!
+//! ```
+//! # use activitypub_federation::config::FederationConfig;
+//! # use activitypub_federation::example_storage::{DbUser, InMemoryStorage};
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let storage = InMemoryStorage::default();
+//! let alice = DbUser::new(
+//!     "alice",
+//!     "https://example.com/u/alice".parse()?,
+//!     "https://example.com/u/alice/inbox".parse()?,
+//! )?;
+//! storage.upsert_user(alice.clone());
+//!
+//! let config = FederationConfig::builder()
+//!     .domain("example.com")
+//!     .app_data(storage)
+//!     .build()
+//!     .await?;
+//! assert_eq!(config.read_local_user("alice").map(|u| u.ap_id), Some(alice.ap_id));
+//! # Ok::<(), anyhow::Error>(())
+//! # }).unwrap();
+//! ```
+
+use crate::{
+    config::Data,
+    error::Error,
+    fetch::object_id::ObjectId,
+    http_signatures::{generate_actor_keypair, Keypair},
+    instance_actor::InstanceKeypairStore,
+    kinds::{actor::PersonType, collection::OrderedCollectionType, object::NoteType, public},
+    protocol::{
+        endpoints::Endpoints,
+        helpers::deserialize_one_or_many,
+        public_key::PublicKey,
+        tombstone::Tombstone,
+        verification::verify_domains_match,
+    },
+    traits::{Actor, Collection, Object},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use url::Url;
+
+/// Stores users and posts in memory. Cloning is cheap and all clones share the same data.
+///
+/// Users and posts are keyed by their [ObjectId], and can be looked up with a plain [Url].
+#[derive(Clone, Default)]
+pub struct InMemoryStorage {
+    users: Arc<RwLock<HashMap<ObjectId<DbUser>, DbUser>>>,
+    posts: Arc<RwLock<HashMap<ObjectId<DbPost>, DbPost>>>,
+    tombstones: Arc<RwLock<HashMap<Url, Tombstone>>>,
+    instance_keypair: Arc<RwLock<Option<Keypair>>>,
+}
+
+impl InMemoryStorage {
+    /// Inserts the user, or replaces an existing user with the same `ap_id`.
+    pub fn upsert_user(&self, user: DbUser) {
+        let mut users = self.users.write().expect("lock users");
+        users.insert(user.ap_id.clone(), user);
+    }
+
+    /// Reads a local or remote user by its Activitypub id.
+    pub fn read_user(&self, ap_id: &Url) -> Option<DbUser> {
+        self.users.read().expect("lock users").get(ap_id).cloned()
+    }
+
+    /// Reads a local user by its name, eg for webfinger or HTTP handlers.
+    pub fn read_local_user(&self, name: &str) -> Option<DbUser> {
+        self.users
+            .read()
+            .expect("lock users")
+            .values()
+            .find(|u| u.local && u.name == name)
+            .cloned()
+    }
+
+    /// Inserts the post, or replaces an existing post with the same `ap_id`.
+    pub fn upsert_post(&self, post: DbPost) {
+        let mut posts = self.posts.write().expect("lock posts");
+        posts.insert(post.ap_id.clone(), post);
+    }
+
+    /// Reads a local or remote post by its Activitypub id.
+    pub fn read_post(&self, ap_id: &Url) -> Option<DbPost> {
+        self.posts.read().expect("lock posts").get(ap_id).cloned()
+    }
+
+    /// Removes the post with the id of `tombstone`, and keeps the tombstone in its place.
+    pub fn delete_post(&self, tombstone: Tombstone) {
+        self.posts
+            .write()
+            .expect("lock posts")
+            .remove(&tombstone.id);
+        let mut tombstones = self.tombstones.write().expect("lock tombstones");
+        tombstones.insert(tombstone.id.clone(), tombstone);
+    }
+
+    /// Reads the tombstone of a deleted post by its Activitypub id.
+    pub fn read_tombstone(&self, ap_id: &Url) -> Option<Tombstone> {
+        self.tombstones
+            .read()
+            .expect("lock tombstones")
+            .get(ap_id)
+            .cloned()
+    }
+
+    /// Returns all stored posts, in no particular order.
+    pub fn posts(&self) -> Vec<DbPost> {
+        self.posts
+            .read()
+            .expect("lock posts")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds `follower` to the followers of user `ap_id`. Does nothing if the user is unknown
+    /// or already followed by `follower`.
+    pub fn add_follower(&self, ap_id: &Url, follower: Url) {
+        let mut users = self.users.write().expect("lock users");
+        if let Some(user) = users.get_mut(ap_id) {
+            if !user.followers.contains(&follower) {
+                user.followers.push(follower);
+            }
+        }
+    }
+
+    /// Removes `follower` from the followers of user `ap_id`.
+    pub fn remove_follower(&self, ap_id: &Url, follower: &Url) {
+        let mut users = self.users.write().expect("lock users");
+        if let Some(user) = users.get_mut(ap_id) {
+            user.followers.retain(|f| f != follower);
+        }
+    }
+}
+
+/// Keeps the keypair of the [InstanceActor](crate::instance_actor::InstanceActor) in memory, so
+/// a new one is generated after each restart.
+#[async_trait]
+impl InstanceKeypairStore for InMemoryStorage {
+    async fn read_instance_keypair(&self) -> Result<Option<Keypair>, Error> {
+        Ok(self.instance_keypair.read().expect("lock keypair").clone())
+    }
+
+    async fn write_instance_keypair(&self, keypair: &Keypair) -> Result<(), Error> {
+        *self.instance_keypair.write().expect("lock keypair") = Some(keypair.clone());
+        Ok(())
+    }
+}
+
+/// A local or remote user.
+#[derive(Clone, Debug)]
+pub struct DbUser {
+    /// Username, used for webfinger
+    pub name: String,
+    /// Activitypub id of the user
+    pub ap_id: ObjectId<DbUser>,
+    /// Inbox where activities for this user are delivered
+    pub inbox: Url,
+    /// Inbox shared by all users of the instance, if any
+    pub shared_inbox: Option<Url>,
+    /// Exists for all users, necessary to verify HTTP signatures
+    pub public_key: String,
+    /// Exists only for local users
+    pub private_key: Option<String>,
+    /// Time when the user was last fetched from its instance
+    pub last_refreshed_at: DateTime<Utc>,
+    /// Activitypub ids of users following this user
+    pub followers: Vec<Url>,
+    /// True if the user belongs to the local instance
+    pub local: bool,
+}
+
+impl DbUser {
+    /// Creates a new local user with a freshly generated keypair.
+    pub fn new(name: &str, ap_id: Url, inbox: Url) -> Result<DbUser, Error> {
+        let keypair = generate_actor_keypair()?;
+        Ok(DbUser {
+            name: name.to_string(),
+            ap_id: ap_id.into(),
+            inbox,
+            shared_inbox: None,
+            public_key: keypair.public_key,
+            private_key: Some(keypair.private_key),
+            last_refreshed_at: Utc::now(),
+            followers: vec![],
+            local: true,
+        })
+    }
+
+    /// Url of the followers collection for this user.
+    pub fn followers_url(&self) -> Result<Url, Error> {
+        Ok(Url::parse(&format!("{}/followers", self.ap_id.inner()))?)
+    }
+}
+
+/// Activitypub representation of [DbUser].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Person {
+    #[serde(rename = "type")]
+    kind: PersonType,
+    /// Username of the actor
+    pub preferred_username: String,
+    /// Activitypub id of the actor
+    pub id: ObjectId<DbUser>,
+    /// Inbox of the actor
+    pub inbox: Url,
+    /// Public key for verifying HTTP signatures
+    pub public_key: PublicKey,
+    /// Contains the shared inbox, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoints: Option<Endpoints>,
+}
+
+#[async_trait]
+impl Object for DbUser {
+    type DataType = InMemoryStorage;
+    type Kind = Person;
+    type Error = Error;
+
+    fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
+        Some(self.last_refreshed_at)
+    }
+
+    async fn read_from_id(
+        object_id: Url,
+        data: &Data<Self::DataType>,
+    ) -> Result<Option<Self>, Self::Error> {
+        Ok(data.read_user(&object_id))
+    }
+
+    async fn into_json(self, data: &Data<Self::DataType>) -> Result<Self::Kind, Self::Error> {
+        Ok(Person {
+            kind: Default::default(),
+            preferred_username: self.name.clone(),
+            id: self.ap_id.clone(),
+            inbox: self.inbox.clone(),
+            public_key: self.public_key_with_strategy(data.key_id_strategy()),
+            endpoints: self.endpoints(),
+        })
+    }
+
+    async fn verify(
+        json: &Self::Kind,
+        expected_domain: &Url,
+        _data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        verify_domains_match(json.id.inner(), expected_domain)?;
+        Ok(())
+    }
+
+    async fn from_json(json: Self::Kind, data: &Data<Self::DataType>) -> Result<Self, Self::Error> {
+        // Keep known followers when updating an existing user
+        let followers = data
+            .read_user(json.id.inner())
+            .map(|u| u.followers)
+            .unwrap_or_default();
+        let user = DbUser {
+            name: json.preferred_username,
+            ap_id: json.id,
+            inbox: json.inbox,
+            shared_inbox: json.endpoints.and_then(|e| e.shared_inbox),
+            public_key: json.public_key.public_key_pem,
+            private_key: None,
+            last_refreshed_at: Utc::now(),
+            followers,
+            local: false,
+        };
+        data.upsert_user(user.clone());
+        Ok(user)
+    }
+}
+
+impl Actor for DbUser {
+    fn id(&self) -> Url {
+        self.ap_id.inner().clone()
+    }
+
+    fn public_key_pem(&self) -> &str {
+        &self.public_key
+    }
+
+    fn private_key_pem(&self) -> Option<String> {
+        self.private_key.clone()
+    }
+
+    fn inbox(&self) -> Url {
+        self.inbox.clone()
+    }
+
+    fn shared_inbox(&self) -> Option<Url> {
+        self.shared_inbox.clone()
+    }
+}
+
+/// A local or remote post.
+#[derive(Clone, Debug)]
+pub struct DbPost {
+    /// Text content of the post
+    pub text: String,
+    /// Activitypub id of the post
+    pub ap_id: ObjectId<DbPost>,
+    /// User who wrote the post
+    pub creator: ObjectId<DbUser>,
+    /// True if the post belongs to the local instance
+    pub local: bool,
+}
+
+impl DbPost {
+    /// Creates a new local post.
+    pub fn new(text: String, ap_id: Url, creator: ObjectId<DbUser>) -> DbPost {
+        DbPost {
+            text,
+            ap_id: ap_id.into(),
+            creator,
+            local: true,
+        }
+    }
+}
+
+/// Activitypub representation of [DbPost].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Note {
+    #[serde(rename = "type")]
+    kind: NoteType,
+    /// Activitypub id of the post
+    pub id: ObjectId<DbPost>,
+    /// User who wrote the post
+    pub attributed_to: ObjectId<DbUser>,
+    /// Recipients of the post
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub to: Vec<Url>,
+    /// Text content of the post
+    pub content: String,
+}
+
+#[async_trait]
+impl Object for DbPost {
+    type DataType = InMemoryStorage;
+    type Kind = Note;
+    type Error = Error;
+
+    async fn read_from_id(
+        object_id: Url,
+        data: &Data<Self::DataType>,
+    ) -> Result<Option<Self>, Self::Error> {
+        Ok(data.read_post(&object_id))
+    }
+
+    async fn delete(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        data.delete_post(Tombstone::new(self.ap_id.into_inner()).with_former_type("Note"));
+        Ok(())
+    }
+
+    async fn into_json(self, data: &Data<Self::DataType>) -> Result<Self::Kind, Self::Error> {
+        let creator = self.creator.dereference_local(data).await?;
+        Ok(Note {
+            kind: Default::default(),
+            id: self.ap_id,
+            attributed_to: self.creator,
+            to: vec![public(), creator.followers_url()?],
+            content: self.text,
+        })
+    }
+
+    async fn verify(
+        json: &Self::Kind,
+        expected_domain: &Url,
+        _data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        verify_domains_match(json.id.inner(), expected_domain)?;
+        verify_domains_match(json.id.inner(), json.attributed_to.inner())?;
+        Ok(())
+    }
+
+    async fn from_json(json: Self::Kind, data: &Data<Self::DataType>) -> Result<Self, Self::Error> {
+        let post = DbPost {
+            text: json.content,
+            ap_id: json.id,
+            creator: json.attributed_to,
+            local: false,
+        };
+        data.upsert_post(post.clone());
+        Ok(post)
+    }
+}
+
+/// Followers of a [DbUser], as list of actor ids.
+#[derive(Clone, Debug)]
+pub struct DbFollowers(pub Vec<Url>);
+
+/// Activitypub representation of [DbFollowers].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Followers {
+    #[serde(rename = "type")]
+    kind: OrderedCollectionType,
+    /// Activitypub id of the collection
+    pub id: Url,
+    /// Number of followers
+    pub total_items: usize,
+    /// Activitypub ids of the followers
+    pub ordered_items: Vec<Url>,
+}
+
+#[async_trait]
+impl Collection for DbFollowers {
+    type Owner = DbUser;
+    type DataType = InMemoryStorage;
+    type Kind = Followers;
+    type Error = Error;
+
+    async fn read_local(
+        owner: &Self::Owner,
+        data: &Data<Self::DataType>,
+    ) -> Result<Self::Kind, Self::Error> {
+        let followers = data
+            .read_user(owner.ap_id.inner())
+            .map(|u| u.followers)
+            .unwrap_or_default();
+        Ok(Followers {
+            kind: Default::default(),
+            id: owner.followers_url()?,
+            total_items: followers.len(),
+            ordered_items: followers,
+        })
+    }
+
+    async fn verify(
+        json: &Self::Kind,
+        expected_domain: &Url,
+        _data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        verify_domains_match(&json.id, expected_domain)?;
+        Ok(())
+    }
+
+    async fn from_json(
+        json: Self::Kind,
+        owner: &Self::Owner,
+        data: &Data<Self::DataType>,
+    ) -> Result<Self, Self::Error> {
+        if let Some(mut user) = data.read_user(owner.ap_id.inner()) {
+            user.followers.clone_from(&json.ordered_items);
+            data.upsert_user(user);
+        }
+        Ok(DbFollowers(json.ordered_items))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::config::FederationConfig;
+
+    async fn data() -> Data<InMemoryStorage> {
+        FederationConfig::builder()
+            .domain("example.com")
+            .app_data(InMemoryStorage::default())
+            .build()
+            .await
+            .unwrap()
+            .to_request_data()
+    }
+
+    fn user(name: &str) -> DbUser {
+        DbUser::new(
+            name,
+            format!("https://example.com/u/{name}").parse().unwrap(),
+            format!("https://example.com/u/{name}/inbox")
+                .parse()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upsert_user() {
+        let data = data().await;
+        let mut alice = user("alice");
+        data.upsert_user(alice.clone());
+        alice.inbox = "https://example.com/inbox".parse().unwrap();
+        data.upsert_user(alice.clone());
+
+        let read = data.read_user(alice.ap_id.inner()).unwrap();
+        assert_eq!(read.inbox, alice.inbox);
+        assert_eq!(data.users.read().unwrap().len(), 1);
+        assert!(data.read_local_user("alice").is_some());
+        assert!(data.read_local_user("bob").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_from_id() {
+        let data = data().await;
+        let alice = user("alice");
+        data.upsert_user(alice.clone());
+        let post = DbPost::new(
+            "hello".to_string(),
+            "https://example.com/post/1".parse().unwrap(),
+            alice.ap_id.clone(),
+        );
+        data.upsert_post(post.clone());
+
+        let read = DbUser::read_from_id(alice.ap_id.inner().clone(), &data)
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap().ap_id, alice.ap_id);
+        let read = DbPost::read_from_id(post.ap_id.inner().clone(), &data)
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap().text, "hello");
+        let unknown = DbUser::read_from_id("https://example.com/u/bob".parse().unwrap(), &data)
+            .await
+            .unwrap();
+        assert!(unknown.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_person_endpoints() {
+        let data = data().await;
+        let mut alice = user("alice");
+        let json = serde_json::to_value(alice.clone().into_json(&data).await.unwrap()).unwrap();
+        assert!(json.get("endpoints").is_none());
+
+        alice.shared_inbox = Some("https://example.com/inbox".parse().unwrap());
+        let person = alice.clone().into_json(&data).await.unwrap();
+        let json = serde_json::to_value(&person).unwrap();
+        assert_eq!(
+            json["endpoints"],
+            serde_json::json!({"sharedInbox": "https://example.com/inbox"})
+        );
+
+        let read = DbUser::from_json(person, &data).await.unwrap();
+        assert_eq!(
+            read.shared_inbox_or_inbox(),
+            "https://example.com/inbox".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_followers() {
+        let data = data().await;
+        let alice = user("alice");
+        data.upsert_user(alice.clone());
+        let bob: Url = "https://other.com/u/bob".parse().unwrap();
+
+        data.add_follower(alice.ap_id.inner(), bob.clone());
+        data.add_follower(alice.ap_id.inner(), bob.clone());
+        let followers = DbFollowers::read_local(&alice, &data).await.unwrap();
+        assert_eq!(followers.ordered_items, vec![bob.clone()]);
+        assert_eq!(followers.total_items, 1);
+
+        data.remove_follower(alice.ap_id.inner(), &bob);
+        let followers = DbFollowers::read_local(&alice, &data).await.unwrap();
+        assert!(followers.ordered_items.is_empty());
+    }
+}
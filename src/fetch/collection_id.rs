@@ -1,15 +1,31 @@
-use crate::{config::Data, error::Error, fetch::fetch_object_http, traits::Collection};
+use crate::{
+    config::{Data, RequestKind},
+    error::{Error, JsonError},
+    fetch::fetch_object_http_with_kind,
+    protocol::verification::verify_domains_match,
+    traits::Collection,
+    url::{deserialize_normalized, normalize},
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::HashSet,
     fmt::{Debug, Display, Formatter},
+    hash::{Hash, Hasher},
     marker::PhantomData,
+    str::FromStr,
 };
 use url::Url;
 
 /// Typed wrapper for Activitypub Collection ID which helps with dereferencing.
 #[derive(Serialize, Deserialize)]
 #[serde(transparent)]
-pub struct CollectionId<Kind>(Box<Url>, PhantomData<Kind>)
+pub struct CollectionId<Kind>(
+    #[serde(deserialize_with = "deserialize_normalized")] Box<Url>,
+    PhantomData<Kind>,
+)
 where
     Kind: Collection,
     for<'de2> <Kind as Collection>::Kind: Deserialize<'de2>;
@@ -21,13 +37,29 @@ where
 {
     /// Construct a new CollectionId instance
     pub fn parse(url: &str) -> Result<Self, url::ParseError> {
-        Ok(Self(Box::new(Url::parse(url)?), PhantomData::<Kind>))
+        Ok(Self(
+            Box::new(normalize(Url::parse(url)?)),
+            PhantomData::<Kind>,
+        ))
+    }
+
+    /// Returns a reference to the wrapped URL value
+    pub fn inner(&self) -> &Url {
+        &self.0
+    }
+
+    /// Returns the wrapped URL value
+    pub fn into_inner(self) -> Url {
+        *self.0
     }
 
     /// Fetches collection over HTTP
     ///
     /// Unlike [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference) this method doesn't do
     /// any caching.
+    ///
+    /// Local collections are never fetched over HTTP, instead this returns [Error::NotFound]. Use
+    /// [CollectionId::dereference_local] to read them.
     pub async fn dereference(
         &self,
         owner: &<Kind as Collection>::Owner,
@@ -36,11 +68,149 @@ where
     where
         <Kind as Collection>::Error: From<Error>,
     {
-        let res = fetch_object_http(&self.0, data).await?;
+        if self.is_local(data) {
+            return Err(Error::NotFound.into());
+        }
+        let res =
+            fetch_object_http_with_kind(&self.0, data, RequestKind::Collection, false).await?;
         let redirect_url = &res.url;
         Kind::verify(&res.object, redirect_url, data).await?;
         Kind::from_json(res.object, owner, data).await
     }
+
+    /// Fetches a paginated collection over HTTP, following the `first` and `next` links of its
+    /// pages.
+    ///
+    /// The items of all pages are collected into the `orderedItems` (or `items`) field of the
+    /// collection, which is then passed to [Collection::verify] and [Collection::from_json] like
+    /// with [CollectionId::dereference]. At most `max_pages` pages are fetched. Pagination also
+    /// stops early when [FederationConfigBuilder::http_fetch_limit](crate::config::FederationConfigBuilder::http_fetch_limit)
+    /// is reached, or when a page links to a page which was already fetched. Pages must be on the
+    /// same domain as the collection. Like with [CollectionId::dereference], local collections
+    /// are not fetched.
+    pub async fn dereference_paginated(
+        &self,
+        owner: &<Kind as Collection>::Owner,
+        data: &Data<<Kind as Collection>::DataType>,
+        max_pages: usize,
+    ) -> Result<Kind, <Kind as Collection>::Error>
+    where
+        <Kind as Collection>::Error: From<Error>,
+    {
+        if self.is_local(data) {
+            return Err(Error::NotFound.into());
+        }
+        let res =
+            fetch_object_http_with_kind::<_, Value>(&self.0, data, RequestKind::Collection, false)
+                .await?;
+        let mut collection = res.object;
+        let items_key = match collection.get("orderedItems") {
+            None if collection.get("items").is_some() => "items",
+            _ => "orderedItems",
+        };
+        let mut items = take_items(&mut collection);
+        let mut visited = HashSet::from([self.0.as_ref().clone(), res.url.clone()]);
+        let mut next = collection.get("first").cloned();
+        let mut pages = 0;
+
+        while let Some(page) = next.take() {
+            if pages >= max_pages {
+                break;
+            }
+            let mut page = match page {
+                // Page is embedded in the collection
+                Value::Object(_) if page.get("id").is_none() || has_items(&page) => page,
+                _ => {
+                    let Some(url) = page_url(&page) else {
+                        break;
+                    };
+                    verify_domains_match(&url, &res.url)?;
+                    if !visited.insert(url.clone()) || data.remaining_requests() == 0 {
+                        break;
+                    }
+                    fetch_object_http_with_kind::<_, Value>(
+                        &url,
+                        data,
+                        RequestKind::Collection,
+                        false,
+                    )
+                    .await?
+                    .object
+                }
+            };
+            pages += 1;
+            items.append(&mut take_items(&mut page));
+            next = page.get("next").cloned();
+        }
+
+        if let Value::Object(map) = &mut collection {
+            map.insert(items_key.to_string(), Value::Array(items));
+        }
+        let json = JsonError::from_value(collection.clone()).map_err(|e| {
+            Error::ParseFetchedObject(Box::new(e), res.url.clone(), collection.to_string())
+        })?;
+        Kind::verify(&json, &res.url, data).await?;
+        Kind::from_json(json, owner, data).await
+    }
+
+    /// Reads a local collection with [Collection::read_local], for example to serve it over HTTP.
+    /// Remote collections are never read from the database, instead this returns
+    /// [Error::NotFound].
+    pub async fn dereference_local(
+        &self,
+        owner: &<Kind as Collection>::Owner,
+        data: &Data<<Kind as Collection>::DataType>,
+    ) -> Result<<Kind as Collection>::Kind, <Kind as Collection>::Error>
+    where
+        <Kind as Collection>::Error: From<Error>,
+    {
+        if !self.is_local(data) {
+            return Err(Error::NotFound.into());
+        }
+        Kind::read_local(owner, data).await
+    }
+
+    /// Returns true if the collection's domain matches the one defined in
+    /// [FederationConfigBuilder::domain](crate::config::FederationConfigBuilder::domain).
+    pub fn is_local(&self, data: &Data<<Kind as Collection>::DataType>) -> bool {
+        data.config.is_local_url(&self.0)
+    }
+}
+
+/// Removes the `orderedItems` or `items` of a collection or collection page and returns them.
+fn take_items(json: &mut Value) -> Vec<Value> {
+    let Value::Object(map) = json else {
+        return vec![];
+    };
+    match map.remove("orderedItems").or_else(|| map.remove("items")) {
+        Some(Value::Array(items)) => items,
+        _ => vec![],
+    }
+}
+
+fn has_items(json: &Value) -> bool {
+    json.get("orderedItems").is_some() || json.get("items").is_some()
+}
+
+/// Url of a collection page, which is either given directly or as the `id` of an object.
+fn page_url(page: &Value) -> Option<Url> {
+    match page {
+        Value::String(url) => url.parse().ok(),
+        Value::Object(_) => page.get("id")?.as_str()?.parse().ok(),
+        _ => None,
+    }
+}
+
+impl<Kind> FromStr for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: Deserialize<'de2>,
+{
+    type Err = url::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CollectionId::parse(s)
+    }
 }
 
 /// Need to implement clone manually, to avoid requiring Kind to be Clone
@@ -89,7 +259,7 @@ where
     for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
 {
     fn from(url: Url) -> Self {
-        CollectionId(Box::new(url), PhantomData::<Kind>)
+        CollectionId(Box::new(normalize(url)), PhantomData::<Kind>)
     }
 }
 
@@ -103,8 +273,67 @@ where
     }
 }
 
+impl<Kind> Eq for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
+{
+}
+
+/// Only hashes the url, so that it is consistent with the [Borrow] impl
+impl<Kind> Hash for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<Kind> PartialOrd for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Kind> Ord for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// Allows looking up ids in maps and sets with a plain [Url]
+impl<Kind> Borrow<Url> for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
+{
+    fn borrow(&self) -> &Url {
+        &self.0
+    }
+}
+
+impl<Kind> AsRef<Url> for CollectionId<Kind>
+where
+    Kind: Collection,
+    for<'de2> <Kind as Collection>::Kind: serde::Deserialize<'de2>,
+{
+    fn as_ref(&self) -> &Url {
+        &self.0
+    }
+}
+
 #[cfg(feature = "diesel")]
-const _IMPL_DIESEL_NEW_TYPE_FOR_COLLECTION_ID: () = {
+const _: () = {
     use diesel::{
         backend::Backend,
         deserialize::{FromSql, FromStaticSqlRow},
@@ -191,3 +420,323 @@ const _IMPL_DIESEL_NEW_TYPE_FOR_COLLECTION_ID: () = {
         type QueryId = Self;
     }
 };
+
+#[cfg(feature = "sqlx")]
+const _: () = {
+    use sqlx::{
+        encode::IsNull,
+        error::BoxDynError,
+        postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+        Decode,
+        Encode,
+        Postgres,
+        Type,
+    };
+
+    impl<Kind> Type<Postgres> for CollectionId<Kind>
+    where
+        Kind: Collection,
+        for<'de2> <Kind as Collection>::Kind: Deserialize<'de2>,
+    {
+        fn type_info() -> PgTypeInfo {
+            <String as Type<Postgres>>::type_info()
+        }
+
+        fn compatible(ty: &PgTypeInfo) -> bool {
+            <String as Type<Postgres>>::compatible(ty)
+        }
+    }
+    impl<Kind> Encode<'_, Postgres> for CollectionId<Kind>
+    where
+        Kind: Collection,
+        for<'de2> <Kind as Collection>::Kind: Deserialize<'de2>,
+    {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+            <&str as Encode<Postgres>>::encode(self.0.as_str(), buf)
+        }
+    }
+    impl<'r, Kind> Decode<'r, Postgres> for CollectionId<Kind>
+    where
+        Kind: Collection + Send + 'static,
+        for<'de2> <Kind as Collection>::Kind: Deserialize<'de2>,
+    {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            let string = <&str as Decode<Postgres>>::decode(value)?;
+            Ok(CollectionId::parse(string)?)
+        }
+    }
+};
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{config::FederationConfig, traits::tests::DbConnection, FEDERATION_CONTENT_TYPE};
+    use async_trait::async_trait;
+    use axum::{
+        extract::{Query, State},
+        http::header,
+        routing::get,
+        Router,
+    };
+    use serde_json::json;
+    use std::collections::{BTreeSet, HashMap};
+
+    #[derive(Debug)]
+    struct Outbox(Vec<String>);
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct OutboxJson {
+        ordered_items: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Collection for Outbox {
+        type Owner = ();
+        type DataType = DbConnection;
+        type Kind = OutboxJson;
+        type Error = Error;
+
+        async fn read_local(_: &(), _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Ok(OutboxJson {
+                ordered_items: vec!["local".to_string()],
+            })
+        }
+
+        async fn verify(_: &Self::Kind, _: &Url, _: &Data<Self::DataType>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn from_json(
+            json: Self::Kind,
+            _: &(),
+            _: &Data<Self::DataType>,
+        ) -> Result<Self, Error> {
+            Ok(Outbox(json.ordered_items))
+        }
+    }
+
+    /// Serve an outbox with three pages, where the last page links back to the first one. With
+    /// the `foreign` query parameter, the first page is on a different domain.
+    async fn serve_outbox() -> Url {
+        async fn outbox(
+            State(base): State<Url>,
+            Query(query): Query<HashMap<String, String>>,
+        ) -> ([(header::HeaderName, &'static str); 1], String) {
+            let page = |n: u32, next: String| {
+                json!({
+                    "id": format!("{base}outbox?page={n}"),
+                    "type": "OrderedCollectionPage",
+                    "orderedItems": [format!("{n}a"), format!("{n}b")],
+                    "next": next,
+                })
+            };
+            let json = match query.get("page").map(String::as_str) {
+                None if query.contains_key("foreign") => json!({
+                    "id": format!("{base}outbox?foreign"),
+                    "type": "OrderedCollection",
+                    "first": "https://example.net/outbox?page=1",
+                }),
+                None => json!({
+                    "id": format!("{base}outbox"),
+                    "type": "OrderedCollection",
+                    "first": format!("{base}outbox?page=1"),
+                }),
+                Some("1") => page(1, format!("{base}outbox?page=2")),
+                Some("2") => page(2, format!("{base}outbox?page=3")),
+                _ => page(3, format!("{base}outbox?page=1")),
+            };
+            (
+                [(header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE)],
+                json.to_string(),
+            )
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let app = Router::new()
+            .route("/outbox", get(outbox))
+            .with_state(base.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base.join("outbox").unwrap()
+    }
+
+    async fn request_data(http_fetch_limit: u32) -> Data<DbConnection> {
+        FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .http_fetch_limit(http_fetch_limit)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data()
+    }
+
+    #[tokio::test]
+    async fn test_dereference_paginated() -> Result<(), Error> {
+        let id = CollectionId::<Outbox>::from(serve_outbox().await);
+
+        // Stops at the page which links back to the first one
+        let data = request_data(20).await;
+        let outbox = id.dereference_paginated(&(), &data, 10).await?;
+        assert_eq!(vec!["1a", "1b", "2a", "2b", "3a", "3b"], outbox.0);
+        assert_eq!(4, data.collection_fetch_count());
+
+        let data = request_data(20).await;
+        let outbox = id.dereference_paginated(&(), &data, 2).await?;
+        assert_eq!(vec!["1a", "1b", "2a", "2b"], outbox.0);
+        assert_eq!(3, data.collection_fetch_count());
+
+        let data = request_data(2).await;
+        let outbox = id.dereference_paginated(&(), &data, 10).await?;
+        assert_eq!(vec!["1a", "1b"], outbox.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dereference_paginated_foreign_page() -> Result<(), Error> {
+        let mut url = serve_outbox().await;
+        url.set_query(Some("foreign"));
+        let data = request_data(20).await;
+        let res = CollectionId::<Outbox>::from(url)
+            .dereference_paginated(&(), &data, 10)
+            .await;
+        assert_eq!(Some(Error::UrlVerificationError("")), res.err());
+        assert_eq!(1, data.collection_fetch_count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dereference_local() -> Result<(), Error> {
+        let url = serve_outbox().await;
+        let data = FederationConfig::builder()
+            .domain(format!("localhost:{}", url.port().unwrap()))
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let id = CollectionId::<Outbox>::from(url);
+        assert!(id.is_local(&data));
+
+        // Local collections are read from the database, and not fetched over HTTP
+        let json = id.dereference_local(&(), &data).await?;
+        assert_eq!(vec!["local"], json.ordered_items);
+        assert_eq!(
+            Some(Error::NotFound),
+            id.dereference(&(), &data).await.err()
+        );
+        assert_eq!(
+            Some(Error::NotFound),
+            id.dereference_paginated(&(), &data, 10).await.err()
+        );
+        assert_eq!(0, data.request_count());
+
+        // Remote collections are only fetched over HTTP
+        let remote = CollectionId::<Outbox>::parse("https://remote.example/outbox")?;
+        assert!(!remote.is_local(&data));
+        assert_eq!(
+            Some(Error::NotFound),
+            remote.dereference_local(&(), &data).await.err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_id_from_str() -> Result<(), Error> {
+        let id: CollectionId<Outbox> = "https://Example.com/u/alice/outbox#main".parse()?;
+        assert_eq!("https://example.com/u/alice/outbox", id.to_string());
+        assert_eq!(
+            id,
+            CollectionId::parse("https://example.com/u/alice/outbox")?
+        );
+        assert_eq!(
+            id.inner(),
+            &Url::parse("https://example.com/u/alice/outbox")?
+        );
+        assert!("not a url".parse::<CollectionId<Outbox>>().is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "diesel")]
+    #[test]
+    fn test_diesel_collection_id() {
+        use diesel::{
+            deserialize::FromSql,
+            expression::AsExpression,
+            pg::Pg,
+            serialize::ToSql,
+            sql_types::Text,
+            Queryable,
+        };
+        fn assert_sql_type<T: ToSql<Text, Pg> + FromSql<Text, Pg> + AsExpression<Text>>() {}
+        assert_sql_type::<CollectionId<Outbox>>();
+
+        let id = <CollectionId<Outbox> as Queryable<Text, Pg>>::build(
+            "https://Example.com/u/alice/outbox#main".to_string(),
+        )
+        .unwrap();
+        assert_eq!("https://example.com/u/alice/outbox", id.inner().as_str());
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[test]
+    fn test_sqlx_collection_id() {
+        use sqlx::{encode::IsNull, postgres::PgArgumentBuffer, Decode, Encode, Postgres, Type};
+        fn assert_sql_type<T>()
+        where
+            T: Type<Postgres> + for<'q> Encode<'q, Postgres> + for<'r> Decode<'r, Postgres>,
+        {
+        }
+        assert_sql_type::<CollectionId<Outbox>>();
+        assert!(<CollectionId<Outbox> as Type<Postgres>>::compatible(
+            &<String as Type<Postgres>>::type_info()
+        ));
+
+        // Stored as text in normalized form
+        let id = CollectionId::<Outbox>::parse("https://Example.com/u/alice/outbox#main").unwrap();
+        let mut buf = PgArgumentBuffer::default();
+        assert!(matches!(id.encode_by_ref(&mut buf), Ok(IsNull::No)));
+        assert_eq!("https://example.com/u/alice/outbox".as_bytes(), buf.as_slice());
+    }
+
+    #[test]
+    fn test_collection_id_map_key() -> Result<(), Error> {
+        let outbox = CollectionId::<Outbox>::parse("https://example.com/u/alice/outbox")?;
+        let mut map = HashMap::new();
+        map.insert(outbox.clone(), 1);
+        let url = Url::parse("https://example.com/u/alice/outbox")?;
+        assert_eq!(Some(&1), map.get(&url));
+        assert_eq!(Some(&1), map.get(outbox.as_ref()));
+
+        let other = CollectionId::<Outbox>::parse("https://example.com/u/bob/outbox")?;
+        assert!(outbox < other);
+        assert_eq!(
+            vec![&outbox, &other],
+            BTreeSet::from([other.clone(), outbox.clone()])
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_url() {
+        assert_eq!(
+            Some("https://example.com/outbox?page=1".parse().unwrap()),
+            page_url(&json!("https://example.com/outbox?page=1"))
+        );
+        assert_eq!(
+            Some("https://example.com/outbox?page=1".parse().unwrap()),
+            page_url(&json!({"id": "https://example.com/outbox?page=1"}))
+        );
+        assert_eq!(None, page_url(&json!(1)));
+    }
+}
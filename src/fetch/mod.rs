@@ -3,37 +3,122 @@
 #![doc = include_str!("../../docs/07_fetching_data.md")]
 
 use crate::{
-    config::Data,
-    error::{Error, Error::ParseFetchedObject},
+    config::{Data, FederationConfig, RequestKind, VerifyContext},
+    error::{body_snippet, Error, Error::ParseFetchedObject, JsonError},
     extract_id,
-    http_signatures::sign_request,
+    fetch::webfinger::WebFingerError,
+    http_signatures::{sign_request, verify_response_signature},
+    protocol::{tombstone::Tombstone, verification::verify_json_depth},
     reqwest_shim::ResponseExt,
+    url::normalize,
     FEDERATION_CONTENT_TYPE,
 };
 use bytes::Bytes;
-use http::{header::LOCATION, HeaderValue, StatusCode};
-use serde::de::DeserializeOwned;
-use std::sync::atomic::Ordering;
-use tracing::info;
+use http::{
+    header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LINK, LOCATION},
+    HeaderMap,
+    HeaderValue,
+    Method,
+    StatusCode,
+};
+use mime::Mime;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+use tokio::sync::OnceCell;
+use tracing::{debug, field, info, instrument, Span};
 use url::Url;
 
 /// Typed wrapper for collection IDs
 pub mod collection_id;
+/// Serving and fetching nodeinfo, and detecting the software of remote hosts to adjust requests for them
+pub mod nodeinfo;
 /// Typed wrapper for Activitypub Object ID which helps with dereferencing and caching
 pub mod object_id;
+/// Fetch arbitrary remote resources with the same protections as federated objects
+pub mod safe_get;
+/// Fetching objects whose type isn't known in advance
+pub mod untyped;
 /// Resolves identifiers of the form `name@example.com`
 pub mod webfinger;
 
 /// Response from fetching a remote object
+#[derive(Clone)]
 pub struct FetchObjectResponse<Kind> {
     /// The resolved object
     pub object: Kind,
     /// Contains the final URL (different from request URL in case of redirect)
     pub url: Url,
+    /// Headers of the HTTP response
+    pub headers: HeaderMap,
+    body: Bytes,
     content_type: Option<HeaderValue>,
     object_id: Option<Url>,
 }
 
+impl<Kind> FetchObjectResponse<Kind> {
+    /// Same response without the parsed object, so that it can be stored for later use
+    pub(crate) fn without_object(&self) -> FetchObjectResponse<()> {
+        FetchObjectResponse {
+            object: (),
+            url: self.url.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            content_type: self.content_type.clone(),
+            object_id: self.object_id.clone(),
+        }
+    }
+
+    /// Value of the `ETag` header, which can be stored to refetch the object later with
+    /// [fetch_object_http_conditional]
+    pub fn etag(&self) -> Option<&str> {
+        self.headers.get(ETAG).and_then(|h| h.to_str().ok())
+    }
+
+    /// Value of the `Last-Modified` header, which can be stored to refetch the object later with
+    /// [fetch_object_http_conditional]
+    pub fn last_modified(&self) -> Option<&str> {
+        self.headers
+            .get(LAST_MODIFIED)
+            .and_then(|h| h.to_str().ok())
+    }
+
+    /// Verifies that the response was signed with the given public key, see
+    /// [crate::http_signatures::sign_response]. Most servers don't sign their responses, so only
+    /// use this where it is known to be supported.
+    pub fn verify_response_signature(&self, public_key: &str) -> Result<(), Error> {
+        verify_response_signature(
+            &Method::GET,
+            &self.url,
+            &self.headers,
+            &self.body,
+            public_key,
+        )
+    }
+}
+
+impl FetchObjectResponse<()> {
+    /// Parses the object from the response body again
+    pub(crate) fn parse<Kind: DeserializeOwned>(self) -> Result<FetchObjectResponse<Kind>, Error> {
+        let text = decode_body(&self.body, self.content_type.as_ref())
+            .ok_or_else(|| Error::FetchInvalidEncoding(self.url.clone()))?;
+        let object = parse_object(&text, &self.url, self.content_type.as_ref())?;
+        Ok(FetchObjectResponse {
+            object,
+            url: self.url,
+            headers: self.headers,
+            body: self.body,
+            content_type: self.content_type,
+            object_id: self.object_id,
+        })
+    }
+}
+
 /// Fetch a remote object over HTTP and convert to `Kind`.
 ///
 /// [crate::fetch::object_id::ObjectId::dereference] wraps this function to add caching and
@@ -43,130 +128,651 @@ pub struct FetchObjectResponse<Kind> {
 /// Every time an object is fetched via HTTP, [RequestData.request_counter] is incremented by one.
 /// If the value exceeds [FederationSettings.http_fetch_limit], the request is aborted with
 /// [Error::RequestLimit]. This prevents denial of service attacks where an attack triggers
-/// infinite, recursive fetching of data.
+/// infinite, recursive fetching of data. The request is also counted in
+/// [Data::object_fetch_count].
+///
+/// With [FederationConfigBuilder::request_deadline](crate::config::FederationConfigBuilder::request_deadline)
+/// the total time of all fetches is limited as well, and fetches fail with
+/// [Error::RequestDeadlineExceeded] once it is used up.
 ///
 /// The `Accept` header will be set to the content of [`FEDERATION_CONTENT_TYPE`]. When parsing the
 /// response it ensures that it has a valid `Content-Type` header as defined by ActivityPub, to
 /// prevent security vulnerabilities like [this one](https://github.com/mastodon/mastodon/security/advisories/GHSA-jhrq-qvrm-qr36).
 /// Additionally it checks that the `id` field is identical to the fetch URL (after redirects).
+/// If the `id` is a different url on the same host, that url is fetched once instead. See
+/// [FederationConfigBuilder::allow_fetch_id_mismatch_same_domain](crate::config::FederationConfigBuilder::allow_fetch_id_mismatch_same_domain)
+/// for servers which return yet another `id` from there.
+///
+/// If the response has a different content type but includes a `Link` header with
+/// `rel="alternate"` and an ActivityPub media type, the linked url is fetched instead. This is used
+/// by Peertube for example. Only a single alternate link is followed.
 pub async fn fetch_object_http<T: Clone, Kind: DeserializeOwned>(
     url: &Url,
     data: &Data<T>,
 ) -> Result<FetchObjectResponse<Kind>, Error> {
+    fetch_object_http_with_kind(url, data, RequestKind::Object, false).await
+}
+
+/// Response of [fetch_object_http_conditional]
+pub enum ConditionalFetchResponse<Kind> {
+    /// The object was modified, or the server doesn't support conditional requests
+    Modified(Box<FetchObjectResponse<Kind>>),
+    /// The server responded with `304 Not Modified`, so the previously fetched version is still
+    /// current
+    NotModified,
+}
+
+/// Same as [fetch_object_http], but sends the `etag` and `last_modified` values of a previous
+/// response as `If-None-Match` and `If-Modified-Since` headers, see [FetchObjectResponse::etag]
+/// and [FetchObjectResponse::last_modified].
+///
+/// If the server responds with `304 Not Modified`, the body is not parsed and
+/// [ConditionalFetchResponse::NotModified] is returned. Values which are not valid header values
+/// are ignored.
+pub async fn fetch_object_http_conditional<T: Clone, Kind: DeserializeOwned>(
+    url: &Url,
+    data: &Data<T>,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Result<ConditionalFetchResponse<Kind>, Error> {
+    let mut headers = HeaderMap::new();
+    if let Some(etag) = etag.and_then(|e| HeaderValue::from_str(e).ok()) {
+        headers.insert(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = last_modified.and_then(|l| HeaderValue::from_str(l).ok()) {
+        headers.insert(IF_MODIFIED_SINCE, last_modified);
+    }
+    let conditional_headers = (!headers.is_empty()).then_some(&headers);
+    let res = fetch_object_http_checked(
+        url,
+        data,
+        RequestKind::Object,
+        false,
+        false,
+        conditional_headers,
+    )
+    .await?;
+    Ok(match res {
+        Fetched::Object(res) => ConditionalFetchResponse::Modified(res),
+        Fetched::NotModified => ConditionalFetchResponse::NotModified,
+    })
+}
+
+/// Response of a request which may be conditional, see [FetchOptions::conditional_headers]
+#[derive(Clone)]
+pub(crate) enum Fetched<Kind> {
+    /// The object was fetched
+    Object(Box<FetchObjectResponse<Kind>>),
+    /// The server responded with `304 Not Modified` to a conditional request
+    NotModified,
+}
+
+impl<Kind> Fetched<Kind> {
+    /// Returns the fetched object of a request which is not conditional. Servers can still
+    /// respond with `304 Not Modified`, but without a body this is not a valid object.
+    pub(crate) fn into_object(self, url: &Url) -> Result<FetchObjectResponse<Kind>, Error> {
+        match self {
+            Fetched::Object(res) => Ok(*res),
+            Fetched::NotModified => Err(Error::FetchInvalidContentType(url.clone())),
+        }
+    }
+}
+
+/// Maximum number of urls for which concurrent fetches are combined, additional fetches are sent
+/// separately
+const MAX_IN_FLIGHT_FETCHES: usize = 1000;
+
+/// Response of a fetch which is shared by all concurrent dereferences of the url
+type SharedFetch = Arc<OnceCell<Result<Fetched<()>, Error>>>;
+
+/// Fetches which are combined, because they have the same url, signed fetch actor and
+/// conditional headers
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct InFlightKey {
+    url: Url,
+    signed_fetch_actor: Option<Url>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Fetches of remote objects which are currently running.
+#[derive(Default)]
+pub(crate) struct InFlightFetches(Mutex<HashMap<InFlightKey, SharedFetch>>);
+
+impl InFlightFetches {
+    /// Fetches the object at `url` without parsing it, as conditional request if `etag` or
+    /// `last_modified` is given, see [fetch_object_http_conditional]. If the same request is
+    /// already running, waits for it and returns a copy of its response instead of sending
+    /// another one. Only the request data which sends the request counts it.
+    ///
+    /// If that request fails with [Error::RequestLimit] or [Error::RequestDeadlineExceeded], the
+    /// limit of the other request data may not be reached yet, so they send their own request.
+    pub(crate) async fn fetch<T: Clone>(
+        &self,
+        url: &Url,
+        data: &Data<T>,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<Fetched<()>, Error> {
+        let fetch = || async {
+            let res =
+                fetch_object_http_conditional::<_, IgnoredAny>(url, data, etag, last_modified)
+                    .await?;
+            Ok(match res {
+                ConditionalFetchResponse::Modified(res) => {
+                    Fetched::Object(Box::new(res.without_object()))
+                }
+                ConditionalFetchResponse::NotModified => Fetched::NotModified,
+            })
+        };
+        let key = InFlightKey {
+            url: url.clone(),
+            signed_fetch_actor: data.signed_fetch_actor().map(|actor| actor.0.clone()),
+            etag: etag.map(ToString::to_string),
+            last_modified: last_modified.map(ToString::to_string),
+        };
+        let shared = {
+            let mut fetches = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            if fetches.len() < MAX_IN_FLIGHT_FETCHES || fetches.contains_key(&key) {
+                Some(fetches.entry(key.clone()).or_default().clone())
+            } else {
+                None
+            }
+        };
+        let Some(shared) = shared else {
+            return fetch().await;
+        };
+        let _guard = InFlightGuard {
+            fetches: self,
+            key,
+            shared: shared.clone(),
+        };
+        let mut sent = false;
+        let res = shared
+            .get_or_init(|| {
+                sent = true;
+                fetch()
+            })
+            .await
+            .clone();
+        if !sent
+            && matches!(
+                res,
+                Err(Error::RequestLimit | Error::RequestDeadlineExceeded)
+            )
+        {
+            return fetch().await;
+        }
+        res
+    }
+}
+
+/// Removes the entry of a fetch once it is finished, or once all callers waiting for it were
+/// cancelled.
+struct InFlightGuard<'a> {
+    fetches: &'a InFlightFetches,
+    key: InFlightKey,
+    shared: SharedFetch,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut fetches = self
+            .fetches
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(entry) = fetches.get(&self.key) else {
+            return;
+        };
+        // The map and this guard hold the last two references if no other caller is waiting
+        let last_caller = Arc::strong_count(entry) <= 2;
+        if Arc::ptr_eq(entry, &self.shared) && (self.shared.initialized() || last_caller) {
+            fetches.remove(&self.key);
+        }
+    }
+}
+
+/// Same as [`fetch_object_http`], but counts the request towards the given category.
+///
+/// Local objects can only be fetched with `allow_local`. Otherwise a local url may still be
+/// requested, because it can redirect to a remote object. But a remote url which redirects to a
+/// local one is rejected before following the redirect.
+pub(crate) async fn fetch_object_http_with_kind<T: Clone, Kind: DeserializeOwned>(
+    url: &Url,
+    data: &Data<T>,
+    kind: RequestKind,
+    allow_local: bool,
+) -> Result<FetchObjectResponse<Kind>, Error> {
+    fetch_object_http_checked(url, data, kind, allow_local, false, None)
+        .await?
+        .into_object(url)
+}
+
+/// Fetches the object and checks its content type and `id`. `refetched` is true if `url` is the
+/// `id` of a previously fetched object with a mismatching id. With `conditional_headers`, a
+/// `304 Not Modified` response returns [Fetched::NotModified].
+async fn fetch_object_http_checked<T: Clone, Kind: DeserializeOwned>(
+    url: &Url,
+    data: &Data<T>,
+    kind: RequestKind,
+    allow_local: bool,
+    refetched: bool,
+    conditional_headers: Option<&HeaderMap>,
+) -> Result<Fetched<Kind>, Error> {
     static FETCH_CONTENT_TYPE: HeaderValue = HeaderValue::from_static(FEDERATION_CONTENT_TYPE);
-    const VALID_RESPONSE_CONTENT_TYPES: [&str; 3] = [
-        FEDERATION_CONTENT_TYPE, // lemmy
-        r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#, // activitypub standard
-        r#"application/activity+json; charset=utf-8"#,                             // mastodon
-    ];
-    let res = fetch_object_http_with_accept(url, data, &FETCH_CONTENT_TYPE, false).await?;
+    let options = FetchOptions {
+        recursive: false,
+        follow_alternate: true,
+        allow_local,
+        signed: true,
+        conditional_headers,
+    };
+    let res = fetch_object_http_with_accept(url, data, &FETCH_CONTENT_TYPE, kind, options).await?;
+    let Fetched::Object(mut res) = res else {
+        return Ok(Fetched::NotModified);
+    };
 
     // Ensure correct content-type to prevent vulnerabilities, with case insensitive comparison.
-    let content_type = res
-        .content_type
-        .as_ref()
-        .and_then(|c| Some(c.to_str().ok()?.to_lowercase()))
-        .ok_or(Error::FetchInvalidContentType(res.url.clone()))?;
-    if !VALID_RESPONSE_CONTENT_TYPES.contains(&content_type.as_str()) {
+    if !is_accepted_content_type(&data.config, res.content_type.as_ref()) {
         return Err(Error::FetchInvalidContentType(res.url));
     }
 
+    // Proxies may serve an object over http while its id was upgraded to https. In this case the
+    // https id is used as canonical url, instead of fetching it again.
+    if let Some(object_id) = &res.object_id {
+        if is_https_upgrade(&res.url, object_id) {
+            res.url = object_id.clone();
+        }
+    }
+
     // Ensure id field matches final url after redirect
-    if res.object_id.as_ref() != Some(&res.url) {
-        if let Some(res_object_id) = res.object_id {
-            data.config.verify_url_valid(&res_object_id).await?;
-            // If id is different but still on the same domain, attempt to request object
-            // again from url in id field.
-            if res_object_id.domain() == res.url.domain() {
-                return Box::pin(fetch_object_http(&res_object_id, data)).await;
-            }
+    if res.object_id.clone().map(normalize) != Some(normalize(res.url.clone())) {
+        let Some(res_object_id) = res.object_id.clone() else {
+            return Err(Error::FetchWrongId(res.url));
+        };
+        data.config
+            .verify_url_valid(&res_object_id, VerifyContext::Fetch)
+            .await?;
+        // If id is different but still on the same domain, attempt to request object
+        // again from url in id field.
+        if res_object_id.domain() != res.url.domain() {
+            return Err(Error::FetchWrongId(res.url));
+        }
+        if !allow_local && data.config.is_local_url(&res_object_id) {
+            return Err(Error::NotFound);
+        }
+        if !refetched {
+            return Box::pin(fetch_object_http_checked(
+                &res_object_id,
+                data,
+                kind,
+                allow_local,
+                true,
+                None,
+            ))
+            .await;
         }
-        // Failed to fetch the object from its specified id
-        return Err(Error::FetchWrongId(res.url));
+        // Software which serves objects under multiple urls may also return a different id
+        // from there. If allowed, such an object is accepted with its id as url.
+        if !data.config.allow_fetch_id_mismatch_same_domain {
+            return Err(Error::FetchWrongId(res.url));
+        }
+        res.url = res_object_id;
     }
 
     // Dont allow fetching local object. Only check this after the request as a local url
     // may redirect to a remote object.
-    if data.config.is_local_url(&res.url) {
+    if !allow_local && data.config.is_local_url(&res.url) {
         return Err(Error::NotFound);
     }
 
-    Ok(res)
+    Ok(Fetched::Object(res))
+}
+
+/// Returns true if `id` is identical to `url`, except that it uses https while `url` uses http.
+/// Default ports are ignored, so that `http://example.com/` and `https://example.com/` match. A
+/// downgrade from https to http is never accepted.
+fn is_https_upgrade(url: &Url, id: &Url) -> bool {
+    url.scheme() == "http"
+        && id.scheme() == "https"
+        && url.host_str() == id.host_str()
+        && url.port() == id.port()
+        && url.path() == id.path()
+        && url.query() == id.query()
+}
+
+/// Profile which identifies ActivityStreams documents in the `application/ld+json` media type
+const ACTIVITYSTREAMS_PROFILE: &str = "https://www.w3.org/ns/activitystreams";
+
+/// Returns true if the content type is an ActivityPub media type, see
+/// [is_activitypub_media_type].
+fn is_activitypub_content_type(content_type: Option<&HeaderValue>) -> bool {
+    content_type
+        .and_then(|c| c.to_str().ok())
+        .is_some_and(is_activitypub_media_type)
+}
+
+/// Returns true if the media type is `application/activity+json` (used by Lemmy, Mastodon etc)
+/// or `application/ld+json` (ActivityPub standard). If there is a `profile` parameter, it must
+/// contain the ActivityStreams profile. Comparison is case insensitive, and other parameters
+/// are ignored. The charset is handled when decoding the body.
+fn is_activitypub_media_type(media_type: &str) -> bool {
+    let Ok(mime) = media_type.parse::<Mime>() else {
+        return false;
+    };
+    let essence = mime.essence_str().to_lowercase();
+    let valid_profile = mime.get_param("profile").is_none_or(|profile| {
+        profile
+            .as_str()
+            .split_whitespace()
+            .any(|p| p == ACTIVITYSTREAMS_PROFILE)
+    });
+    (essence == FEDERATION_CONTENT_TYPE || essence == "application/ld+json") && valid_profile
+}
+
+/// Returns true if the content type is an ActivityPub media type, or one of
+/// [FederationConfigBuilder::extra_accepted_content_types](crate::config::FederationConfigBuilder::extra_accepted_content_types).
+fn is_accepted_content_type<T: Clone>(
+    config: &FederationConfig<T>,
+    content_type: Option<&HeaderValue>,
+) -> bool {
+    let Some(content_type) = content_type.and_then(|c| c.to_str().ok()) else {
+        return false;
+    };
+    let essence = |media_type: &str| {
+        media_type
+            .parse::<Mime>()
+            .ok()
+            .map(|mime| mime.essence_str().to_lowercase())
+    };
+    is_activitypub_media_type(content_type)
+        || essence(content_type).is_some_and(|content_type| {
+            config
+                .extra_accepted_content_types
+                .iter()
+                .any(|extra| essence(extra).as_ref() == Some(&content_type))
+        })
+}
+
+/// Options for [fetch_object_http_with_accept]
+#[derive(Clone, Copy)]
+pub(crate) struct FetchOptions<'a> {
+    /// Set when following a redirect, further redirects are then ignored
+    pub(crate) recursive: bool,
+    /// Follow a `Link` header to an alternate ActivityPub representation
+    pub(crate) follow_alternate: bool,
+    /// Allow requests to local urls
+    pub(crate) allow_local: bool,
+    /// Sign the request with the signed fetch actor, if one is configured
+    pub(crate) signed: bool,
+    /// Headers for a conditional request, a `304 Not Modified` response then returns
+    /// [Fetched::NotModified]
+    pub(crate) conditional_headers: Option<&'a HeaderMap>,
 }
 
 /// Fetch a remote object over HTTP and convert to `Kind`. This function works exactly as
 /// [`fetch_object_http`] except that the `Accept` header is specified in `content_type`.
+///
+/// With `follow_alternate`, a response which is not ActivityPub but has a `Link` header pointing
+/// to an alternate ActivityPub representation (as used by Peertube) is followed once. Redirects
+/// and alternate links to local urls are only followed with `allow_local`.
+#[instrument(
+    name = "fetch_object_http",
+    skip_all,
+    fields(url = %url, status = field::Empty, duration_ms = field::Empty)
+)]
 async fn fetch_object_http_with_accept<T: Clone, Kind: DeserializeOwned>(
     url: &Url,
     data: &Data<T>,
     content_type: &HeaderValue,
-    recursive: bool,
-) -> Result<FetchObjectResponse<Kind>, Error> {
+    kind: RequestKind,
+    options: FetchOptions<'_>,
+) -> Result<Fetched<Kind>, Error> {
     let config = &data.config;
-    config.verify_url_valid(url).await?;
+    let context = match kind {
+        RequestKind::Webfinger => VerifyContext::Webfinger,
+        _ => VerifyContext::Fetch,
+    };
+    config.verify_url_valid(url, context).await?;
     info!("Fetching remote object {}", url.to_string());
 
-    let mut counter = data.request_counter.fetch_add(1, Ordering::SeqCst);
-    // fetch_add returns old value so we need to increment manually here
-    counter += 1;
+    let timeout = data.timeout_within_deadline(config.request_timeout)?;
+    let counter = data.request_counter.increment(kind);
     if counter > config.http_fetch_limit {
         return Err(Error::RequestLimit);
     }
 
-    let req = config
+    let mut req = config
         .client
         .get(url.as_str())
         .header("Accept", content_type)
-        .timeout(config.request_timeout);
+        .timeout(timeout);
+    if let Some(conditional_headers) = options.conditional_headers {
+        req = req.headers(conditional_headers.clone());
+    }
 
-    let res = if let Some((actor_id, private_key_pem)) = config.signed_fetch_actor.as_deref() {
+    let signed_fetch_actor = data.signed_fetch_actor().filter(|_| options.signed);
+    let start = Instant::now();
+    let res = if let Some((actor_id, private_key_pem)) = signed_fetch_actor.as_deref() {
+        let http_signature_compat = config.http_signature_compat
+            || config.peer_software.quirks(url).await.http_signature_compat;
         let req = sign_request(
             req,
-            actor_id,
+            config.key_id_strategy.key_id(actor_id),
             Bytes::new(),
             private_key_pem.clone(),
-            data.config.http_signature_compat,
+            http_signature_compat,
+            config.use_rfc9421_signatures,
+            false,
+            &config.signing_limiter,
         )
         .await?;
-        config.client.execute(req).await?
+        config.client.execute(req).await
     } else {
-        req.send().await?
+        req.send().await
     };
+    let duration = start.elapsed();
+    let status = res.as_ref().ok().map(reqwest::Response::status);
+    let span = Span::current();
+    span.record("duration_ms", duration.as_millis() as u64);
+    if let Some(status) = status {
+        span.record("status", status.as_u16());
+    }
+    config.metrics_hook.on_fetch(url, status, duration);
+    let res = res.map_err(|e| data.deadline_error(e))?;
+    if res.status().is_success() {
+        config.peer_software.discover(url);
+    }
 
     // Allow a single redirect using recursion. Further redirects are ignored.
     let location = res.headers().get(LOCATION).and_then(|l| l.to_str().ok());
-    if let (Some(location), false) = (location, recursive) {
+    if let (Some(location), false) = (location, options.recursive) {
         let location = location.parse()?;
+        if !options.allow_local && config.is_local_url(&location) {
+            return Err(Error::NotFound);
+        }
+        let options = FetchOptions {
+            recursive: true,
+            ..options
+        };
         return Box::pin(fetch_object_http_with_accept(
             &location,
             data,
             content_type,
-            true,
+            kind,
+            options,
         ))
         .await;
     }
 
+    if kind == RequestKind::Webfinger && !res.status().is_success() {
+        return Err(WebFingerError::from_status(res.status()).into());
+    }
+
+    if options.conditional_headers.is_some() && res.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+
     if res.status() == StatusCode::GONE {
-        return Err(Error::ObjectDeleted(url.clone()));
+        // Include the tombstone if the server returned one, with the same checks as for objects
+        let res_url = res.url().clone();
+        let content_type = res.headers().get(CONTENT_TYPE).cloned();
+        let tombstone = res
+            .bytes_limited_to(data.config.max_fetch_body_size)
+            .await
+            .ok()
+            .filter(|body| verify_json_depth(body, data.config.max_json_depth).is_ok())
+            .and_then(|body| serde_json::from_slice::<Tombstone>(&body).ok())
+            .filter(|tombstone| {
+                is_activitypub_content_type(content_type.as_ref())
+                    && (tombstone.id == res_url || is_https_upgrade(&res_url, &tombstone.id))
+            });
+        return Err(Error::ObjectDeleted(url.clone(), tombstone.map(Box::new)));
+    }
+
+    if options.follow_alternate
+        && !is_accepted_content_type(config, res.headers().get(CONTENT_TYPE))
+    {
+        if let Some(alternate) = alternate_link(res.headers(), res.url()) {
+            if !options.allow_local && config.is_local_url(&alternate) {
+                return Err(Error::NotFound);
+            }
+            let options = FetchOptions {
+                follow_alternate: false,
+                conditional_headers: None,
+                ..options
+            };
+            return Box::pin(fetch_object_http_with_accept(
+                &alternate,
+                data,
+                content_type,
+                kind,
+                options,
+            ))
+            .await;
+        }
     }
 
     let url = res.url().clone();
-    let content_type = res.headers().get("Content-Type").cloned();
-    let text = res.bytes_limited().await?;
+    let headers = res.headers().clone();
+    let content_type = headers.get("Content-Type").cloned();
+    let body = res
+        .bytes_limited_to(data.config.max_fetch_body_size)
+        .await
+        .map_err(|e| data.deadline_error(e))?;
+    let text = decode_body(&body, content_type.as_ref())
+        .ok_or_else(|| Error::FetchInvalidEncoding(url.clone()))?;
+    verify_json_depth(&text, data.config.max_json_depth)?;
     let object_id = extract_id(&text).ok();
 
-    match serde_json::from_slice(&text) {
-        Ok(object) => Ok(FetchObjectResponse {
-            object,
-            url,
-            content_type,
-            object_id,
-        }),
-        Err(e) => Err(ParseFetchedObject(
-            e,
-            url,
-            String::from_utf8(Vec::from(text))?,
-        )),
+    let object = parse_object(&text, &url, content_type.as_ref())?;
+    Ok(Fetched::Object(Box::new(FetchObjectResponse {
+        object,
+        url,
+        headers,
+        body,
+        content_type,
+        object_id,
+    })))
+}
+
+/// Parses the fetched object. If this fails because the server returned a `Tombstone` for the
+/// url instead, [Error::ObjectDeleted] is returned with the tombstone.
+fn parse_object<Kind: DeserializeOwned>(
+    text: &[u8],
+    url: &Url,
+    content_type: Option<&HeaderValue>,
+) -> Result<Kind, Error> {
+    JsonError::parse(text).map_err(|e| {
+        match serde_json::from_slice::<Tombstone>(text) {
+            // Same checks as for objects, so that only the server of the object can delete it
+            Ok(tombstone)
+                if is_activitypub_content_type(content_type)
+                    && (&tombstone.id == url || is_https_upgrade(url, &tombstone.id)) =>
+            {
+                Error::ObjectDeleted(url.clone(), Some(Box::new(tombstone)))
+            }
+            _ => {
+                debug!("Failed to parse object {url}: {}", body_snippet(text));
+                let text = String::from_utf8_lossy(text).into_owned();
+                ParseFetchedObject(Box::new(e), url.clone(), text)
+            }
+        }
+    })
+}
+
+/// Finds a `Link` header with `rel="alternate"` and an ActivityPub media type, for example
+/// `<https://example.com/note/1>; rel="alternate"; type="application/activity+json"`.
+fn alternate_link(headers: &HeaderMap, base: &Url) -> Option<Url> {
+    static LINK_REGEX: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r#"<([^>]*)>((?:\s*;\s*[^;,=]+=(?:"[^"]*"|[^;,]*))*)"#).expect("compile regex")
+    });
+    static PARAM_REGEX: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"([^;,=\s]+)=(?:"([^"]*)"|([^;,]*))"#).expect("compile regex"));
+
+    headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| LINK_REGEX.captures_iter(value))
+        .find_map(|link| {
+            let mut rel_alternate = false;
+            let mut activitypub_type = false;
+            for param in PARAM_REGEX.captures_iter(link.get(2)?.as_str()) {
+                let value = param.get(2).or(param.get(3))?.as_str().trim();
+                match param.get(1)?.as_str().to_lowercase().as_str() {
+                    "rel" => rel_alternate = value.split_whitespace().any(|r| r == "alternate"),
+                    "type" => activitypub_type = is_activitypub_media_type(value),
+                    _ => {}
+                }
+            }
+            if rel_alternate && activitypub_type {
+                base.join(link.get(1)?.as_str()).ok()
+            } else {
+                None
+            }
+        })
+}
+
+/// Converts the response body to UTF-8, according to the charset parameter of the content type.
+/// Bodies without charset are expected to be UTF-8. Returns `None` if the charset is not supported
+/// or the body is not valid in the given charset.
+fn decode_body(body: &Bytes, content_type: Option<&HeaderValue>) -> Option<Bytes> {
+    let charset = content_type.and_then(|c| c.to_str().ok()).and_then(|c| {
+        c.split(';')
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+            .map(|(_, value)| value.trim_matches('"').to_lowercase())
+    });
+    match charset.as_deref() {
+        None | Some("utf-8" | "utf8" | "us-ascii") => {
+            std::str::from_utf8(body).ok()?;
+            Some(body.clone())
+        }
+        Some("iso-8859-1" | "latin1" | "windows-1252" | "cp1252") => Some(
+            body.iter()
+                .map(|b| windows_1252_char(*b))
+                .collect::<String>()
+                .into(),
+        ),
+        Some(_) => None,
+    }
+}
+
+/// Maps a windows-1252 byte to its unicode character. This is a superset of iso-8859-1, and
+/// following the WHATWG encoding standard both are decoded in the same way.
+fn windows_1252_char(byte: u8) -> char {
+    const HIGH: [char; 32] = [
+        '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}',
+        '\u{8F}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}',
+        '\u{2014}', '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}',
+        '\u{178}',
+    ];
+    match byte {
+        0x80..=0x9F => HIGH[usize::from(byte - 0x80)],
+        _ => char::from(byte),
     }
 }
 
@@ -176,8 +782,651 @@ mod tests {
     use super::*;
     use crate::{
         config::FederationConfig,
-        traits::tests::{DbConnection, Person},
+        traits::tests::{debug_data, serve_counting, serve_router, DbConnection, Person, DB_USER},
     };
+    use serde_json::Value;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serve a response with the given headers on a random port. The body is generated from the
+    /// url under which it is served. Returns the url.
+    async fn serve(headers: &'static str, body: fn(&Url) -> Vec<u8>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = Url::parse(&format!("http://localhost:{port}/note")).unwrap();
+        let body = body(&url);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let mut res = format!(
+                    "HTTP/1.1 200 OK\r\n{headers}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                res.extend_from_slice(&body);
+                let _ = stream.write_all(&res).await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_fetch_latin1() -> Result<(), Error> {
+        let url = serve(
+            "Content-Type: application/activity+json; charset=ISO-8859-1\r\n\
+            Content-Disposition: attachment; filename=\"note.json\"",
+            |url| {
+                let mut body = format!(r#"{{"id":"{url}","content":"Caf"#).into_bytes();
+                // "é" and "€" in windows-1252
+                body.extend_from_slice(&[0xE9, b' ', 0x80]);
+                body.extend_from_slice(br#""}"#);
+                body
+            },
+        )
+        .await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&url, &data).await?;
+        assert_eq!(Some("Café €"), res.object["content"].as_str());
+        Ok(())
+    }
+
+    /// Serves an object on a random port, and returns the url together with the `keyId` of each
+    /// request signature which was received.
+    async fn serve_signed() -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = Url::parse(&format!("http://localhost:{port}/note")).unwrap();
+        let body = format!(r#"{{"id":"{url}"}}"#);
+        let key_ids = Arc::new(Mutex::new(vec![]));
+        let key_ids_ = key_ids.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&buf[..len]);
+                if let Some((_, key_id)) = request.split_once("keyId=\"") {
+                    let key_id = key_id.split('"').next().unwrap_or_default();
+                    key_ids_.lock().unwrap().push(key_id.to_string());
+                }
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/activity+json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(res.as_bytes()).await;
+            }
+        });
+        (url, key_ids)
+    }
+
+    #[tokio::test]
+    async fn test_set_signed_fetch_actor() -> Result<(), Error> {
+        let (url, key_ids) = serve_signed().await;
+        let mut other_user = DB_USER.clone();
+        other_user.federation_id = "https://localhost/456".parse()?;
+        let config = debug_data().await.config;
+
+        // Unsigned without signed fetch actor
+        fetch_object_http::<_, Value>(&url, &config.to_request_data()).await?;
+        assert!(key_ids.lock().unwrap().is_empty());
+
+        // Actor can be set after building the config, and applies to existing clones
+        let cloned = config.clone();
+        config.set_signed_fetch_actor(&*DB_USER)?;
+        fetch_object_http::<_, Value>(&url, &cloned.to_request_data()).await?;
+        assert_eq!(
+            key_ids.lock().unwrap().last(),
+            Some(&"https://localhost/123#main-key".to_string())
+        );
+
+        // Per-request actor takes precedence over the config
+        let data = config.to_request_data();
+        data.set_signed_fetch_actor(&other_user)?;
+        fetch_object_http::<_, Value>(&url, &data).await?;
+        fetch_object_http::<_, Value>(&url, &data.reset_request_count()).await?;
+        fetch_object_http::<_, Value>(&url, &config.to_request_data()).await?;
+        assert_eq!(
+            key_ids.lock().unwrap()[1..],
+            [
+                "https://localhost/456#main-key",
+                "https://localhost/456#main-key",
+                "https://localhost/123#main-key"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_body_limit() -> Result<(), Error> {
+        let url = serve("Content-Type: application/activity+json", |url| {
+            format!(r#"{{"id":"{url}","content":"{}"}}"#, "a".repeat(4096)).into_bytes()
+        })
+        .await;
+        let data = debug_data().await;
+        fetch_object_http::<_, Value>(&url, &data).await?;
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .max_fetch_body_size(1024)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let res = fetch_object_http::<_, Value>(&url, &data).await;
+        assert_eq!(res.err(), Some(Error::ResponseBodyLimit));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_depth() -> Result<(), Error> {
+        let url = serve("Content-Type: application/activity+json", |url| {
+            format!(
+                r#"{{"id":"{url}","replies":{}{}}}"#,
+                "[".repeat(19),
+                "]".repeat(19)
+            )
+            .into_bytes()
+        })
+        .await;
+        let data = debug_data().await;
+        fetch_object_http::<_, Value>(&url, &data).await?;
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .max_json_depth(19)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let res = fetch_object_http::<_, Value>(&url, &data).await;
+        assert_eq!(res.err(), Some(Error::JsonDepthLimit));
+
+        // Pathologically nested replies are rejected with the default limit
+        let url = serve("Content-Type: application/activity+json", |url| {
+            format!(
+                r#"{{"id":"{url}","replies":{}{}}}"#,
+                "[".repeat(50_000),
+                "]".repeat(50_000)
+            )
+            .into_bytes()
+        })
+        .await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&url, &data).await;
+        assert_eq!(res.err(), Some(Error::JsonDepthLimit));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_invalid_encoding() -> Result<(), Error> {
+        let url = serve("Content-Type: application/activity+json", |_| {
+            vec![0xFF, 0xFE, 0x00, 0x7B, 0xC3]
+        })
+        .await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&url, &data).await;
+        let err = res.err().unwrap();
+        assert_eq!(Error::FetchInvalidEncoding(url.clone()), err);
+        assert!(err.to_string().contains(url.as_str()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_https_upgraded_id() -> Result<(), Error> {
+        let url = serve("Content-Type: application/activity+json", |url| {
+            let mut id = url.clone();
+            id.set_scheme("https").unwrap();
+            format!(r#"{{"id":"{id}"}}"#).into_bytes()
+        })
+        .await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&url, &data).await?;
+        // Accepted without fetching again, with the https id as canonical url
+        assert_eq!(1, data.request_count());
+        assert_eq!("https", res.url.scheme());
+        assert_eq!(Some(res.url.as_str()), res.object["id"].as_str());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_https_upgrade() -> Result<(), Error> {
+        let http = Url::parse("http://example.com/note/1?page=2")?;
+        let https = Url::parse("https://example.com/note/1?page=2")?;
+        assert!(is_https_upgrade(&http, &https));
+        // Default ports are ignored
+        assert!(is_https_upgrade(
+            &Url::parse("http://example.com:80/note/1?page=2")?,
+            &Url::parse("https://example.com:443/note/1?page=2")?
+        ));
+        // No downgrade to http
+        assert!(!is_https_upgrade(&https, &http));
+        assert!(!is_https_upgrade(&http, &http));
+        // Different path, query, host or port
+        assert!(!is_https_upgrade(
+            &http,
+            &Url::parse("https://example.com/note/2?page=2")?
+        ));
+        assert!(!is_https_upgrade(
+            &http,
+            &Url::parse("https://example.com/note/1")?
+        ));
+        assert!(!is_https_upgrade(
+            &http,
+            &Url::parse("https://example.org/note/1?page=2")?
+        ));
+        assert!(!is_https_upgrade(
+            &http,
+            &Url::parse("https://example.com:8443/note/1?page=2")?
+        ));
+        Ok(())
+    }
+
+    /// Serve html pages with alternate links, and a note at `/note`
+    async fn serve_alternate() -> Url {
+        use axum::{extract::Path, http::header, response::IntoResponse, routing::get, Router};
+
+        let html = |link: &str| {
+            (
+                [
+                    (header::CONTENT_TYPE, "text/html".to_string()),
+                    (header::LINK, link.to_string()),
+                ],
+                "<html></html>",
+            )
+                .into_response()
+        };
+        serve_router(|base| {
+            let note = format!(r#"{{"id":"{base}note","type":"Note"}}"#);
+            Router::new()
+            .route(
+                "/note",
+                get(move || {
+                    let note = note.clone();
+                    async move { ([(header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE)], note) }
+                }),
+            )
+            .route(
+                "/page/:name",
+                get(move |Path(name): Path<String>| async move {
+                    match name.as_str() {
+                        "video" => html(
+                            r#"<https://example.com/oembed>; rel="alternate"; type="application/json+oembed", </note>; rel="alternate"; type="application/activity+json""#,
+                        ),
+                        "loop_a" => html(r#"</page/loop_b>; rel="alternate"; type="application/activity+json""#),
+                        "loop_b" => html(r#"</page/loop_a>; rel="alternate"; type="application/activity+json""#),
+                        _ => html(r#"</note>; rel="canonical"; type="application/activity+json""#),
+                    }
+                }),
+            )
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_fetch_alternate_link() -> Result<(), Error> {
+        let base = serve_alternate().await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/page/video")?, &data).await?;
+        assert_eq!(base.join("/note")?, res.url);
+        assert_eq!(Some("Note"), res.object["type"].as_str());
+        assert_eq!(2, data.request_count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_alternate_link_single_hop() -> Result<(), Error> {
+        let base = serve_alternate().await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/page/loop_a")?, &data).await;
+        assert!(res.is_err());
+        assert_eq!(2, data.request_count());
+
+        // Links which are not rel=alternate are ignored
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/page/other")?, &data).await;
+        assert!(res.is_err());
+        assert_eq!(1, data.request_count());
+        Ok(())
+    }
+
+    /// Serve a note at `/note` with an etag and last modified time, which responds with
+    /// `304 Not Modified` if either of them matches the request
+    async fn serve_cached() -> Url {
+        use axum::{
+            http::{header, HeaderMap},
+            response::IntoResponse,
+            routing::get,
+            Router,
+        };
+
+        let base = serve_router(|base| {
+            let note = format!(r#"{{"id":"{base}note","type":"Note"}}"#);
+            Router::new().route(
+                "/note",
+                get(move |headers: HeaderMap| async move {
+                    let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+                    if header(header::IF_NONE_MATCH) == Some("\"v1\"")
+                        || header(header::IF_MODIFIED_SINCE) == Some(LAST_MODIFIED_VALUE)
+                    {
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    (
+                        [
+                            (header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE),
+                            (header::ETAG, "\"v1\""),
+                            (header::LAST_MODIFIED, LAST_MODIFIED_VALUE),
+                        ],
+                        note,
+                    )
+                        .into_response()
+                }),
+            )
+        })
+        .await;
+        base.join("note").unwrap()
+    }
+
+    const LAST_MODIFIED_VALUE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    #[tokio::test]
+    async fn test_fetch_conditional() -> Result<(), Error> {
+        let url = serve_cached().await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&url, &data).await?;
+        assert_eq!(Some("\"v1\""), res.etag());
+        assert_eq!(Some(LAST_MODIFIED_VALUE), res.last_modified());
+
+        let conditional = |etag, last_modified| {
+            fetch_object_http_conditional::<_, Value>(&url, &data, etag, last_modified)
+        };
+        let res = conditional(res.etag(), None).await?;
+        assert!(matches!(res, ConditionalFetchResponse::NotModified));
+        let res = conditional(None, Some(LAST_MODIFIED_VALUE)).await?;
+        assert!(matches!(res, ConditionalFetchResponse::NotModified));
+
+        // Outdated values, or no values at all
+        let res = conditional(Some("\"v0\""), None).await?;
+        let ConditionalFetchResponse::Modified(res) = res else {
+            panic!("expected modified object");
+        };
+        assert_eq!(Some("Note"), res.object["type"].as_str());
+        let res = conditional(None, None).await?;
+        assert!(matches!(res, ConditionalFetchResponse::Modified(_)));
+        assert_eq!(5, data.request_count());
+        Ok(())
+    }
+
+    /// Serve a person whose id alternates between `/users/alice` and `/@alice`, a note at
+    /// `/note/1` with id `/note/2`, and a note with an id on another host at `/other_host`
+    async fn serve_mismatching_ids() -> Url {
+        use axum::{extract::Path, http::header, routing::get, Router};
+
+        serve_router(|base| {
+            let ids = base.clone();
+            Router::new().route(
+                "/*path",
+                get(move |Path(path): Path<String>| async move {
+                    let id = match path.as_str() {
+                        "users/alice" => format!("{ids}@alice"),
+                        "@alice" => format!("{ids}users/alice"),
+                        "other_host" => "http://example.org/other_host".to_string(),
+                        _ => format!("{ids}note/2"),
+                    };
+                    let json = format!(r#"{{"id":"{id}"}}"#);
+                    ([(header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE)], json)
+                }),
+            )
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_fetch_id_mismatch_strict() -> Result<(), Error> {
+        let base = serve_mismatching_ids().await;
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/note/1")?, &data).await?;
+        assert_eq!(base.join("/note/2")?, res.url);
+        assert_eq!(2, data.request_count());
+
+        // The id is only fetched once
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/users/alice")?, &data).await;
+        assert!(matches!(res, Err(Error::FetchWrongId(url)) if url == base.join("/@alice")?));
+        assert_eq!(2, data.request_count());
+
+        let data = debug_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/other_host")?, &data).await;
+        assert!(matches!(res, Err(Error::FetchWrongId(_))));
+        assert_eq!(1, data.request_count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_id_mismatch_same_domain_allowed() -> Result<(), Error> {
+        let base = serve_mismatching_ids().await;
+        let relaxed_data = || async {
+            FederationConfig::builder()
+                .domain("example.com")
+                .app_data(DbConnection)
+                .debug(true)
+                .allow_fetch_id_mismatch_same_domain(true)
+                .build()
+                .await
+                .unwrap()
+                .to_request_data()
+        };
+        let data = relaxed_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/note/1")?, &data).await?;
+        assert_eq!(base.join("/note/2")?, res.url);
+        assert_eq!(2, data.request_count());
+
+        // Accepted after fetching the id once, with the id as url
+        let data = relaxed_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/users/alice")?, &data).await?;
+        assert_eq!(base.join("/users/alice")?, res.url);
+        assert_eq!(Some(res.url.as_str()), res.object["id"].as_str());
+        assert_eq!(2, data.request_count());
+
+        // Ids on other hosts are still rejected
+        let data = relaxed_data().await;
+        let res = fetch_object_http::<_, Value>(&base.join("/other_host")?, &data).await;
+        assert!(matches!(res, Err(Error::FetchWrongId(_))));
+        assert_eq!(1, data.request_count());
+        Ok(())
+    }
+
+    /// Serve a note at `/note`, and a redirect to the `to` query parameter at `/redirect`. Returns
+    /// the base url and the number of requests.
+    async fn serve_redirect() -> (Url, Arc<AtomicUsize>) {
+        use axum::{
+            extract::Query,
+            http::{header, StatusCode},
+            routing::get,
+            Router,
+        };
+        use std::collections::HashMap;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (note_requests, redirect_requests) = (requests.clone(), requests.clone());
+        let base = serve_router(|base| {
+            let note = format!(r#"{{"id":"{base}note","type":"Note"}}"#);
+            Router::new()
+            .route(
+                "/note",
+                get(move || {
+                    note_requests.fetch_add(1, Ordering::Relaxed);
+                    let note = note.clone();
+                    async move { ([(header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE)], note) }
+                }),
+            )
+            .route(
+                "/redirect",
+                get(move |Query(query): Query<HashMap<String, String>>| {
+                    redirect_requests.fetch_add(1, Ordering::Relaxed);
+                    async move { (StatusCode::FOUND, [(header::LOCATION, query["to"].clone())]) }
+                }),
+            )
+        })
+        .await;
+        (base, requests)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_local_and_remote() -> Result<(), Error> {
+        let (local, local_requests) = serve_redirect().await;
+        let (remote, remote_requests) = serve_redirect().await;
+        let data = FederationConfig::builder()
+            .domain(format!("localhost:{}", local.port().unwrap()))
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let redirect = |from: &Url, to: &Url| {
+            let mut url = from.join("/redirect").unwrap();
+            url.query_pairs_mut()
+                .append_pair("to", to.join("/note").unwrap().as_str());
+            url
+        };
+
+        // remote url, remote object
+        let res = fetch_object_http::<_, Value>(&redirect(&remote, &remote), &data).await?;
+        assert_eq!(remote.join("/note")?, res.url);
+        assert_eq!(2, remote_requests.load(Ordering::Relaxed));
+
+        // local url which redirects to remote object
+        let res = fetch_object_http::<_, Value>(&redirect(&local, &remote), &data).await?;
+        assert_eq!(remote.join("/note")?, res.url);
+        assert_eq!(1, local_requests.load(Ordering::Relaxed));
+        assert_eq!(3, remote_requests.load(Ordering::Relaxed));
+
+        // local url, local object
+        let res = fetch_object_http::<_, Value>(&local.join("/note")?, &data).await;
+        assert_eq!(Some(Error::NotFound), res.err());
+        assert_eq!(2, local_requests.load(Ordering::Relaxed));
+
+        // remote url which redirects to local object, the redirect is not followed
+        let res = fetch_object_http::<_, Value>(&redirect(&remote, &local), &data).await;
+        assert_eq!(Some(Error::NotFound), res.err());
+        assert_eq!(4, remote_requests.load(Ordering::Relaxed));
+        assert_eq!(2, local_requests.load(Ordering::Relaxed));
+
+        // local objects can be fetched explicitly
+        let res = fetch_object_http_with_kind::<_, Value>(
+            &redirect(&remote, &local),
+            &data,
+            RequestKind::Object,
+            true,
+        )
+        .await?;
+        assert_eq!(local.join("/note")?, res.url);
+        assert_eq!(3, local_requests.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_activitypub_media_type() {
+        let valid = [
+            FEDERATION_CONTENT_TYPE,
+            "APPLICATION/ACTIVITY+JSON",
+            "Application/Activity+JSON; Charset=UTF-8",
+            "application/ld+json",
+            r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#,
+            r#"application/ld+json;profile="https://www.w3.org/ns/activitystreams";charset=utf-8"#,
+            r#"application/ld+json; charset=utf-8; profile="https://www.w3.org/ns/activitystreams""#,
+            r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams https://example.com/profile""#,
+        ];
+        for media_type in valid {
+            assert!(is_activitypub_media_type(media_type), "{media_type}");
+        }
+        let invalid = [
+            "application/json",
+            "text/html; charset=utf-8",
+            r#"application/ld+json; profile="https://example.com/profile""#,
+            "application/activity+json;;",
+            "",
+        ];
+        for media_type in invalid {
+            assert!(!is_activitypub_media_type(media_type), "{media_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extra_accepted_content_types() -> Result<(), Error> {
+        let config = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .extra_accepted_content_types(vec!["Application/JSON".to_string()])
+            .build()
+            .await
+            .unwrap();
+        let accepted = |content_type: &'static str| {
+            is_accepted_content_type(&config, Some(&HeaderValue::from_static(content_type)))
+        };
+        assert!(accepted("application/json; charset=utf-8"));
+        assert!(accepted(FEDERATION_CONTENT_TYPE));
+        assert!(!accepted("text/html"));
+
+        let default_config = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap();
+        let json = HeaderValue::from_static("application/json");
+        assert!(!is_accepted_content_type(&default_config, Some(&json)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() -> Result<(), Error> {
+        let (port, requests) = serve_counting(Duration::from_millis(400)).await;
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .request_deadline(Duration::from_secs(1))
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+
+        // Two fetches fit into the deadline, the third one is aborted when it runs out
+        let mut results = vec![];
+        for i in 0..5 {
+            let url = Url::parse(&format!("http://localhost:{port}/note/{i}"))?;
+            results.push(fetch_object_http::<_, Value>(&url, &data).await.err());
+        }
+        assert_eq!(results[..2], [None, None]);
+        assert!(results[2..]
+            .iter()
+            .all(|r| r == &Some(Error::RequestDeadlineExceeded)));
+        // Only the first three fetches were actually sent
+        assert_eq!(data.request_count(), 3);
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+
+        // A new deadline starts with reset
+        let data = data.reset_request_count();
+        let url = Url::parse(&format!("http://localhost:{port}/note/5"))?;
+        fetch_object_http::<_, Value>(&url, &data).await?;
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_request_limit() -> Result<(), Error> {
@@ -0,0 +1,798 @@
+//! Serving and fetching nodeinfo, which describes the software and usage of an instance
+//!
+//! Instances serve a discovery document at `/.well-known/nodeinfo`, which links to the actual
+//! nodeinfo document. Both can be returned directly from axum and actix-web handlers:
+//!
+//! ```
+//! # use activitypub_federation::config::Data;
+//! # use activitypub_federation::fetch::nodeinfo::{NodeInfo, NodeInfoWellKnown};
+//! # use activitypub_federation::traits::tests::DbConnection;
+//! # use url::Url;
+//! async fn well_known_nodeinfo(data: Data<DbConnection>) -> Result<NodeInfoWellKnown, url::ParseError> {
+//!     let href = Url::parse(&format!("https://{}/nodeinfo/2.1", data.domain()))?;
+//!     Ok(NodeInfoWellKnown::new(href, "2.1"))
+//! }
+//!
+//! async fn nodeinfo() -> NodeInfo {
+//!     NodeInfo::new("example-software", "1.0.0")
+//!         .open_registrations(true)
+//!         .users(1000, 300, 100)
+//!         .local_posts(20000)
+//! }
+//! ```
+//!
+//! Use [fetch_nodeinfo] to fetch the nodeinfo of another instance. In addition the software of
+//! remote hosts can be detected automatically, to adjust requests for them, see
+//! [FederationConfigBuilder::detect_peer_software](crate::config::FederationConfigBuilder::detect_peer_software).
+//!
+//! <https://nodeinfo.diaspora.software/protocol>
+
+use crate::{
+    config::{Data, DOMAIN_REGEX},
+    error::{Error, JsonError},
+    fetch::safe_get::SafeGetOptions,
+    protocol::{helpers::deserialize_skip_error, verification::normalize_domain},
+    reqwest_shim::ResponseExt,
+};
+use dyn_clone::{clone_trait_object, DynClone};
+use http::HeaderValue;
+use moka::future::Cache;
+use reqwest::Client;
+use reqwest_middleware::ClientWithMiddleware;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tracing::debug;
+use url::Url;
+
+/// Prefix of the `rel` values for nodeinfo schema links
+const NODEINFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/";
+
+/// How long the software of a host is remembered
+const SOFTWARE_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// How long to wait before retrying a host whose nodeinfo couldn't be fetched
+const FAILURE_TTL: Duration = Duration::from_secs(3600);
+
+/// How long a host which only accepted compat signatures is remembered, after that the modern
+/// signature is tried again
+const SIGNATURE_COMPAT_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Software which is running on a remote host, as reported by its nodeinfo
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct PeerSoftware {
+    /// Name of the software, eg `mastodon` or `pleroma`
+    pub name: String,
+    /// Version string of the software, eg `4.2.8` or `2.4.5-1-g123abc`
+    #[serde(default)]
+    pub version: String,
+}
+
+impl PeerSoftware {
+    /// Leading numeric parts of the version, eg `[2, 4, 5]` for `2.4.5-1-g123abc`. These can be
+    /// compared directly, so that `software.version_numbers() < vec![2, 5]` checks for versions
+    /// before 2.5.
+    pub fn version_numbers(&self) -> Vec<u64> {
+        self.version
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map_while(|n| n.parse().ok())
+            .collect()
+    }
+
+    fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Adjustments to outgoing requests for a specific remote host
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerQuirks {
+    /// Sign HTTP signatures according to draft 10, same as
+    /// [FederationConfigBuilder::http_signature_compat](crate::config::FederationConfigBuilder::http_signature_compat)
+    /// but only for this host.
+    pub http_signature_compat: bool,
+}
+
+/// Rules which determine [PeerQuirks] based on the software of the remote host.
+///
+/// The software is detected if [FederationConfigBuilder::detect_peer_software](crate::config::FederationConfigBuilder::detect_peer_software)
+/// is enabled. Until it is known, requests are sent with default settings.
+///
+/// ```
+/// # use activitypub_federation::fetch::nodeinfo::{DefaultQuirksTable, PeerQuirks, PeerSoftware, QuirksTable};
+/// #[derive(Clone)]
+/// struct MyQuirks;
+///
+/// impl QuirksTable for MyQuirks {
+///     fn quirks(&self, software: &PeerSoftware) -> PeerQuirks {
+///         if software.name == "gotosocial" {
+///             return PeerQuirks {
+///                 http_signature_compat: true,
+///             };
+///         }
+///         DefaultQuirksTable.quirks(software)
+///     }
+/// }
+/// ```
+pub trait QuirksTable: DynClone + Send + Sync {
+    /// Returns the adjustments which are needed for the given software
+    fn quirks(&self, software: &PeerSoftware) -> PeerQuirks;
+}
+
+clone_trait_object!(QuirksTable);
+
+/// Builtin rules for well-known software:
+///
+/// - Pleroma before 2.5 only accepts HTTP signatures according to draft 10
+#[derive(Clone, Debug)]
+pub struct DefaultQuirksTable;
+
+impl QuirksTable for DefaultQuirksTable {
+    fn quirks(&self, software: &PeerSoftware) -> PeerQuirks {
+        PeerQuirks {
+            http_signature_compat: software.is("pleroma")
+                && software.version_numbers() < vec![2, 5],
+        }
+    }
+}
+
+/// Discovery document which is served at `/.well-known/nodeinfo`, see the [module docs](self)
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NodeInfoWellKnown {
+    /// Links to the nodeinfo documents in different schema versions
+    pub links: Vec<NodeInfoLink>,
+}
+
+/// Link to a nodeinfo document, part of [NodeInfoWellKnown]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NodeInfoLink {
+    /// Schema of the document, eg `http://nodeinfo.diaspora.software/ns/schema/2.1`
+    pub rel: String,
+    /// Url of the document
+    pub href: Url,
+}
+
+impl NodeInfoWellKnown {
+    /// Discovery document with a link to the nodeinfo document at `href`, which uses the schema
+    /// `version`, eg `2.1`
+    pub fn new(href: Url, version: &str) -> Self {
+        NodeInfoWellKnown { links: vec![] }.link(href, version)
+    }
+
+    /// Adds a link to another nodeinfo document, for instances which serve multiple schema
+    /// versions
+    pub fn link(mut self, href: Url, version: &str) -> Self {
+        self.links.push(NodeInfoLink {
+            rel: format!("{NODEINFO_SCHEMA}{version}"),
+            href,
+        });
+        self
+    }
+
+    /// Link to the document with the newest schema version
+    fn newest_link(self) -> Option<NodeInfoLink> {
+        self.links
+            .into_iter()
+            .filter(|l| l.rel.starts_with(NODEINFO_SCHEMA))
+            .max_by(|a, b| a.rel.cmp(&b.rel))
+    }
+}
+
+/// Nodeinfo document in schema version 2.0 or 2.1, see the [module docs](self).
+///
+/// Only `software` is required when parsing, so that documents of software which omits other
+/// fields can be read.
+///
+/// <https://github.com/jhass/nodeinfo/blob/main/schemas/2.1/schema.json>
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfo {
+    /// Schema version, `2.0` or `2.1`
+    #[serde(default)]
+    pub version: String,
+    /// Software which the instance is running
+    pub software: NodeInfoSoftware,
+    /// Supported federation protocols, eg `activitypub`
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// Third party sites which the instance can connect to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<NodeInfoServices>,
+    /// Usage statistics of the instance
+    #[serde(default)]
+    pub usage: NodeInfoUsage,
+    /// Whether new users can sign up
+    #[serde(default)]
+    pub open_registrations: bool,
+    /// Free form information about the instance, such as its name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+}
+
+/// Software of an instance, part of [NodeInfo]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NodeInfoSoftware {
+    /// Name of the software in lowercase, eg `mastodon` or `lemmy`
+    pub name: String,
+    /// Version of the software
+    #[serde(default)]
+    pub version: String,
+    /// Url of the source code repository, only in schema 2.1
+    #[serde(
+        default,
+        deserialize_with = "deserialize_skip_error",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub repository: Option<Url>,
+    /// Url of the homepage of the software, only in schema 2.1
+    #[serde(
+        default,
+        deserialize_with = "deserialize_skip_error",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub homepage: Option<Url>,
+}
+
+/// Third party sites which an instance can receive from or publish to, part of [NodeInfo]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NodeInfoServices {
+    /// Sites which the instance can retrieve messages from
+    #[serde(default)]
+    pub inbound: Vec<String>,
+    /// Sites which the instance can publish messages to
+    #[serde(default)]
+    pub outbound: Vec<String>,
+}
+
+/// Usage statistics of an instance, part of [NodeInfo]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfoUsage {
+    /// Numbers of users
+    #[serde(default)]
+    pub users: NodeInfoUsers,
+    /// Number of posts made by local users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_posts: Option<u64>,
+    /// Number of comments made by local users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_comments: Option<u64>,
+}
+
+/// Numbers of users of an instance, part of [NodeInfoUsage]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfoUsers {
+    /// Total number of registered users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// Users who were active in the last 180 days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_halfyear: Option<u64>,
+    /// Users who were active in the last 30 days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_month: Option<u64>,
+}
+
+impl NodeInfo {
+    /// Nodeinfo 2.1 document for the given software, with the `activitypub` protocol, closed
+    /// registrations and without usage statistics
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Self {
+        NodeInfo {
+            version: "2.1".to_string(),
+            software: NodeInfoSoftware {
+                name: name.into(),
+                version: version.into(),
+                repository: None,
+                homepage: None,
+            },
+            protocols: vec!["activitypub".to_string()],
+            services: Some(NodeInfoServices::default()),
+            usage: NodeInfoUsage::default(),
+            open_registrations: false,
+            metadata: Some(Value::Object(Default::default())),
+        }
+    }
+
+    /// Whether new users can sign up
+    pub fn open_registrations(mut self, open_registrations: bool) -> Self {
+        self.open_registrations = open_registrations;
+        self
+    }
+
+    /// Total number of users, and the users who were active in the last 180 and 30 days
+    pub fn users(mut self, total: u64, active_halfyear: u64, active_month: u64) -> Self {
+        self.usage.users = NodeInfoUsers {
+            total: Some(total),
+            active_halfyear: Some(active_halfyear),
+            active_month: Some(active_month),
+        };
+        self
+    }
+
+    /// Number of posts made by local users
+    pub fn local_posts(mut self, local_posts: u64) -> Self {
+        self.usage.local_posts = Some(local_posts);
+        self
+    }
+
+    /// Number of comments made by local users
+    pub fn local_comments(mut self, local_comments: u64) -> Self {
+        self.usage.local_comments = Some(local_comments);
+        self
+    }
+
+    /// Urls of the source code repository and of the homepage of the software
+    pub fn software_urls(mut self, repository: Option<Url>, homepage: Option<Url>) -> Self {
+        self.software.repository = repository;
+        self.software.homepage = homepage;
+        self
+    }
+
+    /// Free form information about the instance, such as its name
+    pub fn metadata(mut self, metadata: Value) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Content type of the document, which includes the schema version as profile
+    #[cfg(any(feature = "actix-web", feature = "axum"))]
+    pub(crate) fn content_type(&self) -> String {
+        format!(
+            "application/json; profile=\"{NODEINFO_SCHEMA}{}#\"",
+            self.version
+        )
+    }
+}
+
+/// Fetches the nodeinfo of the instance with the given domain, eg `mastodon.social`.
+///
+/// First the discovery document at `/.well-known/nodeinfo` is fetched, and then the linked
+/// document with the newest schema version. Both requests are made with [Data::safe_get], so the
+/// urls are verified and the configured timeout applies. Fails with [Error::NotFound] if the
+/// instance doesn't serve nodeinfo, and with [Error::UrlVerificationError] if the document is
+/// linked on another host.
+pub async fn fetch_nodeinfo<T: Clone>(domain: &str, data: &Data<T>) -> Result<NodeInfo, Error> {
+    let domain = normalize_domain(domain);
+    // For production mode make sure that domain doesnt contain any port or path.
+    if !data.config.debug && !DOMAIN_REGEX.is_match(&domain) {
+        return Err(Error::UrlVerificationError("Invalid characters in domain"));
+    }
+    let protocol = if data.config.debug { "http" } else { "https" };
+    let well_known_url = Url::parse(&format!("{protocol}://{domain}/.well-known/nodeinfo"))?;
+    let well_known: NodeInfoWellKnown = safe_get_json(&well_known_url, data).await?;
+
+    let link = well_known.newest_link().ok_or(Error::NotFound)?;
+    if link.href.host_str() != well_known_url.host_str() {
+        return Err(Error::UrlVerificationError(
+            "Nodeinfo link points to different host",
+        ));
+    }
+    safe_get_json(&link.href, data).await
+}
+
+async fn safe_get_json<T: Clone, Kind: DeserializeOwned>(
+    url: &Url,
+    data: &Data<T>,
+) -> Result<Kind, Error> {
+    let options = SafeGetOptions {
+        accept: Some(HeaderValue::from_static("application/json")),
+        ..Default::default()
+    };
+    let res = data.safe_get(url, &options).await?;
+    if !res.status.is_success() {
+        return Err(Error::NotFound);
+    }
+    JsonError::parse(&res.body).map_err(|e| {
+        Error::ParseFetchedObject(
+            Box::new(e),
+            url.clone(),
+            String::from_utf8_lossy(&res.body).to_string(),
+        )
+    })
+}
+
+/// Fetches the nodeinfo of the host of `url` and returns its software.
+///
+/// <https://nodeinfo.diaspora.software/protocol>
+pub(crate) async fn fetch_peer_software(
+    client: &ClientWithMiddleware,
+    url: &Url,
+    timeout: Duration,
+) -> Result<PeerSoftware, Error> {
+    let mut well_known = url.clone();
+    well_known.set_path("/.well-known/nodeinfo");
+    well_known.set_query(None);
+    well_known.set_fragment(None);
+    let well_known: NodeInfoWellKnown = fetch_json(client, &well_known, timeout).await?;
+
+    // Use the newest schema version
+    let link = well_known.newest_link().ok_or(Error::NotFound)?;
+    if link.href.host_str() != url.host_str() {
+        return Err(Error::UrlVerificationError(
+            "Nodeinfo link points to different host",
+        ));
+    }
+    let nodeinfo: NodeInfo = fetch_json(client, &link.href, timeout).await?;
+    Ok(PeerSoftware {
+        name: nodeinfo.software.name,
+        version: nodeinfo.software.version,
+    })
+}
+
+async fn fetch_json<T: DeserializeOwned>(
+    client: &ClientWithMiddleware,
+    url: &Url,
+    timeout: Duration,
+) -> Result<T, Error> {
+    let res = client
+        .get(url.as_str())
+        .header("Accept", "application/json")
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let body = res.bytes_limited().await?;
+    JsonError::parse(&body).map_err(|e| {
+        Error::ParseFetchedObject(
+            Box::new(e),
+            url.clone(),
+            String::from_utf8_lossy(&body).to_string(),
+        )
+    })
+}
+
+/// Remembers the software of remote hosts, which is fetched in the background on first contact.
+/// Also remembers hosts which only accept compat signatures, see
+/// [FederationConfigBuilder::http_signature_auto_compat](crate::config::FederationConfigBuilder::http_signature_auto_compat).
+pub(crate) struct PeerSoftwareCache {
+    enabled: bool,
+    client: ClientWithMiddleware,
+    timeout: Duration,
+    quirks_table: Box<dyn QuirksTable>,
+    software: Cache<String, PeerSoftware>,
+    /// Hosts whose nodeinfo couldn't be fetched, these are not retried for some time
+    failed: Cache<String, ()>,
+    /// Hosts whose nodeinfo is currently being fetched
+    pending: Mutex<HashSet<String>>,
+    /// Hosts which rejected a signature and then accepted it in compat mode, `None` if
+    /// automatic compat mode is disabled
+    signature_compat: Option<Cache<String, ()>>,
+}
+
+impl PeerSoftwareCache {
+    pub(crate) fn new(
+        enabled: bool,
+        client: ClientWithMiddleware,
+        timeout: Duration,
+        quirks_table: Box<dyn QuirksTable>,
+        auto_signature_compat: bool,
+    ) -> Self {
+        PeerSoftwareCache {
+            enabled,
+            client,
+            timeout,
+            quirks_table,
+            software: Cache::builder()
+                .max_capacity(10000)
+                .time_to_live(SOFTWARE_TTL)
+                .build(),
+            failed: Cache::builder()
+                .max_capacity(10000)
+                .time_to_live(FAILURE_TTL)
+                .build(),
+            pending: Default::default(),
+            signature_compat: auto_signature_compat.then(|| {
+                Cache::builder()
+                    .max_capacity(10000)
+                    .time_to_live(SIGNATURE_COMPAT_TTL)
+                    .build()
+            }),
+        }
+    }
+
+    fn host(url: &Url) -> String {
+        format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        )
+    }
+
+    /// Software of the host of `url`, if it is already known
+    pub(crate) async fn get(&self, url: &Url) -> Option<PeerSoftware> {
+        self.software.get(&Self::host(url)).await
+    }
+
+    /// Adjustments for requests to the host of `url`. Returns the defaults while the software is
+    /// not known, except for compat signatures if the host required them before.
+    pub(crate) async fn quirks(&self, url: &Url) -> PeerQuirks {
+        let mut quirks: PeerQuirks = self
+            .get(url)
+            .await
+            .map(|software| self.quirks_table.quirks(&software))
+            .unwrap_or_default();
+        if let Some(signature_compat) = &self.signature_compat {
+            quirks.http_signature_compat |= signature_compat.contains_key(&Self::host(url));
+        }
+        quirks
+    }
+
+    /// Returns true if a rejected signature should be retried in compat mode
+    pub(crate) fn auto_signature_compat(&self) -> bool {
+        self.signature_compat.is_some()
+    }
+
+    /// Use compat signatures for further requests to the host of `url`, after it accepted a
+    /// compat signature which was retried
+    pub(crate) async fn remember_signature_compat(&self, url: &Url) {
+        if let Some(signature_compat) = &self.signature_compat {
+            debug!("Using compat signatures for {}", Self::host(url));
+            signature_compat.insert(Self::host(url), ()).await;
+        }
+    }
+
+    /// Fetches the software of the host of `url` in the background, unless it is already known or
+    /// failed recently. Never blocks the caller.
+    pub(crate) fn discover(self: &Arc<Self>, url: &Url) {
+        if !self.enabled {
+            return;
+        }
+        let host = Self::host(url);
+        if self.software.contains_key(&host) || self.failed.contains_key(&host) {
+            return;
+        }
+        if !self
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(host.clone())
+        {
+            return;
+        }
+        let cache = self.clone();
+        let url = url.clone();
+        tokio::spawn(async move {
+            match fetch_peer_software(&cache.client, &url, cache.timeout).await {
+                Ok(software) => {
+                    debug!("Host {host} is running {software:?}");
+                    cache.software.insert(host.clone(), software).await;
+                }
+                Err(err) => {
+                    debug!("Failed to fetch nodeinfo for {host}: {err}");
+                    cache.failed.insert(host.clone(), ()).await;
+                }
+            }
+            cache
+                .pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&host);
+        });
+    }
+}
+
+impl Default for PeerSoftwareCache {
+    fn default() -> Self {
+        PeerSoftwareCache::new(
+            false,
+            Client::default().into(),
+            Duration::from_secs(10),
+            Box::new(DefaultQuirksTable),
+            false,
+        )
+    }
+}
+
+impl Debug for PeerSoftwareCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerSoftwareCache")
+            .field("enabled", &self.enabled)
+            .field("software", &self.software.entry_count())
+            .field("failed", &self.failed.entry_count())
+            .field(
+                "signature_compat",
+                &self.signature_compat.as_ref().map(Cache::entry_count),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{config::FederationConfig, traits::tests::DbConnection};
+    use serde_json::json;
+
+    fn mastodon_well_known() -> Value {
+        json!({
+            "links": [{
+                "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                "href": "https://mastodon.social/nodeinfo/2.0"
+            }]
+        })
+    }
+
+    fn mastodon_nodeinfo() -> Value {
+        json!({
+            "version": "2.0",
+            "software": { "name": "mastodon", "version": "4.2.8" },
+            "protocols": ["activitypub"],
+            "services": { "outbound": [], "inbound": [] },
+            "usage": {
+                "users": { "total": 2168234, "activeMonth": 280534, "activeHalfyear": 609498 },
+                "localPosts": 109346713
+            },
+            "openRegistrations": true,
+            "metadata": {
+                "nodeName": "Mastodon",
+                "nodeDescription": "The original server operated by the Mastodon gGmbH non-profit"
+            }
+        })
+    }
+
+    fn lemmy_well_known() -> Value {
+        json!({
+            "links": [
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                    "href": "https://lemmy.ml/nodeinfo/2.0.json"
+                },
+                {
+                    "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                    "href": "https://lemmy.ml/nodeinfo/2.1"
+                }
+            ]
+        })
+    }
+
+    fn lemmy_nodeinfo() -> Value {
+        json!({
+            "version": "2.1",
+            "software": {
+                "name": "lemmy",
+                "version": "0.19.5",
+                "repository": "https://github.com/LemmyNet/lemmy",
+                "homepage": "https://join-lemmy.org/"
+            },
+            "protocols": ["activitypub"],
+            "usage": {
+                "users": { "total": 57631, "activeHalfyear": 9754, "activeMonth": 4582 },
+                "localPosts": 117427,
+                "localComments": 1033521
+            },
+            "openRegistrations": true,
+            "services": { "inbound": [], "outbound": [] },
+            "metadata": {}
+        })
+    }
+
+    #[test]
+    fn test_nodeinfo_round_trip() {
+        for fixture in [mastodon_nodeinfo(), lemmy_nodeinfo()] {
+            let nodeinfo: NodeInfo = serde_json::from_value(fixture.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&nodeinfo).unwrap(), fixture);
+        }
+        for fixture in [mastodon_well_known(), lemmy_well_known()] {
+            let well_known: NodeInfoWellKnown = serde_json::from_value(fixture.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&well_known).unwrap(), fixture);
+        }
+
+        let nodeinfo: NodeInfo = serde_json::from_value(lemmy_nodeinfo()).unwrap();
+        assert_eq!(nodeinfo.usage.users.active_month, Some(4582));
+        assert_eq!(
+            nodeinfo.software.homepage.unwrap().as_str(),
+            "https://join-lemmy.org/"
+        );
+        let well_known: NodeInfoWellKnown = serde_json::from_value(lemmy_well_known()).unwrap();
+        assert_eq!(
+            well_known.newest_link().unwrap().href.as_str(),
+            "https://lemmy.ml/nodeinfo/2.1"
+        );
+
+        // Only the software is required
+        let minimal: NodeInfo =
+            serde_json::from_value(json!({ "software": { "name": "pleroma" } })).unwrap();
+        assert_eq!(minimal.software.name, "pleroma");
+        assert!(!minimal.open_registrations);
+    }
+
+    #[test]
+    fn test_nodeinfo_builder() {
+        let nodeinfo = NodeInfo::new("lemmy", "0.19.5")
+            .software_urls(
+                Some("https://github.com/LemmyNet/lemmy".parse().unwrap()),
+                Some("https://join-lemmy.org/".parse().unwrap()),
+            )
+            .open_registrations(true)
+            .users(57631, 9754, 4582)
+            .local_posts(117427)
+            .local_comments(1033521);
+        assert_eq!(serde_json::to_value(&nodeinfo).unwrap(), lemmy_nodeinfo());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_nodeinfo() -> Result<(), Error> {
+        use axum::{routing::get, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let href = Url::parse(&format!("http://{domain}/nodeinfo/2.1"))?;
+        let well_known = NodeInfoWellKnown::new(href, "2.1");
+        let app = Router::new()
+            .route(
+                "/.well-known/nodeinfo",
+                get(move || async move { well_known.clone() }),
+            )
+            .route(
+                "/nodeinfo/2.1",
+                get(|| async { NodeInfo::new("lemmy", "0.19.5").users(3, 2, 1) }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let nodeinfo = fetch_nodeinfo(&domain, &data).await?;
+        assert_eq!(nodeinfo, NodeInfo::new("lemmy", "0.19.5").users(3, 2, 1));
+        assert_eq!(data.safe_get_count(), 2);
+
+        // The response has the nodeinfo profile
+        let res = reqwest::get(format!("http://{domain}/nodeinfo/2.1"))
+            .await
+            .unwrap();
+        assert_eq!(
+            res.headers()[http::header::CONTENT_TYPE],
+            "application/json; profile=\"http://nodeinfo.diaspora.software/ns/schema/2.1#\""
+        );
+        Ok(())
+    }
+
+    fn software(name: &str, version: &str) -> PeerSoftware {
+        PeerSoftware {
+            name: name.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_version_numbers() {
+        assert_eq!(software("a", "4.2.8").version_numbers(), vec![4, 2, 8]);
+        assert_eq!(
+            software("a", "2.4.5-1-g123abc").version_numbers(),
+            vec![2, 4, 5]
+        );
+        assert_eq!(
+            software("a", "2.5.0+soapbox").version_numbers(),
+            vec![2, 5, 0]
+        );
+        assert_eq!(software("a", "").version_numbers(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_default_quirks() {
+        let compat = |name, version| {
+            DefaultQuirksTable
+                .quirks(&software(name, version))
+                .http_signature_compat
+        };
+        assert!(compat("pleroma", "2.4.5"));
+        assert!(compat("Pleroma", "2.4.55-12-gabcdef"));
+        assert!(!compat("pleroma", "2.5.0"));
+        assert!(!compat("mastodon", "2.4.0"));
+    }
+}
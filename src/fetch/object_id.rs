@@ -1,11 +1,35 @@
-use crate::{config::Data, error::Error, fetch::fetch_object_http, traits::Object};
+use crate::{
+    config::{Data, RequestKind},
+    error::{Error, JsonError},
+    fetch::{fetch_object_http_with_kind, FetchObjectResponse, Fetched},
+    traits::Object,
+    url::{deserialize_normalized, normalize},
+};
 use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::{
+    any::type_name,
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::HashSet,
     fmt::{Debug, Display, Formatter},
+    future::Future,
+    hash::{Hash, Hasher},
     marker::PhantomData,
+    pin::Pin,
     str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Semaphore,
+    },
+    task::{JoinHandle, JoinSet},
 };
+use tracing::{debug, warn};
 use url::Url;
 
 impl<T> FromStr for ObjectId<T>
@@ -28,7 +52,9 @@ where
 /// Every time an object is fetched via HTTP, [RequestData.request_counter] is incremented by one.
 /// If the value exceeds [FederationSettings.http_fetch_limit], the request is aborted with
 /// [Error::RequestLimit]. This prevents denial of service attacks where an attack triggers
-/// infinite, recursive fetching of data.
+/// infinite, recursive fetching of data. If an object dereferences its own id from
+/// [Object::verify] or [Object::from_json], the fetch is aborted immediately with
+/// [Error::SelfReferentialFetch].
 ///
 /// ```
 /// # use activitypub_federation::fetch::object_id::ObjectId;
@@ -54,7 +80,10 @@ where
 /// ```
 #[derive(Serialize, Deserialize)]
 #[serde(transparent)]
-pub struct ObjectId<Kind>(Box<Url>, PhantomData<Kind>)
+pub struct ObjectId<Kind>(
+    #[serde(deserialize_with = "deserialize_normalized")] Box<Url>,
+    PhantomData<Kind>,
+)
 where
     Kind: Object,
     for<'de2> <Kind as Object>::Kind: Deserialize<'de2>;
@@ -66,7 +95,7 @@ where
 {
     /// Construct a new objectid instance
     pub fn parse(url: &str) -> Result<Self, url::ParseError> {
-        Ok(Self(Box::new(Url::parse(url)?), PhantomData::<Kind>))
+        Ok(Url::parse(url)?.into())
     }
 
     /// Returns a reference to the wrapped URL value
@@ -80,6 +109,21 @@ where
     }
 
     /// Fetches an activitypub object, either from local database (if possible), or over http.
+    ///
+    /// Remote objects which were last refreshed more than a day ago are fetched again, unless
+    /// [FederationConfigBuilder::disable_automatic_refetch](crate::config::FederationConfigBuilder::disable_automatic_refetch)
+    /// is set. If the stored object has an [Object::cached_etag] or [Object::cached_last_modified],
+    /// the refetch is a conditional request, and [Object::mark_refreshed] is called if the object
+    /// was not modified.
+    ///
+    /// With [FederationConfigBuilder::background_object_refresh](crate::config::FederationConfigBuilder::background_object_refresh),
+    /// outdated objects are returned immediately and refetched in the background instead.
+    ///
+    /// Each object is fetched over HTTP at most once per [Data]. Dereferencing the same url again
+    /// converts the object from the previous response, see [Data::was_fetched]. Only the last
+    /// 100 responses are kept. Objects which are
+    /// embedded in the received activity are converted from the embedded json instead, if they
+    /// were added with [Data::add_prefetched].
     pub async fn dereference(
         &self,
         data: &Data<<Kind as Object>::DataType>,
@@ -87,27 +131,92 @@ where
     where
         <Kind as Object>::Error: From<Error>,
     {
+        let refresh = !data.config.disable_automatic_refetch;
+        if refresh && data.config.background_object_refresh {
+            let object = self.dereference_with_refresh(data, false).await?;
+            if self.is_outdated(&object, data) {
+                self.refresh_in_background(data);
+            }
+            return Ok(object);
+        }
+        self.dereference_with_refresh(data, refresh).await
+    }
+
+    /// Same as [ObjectId::dereference], but never refetches objects which are already stored in
+    /// the local database, regardless of how old they are.
+    pub async fn dereference_no_refresh(
+        &self,
+        data: &Data<<Kind as Object>::DataType>,
+    ) -> Result<Kind, <Kind as Object>::Error>
+    where
+        <Kind as Object>::Error: From<Error>,
+    {
+        self.dereference_with_refresh(data, false).await
+    }
+
+    async fn dereference_with_refresh(
+        &self,
+        data: &Data<<Kind as Object>::DataType>,
+        refresh: bool,
+    ) -> Result<Kind, <Kind as Object>::Error>
+    where
+        <Kind as Object>::Error: From<Error>,
+    {
+        if let Some(object) = self.dereference_prefetched(data).await? {
+            return Ok(object);
+        }
         let db_object = self.dereference_from_db(data).await?;
 
         // object found in database
         if let Some(object) = db_object {
-            if let Some(last_refreshed_at) = object.last_refreshed_at() {
-                let is_local = self.is_local(data);
-                if !is_local && should_refetch_object(last_refreshed_at) {
-                    // object is outdated and should be refetched
-                    return self.dereference_from_http(data, Some(object)).await;
-                }
+            if refresh && self.is_outdated(&object, data) {
+                // object is outdated and should be refetched
+                return self.dereference_from_http(data, Some(object), false).await;
             }
             Ok(object)
         }
         // object not found, need to fetch over http
         else {
-            self.dereference_from_http(data, None).await
+            self.dereference_from_http(data, None, false).await
         }
     }
 
+    /// Returns true if this is a remote object which was last refreshed too long ago, see
+    /// [should_refetch_object]
+    fn is_outdated(&self, object: &Kind, data: &Data<<Kind as Object>::DataType>) -> bool {
+        object.last_refreshed_at().is_some_and(|last_refreshed_at| {
+            !self.is_local(data) && should_refetch_object::<Kind>(last_refreshed_at, data)
+        })
+    }
+
+    /// Refetches the object with [ObjectId::dereference_forced] on the [BackgroundRefresher]. It
+    /// uses a new [Data], so that the refresh has its own request counter.
+    fn refresh_in_background(&self, data: &Data<<Kind as Object>::DataType>)
+    where
+        <Kind as Object>::Error: From<Error>,
+    {
+        let id = self.clone();
+        let data = data.config.to_request_data();
+        let refresher = data.config.background_refresher.clone();
+        refresher.spawn(
+            self.inner().clone(),
+            Box::new(move || {
+                Box::pin(async move {
+                    if id.dereference_forced(&data).await.is_err() {
+                        debug!("Failed to refresh {id} in background");
+                    }
+                })
+            }),
+        );
+    }
+
     /// If this is a remote object, fetch it from origin instance unconditionally to get the
-    /// latest version, regardless of refresh interval.
+    /// latest version, regardless of refresh interval. A response which was already fetched with
+    /// the same [Data] is not reused.
+    ///
+    /// Local objects are never fetched over HTTP. They are read from the local database instead,
+    /// returning [Error::NotFound] if they don't exist. Use [ObjectId::dereference_http_unchecked]
+    /// to fetch a local object over HTTP.
     pub async fn dereference_forced(
         &self,
         data: &Data<<Kind as Object>::DataType>,
@@ -121,10 +230,26 @@ where
                 .map(|o| o.ok_or(Error::NotFound.into()))?
         } else {
             // Don't pass in any db object, otherwise it would be returned in case http fetch fails
-            self.dereference_from_http(data, None).await
+            self.dereference_from_http(data, None, true).await
         }
     }
 
+    /// Fetch the object over HTTP even if it is local, and return the json without converting
+    /// it with [Object::from_json].
+    ///
+    /// This is meant as a tool for diagnostics, for example to check that the local endpoints
+    /// serve objects correctly, or for clusters where the object is served by a different node.
+    /// Content type and id of the response are checked like for remote objects, but there is no
+    /// [Object::verify] call. The local database is not read or modified, so for normal use
+    /// [ObjectId::dereference] is always preferable.
+    pub async fn dereference_http_unchecked(
+        &self,
+        data: &Data<<Kind as Object>::DataType>,
+    ) -> Result<<Kind as Object>::Kind, Error> {
+        let res = fetch_object_http_with_kind(&self.0, data, RequestKind::Object, true).await?;
+        Ok(res.object)
+    }
+
     /// Fetch an object from the local db. Instead of falling back to http, this throws an error if
     /// the object is not found in the database.
     pub async fn dereference_local(
@@ -147,28 +272,98 @@ where
         Object::read_from_id(*id, data).await
     }
 
-    /// Fetch object from origin instance over HTTP, then verify and parse it.
-    ///
+    /// Verify and parse the object from json which is embedded in the received activity, see
+    /// [Data::add_prefetched]. Returns none if no json was added for this id.
+    async fn dereference_prefetched(
+        &self,
+        data: &Data<<Kind as Object>::DataType>,
+    ) -> Result<Option<Kind>, <Kind as Object>::Error>
+    where
+        <Kind as Object>::Error: From<Error>,
+    {
+        let Some(json) = data.prefetched_objects.take(&self.0) else {
+            return Ok(None);
+        };
+        let object = JsonError::parse(&json).map_err(|e| {
+            let text = String::from_utf8_lossy(&json).into_owned();
+            Error::ParseFetchedObject(Box::new(e), self.inner().clone(), text)
+        })?;
+        Box::pin(Kind::verify(&object, &self.0, data)).await?;
+        Box::pin(Kind::from_json(object, data)).await.map(Some)
+    }
+
+    /// Fetch object from origin instance over HTTP, then verify and parse it. Fails with
+    /// [Error::SelfReferentialFetch] if this is called while parsing the same object. With
+    /// `forced`, a response which was already fetched with the same data is not reused.
+    async fn dereference_from_http(
+        &self,
+        data: &Data<<Kind as Object>::DataType>,
+        db_object: Option<Kind>,
+        forced: bool,
+    ) -> Result<Kind, <Kind as Object>::Error>
+    where
+        <Kind as Object>::Error: From<Error>,
+    {
+        if FETCHING
+            .try_with(|url| url == self.inner())
+            .unwrap_or(false)
+        {
+            warn_self_referential_fetch::<Kind>(&self.0);
+            return Err(Error::SelfReferentialFetch(self.inner().clone()).into());
+        }
+        // Set until the object is parsed, so that nested fetches of the same url are detected
+        FETCHING
+            .scope(
+                self.inner().clone(),
+                Box::pin(self.fetch_and_parse(data, db_object, forced)),
+            )
+            .await
+    }
+
     /// Uses Box::pin to wrap futures to reduce stack size and avoid stack overflow when
     /// when fetching objects recursively.
-    async fn dereference_from_http(
+    async fn fetch_and_parse(
         &self,
         data: &Data<<Kind as Object>::DataType>,
         db_object: Option<Kind>,
+        forced: bool,
     ) -> Result<Kind, <Kind as Object>::Error>
     where
         <Kind as Object>::Error: From<Error>,
     {
-        let res = Box::pin(fetch_object_http(&self.0, data)).await;
+        // Reuse the response if the object was already fetched with the same data
+        let stored = (!forced)
+            .then(|| data.fetched_objects.get(&self.0))
+            .flatten();
+        let fetched = match stored {
+            Some(res) => Ok(Fetched::Object(Box::new(res))),
+            None => Box::pin(self.fetch_unparsed(data, db_object.as_ref()))
+                .await
+                .inspect(|res| {
+                    if let Fetched::Object(res) = res {
+                        data.fetched_objects
+                            .insert(self.inner().clone(), *res.clone())
+                    }
+                }),
+        };
+        // Conditional requests are only sent for a stored object, which is still current
+        if matches!(fetched, Ok(Fetched::NotModified)) {
+            if let Some(db_object) = db_object {
+                return db_object.mark_refreshed(data).await;
+            }
+        }
+        let res = fetched
+            .and_then(|fetched| fetched.into_object(&self.0))
+            .and_then(FetchObjectResponse::parse);
 
-        if let Err(Error::ObjectDeleted(url)) = res {
+        if let Err(Error::ObjectDeleted(url, tombstone)) = res {
             if let Some(db_object) = db_object {
                 db_object.delete(data).await?;
             }
-            return Err(Error::ObjectDeleted(url).into());
+            return Err(Error::ObjectDeleted(url, tombstone).into());
         }
 
-        // If fetch failed, return the existing object from local database
+        // If the fetch failed, return the existing object from local database
         if let (Err(_), Some(db_object)) = (&res, db_object) {
             return Ok(db_object);
         }
@@ -187,12 +382,174 @@ where
         Box::pin(Kind::from_json(res.object, data)).await
     }
 
+    /// Fetches the object without parsing it. If the stored object has an etag or last modified
+    /// time, a conditional request is sent which returns [Fetched::NotModified] if the stored
+    /// object is still current.
+    async fn fetch_unparsed(
+        &self,
+        data: &Data<<Kind as Object>::DataType>,
+        db_object: Option<&Kind>,
+    ) -> Result<Fetched<()>, Error> {
+        let etag = db_object.and_then(Object::cached_etag);
+        let last_modified = db_object.and_then(Object::cached_last_modified);
+        // Concurrent fetches of the same url share a single request
+        data.config
+            .in_flight_fetches
+            .fetch(&self.0, data, etag.as_deref(), last_modified.as_deref())
+            .await
+    }
+
     /// Returns true if the object's domain matches the one defined in [[FederationConfig.domain]].
     pub fn is_local(&self, data: &Data<<Kind as Object>::DataType>) -> bool {
         data.config.is_local_url(&self.0)
     }
 }
 
+tokio::task_local! {
+    /// Url of the object which is dereferenced over HTTP by the enclosing call. Each dereference
+    /// sets it only for its own future, so that independent dereferences of the same url which
+    /// run at the same time don't affect each other.
+    static FETCHING: Url;
+}
+
+/// Creates the future which refreshes an object, run by [BackgroundRefresher]
+type RefreshTask = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// Maximum number of background refreshes which run at the same time
+const MAX_CONCURRENT_REFRESHES: usize = 4;
+
+/// Maximum number of background refreshes which wait for a free slot. Further refreshes are
+/// skipped, and the object is refreshed by a later dereference instead.
+const MAX_QUEUED_REFRESHES: usize = 100;
+
+/// Worker for [FederationConfigBuilder::background_object_refresh](crate::config::FederationConfigBuilder::background_object_refresh).
+///
+/// Refreshes run on the tokio runtime of the caller, at most [MAX_CONCURRENT_REFRESHES] at the
+/// same time. The futures of [Object] methods don't need to be `Send`, so each refresh is driven
+/// by a thread of the blocking pool. The worker is started for the first refresh, and stopped by
+/// [FederationConfig::shutdown](crate::config::FederationConfig::shutdown) which waits for the
+/// queued refreshes to finish.
+#[derive(Default)]
+pub(crate) struct BackgroundRefresher {
+    worker: Mutex<RefreshWorker>,
+    /// Urls of objects which are currently refreshed, so that only one refresh per object runs
+    /// at the same time
+    running: Arc<Mutex<HashSet<Url>>>,
+}
+
+/// Channel and task of the worker, which are created for the first refresh
+#[derive(Default)]
+struct RefreshWorker {
+    sender: Option<Sender<RefreshTask>>,
+    handle: Option<JoinHandle<()>>,
+    stopped: bool,
+}
+
+impl BackgroundRefresher {
+    /// Runs the refresh of the object with the given url, unless it is already being refreshed
+    fn spawn(&self, url: Url, task: RefreshTask) {
+        let first_refresh = self
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(url.clone());
+        if !first_refresh {
+            return;
+        }
+        let guard = RefreshGuard {
+            running: self.running.clone(),
+            url: url.clone(),
+        };
+        let task: RefreshTask = Box::new(move || {
+            Box::pin(async move {
+                // Removes the url also if the refresh panics
+                let _guard = guard;
+                task().await;
+            })
+        });
+        let mut worker = self.worker.lock().unwrap_or_else(PoisonError::into_inner);
+        if worker.stopped {
+            return;
+        }
+        let sender = match &worker.sender {
+            Some(sender) => sender.clone(),
+            None => {
+                let (sender, receiver) = channel(MAX_QUEUED_REFRESHES);
+                worker.sender = Some(sender.clone());
+                worker.handle = Some(tokio::spawn(refresh_worker(receiver)));
+                sender
+            }
+        };
+        drop(worker);
+        if sender.try_send(task).is_err() {
+            debug!("Too many background refreshes, skipping {url}");
+        }
+    }
+
+    /// Stops the worker, and waits until the refreshes which are already queued are finished
+    pub(crate) async fn shutdown(&self) {
+        let handle = {
+            let mut worker = self.worker.lock().unwrap_or_else(PoisonError::into_inner);
+            worker.stopped = true;
+            // Closes the channel, so that the worker stops after the queued refreshes
+            worker.sender = None;
+            worker.handle.take()
+        };
+        if let Some(handle) = handle {
+            handle.await.ok();
+        }
+    }
+}
+
+/// Runs the refreshes from the channel until it is closed, and then waits for them to finish
+async fn refresh_worker(mut receiver: Receiver<RefreshTask>) {
+    let runtime = Handle::current();
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REFRESHES));
+    let mut join_set = JoinSet::new();
+    while let Some(task) = receiver.recv().await {
+        let Ok(permit) = semaphore.clone().acquire_owned().await else {
+            break;
+        };
+        let runtime = runtime.clone();
+        join_set.spawn_blocking(move || {
+            runtime.block_on(task());
+            drop(permit);
+        });
+        // Remove finished refreshes, so that the set doesn't grow
+        while join_set.try_join_next().is_some() {}
+    }
+    while join_set.join_next().await.is_some() {}
+}
+
+/// Removes the url from [BackgroundRefresher::running] once the refresh is finished
+struct RefreshGuard {
+    running: Arc<Mutex<HashSet<Url>>>,
+    url: Url,
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        self.running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.url);
+    }
+}
+
+/// Log a warning when an object of type `Kind` dereferences itself. Only logged the first time for
+/// each type to avoid spamming logs.
+fn warn_self_referential_fetch<Kind>(url: &Url) {
+    static WARNED: Lazy<Mutex<HashSet<&'static str>>> = Lazy::new(Default::default);
+    let kind = type_name::<Kind>();
+    let mut warned = WARNED.lock().unwrap_or_else(PoisonError::into_inner);
+    if warned.insert(kind) {
+        warn!(
+            "{kind} dereferenced its own id {url} while being dereferenced. Object::verify and \
+            Object::from_json should use the received json instead of fetching it again."
+        );
+    }
+}
+
 /// Need to implement clone manually, to avoid requiring Kind to be Clone
 impl<Kind> Clone for ObjectId<Kind>
 where
@@ -207,18 +564,30 @@ where
 static ACTOR_REFETCH_INTERVAL_SECONDS: i64 = 24 * 60 * 60;
 static ACTOR_REFETCH_INTERVAL_SECONDS_DEBUG: i64 = 20;
 
-/// Determines when a remote actor should be refetched from its instance. In release builds, this is
-/// `ACTOR_REFETCH_INTERVAL_SECONDS` after the last refetch, in debug builds
-/// `ACTOR_REFETCH_INTERVAL_SECONDS_DEBUG`.
-fn should_refetch_object(last_refreshed: DateTime<Utc>) -> bool {
-    let update_interval = if cfg!(debug_assertions) {
+/// Determines when a remote object should be refetched from its instance. This is the
+/// [Object::refetch_interval] of `Kind` after the last refetch, or otherwise the
+/// [FederationConfigBuilder::object_refetch_interval](crate::config::FederationConfigBuilder::object_refetch_interval).
+/// If neither is set, it is `ACTOR_REFETCH_INTERVAL_SECONDS` in release builds and
+/// `ACTOR_REFETCH_INTERVAL_SECONDS_DEBUG` in debug builds.
+fn should_refetch_object<Kind: Object>(
+    last_refreshed: DateTime<Utc>,
+    data: &Data<Kind::DataType>,
+) -> bool {
+    let update_interval = match Kind::refetch_interval().or(data.config.object_refetch_interval) {
+        Some(interval) => ChronoDuration::from_std(interval).unwrap_or(ChronoDuration::MAX),
         // avoid infinite loop when fetching community outbox
-        ChronoDuration::try_seconds(ACTOR_REFETCH_INTERVAL_SECONDS_DEBUG).expect("valid duration")
-    } else {
-        ChronoDuration::try_seconds(ACTOR_REFETCH_INTERVAL_SECONDS).expect("valid duration")
+        None if cfg!(debug_assertions) => {
+            ChronoDuration::try_seconds(ACTOR_REFETCH_INTERVAL_SECONDS_DEBUG)
+                .expect("valid duration")
+        }
+        None => {
+            ChronoDuration::try_seconds(ACTOR_REFETCH_INTERVAL_SECONDS).expect("valid duration")
+        }
     };
-    let refresh_limit = Utc::now() - update_interval;
-    last_refreshed.lt(&refresh_limit)
+    // Intervals which reach before the earliest representable time never expire
+    Utc::now()
+        .checked_sub_signed(update_interval)
+        .is_some_and(|refresh_limit| last_refreshed.lt(&refresh_limit))
 }
 
 impl<Kind> Display for ObjectId<Kind>
@@ -257,7 +626,7 @@ where
     for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
 {
     fn from(url: Url) -> Self {
-        ObjectId(Box::new(url), PhantomData::<Kind>)
+        ObjectId(Box::new(normalize(url)), PhantomData::<Kind>)
     }
 }
 
@@ -271,8 +640,67 @@ where
     }
 }
 
+impl<Kind> Eq for ObjectId<Kind>
+where
+    Kind: Object,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+{
+}
+
+/// Only hashes the url, so that it is consistent with the [Borrow] impl
+impl<Kind> Hash for ObjectId<Kind>
+where
+    Kind: Object,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<Kind> PartialOrd for ObjectId<Kind>
+where
+    Kind: Object,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Kind> Ord for ObjectId<Kind>
+where
+    Kind: Object,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// Allows looking up ids in maps and sets with a plain [Url]
+impl<Kind> Borrow<Url> for ObjectId<Kind>
+where
+    Kind: Object,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+{
+    fn borrow(&self) -> &Url {
+        &self.0
+    }
+}
+
+impl<Kind> AsRef<Url> for ObjectId<Kind>
+where
+    Kind: Object,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+{
+    fn as_ref(&self) -> &Url {
+        &self.0
+    }
+}
+
 #[cfg(feature = "diesel")]
-const _IMPL_DIESEL_NEW_TYPE_FOR_OBJECT_ID: () = {
+const _: () = {
     use diesel::{
         backend::Backend,
         deserialize::{FromSql, FromStaticSqlRow},
@@ -360,12 +788,632 @@ const _IMPL_DIESEL_NEW_TYPE_FOR_OBJECT_ID: () = {
     }
 };
 
+#[cfg(feature = "sqlx")]
+const _: () = {
+    use sqlx::{
+        encode::IsNull,
+        error::BoxDynError,
+        postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+        Decode,
+        Encode,
+        Postgres,
+        Type,
+    };
+
+    impl<Kind> Type<Postgres> for ObjectId<Kind>
+    where
+        Kind: Object,
+        for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+    {
+        fn type_info() -> PgTypeInfo {
+            <String as Type<Postgres>>::type_info()
+        }
+
+        fn compatible(ty: &PgTypeInfo) -> bool {
+            <String as Type<Postgres>>::compatible(ty)
+        }
+    }
+    impl<Kind> Encode<'_, Postgres> for ObjectId<Kind>
+    where
+        Kind: Object,
+        for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+    {
+        fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+            <&str as Encode<Postgres>>::encode(self.0.as_str(), buf)
+        }
+    }
+    impl<'r, Kind> Decode<'r, Postgres> for ObjectId<Kind>
+    where
+        Kind: Object + Send + 'static,
+        for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+    {
+        fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+            let string = <&str as Decode<Postgres>>::decode(value)?;
+            Ok(ObjectId::parse(string)?)
+        }
+    }
+};
+
 /// Internal only
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 pub mod tests {
     use super::*;
-    use crate::traits::tests::DbUser;
+    use crate::{
+        config::{FederationConfig, FetchedObjects, MAX_FETCHED_OBJECTS},
+        protocol::verification::verify_domains_match,
+        traits::tests::{debug_data, serve_counting, serve_router, DbConnection, DbUser},
+        FEDERATION_CONTENT_TYPE,
+    };
+    use activitystreams_kinds::object::NoteType;
+    use async_trait::async_trait;
+    use axum::{
+        extract::Path,
+        http::{header, HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    #[derive(Debug)]
+    struct Note;
+
+    #[derive(Deserialize)]
+    struct NoteJson {
+        parent: Option<Url>,
+    }
+
+    #[async_trait]
+    impl Object for Note {
+        type DataType = DbConnection;
+        type Kind = NoteJson;
+        type Error = Error;
+
+        async fn read_from_id(_: Url, _: &Data<Self::DataType>) -> Result<Option<Self>, Error> {
+            Ok(None)
+        }
+
+        async fn into_json(self, _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn verify(_: &Self::Kind, _: &Url, _: &Data<Self::DataType>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn from_json(json: Self::Kind, data: &Data<Self::DataType>) -> Result<Self, Error> {
+            if let Some(parent) = json.parent {
+                ObjectId::<Note>::from(parent).dereference(data).await?;
+            }
+            Ok(Note)
+        }
+    }
+
+    /// Serve notes on a random port. `/self` has itself as parent, `/child` and `/sibling` have
+    /// `/parent` as parent. `/deleted` returns a tombstone.
+    async fn serve() -> u16 {
+        let base = serve_router(|base| {
+            let base = base.clone();
+            let handler = move |Path(path): Path<String>| {
+                let parent = match path.as_str() {
+                    "self" => format!("\"{base}self\""),
+                    "child" | "sibling" => format!("\"{base}parent\""),
+                    _ => "null".to_string(),
+                };
+                let body = match path.as_str() {
+                    "deleted" => format!(
+                        r#"{{"id":"{base}{path}","type":"Tombstone","formerType":"Note","deleted":"2024-01-02T03:04:05Z"}}"#
+                    ),
+                    _ => format!(r#"{{"id":"{base}{path}","parent":{parent}}}"#),
+                };
+                async move { ([(header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE)], body) }
+            };
+            Router::new().route("/*path", get(handler))
+        })
+        .await;
+        base.port().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_self_referential_fetch() -> Result<(), Error> {
+        let port = serve().await;
+        let data = debug_data().await;
+        let url = Url::parse(&format!("http://localhost:{port}/self"))?;
+        let res = ObjectId::<Note>::from(url.clone()).dereference(&data).await;
+        let err = res.unwrap_err();
+        assert_eq!(Error::SelfReferentialFetch(url.clone()), err);
+        assert!(err.to_string().contains(url.as_str()));
+        assert_eq!(1, data.request_count());
+
+        // Independent dereferences of the same url at the same time are not affected
+        let (port, _) = serve_counting(Duration::from_millis(200)).await;
+        let id = ObjectId::<Note>::parse(&format!("http://localhost:{port}/note"))?;
+        let (first, second) = futures::join!(id.dereference(&data), id.dereference(&data));
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+        Ok(())
+    }
+
+    /// Note which is stored in the database, but was last refreshed two days ago
+    #[derive(Debug)]
+    struct StaleNote(bool);
+
+    #[async_trait]
+    impl Object for StaleNote {
+        type DataType = DbConnection;
+        type Kind = NoteJson;
+        type Error = Error;
+
+        fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
+            Some(Utc::now() - ChronoDuration::try_days(2).unwrap())
+        }
+
+        async fn read_from_id(_: Url, _: &Data<Self::DataType>) -> Result<Option<Self>, Error> {
+            Ok(Some(StaleNote(false)))
+        }
+
+        async fn into_json(self, _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn verify(_: &Self::Kind, _: &Url, _: &Data<Self::DataType>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn from_json(_: Self::Kind, _: &Data<Self::DataType>) -> Result<Self, Error> {
+            Ok(StaleNote(true))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disable_automatic_refetch() -> Result<(), Error> {
+        let port = serve().await;
+        let id = ObjectId::<StaleNote>::parse(&format!("http://localhost:{port}/parent"))?;
+
+        let request_data = debug_data().await;
+        assert!(id.dereference(&request_data).await?.0);
+        assert_eq!(1, request_data.request_count());
+
+        let request_data = debug_data().await;
+        assert!(!id.dereference_no_refresh(&request_data).await?.0);
+        assert_eq!(0, request_data.request_count());
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .disable_automatic_refetch(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        assert!(!id.dereference(&data).await?.0);
+        assert_eq!(0, data.request_count());
+        assert!(id.dereference_forced(&data).await?.0);
+        assert_eq!(1, data.request_count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dereference_local() -> Result<(), Error> {
+        let port = serve().await;
+        let data = FederationConfig::builder()
+            .domain(format!("localhost:{port}"))
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let id = ObjectId::<Note>::parse(&format!("http://localhost:{port}/parent"))?;
+
+        // Not found in database, and not fetched over HTTP
+        assert_eq!(
+            Some(Error::NotFound),
+            id.dereference_forced(&data).await.err()
+        );
+        assert_eq!(0, data.request_count());
+
+        let json = id.dereference_http_unchecked(&data).await?;
+        assert!(json.parent.is_none());
+        assert_eq!(1, data.request_count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested_fetch() -> Result<(), Error> {
+        let port = serve().await;
+        let data = debug_data().await;
+        let url = Url::parse(&format!("http://localhost:{port}/child"))?;
+        ObjectId::<Note>::from(url).dereference(&data).await?;
+        assert_eq!(2, data.request_count());
+
+        // Same url can be dereferenced again once the previous fetch is finished, the previous
+        // response is reused for this
+        let url = Url::parse(&format!("http://localhost:{port}/parent"))?;
+        ObjectId::<Note>::from(url.clone())
+            .dereference(&data)
+            .await?;
+        ObjectId::<Note>::from(url).dereference(&data).await?;
+        assert_eq!(2, data.request_count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_once_per_request() -> Result<(), Error> {
+        let port = serve().await;
+        let data = debug_data().await;
+        let parent = Url::parse(&format!("http://localhost:{port}/parent"))?;
+        assert!(!data.was_fetched(&parent));
+
+        // Both notes reply to the same parent, which is only fetched once
+        let child = ObjectId::<Note>::parse(&format!("http://localhost:{port}/child"))?;
+        let sibling = ObjectId::<Note>::parse(&format!("http://localhost:{port}/sibling"))?;
+        child.dereference(&data).await?;
+        sibling.dereference(&data).await?;
+        assert_eq!(3, data.request_count());
+        assert!(data.was_fetched(&parent));
+
+        // Forced dereference doesn't use the previous response
+        ObjectId::<Note>::from(parent.clone())
+            .dereference_forced(&data)
+            .await?;
+        assert_eq!(4, data.request_count());
+
+        // New request data fetches the object again
+        let data = data.reset_request_count();
+        assert!(!data.was_fetched(&parent));
+        ObjectId::<Note>::from(parent).dereference(&data).await?;
+        assert_eq!(1, data.request_count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetched_objects_limit() -> Result<(), Error> {
+        let fetched = FetchedObjects::default();
+        let url = |i| Url::parse(&format!("https://example.com/note/{i}"));
+        for i in 0..=MAX_FETCHED_OBJECTS {
+            let response = FetchObjectResponse {
+                object: (),
+                url: url(i)?,
+                headers: Default::default(),
+                body: Default::default(),
+                content_type: None,
+                object_id: None,
+            };
+            fetched.insert(url(i)?, response);
+        }
+        // The response which was stored first is removed
+        assert!(fetched.get(&url(0)?).is_none());
+        assert!(fetched.get(&url(1)?).is_some());
+        assert!(fetched.get(&url(MAX_FETCHED_OBJECTS)?).is_some());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_coalesce_concurrent_fetches() -> Result<(), Error> {
+        let (port, requests) = serve_counting(Duration::from_millis(200)).await;
+        let config = debug_data().await.config;
+        let id = ObjectId::<Note>::parse(&format!("http://localhost:{port}/note"))?;
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let data = config.to_request_data();
+                let id = id.clone();
+                tokio::spawn(async move { id.dereference_forced(&data).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap()?;
+        }
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        // The entry is removed once the fetch is finished, so later dereferences fetch again
+        id.dereference_forced(&config.to_request_data()).await?;
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_fetch_deadline() -> Result<(), Error> {
+        let (port, requests) = serve_counting(Duration::from_millis(200)).await;
+        let config = debug_data().await.config;
+        let id = ObjectId::<Note>::parse(&format!("http://localhost:{port}/note"))?;
+
+        // The deadline of the first data passes while waiting for the response
+        let mut first = config.to_request_data();
+        first.deadline = Some(std::time::Instant::now() + Duration::from_millis(50));
+        let second = config.to_request_data();
+        let (first_res, second_res) = futures::join!(id.dereference_forced(&first), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            id.dereference_forced(&second).await
+        });
+        assert_eq!(Some(Error::RequestDeadlineExceeded), first_res.err());
+        assert!(second_res.is_ok());
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_background_object_refresh() -> Result<(), Error> {
+        static REFRESHED: AtomicUsize = AtomicUsize::new(0);
+
+        /// Stored note which needs to be refetched, and counts the refreshes
+        #[derive(Debug)]
+        struct RefreshedNote(bool);
+
+        #[async_trait]
+        impl Object for RefreshedNote {
+            type DataType = DbConnection;
+            type Kind = NoteJson;
+            type Error = Error;
+
+            fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
+                Some(Utc::now() - ChronoDuration::try_days(2).unwrap())
+            }
+
+            async fn read_from_id(_: Url, _: &Data<DbConnection>) -> Result<Option<Self>, Error> {
+                Ok(Some(RefreshedNote(false)))
+            }
+
+            async fn into_json(self, _: &Data<DbConnection>) -> Result<NoteJson, Error> {
+                Err(Error::NotFound)
+            }
+
+            async fn verify(_: &NoteJson, _: &Url, _: &Data<DbConnection>) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn from_json(_: NoteJson, _: &Data<DbConnection>) -> Result<Self, Error> {
+                REFRESHED.fetch_add(1, Ordering::SeqCst);
+                Ok(RefreshedNote(true))
+            }
+        }
+
+        let (port, requests) = serve_counting(Duration::from_millis(200)).await;
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .background_object_refresh(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let id = ObjectId::<RefreshedNote>::parse(&format!("http://localhost:{port}/note"))?;
+
+        // The stored object is returned without waiting for the slow response
+        let start = Instant::now();
+        assert!(!id.dereference(&data).await?.0);
+        assert!(!id.dereference(&data).await?.0);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(0, data.request_count());
+
+        // Shutdown waits for the refresh, and only one refresh runs at the same time
+        data.config.shutdown(false, Duration::from_secs(5)).await?;
+        assert_eq!(1, REFRESHED.load(Ordering::SeqCst));
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        // No refreshes are started after shutdown
+        assert!(!id.dereference(&data).await?.0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        // Without the option, the refresh blocks the dereference
+        let data = debug_data().await;
+        assert!(id.dereference(&data).await?.0);
+        assert_eq!(2, REFRESHED.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_tombstone() -> Result<(), Error> {
+        static DELETED: AtomicBool = AtomicBool::new(false);
+
+        /// Unlike [NoteJson], a tombstone can't be parsed as this type
+        #[derive(Deserialize)]
+        struct StoredNoteJson {
+            #[serde(rename = "type")]
+            _kind: NoteType,
+        }
+
+        /// Stored note which needs to be refetched
+        #[derive(Debug)]
+        struct StoredNote;
+
+        #[async_trait]
+        impl Object for StoredNote {
+            type DataType = DbConnection;
+            type Kind = StoredNoteJson;
+            type Error = Error;
+
+            fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
+                Some(Utc::now() - ChronoDuration::try_days(2).unwrap())
+            }
+
+            async fn read_from_id(_: Url, _: &Data<DbConnection>) -> Result<Option<Self>, Error> {
+                Ok(Some(StoredNote))
+            }
+
+            async fn delete(self, _: &Data<DbConnection>) -> Result<(), Error> {
+                DELETED.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+
+            async fn into_json(self, _: &Data<DbConnection>) -> Result<StoredNoteJson, Error> {
+                Err(Error::NotFound)
+            }
+
+            async fn verify(
+                _: &StoredNoteJson,
+                _: &Url,
+                _: &Data<DbConnection>,
+            ) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn from_json(_: StoredNoteJson, _: &Data<DbConnection>) -> Result<Self, Error> {
+                Ok(StoredNote)
+            }
+        }
+
+        let port = serve().await;
+        let data = debug_data().await;
+        let url = Url::parse(&format!("http://localhost:{port}/deleted"))?;
+        let err = ObjectId::<StoredNote>::from(url.clone())
+            .dereference(&data)
+            .await
+            .unwrap_err();
+        let Error::ObjectDeleted(deleted_url, Some(tombstone)) = err else {
+            panic!("expected tombstone, got {err}");
+        };
+        assert_eq!(url, deleted_url);
+        assert_eq!(url, tombstone.id);
+        assert_eq!(Some("Note"), tombstone.former_type.as_deref());
+        assert!(tombstone.deleted.is_some());
+        assert!(DELETED.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dereference_not_modified() -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct CachedNoteJson {
+            id: Url,
+        }
+
+        /// Stored note with an etag, which needs to be refetched
+        #[derive(Debug)]
+        struct CachedNote {
+            etag: Option<String>,
+            refreshed: bool,
+        }
+
+        #[async_trait]
+        impl Object for CachedNote {
+            type DataType = DbConnection;
+            type Kind = CachedNoteJson;
+            type Error = Error;
+
+            fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
+                Some(Utc::now() - ChronoDuration::try_days(2).unwrap())
+            }
+
+            fn cached_etag(&self) -> Option<String> {
+                self.etag.clone()
+            }
+
+            async fn mark_refreshed(self, _: &Data<DbConnection>) -> Result<Self, Error> {
+                Ok(CachedNote {
+                    refreshed: true,
+                    ..self
+                })
+            }
+
+            async fn read_from_id(url: Url, _: &Data<DbConnection>) -> Result<Option<Self>, Error> {
+                let etag = match url.path() {
+                    "/current" => "\"v1\"",
+                    _ => "\"v0\"",
+                };
+                Ok(Some(CachedNote {
+                    etag: Some(etag.to_string()),
+                    refreshed: false,
+                }))
+            }
+
+            async fn into_json(self, _: &Data<DbConnection>) -> Result<CachedNoteJson, Error> {
+                Err(Error::NotFound)
+            }
+
+            async fn verify(
+                _: &CachedNoteJson,
+                _: &Url,
+                _: &Data<DbConnection>,
+            ) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn from_json(
+                json: CachedNoteJson,
+                data: &Data<DbConnection>,
+            ) -> Result<Self, Error> {
+                let res = data.fetched_response(&json.id).unwrap();
+                Ok(CachedNote {
+                    etag: res.etag().map(ToString::to_string),
+                    refreshed: false,
+                })
+            }
+        }
+
+        // Responds with `304 Not Modified` to requests with the current etag, after a delay
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base = serve_router(|base| {
+            let base = base.clone();
+            let handler = move |Path(path): Path<String>, headers: HeaderMap| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let body = format!(r#"{{"id":"{base}{path}"}}"#);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    if headers
+                        .get(header::IF_NONE_MATCH)
+                        .is_some_and(|e| e == "\"v1\"")
+                    {
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    let headers = [
+                        (header::CONTENT_TYPE, FEDERATION_CONTENT_TYPE),
+                        (header::ETAG, "\"v1\""),
+                    ];
+                    (headers, body).into_response()
+                }
+            };
+            Router::new().route("/*path", get(handler))
+        })
+        .await;
+        let port = base.port().unwrap();
+
+        // The stored object is kept without parsing the response
+        let data = debug_data().await;
+        let id = ObjectId::<CachedNote>::parse(&format!("http://localhost:{port}/current"))?;
+        let note = id.dereference(&data).await?;
+        assert!(note.refreshed);
+        assert_eq!(Some("\"v1\""), note.etag.as_deref());
+        assert_eq!(1, data.request_count());
+        assert!(!data.was_fetched(id.inner()));
+
+        // Outdated objects are replaced, with the new etag
+        let id = ObjectId::<CachedNote>::parse(&format!("http://localhost:{port}/outdated"))?;
+        let note = id.dereference(&data).await?;
+        assert!(!note.refreshed);
+        assert_eq!(Some("\"v1\""), note.etag.as_deref());
+        assert_eq!(2, data.request_count());
+
+        // Concurrent conditional refetches share a single request
+        let id = ObjectId::<CachedNote>::parse(&format!("http://localhost:{port}/current"))?;
+        requests.store(0, Ordering::SeqCst);
+        let config = data.config.clone();
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let data = config.to_request_data();
+                let id = id.clone();
+                tokio::spawn(async move { id.dereference(&data).await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap()?.refreshed);
+        }
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+        Ok(())
+    }
 
     #[test]
     fn test_deserialize() {
@@ -379,11 +1427,296 @@ pub mod tests {
     }
 
     #[test]
-    fn test_should_refetch_object() {
+    fn test_normalize_id() {
+        let id = ObjectId::<DbUser>::parse("https://example.com/users/Foo?Tab=1").unwrap();
+        let spellings = [
+            "https://Example.COM/users/Foo?Tab=1",
+            "https://example.com:443/users/Foo?Tab=1",
+            "https://example.com/users/Foo?Tab=1#main-key",
+        ];
+        for spelling in spellings {
+            assert_eq!(ObjectId::<DbUser>::parse(spelling).unwrap(), id);
+            let url = Url::parse(spelling).unwrap();
+            assert_eq!(ObjectId::<DbUser>::from(url), id);
+            let json = format!("\"{spelling}\"");
+            let parsed: ObjectId<DbUser> = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, id);
+        }
+
+        // Path and query are case sensitive
+        let other = ObjectId::<DbUser>::parse("https://example.com/users/foo?tab=1").unwrap();
+        assert_ne!(other, id);
+        // Only the default port is removed
+        let http = ObjectId::<DbUser>::parse("http://example.com:80/users/Foo").unwrap();
+        assert_eq!(http.inner().as_str(), "http://example.com/users/Foo");
+        let port = ObjectId::<DbUser>::parse("https://example.com:8443/users/Foo").unwrap();
+        assert_eq!(port.inner().port(), Some(8443));
+    }
+
+    /// Note which is refetched after a week, regardless of the config
+    #[derive(Debug)]
+    struct WeeklyNote;
+
+    #[async_trait]
+    impl Object for WeeklyNote {
+        type DataType = DbConnection;
+        type Kind = NoteJson;
+        type Error = Error;
+
+        fn refetch_interval() -> Option<Duration> {
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        }
+
+        async fn read_from_id(_: Url, _: &Data<Self::DataType>) -> Result<Option<Self>, Error> {
+            Ok(None)
+        }
+
+        async fn into_json(self, _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn verify(_: &Self::Kind, _: &Url, _: &Data<Self::DataType>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn from_json(_: Self::Kind, _: &Data<Self::DataType>) -> Result<Self, Error> {
+            Ok(WeeklyNote)
+        }
+    }
+
+    #[cfg(feature = "diesel")]
+    #[test]
+    fn test_diesel_object_id() {
+        use diesel::{
+            deserialize::FromSql,
+            expression::AsExpression,
+            pg::Pg,
+            serialize::ToSql,
+            sql_types::Text,
+            Queryable,
+        };
+        fn assert_sql_type<T: ToSql<Text, Pg> + FromSql<Text, Pg> + AsExpression<Text>>() {}
+        assert_sql_type::<ObjectId<Note>>();
+
+        // Urls read from the database are normalized like deserialized ones
+        let id = <ObjectId<Note> as Queryable<Text, Pg>>::build(
+            "https://Example.com/u/alice#main-key".to_string(),
+        )
+        .unwrap();
+        assert_eq!("https://example.com/u/alice", id.inner().as_str());
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[test]
+    fn test_sqlx_object_id() {
+        use sqlx::{encode::IsNull, postgres::PgArgumentBuffer, Decode, Encode, Postgres, Type};
+        fn assert_sql_type<T>()
+        where
+            T: Type<Postgres> + for<'q> Encode<'q, Postgres> + for<'r> Decode<'r, Postgres>,
+        {
+        }
+        assert_sql_type::<ObjectId<Note>>();
+        assert!(<ObjectId<Note> as Type<Postgres>>::compatible(
+            &<String as Type<Postgres>>::type_info()
+        ));
+
+        // Stored as text in normalized form
+        let id = ObjectId::<Note>::parse("https://Example.com/u/alice#main-key").unwrap();
+        let mut buf = PgArgumentBuffer::default();
+        assert!(matches!(id.encode_by_ref(&mut buf), Ok(IsNull::No)));
+        assert_eq!("https://example.com/u/alice".as_bytes(), buf.as_slice());
+    }
+
+    #[test]
+    fn test_object_id_map_key() -> Result<(), Error> {
+        let alice = ObjectId::<Note>::parse("https://example.com/u/alice")?;
+        let bob = ObjectId::<Note>::parse("https://example.com/u/bob")?;
+        let mut map = HashMap::new();
+        map.insert(alice.clone(), 1);
+        map.insert(bob.clone(), 2);
+
+        // Lookup with a plain url, which is equal to the normalized id
+        let url = Url::parse("https://example.com/u/alice")?;
+        assert_eq!(Some(&1), map.get(&url));
+        assert_eq!(Some(&2), map.get(bob.as_ref()));
+        assert_eq!(None, map.get(&Url::parse("https://example.com/u/carol")?));
+
+        let set = HashSet::from([alice.clone(), alice.clone(), bob.clone()]);
+        assert_eq!(2, set.len());
+        assert!(set.contains(&url));
+
+        let tree = BTreeMap::from([(bob.clone(), 2), (alice.clone(), 1)]);
+        assert_eq!(vec![&alice, &bob], tree.keys().collect::<Vec<_>>());
+        assert_eq!(Some(&1), tree.get(&url));
+        assert!(alice < bob);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_should_refetch_object() {
+        let data = debug_data().await;
         let one_second_ago = Utc::now() - ChronoDuration::try_seconds(1).unwrap();
-        assert!(!should_refetch_object(one_second_ago));
+        assert!(!should_refetch_object::<Note>(one_second_ago, &data));
+
+        let two_days_ago = Utc::now() - ChronoDuration::try_days(2).unwrap();
+        assert!(should_refetch_object::<Note>(two_days_ago, &data));
+    }
 
+    #[tokio::test]
+    async fn test_refetch_interval() {
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .object_refetch_interval(Duration::from_secs(3 * 24 * 60 * 60))
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
         let two_days_ago = Utc::now() - ChronoDuration::try_days(2).unwrap();
-        assert!(should_refetch_object(two_days_ago));
+        let four_days_ago = Utc::now() - ChronoDuration::try_days(4).unwrap();
+        let ten_days_ago = Utc::now() - ChronoDuration::try_days(10).unwrap();
+        assert!(!should_refetch_object::<Note>(two_days_ago, &data));
+        assert!(should_refetch_object::<Note>(four_days_ago, &data));
+
+        // The interval of the type takes precedence
+        assert!(!should_refetch_object::<WeeklyNote>(four_days_ago, &data));
+        assert!(should_refetch_object::<WeeklyNote>(ten_days_ago, &data));
+
+        // Objects with a very long interval are never refetched
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .object_refetch_interval(Duration::MAX)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        assert!(!should_refetch_object::<Note>(ten_days_ago, &data));
+    }
+
+    #[derive(Debug)]
+    struct Author(String);
+
+    #[derive(Deserialize, Serialize)]
+    struct AuthorJson {
+        id: Url,
+        name: String,
+    }
+
+    #[async_trait]
+    impl Object for Author {
+        type DataType = DbConnection;
+        type Kind = AuthorJson;
+        type Error = Error;
+
+        async fn read_from_id(_: Url, _: &Data<Self::DataType>) -> Result<Option<Self>, Error> {
+            Ok(None)
+        }
+
+        async fn into_json(self, _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn verify(
+            json: &Self::Kind,
+            expected: &Url,
+            _: &Data<DbConnection>,
+        ) -> Result<(), Error> {
+            verify_domains_match(&json.id, expected)
+        }
+
+        async fn from_json(json: Self::Kind, _: &Data<Self::DataType>) -> Result<Self, Error> {
+            Ok(Author(json.name))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Post(Author);
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct PostJson {
+        id: Url,
+        attributed_to: ObjectId<Author>,
+    }
+
+    #[async_trait]
+    impl Object for Post {
+        type DataType = DbConnection;
+        type Kind = PostJson;
+        type Error = Error;
+
+        async fn read_from_id(_: Url, _: &Data<Self::DataType>) -> Result<Option<Self>, Error> {
+            Ok(None)
+        }
+
+        async fn into_json(self, _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn verify(
+            json: &Self::Kind,
+            expected: &Url,
+            _: &Data<DbConnection>,
+        ) -> Result<(), Error> {
+            verify_domains_match(&json.id, expected)
+        }
+
+        async fn from_json(json: Self::Kind, data: &Data<Self::DataType>) -> Result<Self, Error> {
+            Ok(Post(json.attributed_to.dereference(data).await?))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dereference_prefetched() -> Result<(), Error> {
+        let data = debug_data().await;
+        let create = serde_json::json!({
+            "id": "https://remote.example/activities/1",
+            "type": "Create",
+            "actor": "https://remote.example/u/alice",
+            "object": {
+                "id": "https://remote.example/notes/1",
+                "type": "Note",
+                "attributedTo": {
+                    "id": "https://remote.example/u/alice",
+                    "type": "Person",
+                    "name": "Alice"
+                }
+            }
+        });
+        let author: AuthorJson =
+            serde_json::from_value(create["object"]["attributedTo"].clone()).unwrap();
+        let note_id = ObjectId::<Post>::parse("https://remote.example/notes/1")?;
+        // The handler of the activity replaces the embedded author with its id
+        let note = || PostJson {
+            id: note_id.inner().clone(),
+            attributed_to: author.id.clone().into(),
+        };
+
+        // Only possible while receiving an activity
+        assert!(data.add_prefetched::<Post>(note()).is_err());
+
+        let _received = data
+            .received_activity
+            .enter(serde_json::to_vec(&create).unwrap().into());
+        data.add_prefetched::<Post>(note())?;
+        data.add_prefetched::<Author>(author)?;
+
+        // The note and its author are converted from the embedded json, without any fetch
+        let post = note_id.dereference(&data).await?;
+        assert_eq!(post.0 .0, "Alice");
+        assert_eq!(0, data.request_count());
+
+        // Objects on another domain than the actor are not trusted
+        let other = AuthorJson {
+            id: Url::parse("https://other.example/u/mallory")?,
+            name: "Mallory".to_string(),
+        };
+        assert_eq!(
+            data.add_prefetched::<Author>(other),
+            Err(Error::UrlVerificationError("Domains do not match"))
+        );
+        Ok(())
     }
 }
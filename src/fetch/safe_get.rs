@@ -0,0 +1,249 @@
+use crate::{
+    config::{is_invalid_ip, Data, RequestKind, VerifyContext},
+    error::Error,
+    reqwest_shim::{ResponseExt, MAX_BODY_SIZE},
+};
+use bytes::Bytes;
+use http::{header::LOCATION, HeaderMap, HeaderValue, StatusCode};
+use reqwest::{redirect::Policy, Client};
+use reqwest_middleware::ClientWithMiddleware;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tracing::info;
+use url::Url;
+
+/// Options for [Data::safe_get]
+#[derive(Clone, Debug)]
+pub struct SafeGetOptions {
+    /// Maximum size of the response body in bytes. Larger responses are aborted with
+    /// [Error::ResponseBodyLimit]. Defaults to 200KB, the same as for federated objects.
+    pub max_body_size: usize,
+    /// Timeout for each request. Defaults to [FederationConfigBuilder::request_timeout](crate::config::FederationConfigBuilder::request_timeout).
+    pub timeout: Option<Duration>,
+    /// Maximum number of redirects to follow. The target of each redirect is verified in the
+    /// same way as the original url. Defaults to 1, the same as for federated objects.
+    pub max_redirects: u8,
+    /// Resolve the domain only once, verify the resolved addresses and connect to exactly these
+    /// addresses. This prevents DNS rebinding, where the domain resolves to a public address
+    /// during verification and to a private address when connecting. Note that the request is
+    /// then made with a separate client, so middleware of the configured client is not used.
+    pub pin_dns: bool,
+    /// Value for the `Accept` header
+    pub accept: Option<HeaderValue>,
+}
+
+impl Default for SafeGetOptions {
+    fn default() -> Self {
+        SafeGetOptions {
+            max_body_size: MAX_BODY_SIZE,
+            timeout: None,
+            max_redirects: 1,
+            pin_dns: false,
+            accept: None,
+        }
+    }
+}
+
+/// Response from [Data::safe_get]
+#[derive(Clone, Debug)]
+pub struct SafeGetResponse {
+    /// Status code of the final response
+    pub status: StatusCode,
+    /// Final URL (different from request URL in case of redirect)
+    pub url: Url,
+    /// Headers of the final response
+    pub headers: HeaderMap,
+    /// Response body, at most [SafeGetOptions::max_body_size] bytes
+    pub body: Bytes,
+}
+
+impl<T: Clone> Data<T> {
+    /// Fetch an arbitrary remote resource, such as an HTML page for a link preview or an image
+    /// referenced from federated content.
+    ///
+    /// This applies the same protections as federated fetches: The url is checked for scheme,
+    /// private IP addresses and with the configured [UrlVerifier](crate::config::UrlVerifier),
+    /// the request counts towards [FederationConfigBuilder::http_fetch_limit](crate::config::FederationConfigBuilder::http_fetch_limit)
+    /// and the response body size is limited. Redirects are only followed up to
+    /// [SafeGetOptions::max_redirects], and each target is verified again.
+    ///
+    /// Unlike [fetch_object_http](crate::fetch::fetch_object_http) there is no validation of
+    /// content type or object id, and the request is not signed.
+    pub async fn safe_get(
+        &self,
+        url: &Url,
+        options: &SafeGetOptions,
+    ) -> Result<SafeGetResponse, Error> {
+        let config = &self.config;
+        let mut url = url.clone();
+        let mut redirects = 0;
+        loop {
+            config.verify_url_valid(&url, VerifyContext::Fetch).await?;
+            info!("Fetching remote resource {}", url.to_string());
+            let timeout =
+                self.timeout_within_deadline(options.timeout.unwrap_or(config.request_timeout))?;
+
+            let counter = self.request_counter.increment(RequestKind::SafeGet);
+            if counter > config.http_fetch_limit {
+                return Err(Error::RequestLimit);
+            }
+
+            let pinned_client;
+            let client = if options.pin_dns {
+                pinned_client = self.pinned_client(&url).await?;
+                &pinned_client
+            } else {
+                &config.client
+            };
+            let mut req = client.get(url.as_str()).timeout(timeout);
+            if let Some(accept) = &options.accept {
+                req = req.header("Accept", accept);
+            }
+            let res = req.send().await.map_err(|e| self.deadline_error(e))?;
+
+            let location = res.headers().get(LOCATION).and_then(|l| l.to_str().ok());
+            if let (Some(location), true) = (location, redirects < options.max_redirects) {
+                // Location may be relative to the current url
+                url = url.join(location)?;
+                redirects += 1;
+                continue;
+            }
+
+            let status = res.status();
+            let url = res.url().clone();
+            let headers = res.headers().clone();
+            let body = res.bytes_limited_to(options.max_body_size).await?;
+            return Ok(SafeGetResponse {
+                status,
+                url,
+                headers,
+                body,
+            });
+        }
+    }
+
+    /// Build a client which connects only to the addresses that the url's domain resolves to
+    /// right now.
+    async fn pinned_client(&self, url: &Url) -> Result<ClientWithMiddleware, Error> {
+        let mut builder = Client::builder().redirect(Policy::none());
+        if let (Some(domain), false) = (url.domain(), self.config.is_local_url(url)) {
+            let port = url.port_or_known_default().unwrap_or(443);
+            let addrs: Vec<_> = lookup_host((domain, port)).await?.collect();
+            if !self.config.debug && addrs.iter().any(|addr| is_invalid_ip(addr.ip())) {
+                return Err(Error::UrlVerificationError(
+                    "Localhost is only allowed in debug mode",
+                ));
+            }
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        Ok(builder.build()?.into())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{FederationConfig, UrlVerifier},
+        traits::tests::DbConnection,
+    };
+    use async_trait::async_trait;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[derive(Clone)]
+    struct Blocklist;
+
+    #[async_trait]
+    impl UrlVerifier for Blocklist {
+        async fn verify(&self, url: &Url) -> Result<(), Error> {
+            if url.domain() == Some("evil.com") {
+                return Err(Error::UrlVerificationError("Domain is blocked"));
+            }
+            Ok(())
+        }
+    }
+
+    /// Serve an html page with the given body size on a random port
+    async fn serve(body_size: usize) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {body_size}\r\nConnection: close\r\n\r\n{}",
+                    "a".repeat(body_size)
+                );
+                let _ = stream.write_all(res.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    async fn data(debug: bool) -> Data<DbConnection> {
+        FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(debug)
+            .url_verifier(Box::new(Blocklist))
+            .build()
+            .await
+            .unwrap()
+            .to_request_data()
+    }
+
+    #[tokio::test]
+    async fn test_safe_get_private_ip() {
+        let data = data(false).await;
+        let options = SafeGetOptions::default();
+        let url = Url::parse("https://localhost/image.png").unwrap();
+        let res = data.safe_get(&url, &options).await;
+        assert_eq!(res.err(), Some(Error::UrlVerificationError("")));
+        let url = Url::parse("https://127.0.0.1/image.png").unwrap();
+        let res = data.safe_get(&url, &options).await;
+        assert_eq!(res.err(), Some(Error::UrlVerificationError("")));
+    }
+
+    #[tokio::test]
+    async fn test_safe_get_blocklist() {
+        let data = data(true).await;
+        let url = Url::parse("https://evil.com/page.html").unwrap();
+        let res = data.safe_get(&url, &SafeGetOptions::default()).await;
+        assert_eq!(res.err(), Some(Error::UrlVerificationError("")));
+        assert_eq!(0, data.request_count());
+    }
+
+    #[tokio::test]
+    async fn test_safe_get() -> Result<(), Error> {
+        let port = serve(1000).await;
+        let data = data(true).await;
+        let url = Url::parse(&format!("http://localhost:{port}/page.html"))?;
+
+        let res = data.safe_get(&url, &SafeGetOptions::default()).await?;
+        assert_eq!(StatusCode::OK, res.status);
+        assert_eq!(1000, res.body.len());
+        assert_eq!("text/html", res.headers.get("content-type").unwrap());
+        assert_eq!(1, data.safe_get_count());
+
+        let options = SafeGetOptions {
+            pin_dns: true,
+            ..Default::default()
+        };
+        let res = data.safe_get(&url, &options).await?;
+        assert_eq!(1000, res.body.len());
+
+        let options = SafeGetOptions {
+            max_body_size: 500,
+            ..Default::default()
+        };
+        let res = data.safe_get(&url, &options).await;
+        assert_eq!(res.err(), Some(Error::ResponseBodyLimit));
+        assert_eq!(3, data.request_count());
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Fetching objects whose type isn't known in advance, for example urls which are pasted into a
+//! search field.
+//!
+//! [UntypedObject](crate::fetch::untyped::UntypedObject) combines two
+//! [Object](crate::traits::Object) types into one, by trying them in order. It can be used with
+//! [ObjectId](crate::fetch::object_id::ObjectId) like any other object, so the result is read
+//! from the local database if possible, and otherwise fetched over HTTP and verified. More than
+//! two types can be combined by nesting, for example
+//! `UntypedObject<DbUser, UntypedObject<DbPost, DbComment>>`.
+//!
+//! ```no_run
+//! # use activitypub_federation::config::FederationConfig;
+//! # use activitypub_federation::fetch::untyped::{UntypedObject, UntypedObjectId};
+//! # use activitypub_federation::traits::tests::{DbConnection, DbPost, DbUser};
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! # let config = FederationConfig::builder().domain("example.com").app_data(DbConnection).build().await?;
+//! # let data = config.to_request_data();
+//! let object_id = UntypedObjectId::<DbUser, DbPost>::parse("https://example.com/id/413")?;
+//! match object_id.dereference(&data).await? {
+//!     UntypedObject::First(user) => {}  // object is a user
+//!     UntypedObject::Second(post) => {} // object is a post
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! # }).unwrap();
+//! ```
+
+use crate::{
+    config::Data,
+    error::Error,
+    fetch::{object_id::ObjectId, webfinger::webfinger_resolve_actor},
+    traits::{Actor, Object},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+use url::Url;
+
+/// Object which is either of type `A` or `B`, see the [module docs](self).
+#[derive(Clone, Debug)]
+pub enum UntypedObject<A, B> {
+    /// The object has type `A`
+    First(A),
+    /// The object has type `B`
+    Second(B),
+}
+
+/// Activitypub representation of [UntypedObject].
+///
+/// Received JSON is deserialized as `A` if possible, and otherwise as `B`. So if `B::Kind` would
+/// also accept the JSON of `A::Kind`, it is important to put the more specific type first. Using
+/// a `kind` field with the expected `type`, for example [PersonType](activitystreams_kinds::actor::PersonType),
+/// avoids this problem.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UntypedKind<A, B> {
+    /// JSON of type `A`
+    First(A),
+    /// JSON of type `B`
+    Second(B),
+}
+
+/// Id of an object which is either of type `A` or `B`, see the [module docs](self).
+pub type UntypedObjectId<A, B> = ObjectId<UntypedObject<A, B>>;
+
+#[async_trait]
+impl<A, B> Object for UntypedObject<A, B>
+where
+    A: Object + Send + Sync,
+    B: Object<DataType = A::DataType> + Send + Sync,
+    A::Kind: Send + Sync,
+    B::Kind: Send + Sync,
+    A::Error: From<B::Error> + Send,
+{
+    type DataType = A::DataType;
+    type Kind = UntypedKind<A::Kind, B::Kind>;
+    type Error = A::Error;
+
+    fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            UntypedObject::First(a) => a.last_refreshed_at(),
+            UntypedObject::Second(b) => b.last_refreshed_at(),
+        }
+    }
+
+    async fn read_from_id(
+        object_id: Url,
+        data: &Data<Self::DataType>,
+    ) -> Result<Option<Self>, Self::Error> {
+        if let Some(a) = A::read_from_id(object_id.clone(), data).await? {
+            return Ok(Some(UntypedObject::First(a)));
+        }
+        Ok(B::read_from_id(object_id, data)
+            .await?
+            .map(UntypedObject::Second))
+    }
+
+    async fn delete(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        match self {
+            UntypedObject::First(a) => a.delete(data).await,
+            UntypedObject::Second(b) => Ok(b.delete(data).await?),
+        }
+    }
+
+    async fn into_json(self, data: &Data<Self::DataType>) -> Result<Self::Kind, Self::Error> {
+        Ok(match self {
+            UntypedObject::First(a) => UntypedKind::First(a.into_json(data).await?),
+            UntypedObject::Second(b) => UntypedKind::Second(b.into_json(data).await?),
+        })
+    }
+
+    async fn verify(
+        json: &Self::Kind,
+        expected_domain: &Url,
+        data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        match json {
+            UntypedKind::First(a) => A::verify(a, expected_domain, data).await,
+            UntypedKind::Second(b) => Ok(B::verify(b, expected_domain, data).await?),
+        }
+    }
+
+    async fn from_json(json: Self::Kind, data: &Data<Self::DataType>) -> Result<Self, Self::Error> {
+        Ok(match json {
+            UntypedKind::First(a) => UntypedObject::First(A::from_json(a, data).await?),
+            UntypedKind::Second(b) => UntypedObject::Second(B::from_json(b, data).await?),
+        })
+    }
+}
+
+/// Resolves the query of a search field, which is either the url of an object or an identifier
+/// of the form `name@example.com`.
+///
+/// Urls are dereferenced as [UntypedObjectId], so the result may be an actor of type `A` or an
+/// object of type `B`. Other queries are resolved with [webfinger_resolve_actor], optionally with
+/// a leading `@` or `acct:`, and always return an actor.
+pub async fn resolve_actor_or_object<T: Clone, A, B>(
+    query: &str,
+    data: &Data<T>,
+) -> Result<UntypedObject<A, B>, <A as Object>::Error>
+where
+    A: Object<DataType = T> + Actor + Send + Sync + 'static,
+    B: Object<DataType = T> + Send + Sync + 'static,
+    for<'de2> <A as Object>::Kind: Deserialize<'de2>,
+    for<'de2> <B as Object>::Kind: Deserialize<'de2>,
+    <A as Object>::Kind: Send + Sync,
+    <B as Object>::Kind: Send + Sync,
+    <A as Object>::Error: From<Error> + From<<B as Object>::Error> + Send + Sync + Display,
+{
+    let query = query.trim();
+    match Url::parse(query) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {
+            UntypedObjectId::<A, B>::from(url).dereference(data).await
+        }
+        _ => {
+            let identifier = query.strip_prefix("acct:").unwrap_or(query);
+            let identifier = identifier.strip_prefix('@').unwrap_or(identifier);
+            Ok(UntypedObject::First(
+                webfinger_resolve_actor::<T, A>(identifier, data).await?,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_untyped_kind() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Person {
+            name: String,
+        }
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Note {
+            content: String,
+        }
+
+        let person: UntypedKind<Person, Note> =
+            serde_json::from_str(r#"{"name":"alice"}"#).unwrap();
+        assert!(matches!(person, UntypedKind::First(p) if p.name == "alice"));
+        let note: UntypedKind<Person, Note> =
+            serde_json::from_str(r#"{"content":"hello"}"#).unwrap();
+        assert!(matches!(note, UntypedKind::Second(n) if n.content == "hello"));
+        assert!(serde_json::from_str::<UntypedKind<Person, Note>>(r#"{"id":1}"#).is_err());
+    }
+
+    #[cfg(all(feature = "axum", feature = "example-storage"))]
+    #[tokio::test]
+    async fn test_resolve_actor_or_object() -> Result<(), Error> {
+        use crate::{
+            axum::json::FederationJson,
+            config::FederationConfig,
+            example_storage::{DbPost, DbUser, InMemoryStorage},
+            fetch::webfinger::build_webfinger_response,
+        };
+        use axum::{routing::get, Json, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let ap_id = Url::parse(&format!("http://{domain}/u/alice"))?;
+        let post_id = Url::parse(&format!("http://{domain}/post/1"))?;
+
+        let remote = FederationConfig::builder()
+            .domain(domain.clone())
+            .app_data(InMemoryStorage::default())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let user = DbUser::new("alice", ap_id.clone(), ap_id.join("/u/alice/inbox")?)?;
+        remote.upsert_user(user.clone());
+        let post = DbPost::new("hello".to_string(), post_id.clone(), user.ap_id.clone());
+        let note = serde_json::to_value(post.into_json(&remote).await?).unwrap();
+        let person = serde_json::to_value(user.into_json(&remote).await?).unwrap();
+        let webfinger = serde_json::to_value(build_webfinger_response(
+            format!("acct:alice@{domain}"),
+            ap_id.clone(),
+        ))
+        .unwrap();
+        let app = Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(move || {
+                    let webfinger = webfinger.clone();
+                    async move { Json(webfinger) }
+                }),
+            )
+            .route(
+                "/u/alice",
+                get(move || {
+                    let person = person.clone();
+                    async move { FederationJson(person) }
+                }),
+            )
+            .route(
+                "/post/1",
+                get(move || {
+                    let note = note.clone();
+                    async move { FederationJson(note) }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(InMemoryStorage::default())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let resolve = |query: String| {
+            let data = data.reset_request_count();
+            async move { resolve_actor_or_object::<_, DbUser, DbPost>(&query, &data).await }
+        };
+
+        let UntypedObject::First(resolved) = resolve(ap_id.to_string()).await? else {
+            panic!("expected user");
+        };
+        assert_eq!(resolved.name, "alice");
+        let UntypedObject::Second(resolved) = resolve(post_id.to_string()).await? else {
+            panic!("expected post");
+        };
+        assert_eq!(resolved.text, "hello");
+        assert_eq!(resolved.creator.inner(), &ap_id);
+        for query in [format!("alice@{domain}"), format!("@alice@{domain}")] {
+            let UntypedObject::First(resolved) = resolve(query).await? else {
+                panic!("expected user");
+            };
+            assert_eq!(resolved.ap_id.inner(), &ap_id);
+        }
+
+        // Both objects are stored now, and can be read without fetching
+        let stored = UntypedObjectId::<DbUser, DbPost>::from(post_id);
+        assert!(matches!(
+            stored.dereference_local(&data).await?,
+            UntypedObject::Second(_)
+        ));
+        let stored = UntypedObjectId::<DbUser, DbPost>::from(ap_id);
+        assert!(matches!(
+            stored.dereference_local(&data).await?,
+            UntypedObject::First(_)
+        ));
+        Ok(())
+    }
+}
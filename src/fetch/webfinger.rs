@@ -1,21 +1,23 @@
 use crate::{
-    config::{Data, DOMAIN_REGEX},
+    config::{Data, RequestKind, VerifyContext, DOMAIN_REGEX},
     error::Error,
-    fetch::{fetch_object_http_with_accept, object_id::ObjectId},
+    fetch::{fetch_object_http_with_accept, object_id::ObjectId, FetchOptions},
+    protocol::verification::{normalize_domain, verify_domains_match},
     traits::{Actor, Object},
     FEDERATION_CONTENT_TYPE,
 };
-use http::HeaderValue;
+use http::{HeaderValue, StatusCode};
 use itertools::Itertools;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, fmt::Display, time::Duration};
 use tracing::debug;
-use url::Url;
+use url::{form_urlencoded, Url};
 
 /// Errors relative to webfinger handling
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Debug, Clone)]
 pub enum WebFingerError {
     /// The webfinger identifier is invalid
     #[error("The webfinger identifier is invalid")]
@@ -26,21 +28,54 @@ pub enum WebFingerError {
     /// The wefinger object did not contain any link to an activitypub item
     #[error("The webfinger object did not contain any link to an activitypub item")]
     NoValidLink,
+    /// The remote instance rejected the webfinger request with status 401 or 403, for example
+    /// because it requires signed requests
+    #[error("The webfinger request was rejected as unauthorized")]
+    Unauthorized,
+    /// The remote instance doesn't know the webfinger identifier
+    #[error("The webfinger identifier was not found")]
+    NotFound,
+    /// The webfinger request failed with another HTTP status
+    #[error("The webfinger request failed with status {0}")]
+    HttpStatus(StatusCode),
 }
 
 impl WebFingerError {
     fn into_crate_error(self) -> Error {
         self.into()
     }
+
+    /// Error for a webfinger response with unsuccessful `status`
+    pub(crate) fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => WebFingerError::Unauthorized,
+            StatusCode::NOT_FOUND | StatusCode::GONE => WebFingerError::NotFound,
+            status => WebFingerError::HttpStatus(status),
+        }
+    }
 }
 
 /// The content-type for webfinger responses.
 pub static WEBFINGER_CONTENT_TYPE: HeaderValue = HeaderValue::from_static("application/jrd+json");
 
+/// Default duration for which successful webfinger lookups are cached
+pub(crate) const WEBFINGER_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Relationship of webfinger links with a template for remote follows
+const SUBSCRIBE_REL: &str = "http://ostatus.org/schema/1.0/subscribe";
+
+/// Property of webfinger links which contains the type of the actor, such as `Person` or `Group`
+const ACTIVITYSTREAMS_TYPE: &str = "https://www.w3.org/ns/activitystreams#type";
+
 /// Takes an identifier of the form `name@example.com`, and returns an object of `Kind`.
 ///
 /// For this the identifier is first resolved via webfinger protocol to an Activitypub ID. This ID
 /// is then fetched using [ObjectId::dereference], and the result returned.
+///
+/// Links are tried in the order of [webfinger_link_priority]. Links with `rel="self"` and an
+/// ActivityPub media type are tried first, then other links with an `application/*` media type.
+/// Links with an XML media type are ignored. If [Actor::actor_type] is set, links with a matching
+/// `https://www.w3.org/ns/activitystreams#type` property are preferred.
 pub async fn webfinger_resolve_actor<T: Clone, Kind>(
     identifier: &str,
     data: &Data<T>,
@@ -50,61 +85,284 @@ where
     for<'de2> <Kind as Object>::Kind: serde::Deserialize<'de2>,
     <Kind as Object>::Error: From<crate::error::Error> + Send + Sync + Display,
 {
-    let (_, domain) = identifier
-        .splitn(2, '@')
-        .collect_tuple()
-        .ok_or(WebFingerError::WrongFormat.into_crate_error())?;
+    Ok(
+        webfinger_resolve_actor_with_meta::<T, Kind>(identifier, data)
+            .await?
+            .actor,
+    )
+}
 
-    // For production mode make sure that domain doesnt contain any port or path.
-    if !data.config.debug && !DOMAIN_REGEX.is_match(domain) {
-        return Err(Error::UrlVerificationError("Invalid characters in domain").into());
+/// Actor resolved with [webfinger_resolve_actor_with_meta]
+#[derive(Debug)]
+pub struct WebfingerResolved<Kind> {
+    /// The resolved actor
+    pub actor: Kind,
+    /// The webfinger link which was used to dereference the actor
+    pub link: WebfingerLink,
+}
+
+/// Same as [webfinger_resolve_actor], but also returns the webfinger link which was used to
+/// dereference the actor.
+///
+/// Successful lookups are cached for the duration set with
+/// [FederationConfigBuilder::webfinger_cache_ttl](crate::config::FederationConfigBuilder::webfinger_cache_ttl).
+pub async fn webfinger_resolve_actor_with_meta<T: Clone, Kind>(
+    identifier: &str,
+    data: &Data<T>,
+) -> Result<WebfingerResolved<Kind>, <Kind as Object>::Error>
+where
+    Kind: Object + Actor + Send + 'static + Object<DataType = T>,
+    for<'de2> <Kind as Object>::Kind: serde::Deserialize<'de2>,
+    <Kind as Object>::Error: From<crate::error::Error> + Send + Sync + Display,
+{
+    let cache = &data.config.webfinger_cache;
+    // Links are selected by actor type, so a user and a community with the same name have
+    // separate entries
+    let cache_key = (identifier.to_string(), Kind::actor_type());
+    if let Some(link) = cache.get(&cache_key).await {
+        if let Some(href) = link.href.clone() {
+            match ObjectId::<Kind>::from(href).dereference(data).await {
+                Ok(actor) => return Ok(WebfingerResolved { actor, link }),
+                Err(error) => debug!(%error, "Failed to dereference cached webfinger link"),
+            }
+        }
+        cache.invalidate(&cache_key).await;
     }
 
-    let protocol = if data.config.debug { "http" } else { "https" };
-    let fetch_url =
-        format!("{protocol}://{domain}/.well-known/webfinger?resource=acct:{identifier}");
+    let (webfinger, _) = fetch_webfinger(identifier, data).await?;
+    debug_assert_eq!(webfinger.subject, format!("acct:{identifier}"));
+    for link in select_links(webfinger.links, Kind::actor_type()) {
+        let Some(href) = link.href.clone() else {
+            continue;
+        };
+        let object = ObjectId::<Kind>::from(href).dereference(data).await;
+        match object {
+            Ok(actor) => {
+                cache.insert(cache_key, link.clone()).await;
+                return Ok(WebfingerResolved { actor, link });
+            }
+            Err(error) => debug!(%error, "Failed to dereference link"),
+        }
+    }
+    Err(WebFingerError::NoValidLink.into_crate_error().into())
+}
+
+/// Fetches the webfinger document of `identifier`, and returns it together with the url which it
+/// was served from.
+///
+/// The lookup is unsigned first, and only signed with the signed fetch actor if the remote
+/// instance requires it.
+async fn fetch_webfinger<T: Clone>(
+    identifier: &str,
+    data: &Data<T>,
+) -> Result<(Webfinger, Url), Error> {
+    let fetch_url = webfinger_url(identifier, data)?;
     debug!("Fetching webfinger url: {}", &fetch_url);
 
-    let res = fetch_object_http_with_accept::<_, Webfinger>(
-        &Url::parse(&fetch_url).map_err(Error::UrlParse)?,
+    let mut options = FetchOptions {
+        recursive: false,
+        follow_alternate: false,
+        allow_local: true,
+        signed: false,
+        conditional_headers: None,
+    };
+    let mut res = fetch_object_http_with_accept::<_, Webfinger>(
+        &fetch_url,
         data,
         &WEBFINGER_CONTENT_TYPE,
-        false,
+        RequestKind::Webfinger,
+        options,
     )
-    .await?;
-    if res.url.as_str() != fetch_url {
-        data.config.verify_url_valid(&res.url).await?;
+    .await;
+    if let (Err(Error::WebfingerResolveFailed(WebFingerError::Unauthorized)), Some(_)) =
+        (&res, data.signed_fetch_actor())
+    {
+        debug!("Webfinger lookup was unauthorized, retrying with signature");
+        options.signed = true;
+        res = fetch_object_http_with_accept::<_, Webfinger>(
+            &fetch_url,
+            data,
+            &WEBFINGER_CONTENT_TYPE,
+            RequestKind::Webfinger,
+            options,
+        )
+        .await;
     }
+    let res = res?.into_object(&fetch_url)?;
+    if res.url != fetch_url {
+        data.config
+            .verify_url_valid(&res.url, VerifyContext::Webfinger)
+            .await?;
+    }
+
+    Ok((res.object, res.url))
+}
 
-    debug_assert_eq!(res.object.subject, format!("acct:{identifier}"));
-    let links: Vec<Url> = res
-        .object
+/// Looks up the template for remote follows and other interactions of `identifier`, which has the
+/// form `name@example.com`. This is the `template` of the webfinger link with relationship
+/// `http://ostatus.org/schema/1.0/subscribe`, for example
+/// `https://mastodon.social/authorize_interaction?uri={uri}`.
+///
+/// Returns `None` if the webfinger response contains no such link. Fails with
+/// [Error::UrlVerificationError] if the template doesn't contain `{uri}`, doesn't use https, or
+/// is on a different domain than the webfinger response. Use [apply_interaction_template] to
+/// build the url where the user can interact with an object.
+pub async fn fetch_interaction_template<T: Clone>(
+    identifier: &str,
+    data: &Data<T>,
+) -> Result<Option<String>, Error> {
+    let (webfinger, url) = fetch_webfinger(identifier, data).await?;
+    let Some(template) = webfinger
         .links
+        .into_iter()
+        .filter(|link| link.rel.as_deref() == Some(SUBSCRIBE_REL))
+        .find_map(|link| link.template)
+    else {
+        return Ok(None);
+    };
+    verify_interaction_template(&template, &url, data)?;
+    Ok(Some(template))
+}
+
+/// Checks that `template` can be used with [apply_interaction_template], and that it is on the
+/// same domain as the webfinger response which was fetched from `webfinger_url`.
+fn verify_interaction_template<T: Clone>(
+    template: &str,
+    webfinger_url: &Url,
+    data: &Data<T>,
+) -> Result<(), Error> {
+    if !template.contains(INTERACTION_TEMPLATE_PLACEHOLDER) {
+        return Err(Error::UrlVerificationError(
+            "Interaction template doesn't contain {uri}",
+        ));
+    }
+    let url = Url::parse(&template.replace(INTERACTION_TEMPLATE_PLACEHOLDER, ""))?;
+    let https = url.scheme() == "https" || (data.config.debug && url.scheme() == "http");
+    if !https {
+        return Err(Error::UrlVerificationError(
+            "Interaction template doesn't use https",
+        ));
+    }
+    verify_domains_match(&url, webfinger_url)
+}
+
+/// Placeholder in interaction templates which is replaced with the url of an object
+const INTERACTION_TEMPLATE_PLACEHOLDER: &str = "{uri}";
+
+/// Replaces `{uri}` in an interaction template from [fetch_interaction_template] with `target`,
+/// and returns the resulting url. `target` is percent-encoded, so that it can't add query
+/// parameters or change the path of the url.
+///
+/// ```
+/// # use activitypub_federation::fetch::webfinger::apply_interaction_template;
+/// # use url::Url;
+/// let template = "https://mastodon.social/authorize_interaction?uri={uri}";
+/// let target = Url::parse("https://lemmy.ml/post/1?a=b&c=d")?;
+/// assert_eq!(
+///     apply_interaction_template(template, &target)?.as_str(),
+///     "https://mastodon.social/authorize_interaction?uri=https%3A%2F%2Flemmy.ml%2Fpost%2F1%3Fa%3Db%26c%3Dd"
+/// );
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn apply_interaction_template(template: &str, target: &Url) -> Result<Url, Error> {
+    let target: String = form_urlencoded::byte_serialize(target.as_str().as_bytes()).collect();
+    Ok(Url::parse(
+        &template.replace(INTERACTION_TEMPLATE_PLACEHOLDER, &target),
+    )?)
+}
+
+/// Returns the links which may point to an actor, in the order in which they should be tried.
+fn select_links(links: Vec<WebfingerLink>, actor_type: Option<&str>) -> Vec<WebfingerLink> {
+    links
+        .into_iter()
+        .filter_map(|link| Some((webfinger_link_priority(&link, actor_type)?, link)))
+        .sorted_by_key(|(priority, _)| *priority)
+        .map(|(_, link)| link)
+        .collect()
+}
+
+/// Priority of a webfinger link when resolving an actor, lower values are tried first. Returns
+/// `None` for links which can't point to an actor, because they have no `href` or no
+/// `application/*` media type, or an XML media type such as an Atom feed.
+///
+/// Links are ranked first by their `https://www.w3.org/ns/activitystreams#type` property if
+/// `actor_type` is given: links of this type come first, then links without type, then links of
+/// other types. This way a community can be resolved on Lemmy, where a user may have the same
+/// name. Within each rank, links with `rel="self"` and media type `application/activity+json`
+/// or `application/ld+json; profile="https://www.w3.org/ns/activitystreams"` come first, then
+/// other links with one of these media types, then other `application/*` links.
+///
+/// ```
+/// # use activitypub_federation::fetch::webfinger::{webfinger_link_priority, WebfingerLink};
+/// let link = WebfingerLink {
+///     rel: Some("self".to_string()),
+///     kind: Some("application/activity+json".to_string()),
+///     href: Some("https://lemmy.ml/c/lemmy".parse()?),
+///     ..Default::default()
+/// };
+/// let feed = WebfingerLink {
+///     kind: Some("application/atom+xml".to_string()),
+///     ..link.clone()
+/// };
+/// assert_eq!(webfinger_link_priority(&link, None), Some(0));
+/// assert_eq!(webfinger_link_priority(&feed, None), None);
+/// # Ok::<(), url::ParseError>(())
+/// ```
+pub fn webfinger_link_priority(link: &WebfingerLink, actor_type: Option<&str>) -> Option<u8> {
+    let media_type = link.kind.as_deref()?.to_ascii_lowercase();
+    if link.href.is_none() || !media_type.starts_with("application/") || media_type.contains("xml")
+    {
+        return None;
+    }
+    let mut params = media_type.split(';').map(str::trim);
+    let essence = params.next().unwrap_or_default();
+    let is_activitypub = essence == "application/activity+json"
+        || (essence == "application/ld+json"
+            && params.any(|p| p == r#"profile="https://www.w3.org/ns/activitystreams""#));
+    let link_rank = match (link.rel.as_deref() == Some("self"), is_activitypub) {
+        (true, true) => 0,
+        (false, true) => 1,
+        (_, false) => 2,
+    };
+    let link_type = link
+        .properties
         .iter()
-        .filter(|link| {
-            if let Some(type_) = &link.kind {
-                type_.starts_with("application/")
-            } else {
-                false
-            }
-        })
-        .filter_map(|l| l.href.clone())
-        .collect();
+        .find(|(key, _)| key.as_str() == ACTIVITYSTREAMS_TYPE)
+        .and_then(|(_, value)| value.as_deref());
+    let type_rank = match (actor_type, link_type) {
+        (None, _) => 0,
+        (Some(expected), Some(actual)) if expected.eq_ignore_ascii_case(actual) => 0,
+        (Some(_), None) => 1,
+        (Some(_), Some(_)) => 2,
+    };
+    Some(type_rank * 3 + link_rank)
+}
 
-    for l in links {
-        let object = ObjectId::<Kind>::from(l).dereference(data).await;
-        match object {
-            Ok(obj) => return Ok(obj),
-            Err(error) => debug!(%error, "Failed to dereference link"),
-        }
+/// Builds the url for a webfinger query of `identifier`. Internationalized domains are converted
+/// to punycode for the request, while the `acct:` resource keeps the identifier as provided.
+fn webfinger_url<T: Clone>(identifier: &str, data: &Data<T>) -> Result<Url, Error> {
+    let (_, domain) = identifier
+        .splitn(2, '@')
+        .collect_tuple()
+        .ok_or(WebFingerError::WrongFormat.into_crate_error())?;
+    let domain = normalize_domain(domain);
+
+    // For production mode make sure that domain doesnt contain any port or path.
+    if !data.config.debug && !DOMAIN_REGEX.is_match(&domain) {
+        return Err(Error::UrlVerificationError("Invalid characters in domain"));
     }
-    Err(WebFingerError::NoValidLink.into_crate_error().into())
+
+    let protocol = if data.config.debug { "http" } else { "https" };
+    Ok(Url::parse(&format!(
+        "{protocol}://{domain}/.well-known/webfinger?resource=acct:{identifier}"
+    ))?)
 }
 
 /// Extracts username from a webfinger resource parameter.
 ///
 /// Use this method for your HTTP handler at `.well-known/webfinger` to handle incoming webfinger
-/// request. For a parameter of the form `acct:gargron@mastodon.social` it returns `gargron`.
+/// request. For a parameter of the form `acct:gargron@mastodon.social` it returns `gargron`. See
+/// [parse_webfinger_query] for the accepted formats.
 ///
 /// Returns an error if query doesn't match local domain.
 ///
@@ -130,20 +388,127 @@ pub fn extract_webfinger_name<'i, T>(query: &'i str, data: &Data<T>) -> Result<&
 where
     T: Clone,
 {
-    static WEBFINGER_REGEX: Lazy<Regex> =
-        Lazy::new(|| Regex::new(r"^acct:([\p{L}0-9_\.\-]+)@(.*)$").expect("compile regex"));
-    // Regex to extract usernames from webfinger query. Supports different alphabets using `\p{L}`.
-    // TODO: This should use a URL parser
-    let captures = WEBFINGER_REGEX
-        .captures(query)
+    Ok(parse_webfinger_query(query, data)?.name)
+}
+
+/// Parsed `resource` parameter of an incoming webfinger request, see [parse_webfinger_query].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebfingerQuery<'i> {
+    /// Name of the requested actor, for example `gargron`
+    pub name: &'i str,
+    /// Domain of the requested actor in lowercase punycode form, including the port if any
+    pub domain: String,
+}
+
+/// Parses the resource parameter of a webfinger request of the form
+/// `acct:gargron@mastodon.social`. Domains are compared case-insensitively and may include a
+/// port, such as `localhost:8001` during development. Use [parse_webfinger_resource] to also
+/// accept actor ids.
+///
+/// Returns an error if the domain doesn't match the local domain.
+///
+///```
+/// # use activitypub_federation::config::FederationConfig;
+/// # use activitypub_federation::traits::tests::DbConnection;
+/// # use activitypub_federation::fetch::webfinger::parse_webfinger_query;
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let config = FederationConfig::builder()
+///     .domain("example.com")
+///     .app_data(DbConnection)
+///     .build()
+///     .await?;
+/// let data = config.to_request_data();
+/// let query = parse_webfinger_query("acct:Alice@Example.COM", &data)?;
+/// assert_eq!(query.name, "Alice");
+/// assert_eq!(query.domain, "example.com");
+/// # Ok::<(), anyhow::Error>(())
+/// # }).unwrap();
+///```
+pub fn parse_webfinger_query<'i, T>(
+    query: &'i str,
+    data: &Data<T>,
+) -> Result<WebfingerQuery<'i>, Error>
+where
+    T: Clone,
+{
+    // Supports different alphabets using `\p{L}`
+    static NAME_REGEX: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^[\p{L}0-9_\.\-]+$").expect("compile regex"));
+
+    let (name, domain) = query
+        .strip_prefix("acct:")
+        .and_then(|account| account.rsplit_once('@'))
         .ok_or(WebFingerError::WrongFormat)?;
+    if !NAME_REGEX.is_match(name) {
+        return Err(WebFingerError::WrongFormat.into());
+    }
+    let domain = normalize_domain(domain);
+    if domain != normalize_domain(data.domain()) {
+        return Err(WebFingerError::WrongDomain.into());
+    }
+    Ok(WebfingerQuery { name, domain })
+}
 
-    let account_name = captures.get(1).ok_or(WebFingerError::WrongFormat)?;
+/// Parsed `resource` parameter of an incoming webfinger request, see [parse_webfinger_resource].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WebfingerResource<'i> {
+    /// Resource of the form `acct:gargron@mastodon.social`
+    Account(WebfingerQuery<'i>),
+    /// Id of a local actor such as `https://mastodon.social/users/gargron`, which some clients
+    /// send instead of the `acct:` form. The id doesn't necessarily contain the name, so the
+    /// actor needs to be read by its id, for example with
+    /// [ObjectId::dereference_local](crate::fetch::object_id::ObjectId::dereference_local).
+    Actor(Url),
+}
 
-    if captures.get(2).map(|m| m.as_str()) != Some(data.domain()) {
+/// Parses the resource parameter of a webfinger request, which is either of the form
+/// `acct:gargron@mastodon.social` as in [parse_webfinger_query], or the id of a local actor.
+///
+/// Returns an error if the resource doesn't belong to the local domain.
+///
+///```
+/// # use activitypub_federation::config::FederationConfig;
+/// # use activitypub_federation::fetch::object_id::ObjectId;
+/// # use activitypub_federation::traits::tests::{DbConnection, DbUser};
+/// # use activitypub_federation::fetch::webfinger::{parse_webfinger_resource, WebfingerResource};
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let config = FederationConfig::builder()
+///     .domain("example.com")
+///     .app_data(DbConnection)
+///     .build()
+///     .await?;
+/// let data = config.to_request_data();
+/// match parse_webfinger_resource("https://example.com/users/1", &data)? {
+///     WebfingerResource::Account(query) => {
+///         // Read the local actor with name `query.name`
+///     }
+///     WebfingerResource::Actor(id) => {
+///         let actor = ObjectId::<DbUser>::from(id).dereference_local(&data).await;
+///     }
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// # }).unwrap();
+///```
+pub fn parse_webfinger_resource<'i, T>(
+    resource: &'i str,
+    data: &Data<T>,
+) -> Result<WebfingerResource<'i>, Error>
+where
+    T: Clone,
+{
+    if resource.starts_with("acct:") {
+        return Ok(WebfingerResource::Account(parse_webfinger_query(
+            resource, data,
+        )?));
+    }
+    let url = Url::parse(resource).map_err(|_| WebFingerError::WrongFormat)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(WebFingerError::WrongFormat.into());
+    }
+    if !data.config.is_local_url(&url) {
         return Err(WebFingerError::WrongDomain.into());
     }
-    Ok(account_name.as_str())
+    Ok(WebfingerResource::Actor(url))
 }
 
 /// Builds a basic webfinger response for the actor.
@@ -186,39 +551,100 @@ pub fn build_webfinger_response_with_type(
     subject: String,
     urls: Vec<(Url, Option<&str>)>,
 ) -> Webfinger {
-    Webfinger {
-        subject,
-        links: urls.iter().fold(vec![], |mut acc, (url, kind)| {
-            let properties: HashMap<Url, String> = kind
-                .map(|kind| {
-                    HashMap::from([(
-                        "https://www.w3.org/ns/activitystreams#type"
-                            .parse()
-                            .expect("parse url"),
-                        kind.to_string(),
-                    )])
-                })
-                .unwrap_or_default();
-            let mut links = vec![
-                WebfingerLink {
-                    rel: Some("http://webfinger.net/rel/profile-page".to_string()),
-                    kind: Some("text/html".to_string()),
-                    href: Some(url.clone()),
-                    ..Default::default()
-                },
-                WebfingerLink {
-                    rel: Some("self".to_string()),
-                    kind: Some(FEDERATION_CONTENT_TYPE.to_string()),
-                    href: Some(url.clone()),
-                    properties,
-                    ..Default::default()
-                },
-            ];
-            acc.append(&mut links);
-            acc
-        }),
-        aliases: vec![],
-        properties: Default::default(),
+    urls.into_iter()
+        .fold(
+            WebfingerResponseBuilder::new(subject),
+            |builder, (url, kind)| builder.with_actor(url, kind),
+        )
+        .build()
+}
+
+/// Builds a [Webfinger] response step by step, for example to include aliases of the actor or a
+/// template for remote follows.
+///
+/// ```
+/// # use url::Url;
+/// # use activitypub_federation::fetch::webfinger::WebfingerResponseBuilder;
+/// let webfinger = WebfingerResponseBuilder::new("acct:nutomic@lemmy.ml".to_string())
+///     .with_actor(Url::parse("https://lemmy.ml/u/nutomic")?, Some("Person"))
+///     .with_alias(Url::parse("https://lemmy.ml/@nutomic")?)
+///     .with_subscribe_template("https://lemmy.ml/authorize_interaction?uri={uri}")
+///     .build();
+/// assert_eq!(webfinger.links.len(), 3);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct WebfingerResponseBuilder {
+    subject: String,
+    links: Vec<WebfingerLink>,
+    aliases: Vec<Url>,
+}
+
+impl WebfingerResponseBuilder {
+    /// Start a response for `subject`, for example `acct:nutomic@lemmy.ml`
+    pub fn new(subject: String) -> Self {
+        WebfingerResponseBuilder {
+            subject,
+            links: vec![],
+            aliases: vec![],
+        }
+    }
+
+    /// Adds links to the actor at `url`, both for viewing it in a browser as HTML and for
+    /// fetching it over Activitypub. If `kind` is given, such as `"Person"` or `"Group"`, it is
+    /// included in the properties of the Activitypub link.
+    pub fn with_actor(mut self, url: Url, kind: Option<&str>) -> Self {
+        let properties: HashMap<Url, Option<String>> = kind
+            .map(|kind| {
+                HashMap::from([(
+                    ACTIVITYSTREAMS_TYPE.parse().expect("parse url"),
+                    Some(kind.to_string()),
+                )])
+            })
+            .unwrap_or_default();
+        self.links.push(WebfingerLink {
+            rel: Some("http://webfinger.net/rel/profile-page".to_string()),
+            kind: Some("text/html".to_string()),
+            href: Some(url.clone()),
+            ..Default::default()
+        });
+        self.links.push(WebfingerLink {
+            rel: Some("self".to_string()),
+            kind: Some(FEDERATION_CONTENT_TYPE.to_string()),
+            href: Some(url),
+            properties,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Adds another Url which identifies the same actor, such as a profile page under a
+    /// different path or an account the actor has moved from.
+    pub fn with_alias(mut self, url: Url) -> Self {
+        self.aliases.push(url);
+        self
+    }
+
+    /// Adds a link for remote follows. The `template` is a Url containing `{uri}`, which is
+    /// replaced with the object to interact with, for example
+    /// `https://lemmy.ml/authorize_interaction?uri={uri}`.
+    pub fn with_subscribe_template(mut self, template: impl Into<String>) -> Self {
+        self.links.push(WebfingerLink {
+            rel: Some(SUBSCRIBE_REL.to_string()),
+            template: Some(template.into()),
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Returns the finished response
+    pub fn build(self) -> Webfinger {
+        Webfinger {
+            subject: self.subject,
+            links: self.links,
+            aliases: self.aliases,
+            properties: Default::default(),
+        }
     }
 }
 
@@ -232,26 +658,62 @@ pub struct Webfinger {
     /// Other Urls which identify the same actor as the `subject`
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub aliases: Vec<Url>,
-    /// Additional data about the subject
-    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub properties: HashMap<Url, String>,
+    /// Additional data about the subject, see [WebfingerLink::properties]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_properties",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub properties: HashMap<Url, Option<String>>,
 }
 
 /// A single link included as part of a [Webfinger] response.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct WebfingerLink {
     /// Relationship of the link, such as `self` or `http://webfinger.net/rel/profile-page`
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rel: Option<String>,
     /// Media type of the target resource
-    #[serde(rename = "type")]
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
     pub kind: Option<String>,
     /// Url pointing to the target resource
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub href: Option<Url>,
-    /// Used for remote follow external interaction url
+    /// Used for remote follow external interaction url, see [fetch_interaction_template]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub template: Option<String>,
-    /// Additional data about the link
-    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub properties: HashMap<Url, String>,
+    /// Additional data about the link. Values are strings or null, as in
+    /// [RFC 7033](https://www.rfc-editor.org/rfc/rfc7033#section-4.4.4). When parsing, entries
+    /// with other values or with keys that aren't urls are skipped.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_properties",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub properties: HashMap<Url, Option<String>>,
+}
+
+/// Some implementations send properties with numbers or other values, which would otherwise make
+/// the whole webfinger response unparseable and the actor unresolvable.
+fn deserialize_properties<'de, D>(deserializer: D) -> Result<HashMap<Url, Option<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Value::Object(properties) = Value::deserialize(deserializer)? else {
+        return Ok(HashMap::new());
+    };
+    let properties = properties
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => Some(value),
+                Value::Null => None,
+                _ => return None,
+            };
+            Some((key.parse().ok()?, value))
+        })
+        .collect();
+    Ok(properties)
 }
 
 #[cfg(test)]
@@ -260,8 +722,81 @@ mod tests {
     use super::*;
     use crate::{
         config::FederationConfig,
-        traits::tests::{DbConnection, DbUser},
+        traits::tests::{debug_data, serve_router, DbConnection, DbUser, DB_USER},
     };
+    use axum::{extract::Query, routing::get, Json, Router};
+    use http::HeaderMap;
+    use serde_json::{json, Value};
+
+    /// Webfinger endpoint which requires signed requests, and only knows the user `alice`
+    async fn signed_webfinger(
+        headers: HeaderMap,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        if !headers.contains_key("Signature") {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let resource = &query["resource"];
+        if !resource.starts_with("acct:alice@") {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(Json(json!({
+            "subject": resource,
+            "links": [{
+                "rel": "self",
+                "type": "application/activity+json",
+                "href": "https://localhost/123",
+            }]
+        })))
+    }
+
+    async fn signed_webfinger_config(
+        domain: &str,
+        signed: bool,
+    ) -> Result<Data<DbConnection>, Error> {
+        let mut config = FederationConfig::builder();
+        config.domain(domain).app_data(DbConnection).debug(true);
+        if signed {
+            config.signed_fetch_actor(&*DB_USER);
+        }
+        Ok(config.build().await.unwrap().to_request_data())
+    }
+
+    #[tokio::test]
+    async fn test_webfinger_unauthorized() -> Result<(), Error> {
+        let base =
+            serve_router(|_| Router::new().route("/.well-known/webfinger", get(signed_webfinger)))
+                .await;
+        let host = format!("localhost:{}", base.port().unwrap());
+
+        // Without signed fetch actor the lookup fails with a distinct error
+        let data = signed_webfinger_config("example.com", false).await?;
+        let res =
+            webfinger_resolve_actor::<DbConnection, DbUser>(&format!("alice@{host}"), &data).await;
+        assert!(matches!(
+            res,
+            Err(Error::WebfingerResolveFailed(WebFingerError::Unauthorized))
+        ));
+        assert_eq!(data.request_count(), 1);
+
+        // With signed fetch actor, the lookup is retried with signature
+        let data = signed_webfinger_config("example.com", true).await?;
+        let resolved = webfinger_resolve_actor_with_meta::<DbConnection, DbUser>(
+            &format!("alice@{host}"),
+            &data,
+        )
+        .await?;
+        assert_eq!(resolved.actor.federation_id, DB_USER.federation_id);
+        assert_eq!(data.request_count(), 2);
+
+        let res =
+            webfinger_resolve_actor::<DbConnection, DbUser>(&format!("bob@{host}"), &data).await;
+        assert!(matches!(
+            res,
+            Err(Error::WebfingerResolveFailed(WebFingerError::NotFound))
+        ));
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_webfinger() -> Result<(), Error> {
@@ -287,7 +822,12 @@ mod tests {
                 .build()
                 .await
                 .unwrap(),
+            deadline: None,
             request_counter: Default::default(),
+            fetched_objects: Default::default(),
+            prefetched_objects: Default::default(),
+            received_activity: Default::default(),
+            signed_fetch_actor: Default::default(),
         };
         assert_eq!(
             Ok("test123"),
@@ -311,4 +851,762 @@ mod tests {
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_webfinger_idn() -> Result<(), Error> {
+        let data = FederationConfig::builder()
+            .domain("bücher.example")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        assert_eq!(
+            Ok("user"),
+            extract_webfinger_name("acct:user@bücher.example", &data)
+        );
+        assert_eq!(
+            Ok("user"),
+            extract_webfinger_name("acct:user@xn--bcher-kva.example", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongDomain.into()),
+            extract_webfinger_name("acct:user@bucher.example", &data)
+        );
+
+        let url = webfinger_url("user@bücher.example", &data)?;
+        assert_eq!(Some("xn--bcher-kva.example"), url.domain());
+        let resource = url.query_pairs().find(|(k, _)| k == "resource").unwrap().1;
+        assert_eq!("acct:user@bücher.example", resource);
+
+        assert!(webfinger_url("user@bücher.example/path", &data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_webfinger_response_builder() {
+        let webfinger = WebfingerResponseBuilder::new("acct:alice@example.com".to_string())
+            .with_actor(
+                "https://example.com/u/alice".parse().unwrap(),
+                Some("Person"),
+            )
+            .with_alias("https://old.example.com/u/alice".parse().unwrap())
+            .with_subscribe_template("https://example.com/interact?uri={uri}")
+            .build();
+        assert_eq!(
+            serde_json::to_value(webfinger).unwrap(),
+            json!({
+                "subject": "acct:alice@example.com",
+                "links": [
+                    {
+                        "rel": "http://webfinger.net/rel/profile-page",
+                        "type": "text/html",
+                        "href": "https://example.com/u/alice"
+                    },
+                    {
+                        "rel": "self",
+                        "type": "application/activity+json",
+                        "href": "https://example.com/u/alice",
+                        "properties": {
+                            "https://www.w3.org/ns/activitystreams#type": "Person"
+                        }
+                    },
+                    {
+                        "rel": "http://ostatus.org/schema/1.0/subscribe",
+                        "template": "https://example.com/interact?uri={uri}"
+                    }
+                ],
+                "aliases": ["https://old.example.com/u/alice"]
+            })
+        );
+
+        // The old functions produce the same links
+        let webfinger = build_webfinger_response_with_type(
+            "acct:alice@example.com".to_string(),
+            vec![("https://example.com/u/alice".parse().unwrap(), None)],
+        );
+        assert_eq!(2, webfinger.links.len());
+        assert!(webfinger.aliases.is_empty());
+        assert!(webfinger.links[1].properties.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_parse_webfinger_query() -> Result<(), Error> {
+        let data = FederationConfig::builder()
+            .domain("localhost:8001")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let expected = |name| WebfingerQuery {
+            name,
+            domain: "localhost:8001".to_string(),
+        };
+
+        // Port and case
+        assert_eq!(
+            expected("user"),
+            parse_webfinger_query("acct:user@localhost:8001", &data)?
+        );
+        assert_eq!(
+            expected("User"),
+            parse_webfinger_query("acct:User@LocalHost:8001", &data)?
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongDomain.into()),
+            parse_webfinger_query("acct:user@localhost:8002", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongDomain.into()),
+            parse_webfinger_query("acct:user@localhost", &data)
+        );
+
+        // Url form is only accepted by parse_webfinger_resource
+        assert_eq!(
+            Err(WebFingerError::WrongFormat.into()),
+            parse_webfinger_query("http://localhost:8001/u/user", &data)
+        );
+        let actor = |id: &str| Ok(WebfingerResource::Actor(Url::parse(id).unwrap()));
+        assert_eq!(
+            actor("http://localhost:8001/users/12345"),
+            parse_webfinger_resource("http://localhost:8001/users/12345", &data)
+        );
+        assert_eq!(
+            actor("https://localhost:8001/users/user/?x=1#y"),
+            parse_webfinger_resource("https://LOCALHOST:8001/users/user/?x=1#y", &data)
+        );
+        assert_eq!(
+            Ok(WebfingerResource::Account(expected("User"))),
+            parse_webfinger_resource("acct:User@localhost:8001", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongFormat.into()),
+            parse_webfinger_resource("mailto:user@localhost:8001", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongFormat.into()),
+            parse_webfinger_resource("user@localhost:8001", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongDomain.into()),
+            parse_webfinger_resource("https://example.com/u/user", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongDomain.into()),
+            parse_webfinger_resource("acct:user@example.com", &data)
+        );
+
+        // Unicode names
+        assert_eq!(
+            expected("Владимир"),
+            parse_webfinger_query("acct:Владимир@localhost:8001", &data)?
+        );
+        assert_eq!(
+            expected("تجريب"),
+            parse_webfinger_query("acct:تجريب@localhost:8001", &data)?
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongFormat.into()),
+            parse_webfinger_query("acct:us er@localhost:8001", &data)
+        );
+        assert_eq!(
+            Err(WebFingerError::WrongFormat.into()),
+            parse_webfinger_query("acct:user", &data)
+        );
+        Ok(())
+    }
+
+    fn link(rel: &str, kind: &str, href: &str) -> WebfingerLink {
+        WebfingerLink {
+            rel: Some(rel.to_string()),
+            kind: Some(kind.to_string()),
+            href: Some(href.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_select_links() {
+        let links = vec![
+            link(
+                "http://webfinger.net/rel/profile-page",
+                "text/html",
+                "https://example.com/@alice",
+            ),
+            link("lrdd", "application/xrd+xml", "https://example.com/xrd"),
+            link("alternate", "application/json", "https://example.com/json"),
+            link(
+                "self",
+                "application/activity+json",
+                "https://example.com/u/alice",
+            ),
+        ];
+        assert_eq!(
+            vec!["https://example.com/u/alice", "https://example.com/json"],
+            selected_hrefs(links, None)
+        );
+    }
+
+    fn selected_hrefs(links: Vec<WebfingerLink>, actor_type: Option<&str>) -> Vec<String> {
+        select_links(links, actor_type)
+            .into_iter()
+            .filter_map(|l| l.href.map(|h| h.to_string()))
+            .collect()
+    }
+
+    fn fixture_links(webfinger: Value) -> Vec<WebfingerLink> {
+        serde_json::from_value::<Webfinger>(webfinger)
+            .unwrap()
+            .links
+    }
+
+    #[test]
+    fn test_select_links_mastodon() {
+        let links = fixture_links(json!({
+            "subject": "acct:Gargron@mastodon.social",
+            "aliases": [
+                "https://mastodon.social/@Gargron",
+                "https://mastodon.social/users/Gargron"
+            ],
+            "links": [
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": "https://mastodon.social/@Gargron"
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://mastodon.social/users/Gargron"
+                },
+                {
+                    "rel": "http://ostatus.org/schema/1.0/subscribe",
+                    "template": "https://mastodon.social/authorize_interaction?uri={uri}"
+                },
+                {
+                    "rel": "http://webfinger.net/rel/avatar",
+                    "type": "image/png",
+                    "href": "https://files.mastodon.social/accounts/avatars/000/000/001/original/a.png"
+                }
+            ]
+        }));
+        assert_eq!(
+            vec!["https://mastodon.social/users/Gargron"],
+            selected_hrefs(links.clone(), None)
+        );
+        // Links without type property are used for any actor type
+        assert_eq!(
+            vec!["https://mastodon.social/users/Gargron"],
+            selected_hrefs(links, Some("Group"))
+        );
+    }
+
+    #[test]
+    fn test_select_links_lemmy() {
+        // User and community with the same name
+        let links = fixture_links(json!({
+            "subject": "acct:lemmy@lemmy.ml",
+            "links": [
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": "https://lemmy.ml/u/lemmy",
+                    "template": null
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://lemmy.ml/u/lemmy",
+                    "template": null,
+                    "properties": {
+                        "https://www.w3.org/ns/activitystreams#type": "Person"
+                    }
+                },
+                {
+                    "rel": "http://ostatus.org/schema/1.0/subscribe",
+                    "type": null,
+                    "href": null,
+                    "template": "https://lemmy.ml/activitypub/externalInteraction?uri={uri}"
+                },
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": "https://lemmy.ml/c/lemmy",
+                    "template": null
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://lemmy.ml/c/lemmy",
+                    "template": null,
+                    "properties": {
+                        "https://www.w3.org/ns/activitystreams#type": "Group"
+                    }
+                }
+            ]
+        }));
+        let user = "https://lemmy.ml/u/lemmy";
+        let community = "https://lemmy.ml/c/lemmy";
+        assert_eq!(vec![user, community], selected_hrefs(links.clone(), None));
+        assert_eq!(
+            vec![user, community],
+            selected_hrefs(links.clone(), Some("Person"))
+        );
+        assert_eq!(vec![community, user], selected_hrefs(links, Some("group")));
+    }
+
+    #[test]
+    fn test_select_links_friendica() {
+        let links = fixture_links(json!({
+            "subject": "acct:heluecht@pirati.ca",
+            "aliases": ["https://pirati.ca/~heluecht", "https://pirati.ca/profile/heluecht"],
+            "links": [
+                {
+                    "rel": "http://purl.org/macgirvin/dfrn/1.0",
+                    "href": "https://pirati.ca/profile/heluecht"
+                },
+                {
+                    "rel": "http://schemas.google.com/g/2010#updates-from",
+                    "type": "application/atom+xml",
+                    "href": "https://pirati.ca/dfrn_poll/heluecht"
+                },
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": "https://pirati.ca/profile/heluecht"
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://pirati.ca/profile/heluecht"
+                },
+                {
+                    "rel": "http://microformats.org/profile/hcard",
+                    "type": "text/html",
+                    "href": "https://pirati.ca/hcard/heluecht"
+                },
+                {
+                    "rel": "salmon",
+                    "href": "https://pirati.ca/salmon/heluecht"
+                },
+                {
+                    "rel": "http://ostatus.org/schema/1.0/subscribe",
+                    "template": "https://pirati.ca/follow?url={uri}"
+                },
+                {
+                    "rel": "http://purl.org/openwebauth/v1",
+                    "type": "application/x-zot+json",
+                    "href": "https://pirati.ca/owa"
+                }
+            ]
+        }));
+        assert_eq!(
+            vec![
+                "https://pirati.ca/profile/heluecht",
+                "https://pirati.ca/owa"
+            ],
+            selected_hrefs(links, None)
+        );
+    }
+
+    #[test]
+    fn test_webfinger_properties() {
+        // Friendica sends null properties, others sometimes send numbers
+        let webfinger: Webfinger = serde_json::from_value(json!({
+            "subject": "acct:heluecht@pirati.ca",
+            "properties": {
+                "http://schema.org/name": "Michael",
+                "http://purl.org/zot/protocol/6.0": null,
+                "http://example.com/followers": 42,
+                "not a url": "value"
+            },
+            "links": [
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": "https://pirati.ca/profile/heluecht",
+                    "properties": {
+                        "https://www.w3.org/ns/activitystreams#type": null,
+                        "http://example.com/priority": 1.5,
+                        "http://example.com/tags": ["a"]
+                    }
+                },
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "href": "https://pirati.ca/~heluecht",
+                    "properties": null
+                }
+            ]
+        }))
+        .unwrap();
+
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert_eq!(
+            HashMap::from([
+                (url("http://schema.org/name"), Some("Michael".to_string())),
+                (url("http://purl.org/zot/protocol/6.0"), None),
+            ]),
+            webfinger.properties
+        );
+        assert_eq!(
+            HashMap::from([(url(ACTIVITYSTREAMS_TYPE), None)]),
+            webfinger.links[0].properties
+        );
+        assert!(webfinger.links[1].properties.is_empty());
+
+        // A null type is treated like a missing type, so the actor can still be resolved
+        assert_eq!(
+            vec!["https://pirati.ca/profile/heluecht"],
+            selected_hrefs(webfinger.links.clone(), Some("Person"))
+        );
+
+        // Only strings and null are serialized
+        let json = serde_json::to_value(&webfinger).unwrap();
+        assert_eq!(
+            json!({
+                "https://www.w3.org/ns/activitystreams#type": null
+            }),
+            json["links"][0]["properties"]
+        );
+        assert_eq!(
+            Value::Null,
+            json["properties"]["http://purl.org/zot/protocol/6.0"]
+        );
+    }
+
+    #[test]
+    fn test_webfinger_link_priority() {
+        let priority = |rel: &str, kind: &str| {
+            webfinger_link_priority(&link(rel, kind, "https://example.com/u/alice"), None)
+        };
+        let ld_json = r#"application/ld+json; profile="https://www.w3.org/ns/activitystreams""#;
+        assert_eq!(Some(0), priority("self", "application/activity+json"));
+        assert_eq!(Some(0), priority("self", ld_json));
+        assert_eq!(Some(0), priority("self", "Application/Activity+JSON"));
+        assert_eq!(Some(1), priority("alternate", ld_json));
+        // Without the activitystreams profile this is generic JSON-LD
+        assert_eq!(Some(2), priority("self", "application/ld+json"));
+        assert_eq!(Some(2), priority("self", "application/json"));
+        assert_eq!(None, priority("self", "application/atom+xml"));
+        assert_eq!(None, priority("self", "text/html"));
+
+        let mut typed = link(
+            "self",
+            FEDERATION_CONTENT_TYPE,
+            "https://example.com/c/main",
+        );
+        typed.properties.insert(
+            ACTIVITYSTREAMS_TYPE.parse().unwrap(),
+            Some("Group".to_string()),
+        );
+        assert_eq!(Some(0), webfinger_link_priority(&typed, Some("Group")));
+        assert_eq!(Some(0), webfinger_link_priority(&typed, None));
+        let untyped = link(
+            "self",
+            FEDERATION_CONTENT_TYPE,
+            "https://example.com/u/alice",
+        );
+        assert_eq!(Some(3), webfinger_link_priority(&untyped, Some("Group")));
+        assert_eq!(Some(6), webfinger_link_priority(&typed, Some("Person")));
+    }
+
+    #[cfg(all(feature = "axum", feature = "example-storage"))]
+    #[tokio::test]
+    async fn test_webfinger_prefers_self_link() -> Result<(), Error> {
+        use crate::{
+            axum::json::FederationJson,
+            example_storage::{self, InMemoryStorage},
+            traits::Object,
+        };
+        use axum::{routing::get, Json, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let ap_id = Url::parse(&format!("http://{domain}/u/alice"))?;
+        let inbox = Url::parse(&format!("http://{domain}/u/alice/inbox"))?;
+        let user = example_storage::DbUser::new("alice", ap_id.clone(), inbox)?;
+
+        let remote = FederationConfig::builder()
+            .domain(domain.clone())
+            .app_data(InMemoryStorage::default())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let person = serde_json::to_value(user.into_json(&remote).await?).unwrap();
+        // Links in unfavorable order, only the last one points to the actor
+        let webfinger = serde_json::to_value(Webfinger {
+            subject: format!("acct:alice@{domain}"),
+            links: vec![
+                link(
+                    "http://webfinger.net/rel/profile-page",
+                    "text/html",
+                    &format!("http://{domain}/@alice"),
+                ),
+                link(
+                    "lrdd",
+                    "application/xrd+xml",
+                    &format!("http://{domain}/xrd"),
+                ),
+                link(
+                    "alternate",
+                    "application/json",
+                    &format!("http://{domain}/json"),
+                ),
+                link("self", FEDERATION_CONTENT_TYPE, ap_id.as_str()),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        let app = Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(move || {
+                    let webfinger = webfinger.clone();
+                    async move { Json(webfinger) }
+                }),
+            )
+            .route(
+                "/u/alice",
+                get(move || {
+                    let person = person.clone();
+                    async move { FederationJson(person) }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(InMemoryStorage::default())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let resolved: WebfingerResolved<example_storage::DbUser> =
+            webfinger_resolve_actor_with_meta(&format!("alice@{domain}"), &data).await?;
+        assert_eq!("alice", resolved.actor.name);
+        assert_eq!(Some("self"), resolved.link.rel.as_deref());
+        assert_eq!(Some(FEDERATION_CONTENT_TYPE), resolved.link.kind.as_deref());
+        assert_eq!(1, data.object_fetch_count());
+        Ok(())
+    }
+
+    #[cfg(all(feature = "axum", feature = "example-storage"))]
+    #[tokio::test]
+    async fn test_webfinger_request_counters() -> Result<(), Error> {
+        use crate::{
+            axum::json::FederationJson,
+            example_storage::{self, InMemoryStorage},
+            traits::Object,
+        };
+        use axum::{routing::get, Json, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let ap_id = Url::parse(&format!("http://{domain}/u/alice"))?;
+        let inbox = Url::parse(&format!("http://{domain}/u/alice/inbox"))?;
+        let user = example_storage::DbUser::new("alice", ap_id.clone(), inbox)?;
+
+        let remote = FederationConfig::builder()
+            .domain(domain.clone())
+            .app_data(InMemoryStorage::default())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let person = serde_json::to_value(user.into_json(&remote).await?).unwrap();
+        let webfinger = serde_json::to_value(build_webfinger_response(
+            format!("acct:alice@{domain}"),
+            ap_id,
+        ))
+        .unwrap();
+        let app = Router::new()
+            .route(
+                "/.well-known/webfinger",
+                get(move || {
+                    let webfinger = webfinger.clone();
+                    async move { Json(webfinger) }
+                }),
+            )
+            .route(
+                "/u/alice",
+                get(move || {
+                    let person = person.clone();
+                    async move { FederationJson(person) }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(InMemoryStorage::default())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let resolved: example_storage::DbUser =
+            webfinger_resolve_actor(&format!("alice@{domain}"), &data).await?;
+        assert_eq!("alice", resolved.name);
+        assert_eq!(2, data.request_count());
+        assert_eq!(1, data.webfinger_count());
+        assert_eq!(1, data.object_fetch_count());
+        assert_eq!(0, data.collection_fetch_count());
+
+        // The second lookup uses the cached webfinger link and the stored actor
+        let resolved: example_storage::DbUser =
+            webfinger_resolve_actor(&format!("alice@{domain}"), &data).await?;
+        assert_eq!("alice", resolved.name);
+        assert_eq!(2, data.request_count());
+        assert_eq!(1, data.webfinger_count());
+
+        // The cached link of another actor type with the same name is not used
+        let identifier = format!("alice@{domain}");
+        data.config
+            .webfinger_cache
+            .invalidate(&(identifier.clone(), None))
+            .await;
+        data.config
+            .webfinger_cache
+            .insert(
+                (identifier.clone(), Some("Group")),
+                link("self", FEDERATION_CONTENT_TYPE, "http://localhost/c/alice"),
+            )
+            .await;
+        let resolved: example_storage::DbUser = webfinger_resolve_actor(&identifier, &data).await?;
+        assert_eq!("alice", resolved.name);
+        assert_eq!(2, data.webfinger_count());
+
+        // Without cache, webfinger is requested again
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(data.app_data().clone())
+            .debug(true)
+            .webfinger_cache_ttl(Duration::ZERO)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        for _ in 0..2 {
+            let _: example_storage::DbUser =
+                webfinger_resolve_actor(&format!("alice@{domain}"), &data).await?;
+        }
+        assert_eq!(2, data.webfinger_count());
+        assert_eq!(0, data.object_fetch_count());
+        Ok(())
+    }
+
+    /// Webfinger response of Mastodon for `alice@{host}`, with the subscribe link pointing to
+    /// `template`
+    fn mastodon_webfinger(host: &str, template: &str) -> Value {
+        json!({
+            "subject": format!("acct:alice@{host}"),
+            "aliases": [
+                format!("http://{host}/@alice"),
+                format!("http://{host}/users/alice")
+            ],
+            "links": [
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": format!("http://{host}/@alice")
+                },
+                {
+                    "rel": "self",
+                    "type": "application/activity+json",
+                    "href": format!("http://{host}/users/alice")
+                },
+                {
+                    "rel": "http://ostatus.org/schema/1.0/subscribe",
+                    "template": template.replace("HOST", host)
+                },
+                {
+                    "rel": "http://webfinger.net/rel/avatar",
+                    "type": "image/png",
+                    "href": format!("http://{host}/system/accounts/avatars/1/original/a.png")
+                }
+            ]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fetch_interaction_template() -> Result<(), Error> {
+        let base = serve_router(|_| {
+            Router::new().route(
+                "/.well-known/webfinger",
+                get(|Query(query): Query<HashMap<String, String>>| async move {
+                    let resource = &query["resource"];
+                    let (name, host) = resource
+                        .trim_start_matches("acct:")
+                        .split_once('@')
+                        .unwrap();
+                    let template = match name {
+                        "alice" => "http://HOST/authorize_interaction?uri={uri}",
+                        "bob" => "http://HOST/authorize_interaction",
+                        "carol" => "http://evil.example/authorize_interaction?uri={uri}",
+                        _ => return Json(json!({ "subject": resource, "links": [] })),
+                    };
+                    Json(mastodon_webfinger(host, template))
+                }),
+            )
+        })
+        .await;
+        let host = format!("localhost:{}", base.port().unwrap());
+        let data = debug_data().await;
+
+        let template = fetch_interaction_template(&format!("alice@{host}"), &data).await?;
+        let template = template.unwrap();
+        assert_eq!(
+            template,
+            format!("http://{host}/authorize_interaction?uri={{uri}}")
+        );
+        let target = Url::parse("https://example.com/post/1?x=1&y=2#z")?;
+        let url = apply_interaction_template(&template, &target)?;
+        assert_eq!(url.host_str(), Some("localhost"));
+        assert_eq!(url.path(), "/authorize_interaction");
+        let params: Vec<_> = url.query_pairs().collect();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].1, target.as_str());
+
+        // No subscribe link
+        let template = fetch_interaction_template(&format!("dave@{host}"), &data).await?;
+        assert_eq!(template, None);
+
+        // Template without placeholder, or on another domain
+        for name in ["bob", "carol"] {
+            let res = fetch_interaction_template(&format!("{name}@{host}"), &data).await;
+            assert!(matches!(res, Err(Error::UrlVerificationError(_))));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_interaction_template() -> Result<(), Error> {
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let webfinger = Url::parse("https://mastodon.example/.well-known/webfinger")?;
+        let verify = |template| verify_interaction_template(template, &webfinger, &data).is_ok();
+
+        assert!(verify(
+            "https://mastodon.example/authorize_interaction?uri={uri}"
+        ));
+        assert!(!verify(
+            "http://mastodon.example/authorize_interaction?uri={uri}"
+        ));
+        assert!(!verify("https://mastodon.example/authorize_interaction"));
+        assert!(!verify(
+            "https://other.example/authorize_interaction?uri={uri}"
+        ));
+        assert!(!verify("{uri}"));
+        Ok(())
+    }
 }
@@ -4,36 +4,59 @@
 //! [send_activity](crate::activity_sending::SendActivityTask::sign_and_send) and
 //! [receive_activity (actix-web)](crate::actix_web::inbox::receive_activity) /
 //! [receive_activity (axum)](crate::axum::inbox::receive_activity).
+//!
+//! Keys are generated, and signatures created and verified, with the pure-Rust `rsa` and `sha2`
+//! crates (RSA PKCS#1 v1.5 with SHA-256). There is no dependency on openssl, so the library can be
+//! built for static musl targets without additional setup.
 
 use crate::{
+    activity_sending::generate_request_headers,
     config::Data,
     error::{Error, Error::ActivitySignatureInvalid},
     fetch::object_id::ObjectId,
-    protocol::public_key::main_key_id,
+    protocol::public_key::KeyIdStrategy,
     traits::{Actor, Object},
 };
 use base64::{engine::general_purpose::STANDARD as Base64, Engine};
 use bytes::Bytes;
-use http::{header::HeaderName, uri::PathAndQuery, HeaderValue, Method, Uri};
-use http_signature_normalization_reqwest::{
-    prelude::{Config, SignExt},
-    DefaultSpawner,
+use http::{
+    header::{HeaderName, DATE},
+    uri::PathAndQuery,
+    HeaderMap,
+    HeaderValue,
+    Method,
+    Uri,
 };
+use httpdate::fmt_http_date;
 use once_cell::sync::Lazy;
 use reqwest::Request;
 use reqwest_middleware::RequestBuilder;
 use rsa::{
-    pkcs8::{DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
     Pkcs1v15Sign,
     RsaPrivateKey,
     RsaPublicKey,
 };
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
-use std::{collections::BTreeMap, fmt::Debug, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::{Semaphore, SemaphorePermit};
 use tracing::debug;
 use url::Url;
 
+mod rfc9421;
+
+pub(crate) use rfc9421::split_top_level;
+
 /// A private/public key pair used for HTTP signatures
 #[derive(Debug, Clone)]
 pub struct Keypair {
@@ -44,11 +67,9 @@ pub struct Keypair {
 }
 
 impl Keypair {
-    /// Helper method to turn this into an openssl private key
+    /// Helper method to turn this into an rsa private key
     #[cfg(test)]
     pub(crate) fn private_key(&self) -> Result<RsaPrivateKey, anyhow::Error> {
-        use rsa::pkcs8::DecodePrivateKey;
-
         Ok(RsaPrivateKey::from_pkcs8_pem(&self.private_key)?)
     }
 }
@@ -73,66 +94,523 @@ pub fn generate_actor_keypair() -> Result<Keypair, Error> {
 /// to avoid any potential problems due to wrong clocks, overloaded servers or delayed delivery.
 pub(crate) const EXPIRES_AFTER: Duration = Duration::from_secs(60 * 60);
 
+/// Limits the number of concurrent signing operations and records how long they take.
+///
+/// RSA operations run on the blocking thread pool of tokio, so that they don't block the async
+/// executor. During a large fan-out this could use up the blocking pool which the application also
+/// needs, so the concurrency can be limited with
+/// [FederationConfigBuilder::max_concurrent_signatures](crate::config::FederationConfigBuilder::max_concurrent_signatures).
+#[derive(Default)]
+pub(crate) struct SigningLimiter {
+    semaphore: Option<Semaphore>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    sign_time: TimingStats,
+    key_parse_time: TimingStats,
+}
+
+impl SigningLimiter {
+    /// Create a new limiter. `0` means that there is no limit.
+    pub(crate) fn new(max_concurrent: usize) -> Self {
+        SigningLimiter {
+            semaphore: (max_concurrent > 0).then(|| Semaphore::new(max_concurrent)),
+            ..Default::default()
+        }
+    }
+
+    /// Parse a private key in PEM format. This is an expensive blocking call, so it runs on the
+    /// blocking thread pool.
+    pub(crate) async fn parse_private_key(&self, pem: String) -> Result<RsaPrivateKey, Error> {
+        let _guard = self.acquire().await;
+        let (pkey, elapsed) = tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let pkey =
+                RsaPrivateKey::from_pkcs8_pem(&pem).map_err(|err| Error::KeyParse(err.to_string()));
+            (pkey, start.elapsed())
+        })
+        .await
+        .map_err(|err| Error::Other(format!("Error joining: {err}")))?;
+        self.key_parse_time.record(elapsed);
+        pkey
+    }
+
+    async fn acquire(&self) -> InFlightGuard<'_> {
+        let permit = match &self.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("signing semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        InFlightGuard {
+            in_flight: &self.in_flight,
+            _permit: permit,
+        }
+    }
+
+    /// Highest number of signing operations which were running at the same time
+    #[cfg(test)]
+    pub(crate) fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn metrics(&self) -> SigningMetrics {
+        SigningMetrics {
+            sign_count: self.sign_time.count(),
+            avg_sign_time: self.sign_time.average(),
+            p50_sign_time: self.sign_time.percentile(50),
+            p99_sign_time: self.sign_time.percentile(99),
+            key_parse_count: self.key_parse_time.count(),
+            avg_key_parse_time: self.key_parse_time.average(),
+        }
+    }
+}
+
+impl Debug for SigningLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let metrics = self.metrics();
+        write!(
+            f,
+            "Signing stats: signatures: {}, avg: {:?}, p50: {:?}, p99: {:?}, key parses: {}, avg: {:?}",
+            metrics.sign_count,
+            metrics.avg_sign_time,
+            metrics.p50_sign_time,
+            metrics.p99_sign_time,
+            metrics.key_parse_count,
+            metrics.avg_key_parse_time
+        )
+    }
+}
+
+/// Counts a running signing operation, and releases the semaphore permit when dropped.
+struct InFlightGuard<'a> {
+    in_flight: &'a AtomicUsize,
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Number of histogram buckets, each bucket `i` counts durations below `2^(i+1)` microseconds.
+const TIMING_BUCKETS: usize = 32;
+
+/// Lock-free recording of durations
+#[derive(Default)]
+struct TimingStats {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    buckets: [AtomicU64; TIMING_BUCKETS],
+}
+
+impl TimingStats {
+    fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.saturating_sub(1).min(TIMING_BUCKETS - 1)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn average(&self) -> Duration {
+        let total = self.total_micros.load(Ordering::Relaxed);
+        Duration::from_micros(total.checked_div(self.count()).unwrap_or(0))
+    }
+
+    /// Upper bound of the bucket which contains the given percentile, so the result is at most
+    /// twice the actual value.
+    fn percentile(&self, percentile: u64) -> Duration {
+        let target = (self.count() * percentile).div_ceil(100);
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target && seen > 0 {
+                return Duration::from_micros(1 << (i + 1));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// Timing of signing operations, see [Data::signing_metrics](crate::config::Data::signing_metrics).
+///
+/// Percentiles are approximated and may be up to twice as high as the actual value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SigningMetrics {
+    /// Number of HTTP signatures which were created
+    pub sign_count: u64,
+    /// Average time to create an HTTP signature, including the body digest
+    pub avg_sign_time: Duration,
+    /// Median time to create an HTTP signature
+    pub p50_sign_time: Duration,
+    /// 99th percentile of the time to create an HTTP signature
+    pub p99_sign_time: Duration,
+    /// Number of private keys which were parsed. Keys are cached, so this only happens once per
+    /// actor.
+    pub key_parse_count: u64,
+    /// Average time to parse a private key
+    pub avg_key_parse_time: Duration,
+}
+
 /// Creates an HTTP post request to `inbox_url`, with the given `client` and `headers`, and
 /// `activity` as request body. The request is signed with `private_key` and then sent.
+///
+/// With `rfc9421` the request is signed according to RFC 9421, otherwise with draft-cavage
+/// signatures. The digest and signature are calculated on the blocking thread pool, limited by
+/// `limiter`. With `both_digest_headers` the request contains both the legacy `Digest` and the
+/// `Content-Digest` header from RFC 9530, otherwise only the one which belongs to the signature
+/// scheme.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn sign_request(
     request_builder: RequestBuilder,
-    actor_id: &Url,
+    key_id: String,
     activity: Bytes,
     private_key: RsaPrivateKey,
     http_signature_compat: bool,
+    rfc9421: bool,
+    both_digest_headers: bool,
+    limiter: &Arc<SigningLimiter>,
 ) -> Result<Request, Error> {
-    static CONFIG: Lazy<Config<DefaultSpawner>> =
-        Lazy::new(|| Config::new().set_expiration(EXPIRES_AFTER));
-    static CONFIG_COMPAT: Lazy<Config> = Lazy::new(|| {
-        Config::new()
+    if rfc9421 {
+        let request_builder = match both_digest_headers {
+            true => request_builder.header("digest", digest_header(&activity)),
+            false => request_builder,
+        };
+        return sign_request_rfc9421(request_builder, key_id, activity, private_key, limiter).await;
+    }
+    let request_builder = match both_digest_headers {
+        true => request_builder.header("content-digest", content_digest_header(&activity)),
+        false => request_builder,
+    };
+
+    let mut request = request_builder.body(activity.clone()).build()?;
+    let method = request.method().clone();
+    let url = request.url().clone();
+    let mut headers = request.headers().clone();
+
+    let _guard = limiter.acquire().await;
+    let signing_limiter = limiter.clone();
+    let headers = tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let res = sign_headers(
+            &url,
+            &method,
+            &mut headers,
+            &activity,
+            key_id,
+            &private_key,
+            http_signature_compat,
+        );
+        signing_limiter.sign_time.record(start.elapsed());
+        res.map(|()| headers)
+    })
+    .await
+    .map_err(|e| Error::Other(e.to_string()))??;
+    *request.headers_mut() = headers;
+    Ok(request)
+}
+
+async fn sign_request_rfc9421(
+    request_builder: RequestBuilder,
+    key_id: String,
+    activity: Bytes,
+    private_key: RsaPrivateKey,
+    limiter: &Arc<SigningLimiter>,
+) -> Result<Request, Error> {
+    let mut request = request_builder.body(activity.clone()).build()?;
+    let method = request.method().clone();
+    let url = request.url().clone();
+    let mut headers = request.headers().clone();
+
+    let _guard = limiter.acquire().await;
+    let signing_limiter = limiter.clone();
+    let headers = tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let res = rfc9421::sign_headers(
+            &method,
+            &url,
+            &mut headers,
+            &activity,
+            &key_id,
+            &private_key,
+        );
+        signing_limiter.sign_time.record(start.elapsed());
+        res.map(|_| headers)
+    })
+    .await
+    .map_err(|e| Error::Other(e.to_string()))??;
+    *request.headers_mut() = headers;
+    Ok(request)
+}
+
+/// Signs the string with RSA PKCS#1 v1.5 and SHA-256, and returns the signature as base64.
+pub(crate) fn sign_string(
+    private_key: &RsaPrivateKey,
+    signing_string: &str,
+) -> Result<String, Error> {
+    let signature = private_key.sign(
+        Pkcs1v15Sign::new::<Sha256>(),
+        &Sha256::digest(signing_string.as_bytes()),
+    )?;
+    Ok(Base64.encode(signature))
+}
+
+/// Returns the `Date`, `Host`, `Content-Type`, `Digest` and `Signature` headers which are sent
+/// when delivering `body` to `target`, signed with the key of `actor_id`. The key id is created
+/// with `key_id_strategy`, which should be the one from
+/// [FederationConfigBuilder::key_id_strategy](crate::config::FederationConfigBuilder::key_id_strategy).
+///
+/// This is the same as the headers created by [SendActivityTask::sign_and_send](crate::activity_sending::SendActivityTask::sign_and_send),
+/// but without sending the request. It can be used to deliver activities over a different
+/// transport, or to generate test fixtures. With `http_signature_compat` the signature is
+/// created according to draft 10, see [FederationConfigBuilder::http_signature_compat](crate::config::FederationConfigBuilder::http_signature_compat).
+///
+/// The headers can be verified by the receiver with [verify_response_signature], which performs
+/// the same checks for requests and responses:
+///
+/// ```
+/// # use activitypub_federation::http_signatures::{build_signed_headers, generate_actor_keypair, verify_response_signature};
+/// # use activitypub_federation::protocol::public_key::KeyIdStrategy;
+/// # use http::Method;
+/// # use url::Url;
+/// let keypair = generate_actor_keypair()?;
+/// let actor_id = Url::parse("https://example.com/u/alice")?;
+/// let inbox = Url::parse("https://example.net/inbox")?;
+/// let body = br#"{"type":"Follow"}"#;
+///
+/// let headers = build_signed_headers(
+///     &inbox,
+///     Method::POST,
+///     body,
+///     &actor_id,
+///     &keypair.private_key,
+///     false,
+///     &KeyIdStrategy::default(),
+/// )?;
+/// verify_response_signature(&Method::POST, &inbox, &headers, body, &keypair.public_key)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn build_signed_headers(
+    target: &Url,
+    method: Method,
+    body: &[u8],
+    actor_id: &Url,
+    private_key_pem: &str,
+    http_signature_compat: bool,
+    key_id_strategy: &KeyIdStrategy,
+) -> Result<HeaderMap, Error> {
+    let private_key = RsaPrivateKey::from_pkcs8_pem(private_key_pem)?;
+    let mut headers = generate_request_headers(target);
+    sign_headers(
+        target,
+        &method,
+        &mut headers,
+        body,
+        key_id_strategy.key_id(actor_id),
+        &private_key,
+        http_signature_compat,
+    )?;
+    Ok(headers)
+}
+
+/// Adds `Digest` and `Signature` headers for a request with the given `headers`, which need to
+/// include `Date` and `Host`. This is used for all draft-cavage signatures of outgoing requests.
+fn sign_headers(
+    target: &Url,
+    method: &Method,
+    headers: &mut HeaderMap,
+    body: &[u8],
+    key_id: String,
+    private_key: &RsaPrivateKey,
+    http_signature_compat: bool,
+) -> Result<(), Error> {
+    static CONFIG: Lazy<http_signature_normalization::Config> =
+        Lazy::new(|| http_signature_normalization::Config::new().set_expiration(EXPIRES_AFTER));
+    static CONFIG_COMPAT: Lazy<http_signature_normalization::Config> = Lazy::new(|| {
+        http_signature_normalization::Config::new()
             .mastodon_compat()
             .set_expiration(EXPIRES_AFTER)
     });
 
-    let key_id = main_key_id(actor_id);
-    let sig_conf = match http_signature_compat {
-        false => CONFIG.clone(),
-        true => CONFIG_COMPAT.clone(),
+    headers.insert(
+        HeaderName::from_static("digest"),
+        header_value(digest_header(body))?,
+    );
+
+    let header_map = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let path_and_query = match target.query() {
+        Some(query) => format!("{}?{query}", target.path()),
+        None => target.path().to_string(),
     };
-    request_builder
-        .signature_with_digest(
-            sig_conf.clone(),
-            key_id,
-            Sha256::new(),
-            activity,
-            move |signing_string| {
-                Ok(Base64.encode(private_key.sign(
-                    Pkcs1v15Sign::new::<Sha256>(),
-                    &Sha256::digest(signing_string.as_bytes()),
-                )?)) as Result<_, Error>
-            },
-        )
-        .await
+    let config = match http_signature_compat {
+        false => &CONFIG,
+        true => &CONFIG_COMPAT,
+    };
+    let signature = config
+        .begin_sign(method.as_str(), &path_and_query, header_map)
+        .map_err(|e| Error::Other(e.to_string()))?
+        .sign(key_id, |signing_string| {
+            sign_string(private_key, signing_string)
+        })?
+        .signature_header();
+    headers.insert(
+        HeaderName::from_static("signature"),
+        header_value(signature)?,
+    );
+    Ok(())
 }
 
 /// Verifies the HTTP signature on an incoming federation request
 /// for a given actor's public key.
 ///
 /// Internally, this just converts the headers to a BTreeMap and passes to
-/// `verify_signature_inner` for actual signature verification.
-pub(crate) fn verify_signature<'a, H>(
+/// `verify_signature_inner` for actual signature verification. If the request contains multiple
+/// `Signature` headers, it is enough that one of them can be verified. Only the first three are
+/// tried. Requests with a `Signature-Input` header are verified according to RFC 9421 instead.
+///
+/// RSA verification is expensive, so it runs on the blocking thread pool of tokio, limited by
+/// the same `limiter` as signing.
+pub(crate) async fn verify_signature<'a, H>(
     headers: H,
     method: &Method,
     uri: &Uri,
-    public_key: &str,
+    public_key: &RsaPublicKey,
+    limiter: &SigningLimiter,
 ) -> Result<(), Error>
 where
     H: IntoIterator<Item = (&'a HeaderName, &'a HeaderValue)>,
 {
-    let mut header_map = BTreeMap::<String, String>::new();
-    for (name, value) in headers {
-        if let Ok(value) = value.to_str() {
-            header_map.insert(name.to_string(), value.to_string());
+    let (header_map, signatures) = split_signatures(headers);
+    let method = method.clone();
+    let uri = uri.clone();
+    let public_key = public_key.clone();
+    let _guard = limiter.acquire().await;
+    tokio::task::spawn_blocking(move || {
+        verify_any_signature(&header_map, signatures, &method, &uri, &public_key)
+    })
+    .await
+    .map_err(|err| Error::Other(format!("Error joining: {err}")))?
+}
+
+/// Returns `Ok` if any of the signatures can be verified with `public_key`. This is a blocking
+/// call.
+fn verify_any_signature(
+    header_map: &BTreeMap<String, String>,
+    signatures: Vec<RequestSignature>,
+    method: &Method,
+    uri: &Uri,
+    public_key: &RsaPublicKey,
+) -> Result<(), Error> {
+    let mut result = Err(ActivitySignatureInvalid);
+    for signature in signatures {
+        result = signature.verify(header_map, method, uri, public_key);
+        if result.is_ok() {
+            break;
         }
     }
+    result
+}
+
+/// Parses a public key in PEM format
+fn parse_public_key(public_key_pem: &str) -> Result<RsaPublicKey, Error> {
+    #[cfg(test)]
+    test::PUBLIC_KEY_PARSES.with(|parses| parses.set(parses.get() + 1));
+    Ok(RsaPublicKey::from_public_key_pem(public_key_pem)?)
+}
+
+/// Parses a public key in PEM format, or returns it from
+/// [FederationConfigBuilder::actor_public_key_cache](crate::config::FederationConfigBuilder::actor_public_key_cache)
+/// if it was parsed before. Keys are cached by their PEM, so a changed key is parsed again.
+pub(crate) async fn parse_public_key_cached(
+    public_key_pem: &str,
+    data: &Data<impl Clone>,
+) -> Result<RsaPublicKey, Error> {
+    data.config
+        .actor_public_key_cache
+        .try_get_with_by_ref(public_key_pem, async { parse_public_key(public_key_pem) })
+        .await
+        .map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Minimum time between refetches of the same actor because its signature couldn't be verified
+pub(crate) const KEY_REFETCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Verifies the signature of an incoming request with the public key of `actor`, which was
+/// dereferenced from `actor_id`.
+///
+/// If this fails, the actor may have rotated its key. In this case the actor is fetched again and
+/// the signature is verified with the new key, which is then returned. To prevent invalid
+/// signatures from causing unlimited requests, each actor is refetched at most once per
+/// [KEY_REFETCH_INTERVAL].
+pub(crate) async fn verify_signature_with_refetch<A>(
+    headers: &HeaderMap,
+    method: &Method,
+    uri: &Uri,
+    actor_id: &Url,
+    actor: A,
+    data: &Data<<A as Object>::DataType>,
+) -> Result<A, <A as Object>::Error>
+where
+    A: Object + Actor,
+    <A as Object>::Error: From<Error>,
+    for<'de2> <A as Object>::Kind: Deserialize<'de2>,
+{
+    let public_key = parse_public_key_cached(actor.public_key_pem(), data).await;
+    let err = match public_key {
+        Ok(public_key) => match verify_signature(
+            headers,
+            method,
+            uri,
+            &public_key,
+            &data.config.signing_limiter,
+        )
+        .await
+        {
+            Ok(()) => return Ok(actor),
+            Err(e) => e,
+        },
+        Err(e) => e,
+    };
+    if data.config.is_local_url(actor_id) {
+        return Err(err.into());
+    }
+    let first_refetch = data
+        .config
+        .key_refetches
+        .entry(actor_id.clone())
+        .or_insert(())
+        .await
+        .is_fresh();
+    if !first_refetch {
+        return Err(err.into());
+    }
 
-    verify_signature_inner(header_map, method, uri, public_key)
+    debug!("Refetching actor {actor_id} because its signature couldn't be verified");
+    let Ok(actor) = ObjectId::<A>::from(actor_id.clone())
+        .dereference_forced(data)
+        .await
+    else {
+        debug!("Failed to refetch actor {actor_id}");
+        return Err(err.into());
+    };
+    let public_key = parse_public_key_cached(actor.public_key_pem(), data).await?;
+    let limiter = &data.config.signing_limiter;
+    verify_signature(headers, method, uri, &public_key, limiter).await?;
+    Ok(actor)
 }
 
 /// Checks whether the given federation request has a valid signature,
@@ -151,30 +629,216 @@ where
     for<'de2> <A as Object>::Kind: Deserialize<'de2>,
     H: IntoIterator<Item = (&'a HeaderName, &'a HeaderValue)>,
 {
-    let mut header_map = BTreeMap::<String, String>::new();
-    for (name, value) in headers {
-        if let Ok(value) = value.to_str() {
-            header_map.insert(name.to_string(), value.to_string());
+    let (actor, key_id) = signing_actor_with_key_id::<A, H>(headers, method, uri, data).await?;
+    debug!("verified signature with key {key_id}");
+    Ok(actor)
+}
+
+/// Same as [signing_actor], but also returns the key id of the verified signature.
+///
+/// Requests may contain multiple `Signature` headers, for example signed by both an instance actor
+/// and a user, or with old and new key during key rotation. These are tried in order and the
+/// first one which can be verified is used. At most three signatures are tried, as each of them
+/// can require fetching an actor.
+pub(crate) async fn signing_actor_with_key_id<'a, A, H>(
+    headers: H,
+    method: &Method,
+    uri: &Uri,
+    data: &Data<<A as Object>::DataType>,
+) -> Result<(A, String), <A as Object>::Error>
+where
+    A: Object + Actor,
+    <A as Object>::Error: From<Error>,
+    for<'de2> <A as Object>::Kind: Deserialize<'de2>,
+    H: IntoIterator<Item = (&'a HeaderName, &'a HeaderValue)>,
+{
+    let (header_map, signatures) = split_signatures(headers);
+    let mut result = Err(Error::ActivitySignatureInvalid.into());
+    for signature in signatures {
+        result = verify_signing_actor(&header_map, signature, method, uri, data).await;
+        match &result {
+            Ok(_) => break,
+            Err(_) => debug!("failed to verify signature, trying next one"),
         }
     }
-    let signature = header_map
-        .get("signature")
-        .ok_or(Error::ActivitySignatureInvalid)?;
+    result
+}
 
-    let actor_id_re = regex::Regex::new("keyId=\"([^\"]+)#([^\"]+)\"").expect("regex error");
-    let actor_id = match actor_id_re.captures(signature) {
-        None => return Err(Error::ActivitySignatureInvalid.into()),
-        Some(caps) => caps.get(1).expect("regex error").as_str(),
+async fn verify_signing_actor<A>(
+    header_map: &BTreeMap<String, String>,
+    signature: RequestSignature,
+    method: &Method,
+    uri: &Uri,
+    data: &Data<<A as Object>::DataType>,
+) -> Result<(A, String), <A as Object>::Error>
+where
+    A: Object + Actor,
+    <A as Object>::Error: From<Error>,
+    for<'de2> <A as Object>::Kind: Deserialize<'de2>,
+{
+    let Some((key_id, actor_url)) = signature
+        .key_id()
+        .and_then(|key_id| Some((key_id.to_string(), key_actor_id(key_id)?)))
+    else {
+        return Err(Error::ActivitySignatureInvalid.into());
     };
-    let actor_url = Url::parse(actor_id).map_err(|_| Error::ActivitySignatureInvalid)?;
     let actor_id: ObjectId<A> = actor_url.into();
 
     let actor = actor_id.dereference(data).await?;
-    let public_key = actor.public_key_pem();
+    let public_key = parse_public_key_cached(actor.public_key_pem(), data).await?;
 
-    verify_signature_inner(header_map, method, uri, public_key)?;
+    signature
+        .verify_blocking(
+            header_map,
+            method,
+            uri,
+            &public_key,
+            &data.config.signing_limiter,
+        )
+        .await?;
 
-    Ok(actor)
+    Ok((actor, key_id))
+}
+
+/// Key ids of all signatures of an incoming request, in the order in which they were received
+pub(crate) fn signature_key_ids(headers: &HeaderMap) -> Vec<String> {
+    split_signatures(headers)
+        .1
+        .iter()
+        .filter_map(|signature| signature.key_id().map(ToString::to_string))
+        .collect()
+}
+
+/// Returns true if `key_id` is a key of the actor `actor_id`. This is the case if the key id is
+/// the actor id with a fragment, as in `https://example.com/u/alice#main-key`, or with one more
+/// path segment, as in `https://example.com/u/alice/main-key`.
+pub(crate) fn is_key_of_actor(key_id: &str, actor_id: &Url) -> bool {
+    let actor_id = actor_id.as_str().trim_end_matches('/');
+    let Some(suffix) = key_id.strip_prefix(actor_id) else {
+        return false;
+    };
+    let key = suffix.strip_prefix('/').unwrap_or(suffix);
+    if key.starts_with('#') {
+        return true;
+    }
+    suffix.starts_with('/') && !key.is_empty() && !key.contains(['/', '#', '?'])
+}
+
+/// Returns the id of the actor which owns `key_id`, so that [is_key_of_actor] is true for it
+fn key_actor_id(key_id: &str) -> Option<Url> {
+    let actor_id = match key_id.split_once('#') {
+        Some((actor_id, _)) => actor_id,
+        None => key_id.rsplit_once('/')?.0,
+    };
+    let actor_id = Url::parse(actor_id).ok()?;
+    is_key_of_actor(key_id, &actor_id).then_some(actor_id)
+}
+
+/// Signature of an incoming request, in one of the supported formats
+enum RequestSignature {
+    /// Value of a draft-cavage `Signature` header
+    Cavage(String),
+    /// Signature according to RFC 9421, from `Signature-Input` and `Signature` headers
+    Rfc9421(rfc9421::Signature),
+}
+
+impl RequestSignature {
+    fn key_id(&self) -> Option<&str> {
+        static KEY_ID_REGEX: Lazy<regex::Regex> =
+            Lazy::new(|| regex::Regex::new("keyId=\"([^\"]+)\"").expect("regex error"));
+        match self {
+            RequestSignature::Cavage(signature) => {
+                Some(KEY_ID_REGEX.captures(signature)?.get(1)?.as_str())
+            }
+            RequestSignature::Rfc9421(signature) => signature.key_id(),
+        }
+    }
+
+    fn verify(
+        &self,
+        header_map: &BTreeMap<String, String>,
+        method: &Method,
+        uri: &Uri,
+        public_key: &RsaPublicKey,
+    ) -> Result<(), Error> {
+        match self {
+            RequestSignature::Cavage(signature) => verify_signature_inner(
+                with_signature(header_map, signature.clone()),
+                method,
+                uri,
+                public_key,
+            ),
+            RequestSignature::Rfc9421(signature) => {
+                signature.verify(header_map, method, uri, public_key)
+            }
+        }
+    }
+
+    /// Same as [RequestSignature::verify], but runs on the blocking thread pool of tokio, limited
+    /// by `limiter`
+    async fn verify_blocking(
+        self,
+        header_map: &BTreeMap<String, String>,
+        method: &Method,
+        uri: &Uri,
+        public_key: &RsaPublicKey,
+        limiter: &SigningLimiter,
+    ) -> Result<(), Error> {
+        let header_map = header_map.clone();
+        let method = method.clone();
+        let uri = uri.clone();
+        let public_key = public_key.clone();
+        let _guard = limiter.acquire().await;
+        tokio::task::spawn_blocking(move || self.verify(&header_map, &method, &uri, &public_key))
+            .await
+            .map_err(|err| Error::Other(format!("Error joining: {err}")))?
+    }
+}
+
+/// Maximum number of signatures of a request which are tried. Each of them can require fetching
+/// an actor and an RSA verification, so a request with many signatures is expensive.
+const MAX_SIGNATURES: usize = 3;
+
+/// Converts the headers to a BTreeMap, and returns it together with the signatures, in the order
+/// in which they were received. Only the first [MAX_SIGNATURES] signatures are returned. If there
+/// is a `Signature-Input` header, the signatures are parsed according to RFC 9421.
+fn split_signatures<'a, H>(headers: H) -> (BTreeMap<String, String>, Vec<RequestSignature>)
+where
+    H: IntoIterator<Item = (&'a HeaderName, &'a HeaderValue)>,
+{
+    let mut header_map = BTreeMap::<String, String>::new();
+    let mut signatures = vec![];
+    for (name, value) in headers {
+        if let Ok(value) = value.to_str() {
+            if name.as_str().eq_ignore_ascii_case("signature") {
+                signatures.push(value.to_string());
+            } else {
+                header_map.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+    let signatures = match header_map.get("signature-input") {
+        Some(input) => rfc9421::Signature::parse(input, &signatures)
+            .into_iter()
+            .take(MAX_SIGNATURES)
+            .map(RequestSignature::Rfc9421)
+            .collect(),
+        None => signatures
+            .into_iter()
+            .take(MAX_SIGNATURES)
+            .map(RequestSignature::Cavage)
+            .collect(),
+    };
+    (header_map, signatures)
+}
+
+fn with_signature(
+    header_map: &BTreeMap<String, String>,
+    signature: String,
+) -> BTreeMap<String, String> {
+    let mut header_map = header_map.clone();
+    header_map.insert("signature".to_string(), signature);
+    header_map
 }
 
 /// Verifies that the signature present in the request is valid for
@@ -183,26 +847,32 @@ fn verify_signature_inner(
     header_map: BTreeMap<String, String>,
     method: &Method,
     uri: &Uri,
-    public_key: &str,
+    public_key: &RsaPublicKey,
 ) -> Result<(), Error> {
     static CONFIG: Lazy<http_signature_normalization::Config> = Lazy::new(|| {
         http_signature_normalization::Config::new()
             .set_expiration(EXPIRES_AFTER)
             .require_digest()
     });
+    // For requests which only have the newer `Content-Digest` header, that header needs to be
+    // signed instead
+    static CONFIG_CONTENT_DIGEST: Lazy<http_signature_normalization::Config> = Lazy::new(|| {
+        http_signature_normalization::Config::new()
+            .set_expiration(EXPIRES_AFTER)
+            .require_header("content-digest")
+    });
 
     let path_and_query = uri.path_and_query().map(PathAndQuery::as_str).unwrap_or("");
+    let config = match header_map.contains_key("digest") {
+        false if header_map.contains_key("content-digest") => &CONFIG_CONTENT_DIGEST,
+        _ => &CONFIG,
+    };
 
-    let verified = CONFIG
+    let verified = config
         .begin_verify(method.as_str(), path_and_query, header_map)
         .map_err(|val| Error::Other(val.to_string()))?
         .verify(|signature, signing_string| -> Result<bool, Error> {
-            debug!(
-                "Verifying with key {}, message {}",
-                &public_key, &signing_string
-            );
-            let public_key = RsaPublicKey::from_public_key_pem(public_key)?;
-
+            debug!("Verifying signature, message {}", &signing_string);
             let base64_decoded = Base64
                 .decode(signature)
                 .map_err(|err| Error::Other(err.to_string()))?;
@@ -224,56 +894,276 @@ fn verify_signature_inner(
     }
 }
 
+/// Headers which are included in response signatures, if present. Other headers may be changed
+/// by proxies, so they are left out.
+const SIGNED_RESPONSE_HEADERS: [&str; 3] = ["content-type", "date", "digest"];
+
+/// Adds `Digest`, `Date` and `Signature` headers to an HTTP response, so that the receiver can
+/// verify that it was served by the owner of `private_key_pem`.
+///
+/// `method` and `uri` are taken from the request which is being answered, and are included in the
+/// signature. This way a signed response can't be replayed for a different url. Responses can be
+/// verified with [verify_response_signature].
+///
+/// For axum and actix-web, the `SignResponses` middleware can be used instead of calling this
+/// directly.
+pub fn sign_response(
+    method: &Method,
+    uri: &Uri,
+    headers: &mut HeaderMap,
+    body: &[u8],
+    key_id: String,
+    private_key_pem: &str,
+) -> Result<(), Error> {
+    let private_key = RsaPrivateKey::from_pkcs8_pem(private_key_pem)?;
+    sign_response_with_key(method, uri, headers, body, key_id, &private_key)
+}
+
+pub(crate) fn sign_response_with_key(
+    method: &Method,
+    uri: &Uri,
+    headers: &mut HeaderMap,
+    body: &[u8],
+    key_id: String,
+    private_key: &RsaPrivateKey,
+) -> Result<(), Error> {
+    static CONFIG: Lazy<http_signature_normalization::Config> = Lazy::new(|| {
+        http_signature_normalization::Config::new()
+            .set_expiration(EXPIRES_AFTER)
+            .require_digest()
+    });
+
+    headers.insert(
+        HeaderName::from_static("digest"),
+        header_value(digest_header(body))?,
+    );
+    if !headers.contains_key(DATE) {
+        headers.insert(DATE, header_value(fmt_http_date(SystemTime::now()))?);
+    }
+
+    let header_map = SIGNED_RESPONSE_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(*name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let path_and_query = uri.path_and_query().map(PathAndQuery::as_str).unwrap_or("");
+    let signature = CONFIG
+        .begin_sign(method.as_str(), path_and_query, header_map)
+        .map_err(|e| Error::Other(e.to_string()))?
+        .sign(key_id, |signing_string| {
+            sign_string(private_key, signing_string)
+        })?
+        .signature_header();
+    headers.insert(
+        HeaderName::from_static("signature"),
+        header_value(signature)?,
+    );
+    Ok(())
+}
+
+/// Verifies the signature of an HTTP response which was created with [sign_response].
+///
+/// `method` and `url` are those of the request which was answered with this response. Fails if the
+/// response is unsigned, the body doesn't match the `Digest` header or the signature is invalid
+/// for `public_key`.
+pub fn verify_response_signature(
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+    body: &[u8],
+    public_key: &str,
+) -> Result<(), Error> {
+    verify_body_hash(headers, body)?;
+    let uri = Uri::try_from(url.as_str()).map_err(|e| Error::Other(e.to_string()))?;
+    let public_key = parse_public_key(public_key)?;
+    let (header_map, signatures) = split_signatures(headers);
+    verify_any_signature(&header_map, signatures, method, &uri, &public_key)
+}
+
+/// Signs responses of the `SignResponses` middleware for axum and actix-web.
+#[cfg(any(feature = "actix-web", feature = "axum"))]
+#[derive(Clone)]
+pub(crate) struct ResponseSigner {
+    actor_id: Url,
+    key_id: String,
+    private_key: RsaPrivateKey,
+}
+
+#[cfg(any(feature = "actix-web", feature = "axum"))]
+impl ResponseSigner {
+    pub(crate) fn new<A: Actor>(actor: &A) -> Result<Self, Error> {
+        let actor_id = actor.id();
+        let private_key_pem = actor.private_key_pem().ok_or_else(|| {
+            Error::Other(format!(
+                "Actor {actor_id} does not contain a private key for signing"
+            ))
+        })?;
+        Ok(ResponseSigner {
+            key_id: crate::protocol::public_key::main_key_id(&actor_id),
+            actor_id,
+            private_key: RsaPrivateKey::from_pkcs8_pem(&private_key_pem)?,
+        })
+    }
+
+    pub(crate) fn set_key_id_strategy(
+        &mut self,
+        strategy: &crate::protocol::public_key::KeyIdStrategy,
+    ) {
+        self.key_id = strategy.key_id(&self.actor_id);
+    }
+
+    pub(crate) fn sign(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &mut HeaderMap,
+        body: &[u8],
+    ) -> Result<(), Error> {
+        sign_response_with_key(
+            method,
+            uri,
+            headers,
+            body,
+            self.key_id.clone(),
+            &self.private_key,
+        )
+    }
+}
+
+/// Returns true if the `Content-Type` header is `application/activity+json` or
+/// `application/ld+json`, with optional parameters.
+#[cfg(any(feature = "actix-web", feature = "axum"))]
+pub(crate) fn is_activitypub_response(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .and_then(|c| c.split(';').next())
+        .map(|c| c.trim().to_lowercase())
+        .is_some_and(|c| c == "application/activity+json" || c == "application/ld+json")
+}
+
+fn header_value(value: String) -> Result<HeaderValue, Error> {
+    HeaderValue::try_from(value).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Algorithm of body digests, which is used by all major fediverse platforms
+const DIGEST_ALGORITHM: &str = "sha-256";
+
+/// Value of a `Digest` header for `body`, of the form `SHA-256=base64`
+pub(crate) fn digest_header(body: &[u8]) -> String {
+    format!("SHA-256={}", Base64.encode(Sha256::digest(body)))
+}
+
+/// Value of a `Content-Digest` header for `body` as in RFC 9530, of the form `sha-256=:base64:`
+pub(crate) fn content_digest_header(body: &[u8]) -> String {
+    format!("sha-256=:{}:", Base64.encode(Sha256::digest(body)))
+}
+
+/// Single hash from a `Digest` or `Content-Digest` header
 #[derive(Clone, Debug)]
-struct DigestPart {
-    /// We assume that SHA256 is used which is the case with all major fediverse platforms
-    #[allow(dead_code)]
-    pub algorithm: String,
-    /// The hashsum
-    pub digest: String,
-}
-
-impl DigestPart {
-    fn try_from_header(h: &HeaderValue) -> Option<Vec<DigestPart>> {
-        let h = h.to_str().ok()?.split(';').next()?;
-        let v: Vec<_> = h
+struct DigestPart<'a> {
+    /// Name of the hash algorithm, eg `SHA-256`
+    algorithm: &'a str,
+    /// Base64 encoded hash
+    digest: &'a str,
+}
+
+impl<'a> DigestPart<'a> {
+    /// Parses a `Digest` header of the form `SHA-256=base64`, as used with draft-cavage
+    /// signatures.
+    fn parse_digest(header: &'a str) -> Vec<DigestPart<'a>> {
+        header
             .split(',')
-            .filter_map(|p| {
-                let mut iter = p.splitn(2, '=');
-                iter.next()
-                    .and_then(|alg| iter.next().map(|value| (alg, value)))
+            .filter_map(|part| {
+                let (algorithm, digest) = part.split_once('=')?;
+                Some(DigestPart {
+                    algorithm: algorithm.trim(),
+                    digest: digest.trim(),
+                })
             })
-            .map(|(alg, value)| DigestPart {
-                algorithm: alg.to_owned(),
-                digest: value.to_owned(),
+            .collect()
+    }
+
+    /// Parses a `Content-Digest` header of the form `sha-256=:base64:`, which is a structured
+    /// field dictionary with byte sequences as values. Parameters after `;` are ignored. Returns
+    /// `None` if a value isn't framed by colons.
+    ///
+    /// <https://www.rfc-editor.org/rfc/rfc9530>
+    fn parse_content_digest(header: &'a str) -> Option<Vec<DigestPart<'a>>> {
+        split_top_level(header, ',')
+            .filter(|member| !member.trim().is_empty())
+            .map(|member| {
+                let member = member.split(';').next()?;
+                let (algorithm, digest) = member.split_once('=')?;
+                let digest = digest.trim().strip_prefix(':')?.strip_suffix(':')?;
+                Some(DigestPart {
+                    algorithm: algorithm.trim(),
+                    digest,
+                })
             })
-            .collect();
+            .collect()
+    }
+}
 
-        if v.is_empty() {
-            None
-        } else {
-            Some(v)
-        }
+/// Verify body of an inbox request against the hash provided in `Content-Digest` or `Digest`
+/// header.
+pub(crate) fn verify_body_hash(headers: &HeaderMap, body: &[u8]) -> Result<(), Error> {
+    verify_body_digest(headers, &Sha256::digest(body))
+}
+
+/// Same as [verify_body_hash], but takes the SHA-256 hash of the body which was already computed,
+/// for example while reading the body from the request stream.
+///
+/// Both the `Content-Digest` header from RFC 9530 and the `Digest` header are checked if they are
+/// present, and each of them needs to contain a matching SHA-256 hash. Otherwise a sender could
+/// sign only one of the headers, and an attacker could replace the body and add the other header
+/// with a matching hash.
+pub(crate) fn verify_body_digest(headers: &HeaderMap, body_digest: &[u8]) -> Result<(), Error> {
+    let header = |name| {
+        headers
+            .get(name)
+            .map(|value| value.to_str().map_err(|_| Error::ActivityBodyDigestInvalid))
+    };
+    let content_digest = header("content-digest").transpose()?;
+    let digest = header("digest").transpose()?;
+    if content_digest.is_none() && digest.is_none() {
+        return Err(Error::ActivityBodyDigestInvalid);
+    }
+    if let Some(content_digest) = content_digest {
+        let parts = DigestPart::parse_content_digest(content_digest)
+            .ok_or(Error::ActivityBodyDigestInvalid)?;
+        verify_digest_parts(&parts, body_digest)?;
+    }
+    if let Some(digest) = digest {
+        verify_digest_parts(&DigestPart::parse_digest(digest), body_digest)?;
     }
+    Ok(())
 }
 
-/// Verify body of an inbox request against the hash provided in `Digest` header.
-pub(crate) fn verify_body_hash(
-    digest_header: Option<&HeaderValue>,
-    body: &[u8],
-) -> Result<(), Error> {
-    let digest = digest_header
-        .and_then(DigestPart::try_from_header)
-        .ok_or(Error::ActivityBodyDigestInvalid)?;
-    let mut hasher = Sha256::new();
-
-    for part in digest {
-        hasher.update(body);
-        if Base64.encode(hasher.finalize_reset()) != part.digest {
+/// Checks the SHA-256 hashes among `parts` against `body_digest`, with a comparison which takes
+/// constant time. Hashes with other algorithms are ignored, but if there is no SHA-256 hash
+/// this fails with [Error::UnsupportedDigestAlgorithm].
+fn verify_digest_parts(parts: &[DigestPart], body_digest: &[u8]) -> Result<(), Error> {
+    let mut supported = parts
+        .iter()
+        .filter(|part| part.algorithm.eq_ignore_ascii_case(DIGEST_ALGORITHM))
+        .peekable();
+    if supported.peek().is_none() {
+        return Err(match parts.first() {
+            Some(part) => Error::UnsupportedDigestAlgorithm(part.algorithm.to_string()),
+            None => Error::ActivityBodyDigestInvalid,
+        });
+    }
+    for part in supported {
+        let digest = Base64
+            .decode(part.digest)
+            .map_err(|_| Error::ActivityBodyDigestInvalid)?;
+        if !bool::from(digest.ct_eq(body_digest)) {
             return Err(Error::ActivityBodyDigestInvalid);
         }
     }
-
     Ok(())
 }
 
@@ -282,11 +1172,21 @@ pub(crate) fn verify_body_hash(
 #[allow(clippy::unwrap_used)]
 pub mod test {
     use super::*;
-    use crate::activity_sending::generate_request_headers;
+    use crate::{
+        config::FederationConfig,
+        protocol::public_key::{main_key_id, KeyIdStrategy, PublicKey},
+        traits::tests::{DbConnection, DbUser, DB_USER, DB_USER_KEYPAIR},
+    };
+    use http_signature_normalization_reqwest::prelude::{Config, Sign};
     use reqwest::Client;
     use reqwest_middleware::ClientWithMiddleware;
     use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey};
-    use std::str::FromStr;
+    use std::{cell::Cell, str::FromStr};
+
+    thread_local! {
+        /// Number of public keys which were parsed on this thread
+        pub(super) static PUBLIC_KEY_PARSES: Cell<usize> = const { Cell::new(0) };
+    }
 
     static ACTOR_ID: Lazy<Url> = Lazy::new(|| Url::parse("https://example.com/u/alice").unwrap());
     static INBOX_URL: Lazy<Url> =
@@ -306,12 +1206,15 @@ pub mod test {
             .headers(headers);
         let request = sign_request(
             request_builder,
-            &ACTOR_ID,
+            main_key_id(&ACTOR_ID),
             "my activity".into(),
             RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key).unwrap(),
             // set this to prevent created/expires headers to be generated and inserted
             // automatically from current time
             true,
+            false,
+            false,
+            &Default::default(),
         )
         .await
         .unwrap();
@@ -334,6 +1237,86 @@ pub mod test {
         assert_eq!(signature, expected_signature);
     }
 
+    #[tokio::test]
+    async fn test_build_signed_headers() -> Result<(), Error> {
+        let mut headers = generate_request_headers(&INBOX_URL);
+        headers.insert(
+            "date",
+            HeaderValue::from_str("Tue, 28 Mar 2023 21:03:44 GMT").unwrap(),
+        );
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key)?;
+        let request_builder = ClientWithMiddleware::from(Client::new())
+            .post(INBOX_URL.to_string())
+            .headers(headers.clone());
+        let request = sign_request(
+            request_builder,
+            main_key_id(&ACTOR_ID),
+            "my activity".into(),
+            private_key.clone(),
+            true,
+            false,
+            false,
+            &Default::default(),
+        )
+        .await?;
+
+        // Same headers as with reqwest
+        sign_headers(
+            &INBOX_URL,
+            &Method::POST,
+            &mut headers,
+            b"my activity",
+            main_key_id(&ACTOR_ID),
+            &private_key,
+            true,
+        )?;
+        for name in ["date", "host", "content-type", "digest", "signature"] {
+            assert_eq!(request.headers().get(name), headers.get(name), "{name}");
+        }
+
+        // Headers attached to a request manually pass verification
+        for compat in [true, false] {
+            let headers = build_signed_headers(
+                &INBOX_URL,
+                Method::POST,
+                b"my activity",
+                &ACTOR_ID,
+                &test_keypair().private_key,
+                compat,
+                &Default::default(),
+            )?;
+            let request = Client::new()
+                .post(INBOX_URL.to_string())
+                .headers(headers)
+                .body("my activity")
+                .build()?;
+            verify_body_hash(request.headers(), b"my activity")?;
+            verify_signature(
+                request.headers(),
+                request.method(),
+                &Uri::from_str(request.url().as_str()).unwrap(),
+                &parse_public_key(&test_keypair().public_key).unwrap(),
+                &Default::default(),
+            )
+            .await?;
+        }
+
+        // The key id follows the strategy
+        let strategy = KeyIdStrategy::PathSuffix("main-key".to_string());
+        let headers = build_signed_headers(
+            &INBOX_URL,
+            Method::POST,
+            b"my activity",
+            &ACTOR_ID,
+            &test_keypair().private_key,
+            false,
+            &strategy,
+        )?;
+        let signature = headers.get("signature").unwrap().to_str().unwrap();
+        assert!(signature.contains("keyId=\"https://example.com/u/alice/main-key\""));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_verify() {
         let headers = generate_request_headers(&INBOX_URL);
@@ -342,10 +1325,13 @@ pub mod test {
             .headers(headers);
         let request = sign_request(
             request_builder,
-            &ACTOR_ID,
+            main_key_id(&ACTOR_ID),
             "my activity".to_string().into(),
             RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key).unwrap(),
             false,
+            false,
+            false,
+            &Default::default(),
         )
         .await
         .unwrap();
@@ -354,31 +1340,539 @@ pub mod test {
             request.headers(),
             request.method(),
             &Uri::from_str(request.url().as_str()).unwrap(),
-            &test_keypair().public_key,
-        );
+            &parse_public_key(&test_keypair().public_key).unwrap(),
+            &Default::default(),
+        )
+        .await;
         println!("{:?}", &valid);
         assert!(valid.is_ok());
     }
 
+    /// Verification runs on the blocking thread pool, so other tasks keep running meanwhile, even
+    /// on a single threaded runtime. The signing limiter bounds how many threads are used.
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_verify_concurrent() -> Result<(), Error> {
+        let request_builder = ClientWithMiddleware::from(Client::new())
+            .post(INBOX_URL.to_string())
+            .headers(generate_request_headers(&INBOX_URL));
+        let request = sign_request(
+            request_builder,
+            main_key_id(&ACTOR_ID),
+            "my activity".into(),
+            test_keypair().private_key().unwrap(),
+            false,
+            false,
+            false,
+            &Default::default(),
+        )
+        .await?;
+        let uri = Uri::from_str(request.url().as_str()).unwrap();
+        let public_key = parse_public_key(&test_keypair().public_key)?;
+        let limiter = SigningLimiter::new(4);
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        let results = futures::future::join_all((0..1000).map(|_| {
+            verify_signature(
+                request.headers(),
+                request.method(),
+                &uri,
+                &public_key,
+                &limiter,
+            )
+        }))
+        .await;
+        ticker.abort();
+        assert!(results.iter().all(Result::is_ok));
+        assert!(ticks.load(Ordering::SeqCst) > 0);
+        assert_eq!(limiter.max_in_flight(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_multiple_signatures() -> Result<(), Error> {
+        let valid_key_id = main_key_id(&DB_USER.federation_id);
+        let invalid_key_id = "https://localhost/456#main-key".to_string();
+        let headers = generate_request_headers(&INBOX_URL);
+        let limiter = Default::default();
+        let sign = |key_id: String, private_key: RsaPrivateKey| {
+            let request_builder = ClientWithMiddleware::from(Client::new())
+                .post(INBOX_URL.to_string())
+                .headers(headers.clone());
+            sign_request(
+                request_builder,
+                key_id,
+                "my activity".into(),
+                private_key,
+                false,
+                false,
+                false,
+                &limiter,
+            )
+        };
+        let invalid = sign(
+            invalid_key_id,
+            RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key)?,
+        )
+        .await?;
+        let mut request = sign(
+            valid_key_id.clone(),
+            RsaPrivateKey::from_pkcs8_pem(&DB_USER_KEYPAIR.private_key)?,
+        )
+        .await?;
+
+        // Put the invalid signature first
+        let valid_signature = request.headers_mut().remove("signature").unwrap();
+        let invalid_signature = invalid.headers().get("signature").unwrap().clone();
+        request
+            .headers_mut()
+            .append("signature", invalid_signature.clone());
+        request
+            .headers_mut()
+            .append("signature", valid_signature.clone());
+        let uri = Uri::from_str(request.url().as_str()).unwrap();
+
+        verify_signature(
+            request.headers(),
+            request.method(),
+            &uri,
+            &parse_public_key(&DB_USER_KEYPAIR.public_key).unwrap(),
+            &Default::default(),
+        )
+        .await?;
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let (actor, key_id) = signing_actor_with_key_id::<DbUser, _>(
+            request.headers(),
+            request.method(),
+            &uri,
+            &data,
+        )
+        .await?;
+        assert_eq!(valid_key_id, key_id);
+        assert_eq!(DB_USER.federation_id, actor.federation_id);
+
+        // The valid signature comes after the maximum number of signatures which are tried
+        request.headers_mut().remove("signature");
+        for _ in 0..MAX_SIGNATURES {
+            request
+                .headers_mut()
+                .append("signature", invalid_signature.clone());
+        }
+        request.headers_mut().append("signature", valid_signature);
+        assert!(verify_signature(
+            request.headers(),
+            request.method(),
+            &uri,
+            &parse_public_key(&DB_USER_KEYPAIR.public_key).unwrap(),
+            &Default::default(),
+        )
+        .await
+        .is_err());
+
+        // Only the invalid signature
+        request.headers_mut().remove("signature");
+        request.headers_mut().insert("signature", invalid_signature);
+        assert!(verify_signature(
+            request.headers(),
+            request.method(),
+            &uri,
+            &parse_public_key(&DB_USER_KEYPAIR.public_key).unwrap(),
+            &Default::default(),
+        )
+        .await
+        .is_err());
+        assert!(
+            signing_actor::<DbUser, _>(request.headers(), request.method(), &uri, &data)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_public_key_cache() -> Result<(), Error> {
+        let request_builder = ClientWithMiddleware::from(Client::new())
+            .post(INBOX_URL.to_string())
+            .headers(generate_request_headers(&INBOX_URL));
+        let request = sign_request(
+            request_builder,
+            main_key_id(&DB_USER.federation_id),
+            "my activity".into(),
+            DB_USER_KEYPAIR.private_key().unwrap(),
+            false,
+            false,
+            false,
+            &Default::default(),
+        )
+        .await?;
+        let uri = Uri::from_str(request.url().as_str()).unwrap();
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+
+        let parses_before = PUBLIC_KEY_PARSES.get();
+        for _ in 0..10 {
+            signing_actor::<DbUser, _>(request.headers(), request.method(), &uri, &data).await?;
+        }
+        assert_eq!(PUBLIC_KEY_PARSES.get() - parses_before, 1);
+
+        // A different key is parsed again
+        parse_public_key_cached(&test_keypair().public_key, &data).await?;
+        parse_public_key_cached(&DB_USER_KEYPAIR.public_key, &data).await?;
+        assert_eq!(PUBLIC_KEY_PARSES.get() - parses_before, 2);
+
+        // Invalid keys are not cached
+        assert!(parse_public_key_cached("invalid", &data).await.is_err());
+        assert!(parse_public_key_cached("invalid", &data).await.is_err());
+        assert_eq!(PUBLIC_KEY_PARSES.get() - parses_before, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sign_verify_rfc9421() -> Result<(), Error> {
+        let request_builder = ClientWithMiddleware::from(Client::new())
+            .post(INBOX_URL.to_string())
+            .headers(generate_request_headers(&INBOX_URL));
+        let key_id = main_key_id(&ACTOR_ID);
+        let request = sign_request(
+            request_builder,
+            key_id.clone(),
+            "my activity".into(),
+            DB_USER_KEYPAIR.private_key().unwrap(),
+            false,
+            true,
+            false,
+            &Default::default(),
+        )
+        .await?;
+        assert!(request.headers().contains_key("signature-input"));
+        assert!(!request.headers().contains_key("digest"));
+        verify_body_hash(request.headers(), b"my activity")?;
+        assert!(verify_body_hash(request.headers(), b"other activity").is_err());
+
+        // Incoming requests only contain the path
+        let uri = Uri::from_str(request.url().path()).unwrap();
+        verify_signature(
+            request.headers(),
+            request.method(),
+            &uri,
+            &parse_public_key(&DB_USER_KEYPAIR.public_key).unwrap(),
+            &Default::default(),
+        )
+        .await?;
+        assert!(verify_signature(
+            request.headers(),
+            request.method(),
+            &uri,
+            &parse_public_key(&test_keypair().public_key).unwrap(),
+            &Default::default(),
+        )
+        .await
+        .is_err());
+
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let (actor, verified_key_id) = signing_actor_with_key_id::<DbUser, _>(
+            request.headers(),
+            request.method(),
+            &uri,
+            &data,
+        )
+        .await?;
+        assert_eq!(key_id, verified_key_id);
+        assert_eq!(DB_USER.federation_id, actor.federation_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sign_key_id_strategy() {
+        let strategy = KeyIdStrategy::PathSuffix("main-key".to_string());
+        let public_key =
+            PublicKey::new_with_strategy(ACTOR_ID.clone(), test_keypair().public_key, &strategy);
+        assert_eq!(public_key.id, "https://example.com/u/alice/main-key");
+
+        let headers = generate_request_headers(&INBOX_URL);
+        let request_builder = ClientWithMiddleware::from(Client::new())
+            .post(INBOX_URL.to_string())
+            .headers(headers);
+        let request = sign_request(
+            request_builder,
+            strategy.key_id(&ACTOR_ID),
+            "my activity".to_string().into(),
+            RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key).unwrap(),
+            false,
+            false,
+            false,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        let signature = request
+            .headers()
+            .get("signature")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(signature.contains(&format!("keyId=\"{}\"", public_key.id)));
+
+        let valid = verify_signature(
+            request.headers(),
+            request.method(),
+            &Uri::from_str(request.url().as_str()).unwrap(),
+            &parse_public_key(&public_key.public_key_pem).unwrap(),
+            &Default::default(),
+        )
+        .await;
+        assert!(valid.is_ok());
+        assert_eq!(key_actor_id(&public_key.id).as_ref(), Some(&*ACTOR_ID));
+    }
+
+    #[test]
+    fn test_key_actor_id() {
+        let actor_id = |key_id| key_actor_id(key_id).map(String::from);
+        let alice = Some("https://example.com/u/alice".to_string());
+        assert_eq!(actor_id("https://example.com/u/alice#main-key"), alice);
+        assert_eq!(actor_id("https://example.com/u/alice/main-key"), alice);
+        assert_eq!(actor_id("https://example.com/u/alice/main-key?x=1"), None);
+        assert_eq!(actor_id("main-key"), None);
+    }
+
     #[test]
     fn test_verify_body_hash_valid() {
-        let digest_header =
-            HeaderValue::from_static("SHA-256=lzFT+G7C2hdI5j8M+FuJg1tC+O6AGMVJhooTCKGfbKM=");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "digest",
+            HeaderValue::from_static("SHA-256=lzFT+G7C2hdI5j8M+FuJg1tC+O6AGMVJhooTCKGfbKM="),
+        );
         let body = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.";
-        let valid = verify_body_hash(Some(&digest_header), body.as_bytes());
+        let valid = verify_body_hash(&headers, body.as_bytes());
         println!("{:?}", &valid);
         assert!(valid.is_ok());
     }
 
     #[test]
     fn test_verify_body_hash_not_valid() {
-        let digest_header =
-            HeaderValue::from_static("SHA-256=Z9h7DJfYWjffXw2XftmWCnpEaK/yqOHKvzCIzIaqgbU=");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "digest",
+            HeaderValue::from_static("SHA-256=Z9h7DJfYWjffXw2XftmWCnpEaK/yqOHKvzCIzIaqgbU="),
+        );
         let body = "lorem ipsum";
-        let invalid = verify_body_hash(Some(&digest_header), body.as_bytes());
+        let invalid = verify_body_hash(&headers, body.as_bytes());
         assert_eq!(invalid, Err(Error::ActivityBodyDigestInvalid));
     }
 
+    #[test]
+    fn test_verify_body_hash_variants() {
+        let body = b"my activity";
+        let digest = Base64.encode(Sha256::digest(body));
+        let verify_headers = |pairs: &[(&'static str, String)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, header_value(value.clone())?);
+            }
+            verify_body_hash(&headers, body)
+        };
+        let verify = |value: String| verify_headers(&[("digest", value)]);
+        let verify_content = |value: String| verify_headers(&[("content-digest", value)]);
+
+        // Digest header, with any case of the algorithm
+        verify(format!("SHA-256={digest}")).unwrap();
+        verify(format!("sha-256={digest}")).unwrap();
+        verify(format!("SHA-512=AAAA,SHA-256={digest}")).unwrap();
+        // Content-Digest header from RFC 9530, with parameters
+        verify_content(format!("sha-256=:{digest}:")).unwrap();
+        verify_content(format!("sha-256=:{digest}:;param=1")).unwrap();
+        verify_content(format!("sha-512=:AAAA:, sha-256=:{digest}:")).unwrap();
+        // Content-Digest values must be framed by colons
+        let invalid = Err(Error::ActivityBodyDigestInvalid);
+        assert_eq!(verify_content(format!("sha-256={digest}")), invalid);
+
+        // Only unsupported algorithms
+        assert!(matches!(
+            verify("SHA-512=AAAA".to_string()),
+            Err(Error::UnsupportedDigestAlgorithm(alg)) if alg == "SHA-512"
+        ));
+        assert!(matches!(
+            verify_content("sha-512=:AAAA:, unknown=:AAAA:".to_string()),
+            Err(Error::UnsupportedDigestAlgorithm(alg)) if alg == "sha-512"
+        ));
+
+        // Wrong, truncated or malformed digest
+        let wrong = Base64.encode(Sha256::digest(b"other activity"));
+        assert_eq!(verify(format!("SHA-256={wrong}")), invalid);
+        assert_eq!(verify_content(format!("sha-256=:{wrong}:")), invalid);
+        assert_eq!(verify(format!("SHA-256={}", &digest[..20])), invalid);
+        assert_eq!(verify("SHA-256=not base64!".to_string()), invalid);
+        assert_eq!(
+            verify(format!("SHA-256={digest}, SHA-256={wrong}")),
+            invalid
+        );
+        assert_eq!(verify("no digest".to_string()), invalid);
+        assert_eq!(verify_headers(&[]), invalid);
+
+        // If both headers are present, both need to match
+        let both = |content_digest: String, digest: String| {
+            verify_headers(&[("content-digest", content_digest), ("digest", digest)])
+        };
+        both(format!("sha-256=:{digest}:"), format!("SHA-256={digest}")).unwrap();
+        assert_eq!(
+            both(format!("sha-256=:{wrong}:"), format!("SHA-256={digest}")),
+            invalid
+        );
+        // A Content-Digest which matches a replaced body doesn't make up for a signed Digest
+        // which doesn't match
+        assert_eq!(
+            both(format!("sha-256=:{digest}:"), format!("SHA-256={wrong}")),
+            invalid
+        );
+        assert!(matches!(
+            both("sha-512=:AAAA:".to_string(), format!("SHA-256={digest}")),
+            Err(Error::UnsupportedDigestAlgorithm(_))
+        ));
+        assert!(matches!(
+            both(format!("sha-256=:{digest}:"), "SHA-512=AAAA".to_string()),
+            Err(Error::UnsupportedDigestAlgorithm(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_body_hash_interop() {
+        let body = br#"{"type":"Follow"}"#;
+        let digest = Base64.encode(Sha256::digest(body));
+
+        // Mastodon sends only the Digest header
+        let mut mastodon = HeaderMap::new();
+        mastodon.insert("digest", header_value(digest_header(body)).unwrap());
+        assert_eq!(
+            mastodon.get("digest").unwrap(),
+            format!("SHA-256={digest}").as_str()
+        );
+        verify_body_hash(&mastodon, body).unwrap();
+
+        // GoToSocial with RFC 9421 signatures sends only the Content-Digest header
+        let mut gotosocial = HeaderMap::new();
+        gotosocial.insert(
+            "content-digest",
+            header_value(content_digest_header(body)).unwrap(),
+        );
+        assert_eq!(
+            gotosocial.get("content-digest").unwrap(),
+            format!("sha-256=:{digest}:").as_str()
+        );
+        verify_body_hash(&gotosocial, body).unwrap();
+
+        for headers in [mastodon, gotosocial] {
+            assert_eq!(
+                verify_body_hash(&headers, b"{}"),
+                Err(Error::ActivityBodyDigestInvalid)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_both_digest_headers() -> Result<(), Error> {
+        let key_id = main_key_id(&ACTOR_ID);
+        for rfc9421 in [false, true] {
+            let request_builder = ClientWithMiddleware::from(Client::new())
+                .post(INBOX_URL.to_string())
+                .headers(generate_request_headers(&INBOX_URL));
+            let request = sign_request(
+                request_builder,
+                key_id.clone(),
+                "my activity".into(),
+                DB_USER_KEYPAIR.private_key().unwrap(),
+                false,
+                rfc9421,
+                true,
+                &Default::default(),
+            )
+            .await?;
+            let headers = request.headers();
+            assert_eq!(
+                headers.get("digest").unwrap(),
+                digest_header(b"my activity").as_str()
+            );
+            assert_eq!(
+                headers.get("content-digest").unwrap(),
+                content_digest_header(b"my activity").as_str()
+            );
+            verify_body_hash(headers, b"my activity")?;
+
+            // Either header alone is enough to verify the body
+            for name in ["digest", "content-digest"] {
+                let mut headers = headers.clone();
+                headers.remove(name);
+                verify_body_hash(&headers, b"my activity")?;
+            }
+
+            let uri = Uri::from_str(request.url().path()).unwrap();
+            let public_key = parse_public_key(&DB_USER_KEYPAIR.public_key).unwrap();
+            verify_signature(
+                headers,
+                request.method(),
+                &uri,
+                &public_key,
+                &Default::default(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_cavage_content_digest_only() -> Result<(), Error> {
+        let private_key = DB_USER_KEYPAIR.private_key().unwrap();
+        let request = ClientWithMiddleware::from(Client::new())
+            .post(INBOX_URL.to_string())
+            .headers(generate_request_headers(&INBOX_URL))
+            .header("content-digest", content_digest_header(b"my activity"))
+            .body("my activity")
+            .signature(
+                &Config::new().set_expiration(EXPIRES_AFTER),
+                main_key_id(&ACTOR_ID),
+                move |signing_string| sign_string(&private_key, signing_string),
+            )
+            .await?;
+        assert!(!request.headers().contains_key("digest"));
+        verify_body_hash(request.headers(), b"my activity")?;
+
+        let uri = Uri::from_str(request.url().path()).unwrap();
+        let public_key = parse_public_key(&DB_USER_KEYPAIR.public_key).unwrap();
+        verify_signature(
+            request.headers(),
+            request.method(),
+            &uri,
+            &public_key,
+            &Default::default(),
+        )
+        .await?;
+        Ok(())
+    }
+
     /// Internal only, return hardcoded keypair for testing
     pub fn test_keypair() -> Keypair {
         let rsa = RsaPrivateKey::from_pkcs1_pem(PRIVATE_KEY).unwrap();
@@ -0,0 +1,499 @@
+//! HTTP Message Signatures according to RFC 9421, which replace the draft-cavage signatures.
+//!
+//! Only the subset which is used for federation is supported: RSA keys with SHA-256, derived
+//! components for method and target, and plain header fields.
+//!
+//! <https://www.rfc-editor.org/rfc/rfc9421>
+
+use super::{content_digest_header, sign_string, EXPIRES_AFTER};
+use crate::error::Error;
+use base64::{engine::general_purpose::STANDARD as Base64, Engine};
+use http::{header::HeaderName, uri::PathAndQuery, HeaderMap, HeaderValue, Method, Uri};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+/// Label of outgoing signatures
+const LABEL: &str = "sig1";
+
+/// Components which are covered by outgoing signatures. Incoming signatures need to cover these
+/// as well, except for `content-digest` on requests without body. Together with the
+/// `Content-Digest` check, this binds the signature to the body.
+const COVERED_COMPONENTS: [&str; 3] = ["@method", "@target-uri", "content-digest"];
+
+/// The only supported signature algorithm
+const ALGORITHM: &str = "rsa-v1_5-sha256";
+
+/// Allowed difference between the clocks of sender and receiver
+const CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Adds `Content-Digest`, `Signature-Input` and `Signature` headers to a request.
+pub(crate) fn sign_headers(
+    method: &Method,
+    target: &Url,
+    headers: &mut HeaderMap,
+    body: &[u8],
+    key_id: &str,
+    private_key: &RsaPrivateKey,
+) -> Result<(), Error> {
+    let content_digest = content_digest_header(body);
+    headers.insert(
+        HeaderName::from_static("content-digest"),
+        header_value(&content_digest)?,
+    );
+
+    let created = unix_time(SystemTime::now());
+    let components = COVERED_COMPONENTS
+        .iter()
+        .map(|c| format!("\"{c}\""))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let params = format!(
+        "({components});created={created};expires={};keyid=\"{key_id}\";alg=\"{ALGORITHM}\"",
+        created + EXPIRES_AFTER.as_secs()
+    );
+    let mut header_map = BTreeMap::new();
+    header_map.insert("content-digest".to_string(), content_digest);
+    let base = signature_base(
+        &COVERED_COMPONENTS.map(str::to_string),
+        &params,
+        method,
+        target.as_str(),
+        &header_map,
+    )
+    .ok_or(Error::Other("Failed to create signature base".to_string()))?;
+    let signature = sign_string(private_key, &base)?;
+
+    headers.insert(
+        HeaderName::from_static("signature-input"),
+        header_value(&format!("{LABEL}={params}"))?,
+    );
+    headers.insert(
+        HeaderName::from_static("signature"),
+        header_value(&format!("{LABEL}=:{signature}:"))?,
+    );
+    Ok(())
+}
+
+/// A single signature from the `Signature-Input` and `Signature` headers of a request
+#[derive(Clone, Debug)]
+pub(crate) struct Signature {
+    components: Vec<String>,
+    /// Serialized parameters as received, used for the `@signature-params` line
+    params: String,
+    key_id: Option<String>,
+    algorithm: Option<String>,
+    created: Option<u64>,
+    expires: Option<u64>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    /// Parses all signatures which are present in both `Signature-Input` and the values of the
+    /// `Signature` headers. Signatures which can't be parsed are ignored.
+    pub(crate) fn parse(signature_input: &str, signatures: &[String]) -> Vec<Signature> {
+        let values: BTreeMap<&str, Vec<u8>> = signatures
+            .iter()
+            .flat_map(|s| split_top_level(s, ','))
+            .filter_map(|member| {
+                let (label, value) = member.split_once('=')?;
+                let value = value.trim().strip_prefix(':')?.strip_suffix(':')?;
+                Some((label.trim(), Base64.decode(value).ok()?))
+            })
+            .collect();
+        split_top_level(signature_input, ',')
+            .filter_map(|member| {
+                let (label, params) = member.split_once('=')?;
+                let signature = values.get(label.trim())?.clone();
+                Self::parse_params(params.trim(), signature)
+            })
+            .collect()
+    }
+
+    fn parse_params(params: &str, signature: Vec<u8>) -> Option<Signature> {
+        let (components, rest) = params.strip_prefix('(')?.split_once(')')?;
+        let components = components
+            .split_whitespace()
+            .map(|c| {
+                // Component parameters like `;sf` are not supported
+                let name = c.strip_prefix('"')?.strip_suffix('"')?;
+                Some(name.to_lowercase())
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let mut signature = Signature {
+            components,
+            params: params.to_string(),
+            key_id: None,
+            algorithm: None,
+            created: None,
+            expires: None,
+            signature,
+        };
+        for param in split_top_level(rest, ';').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=')?;
+            let string = || Some(value.strip_prefix('"')?.strip_suffix('"')?.to_string());
+            match name.trim() {
+                "keyid" => signature.key_id = Some(string()?),
+                "alg" => signature.algorithm = Some(string()?),
+                "created" => signature.created = Some(value.parse().ok()?),
+                "expires" => signature.expires = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(signature)
+    }
+
+    /// Id of the key which was used to create this signature
+    pub(crate) fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Verifies the signature for a request with the given headers and `public_key`.
+    ///
+    /// The signature must cover the method and the target of the request, and must have a
+    /// `created` parameter within the validity window. For requests with a body, it must also
+    /// cover the `Content-Digest` header.
+    pub(crate) fn verify(
+        &self,
+        header_map: &BTreeMap<String, String>,
+        method: &Method,
+        uri: &Uri,
+        public_key: &RsaPublicKey,
+    ) -> Result<(), Error> {
+        let covers = |c: &str| self.components.iter().any(|component| component == c);
+        let required = match has_body(method, header_map) {
+            true => &COVERED_COMPONENTS[..],
+            false => &COVERED_COMPONENTS[..2],
+        };
+        if !required.iter().all(|c| covers(c)) {
+            return Err(Error::ActivitySignatureInvalid);
+        }
+        if self.algorithm.as_deref().is_some_and(|a| a != ALGORITHM) {
+            return Err(Error::ActivitySignatureInvalid);
+        }
+        // Without creation time, a captured signature could be replayed forever
+        let Some(created) = self.created else {
+            return Err(Error::ActivitySignatureInvalid);
+        };
+        let now = unix_time(SystemTime::now());
+        let expired = self.expires.is_some_and(|e| e < now)
+            || created > now + CLOCK_SKEW.as_secs()
+            || created + EXPIRES_AFTER.as_secs() < now;
+        if expired {
+            return Err(Error::ActivitySignatureInvalid);
+        }
+
+        let verified = target_uris(uri, header_map).iter().any(|target| {
+            signature_base(&self.components, &self.params, method, target, header_map).is_some_and(
+                |base| {
+                    public_key
+                        .verify(
+                            Pkcs1v15Sign::new::<Sha256>(),
+                            &Sha256::digest(base.as_bytes()),
+                            &self.signature,
+                        )
+                        .is_ok()
+                },
+            )
+        });
+        if verified {
+            Ok(())
+        } else {
+            Err(Error::ActivitySignatureInvalid)
+        }
+    }
+}
+
+/// Returns true if the request may have a body. This is the case for all methods except GET and
+/// HEAD, and for any request which declares a body length or transfer encoding.
+fn has_body(method: &Method, header_map: &BTreeMap<String, String>) -> bool {
+    let content_length = header_map
+        .get("content-length")
+        .is_some_and(|l| l.trim() != "0");
+    !matches!(*method, Method::GET | Method::HEAD)
+        || content_length
+        || header_map.contains_key("transfer-encoding")
+}
+
+/// Creates the signature base, which is the string that gets signed. Returns `None` if a component
+/// is not present in the request.
+///
+/// <https://www.rfc-editor.org/rfc/rfc9421#name-creating-the-signature-base>
+fn signature_base(
+    components: &[String],
+    params: &str,
+    method: &Method,
+    target_uri: &str,
+    header_map: &BTreeMap<String, String>,
+) -> Option<String> {
+    let target = Url::parse(target_uri).ok()?;
+    let mut base = String::new();
+    for component in components {
+        let value = match component.as_str() {
+            "@method" => method.as_str().to_string(),
+            "@target-uri" => target_uri.to_string(),
+            "@authority" => match target.port() {
+                Some(port) => format!("{}:{port}", target.host_str()?),
+                None => target.host_str()?.to_string(),
+            },
+            "@scheme" => target.scheme().to_string(),
+            "@path" => target.path().to_string(),
+            "@query" => format!("?{}", target.query().unwrap_or_default()),
+            "@request-target" => match target.query() {
+                Some(query) => format!("{}?{query}", target.path()),
+                None => target.path().to_string(),
+            },
+            name if name.starts_with('@') => return None,
+            name => header_map.get(name)?.trim().to_string(),
+        };
+        base.push_str(&format!("\"{component}\": {value}\n"));
+    }
+    base.push_str(&format!("\"@signature-params\": {params}"));
+    Some(base)
+}
+
+/// Full urls which may have been signed as `@target-uri`. Incoming requests usually only contain
+/// the path, so the url is reconstructed from the `Host` header. As the scheme is not known, both
+/// https and http are tried.
+fn target_uris(uri: &Uri, header_map: &BTreeMap<String, String>) -> Vec<String> {
+    if uri.scheme().is_some() {
+        return vec![uri.to_string()];
+    }
+    let Some(host) = header_map.get("host") else {
+        return vec![];
+    };
+    let path_and_query = uri
+        .path_and_query()
+        .map(PathAndQuery::as_str)
+        .unwrap_or("/");
+    ["https", "http"]
+        .iter()
+        .map(|scheme| format!("{scheme}://{host}{path_and_query}"))
+        .collect()
+}
+
+/// Splits a structured header field at the separator, ignoring separators inside of quoted
+/// strings and inner lists.
+pub(crate) fn split_top_level(value: &str, separator: char) -> impl Iterator<Item = &str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            c if c == separator && !quoted && depth == 0 => {
+                parts.push(value[start..i].trim());
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(value[start..].trim());
+    parts.into_iter()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(value).map_err(|e| Error::Other(e.to_string()))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::http_signatures::test::test_keypair;
+    use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+
+    fn sign(target: &Url) -> HeaderMap {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("example.com"));
+        sign_headers(
+            &Method::POST,
+            target,
+            &mut headers,
+            b"my activity",
+            "https://example.com/u/alice#main-key",
+            &private_key,
+        )
+        .unwrap();
+        headers
+    }
+
+    fn verify(headers: &HeaderMap, method: &Method, uri: &Uri) -> Result<(), Error> {
+        let header_map: BTreeMap<_, _> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+            .collect();
+        let signatures = vec![header_map["signature"].clone()];
+        let signature = Signature::parse(&header_map["signature-input"], &signatures);
+        assert_eq!(1, signature.len());
+        assert_eq!(
+            Some("https://example.com/u/alice#main-key"),
+            signature[0].key_id()
+        );
+        let public_key = RsaPublicKey::from_public_key_pem(&test_keypair().public_key).unwrap();
+        signature[0].verify(&header_map, method, uri, &public_key)
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let target = Url::parse("https://example.com/inbox?page=1").unwrap();
+        let headers = sign(&target);
+        let input = headers["signature-input"].to_str().unwrap();
+        assert!(input.starts_with(r#"sig1=("@method" "@target-uri" "content-digest");created="#));
+        assert!(input
+            .ends_with(r#";keyid="https://example.com/u/alice#main-key";alg="rsa-v1_5-sha256""#));
+
+        // Incoming requests only contain the path
+        let uri = Uri::from_static("/inbox?page=1");
+        verify(&headers, &Method::POST, &uri).unwrap();
+        let uri = Uri::from_static("https://example.com/inbox?page=1");
+        verify(&headers, &Method::POST, &uri).unwrap();
+
+        // Different method, path or body digest
+        assert!(verify(&headers, &Method::PUT, &uri).is_err());
+        let other_uri = Uri::from_static("/inbox?page=2");
+        assert!(verify(&headers, &Method::POST, &other_uri).is_err());
+        let mut modified = headers.clone();
+        modified.insert("content-digest", HeaderValue::from_static("sha-256=:AAAA:"));
+        assert!(verify(&modified, &Method::POST, &uri).is_err());
+    }
+
+    #[test]
+    fn test_verify_required_components() {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key).unwrap();
+        let public_key = RsaPublicKey::from_public_key_pem(&test_keypair().public_key).unwrap();
+        let target = "https://example.com/inbox";
+        let mut header_map = BTreeMap::new();
+        header_map.insert("host".to_string(), "example.com".to_string());
+        header_map.insert(
+            "content-digest".to_string(),
+            content_digest_header(b"my activity"),
+        );
+        let created = unix_time(SystemTime::now());
+        let verify_request = |components: &[&str], method: &Method, header_map: &BTreeMap<_, _>| {
+            let components = components
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            let list = components
+                .iter()
+                .map(|c| format!("\"{c}\""))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let params = format!("({list});created={created};keyid=\"key\"");
+            let base = signature_base(&components, &params, method, target, header_map).unwrap();
+            let signature = Base64
+                .decode(sign_string(&private_key, &base).unwrap())
+                .unwrap();
+            let signature = Signature::parse_params(&params, signature).unwrap();
+            let uri = Uri::from_static("/inbox");
+            signature.verify(header_map, method, &uri, &public_key)
+        };
+        let verify = |components: &[&str]| verify_request(components, &Method::POST, &header_map);
+
+        verify(&["@method", "@target-uri", "content-digest"]).unwrap();
+        verify(&["content-digest", "@target-uri", "@method", "host"]).unwrap();
+        // Signatures which don't cover the body, method or target are rejected
+        let invalid = Err(Error::ActivitySignatureInvalid);
+        assert_eq!(verify(&["@method", "@target-uri"]), invalid);
+        assert_eq!(verify(&["@target-uri", "content-digest"]), invalid);
+        assert_eq!(verify(&["@method", "@path", "content-digest"]), invalid);
+
+        // Signed fetches have no body, so they don't need to cover a Content-Digest
+        let mut get_header_map = BTreeMap::new();
+        get_header_map.insert("host".to_string(), "example.com".to_string());
+        verify_request(&["@method", "@target-uri"], &Method::GET, &get_header_map).unwrap();
+        assert_eq!(
+            verify_request(&["@target-uri"], &Method::GET, &get_header_map),
+            invalid
+        );
+        // A POST without digest is still rejected, and so is a GET with a body
+        assert_eq!(
+            verify_request(&["@method", "@target-uri"], &Method::POST, &get_header_map),
+            invalid
+        );
+        get_header_map.insert("content-length".to_string(), "11".to_string());
+        assert_eq!(
+            verify_request(&["@method", "@target-uri"], &Method::GET, &get_header_map),
+            invalid
+        );
+    }
+
+    #[test]
+    fn test_verify_validity_window() {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&test_keypair().private_key).unwrap();
+        let public_key = RsaPublicKey::from_public_key_pem(&test_keypair().public_key).unwrap();
+        let mut header_map = BTreeMap::new();
+        header_map.insert("host".to_string(), "example.com".to_string());
+        header_map.insert(
+            "content-digest".to_string(),
+            content_digest_header(b"my activity"),
+        );
+        let components = COVERED_COMPONENTS.map(str::to_string);
+        let verify = |params: &str| {
+            let params = format!(r#"("@method" "@target-uri" "content-digest"){params}"#);
+            let base = signature_base(
+                &components,
+                &params,
+                &Method::POST,
+                "https://example.com/inbox",
+                &header_map,
+            )
+            .unwrap();
+            let signature = Base64
+                .decode(sign_string(&private_key, &base).unwrap())
+                .unwrap();
+            let signature = Signature::parse_params(&params, signature).unwrap();
+            let uri = Uri::from_static("/inbox");
+            signature.verify(&header_map, &Method::POST, &uri, &public_key)
+        };
+
+        let now = unix_time(SystemTime::now());
+        verify(&format!(";created={now};keyid=\"key\"")).unwrap();
+        // Signatures without creation time could be replayed forever
+        let invalid = Err(Error::ActivitySignatureInvalid);
+        assert_eq!(verify(";keyid=\"key\""), invalid);
+        assert_eq!(
+            verify(&format!(";expires={};keyid=\"key\"", now + 60)),
+            invalid
+        );
+        // Signatures which are too old, from the future or expired
+        let old = now - EXPIRES_AFTER.as_secs() - 1;
+        assert_eq!(verify(&format!(";created={old};keyid=\"key\"")), invalid);
+        let future = now + CLOCK_SKEW.as_secs() + 60;
+        assert_eq!(verify(&format!(";created={future};keyid=\"key\"")), invalid);
+        assert_eq!(
+            verify(&format!(";created={now};expires={};keyid=\"key\"", now - 1)),
+            invalid
+        );
+    }
+
+    #[test]
+    fn test_split_top_level() {
+        let value = r#"sig1=("@method" "@path");keyid="a,b", sig2=("x");alg="y""#;
+        assert_eq!(
+            vec![
+                r#"sig1=("@method" "@path");keyid="a,b""#,
+                r#"sig2=("x");alg="y""#
+            ],
+            split_top_level(value, ',').collect::<Vec<_>>()
+        );
+    }
+}
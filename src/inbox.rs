@@ -0,0 +1,348 @@
+//! Framework independent handling of incoming activities, for HTTP servers other than actix-web
+//! and axum.
+//!
+//! The request only needs to be converted to types of the [http] crate:
+//!
+//! ```
+//! # use activitypub_federation::config::Data;
+//! # use activitypub_federation::error::Error;
+//! # use activitypub_federation::inbox::receive_activity_parts;
+//! # use activitypub_federation::traits::ActivityHandler;
+//! # use activitypub_federation::traits::tests::{DbConnection, DbUser, Follow};
+//! # use bytes::Bytes;
+//! async fn inbox(request: http::Request<Bytes>, data: Data<DbConnection>) -> Result<(), Error> {
+//!     let (parts, body) = request.into_parts();
+//!     let (activity, _actor) = receive_activity_parts::<Follow, DbUser, DbConnection>(
+//!         &parts.headers,
+//!         &parts.method,
+//!         &parts.uri,
+//!         &body,
+//!         &data,
+//!     )
+//!     .await?;
+//!     activity.verify(&data).await?;
+//!     activity.receive(&data).await
+//! }
+//! ```
+
+use crate::{
+    config::Data,
+    error::Error,
+    extract_kind,
+    http_signatures::{
+        is_key_of_actor,
+        signature_key_ids,
+        signing_actor,
+        verify_body_digest,
+        verify_signature_with_refetch,
+    },
+    parse_received_activity_borrowed,
+    protocol::verification::verify_domains_match,
+    traits::{ActivityHandler, Actor, Object},
+};
+use http::{HeaderMap, Method, Uri};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Verifies the body size, body digest and HTTP signature of an incoming activity, and returns it
+/// together with the signing actor.
+///
+/// This performs the same checks as the inbox helpers for actix-web and axum. The caller is
+/// responsible for calling [ActivityHandler::verify] and [ActivityHandler::receive]. The activity
+/// may borrow from `body`, for example using `&str` fields with `#[serde(borrow)]`.
+pub async fn receive_activity_parts<'a, Activity, ActorT, Datatype>(
+    headers: &HeaderMap,
+    method: &Method,
+    uri: &Uri,
+    body: &'a [u8],
+    data: &Data<Datatype>,
+) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    let body_digest = Sha256::digest(body);
+    verify_activity::<Activity, ActorT, Datatype>(headers, method, uri, body, &body_digest, data)
+        .await
+}
+
+/// Same as [receive_activity_parts], but with the SHA-256 hash of the body which was already
+/// computed while reading it.
+pub(crate) async fn verify_activity<'a, Activity, ActorT, Datatype>(
+    headers: &HeaderMap,
+    method: &Method,
+    uri: &Uri,
+    body: &'a [u8],
+    body_digest: &[u8],
+    data: &Data<Datatype>,
+) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'a> + Send,
+    ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
+    for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
+    <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
+    <ActorT as Object>::Error: From<Error>,
+    Datatype: Clone,
+{
+    if body.len() > data.config.max_incoming_body_size {
+        return Err(Error::RequestBodyLimit.into());
+    }
+    verify_body_digest(headers, body_digest)?;
+
+    let (activity, actor) =
+        parse_received_activity_borrowed::<Activity, ActorT, _>(body, data).await?;
+
+    if is_forwarded(headers, activity.actor(), body, data)? {
+        signing_actor::<ActorT, _>(headers, method, uri, data).await?;
+        return Ok((activity, actor));
+    }
+    let actor = verify_signature_with_refetch::<ActorT>(
+        headers,
+        method,
+        uri,
+        activity.actor(),
+        actor,
+        data,
+    )
+    .await?;
+    Ok((activity, actor))
+}
+
+/// Checks that the request is signed with a key of the activity's `actor`, and returns false in
+/// this case. Returns true if it is instead an `Announce` which is signed by another actor on the
+/// same domain, and
+/// [FederationConfigBuilder::allow_same_origin_announce_forwarding](crate::config::FederationConfigBuilder::allow_same_origin_announce_forwarding)
+/// is enabled.
+///
+/// Requests without a key id are left to the signature verification, which rejects them.
+fn is_forwarded<T: Clone>(
+    headers: &HeaderMap,
+    claimed: &Url,
+    body: &[u8],
+    data: &Data<T>,
+) -> Result<bool, Error> {
+    let key_ids = signature_key_ids(headers);
+    let Some(key_id) = key_ids.first() else {
+        return Ok(false);
+    };
+    if key_ids
+        .iter()
+        .any(|key_id| is_key_of_actor(key_id, claimed))
+    {
+        return Ok(false);
+    }
+    let signer = key_id.split_once('#').map_or(key_id.as_str(), |(id, _)| id);
+    let signer = Url::parse(signer).map_err(|_| Error::ActivitySignatureInvalid)?;
+    let same_origin = key_ids.iter().all(|key_id| {
+        Url::parse(key_id).is_ok_and(|key_id| verify_domains_match(&key_id, claimed).is_ok())
+    });
+    if data.config.allow_same_origin_announce_forwarding
+        && same_origin
+        && extract_kind(body).is_ok_and(|kind| kind == "Announce")
+    {
+        return Ok(true);
+    }
+    Err(Error::SignatureActorMismatch {
+        signer: Box::new(signer),
+        claimed: Box::new(claimed.clone()),
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::FederationConfig,
+        fetch::object_id::ObjectId,
+        http_signatures::build_signed_headers,
+        traits::tests::{DbConnection, DbUser, Follow, DB_USER, DB_USER_KEYPAIR},
+    };
+
+    async fn setup() -> (Vec<u8>, HeaderMap, Data<DbConnection>) {
+        let activity = Follow {
+            actor: ObjectId::parse("http://localhost:123").unwrap(),
+            object: ObjectId::parse("http://localhost:124").unwrap(),
+            kind: Default::default(),
+            id: "http://localhost:123/1".try_into().unwrap(),
+        };
+        let body = serde_json::to_vec(&activity).unwrap();
+        let headers = build_signed_headers(
+            &Url::parse("https://example.com/inbox").unwrap(),
+            Method::POST,
+            &body,
+            activity.actor.inner(),
+            &DB_USER_KEYPAIR.private_key,
+            false,
+            &Default::default(),
+        )
+        .unwrap();
+        let data = FederationConfig::builder()
+            .domain("localhost:8002")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        (body, headers, data)
+    }
+
+    async fn receive(
+        headers: &HeaderMap,
+        body: &[u8],
+        data: &Data<DbConnection>,
+    ) -> Result<(Follow, DbUser), Error> {
+        let uri = Uri::from_static("/inbox");
+        receive_activity_parts::<Follow, DbUser, DbConnection>(
+            headers,
+            &Method::POST,
+            &uri,
+            body,
+            data,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_parts() {
+        let (body, headers, data) = setup().await;
+        let (activity, actor) = receive(&headers, &body, &data).await.unwrap();
+        assert_eq!(activity.id.as_str(), "http://localhost:123/1");
+        assert_eq!(actor.federation_id, DB_USER.federation_id);
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_parts_invalid() {
+        let (body, headers, data) = setup().await;
+
+        let err = receive(&headers, b"invalid", &data).await.unwrap_err();
+        assert_eq!(err, Error::ActivityBodyDigestInvalid);
+
+        let mut unsigned = headers.clone();
+        unsigned.remove("signature");
+        let err = receive(&unsigned, &body, &data).await.unwrap_err();
+        assert_eq!(err, Error::ActivitySignatureInvalid);
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_parts_json_depth() {
+        let (_, _, data) = setup().await;
+        let sign = |body: &[u8]| {
+            build_signed_headers(
+                &Url::parse("https://example.com/inbox").unwrap(),
+                Method::POST,
+                body,
+                &Url::parse("http://localhost:123").unwrap(),
+                &DB_USER_KEYPAIR.private_key,
+                false,
+                &Default::default(),
+            )
+            .unwrap()
+        };
+        // Follow with an unknown field, so that the activity itself is one level of nesting
+        let follow = |depth: usize| {
+            format!(
+                r#"{{"actor":"http://localhost:123","object":"http://localhost:124","type":"Follow","id":"http://localhost:123/1","extra":{}{}}}"#,
+                "[".repeat(depth - 1),
+                "]".repeat(depth - 1)
+            )
+            .into_bytes()
+        };
+
+        // Just under and at the default limit of 128
+        for depth in [127, 128] {
+            let body = follow(depth);
+            receive(&sign(&body), &body, &data).await.unwrap();
+        }
+
+        let body = follow(129);
+        let err = receive(&sign(&body), &body, &data).await.unwrap_err();
+        assert_eq!(err, Error::JsonDepthLimit);
+
+        // Pathological body which would overflow the stack of a recursive parser
+        let body = follow(100_000);
+        let err = receive(&sign(&body), &body, &data).await.unwrap_err();
+        assert_eq!(err, Error::JsonDepthLimit);
+        assert_eq!(err.status_code(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_receive_activity_parts_signer_mismatch() {
+        let (body, _, data) = setup().await;
+
+        // Signed by another actor on the same instance, which happens to have the same key
+        let mallory = Url::parse("http://localhost:123/u/mallory").unwrap();
+        let headers = build_signed_headers(
+            &Url::parse("https://example.com/inbox").unwrap(),
+            Method::POST,
+            &body,
+            &mallory,
+            &DB_USER_KEYPAIR.private_key,
+            false,
+            &Default::default(),
+        )
+        .unwrap();
+        let err = receive(&headers, &body, &data).await.unwrap_err();
+        assert_eq!(
+            err,
+            Error::SignatureActorMismatch {
+                signer: Box::new(mallory),
+                claimed: Box::new(Url::parse("http://localhost:123").unwrap()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_is_forwarded() {
+        let (_, _, data) = setup().await;
+        let forwarding = FederationConfig::builder()
+            .domain("localhost:8002")
+            .app_data(DbConnection)
+            .allow_same_origin_announce_forwarding(true)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let group = Url::parse("http://localhost:123/c/main").unwrap();
+        let headers = |signer: &str, body: &[u8]| {
+            build_signed_headers(
+                &Url::parse("https://example.com/inbox").unwrap(),
+                Method::POST,
+                body,
+                &Url::parse(signer).unwrap(),
+                &DB_USER_KEYPAIR.private_key,
+                false,
+                &Default::default(),
+            )
+            .unwrap()
+        };
+        let announce = br#"{"type":"Announce"}"#;
+        let follow = br#"{"type":"Follow"}"#;
+
+        // Signed by the actor itself
+        let own = headers(group.as_str(), announce);
+        assert!(!is_forwarded(&own, &group, announce, &data).unwrap());
+        assert!(!is_forwarded(&own, &group, announce, &forwarding).unwrap());
+
+        // Announce signed by the instance actor, only accepted if enabled
+        let instance = headers("http://localhost:123/actor", announce);
+        assert!(is_forwarded(&instance, &group, announce, &forwarding).unwrap());
+        assert!(matches!(
+            is_forwarded(&instance, &group, announce, &data),
+            Err(Error::SignatureActorMismatch { .. })
+        ));
+
+        // Other activities and other domains are never forwarded
+        let instance = headers("http://localhost:123/actor", follow);
+        assert!(is_forwarded(&instance, &group, follow, &forwarding).is_err());
+        let remote = headers("http://remote.example/actor", announce);
+        assert!(is_forwarded(&remote, &group, announce, &forwarding).is_err());
+    }
+}
@@ -0,0 +1,479 @@
+//! Actor which represents the whole instance, such as Mastodon's `/actor`
+//!
+//! Secure mode federation and relay subscriptions need an actor which doesn't belong to any
+//! user. It signs fetch requests, see
+//! [FederationConfigBuilder::signed_fetch_actor](crate::config::FederationConfigBuilder::signed_fetch_actor),
+//! and can follow relays. [InstanceActor] implements [Object] and [Actor] for it, so that only
+//! its keypair needs to be persisted with [InstanceKeypairStore]. The keypair is generated on
+//! first start.
+//!
+//! ```
+//! # use activitypub_federation::{config::FederationConfig, http_signatures::Keypair};
+//! # use activitypub_federation::error::Error;
+//! # use activitypub_federation::instance_actor::{InstanceActor, InstanceKeypairStore};
+//! # use std::sync::{Arc, Mutex};
+//! # use url::Url;
+//! #[derive(Clone, Default)]
+//! struct Database {
+//!     keypair: Arc<Mutex<Option<Keypair>>>,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl InstanceKeypairStore for Database {
+//!     async fn read_instance_keypair(&self) -> Result<Option<Keypair>, Error> {
+//!         Ok(self.keypair.lock().unwrap().clone())
+//!     }
+//!
+//!     async fn write_instance_keypair(&self, keypair: &Keypair) -> Result<(), Error> {
+//!         *self.keypair.lock().unwrap() = Some(keypair.clone());
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let database = Database::default();
+//! let id = Url::parse("https://example.com/actor")?;
+//! let instance_actor = InstanceActor::load_or_generate(id, &database).await?;
+//! let config = FederationConfig::builder()
+//!     .domain("example.com")
+//!     .signed_fetch_actor(&instance_actor)
+//!     .app_data(database)
+//!     .build()
+//!     .await?;
+//! # Ok::<(), anyhow::Error>(())
+//! # }).unwrap();
+//! ```
+//!
+//! The actor needs to be served at its id, and its empty inbox and outbox collections at
+//! `{id}/inbox` and `{id}/outbox`. [Application] and [OrderedCollection] can be returned directly
+//! from axum and actix-web handlers, and are served with the default context. Incoming activities
+//! for the instance actor, such as an `Accept` from a relay, can be handled by adding a `POST`
+//! route for `{id}/inbox` which receives them like the shared inbox. With axum:
+//!
+//! ```
+//! # use activitypub_federation::{config::Data, instance_actor::InstanceActor};
+//! # use activitypub_federation::traits::tests::DbConnection;
+//! # use axum::{routing::get, Router};
+//! fn routes(actor: InstanceActor<DbConnection>) -> Router {
+//!     let inbox = actor.inbox_collection();
+//!     let outbox = actor.outbox_collection();
+//!     Router::new()
+//!         .route(
+//!             "/actor",
+//!             get(|data: Data<DbConnection>| async move { actor.to_json(data.key_id_strategy()) }),
+//!         )
+//!         .route("/actor/inbox", get(|| async move { inbox }))
+//!         .route("/actor/outbox", get(|| async move { outbox }))
+//! }
+//! ```
+//!
+//! With actix-web:
+//!
+//! ```
+//! # use activitypub_federation::{config::Data, instance_actor::InstanceActor};
+//! # use activitypub_federation::traits::tests::DbConnection;
+//! # use actix_web::web;
+//! fn routes(actor: InstanceActor<DbConnection>, cfg: &mut web::ServiceConfig) {
+//!     let inbox = actor.inbox_collection();
+//!     let outbox = actor.outbox_collection();
+//!     cfg.route(
+//!         "/actor",
+//!         web::get().to(move |data: Data<DbConnection>| {
+//!             let json = actor.to_json(data.key_id_strategy());
+//!             async move { json }
+//!         }),
+//!     )
+//!     .route("/actor/inbox", web::get().to(move || {
+//!         let inbox = inbox.clone();
+//!         async move { inbox }
+//!     }))
+//!     .route("/actor/outbox", web::get().to(move || {
+//!         let outbox = outbox.clone();
+//!         async move { outbox }
+//!     }));
+//! }
+//! ```
+//!
+//! Webfinger requests for `{host}@{domain}`, for example `example.com@example.com`, should be
+//! answered with [InstanceActor::webfinger]. Remote instance actors can be fetched with
+//! [ObjectId](crate::fetch::object_id::ObjectId), and are not stored, so that they are fetched
+//! again each time.
+
+use crate::{
+    config::Data,
+    error::Error,
+    fetch::webfinger::{build_webfinger_response_with_type, Webfinger},
+    http_signatures::{generate_actor_keypair, Keypair},
+    protocol::{
+        collections::OrderedCollection,
+        endpoints::Endpoints,
+        public_key::{KeyIdStrategy, PublicKey},
+        verification::verify_domains_match,
+    },
+    traits::{Actor, Object},
+};
+use activitystreams_kinds::actor::ApplicationType;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Formatter},
+    marker::PhantomData,
+};
+use url::Url;
+
+/// Persists the keypair of the local [InstanceActor], usually in the app data. It must not
+/// change between restarts, otherwise remote instances can't verify signatures until they
+/// refetch the actor.
+#[async_trait]
+pub trait InstanceKeypairStore: Send + Sync {
+    /// Returns the keypair which was written before, or `None` on first start
+    async fn read_instance_keypair(&self) -> Result<Option<Keypair>, Error>;
+
+    /// Stores a newly generated keypair
+    async fn write_instance_keypair(&self, keypair: &Keypair) -> Result<(), Error>;
+}
+
+/// Actor which represents the whole instance, see the [module docs](self).
+///
+/// `T` is the app data type, which is used as [Object::DataType].
+#[derive(Clone)]
+pub struct InstanceActor<T> {
+    id: Url,
+    name: String,
+    public_key_pem: String,
+    private_key_pem: Option<String>,
+    inbox: Url,
+    outbox: Url,
+    shared_inbox: Option<Url>,
+    _data: PhantomData<fn() -> T>,
+}
+
+impl<T> InstanceActor<T> {
+    /// Local instance actor with the given `id` and keypair. Inbox and outbox are at
+    /// `{id}/inbox` and `{id}/outbox`, and the name is the host of `id`.
+    pub fn new(id: Url, keypair: Keypair) -> Result<Self, Error> {
+        let name = id
+            .host_str()
+            .ok_or(Error::UrlVerificationError("Instance actor id has no host"))?
+            .to_string();
+        let base = id.as_str().trim_end_matches('/');
+        Ok(InstanceActor {
+            inbox: Url::parse(&format!("{base}/inbox"))?,
+            outbox: Url::parse(&format!("{base}/outbox"))?,
+            id,
+            name,
+            public_key_pem: keypair.public_key,
+            private_key_pem: Some(keypair.private_key),
+            shared_inbox: None,
+            _data: PhantomData,
+        })
+    }
+
+    /// Local instance actor with the keypair from `store`. If there is none yet, a new keypair
+    /// is generated and written to `store`.
+    pub async fn load_or_generate(id: Url, store: &T) -> Result<Self, Error>
+    where
+        T: InstanceKeypairStore,
+    {
+        let keypair = match store.read_instance_keypair().await? {
+            Some(keypair) => keypair,
+            None => {
+                let keypair = generate_actor_keypair()?;
+                store.write_instance_keypair(&keypair).await?;
+                keypair
+            }
+        };
+        Self::new(id, keypair)
+    }
+
+    /// Advertise the shared inbox of the instance in `endpoints`
+    pub fn with_shared_inbox(mut self, shared_inbox: Url) -> Self {
+        self.shared_inbox = Some(shared_inbox);
+        self
+    }
+
+    /// Name of the actor in `preferredUsername`, which is the host of the instance
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Outbox of the actor
+    pub fn outbox(&self) -> &Url {
+        &self.outbox
+    }
+
+    /// Json representation of the actor, with the public key id generated by `strategy`.
+    /// [Object::into_json] uses the strategy of the config.
+    pub fn to_json(&self, strategy: &KeyIdStrategy) -> Application {
+        Application {
+            kind: Default::default(),
+            id: self.id.clone(),
+            preferred_username: self.name.clone(),
+            inbox: self.inbox.clone(),
+            outbox: self.outbox.clone(),
+            public_key: PublicKey::new_with_strategy(
+                self.id.clone(),
+                self.public_key_pem.clone(),
+                strategy,
+            ),
+            endpoints: self.shared_inbox.clone().map(|shared_inbox| Endpoints {
+                shared_inbox: Some(shared_inbox),
+            }),
+        }
+    }
+
+    /// Empty collection to serve at the inbox url
+    pub fn inbox_collection(&self) -> OrderedCollection {
+        empty_collection(self.inbox.clone())
+    }
+
+    /// Empty collection to serve at the outbox url
+    pub fn outbox_collection(&self) -> OrderedCollection {
+        empty_collection(self.outbox.clone())
+    }
+
+    /// Webfinger response for `acct:{host}@{domain}`, where the domain includes the port if the
+    /// id has one
+    pub fn webfinger(&self) -> Webfinger {
+        let domain = match self.id.port() {
+            Some(port) => format!("{}:{port}", self.name),
+            None => self.name.clone(),
+        };
+        build_webfinger_response_with_type(
+            format!("acct:{}@{domain}", self.name),
+            vec![(self.id.clone(), Some("Application"))],
+        )
+    }
+}
+
+fn empty_collection(id: Url) -> OrderedCollection {
+    OrderedCollection {
+        kind: Default::default(),
+        id,
+        total_items: 0,
+        first: None,
+        last: None,
+    }
+}
+
+/// The private key is left out
+impl<T> Debug for InstanceActor<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InstanceActor")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("inbox", &self.inbox)
+            .field("outbox", &self.outbox)
+            .field("shared_inbox", &self.shared_inbox)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Json representation of an [InstanceActor]
+///
+/// <https://www.w3.org/TR/activitystreams-vocabulary/#dfn-application>
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Application {
+    /// Always `Application`
+    #[serde(rename = "type")]
+    pub kind: ApplicationType,
+    /// Id of the actor
+    pub id: Url,
+    /// Name of the actor, usually the host of the instance
+    pub preferred_username: String,
+    /// Inbox of the actor
+    pub inbox: Url,
+    /// Outbox of the actor
+    pub outbox: Url,
+    /// Key to verify signatures of the actor
+    pub public_key: PublicKey,
+    /// Shared inbox of the instance, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoints: Option<Endpoints>,
+}
+
+#[async_trait]
+impl<T> Object for InstanceActor<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    type DataType = T;
+    type Kind = Application;
+    type Error = Error;
+
+    /// Instance actors are not stored, so this always returns `None`
+    async fn read_from_id(
+        _object_id: Url,
+        _data: &Data<Self::DataType>,
+    ) -> Result<Option<Self>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn into_json(self, data: &Data<Self::DataType>) -> Result<Self::Kind, Self::Error> {
+        Ok(self.to_json(data.key_id_strategy()))
+    }
+
+    async fn verify(
+        json: &Self::Kind,
+        expected_domain: &Url,
+        _data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        verify_domains_match(&json.id, expected_domain)?;
+        verify_domains_match(&json.public_key.owner, expected_domain)?;
+        Ok(())
+    }
+
+    async fn from_json(
+        json: Self::Kind,
+        _data: &Data<Self::DataType>,
+    ) -> Result<Self, Self::Error> {
+        Ok(InstanceActor {
+            id: json.id,
+            name: json.preferred_username,
+            public_key_pem: json.public_key.public_key_pem,
+            private_key_pem: None,
+            inbox: json.inbox,
+            outbox: json.outbox,
+            shared_inbox: json.endpoints.and_then(|e| e.shared_inbox),
+            _data: PhantomData,
+        })
+    }
+}
+
+impl<T> Actor for InstanceActor<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    fn id(&self) -> Url {
+        self.id.clone()
+    }
+
+    fn public_key_pem(&self) -> &str {
+        &self.public_key_pem
+    }
+
+    fn private_key_pem(&self) -> Option<String> {
+        self.private_key_pem.clone()
+    }
+
+    fn inbox(&self) -> Url {
+        self.inbox.clone()
+    }
+
+    fn shared_inbox(&self) -> Option<Url> {
+        self.shared_inbox.clone()
+    }
+
+    fn actor_type() -> Option<&'static str> {
+        Some("Application")
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{config::FederationConfig, traits::tests::DbConnection};
+    use serde_json::json;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    };
+
+    #[derive(Clone, Default)]
+    struct Store {
+        keypair: Arc<Mutex<Option<Keypair>>>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl InstanceKeypairStore for Store {
+        async fn read_instance_keypair(&self) -> Result<Option<Keypair>, Error> {
+            Ok(self.keypair.lock().unwrap().clone())
+        }
+
+        async fn write_instance_keypair(&self, keypair: &Keypair) -> Result<(), Error> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            *self.keypair.lock().unwrap() = Some(keypair.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_or_generate() -> Result<(), Error> {
+        let store = Store::default();
+        let id = Url::parse("https://example.com/actor")?;
+        let first = InstanceActor::load_or_generate(id.clone(), &store).await?;
+        let second = InstanceActor::load_or_generate(id, &store).await?;
+        assert_eq!(store.writes.load(Ordering::Relaxed), 1);
+        assert_eq!(first.public_key_pem(), second.public_key_pem());
+        assert!(second.private_key_pem().is_some());
+        assert!(!format!("{second:?}").contains("PRIVATE KEY"));
+
+        // Can be used to sign fetches directly
+        FederationConfig::builder()
+            .domain("example.com")
+            .signed_fetch_actor(&second)
+            .app_data(store)
+            .build()
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_instance_actor_json() -> Result<(), Error> {
+        let data = FederationConfig::builder()
+            .domain("localhost:8001")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let keypair = generate_actor_keypair()?;
+        let actor = InstanceActor::<DbConnection>::new(
+            Url::parse("http://localhost:8001/actor")?,
+            keypair.clone(),
+        )?
+        .with_shared_inbox(Url::parse("http://localhost:8001/inbox")?);
+
+        let json = actor.clone().into_json(&data).await?;
+        assert_eq!(
+            serde_json::to_value(&json).unwrap(),
+            json!({
+                "type": "Application",
+                "id": "http://localhost:8001/actor",
+                "preferredUsername": "localhost",
+                "inbox": "http://localhost:8001/actor/inbox",
+                "outbox": "http://localhost:8001/actor/outbox",
+                "publicKey": {
+                    "id": "http://localhost:8001/actor#main-key",
+                    "owner": "http://localhost:8001/actor",
+                    "publicKeyPem": keypair.public_key,
+                },
+                "endpoints": { "sharedInbox": "http://localhost:8001/inbox" }
+            })
+        );
+        assert_eq!(actor.outbox_collection().total_items, 0);
+        assert_eq!(actor.inbox_collection().id, actor.inbox());
+
+        let expected_domain = Url::parse("http://localhost:8001/")?;
+        InstanceActor::<DbConnection>::verify(&json, &expected_domain, &data).await?;
+        let parsed = InstanceActor::<DbConnection>::from_json(json, &data).await?;
+        assert_eq!(parsed.private_key_pem(), None);
+        assert_eq!(
+            parsed.shared_inbox_or_inbox(),
+            actor.shared_inbox_or_inbox()
+        );
+
+        // The webfinger subject must be accepted by webfinger queries
+        let webfinger = actor.webfinger();
+        assert_eq!(webfinger.subject, "acct:localhost@localhost:8001");
+        let query = crate::fetch::webfinger::parse_webfinger_query(&webfinger.subject, &data)?;
+        assert_eq!(query.name, actor.name());
+        Ok(())
+    }
+}
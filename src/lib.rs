@@ -18,44 +18,69 @@ pub mod actix_web;
 pub mod axum;
 pub mod config;
 pub mod error;
+#[cfg(feature = "example-storage")]
+pub mod example_storage;
 pub mod fetch;
 pub mod http_signatures;
+pub mod inbox;
+pub mod instance_actor;
+pub mod metrics;
 pub mod protocol;
 pub(crate) mod reqwest_shim;
 pub mod traits;
+pub mod url;
 
 use crate::{
     config::Data,
-    error::Error,
+    error::{body_snippet, Error, JsonError},
     fetch::object_id::ObjectId,
+    protocol::{helpers::deserialize_one_or_many, verification::verify_json_depth},
     traits::{ActivityHandler, Actor, Object},
 };
 pub use activitystreams_kinds as kinds;
 
-use serde::{de::DeserializeOwned, Deserialize};
-use url::Url;
+use ::url::Url;
+use bytes::Bytes;
+use serde::Deserialize;
+use std::future::Future;
+use tracing::{debug, info_span, Instrument};
+
+/// Used by code which is generated by the derive macros
+#[doc(hidden)]
+pub mod __private {
+    pub use ::url::Url;
+    pub use async_trait::async_trait;
+    pub use serde;
+    pub use serde_json;
+    pub use serde_path_to_error;
+}
 
 /// Mime type for Activitypub data, used for `Accept` and `Content-Type` HTTP headers
 pub const FEDERATION_CONTENT_TYPE: &str = "application/activity+json";
 
 /// Deserialize incoming inbox activity to the given type, perform basic
 /// validation and extract the actor.
-async fn parse_received_activity<Activity, ActorT, Datatype>(
-    body: &[u8],
+///
+/// The activity may borrow data from `body` instead of copying it. This avoids allocations for
+/// large string fields, but the activity can't outlive the request body.
+async fn parse_received_activity_borrowed<'de, Activity, ActorT, Datatype>(
+    body: &'de [u8],
     data: &Data<Datatype>,
 ) -> Result<(Activity, ActorT), <Activity as ActivityHandler>::Error>
 where
-    Activity: ActivityHandler<DataType = Datatype> + DeserializeOwned + Send + 'static,
+    Activity: ActivityHandler<DataType = Datatype> + Deserialize<'de> + Send,
     ActorT: Object<DataType = Datatype> + Actor + Send + 'static,
     for<'de2> <ActorT as Object>::Kind: serde::Deserialize<'de2>,
     <Activity as ActivityHandler>::Error: From<Error> + From<<ActorT as Object>::Error>,
     <ActorT as Object>::Error: From<Error>,
     Datatype: Clone,
 {
-    let activity: Activity = serde_json::from_slice(body).map_err(|e| {
+    verify_json_depth(body, data.config.max_json_depth)?;
+    let activity: Activity = JsonError::parse(body).map_err(|e| {
+        debug!("Failed to parse incoming activity: {}", body_snippet(body));
         // Attempt to include activity id in error message
         let id = extract_id(body).ok();
-        Error::ParseReceivedActivity(e, id)
+        Error::ParseReceivedActivity(Box::new(e), id)
     })?;
     data.config.verify_url_and_domain(&activity).await?;
     let actor = ObjectId::<ActorT>::from(activity.actor().clone())
@@ -64,11 +89,203 @@ where
     Ok((activity, actor))
 }
 
+/// Verifies and receives an activity which was already checked by the inbox. If the activity is
+/// rejected by the activity filter, or its id is in the received activity cache, it is ignored
+/// instead.
+///
+/// After successful verification the activity is passed to `receive`, which usually calls
+/// [ActivityHandler::receive].
+async fn process_received_activity<Activity, Datatype, F, Fut>(
+    activity: Activity,
+    body: Bytes,
+    data: &Data<Datatype>,
+    receive: F,
+) -> Result<ReceiveOutcome, <Activity as ActivityHandler>::Error>
+where
+    Activity: ActivityHandler<DataType = Datatype>,
+    F: FnOnce(Activity) -> Fut,
+    Fut: Future<Output = Result<(), <Activity as ActivityHandler>::Error>>,
+    Datatype: Clone,
+{
+    let activity_id = activity.id().clone();
+    let kind = extract_kind(&body).ok();
+    let span = info_span!(
+        "receive_activity",
+        activity_id = %activity_id,
+        actor = %activity.actor(),
+        r#type = kind.as_deref(),
+    );
+    let outcome = async {
+        if let Some(filter) = &data.config.activity_filter {
+            if !filter
+                .accept(&activity_id, activity.actor(), kind.as_deref())
+                .await
+            {
+                debug!("Ignoring activity {activity_id} which was rejected by filter");
+                return Ok(ReceiveOutcome::FilteredByHook);
+            }
+        }
+
+        let cache = data.config.received_activity_cache.as_ref();
+        if let Some(cache) = cache {
+            let entry = cache.entry(activity_id.clone()).or_insert(()).await;
+            if !entry.is_fresh() {
+                debug!("Ignoring duplicate activity {activity_id}");
+                let activity_id = activity_id.clone();
+                return Ok(ReceiveOutcome::DuplicateIgnored { activity_id });
+            }
+        }
+
+        debug!("Receiving activity {activity_id}");
+        let _received = data.received_activity.enter(body);
+        let res = async {
+            activity.verify(data).await?;
+            receive(activity).await
+        }
+        .await;
+        if let (Err(_), Some(cache)) = (&res, cache) {
+            // Allow the sender to retry failed activities
+            cache.invalidate(&activity_id).await;
+        }
+        let activity_id = activity_id.clone();
+        res.map(|_| ReceiveOutcome::Processed { activity_id })
+    }
+    .instrument(span)
+    .await;
+    data.config
+        .metrics_hook
+        .on_receive(&activity_id, kind.as_deref(), outcome.as_ref().ok());
+    outcome
+}
+
+/// Returns [ReceiveOutcome::UnknownTypeIgnored] if the `type` of the activity in `body` is not
+/// handled by `Activity`, see [ActivityHandler::handles_kind]. Such activities are ignored before
+/// verifying the signature, so that the actor doesn't need to be fetched.
+fn ignore_unknown_kind<Activity, Datatype>(
+    body: &[u8],
+    data: &Data<Datatype>,
+) -> Option<ReceiveOutcome>
+where
+    Activity: ActivityHandler<DataType = Datatype>,
+    Datatype: Clone,
+{
+    let kind = extract_kind(body).ok()?;
+    if Activity::handles_kind(&kind) {
+        return None;
+    }
+    let activity_id = extract_id(body).ok()?;
+    debug!("Ignoring activity {activity_id} with unknown type {kind}");
+    let outcome = ReceiveOutcome::UnknownTypeIgnored { kind: kind.clone() };
+    data.config
+        .metrics_hook
+        .on_receive(&activity_id, Some(&kind), Some(&outcome));
+    Some(outcome)
+}
+
 /// Attempt to parse id field from serialized json
-fn extract_id(data: &[u8]) -> serde_json::Result<Url> {
+fn extract_id(data: &[u8]) -> Result<Url, JsonError> {
     #[derive(Deserialize)]
     struct Id {
         id: Url,
     }
-    Ok(serde_json::from_slice::<Id>(data)?.id)
+    Ok(JsonError::parse::<Id>(data)?.id)
+}
+
+/// Attempt to parse the addressing fields from serialized json, and return the recipients which
+/// belong to the local domain. Entries which are not Urls, such as embedded objects, are skipped.
+fn extract_local_recipients<T: Clone>(body: &[u8], data: &Data<T>) -> Vec<Url> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Recipient {
+        Url(Url),
+        #[allow(dead_code)]
+        Other(serde_json::Map<String, serde_json::Value>),
+        #[allow(dead_code)]
+        Invalid(String),
+    }
+    #[derive(Deserialize)]
+    struct Recipients {
+        #[serde(default, deserialize_with = "deserialize_one_or_many")]
+        to: Vec<Recipient>,
+        #[serde(default, deserialize_with = "deserialize_one_or_many")]
+        cc: Vec<Recipient>,
+        #[serde(default, deserialize_with = "deserialize_one_or_many")]
+        bto: Vec<Recipient>,
+        #[serde(default, deserialize_with = "deserialize_one_or_many")]
+        bcc: Vec<Recipient>,
+        #[serde(default, deserialize_with = "deserialize_one_or_many")]
+        audience: Vec<Recipient>,
+    }
+    let Ok(recipients) = serde_json::from_slice::<Recipients>(body) else {
+        return vec![];
+    };
+    let mut local = vec![];
+    let all = [
+        recipients.to,
+        recipients.cc,
+        recipients.bto,
+        recipients.bcc,
+        recipients.audience,
+    ];
+    for recipient in all.into_iter().flatten() {
+        if let Recipient::Url(url) = recipient {
+            if data.config.is_local_url(&url) && !local.contains(&url) {
+                local.push(url);
+            }
+        }
+    }
+    local
+}
+
+/// Attempt to parse type field from serialized json
+pub(crate) fn extract_kind(data: &[u8]) -> serde_json::Result<String> {
+    #[derive(Deserialize)]
+    struct Kind {
+        #[serde(rename = "type")]
+        kind: String,
+    }
+    Ok(serde_json::from_slice::<Kind>(data)?.kind)
+}
+
+/// Name of the response header which contains the [ReceiveOutcome] of an incoming activity, if
+/// enabled with [FederationConfigBuilder::federation_result_header](crate::config::FederationConfigBuilder::federation_result_header).
+pub const FEDERATION_RESULT_HEADER: &str = "X-Federation-Result";
+
+/// Describes how an incoming activity was handled by the inbox. All of these result in a
+/// successful HTTP response, so the sender can't distinguish them without the
+/// [FEDERATION_RESULT_HEADER].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReceiveOutcome {
+    /// Activity was verified and passed to [ActivityHandler::receive]
+    Processed {
+        /// Id of the activity
+        activity_id: Url,
+    },
+    /// Activity with the same id was already received before, so it was not processed again
+    DuplicateIgnored {
+        /// Id of the activity
+        activity_id: Url,
+    },
+    /// Activity was rejected by the [ActivityFilter](crate::config::ActivityFilter) of the
+    /// application
+    FilteredByHook,
+    /// Activity has a type which is not handled by the application, see
+    /// [ActivityHandler::handles_kind]
+    UnknownTypeIgnored {
+        /// Value of the `type` field
+        kind: String,
+    },
+}
+
+impl ReceiveOutcome {
+    /// Value for the [FEDERATION_RESULT_HEADER]
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            ReceiveOutcome::Processed { .. } => "processed",
+            ReceiveOutcome::DuplicateIgnored { .. } => "duplicate",
+            ReceiveOutcome::FilteredByHook => "filtered",
+            ReceiveOutcome::UnknownTypeIgnored { .. } => "unknown-type",
+        }
+    }
 }
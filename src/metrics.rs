@@ -0,0 +1,314 @@
+//! Hooks for collecting metrics about federation, for example to export them to Prometheus
+//!
+//! Implement [FederationMetricsHook] and set it with
+//! [FederationConfigBuilder::metrics_hook](crate::config::FederationConfigBuilder::metrics_hook).
+//! All methods have an empty default implementation, so only the events of interest need to be
+//! handled. The hook is called directly on the task which sends, receives or fetches, so it
+//! should only update counters and not block.
+//!
+//! ```
+//! # use activitypub_federation::metrics::FederationMetricsHook;
+//! # use http::StatusCode;
+//! # use std::sync::atomic::{AtomicUsize, Ordering};
+//! # use std::time::Duration;
+//! # use url::Url;
+//! #[derive(Default)]
+//! struct FetchCounter {
+//!     fetches: AtomicUsize,
+//!     errors: AtomicUsize,
+//! }
+//!
+//! impl FederationMetricsHook for FetchCounter {
+//!     fn on_fetch(&self, _url: &Url, status: Option<StatusCode>, _duration: Duration) {
+//!         self.fetches.fetch_add(1, Ordering::Relaxed);
+//!         if !status.is_some_and(|s| s.is_success()) {
+//!             self.errors.fetch_add(1, Ordering::Relaxed);
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! In addition, sending, receiving and fetching runs inside the [tracing] spans `send_activity`,
+//! `receive_activity` and `fetch_object_http`. The activity queue runs inside the spans
+//! `activity_queue` and `activity_queue_retries`, and sends each task inside
+//! `activity_queue_worker` or `activity_queue_retry_worker`. The spans contain ids, urls and
+//! status codes, but never bodies or keys.
+
+use crate::{error::Error, ReceiveOutcome};
+use http::StatusCode;
+use std::{
+    fmt::{Debug, Formatter},
+    time::Duration,
+};
+use url::Url;
+
+/// Receives events about outgoing and incoming federation, see the [module docs](self).
+pub trait FederationMetricsHook: Send + Sync {
+    /// Called after each HTTP fetch of a remote object, collection or webfinger. `status` is
+    /// `None` if no response was received, for example because of a timeout.
+    fn on_fetch(&self, _url: &Url, _status: Option<StatusCode>, _duration: Duration) {}
+
+    /// Called when an activity was delivered to an inbox. `attempt` is the number of the send
+    /// attempt, starting at 1.
+    fn on_send_success(
+        &self,
+        _activity_id: &Url,
+        _inbox: &Url,
+        _attempt: usize,
+        _duration: Duration,
+    ) {
+    }
+
+    /// Called after each failed attempt to send an activity, including attempts which are
+    /// retried later.
+    fn on_send_failure(&self, _activity_id: &Url, _inbox: &Url, _attempt: usize, _error: &Error) {}
+
+    /// Called when an incoming activity was handled by the inbox. `kind` is the value of its
+    /// `type` field, and `outcome` is `None` if verifying or receiving the activity failed.
+    fn on_receive(
+        &self,
+        _activity_id: &Url,
+        _kind: Option<&str>,
+        _outcome: Option<&ReceiveOutcome>,
+    ) {
+    }
+}
+
+impl Debug for dyn FederationMetricsHook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FederationMetricsHook")
+    }
+}
+
+/// Default hook which ignores all events
+pub(crate) struct NoMetrics;
+
+impl FederationMetricsHook for NoMetrics {}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        activity_sending::{tests::test_task, SendActivityTask},
+        axum::json::FederationJson,
+        config::FederationConfig,
+        fetch::fetch_object_http,
+        process_received_activity,
+        traits::{
+            tests::{DbConnection, Follow},
+            ActivityHandler,
+        },
+    };
+    use axum::{
+        http::StatusCode as AxumStatusCode,
+        routing::{get, post},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer,
+        Registry,
+    };
+
+    #[derive(Default)]
+    struct CountingHook {
+        fetches: Mutex<Vec<Option<StatusCode>>>,
+        sends: Mutex<Vec<(usize, bool)>>,
+        receives: Mutex<Vec<(Option<String>, bool)>>,
+    }
+
+    impl FederationMetricsHook for CountingHook {
+        fn on_fetch(&self, _url: &Url, status: Option<StatusCode>, _duration: Duration) {
+            self.fetches.lock().unwrap().push(status);
+        }
+
+        fn on_send_success(&self, _: &Url, _: &Url, attempt: usize, _: Duration) {
+            self.sends.lock().unwrap().push((attempt, true));
+        }
+
+        fn on_send_failure(&self, _: &Url, _: &Url, attempt: usize, _: &Error) {
+            self.sends.lock().unwrap().push((attempt, false));
+        }
+
+        fn on_receive(&self, _: &Url, kind: Option<&str>, outcome: Option<&ReceiveOutcome>) {
+            let kind = kind.map(ToString::to_string);
+            self.receives
+                .lock()
+                .unwrap()
+                .push((kind, outcome.is_some()));
+        }
+    }
+
+    /// Fields of a span, formatted as strings
+    #[derive(Clone, Debug, Default)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    /// Collects the name and fields of all closed spans
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<(String, Fields)>>>);
+
+    impl SpanRecorder {
+        fn spans(&self, name: &str) -> Vec<HashMap<String, String>> {
+            let spans = self.0.lock().unwrap();
+            spans
+                .iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, fields)| fields.0.clone())
+                .collect()
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(fields);
+            }
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            if let Some(span) = ctx.span(id) {
+                if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                    values.record(fields);
+                }
+            }
+        }
+
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            if let Some(span) = ctx.span(&id) {
+                let fields = span.extensions_mut().remove::<Fields>().unwrap_or_default();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((span.name().to_string(), fields));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_hook_and_spans() -> Result<(), Error> {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!(
+            "http://localhost:{}",
+            listener.local_addr().unwrap().port()
+        ))?;
+        let object_id = base.join("/object")?;
+        let object = json!({ "id": object_id, "type": "Note" });
+        let app = Router::new()
+            .route(
+                "/object",
+                get(move || async move { FederationJson(object.clone()) }),
+            )
+            .route("/inbox", post(|| async { AxumStatusCode::OK }))
+            .route(
+                "/failing_inbox",
+                post(|| async { AxumStatusCode::INTERNAL_SERVER_ERROR }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let hook = Arc::new(CountingHook::default());
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .metrics_hook(hook.clone())
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+
+        // Fetch
+        fetch_object_http::<_, Value>(&object_id, &data).await?;
+        assert_eq!(*hook.fetches.lock().unwrap(), vec![Some(StatusCode::OK)]);
+        let fetch_spans = recorder.spans("fetch_object_http");
+        assert_eq!(fetch_spans.len(), 1);
+        assert_eq!(fetch_spans[0]["url"], object_id.as_str());
+        assert_eq!(fetch_spans[0]["status"], "200");
+        assert!(fetch_spans[0].contains_key("duration_ms"));
+
+        // Send, with the attempt number
+        let task = |inbox: &str| SendActivityTask {
+            activity_id: base.join("/activity/1").unwrap(),
+            http_signature_compat: false,
+            metrics_hook: hook.clone(),
+            ..test_task(&base.join(inbox).unwrap())
+        };
+        let client = reqwest::Client::default().into();
+        let timeout = Duration::from_secs(10);
+        task("/inbox")
+            .send_attempt(&client, timeout, 1, None)
+            .await?;
+        let failed = task("/failing_inbox")
+            .send_attempt(&client, timeout, 3, None)
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(*hook.sends.lock().unwrap(), vec![(1, true), (3, false)]);
+        let send_spans = recorder.spans("send_activity");
+        assert_eq!(send_spans.len(), 2);
+        assert_eq!(
+            send_spans[1]["inbox"],
+            base.join("/failing_inbox")?.as_str()
+        );
+        assert_eq!(
+            send_spans[1]["activity_id"],
+            base.join("/activity/1")?.as_str()
+        );
+        assert_eq!(send_spans[1]["attempt"], "3");
+
+        // Receive
+        let follow = Follow {
+            actor: base.join("/u/alice")?.into(),
+            object: base.join("/u/bob")?.into(),
+            kind: Default::default(),
+            id: base.join("/activity/2")?,
+        };
+        let body = serde_json::to_vec(&follow).unwrap().into();
+        process_received_activity(follow, body, &data, |a| a.receive(&data)).await?;
+        assert_eq!(
+            *hook.receives.lock().unwrap(),
+            vec![(Some("Follow".to_string()), true)]
+        );
+        let receive_spans = recorder.spans("receive_activity");
+        assert_eq!(receive_spans.len(), 1);
+        assert_eq!(receive_spans[0]["type"], "Follow");
+        assert_eq!(receive_spans[0]["actor"], base.join("/u/alice")?.as_str());
+
+        // Spans never contain bodies or keys
+        let spans = recorder.0.lock().unwrap();
+        for (_, fields) in spans.iter() {
+            assert!(fields
+                .0
+                .values()
+                .all(|value| !value.contains("PRIVATE KEY")));
+            assert!(fields.0.values().all(|value| !value.contains("{")));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,468 @@
+//! Helpers for the `to` and `cc` fields of activities and objects
+//!
+//! Platforms like Mastodon derive the visibility of a post from the way it is addressed, so
+//! mistakes such as putting the public collection into `cc` instead of `to` change how a post
+//! is shown. [Audience] builds the recipients for a given [Visibility], and
+//! [Visibility::from_to_cc] reads the visibility back from received objects.
+//!
+//! ```
+//! # use activitypub_federation::protocol::addressing::{Audience, Visibility};
+//! # use url::Url;
+//! let followers = Url::parse("https://example.com/u/alice/followers")?;
+//! let bob = Url::parse("https://example.org/u/bob")?;
+//! let audience = Audience::public()
+//!     .cc_followers(followers.clone())
+//!     .cc_actor(bob.clone());
+//! assert_eq!(audience.cc, vec![followers.clone(), bob]);
+//!
+//! let visibility = Visibility::from_to_cc(&audience.to, &audience.cc, Some(&followers));
+//! assert_eq!(visibility, Visibility::Public);
+//! # Ok::<(), url::ParseError>(())
+//! ```
+//!
+//! To deliver an activity, the recipients need to be converted into inboxes with
+//! [resolve_addressed_inboxes].
+
+use crate::{
+    config::Data,
+    error::Error,
+    fetch::object_id::ObjectId,
+    protocol::{flag::origin_shared_inbox, verification::verify_domains_match},
+    traits::{Actor, Object},
+};
+pub use activitystreams_kinds::public;
+use serde::Deserialize;
+use std::fmt::Display;
+use tracing::debug;
+use url::Url;
+
+/// Returns true if `url` is the special collection which addresses everyone. Besides the full
+/// url, the compact form `as:Public` is also accepted.
+pub fn is_public(url: &Url) -> bool {
+    url.as_str() == "as:Public" || url == &public()
+}
+
+/// Visibility of an activity or object, in the same way as Mastodon interprets the addressing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    /// Shown to everyone and in public timelines. The public collection is in `to`.
+    Public,
+    /// Shown to everyone, but not in public timelines. The public collection is in `cc`.
+    Unlisted,
+    /// Only shown to the followers of the author, which are addressed with their followers
+    /// collection.
+    FollowersOnly,
+    /// Only shown to the actors which are addressed directly.
+    Direct,
+}
+
+impl Visibility {
+    /// Determines the visibility of a received activity or object.
+    ///
+    /// `followers_url` is the followers collection of the author, if known. Followers are only
+    /// recognized when it is given.
+    pub fn from_to_cc(to: &[Url], cc: &[Url], followers_url: Option<&Url>) -> Visibility {
+        let is_followers = |url: &Url| Some(url) == followers_url;
+        if to.iter().any(is_public) {
+            Visibility::Public
+        } else if cc.iter().any(is_public) {
+            Visibility::Unlisted
+        } else if to.iter().chain(cc).any(is_followers) {
+            Visibility::FollowersOnly
+        } else {
+            Visibility::Direct
+        }
+    }
+}
+
+/// Recipients of an activity or object, split into `to` and `cc`. See the
+/// [module docs](self) for an example.
+///
+/// Urls which were already added are not added a second time, neither to `to` nor to `cc`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Audience {
+    /// Primary recipients
+    pub to: Vec<Url>,
+    /// Secondary recipients
+    pub cc: Vec<Url>,
+}
+
+impl Audience {
+    /// Addressed to the public collection, see [Visibility::Public]. Usually the followers are
+    /// added with [Audience::cc_followers].
+    pub fn public() -> Self {
+        Audience::default().add_to(public())
+    }
+
+    /// Public collection in `cc`, see [Visibility::Unlisted]. Usually the followers are added
+    /// with [Audience::to_followers].
+    pub fn unlisted() -> Self {
+        Audience::default().add_cc(public())
+    }
+
+    /// Addressed to the followers collection `followers_url`, see [Visibility::FollowersOnly]
+    pub fn followers_only(followers_url: Url) -> Self {
+        Audience::default().to_followers(followers_url)
+    }
+
+    /// Without any recipients, see [Visibility::Direct]. Recipients are added with
+    /// [Audience::to_actor].
+    pub fn direct() -> Self {
+        Audience::default()
+    }
+
+    /// Addressing for the given visibility, including the followers collection of the author
+    /// in the same place as Mastodon.
+    pub fn with_visibility(visibility: Visibility, followers_url: Url) -> Self {
+        match visibility {
+            Visibility::Public => Audience::public().cc_followers(followers_url),
+            Visibility::Unlisted => Audience::unlisted().to_followers(followers_url),
+            Visibility::FollowersOnly => Audience::followers_only(followers_url),
+            Visibility::Direct => Audience::direct(),
+        }
+    }
+
+    /// Add an actor, such as a mentioned user, to `to`
+    pub fn to_actor(self, actor: impl Into<Url>) -> Self {
+        self.add_to(actor.into())
+    }
+
+    /// Add an actor to `cc`
+    pub fn cc_actor(self, actor: impl Into<Url>) -> Self {
+        self.add_cc(actor.into())
+    }
+
+    /// Add a followers collection to `to`
+    pub fn to_followers(self, followers_url: Url) -> Self {
+        self.add_to(followers_url)
+    }
+
+    /// Add a followers collection to `cc`
+    pub fn cc_followers(self, followers_url: Url) -> Self {
+        self.add_cc(followers_url)
+    }
+
+    /// Visibility which this audience has for the author with the given followers collection,
+    /// same as [Visibility::from_to_cc]
+    pub fn visibility(&self, followers_url: Option<&Url>) -> Visibility {
+        Visibility::from_to_cc(&self.to, &self.cc, followers_url)
+    }
+
+    fn contains(&self, url: &Url) -> bool {
+        self.to.contains(url) || self.cc.contains(url)
+    }
+
+    fn add_to(mut self, url: Url) -> Self {
+        if !self.contains(&url) {
+            self.to.push(url);
+        }
+        self
+    }
+
+    fn add_cc(mut self, url: Url) -> Self {
+        if !self.contains(&url) {
+            self.cc.push(url);
+        }
+        self
+    }
+}
+
+/// Inboxes to deliver an activity with the given `to` and `cc` recipients to.
+///
+/// - The public collection and local urls are skipped.
+/// - Actors are dereferenced with [ObjectId], and their shared inbox is used if they have one.
+/// - Collections such as the followers of a remote actor are mapped to the shared inbox of their
+///   owner in `attributedTo`. Mastodon doesn't include the owner, then the shared inbox of the
+///   instance is used, see [origin_shared_inbox](crate::protocol::flag::origin_shared_inbox).
+///   That instance delivers the activity to the followers which it knows about.
+///
+/// Recipients which can't be resolved, for example because they are neither an actor of type
+/// `Kind` nor a collection, are skipped. Once
+/// [FederationConfigBuilder::http_fetch_limit](crate::config::FederationConfigBuilder::http_fetch_limit)
+/// is reached the remaining recipients are skipped as well. Each inbox is returned only once.
+pub async fn resolve_addressed_inboxes<T: Clone, Kind>(to_cc: &[Url], data: &Data<T>) -> Vec<Url>
+where
+    Kind: Object + Actor + Send + 'static + Object<DataType = T>,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+    <Kind as Object>::Error: From<Error> + Send + Sync + Display,
+{
+    let mut inboxes = vec![];
+    for url in to_cc {
+        if is_public(url) || data.config.is_local_url(url) {
+            continue;
+        }
+        if data.remaining_requests() == 0 {
+            debug!("Fetch limit reached, skipping remaining recipients from {url}");
+            break;
+        }
+        match resolve_inbox::<T, Kind>(url, data).await {
+            Ok(inbox) if !inboxes.contains(&inbox) => inboxes.push(inbox),
+            Ok(_) => {}
+            Err(error) => debug!(%error, "Failed to resolve inbox of recipient {url}"),
+        }
+    }
+    inboxes
+}
+
+/// Fields of a collection which are needed to find the instance it belongs to
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AddressedCollection {
+    #[serde(rename = "type")]
+    kind: String,
+    attributed_to: Option<Url>,
+}
+
+async fn resolve_inbox<T: Clone, Kind>(url: &Url, data: &Data<T>) -> Result<Url, Kind::Error>
+where
+    Kind: Object + Actor + Send + 'static + Object<DataType = T>,
+    for<'de2> <Kind as Object>::Kind: Deserialize<'de2>,
+    <Kind as Object>::Error: From<Error> + Send + Sync + Display,
+{
+    let error = match ObjectId::<Kind>::from(url.clone()).dereference(data).await {
+        Ok(actor) => return Ok(actor.shared_inbox_or_inbox()),
+        Err(error) => error,
+    };
+    // The response is kept for this data, so it can be parsed again without another fetch
+    let Some(res) = data.fetched_response(url) else {
+        return Err(error);
+    };
+    let collection = match res.parse::<AddressedCollection>() {
+        Ok(res) if res.object.kind.ends_with("Collection") => res.object,
+        _ => return Err(error),
+    };
+    match collection.attributed_to {
+        Some(owner) => {
+            verify_domains_match(&owner, url)?;
+            let owner = ObjectId::<Kind>::from(owner).dereference(data).await?;
+            Ok(owner.shared_inbox_or_inbox())
+        }
+        None => Ok(origin_shared_inbox(url, data).await?),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::FederationConfig,
+        instance_actor::InstanceActor,
+        traits::tests::{serve_json, DbConnection},
+    };
+    use serde_json::{json, Value};
+
+    fn followers() -> Url {
+        Url::parse("https://example.com/u/alice/followers").unwrap()
+    }
+
+    fn bob() -> Url {
+        Url::parse("https://example.org/u/bob").unwrap()
+    }
+
+    #[test]
+    fn test_audience_for_visibility() {
+        let public = Audience::with_visibility(Visibility::Public, followers());
+        assert_eq!(public.to, vec![super::public()]);
+        assert_eq!(public.cc, vec![followers()]);
+
+        let unlisted = Audience::with_visibility(Visibility::Unlisted, followers());
+        assert_eq!(unlisted.to, vec![followers()]);
+        assert_eq!(unlisted.cc, vec![super::public()]);
+
+        let followers_only = Audience::with_visibility(Visibility::FollowersOnly, followers());
+        assert_eq!(followers_only.to, vec![followers()]);
+        assert!(followers_only.cc.is_empty());
+
+        let direct = Audience::with_visibility(Visibility::Direct, followers()).to_actor(bob());
+        assert_eq!(direct.to, vec![bob()]);
+        assert!(direct.cc.is_empty());
+    }
+
+    #[test]
+    fn test_visibility_round_trip() {
+        let visibilities = [
+            Visibility::Public,
+            Visibility::Unlisted,
+            Visibility::FollowersOnly,
+            Visibility::Direct,
+        ];
+        for visibility in visibilities {
+            // Mentions don't change the visibility
+            let audience = Audience::with_visibility(visibility, followers())
+                .to_actor(bob())
+                .cc_actor(Url::parse("https://example.net/u/carol").unwrap());
+            assert_eq!(audience.visibility(Some(&followers())), visibility);
+        }
+    }
+
+    #[test]
+    fn test_visibility_from_to_cc() {
+        let as_public = Url::parse("as:Public").unwrap();
+        assert_eq!(
+            Visibility::from_to_cc(&[as_public], &[], None),
+            Visibility::Public
+        );
+        assert_eq!(
+            Visibility::from_to_cc(&[bob()], &[Url::parse("as:Public").unwrap()], None),
+            Visibility::Unlisted
+        );
+        // Public takes precedence over followers
+        assert_eq!(
+            Visibility::from_to_cc(&[followers()], &[public()], Some(&followers())),
+            Visibility::Unlisted
+        );
+        // Followers in cc, as sent by some implementations
+        assert_eq!(
+            Visibility::from_to_cc(&[bob()], &[followers()], Some(&followers())),
+            Visibility::FollowersOnly
+        );
+        // Unknown followers collection
+        assert_eq!(
+            Visibility::from_to_cc(&[followers()], &[], None),
+            Visibility::Direct
+        );
+        assert_eq!(Visibility::from_to_cc(&[], &[], None), Visibility::Direct);
+    }
+
+    #[test]
+    fn test_audience_deduplicate() {
+        let audience = Audience::public()
+            .cc_followers(followers())
+            .to_followers(followers())
+            .to_actor(bob())
+            .cc_actor(bob())
+            .cc_actor(public());
+        assert_eq!(audience.to, vec![public(), bob()]);
+        assert_eq!(audience.cc, vec![followers()]);
+    }
+
+    /// Json of an actor served by `base`, optionally with a shared inbox
+    fn actor(base: &Url, path: &str, shared_inbox: bool) -> Value {
+        let id = base.join(path).unwrap();
+        let mut actor = json!({
+            "type": "Application",
+            "id": id,
+            "preferredUsername": "localhost",
+            "inbox": format!("{id}/inbox"),
+            "outbox": format!("{id}/outbox"),
+            "publicKey": {
+                "id": format!("{id}#main-key"),
+                "owner": id,
+                "publicKeyPem": "",
+            },
+        });
+        if shared_inbox {
+            actor["endpoints"] = json!({ "sharedInbox": base.join("/inbox").unwrap() });
+        }
+        actor
+    }
+
+    /// Serves an actor without shared inbox, followers collections with and without owner, a
+    /// post and the instance actor, and returns the url of the server
+    async fn serve() -> Url {
+        serve_json(|base| {
+            vec![
+                ("/u/alice", actor(base, "/u/alice", false)),
+                (
+                    "/u/alice/followers",
+                    json!({
+                        "type": "OrderedCollection",
+                        "id": base.join("/u/alice/followers").unwrap(),
+                        "attributedTo": base.join("/u/alice").unwrap(),
+                        "totalItems": 1,
+                    }),
+                ),
+                (
+                    "/u/bob/followers",
+                    json!({
+                        "type": "OrderedCollection",
+                        "id": base.join("/u/bob/followers").unwrap(),
+                        "totalItems": 1,
+                    }),
+                ),
+                (
+                    "/post/1",
+                    json!({ "type": "Note", "id": base.join("/post/1").unwrap() }),
+                ),
+                ("/actor", actor(base, "/actor", true)),
+            ]
+        })
+        .await
+    }
+
+    async fn data(http_fetch_limit: u32) -> Data<DbConnection> {
+        FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .http_fetch_limit(http_fetch_limit)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_addressed_inboxes() -> Result<(), Error> {
+        let base = serve().await;
+        let resolve = |paths: &[&str]| {
+            let urls: Vec<_> = paths.iter().map(|p| base.join(p).unwrap()).collect();
+            async move {
+                let data = data(20).await;
+                resolve_addressed_inboxes::<_, InstanceActor<DbConnection>>(&urls, &data).await
+            }
+        };
+
+        // Actor without shared inbox
+        assert_eq!(
+            resolve(&["/u/alice"]).await,
+            vec![base.join("/u/alice/inbox")?]
+        );
+        // Followers collection with owner
+        assert_eq!(
+            resolve(&["/u/alice/followers"]).await,
+            vec![base.join("/u/alice/inbox")?]
+        );
+        // Followers collection without owner, like Mastodon
+        assert_eq!(
+            resolve(&["/u/bob/followers"]).await,
+            vec![base.join("/inbox")?]
+        );
+        // Actor with shared inbox
+        assert_eq!(resolve(&["/actor"]).await, vec![base.join("/inbox")?]);
+        // Neither actor nor collection, or not found
+        assert!(resolve(&["/post/1", "/u/carol"]).await.is_empty());
+
+        // Public and local urls are skipped without fetching, and inboxes are not repeated
+        let data = data(20).await;
+        let to_cc = [
+            public(),
+            Url::parse("as:Public")?,
+            Url::parse("https://example.com/u/dave")?,
+            base.join("/u/alice")?,
+            base.join("/u/alice/followers")?,
+            base.join("/u/bob/followers")?,
+            base.join("/actor")?,
+        ];
+        let inboxes =
+            resolve_addressed_inboxes::<_, InstanceActor<DbConnection>>(&to_cc, &data).await;
+        assert_eq!(
+            inboxes,
+            vec![base.join("/u/alice/inbox")?, base.join("/inbox")?]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_addressed_inboxes_fetch_limit() -> Result<(), Error> {
+        let base = serve().await;
+        let data = data(1).await;
+        let to_cc = [base.join("/u/alice")?, base.join("/u/bob/followers")?];
+        let inboxes =
+            resolve_addressed_inboxes::<_, InstanceActor<DbConnection>>(&to_cc, &data).await;
+        assert_eq!(inboxes, vec![base.join("/u/alice/inbox")?]);
+        assert_eq!(data.request_count(), 1);
+        Ok(())
+    }
+}
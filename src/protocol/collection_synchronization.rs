@@ -0,0 +1,236 @@
+//! Synchronization of followers collections with the `Collection-Synchronization` header, see
+//! [FEP-8fcf](https://codeberg.org/fediverse/fep/src/branch/main/fep/8fcf/fep-8fcf.md).
+//!
+//! When an activity is delivered to the followers of an actor, the sending instance includes a
+//! digest of those followers which are on the receiving instance. If the digest doesn't match
+//! the followers which the receiving instance knows about, it can fetch the partial followers
+//! collection at `url` and remove follows which the sender doesn't know about anymore.
+//!
+//! The header is sent with outgoing activities if [Actor::collection_synchronization](crate::traits::Actor::collection_synchronization)
+//! is implemented. Incoming headers can be checked in the inbox handler:
+//!
+//! ```
+//! # use activitypub_federation::protocol::collection_synchronization::*;
+//! # use http::HeaderMap;
+//! # use url::Url;
+//! # let collection_id = Url::parse("https://example.com/users/alice/followers")?;
+//! # let url = Url::parse("https://example.com/users/alice/followers_synchronization")?;
+//! # let followers = vec![Url::parse("https://my-instance.com/users/bob")?];
+//! # let mut headers = HeaderMap::new();
+//! # headers.insert(
+//! #     COLLECTION_SYNCHRONIZATION_HEADER,
+//! #     build_collection_synchronization_header(&followers, &collection_id, &url),
+//! # );
+//! if let Some(sync) = headers
+//!     .get(COLLECTION_SYNCHRONIZATION_HEADER)
+//!     .and_then(parse_collection_synchronization)
+//! {
+//!     // Followers of `sync.collection_id` on this instance, according to the local database
+//!     let local_followers = followers;
+//!     if !sync.matches(&local_followers) {
+//!         // Fetch `sync.url` and update the local followers
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use crate::http_signatures::split_top_level;
+use http::HeaderValue;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// Name of the header, which is sent with activities that are addressed to a followers collection
+pub const COLLECTION_SYNCHRONIZATION_HEADER: &str = "Collection-Synchronization";
+
+/// Parsed value of a `Collection-Synchronization` header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CollectionSynchronization {
+    /// Id of the followers collection
+    pub collection_id: Url,
+    /// Url of the partial collection, which only contains the followers on the receiving
+    /// instance
+    pub url: Url,
+    /// Digest of the followers on the receiving instance, see [followers_digest]
+    pub digest: String,
+}
+
+impl CollectionSynchronization {
+    /// Returns true if the digest matches the given followers, which should be all followers of
+    /// the collection on the receiving instance.
+    pub fn matches(&self, followers: &[Url]) -> bool {
+        self.digest
+            .eq_ignore_ascii_case(&followers_digest(followers))
+    }
+}
+
+/// Digest over the ids of the given followers, calculated by combining the SHA-256 hashes of all
+/// ids with XOR, and encoding the result as lowercase hex. The order of followers doesn't
+/// matter.
+pub fn followers_digest(followers: &[Url]) -> String {
+    let digest = followers.iter().fold([0u8; 32], |mut digest, follower| {
+        let hash = Sha256::digest(follower.as_str());
+        digest.iter_mut().zip(hash).for_each(|(d, h)| *d ^= h);
+        digest
+    });
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Builds the value of a `Collection-Synchronization` header in the same format as Mastodon.
+///
+/// - `followers`: Followers of the collection which are on the receiving instance, meaning
+///   their ids have the same scheme, host and port as the inbox
+/// - `collection_id`: Id of the followers collection
+/// - `url`: Url of the partial collection, which returns the followers on the host of the
+///   requesting instance
+pub fn build_collection_synchronization_header(
+    followers: &[Url],
+    collection_id: &Url,
+    url: &Url,
+) -> HeaderValue {
+    let value = format!(
+        r#"collectionId="{collection_id}", digest="{}", url="{url}""#,
+        followers_digest(followers)
+    );
+    HeaderValue::from_str(&value).expect("urls and hex digest are valid header values")
+}
+
+/// Parses the value of a `Collection-Synchronization` header, which is a structured field
+/// dictionary with string values. Returns `None` if it is invalid or a parameter is missing.
+pub fn parse_collection_synchronization(header: &HeaderValue) -> Option<CollectionSynchronization> {
+    let (mut collection_id, mut url, mut digest) = (None, None, None);
+    for member in split_top_level(header.to_str().ok()?, ',') {
+        let Some((name, value)) = member.split_once('=') else {
+            continue;
+        };
+        let value = || parse_string(value.trim());
+        match name.trim() {
+            "collectionId" => collection_id = Url::parse(&value()?).ok(),
+            "url" => url = Url::parse(&value()?).ok(),
+            "digest" => digest = value(),
+            _ => {}
+        }
+    }
+    Some(CollectionSynchronization {
+        collection_id: collection_id?,
+        url: url?,
+        digest: digest?,
+    })
+}
+
+/// Parses a structured field string, which is quoted and may contain escaped quotes and
+/// backslashes. See [RFC 8941](https://www.rfc-editor.org/rfc/rfc8941#section-3.3.3).
+fn parse_string(value: &str) -> Option<String> {
+    let mut chars = value.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut string = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c @ ('"' | '\\') => string.push(c),
+                _ => return None,
+            },
+            '"' => return None,
+            c => string.push(c),
+        }
+    }
+    Some(string)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn followers() -> Vec<Url> {
+        vec![
+            Url::parse("https://example.org/users/bob").unwrap(),
+            Url::parse("https://example.org/users/carol").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_followers_digest() {
+        // Result of Mastodon's `Digest::SHA256.hexdigest` for a single follower
+        assert_eq!(
+            followers_digest(&[Url::parse("https://example.org/users/bob").unwrap()]),
+            "241b00794ce9b46aa864f3220afadef128318da2659782985bac5ed5bd436bff"
+        );
+        assert_eq!(followers_digest(&[]), "0".repeat(64));
+
+        // Order doesn't matter, and adding the same follower twice cancels out
+        let mut followers = followers();
+        let digest = followers_digest(&followers);
+        followers.reverse();
+        assert_eq!(followers_digest(&followers), digest);
+        followers.push(followers[0].clone());
+        followers.push(followers[0].clone());
+        assert_eq!(followers_digest(&followers), digest);
+    }
+
+    #[test]
+    fn test_collection_synchronization_round_trip() {
+        let collection_id = Url::parse("https://example.com/users/alice/followers").unwrap();
+        let url = Url::parse("https://example.com/users/alice/followers_synchronization").unwrap();
+        let header = build_collection_synchronization_header(&followers(), &collection_id, &url);
+        assert_eq!(
+            header.to_str().unwrap(),
+            format!(
+                r#"collectionId="{collection_id}", digest="{}", url="{url}""#,
+                followers_digest(&followers())
+            )
+        );
+        let sync = parse_collection_synchronization(&header).unwrap();
+        assert_eq!(sync.collection_id, collection_id);
+        assert_eq!(sync.url, url);
+        assert!(sync.matches(&followers()));
+        assert!(!sync.matches(&followers()[..1]));
+    }
+
+    #[test]
+    fn test_parse_mastodon_header() {
+        // Format produced by Mastodon's `ActivityPub::DeliveryWorker`
+        let header = HeaderValue::from_static(
+            r#"collectionId="https://mastodon.example/users/alice/followers", digest="241b00794ce9b46aa864f3220afadef128318da2659782985bac5ed5bd436bff", url="https://mastodon.example/users/alice/followers_synchronization""#,
+        );
+        let sync = parse_collection_synchronization(&header).unwrap();
+        assert_eq!(
+            sync.collection_id.as_str(),
+            "https://mastodon.example/users/alice/followers"
+        );
+        assert_eq!(
+            sync.url.as_str(),
+            "https://mastodon.example/users/alice/followers_synchronization"
+        );
+        assert!(sync.matches(&[Url::parse("https://example.org/users/bob").unwrap()]));
+
+        let missing_url = HeaderValue::from_static(
+            r#"collectionId="https://mastodon.example/users/alice/followers", digest="00""#,
+        );
+        assert_eq!(parse_collection_synchronization(&missing_url), None);
+    }
+
+    #[test]
+    fn test_parse_structured_field() {
+        // Commas inside of strings don't separate members
+        let collection_id = Url::parse("https://example.com/users/alice/followers").unwrap();
+        let url = Url::parse("https://example.com/sync?ids=1,2,3&a=b=c").unwrap();
+        let header = build_collection_synchronization_header(&followers(), &collection_id, &url);
+        let sync = parse_collection_synchronization(&header).unwrap();
+        assert_eq!(sync.collection_id, collection_id);
+        assert_eq!(sync.url, url);
+
+        let header = HeaderValue::from_static(
+            r#"collectionId="https://example.com/a\\b,c", digest="00", url="https://example.com/", extra=?1"#,
+        );
+        let sync = parse_collection_synchronization(&header).unwrap();
+        assert_eq!(sync.collection_id.as_str(), "https://example.com/a/b,c");
+
+        // Unterminated or unquoted strings are invalid
+        for value in [
+            r#"collectionId="https://example.com/a, digest="00", url="https://example.com/""#,
+            r#"collectionId=https://example.com/a, digest="00", url="https://example.com/""#,
+        ] {
+            let header = HeaderValue::from_static(value);
+            assert_eq!(parse_collection_synchronization(&header), None);
+        }
+    }
+}
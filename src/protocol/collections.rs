@@ -0,0 +1,306 @@
+//! Structs for serving paginated collections, such as the followers or outbox of an actor
+//!
+//! [OrderedCollectionPager] generates the collection and its pages with correct `totalItems`,
+//! `first`, `last`, `next`, `prev` and `partOf` fields. Pages are linked with a `page` query
+//! parameter, starting at 1.
+//!
+//! ```
+//! # use activitypub_federation::protocol::collections::OrderedCollectionPager;
+//! # use url::Url;
+//! let id = Url::parse("https://example.com/u/alice/outbox")?;
+//! let pager = OrderedCollectionPager::new(id, 45, 20);
+//!
+//! let collection = pager.collection();
+//! assert_eq!(collection.first.unwrap().as_str(), "https://example.com/u/alice/outbox?page=1");
+//! assert_eq!(collection.last.unwrap().as_str(), "https://example.com/u/alice/outbox?page=3");
+//!
+//! let page = pager.page(3, vec!["https://example.com/activity/1"])?;
+//! assert_eq!(page.prev.unwrap().as_str(), "https://example.com/u/alice/outbox?page=2");
+//! assert_eq!(page.next, None);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use activitystreams_kinds::collection::{OrderedCollectionPageType, OrderedCollectionType};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+/// Collection whose items are split into [OrderedCollectionPage]s.
+///
+/// <https://www.w3.org/TR/activitystreams-vocabulary/#dfn-orderedcollection>
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderedCollection {
+    /// Always `OrderedCollection`
+    #[serde(rename = "type")]
+    pub kind: OrderedCollectionType,
+    /// Id of the collection
+    pub id: Url,
+    /// Number of items in all pages
+    pub total_items: usize,
+    /// Link to the first page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first: Option<Url>,
+    /// Link to the last page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last: Option<Url>,
+}
+
+/// Page of an [OrderedCollection] with some of its items.
+///
+/// <https://www.w3.org/TR/activitystreams-vocabulary/#dfn-orderedcollectionpage>
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderedCollectionPage {
+    /// Always `OrderedCollectionPage`
+    #[serde(rename = "type")]
+    pub kind: OrderedCollectionPageType,
+    /// Id of the page
+    pub id: Url,
+    /// Id of the collection which this page belongs to
+    pub part_of: Url,
+    /// Items of this page
+    pub ordered_items: Vec<Value>,
+    /// Link to the next page, if this isn't the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<Url>,
+    /// Link to the previous page, if this isn't the first one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<Url>,
+}
+
+/// Generates an [OrderedCollection] and its [OrderedCollectionPage]s for a collection with the
+/// given number of items. See the [module docs](self) for an example.
+#[derive(Clone, Debug)]
+pub struct OrderedCollectionPager {
+    id: Url,
+    total_items: usize,
+    page_size: usize,
+}
+
+impl OrderedCollectionPager {
+    /// Create a pager for the collection with the given `id`, which has `total_items` items in
+    /// total and `page_size` items per page. A page size of 0 is treated as 1.
+    pub fn new(id: Url, total_items: usize, page_size: usize) -> Self {
+        OrderedCollectionPager {
+            id,
+            total_items,
+            page_size: page_size.max(1),
+        }
+    }
+
+    /// Number of pages. An empty collection has a single empty page.
+    pub fn page_count(&self) -> usize {
+        self.total_items.div_ceil(self.page_size).max(1)
+    }
+
+    /// Number of items to skip when reading the items of `page` from the database. Use it
+    /// together with [OrderedCollectionPager::page_size] as limit.
+    pub fn offset(&self, page: usize) -> usize {
+        page.saturating_sub(1) * self.page_size
+    }
+
+    /// Number of items per page
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Id of the page with the given number, formed by adding the `page` query parameter to the
+    /// collection id
+    pub fn page_id(&self, page: usize) -> Url {
+        let mut url = self.id.clone();
+        url.query_pairs_mut().append_pair("page", &page.to_string());
+        url
+    }
+
+    /// The collection itself, which links to the first and last page
+    pub fn collection(&self) -> OrderedCollection {
+        OrderedCollection {
+            kind: Default::default(),
+            id: self.id.clone(),
+            total_items: self.total_items,
+            first: Some(self.page_id(1)),
+            last: Some(self.page_id(self.page_count())),
+        }
+    }
+
+    /// The page with the given number, starting at 1, which contains `items`. Pages after the
+    /// last one are returned without items, and link back to the last page.
+    pub fn page<T: Serialize>(
+        &self,
+        page: usize,
+        items: Vec<T>,
+    ) -> Result<OrderedCollectionPage, serde_json::Error> {
+        let page = page.max(1);
+        let ordered_items = items
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?;
+        let page_count = self.page_count();
+        Ok(OrderedCollectionPage {
+            kind: Default::default(),
+            id: self.page_id(page),
+            part_of: self.id.clone(),
+            ordered_items,
+            next: (page < page_count).then(|| self.page_id(page + 1)),
+            prev: (page > 1).then(|| self.page_id((page - 1).min(page_count))),
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Data, FederationConfig},
+        error::Error,
+        fetch::collection_id::CollectionId,
+        traits::{tests::DbConnection, Collection},
+    };
+    use async_trait::async_trait;
+    use axum::{
+        extract::{Query, State},
+        response::{IntoResponse, Response},
+        routing::get,
+        Router,
+    };
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn pager(total_items: usize) -> OrderedCollectionPager {
+        let id = Url::parse("https://example.com/outbox?sort=new").unwrap();
+        OrderedCollectionPager::new(id, total_items, 2)
+    }
+
+    #[test]
+    fn test_collection_json() {
+        let json = serde_json::to_value(pager(5).collection()).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "type": "OrderedCollection",
+                "id": "https://example.com/outbox?sort=new",
+                "totalItems": 5,
+                "first": "https://example.com/outbox?sort=new&page=1",
+                "last": "https://example.com/outbox?sort=new&page=3",
+            })
+        );
+
+        let collection = pager(0).collection();
+        assert_eq!(collection.first, collection.last);
+    }
+
+    #[test]
+    fn test_page_json() {
+        let pager = pager(5);
+        let json = serde_json::to_value(pager.page(2, vec!["c", "d"]).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "type": "OrderedCollectionPage",
+                "id": "https://example.com/outbox?sort=new&page=2",
+                "partOf": "https://example.com/outbox?sort=new",
+                "orderedItems": ["c", "d"],
+                "next": "https://example.com/outbox?sort=new&page=3",
+                "prev": "https://example.com/outbox?sort=new&page=1",
+            })
+        );
+        assert_eq!(pager.offset(2), 2);
+
+        let first = pager.page(1, vec!["a", "b"]).unwrap();
+        assert_eq!(first.prev, None);
+        let last = pager.page(3, vec!["e"]).unwrap();
+        assert_eq!(last.next, None);
+        let after_last = pager.page(7, Vec::<String>::new()).unwrap();
+        assert_eq!(after_last.next, None);
+        assert_eq!(after_last.prev, Some(pager.page_id(3)));
+    }
+
+    #[derive(Debug)]
+    struct Outbox(Vec<String>);
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct OutboxJson {
+        total_items: usize,
+        ordered_items: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Collection for Outbox {
+        type Owner = ();
+        type DataType = DbConnection;
+        type Kind = OutboxJson;
+        type Error = Error;
+
+        async fn read_local(_: &(), _: &Data<Self::DataType>) -> Result<Self::Kind, Error> {
+            Err(Error::NotFound)
+        }
+
+        async fn verify(_: &Self::Kind, _: &Url, _: &Data<Self::DataType>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn from_json(
+            json: Self::Kind,
+            _: &(),
+            _: &Data<Self::DataType>,
+        ) -> Result<Self, Error> {
+            assert_eq!(json.total_items, json.ordered_items.len());
+            Ok(Outbox(json.ordered_items))
+        }
+    }
+
+    /// Serves an outbox with five items, using the axum response impls
+    async fn serve_outbox() -> Url {
+        async fn outbox(
+            State(id): State<Url>,
+            Query(query): Query<HashMap<String, usize>>,
+        ) -> Response {
+            let items = ["a", "b", "c", "d", "e"];
+            let pager = OrderedCollectionPager::new(id, items.len(), 2);
+            match query.get("page") {
+                Some(&page) => {
+                    let items = items
+                        .iter()
+                        .skip(pager.offset(page))
+                        .take(pager.page_size())
+                        .collect();
+                    pager.page(page, items).unwrap().into_response()
+                }
+                None => pager.collection().into_response(),
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let id = Url::parse(&format!(
+            "http://localhost:{}/outbox",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let app = Router::new()
+            .route("/outbox", get(outbox))
+            .with_state(id.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        id
+    }
+
+    #[tokio::test]
+    async fn test_dereference_served_collection() -> Result<(), Error> {
+        let id = CollectionId::<Outbox>::from(serve_outbox().await);
+        let data = FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data();
+        let outbox = id.dereference_paginated(&(), &data, 10).await?;
+        assert_eq!(outbox.0, vec!["a", "b", "c", "d", "e"]);
+        // The collection and its three pages
+        assert_eq!(data.collection_fetch_count(), 4);
+        Ok(())
+    }
+}
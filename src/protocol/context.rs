@@ -48,10 +48,71 @@ impl<T> WithContext<T> {
         WithContext { context, inner }
     }
 
+    /// Create a new wrapper with the default Activitypub context, followed by the given extension
+    /// entries. Entries which are already part of the context are only included once.
+    ///
+    /// ```
+    /// # use activitypub_federation::protocol::context::WithContext;
+    /// # use serde_json::json;
+    /// let extra = vec![json!({"sensitive": "as:sensitive"})];
+    /// let note = WithContext::new_with_extra(json!({"content": "Hello world"}), extra);
+    /// assert_eq!(
+    ///     note.context(),
+    ///     &json!(["https://www.w3.org/ns/activitystreams", {"sensitive": "as:sensitive"}])
+    /// );
+    /// ```
+    pub fn new_with_extra(inner: T, extra_contexts: Vec<Value>) -> WithContext<T> {
+        let context = merge_contexts(
+            &Value::String(DEFAULT_CONTEXT.to_string()),
+            &Value::Array(extra_contexts),
+        );
+        WithContext::new(inner, context)
+    }
+
     /// Returns the inner `T` object which this `WithContext` object is wrapping
     pub fn inner(&self) -> &T {
         &self.inner
     }
+
+    /// Returns the `@context` of this object. For received data this is the context which was
+    /// sent by the remote instance, and can be merged into the context of forwarded objects with
+    /// [merge_contexts].
+    pub fn context(&self) -> &Value {
+        &self.context
+    }
+}
+
+/// Combines two values of `@context` into a single array, with the entries of `a` first. Each of
+/// them may be a single entry or an array of entries. Entries which appear more than once are
+/// only included the first time.
+///
+/// ```
+/// # use activitypub_federation::protocol::context::merge_contexts;
+/// # use serde_json::json;
+/// let a = json!("https://www.w3.org/ns/activitystreams");
+/// let b = json!(["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"]);
+/// assert_eq!(
+///     merge_contexts(&a, &b),
+///     json!(["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"])
+/// );
+/// ```
+pub fn merge_contexts(a: &Value, b: &Value) -> Value {
+    let mut merged: Vec<Value> = vec![];
+    for entry in [a, b].into_iter().flat_map(context_entries) {
+        if !merged.contains(entry) {
+            merged.push(entry.clone());
+        }
+    }
+    Value::Array(merged)
+}
+
+/// Returns the entries of a `@context` value, which is either an array or a single entry.
+fn context_entries(context: &Value) -> &[Value] {
+    match context {
+        Value::Array(entries) => entries,
+        Value::Null => &[],
+        entry => std::slice::from_ref(entry),
+    }
 }
 
 #[async_trait::async_trait]
@@ -77,6 +138,14 @@ where
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
         self.inner.receive(data).await
     }
+
+    async fn receive_for(
+        self,
+        recipients: Vec<Url>,
+        data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        self.inner.receive_for(recipients, data).await
+    }
 }
 
 impl<T> Clone for WithContext<T>
@@ -90,3 +159,56 @@ where
         }
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TOOT_CONTEXT: &str = "http://joinmastodon.org/ns#";
+
+    #[test]
+    fn test_new_with_extra() {
+        let extra = vec![
+            json!(DEFAULT_CONTEXT),
+            json!("https://w3id.org/security/v1"),
+            json!({ "toot": TOOT_CONTEXT, "votersCount": "toot:votersCount" }),
+            json!("https://w3id.org/security/v1"),
+        ];
+        let note = WithContext::new_with_extra(json!({ "content": "Hello world" }), extra);
+        let serialized = serde_json::to_value(&note).unwrap();
+        assert_eq!(
+            serialized["@context"],
+            json!([
+                DEFAULT_CONTEXT,
+                "https://w3id.org/security/v1",
+                { "toot": TOOT_CONTEXT, "votersCount": "toot:votersCount" }
+            ])
+        );
+        assert_eq!(serialized["content"], "Hello world");
+    }
+
+    #[test]
+    fn test_merge_received_context() {
+        let received: WithContext<Value> = serde_json::from_value(json!({
+            "@context": [DEFAULT_CONTEXT, { "sensitive": "as:sensitive" }],
+            "type": "Note"
+        }))
+        .unwrap();
+        let own = json!([DEFAULT_CONTEXT, { "toot": TOOT_CONTEXT }]);
+        let merged = merge_contexts(&own, received.context());
+        assert_eq!(
+            merged,
+            json!([
+                DEFAULT_CONTEXT,
+                { "toot": TOOT_CONTEXT },
+                { "sensitive": "as:sensitive" }
+            ])
+        );
+        assert_eq!(
+            merge_contexts(&Value::Null, &json!(DEFAULT_CONTEXT)),
+            json!([DEFAULT_CONTEXT])
+        );
+    }
+}
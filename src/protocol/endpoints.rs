@@ -0,0 +1,48 @@
+//! Struct which is used to federate the `endpoints` of an actor
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Additional endpoints of an actor, most importantly the shared inbox.
+///
+/// Include this in the `endpoints` field of actor json, so that other instances can deliver
+/// activities for multiple recipients with a single request. Use
+/// [Actor::endpoints](crate::traits::Actor::endpoints) to generate it.
+///
+/// <https://www.w3.org/TR/activitypub/#actor-objects>
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoints {
+    /// Inbox which is shared by all actors of the instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_inbox: Option<Url>,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_endpoints_json() {
+        let endpoints = Endpoints {
+            shared_inbox: Some("https://example.com/inbox".parse().unwrap()),
+        };
+        let json = serde_json::to_value(&endpoints).unwrap();
+        assert_eq!(json, json!({"sharedInbox": "https://example.com/inbox"}));
+        assert_eq!(
+            serde_json::from_value::<Endpoints>(json).unwrap(),
+            endpoints
+        );
+
+        assert_eq!(
+            serde_json::to_value(Endpoints::default()).unwrap(),
+            json!({})
+        );
+        assert_eq!(
+            serde_json::from_value::<Endpoints>(json!({})).unwrap(),
+            Endpoints::default()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Reports of objects or actors to the admins of their instance, with the `Flag` activity
+//!
+//! A report is not sent to the followers of the reporting actor, but to the instance where the
+//! reported content originates. [Flag::inbox] discovers the shared inbox of that instance, by
+//! fetching its instance actor. Because the actor which is reported to the remote admins is often
+//! hidden, the `actor` of a flag is usually the instance actor of the reporting instance.
+//!
+//! [Flag] can't be passed to [queue_activity](crate::activity_queue::queue_activity) directly,
+//! because the application defines how received reports are handled. Wrap it in a struct which
+//! implements [ActivityHandler](crate::traits::ActivityHandler):
+//!
+//! ```
+//! # use activitypub_federation::{config::Data, protocol::flag::Flag, traits::ActivityHandler};
+//! # use activitypub_federation::traits::tests::DbConnection;
+//! # use serde::{Deserialize, Serialize};
+//! # use url::Url;
+//! #[derive(Debug, Deserialize, Serialize)]
+//! #[serde(transparent)]
+//! struct Report(Flag);
+//!
+//! #[async_trait::async_trait]
+//! impl ActivityHandler for Report {
+//!     type DataType = DbConnection;
+//!     type Error = anyhow::Error;
+//!
+//!     fn id(&self) -> &Url {
+//!         &self.0.id
+//!     }
+//!
+//!     fn actor(&self) -> &Url {
+//!         &self.0.actor
+//!     }
+//!
+//!     async fn verify(&self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+//!         Ok(())
+//!     }
+//!
+//!     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
+//!         // Store the report for the admins
+//!         Ok(())
+//!     }
+//! }
+//! ```
+//!
+//! When sending, determine the inbox with `report.0.inbox(&data).await?` and pass it to
+//! [queue_activity](crate::activity_queue::queue_activity).
+
+use crate::{
+    config::Data,
+    error::Error,
+    fetch::fetch_object_http,
+    protocol::{
+        endpoints::Endpoints,
+        helpers::{deserialize_one_or_many, serialize_one_or_many},
+        verification::verify_domains_match,
+    },
+};
+use activitystreams_kinds::activity::FlagType;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Report of one or more objects, usually an actor and some of its posts.
+///
+/// `ObjectT` is the type of the reported objects, for example an
+/// [ObjectId](crate::fetch::object_id::ObjectId) or a plain [Url]. See the
+/// [module docs](self) for sending and receiving it.
+///
+/// <https://www.w3.org/TR/activitystreams-vocabulary/#dfn-flag>
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(
+    rename_all = "camelCase",
+    bound(
+        serialize = "ObjectT: Serialize",
+        deserialize = "ObjectT: Deserialize<'de>"
+    )
+)]
+pub struct Flag<ObjectT = Url> {
+    /// Id of the activity
+    pub id: Url,
+    /// Always `Flag`
+    #[serde(rename = "type")]
+    pub kind: FlagType,
+    /// Actor which reports the objects, often the instance actor
+    pub actor: Url,
+    /// Reported objects, serialized as single value if there is only one
+    #[serde(
+        deserialize_with = "deserialize_one_or_many",
+        serialize_with = "serialize_one_or_many"
+    )]
+    pub object: Vec<ObjectT>,
+    /// Additional recipients, for example the community which a reported post belongs to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<Url>,
+    /// Comment of the reporting user, used by Mastodon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Reason for the report, used by Lemmy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+impl<ObjectT> Flag<ObjectT>
+where
+    ObjectT: Clone + Into<Url>,
+{
+    /// Report of the given objects by `actor`, without a comment
+    pub fn new(id: Url, actor: Url, object: Vec<ObjectT>) -> Self {
+        Flag {
+            id,
+            kind: Default::default(),
+            actor,
+            object,
+            to: vec![],
+            content: None,
+            summary: None,
+        }
+    }
+
+    /// Shared inbox of the instance which the reported objects belong to, see
+    /// [origin_shared_inbox]. Fails if the flag has no object, or if the objects belong to
+    /// different instances.
+    pub async fn inbox<T: Clone>(&self, data: &Data<T>) -> Result<Url, Error> {
+        let Some(object) = self.object.first() else {
+            return Err(Error::UrlVerificationError(
+                "Flag doesn't contain an object",
+            ));
+        };
+        let inbox = origin_shared_inbox(&object.clone().into(), data).await?;
+        self.verify_inbox(&inbox)?;
+        Ok(inbox)
+    }
+
+    /// Checks that all reported objects belong to the same domain as `inbox`, so that a report
+    /// isn't sent to an instance which has nothing to do with the objects.
+    pub fn verify_inbox(&self, inbox: &Url) -> Result<(), Error> {
+        self.object
+            .iter()
+            .try_for_each(|object| verify_domains_match(&object.clone().into(), inbox))
+    }
+}
+
+/// Fields of the actor which represents a whole instance, which are needed to find its inbox
+#[derive(Deserialize)]
+struct InstanceActor {
+    inbox: Url,
+    endpoints: Option<Endpoints>,
+}
+
+/// Shared inbox of the instance which `object_id` belongs to.
+///
+/// The instance actor is fetched from the root url of the instance, as served by Lemmy, and
+/// otherwise from `/actor`, as served by Mastodon. Its shared inbox is returned, or its inbox if
+/// it has none. Fails with the error of the second fetch if neither url returns an actor, or with
+/// [Error::UrlVerificationError] if the inbox is on a different domain.
+pub async fn origin_shared_inbox<T: Clone>(object_id: &Url, data: &Data<T>) -> Result<Url, Error> {
+    let actor = match fetch_object_http::<_, InstanceActor>(&object_id.join("/")?, data).await {
+        Ok(res) => res.object,
+        Err(_) => {
+            fetch_object_http::<_, InstanceActor>(&object_id.join("/actor")?, data)
+                .await?
+                .object
+        }
+    };
+    let inbox = actor
+        .endpoints
+        .and_then(|endpoints| endpoints.shared_inbox)
+        .unwrap_or(actor.inbox);
+    verify_domains_match(&inbox, object_id)?;
+    Ok(inbox)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        fetch::object_id::ObjectId,
+        traits::tests::{debug_data, serve_json, DbUser},
+    };
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_flag() {
+        // Mastodon reports the actor and its posts, with an optional comment
+        let mastodon = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://mastodon.example/0cb5c5a8-8d73-4e5c-b0d3-c6b1d3e1a6f1",
+            "type": "Flag",
+            "actor": "https://mastodon.example/actor",
+            "content": "Spam",
+            "object": [
+                "https://remote.example/users/bob",
+                "https://remote.example/users/bob/statuses/1"
+            ]
+        });
+        let flag: Flag = serde_json::from_value(mastodon).unwrap();
+        assert_eq!(flag.object.len(), 2);
+        assert_eq!(flag.content.as_deref(), Some("Spam"));
+
+        // Lemmy reports a single post, addressed to its community
+        let lemmy = json!({
+            "id": "https://lemmy.example/activities/flag/1",
+            "type": "Flag",
+            "actor": "https://lemmy.example/u/alice",
+            "object": "https://remote.example/post/1",
+            "to": ["https://remote.example/c/main"],
+            "summary": "Off topic"
+        });
+        let flag: Flag<ObjectId<DbUser>> = serde_json::from_value(lemmy.clone()).unwrap();
+        assert_eq!(flag.summary.as_deref(), Some("Off topic"));
+        assert_eq!(serde_json::to_value(&flag).unwrap(), lemmy);
+    }
+
+    /// Serves an instance actor at `path` of a new server, and returns the url of the server
+    async fn instance(path: &'static str) -> Url {
+        serve_json(|base| {
+            vec![(
+                path,
+                json!({
+                    "id": base.join(path).unwrap(),
+                    "type": "Application",
+                    "inbox": base.join("/actor/inbox").unwrap(),
+                    "endpoints": { "sharedInbox": base.join("/inbox").unwrap() }
+                }),
+            )]
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_flag_inbox() -> Result<(), Error> {
+        let data = debug_data().await;
+        let actor = Url::parse("https://example.com/actor")?;
+
+        for path in ["/", "/actor"] {
+            let base = instance(path).await;
+            let post = base.join("/post/1")?;
+            let flag = Flag::new(base.join("/flag/1")?, actor.clone(), vec![post.clone()]);
+            let inbox = flag.inbox(&data).await?;
+            assert_eq!(inbox, base.join("/inbox")?);
+            assert_eq!(inbox.host(), post.host());
+            assert_eq!(inbox.port(), post.port());
+        }
+
+        // Objects on another instance than the inbox
+        let base = instance("/actor").await;
+        let flag = Flag::new(
+            base.join("/flag/2")?,
+            actor,
+            vec![
+                base.join("/post/1")?,
+                Url::parse("https://other.example/post/2")?,
+            ],
+        );
+        assert!(flag.verify_inbox(&base.join("/inbox")?).is_err());
+        assert!(matches!(
+            flag.inbox(&data).await,
+            Err(Error::UrlVerificationError(_))
+        ));
+        Ok(())
+    }
+}
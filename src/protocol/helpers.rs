@@ -1,6 +1,15 @@
-//! Serde deserialization functions which help to receive differently shaped data
+//! Serde functions which help to send and receive differently shaped data, and other helpers
+//! for handling received data
 
-use serde::{Deserialize, Deserializer};
+use crate::{
+    config::Data,
+    error::Error,
+    fetch::object_id::ObjectId,
+    protocol::verification::verify_urls_match,
+    traits::{Actor, Object},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Debug;
 
 /// Deserialize JSON single value or array into Vec.
 ///
@@ -45,6 +54,43 @@ where
     })
 }
 
+/// Serialize Vec with a single item as JSON single value, and all other Vecs as array.
+///
+/// This is the counterpart of [deserialize_one_or_many], for example for the `alsoKnownAs` field
+/// of actors, which contains a single id in most cases.
+///
+/// ```
+/// # use activitypub_federation::protocol::helpers::{deserialize_one_or_many, serialize_one_or_many};
+/// # use url::Url;
+/// #[derive(serde::Deserialize, serde::Serialize)]
+/// #[serde(rename_all = "camelCase")]
+/// struct Person {
+///     #[serde(
+///         default,
+///         deserialize_with = "deserialize_one_or_many",
+///         serialize_with = "serialize_one_or_many",
+///         skip_serializing_if = "Vec::is_empty"
+///     )]
+///     also_known_as: Vec<Url>
+/// }
+///
+/// let person: Person = serde_json::from_str(r#"{"alsoKnownAs": "https://example.com/u/alice" }"#)?;
+/// assert_eq!(
+///     serde_json::to_string(&person)?,
+///     r#"{"alsoKnownAs":"https://example.com/u/alice"}"#
+/// );
+/// # Ok::<(), anyhow::Error>(())
+pub fn serialize_one_or_many<T, S>(values: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    match values {
+        [value] => value.serialize(serializer),
+        values => values.serialize(serializer),
+    }
+}
+
 /// Deserialize JSON single value or single element array into single value.
 ///
 /// Useful if your application can only handle a single value for a field, but another federated
@@ -116,12 +162,209 @@ where
     Ok(inner)
 }
 
+/// Removes the fields `bto` and `bcc` from an activity, and from the objects which it contains in
+/// `object`, including nested activities like an `Announce` of a `Create`. The spec requires this
+/// before delivery, so that blind recipients aren't revealed to the other recipients.
+///
+/// Activities which are sent with [SendActivityTask::prepare](crate::activity_sending::SendActivityTask::prepare)
+/// or the activity queue are already stripped unless this was disabled with
+/// [FederationConfigBuilder::strip_private_addressing](crate::config::FederationConfigBuilder::strip_private_addressing).
+///
+/// ```
+/// # use activitypub_federation::protocol::helpers::strip_private_addressing;
+/// # use serde_json::json;
+/// let mut activity = json!({
+///     "type": "Create",
+///     "to": ["https://example.com/u/alice"],
+///     "bcc": ["https://example.com/u/bob"],
+///     "object": { "type": "Note", "bto": ["https://example.com/u/carol"] }
+/// });
+/// strip_private_addressing(&mut activity);
+/// assert_eq!(activity, json!({
+///     "type": "Create",
+///     "to": ["https://example.com/u/alice"],
+///     "object": { "type": "Note" }
+/// }));
+/// ```
+///
+/// <https://www.w3.org/TR/activitypub/#delivery>
+pub fn strip_private_addressing(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.shift_remove("bto");
+            map.shift_remove("bcc");
+            if let Some(object) = map.get_mut("object") {
+                strip_private_addressing(object);
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(strip_private_addressing),
+        _ => {}
+    }
+}
+
+/// Verifies that the account `old_actor` can be moved to `new_actor`, for example when receiving
+/// a `Move` activity for account migration.
+///
+/// Both actors are fetched again with [ObjectId::dereference_forced], so that a recently added
+/// `alsoKnownAs` value is taken into account, and the ids of the fetched actors are checked. The
+/// move is only valid if the new actor lists the old actor in [Actor::also_known_as]. The caller
+/// still needs to verify that the `Move` activity was sent by the old actor, before moving its
+/// followers to the new actor.
+///
+/// Returns the fetched old and new actor.
+pub async fn verify_account_move<ActorT>(
+    old_actor: &ObjectId<ActorT>,
+    new_actor: &ObjectId<ActorT>,
+    data: &Data<<ActorT as Object>::DataType>,
+) -> Result<(ActorT, ActorT), <ActorT as Object>::Error>
+where
+    ActorT: Object + Actor + Send + Debug + 'static,
+    for<'de2> <ActorT as Object>::Kind: Deserialize<'de2>,
+    <ActorT as Object>::Error: From<Error>,
+{
+    if old_actor.inner() == new_actor.inner() {
+        return Err(Error::UrlVerificationError("Account can't be moved to itself").into());
+    }
+    let old = old_actor.dereference_forced(data).await?;
+    verify_urls_match(&old.id(), old_actor.inner())?;
+    let new = new_actor.dereference_forced(data).await?;
+    verify_urls_match(&new.id(), new_actor.inner())?;
+    if !new.also_known_as().contains(old_actor.inner()) {
+        return Err(Error::UrlVerificationError(
+            "New account doesn't list old account in alsoKnownAs",
+        )
+        .into());
+    }
+    Ok((old, new))
+}
+
 #[cfg(test)]
+#[allow(clippy::unwrap_used)]
 mod tests {
+    use super::*;
+    use crate::{
+        axum::json::FederationJson,
+        traits::tests::{debug_data, serve_router, DbConnection, DbUser, Person, DB_USER},
+    };
+    use axum::{
+        extract::{Path, State},
+        routing::get,
+        Router,
+    };
+    use std::{collections::HashMap, sync::Arc};
+    use url::Url;
+
+    type Actors = Arc<HashMap<String, Person>>;
+
+    async fn actor(
+        State(actors): State<Actors>,
+        Path(name): Path<String>,
+    ) -> FederationJson<Person> {
+        FederationJson(actors[&name].clone())
+    }
+
+    /// Serves the given actors at `/u/{name}`, with json built by `person` from the actor url
+    async fn serve_actors(
+        names: &[&str],
+        person: impl Fn(&str, &Url) -> Person,
+    ) -> (Url, Data<DbConnection>) {
+        let base = serve_router(|base| {
+            let base = base.join("u/").unwrap();
+            let actors: Actors = Arc::new(
+                names
+                    .iter()
+                    .map(|name| (name.to_string(), person(name, &base.join(name).unwrap())))
+                    .collect(),
+            );
+            Router::new()
+                .route("/u/:name", get(actor))
+                .with_state(actors)
+        })
+        .await;
+        (base.join("u/").unwrap(), debug_data().await)
+    }
+
+    fn person(id: &Url, also_known_as: Vec<Url>) -> Person {
+        Person {
+            kind: Default::default(),
+            preferred_username: String::new(),
+            id: id.clone().into(),
+            inbox: id.join("inbox").unwrap(),
+            public_key: DB_USER.public_key(),
+            endpoints: None,
+            also_known_as,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_account_move() -> Result<(), Error> {
+        let (base, data) = serve_actors(&["old", "new"], |name, id| match name {
+            "new" => person(id, vec![id.join("old").unwrap()]),
+            _ => person(id, vec![]),
+        })
+        .await;
+        let old = ObjectId::<DbUser>::from(base.join("old")?);
+        let new = ObjectId::<DbUser>::from(base.join("new")?);
+
+        let (old_actor, new_actor) = verify_account_move(&old, &new, &data).await?;
+        assert_eq!(&old_actor.federation_id, old.inner());
+        assert_eq!(new_actor.also_known_as, vec![old.inner().clone()]);
+
+        // The old account doesn't list the new one, so moving back is not possible
+        let err = verify_account_move(&new, &old, &data).await.unwrap_err();
+        assert!(matches!(err, Error::UrlVerificationError(_)));
+        let err = verify_account_move(&old, &old, &data).await.unwrap_err();
+        assert!(matches!(err, Error::UrlVerificationError(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_account_move_spoofed() -> Result<(), Error> {
+        // The new actor is served from the test server, but claims an id on another domain which
+        // the old actor belongs to
+        let (base, data) = serve_actors(&["old", "new"], |name, id| match name {
+            "new" => person(
+                &Url::parse("https://old.example/u/new").unwrap(),
+                vec![id.join("old").unwrap()],
+            ),
+            _ => person(id, vec![]),
+        })
+        .await;
+        let old = ObjectId::<DbUser>::from(base.join("old")?);
+        let new = ObjectId::<DbUser>::from(base.join("new")?);
+
+        let err = verify_account_move(&old, &new, &data).await.unwrap_err();
+        assert!(matches!(err, Error::FetchWrongId(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_private_addressing_nested() {
+        let mut announce = serde_json::json!({
+            "type": "Announce",
+            "bcc": "https://example.com/u/alice",
+            "object": [{
+                "type": "Create",
+                "cc": ["https://example.com/u/bob"],
+                "object": { "type": "Note", "bto": "https://example.com/u/carol", "content": "hi" }
+            }, "https://example.com/note/2"]
+        });
+        strip_private_addressing(&mut announce);
+        assert_eq!(
+            announce,
+            serde_json::json!({
+                "type": "Announce",
+                "object": [{
+                    "type": "Create",
+                    "cc": ["https://example.com/u/bob"],
+                    "object": { "type": "Note", "content": "hi" }
+                }, "https://example.com/note/2"]
+            })
+        );
+    }
+
     #[test]
     fn deserialize_one_multiple_values() {
-        use crate::protocol::helpers::deserialize_one;
-        use url::Url;
         #[derive(serde::Deserialize)]
         struct Note {
             #[serde(deserialize_with = "deserialize_one")]
@@ -0,0 +1,1165 @@
+//! Linked Data Signatures embedded in activities, as used by Mastodon and relays.
+//!
+//! Activities which are forwarded by a relay can't be verified with the HTTP signature, because
+//! the request is signed by the relay and not by the original actor. Instead the activity
+//! contains a `signature` field of type `RsaSignature2017`, which covers the canonicalized
+//! activity. Use [sign_activity_ld](crate::activity_sending::sign_activity_ld) to add such a
+//! signature to outgoing activities, and [verify_activity_ld_signature] to check it.
+//!
+//! Full JSON-LD processing requires fetching arbitrary remote contexts. This implementation only
+//! handles the subset which is used by Fediverse software in practice:
+//!
+//! - `@context` may contain the ActivityStreams context, the security context
+//!   `https://w3id.org/security/v1` and inline term definitions. Other remote contexts,
+//!   `@base`, nested contexts and `@list` objects are rejected.
+//! - Properties which are not defined in the context are ignored, same as in regular JSON-LD
+//!   processing. This means they are not signed, and can be added or changed by anyone who
+//!   forwards the activity without invalidating the signature. Types which are not defined in
+//!   the context are rejected.
+//! - Blank nodes are labeled according to URDNA2015, but documents which contain blank nodes
+//!   that can't be distinguished by their own properties (for example two identical tags) are
+//!   rejected.
+//!
+//! Documents which are outside of this subset can't be signed or verified and result in
+//! [Error::Other].
+
+use crate::{error::Error, http_signatures::sign_string};
+use base64::{engine::general_purpose::STANDARD as Base64, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use rsa::{pkcs8::DecodePublicKey, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use serde_json::{json, Map, Number, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Type of the signature which is created and verified
+const SIGNATURE_TYPE: &str = "RsaSignature2017";
+/// Context which is used for canonicalizing the signature options
+const IDENTITY_CONTEXT: &str = "https://w3id.org/identity/v1";
+
+const XSD: &str = "http://www.w3.org/2001/XMLSchema#";
+const XSD_STRING: &str = "http://www.w3.org/2001/XMLSchema#string";
+const XSD_BOOLEAN: &str = "http://www.w3.org/2001/XMLSchema#boolean";
+const XSD_INTEGER: &str = "http://www.w3.org/2001/XMLSchema#integer";
+const XSD_DOUBLE: &str = "http://www.w3.org/2001/XMLSchema#double";
+const RDF_TYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#type";
+const RDF_FIRST: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#first";
+const RDF_REST: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#rest";
+const RDF_NIL: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#nil";
+const RDF_LANG_STRING: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#langString";
+
+/// Classes of the ActivityStreams vocabulary
+const AS_CLASSES: &[&str] = &[
+    "Accept",
+    "Activity",
+    "IntransitiveActivity",
+    "Add",
+    "Announce",
+    "Application",
+    "Arrive",
+    "Article",
+    "Audio",
+    "Block",
+    "Collection",
+    "CollectionPage",
+    "Relationship",
+    "Create",
+    "Delete",
+    "Dislike",
+    "Document",
+    "Event",
+    "Follow",
+    "Flag",
+    "Group",
+    "Ignore",
+    "Image",
+    "Invite",
+    "Join",
+    "Leave",
+    "Like",
+    "Link",
+    "Mention",
+    "Note",
+    "Object",
+    "Offer",
+    "OrderedCollection",
+    "OrderedCollectionPage",
+    "Organization",
+    "Page",
+    "Person",
+    "Place",
+    "Profile",
+    "Question",
+    "Reject",
+    "Remove",
+    "Service",
+    "TentativeAccept",
+    "TentativeReject",
+    "Tombstone",
+    "Undo",
+    "Update",
+    "Video",
+    "View",
+    "Listen",
+    "Read",
+    "Move",
+    "Travel",
+    "IsFollowing",
+    "IsFollowedBy",
+    "IsContact",
+    "IsMember",
+];
+
+/// ActivityStreams properties whose values are urls
+const AS_ID_PROPERTIES: &[&str] = &[
+    "subject",
+    "relationship",
+    "actor",
+    "attributedTo",
+    "attachment",
+    "bcc",
+    "bto",
+    "cc",
+    "context",
+    "current",
+    "first",
+    "generator",
+    "icon",
+    "image",
+    "inReplyTo",
+    "items",
+    "instrument",
+    "last",
+    "location",
+    "next",
+    "object",
+    "oneOf",
+    "anyOf",
+    "origin",
+    "prev",
+    "preview",
+    "replies",
+    "result",
+    "audience",
+    "partOf",
+    "tag",
+    "target",
+    "to",
+    "url",
+    "href",
+    "describes",
+    "formerType",
+    "outbox",
+    "following",
+    "followers",
+    "streams",
+    "endpoints",
+    "uploadMedia",
+    "proxyUrl",
+    "liked",
+    "oauthAuthorizationEndpoint",
+    "oauthTokenEndpoint",
+    "provideClientKey",
+    "signClientKey",
+    "sharedInbox",
+    "likes",
+    "shares",
+    "alsoKnownAs",
+];
+
+/// ActivityStreams properties with typed literal values, and their datatype
+const AS_TYPED_PROPERTIES: &[(&str, &str)] = &[
+    ("closed", "xsd:dateTime"),
+    ("accuracy", "xsd:float"),
+    ("altitude", "xsd:float"),
+    ("duration", "xsd:duration"),
+    ("endTime", "xsd:dateTime"),
+    ("height", "xsd:nonNegativeInteger"),
+    ("latitude", "xsd:float"),
+    ("longitude", "xsd:float"),
+    ("published", "xsd:dateTime"),
+    ("radius", "xsd:float"),
+    ("startIndex", "xsd:nonNegativeInteger"),
+    ("startTime", "xsd:dateTime"),
+    ("totalItems", "xsd:nonNegativeInteger"),
+    ("updated", "xsd:dateTime"),
+    ("width", "xsd:nonNegativeInteger"),
+    ("deleted", "xsd:dateTime"),
+];
+
+/// ActivityStreams properties with plain literal values
+const AS_PLAIN_PROPERTIES: &[&str] = &[
+    "content",
+    "name",
+    "hreflang",
+    "mediaType",
+    "rel",
+    "summary",
+    "units",
+    "preferredUsername",
+    "source",
+];
+
+/// Subset of `https://www.w3.org/ns/activitystreams`
+fn activitystreams_context() -> Value {
+    let mut context = Map::new();
+    context.insert("@vocab".to_string(), json!("_:"));
+    context.insert("xsd".to_string(), json!(XSD));
+    context.insert(
+        "as".to_string(),
+        json!("https://www.w3.org/ns/activitystreams#"),
+    );
+    context.insert("ldp".to_string(), json!("http://www.w3.org/ns/ldp#"));
+    context.insert(
+        "vcard".to_string(),
+        json!("http://www.w3.org/2006/vcard/ns#"),
+    );
+    context.insert("id".to_string(), json!("@id"));
+    context.insert("type".to_string(), json!("@type"));
+    for class in AS_CLASSES {
+        context.insert(class.to_string(), json!(format!("as:{class}")));
+    }
+    for property in AS_ID_PROPERTIES {
+        context.insert(
+            property.to_string(),
+            json!({ "@id": format!("as:{property}"), "@type": "@id" }),
+        );
+    }
+    for (property, datatype) in AS_TYPED_PROPERTIES {
+        context.insert(
+            property.to_string(),
+            json!({ "@id": format!("as:{property}"), "@type": datatype }),
+        );
+    }
+    for property in AS_PLAIN_PROPERTIES {
+        context.insert(property.to_string(), json!(format!("as:{property}")));
+    }
+    for property in ["content", "name", "summary"] {
+        context.insert(
+            format!("{property}Map"),
+            json!({ "@id": format!("as:{property}"), "@container": "@language" }),
+        );
+    }
+    context.insert(
+        "orderedItems".to_string(),
+        json!({ "@id": "as:items", "@type": "@id", "@container": "@list" }),
+    );
+    context.insert(
+        "inbox".to_string(),
+        json!({ "@id": "ldp:inbox", "@type": "@id" }),
+    );
+    context.insert(
+        "Public".to_string(),
+        json!({ "@id": "as:Public", "@type": "@id" }),
+    );
+    Value::Object(context)
+}
+
+/// Subset of `https://w3id.org/security/v1`
+fn security_context() -> Value {
+    json!({
+        "id": "@id",
+        "type": "@type",
+        "dc": "http://purl.org/dc/terms/",
+        "sec": "https://w3id.org/security#",
+        "xsd": XSD,
+        "created": { "@id": "dc:created", "@type": "xsd:dateTime" },
+        "creator": { "@id": "dc:creator", "@type": "@id" },
+        "domain": "sec:domain",
+        "expires": { "@id": "sec:expiration", "@type": "xsd:dateTime" },
+        "nonce": "sec:nonce",
+        "owner": { "@id": "sec:owner", "@type": "@id" },
+        "publicKey": { "@id": "sec:publicKey", "@type": "@id" },
+        "publicKeyPem": "sec:publicKeyPem",
+        "signature": "sec:signature",
+        "signatureValue": "sec:signatureValue"
+    })
+}
+
+/// Subset of `https://w3id.org/identity/v1`, which is used for the signature options
+fn identity_context() -> Value {
+    json!({
+        "id": "@id",
+        "type": "@type",
+        "dc": "http://purl.org/dc/terms/",
+        "sec": "https://w3id.org/security#",
+        "xsd": XSD,
+        "created": { "@id": "dc:created", "@type": "xsd:dateTime" },
+        "creator": { "@id": "dc:creator", "@type": "@id" },
+        "domain": "sec:domain",
+        "nonce": "sec:nonce",
+        "signatureValue": "sec:signatureValue"
+    })
+}
+
+/// Remote contexts which are known without fetching them
+static KNOWN_CONTEXTS: Lazy<HashMap<&str, Value>> = Lazy::new(|| {
+    HashMap::from([
+        (
+            "https://www.w3.org/ns/activitystreams",
+            activitystreams_context(),
+        ),
+        ("https://w3id.org/security/v1", security_context()),
+        (IDENTITY_CONTEXT, identity_context()),
+    ])
+});
+
+fn unsupported(reason: impl std::fmt::Display) -> Error {
+    Error::Other(format!("Unsupported JSON-LD document: {reason}"))
+}
+
+/// Signs the activity with a Linked Data Signature, and returns it with the `signature` field.
+pub(crate) fn create_ld_signature(
+    activity: Value,
+    key_id: &str,
+    private_key: &RsaPrivateKey,
+    created: DateTime<Utc>,
+) -> Result<Value, Error> {
+    let Value::Object(mut activity) = activity else {
+        return Err(unsupported("activity is not an object"));
+    };
+    let mut options = Map::new();
+    options.insert("type".to_string(), json!(SIGNATURE_TYPE));
+    options.insert("creator".to_string(), json!(key_id));
+    options.insert(
+        "created".to_string(),
+        json!(created.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    );
+    let signing_input = signing_input(&activity, &options)?;
+    let signature = sign_string(private_key, &signing_input)?;
+    options.insert("signatureValue".to_string(), json!(signature));
+    activity.insert("signature".to_string(), Value::Object(options));
+    Ok(Value::Object(activity))
+}
+
+/// Verifies the Linked Data Signature of a received activity with the given public key.
+///
+/// This is useful in [ActivityHandler::verify](crate::traits::ActivityHandler::verify) for
+/// activities which were forwarded by a relay or another instance, so that the HTTP signature
+/// belongs to a different actor. The raw activity is available with
+/// [Data::received_activity_bytes](crate::config::Data::received_activity_bytes), and the key id
+/// of the signing actor is in the `signature.creator` field.
+///
+/// Returns [Error::ActivitySignatureInvalid] if the activity doesn't have an `RsaSignature2017`
+/// signature or it doesn't match. Properties which are not defined in the `@context` are not
+/// covered by the signature, so they shouldn't be trusted.
+///
+/// ```
+/// # use activitypub_federation::protocol::ld_signature::verify_activity_ld_signature;
+/// # use activitypub_federation::traits::tests::DB_USER_KEYPAIR;
+/// # use serde_json::json;
+/// let activity = json!({
+///     "@context": "https://www.w3.org/ns/activitystreams",
+///     "id": "https://example.com/activities/1",
+///     "type": "Announce",
+///     "actor": "https://example.com/users/alice",
+///     "object": "https://example.com/notes/1"
+/// });
+/// assert!(verify_activity_ld_signature(&activity, &DB_USER_KEYPAIR.public_key).is_err());
+/// ```
+pub fn verify_activity_ld_signature(activity: &Value, public_key_pem: &str) -> Result<(), Error> {
+    let Some(options) = activity.get("signature").and_then(Value::as_object) else {
+        return Err(Error::ActivitySignatureInvalid);
+    };
+    if options.get("type").and_then(Value::as_str) != Some(SIGNATURE_TYPE) {
+        return Err(Error::ActivitySignatureInvalid);
+    }
+    let signature = options
+        .get("signatureValue")
+        .and_then(Value::as_str)
+        .and_then(|s| Base64.decode(s).ok())
+        .ok_or(Error::ActivitySignatureInvalid)?;
+    let Value::Object(activity) = activity else {
+        return Err(Error::ActivitySignatureInvalid);
+    };
+
+    let signing_input = signing_input(activity, options)?;
+    let public_key = RsaPublicKey::from_public_key_pem(public_key_pem)?;
+    public_key
+        .verify(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &Sha256::digest(signing_input.as_bytes()),
+            &signature,
+        )
+        .map_err(|_| Error::ActivitySignatureInvalid)
+}
+
+/// The signed data consists of the hashes of the canonicalized signature options and of the
+/// canonicalized activity without signature.
+fn signing_input(
+    activity: &Map<String, Value>,
+    options: &Map<String, Value>,
+) -> Result<String, Error> {
+    let mut options = options.clone();
+    for key in ["type", "id", "signatureValue"] {
+        options.remove(key);
+    }
+    options.insert("@context".to_string(), json!(IDENTITY_CONTEXT));
+    let mut activity = activity.clone();
+    activity.remove("signature");
+    Ok(format!("{}{}", hash(&options)?, hash(&activity)?))
+}
+
+fn hash(document: &Map<String, Value>) -> Result<String, Error> {
+    let canonical = canonicalize(document)?;
+    Ok(format!("{:x}", Sha256::digest(canonical.as_bytes())))
+}
+
+/// Converts the document into canonical N-Quads according to URDNA2015.
+fn canonicalize(document: &Map<String, Value>) -> Result<String, Error> {
+    let mut document = document.clone();
+    let context = document
+        .remove("@context")
+        .ok_or_else(|| unsupported("missing @context"))?;
+    let mut active_context = Context::default();
+    active_context.parse(&context)?;
+    let mut writer = RdfWriter {
+        context: &active_context,
+        quads: vec![],
+        blank_nodes: 0,
+    };
+    writer.node(&document)?;
+    let mut quads = writer.quads;
+    quads.sort();
+    quads.dedup();
+
+    let labels = canonical_blank_node_labels(&quads)?;
+    let mut lines: Vec<String> = quads.iter().map(|q| q.serialize(&labels)).collect();
+    lines.sort();
+    Ok(lines.concat())
+}
+
+/// Assigns canonical labels `c14n0`, `c14n1`... to blank nodes in the order of their first
+/// degree hashes.
+fn canonical_blank_node_labels(quads: &[Quad]) -> Result<HashMap<usize, String>, Error> {
+    let blank_nodes: HashSet<usize> = quads
+        .iter()
+        .flat_map(|q| [&q.subject, &q.object])
+        .filter_map(|t| match t {
+            Term::Blank(id) => Some(*id),
+            _ => None,
+        })
+        .collect();
+    let mut hashes = BTreeMap::new();
+    for id in blank_nodes {
+        let labels = HashMap::from([(id, "a".to_string())]);
+        let mut lines: Vec<String> = quads
+            .iter()
+            .filter(|q| q.subject == Term::Blank(id) || q.object == Term::Blank(id))
+            .map(|q| q.serialize_with(&labels, "z"))
+            .collect();
+        lines.sort();
+        let hash = format!("{:x}", Sha256::digest(lines.concat().as_bytes()));
+        if hashes.insert(hash, id).is_some() {
+            return Err(unsupported("indistinguishable blank nodes"));
+        }
+    }
+    Ok(hashes
+        .into_values()
+        .enumerate()
+        .map(|(i, id)| (id, format!("c14n{i}")))
+        .collect())
+}
+
+/// Term definition of a JSON-LD context, with compact IRIs which are expanded when used
+#[derive(Clone, Debug)]
+struct TermDefinition {
+    id: String,
+    kind: Option<String>,
+    container: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Context {
+    terms: HashMap<String, TermDefinition>,
+    vocab: Option<String>,
+}
+
+impl Context {
+    fn parse(&mut self, context: &Value) -> Result<(), Error> {
+        match context {
+            Value::Null => *self = Context::default(),
+            Value::String(url) => {
+                let known = KNOWN_CONTEXTS
+                    .get(url.trim_end_matches(".jsonld"))
+                    .ok_or_else(|| unsupported(format!("unknown context {url}")))?;
+                self.parse(known)?;
+            }
+            Value::Array(contexts) => {
+                for context in contexts {
+                    self.parse(context)?;
+                }
+            }
+            Value::Object(definitions) => {
+                for (term, definition) in definitions {
+                    self.define(term, definition)?;
+                }
+            }
+            _ => return Err(unsupported("invalid @context")),
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, term: &str, definition: &Value) -> Result<(), Error> {
+        if term == "@vocab" {
+            self.vocab = definition.as_str().map(ToString::to_string);
+            return Ok(());
+        }
+        if term.starts_with('@') {
+            return Err(unsupported(format!("context keyword {term}")));
+        }
+        let definition = match definition {
+            Value::Null => {
+                self.terms.remove(term);
+                return Ok(());
+            }
+            Value::String(id) => TermDefinition {
+                id: id.clone(),
+                kind: None,
+                container: None,
+            },
+            Value::Object(definition) => {
+                let get = |key| definition.get(key).and_then(Value::as_str);
+                TermDefinition {
+                    id: get("@id").unwrap_or(term).to_string(),
+                    kind: get("@type").map(ToString::to_string),
+                    container: get("@container").map(ToString::to_string),
+                }
+            }
+            _ => return Err(unsupported(format!("invalid definition of {term}"))),
+        };
+        self.terms.insert(term.to_string(), definition);
+        Ok(())
+    }
+
+    /// Expands a term, compact IRI or absolute IRI. With `vocab` terms are also expanded,
+    /// otherwise only compact IRIs. Returns keywords unchanged, and `None` for values which
+    /// don't result in an absolute IRI.
+    fn expand_iri(&self, value: &str, vocab: bool) -> Option<String> {
+        self.expand_iri_inner(value, vocab, 0)
+    }
+
+    fn expand_iri_inner(&self, value: &str, vocab: bool, depth: u8) -> Option<String> {
+        if depth > 8 {
+            return None;
+        }
+        if value.starts_with('@') {
+            return Some(value.to_string());
+        }
+        if vocab {
+            if let Some(definition) = self.terms.get(value) {
+                if definition.id == value {
+                    return (!value.contains(':') || value.contains("://"))
+                        .then(|| value.to_string());
+                }
+                return self.expand_iri_inner(&definition.id, true, depth + 1);
+            }
+        }
+        if let Some((prefix, suffix)) = value.split_once(':') {
+            if prefix == "_" {
+                return None;
+            }
+            if suffix.starts_with("//") {
+                return Some(value.to_string());
+            }
+            if let Some(definition) = self.terms.get(prefix) {
+                let prefix = self.expand_iri_inner(&definition.id, true, depth + 1)?;
+                return Some(format!("{prefix}{suffix}"));
+            }
+            return Some(value.to_string());
+        }
+        match &self.vocab {
+            Some(vocab_iri) if vocab && !vocab_iri.starts_with("_:") => {
+                Some(format!("{vocab_iri}{value}"))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Term {
+    Iri(String),
+    Blank(usize),
+    Literal {
+        value: String,
+        datatype: String,
+        language: Option<String>,
+    },
+}
+
+impl Term {
+    fn literal(value: impl Into<String>, datatype: &str) -> Term {
+        Term::Literal {
+            value: value.into(),
+            datatype: datatype.to_string(),
+            language: None,
+        }
+    }
+
+    fn serialize(&self, labels: &HashMap<usize, String>, default_label: &str) -> String {
+        match self {
+            Term::Iri(iri) => format!("<{iri}>"),
+            Term::Blank(id) => format!(
+                "_:{}",
+                labels.get(id).map(String::as_str).unwrap_or(default_label)
+            ),
+            Term::Literal {
+                value,
+                datatype,
+                language,
+            } => {
+                let mut escaped = String::with_capacity(value.len() + 2);
+                for c in value.chars() {
+                    match c {
+                        '\\' => escaped.push_str("\\\\"),
+                        '"' => escaped.push_str("\\\""),
+                        '\n' => escaped.push_str("\\n"),
+                        '\r' => escaped.push_str("\\r"),
+                        c => escaped.push(c),
+                    }
+                }
+                match language {
+                    Some(language) => format!("\"{escaped}\"@{language}"),
+                    None if datatype == XSD_STRING => format!("\"{escaped}\""),
+                    None => format!("\"{escaped}\"^^<{datatype}>"),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Quad {
+    subject: Term,
+    predicate: String,
+    object: Term,
+}
+
+impl Quad {
+    fn serialize(&self, labels: &HashMap<usize, String>) -> String {
+        self.serialize_with(labels, "")
+    }
+
+    /// Serializes as N-Quad, with blank nodes which don't have a label named `default_label`
+    fn serialize_with(&self, labels: &HashMap<usize, String>, default_label: &str) -> String {
+        format!(
+            "{} <{}> {} .\n",
+            self.subject.serialize(labels, default_label),
+            self.predicate,
+            self.object.serialize(labels, default_label)
+        )
+    }
+}
+
+/// Converts JSON-LD nodes into RDF quads in the default graph
+struct RdfWriter<'a> {
+    context: &'a Context,
+    quads: Vec<Quad>,
+    blank_nodes: usize,
+}
+
+impl RdfWriter<'_> {
+    fn blank_node(&mut self) -> Term {
+        self.blank_nodes += 1;
+        Term::Blank(self.blank_nodes)
+    }
+
+    fn push(&mut self, subject: &Term, predicate: &str, object: Term) {
+        self.quads.push(Quad {
+            subject: subject.clone(),
+            predicate: predicate.to_string(),
+            object,
+        });
+    }
+
+    /// Writes the properties of a node object, and returns its subject
+    fn node(&mut self, node: &Map<String, Value>) -> Result<Term, Error> {
+        let keys: Vec<_> = node
+            .iter()
+            .filter_map(|(key, value)| Some((self.context.expand_iri(key, true)?, key, value)))
+            .collect();
+        let subject = match keys.iter().find(|(iri, ..)| iri == "@id") {
+            Some((_, _, Value::String(id))) => match self.context.expand_iri(id, false) {
+                Some(iri) if !iri.starts_with('@') => Term::Iri(iri),
+                _ => return Err(unsupported(format!("invalid id {id}"))),
+            },
+            Some(_) => return Err(unsupported("invalid id")),
+            None => self.blank_node(),
+        };
+
+        for (iri, key, value) in keys {
+            match iri.as_str() {
+                "@id" => {}
+                "@type" => {
+                    for kind in as_array(value) {
+                        let iri = kind
+                            .as_str()
+                            .and_then(|k| self.context.expand_iri(k, true))
+                            .filter(|iri| !iri.starts_with('@'))
+                            .ok_or_else(|| unsupported(format!("unknown type {kind}")))?;
+                        self.push(&subject, RDF_TYPE, Term::Iri(iri));
+                    }
+                }
+                keyword if keyword.starts_with('@') => {
+                    return Err(unsupported(format!("keyword {keyword}")))
+                }
+                _ => {
+                    let definition = self.context.terms.get(key.as_str());
+                    for object in self.values(value, definition)? {
+                        self.push(&subject, &iri, object);
+                    }
+                }
+            }
+        }
+        Ok(subject)
+    }
+
+    /// Converts the value of a property, which may be an array of multiple values
+    fn values(
+        &mut self,
+        value: &Value,
+        definition: Option<&TermDefinition>,
+    ) -> Result<Vec<Term>, Error> {
+        let kind = definition
+            .and_then(|d| d.kind.as_deref())
+            .map(|k| match k {
+                "@id" | "@vocab" => Some(k.to_string()),
+                datatype => self.context.expand_iri(datatype, true),
+            })
+            .map(|k| k.ok_or_else(|| unsupported("invalid datatype")))
+            .transpose()?;
+        let container = definition.and_then(|d| d.container.as_deref());
+        match (container, value) {
+            (Some("@list"), value) => {
+                let items = as_array(value);
+                Ok(vec![self.list(items, kind.as_deref())?])
+            }
+            (Some("@language"), Value::Object(languages)) => {
+                let mut terms = vec![];
+                for (language, values) in languages {
+                    for value in as_array(values) {
+                        match value {
+                            Value::String(value) => terms.push(Term::Literal {
+                                value: value.clone(),
+                                datatype: RDF_LANG_STRING.to_string(),
+                                language: Some(language.clone()),
+                            }),
+                            Value::Null => {}
+                            _ => return Err(unsupported("invalid language map")),
+                        }
+                    }
+                }
+                Ok(terms)
+            }
+            (_, Value::Array(values)) => {
+                let mut terms = vec![];
+                for value in values {
+                    terms.extend(self.value(value, kind.as_deref())?);
+                }
+                Ok(terms)
+            }
+            (_, value) => Ok(self.value(value, kind.as_deref())?.into_iter().collect()),
+        }
+    }
+
+    /// Converts a single value, with the type coercion of the property
+    fn value(&mut self, value: &Value, kind: Option<&str>) -> Result<Option<Term>, Error> {
+        let datatype = kind.filter(|k| !k.starts_with('@'));
+        Ok(match value {
+            Value::Null => None,
+            Value::String(value) => match kind {
+                Some(kind @ ("@id" | "@vocab")) => self
+                    .context
+                    .expand_iri(value, kind == "@vocab")
+                    .filter(|iri| !iri.starts_with('@'))
+                    .map(Term::Iri),
+                Some(datatype) => Some(Term::literal(value.as_str(), datatype)),
+                None => Some(Term::literal(value.as_str(), XSD_STRING)),
+            },
+            Value::Bool(value) => Some(Term::literal(
+                value.to_string(),
+                datatype.unwrap_or(XSD_BOOLEAN),
+            )),
+            Value::Number(number) => Some(number_literal(number, datatype)),
+            Value::Object(object) => {
+                let mut value_keys = object
+                    .iter()
+                    .map(|(key, value)| (self.context.expand_iri(key, true), value));
+                if value_keys.any(|(iri, _)| iri.as_deref() == Some("@list")) {
+                    return Err(unsupported("@list objects"));
+                }
+                let context = self.context;
+                let keyword = |name: &str| {
+                    object
+                        .iter()
+                        .find(|(key, _)| context.expand_iri(key, true).as_deref() == Some(name))
+                        .map(|(_, value)| value)
+                };
+                match keyword("@value") {
+                    Some(inner) => {
+                        let datatype = match keyword("@type").and_then(Value::as_str) {
+                            Some(datatype) => Some(
+                                self.context
+                                    .expand_iri(datatype, true)
+                                    .ok_or_else(|| unsupported("invalid datatype"))?,
+                            ),
+                            None => None,
+                        };
+                        let language = keyword("@language").and_then(Value::as_str);
+                        match (inner, language) {
+                            (Value::String(value), Some(language)) => Some(Term::Literal {
+                                value: value.clone(),
+                                datatype: RDF_LANG_STRING.to_string(),
+                                language: Some(language.to_string()),
+                            }),
+                            (Value::Null, _) => None,
+                            (inner, _) => {
+                                // strings without @type are plain literals
+                                let datatype = datatype
+                                    .as_deref()
+                                    .or(inner.is_string().then_some(XSD_STRING));
+                                self.value(inner, datatype)?
+                            }
+                        }
+                    }
+                    None => Some(self.node(object)?),
+                }
+            }
+            Value::Array(_) => return Err(unsupported("nested arrays")),
+        })
+    }
+
+    /// Writes an RDF list with the given items, and returns its head
+    fn list(&mut self, items: &[Value], kind: Option<&str>) -> Result<Term, Error> {
+        let mut terms = vec![];
+        for item in items {
+            if item.is_array() {
+                return Err(unsupported("nested lists"));
+            }
+            terms.extend(self.value(item, kind)?);
+        }
+        let mut rest = Term::Iri(RDF_NIL.to_string());
+        for term in terms.into_iter().rev() {
+            let node = self.blank_node();
+            self.push(&node, RDF_FIRST, term);
+            self.push(&node, RDF_REST, rest);
+            rest = node;
+        }
+        Ok(rest)
+    }
+}
+
+fn as_array(value: &Value) -> &[Value] {
+    match value {
+        Value::Array(values) => values,
+        value => std::slice::from_ref(value),
+    }
+}
+
+/// Converts a JSON number into a literal with the canonical lexical form of `xsd:integer` or
+/// `xsd:double`.
+fn number_literal(number: &Number, datatype: Option<&str>) -> Term {
+    let is_double = datatype == Some(XSD_DOUBLE);
+    if let (Some(integer), false) = (number.as_i64(), is_double) {
+        return Term::literal(integer.to_string(), datatype.unwrap_or(XSD_INTEGER));
+    }
+    if let (Some(integer), false) = (number.as_u64(), is_double) {
+        return Term::literal(integer.to_string(), datatype.unwrap_or(XSD_INTEGER));
+    }
+    let double = number.as_f64().unwrap_or_default();
+    if double.fract() == 0.0 && double.abs() < 1e21 && !is_double {
+        return Term::literal(format!("{double:.0}"), datatype.unwrap_or(XSD_INTEGER));
+    }
+    // Format with 15 decimal places like `%1.15E`, then remove trailing zeros
+    let formatted = format!("{double:.15E}");
+    let (mantissa, exponent) = formatted.split_once('E').unwrap_or((&formatted, "0"));
+    let mut mantissa = mantissa.trim_end_matches('0').to_string();
+    if mantissa.ends_with('.') {
+        mantissa.push('0');
+    }
+    Term::literal(
+        format!("{mantissa}E{exponent}"),
+        datatype.unwrap_or(XSD_DOUBLE),
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::http_signatures::test::test_keypair;
+    use chrono::TimeZone;
+    use rsa::pkcs8::DecodePrivateKey;
+
+    fn create_note() -> Value {
+        json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                {
+                    "toot": "http://joinmastodon.org/ns#",
+                    "sensitive": "as:sensitive",
+                    "Emoji": "toot:Emoji"
+                }
+            ],
+            "id": "https://example.com/users/alice/statuses/1/activity",
+            "type": "Create",
+            "actor": "https://example.com/users/alice",
+            "published": "2024-01-02T03:04:05Z",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": ["https://example.com/users/alice/followers"],
+            "object": {
+                "id": "https://example.com/users/alice/statuses/1",
+                "type": "Note",
+                "content": "<p>Hello \"world\"</p>",
+                "contentMap": { "en": "<p>Hello \"world\"</p>" },
+                "sensitive": false,
+                "unknownField": "ignored",
+                "tag": [
+                    {
+                        "type": "Mention",
+                        "href": "https://remote.example/users/bob",
+                        "name": "@bob@remote.example"
+                    },
+                    {
+                        "type": "Emoji",
+                        "name": ":blob:",
+                        "icon": { "type": "Image", "url": "https://example.com/blob.png", "width": 32 }
+                    }
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let document = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://example.com/activities/1",
+            "type": "Like",
+            "actor": "https://example.com/users/alice",
+            "object": "https://remote.example/notes/1",
+            "to": ["as:Public", "as:Public"],
+            "tag": { "type": "Mention", "name": "line\nbreak", "height": 2 },
+            "unknown": "dropped"
+        });
+        let canonical = canonicalize(document.as_object().unwrap()).unwrap();
+        let expected = [
+            r#"<https://example.com/activities/1> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Like> ."#,
+            r#"<https://example.com/activities/1> <https://www.w3.org/ns/activitystreams#actor> <https://example.com/users/alice> ."#,
+            r#"<https://example.com/activities/1> <https://www.w3.org/ns/activitystreams#object> <https://remote.example/notes/1> ."#,
+            r#"<https://example.com/activities/1> <https://www.w3.org/ns/activitystreams#tag> _:c14n0 ."#,
+            r#"<https://example.com/activities/1> <https://www.w3.org/ns/activitystreams#to> <https://www.w3.org/ns/activitystreams#Public> ."#,
+            r#"_:c14n0 <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Mention> ."#,
+            r#"_:c14n0 <https://www.w3.org/ns/activitystreams#height> "2"^^<http://www.w3.org/2001/XMLSchema#nonNegativeInteger> ."#,
+            r#"_:c14n0 <https://www.w3.org/ns/activitystreams#name> "line\nbreak" ."#,
+        ];
+        assert_eq!(canonical, format!("{}\n", expected.join("\n")));
+    }
+
+    #[test]
+    fn test_canonicalize_signature_options() {
+        let options = json!({
+            "@context": IDENTITY_CONTEXT,
+            "creator": "https://example.com/users/alice#main-key",
+            "created": "2024-01-02T03:04:05Z"
+        });
+        let canonical = canonicalize(options.as_object().unwrap()).unwrap();
+        assert_eq!(
+            canonical,
+            "_:c14n0 <http://purl.org/dc/terms/created> \"2024-01-02T03:04:05Z\"^^<http://www.w3.org/2001/XMLSchema#dateTime> .\n\
+             _:c14n0 <http://purl.org/dc/terms/creator> <https://example.com/users/alice#main-key> .\n"
+        );
+    }
+
+    #[test]
+    fn test_number_literal() {
+        let literal = |n: Value| match number_literal(n.as_number().unwrap(), None) {
+            Term::Literal { value, .. } => value,
+            _ => unreachable!(),
+        };
+        assert_eq!(literal(json!(5)), "5");
+        assert_eq!(literal(json!(2.0)), "2");
+        assert_eq!(literal(json!(1.5)), "1.5E0");
+        assert_eq!(literal(json!(0.00001)), "1.0E-5");
+    }
+
+    #[test]
+    fn test_sign_verify_ld_signature() {
+        let keypair = test_keypair();
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&keypair.private_key).unwrap();
+        let created = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let key_id = "https://example.com/users/alice#main-key";
+        let signed = create_ld_signature(create_note(), key_id, &private_key, created).unwrap();
+
+        assert_eq!(signed["signature"]["type"], SIGNATURE_TYPE);
+        assert_eq!(signed["signature"]["creator"], key_id);
+        assert_eq!(signed["signature"]["created"], "2024-01-02T03:04:05Z");
+        verify_activity_ld_signature(&signed, &keypair.public_key).unwrap();
+
+        // field order and unknown fields don't affect the signature
+        let mut reordered: Map<String, Value> = signed
+            .as_object()
+            .unwrap()
+            .iter()
+            .rev()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        reordered["object"]["unknownField"] = json!("changed");
+        verify_activity_ld_signature(&Value::Object(reordered), &keypair.public_key).unwrap();
+
+        let mut tampered = signed.clone();
+        tampered["object"]["content"] = json!("<p>Changed</p>");
+        assert_eq!(
+            verify_activity_ld_signature(&tampered, &keypair.public_key),
+            Err(Error::ActivitySignatureInvalid)
+        );
+
+        let mut tampered = signed.clone();
+        tampered["signature"]["created"] = json!("2024-01-03T03:04:05Z");
+        assert_eq!(
+            verify_activity_ld_signature(&tampered, &keypair.public_key),
+            Err(Error::ActivitySignatureInvalid)
+        );
+
+        let other_key = crate::traits::tests::DB_USER_KEYPAIR.public_key.clone();
+        assert_eq!(
+            verify_activity_ld_signature(&signed, &other_key),
+            Err(Error::ActivitySignatureInvalid)
+        );
+    }
+
+    /// Create activity in the format which Mastodon sends, with a signature in the format of
+    /// Mastodon's `LinkedDataSignature#sign!`. The signature was created with the test keypair
+    /// over the canonical form of this crate, so it doesn't show compatibility with Mastodon's
+    /// JSON-LD implementation.
+    fn mastodon_create() -> Value {
+        json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                {
+                    "ostatus": "http://ostatus.org#",
+                    "atomUri": "ostatus:atomUri",
+                    "inReplyToAtomUri": "ostatus:inReplyToAtomUri",
+                    "conversation": "ostatus:conversation",
+                    "sensitive": "as:sensitive",
+                    "toot": "http://joinmastodon.org/ns#",
+                    "votersCount": "toot:votersCount"
+                }
+            ],
+            "id": "https://mastodon.example/users/alice/statuses/111/activity",
+            "type": "Create",
+            "actor": "https://mastodon.example/users/alice",
+            "published": "2024-01-02T03:04:05Z",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": ["https://mastodon.example/users/alice/followers"],
+            "object": {
+                "id": "https://mastodon.example/users/alice/statuses/111",
+                "type": "Note",
+                "summary": null,
+                "inReplyTo": null,
+                "published": "2024-01-02T03:04:05Z",
+                "url": "https://mastodon.example/@alice/111",
+                "attributedTo": "https://mastodon.example/users/alice",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "cc": ["https://mastodon.example/users/alice/followers"],
+                "sensitive": false,
+                "atomUri": "https://mastodon.example/users/alice/statuses/111",
+                "inReplyToAtomUri": null,
+                "conversation": "tag:mastodon.example,2024-01-02:objectId=1:objectType=Conversation",
+                "content": "<p>Hello</p>",
+                "contentMap": { "en": "<p>Hello</p>" },
+                "attachment": [],
+                "tag": [],
+                "replies": {
+                    "id": "https://mastodon.example/users/alice/statuses/111/replies",
+                    "type": "Collection",
+                    "first": {
+                        "type": "CollectionPage",
+                        "next": "https://mastodon.example/users/alice/statuses/111/replies?only_other_accounts=true&page=true",
+                        "partOf": "https://mastodon.example/users/alice/statuses/111/replies",
+                        "items": []
+                    }
+                }
+            },
+            "signature": {
+                "type": "RsaSignature2017",
+                "creator": "https://mastodon.example/users/alice#main-key",
+                "created": "2024-01-02T03:04:06Z",
+                "signatureValue": "M9/93eEWbXHaE8XURkV+2DvCOv67cWeKpQNoIY3vVsrPjd1VTedoSTzvnnDSY0gfcT5kPQtdRGFYfn5VxsmBcZrw9php07ALO3jV8M1L9YLuUbGduW/azsH+p7R+5lJCyE7FCGZdYqrWF+u8EmzSWZCA96+GzatkKqj69c22dAE6a7Kt4sOS0Od9xXuIOcsMYp8O0AghDnCNrI/RFcUubIHFzNmpy9+yGCymoS+pUwEPQT1oUdApkhmANFyZ83tLeiCEXQB9lXjpEo+SqFzcuIhFNyV4VtyCXE00DSBbEC14l0UuRyutLl2jh+n007Wppu2CuILy8crVyY+QRvUZnA=="
+            }
+        })
+    }
+
+    #[test]
+    fn test_mastodon_ld_signature() {
+        let keypair = test_keypair();
+        let activity = mastodon_create();
+        verify_activity_ld_signature(&activity, &keypair.public_key).unwrap();
+
+        // Signing the same activity gives the same signature
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&keypair.private_key).unwrap();
+        let mut unsigned = activity.clone();
+        let signature = unsigned
+            .as_object_mut()
+            .unwrap()
+            .remove("signature")
+            .unwrap();
+        let created = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 6).unwrap();
+        let signed = create_ld_signature(
+            unsigned,
+            "https://mastodon.example/users/alice#main-key",
+            &private_key,
+            created,
+        )
+        .unwrap();
+        assert_eq!(signed["signature"], signature);
+
+        // Properties which are not in the context are not signed, but those of the inline
+        // context are
+        let mut undeclared = activity.clone();
+        undeclared["object"]["quoteUrl"] = json!("https://evil.example/notes/1");
+        verify_activity_ld_signature(&undeclared, &keypair.public_key).unwrap();
+        for (name, value) in [
+            ("votersCount", json!(0)),
+            ("conversation", json!("tag:evil.example,2024:objectId=1")),
+        ] {
+            let mut tampered = activity.clone();
+            tampered["object"][name] = value;
+            assert_eq!(
+                verify_activity_ld_signature(&tampered, &keypair.public_key),
+                Err(Error::ActivitySignatureInvalid)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ld_signature_unsupported() {
+        let keypair = test_keypair();
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&keypair.private_key).unwrap();
+        let sign = |activity| {
+            create_ld_signature(
+                activity,
+                "https://example.com#main-key",
+                &private_key,
+                Utc::now(),
+            )
+        };
+
+        let mut unknown_context = create_note();
+        unknown_context["@context"] = json!("https://example.com/context.json");
+        assert!(sign(unknown_context).is_err());
+
+        let mut unknown_type = create_note();
+        unknown_type["type"] = json!("Hashtag");
+        assert!(sign(unknown_type).is_err());
+
+        let mut identical_tags = create_note();
+        identical_tags["object"]["tag"] = json!([
+            { "type": "Mention", "name": "@bob" },
+            { "type": "Mention", "name": "@bob" }
+        ]);
+        assert!(sign(identical_tags).is_err());
+    }
+}
@@ -1,7 +1,14 @@
 //! Data structures which help to define federated messages
 
+pub mod addressing;
+pub mod collection_synchronization;
+pub mod collections;
 pub mod context;
+pub mod endpoints;
+pub mod flag;
 pub mod helpers;
+pub mod ld_signature;
 pub mod public_key;
+pub mod tombstone;
 pub mod values;
 pub mod verification;
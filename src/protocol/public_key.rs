@@ -22,7 +22,13 @@ impl PublicKey {
     ///
     /// It uses an standard key id of `{actor_id}#main-key`
     pub(crate) fn new(owner: Url, public_key_pem: String) -> Self {
-        let id = main_key_id(&owner);
+        Self::new_with_strategy(owner, public_key_pem, &KeyIdStrategy::default())
+    }
+
+    /// Create a new [PublicKey] struct for the `owner` with `public_key_pem`, using the given
+    /// strategy to generate the key id.
+    pub fn new_with_strategy(owner: Url, public_key_pem: String, strategy: &KeyIdStrategy) -> Self {
+        let id = strategy.key_id(&owner);
         PublicKey {
             id,
             owner,
@@ -31,6 +37,55 @@ impl PublicKey {
     }
 }
 
+/// Determines how the id of an actor's public key is derived from the actor id.
+///
+/// The key id is included in HTTP signatures of outgoing requests, and in the `publicKey` field
+/// of actor json. Both need to match so that other instances can verify signatures. Set it with
+/// [FederationConfigBuilder::key_id_strategy](crate::config::FederationConfigBuilder::key_id_strategy),
+/// and use [Actor::public_key_with_strategy](crate::traits::Actor::public_key_with_strategy) to
+/// generate the actor json.
+///
+/// ```
+/// # use activitypub_federation::protocol::public_key::KeyIdStrategy;
+/// # use url::Url;
+/// let actor_id = Url::parse("https://example.com/users/alice")?;
+/// assert_eq!(
+///     KeyIdStrategy::FragmentMainKey.key_id(&actor_id),
+///     "https://example.com/users/alice#main-key"
+/// );
+/// assert_eq!(
+///     KeyIdStrategy::PathSuffix("main-key".to_string()).key_id(&actor_id),
+///     "https://example.com/users/alice/main-key"
+/// );
+/// # Ok::<(), url::ParseError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub enum KeyIdStrategy {
+    /// Key id of the form `{actor_id}#main-key`, which is used by most Fediverse platforms
+    #[default]
+    FragmentMainKey,
+    /// Key id of the form `{actor_id}/{suffix}`, where the key is served at a dedicated path as
+    /// done by GoToSocial
+    PathSuffix(String),
+    /// Generate the key id from actor id with a custom function
+    Custom(fn(&Url) -> Url),
+}
+
+impl KeyIdStrategy {
+    /// Returns the key id for the actor with the given id
+    pub fn key_id(&self, actor_id: &Url) -> String {
+        match self {
+            KeyIdStrategy::FragmentMainKey => main_key_id(actor_id),
+            KeyIdStrategy::PathSuffix(suffix) => format!(
+                "{}/{}",
+                actor_id.as_str().trim_end_matches('/'),
+                suffix.trim_start_matches('/')
+            ),
+            KeyIdStrategy::Custom(f) => f(actor_id).to_string(),
+        }
+    }
+}
+
 pub(crate) fn main_key_id(owner: &Url) -> String {
     format!("{}#main-key", &owner)
 }
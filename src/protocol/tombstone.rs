@@ -0,0 +1,231 @@
+//! Placeholder which replaces a deleted object, and the `Delete` activity which announces it
+//!
+//! When a local object is deleted, build a [Delete] activity with [build_delete_activity] and
+//! send it to the same audience as the original `Create`. Afterwards the object url should
+//! respond with the tombstone and status `410 Gone`, which is done by the `tombstone_response`
+//! helpers for [axum](crate::axum::json::tombstone_response) and
+//! [actix-web](crate::actix_web::json::tombstone_response). Instances which fetch the url then
+//! receive [Error::ObjectDeleted](crate::error::Error::ObjectDeleted).
+//!
+//! ```
+//! # use activitypub_federation::protocol::{addressing::Audience, tombstone::build_delete_activity};
+//! # use url::Url;
+//! let followers = Url::parse("https://example.com/u/alice/followers")?;
+//! let delete = build_delete_activity(
+//!     Url::parse("https://example.com/activities/delete/1")?,
+//!     Url::parse("https://example.com/u/alice")?,
+//!     Url::parse("https://example.com/post/1")?,
+//!     "Note",
+//!     Audience::public().cc_followers(followers),
+//! );
+//! assert_eq!(delete.object.former_type.as_deref(), Some("Note"));
+//! # Ok::<(), url::ParseError>(())
+//! ```
+
+use crate::protocol::addressing::Audience;
+use activitystreams_kinds::{activity::DeleteType, object::TombstoneType};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Returned instead of an object which was deleted.
+///
+/// When a fetched object turns out to be a tombstone,
+/// [ObjectId::dereference](crate::fetch::object_id::ObjectId::dereference) calls
+/// [Object::delete](crate::traits::Object::delete) on the stored object and returns
+/// [Error::ObjectDeleted](crate::error::Error::ObjectDeleted) with the tombstone.
+///
+/// <https://www.w3.org/TR/activitystreams-vocabulary/#dfn-tombstone>
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    /// Id of the deleted object
+    pub id: Url,
+    /// Always `Tombstone`
+    #[serde(rename = "type")]
+    pub kind: TombstoneType,
+    /// Type of the deleted object, eg `Note`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub former_type: Option<String>,
+    /// When the object was deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<DateTime<Utc>>,
+}
+
+impl Tombstone {
+    /// Create a new tombstone for the deleted object with given `id`
+    pub fn new(id: Url) -> Self {
+        Tombstone {
+            id,
+            kind: Default::default(),
+            former_type: None,
+            deleted: None,
+        }
+    }
+
+    /// Sets the type of the deleted object, eg `Note`
+    pub fn with_former_type(mut self, former_type: impl Into<String>) -> Self {
+        self.former_type = Some(former_type.into());
+        self
+    }
+}
+
+/// Deletion of an object, which is usually replaced by a [Tombstone]. Lemmy sends the object as
+/// plain [Url] instead, which can be received as `Delete<Url>`.
+///
+/// <https://www.w3.org/TR/activitystreams-vocabulary/#dfn-delete>
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(
+    rename_all = "camelCase",
+    bound(
+        serialize = "ObjectT: Serialize",
+        deserialize = "ObjectT: Deserialize<'de>"
+    )
+)]
+pub struct Delete<ObjectT = Tombstone> {
+    /// Id of the activity
+    pub id: Url,
+    /// Always `Delete`
+    #[serde(rename = "type")]
+    pub kind: DeleteType,
+    /// Actor which deleted the object, usually its author
+    pub actor: Url,
+    /// Deleted object
+    pub object: ObjectT,
+    /// Primary recipients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<Url>,
+    /// Secondary recipients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<Url>,
+}
+
+/// Deletion of the object `object_id` by `actor`, with a [Tombstone] of `former_type` as object.
+/// The `audience` should be the same as for the `Create` of the object, so that everyone who
+/// received the object also learns that it was deleted.
+pub fn build_delete_activity(
+    id: Url,
+    actor: Url,
+    object_id: Url,
+    former_type: &str,
+    audience: Audience,
+) -> Delete<Tombstone> {
+    let mut tombstone = Tombstone::new(object_id).with_former_type(former_type);
+    tombstone.deleted = Some(Utc::now());
+    Delete {
+        id,
+        kind: Default::default(),
+        actor,
+        object: tombstone,
+        to: audience.to,
+        cc: audience.cc,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{
+        axum::json::tombstone_response,
+        error::Error,
+        fetch::object_id::ObjectId,
+        protocol::addressing::public,
+        traits::tests::{debug_data, serve_router, DbUser},
+    };
+    use axum::{routing::get, Router};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tombstone() {
+        let json = r#"{"id":"https://example.com/note/1","type":"Tombstone","formerType":"Note","deleted":"2024-01-02T03:04:05Z"}"#;
+        let tombstone: Tombstone = serde_json::from_str(json).unwrap();
+        assert_eq!(Some("Note"), tombstone.former_type.as_deref());
+        assert_eq!(
+            "2024-01-02T03:04:05+00:00",
+            tombstone.deleted.unwrap().to_rfc3339()
+        );
+        assert_eq!(json, serde_json::to_string(&tombstone).unwrap());
+
+        // Mastodon only sends id and type
+        let json = r#"{"id":"https://example.com/note/1","type":"Tombstone"}"#;
+        let tombstone: Tombstone = serde_json::from_str(json).unwrap();
+        assert_eq!(Tombstone::new(tombstone.id.clone()), tombstone);
+        assert!(serde_json::from_str::<Tombstone>(
+            r#"{"id":"https://example.com/note/1","type":"Note"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_delete_activity() {
+        let actor = Url::parse("https://example.com/u/alice").unwrap();
+        let followers = Url::parse("https://example.com/u/alice/followers").unwrap();
+        let delete = build_delete_activity(
+            Url::parse("https://example.com/activities/delete/1").unwrap(),
+            actor.clone(),
+            Url::parse("https://example.com/post/1").unwrap(),
+            "Note",
+            Audience::public().cc_followers(followers.clone()),
+        );
+        let mut json = serde_json::to_value(&delete).unwrap();
+        assert!(json["object"]["deleted"].is_string());
+        json["object"]["deleted"].take();
+        assert_eq!(
+            json,
+            json!({
+                "id": "https://example.com/activities/delete/1",
+                "type": "Delete",
+                "actor": actor,
+                "object": {
+                    "id": "https://example.com/post/1",
+                    "type": "Tombstone",
+                    "formerType": "Note",
+                    "deleted": null
+                },
+                "to": [public()],
+                "cc": [followers]
+            })
+        );
+
+        // Lemmy sends only the id of the deleted object
+        let lemmy: Delete<Url> = serde_json::from_value(json!({
+            "id": "https://lemmy.example/activities/delete/1",
+            "type": "Delete",
+            "actor": "https://lemmy.example/u/bob",
+            "object": "https://lemmy.example/post/1",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "cc": ["https://lemmy.example/c/main"]
+        }))
+        .unwrap();
+        assert_eq!(lemmy.object.as_str(), "https://lemmy.example/post/1");
+    }
+
+    #[tokio::test]
+    async fn test_tombstone_response() -> Result<(), Error> {
+        let base = serve_router(|base| {
+            let served =
+                Tombstone::new(base.join("/u/deleted").unwrap()).with_former_type("Person");
+            Router::new().route(
+                "/u/deleted",
+                get(move || async move { tombstone_response(served.clone()) }),
+            )
+        })
+        .await;
+        let url = base.join("/u/deleted")?;
+        let tombstone = Tombstone::new(url.clone()).with_former_type("Person");
+        let data = debug_data().await;
+
+        let res = ObjectId::<DbUser>::from(url.clone())
+            .dereference_forced(&data)
+            .await;
+        match res {
+            Err(Error::ObjectDeleted(id, Some(received))) => {
+                assert_eq!(id, url);
+                assert_eq!(*received, tombstone);
+            }
+            res => panic!("Expected deleted object, got {res:?}"),
+        }
+        Ok(())
+    }
+}
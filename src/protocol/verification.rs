@@ -1,8 +1,13 @@
 //! Verify that received data is valid
 
-use crate::{config::Data, error::Error, fetch::object_id::ObjectId, traits::Object};
+use crate::{
+    config::{Data, FederationConfig},
+    error::Error,
+    fetch::object_id::ObjectId,
+    traits::Object,
+};
 use serde::Deserialize;
-use url::Url;
+use url::{Host, Url};
 
 /// Check that both urls have the same domain. If not, return UrlVerificationError.
 ///
@@ -15,12 +20,55 @@ use url::Url;
 /// # Ok::<(), url::ParseError>(())
 /// ```
 pub fn verify_domains_match(a: &Url, b: &Url) -> Result<(), Error> {
-    if a.domain() != b.domain() {
+    if a.domain().map(normalize_domain) != b.domain().map(normalize_domain) {
         return Err(Error::UrlVerificationError("Domains do not match"));
     }
     Ok(())
 }
 
+/// Same as [verify_domains_match], but additionally accepts urls whose domains were configured
+/// as aliases of each other with
+/// [FederationConfigBuilder::domain_aliases](crate::config::FederationConfigBuilder::domain_aliases).
+///
+/// ```
+/// # use url::Url;
+/// # use activitypub_federation::config::FederationConfig;
+/// # use activitypub_federation::protocol::verification::verify_domains_match_with;
+/// # use activitypub_federation::traits::tests::DbConnection;
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let config = FederationConfig::builder()
+///     .domain("example.net")
+///     .app_data(DbConnection)
+///     .domain_aliases(vec![("example.com".to_string(), "social.example.com".to_string())])
+///     .build()
+///     .await?;
+/// let a = Url::parse("https://example.com/activities/1")?;
+/// let b = Url::parse("https://social.example.com/users/alice")?;
+/// assert!(verify_domains_match_with(&config, &a, &b).is_ok());
+/// # Ok::<(), anyhow::Error>(())
+/// # }).unwrap();
+/// ```
+pub fn verify_domains_match_with<T: Clone>(
+    config: &FederationConfig<T>,
+    a: &Url,
+    b: &Url,
+) -> Result<(), Error> {
+    let res = verify_domains_match(a, b);
+    let (Err(_), Some(a), Some(b)) = (&res, a.domain(), b.domain()) else {
+        return res;
+    };
+    let (a, b) = (normalize_domain(a), normalize_domain(b));
+    let is_alias = config
+        .domain_aliases
+        .iter()
+        .any(|(x, y)| (x == &a && y == &b) || (x == &b && y == &a));
+    if is_alias {
+        Ok(())
+    } else {
+        res
+    }
+}
+
 /// Check that both urls are identical. If not, return UrlVerificationError.
 ///
 /// ```
@@ -72,3 +120,137 @@ where
         Ok(())
     }
 }
+
+/// Check that JSON arrays and objects in `body` are nested at most `max_depth` levels deep. If
+/// not, return [Error::JsonDepthLimit].
+///
+/// This only scans the bytes, so it doesn't recurse and is safe to call before deserializing
+/// untrusted data. Received activities and fetched objects are checked automatically with the
+/// limit from
+/// [FederationConfigBuilder::max_json_depth](crate::config::FederationConfigBuilder::max_json_depth).
+/// Malformed JSON is not rejected here, but left to the deserializer.
+///
+/// ```
+/// # use activitypub_federation::protocol::verification::verify_json_depth;
+/// let body = br#"{"type": "Note", "tag": [{"type": "Mention"}]}"#;
+/// assert!(verify_json_depth(body, 3).is_ok());
+/// assert!(verify_json_depth(body, 2).is_err());
+/// ```
+pub fn verify_json_depth(body: &[u8], max_depth: usize) -> Result<(), Error> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(Error::JsonDepthLimit);
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Converts a domain, optionally followed by `:port`, into the lowercase punycode form which is
+/// also used by [Url::domain]. This way internationalized domains like `bücher.example` compare
+/// equal to `xn--bcher-kva.example`. Values which can't be parsed as host are only lowercased.
+pub(crate) fn normalize_domain(domain: &str) -> String {
+    let (host, port) = match domain.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            (host, Some(port))
+        }
+        _ => (domain, None),
+    };
+    let host = Host::parse(host)
+        .map(|h| h.to_string())
+        .unwrap_or_else(|_| host.to_lowercase());
+    match port {
+        Some(port) => format!("{host}:{port}"),
+        None => host,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::traits::tests::DbConnection;
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!("xn--bcher-kva.example", normalize_domain("bücher.example"));
+        assert_eq!("xn--bcher-kva.example", normalize_domain("BÜCHER.example"));
+        assert_eq!(
+            "xn--bcher-kva.example",
+            normalize_domain("xn--bcher-kva.example")
+        );
+        assert_eq!("localhost:8080", normalize_domain("LocalHost:8080"));
+        assert_eq!(
+            "xn--bcher-kva.example:8080",
+            normalize_domain("bücher.example:8080")
+        );
+    }
+
+    #[test]
+    fn test_verify_domains_match_idn() {
+        let unicode = Url::parse("https://bücher.example/u/alice").unwrap();
+        let punycode = Url::parse("https://xn--bcher-kva.example/u/alice").unwrap();
+        assert!(verify_domains_match(&unicode, &punycode).is_ok());
+
+        let other = Url::parse("https://bucher.example/u/alice").unwrap();
+        assert!(verify_domains_match(&unicode, &other).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_domains_match_with_aliases() {
+        let config = FederationConfig::builder()
+            .domain("example.net")
+            .app_data(DbConnection)
+            .domain_aliases(vec![(
+                "Example.com".to_string(),
+                "social.example.com".to_string(),
+            )])
+            .build()
+            .await
+            .unwrap();
+        let activity = Url::parse("https://example.com/activities/1").unwrap();
+        let actor = Url::parse("https://social.example.com/users/alice").unwrap();
+        assert!(verify_domains_match_with(&config, &activity, &actor).is_ok());
+        assert!(verify_domains_match_with(&config, &actor, &activity).is_ok());
+        assert!(verify_domains_match(&activity, &actor).is_err());
+
+        let other = Url::parse("https://other.example.com/users/alice").unwrap();
+        assert!(verify_domains_match_with(&config, &activity, &other).is_err());
+        assert!(verify_domains_match_with(&config, &other, &actor).is_err());
+    }
+
+    #[test]
+    fn test_verify_json_depth() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(verify_json_depth(nested(128).as_bytes(), 128).is_ok());
+        assert_eq!(
+            verify_json_depth(nested(129).as_bytes(), 128),
+            Err(Error::JsonDepthLimit)
+        );
+
+        // Brackets inside strings, including escaped quotes, are not counted
+        let body = r#"{"content": "[[[{{\"[[", "tag": [{"name": "]]]"}]}"#;
+        assert!(verify_json_depth(body.as_bytes(), 3).is_ok());
+        assert!(verify_json_depth(body.as_bytes(), 2).is_err());
+        assert!(verify_json_depth(b"\"no nesting\"", 0).is_ok());
+    }
+}
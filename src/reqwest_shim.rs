@@ -11,13 +11,14 @@ use std::{
 };
 
 /// 200KB
-const MAX_BODY_SIZE: usize = 204800;
+pub(crate) const MAX_BODY_SIZE: usize = 204800;
 
 pin_project! {
     pub struct BytesFuture {
         #[pin]
         stream: BoxStream<'static, reqwest::Result<Bytes>>,
         limit: usize,
+        too_large: bool,
         aggregator: BytesMut,
     }
 }
@@ -26,6 +27,9 @@ impl Future for BytesFuture {
     type Output = Result<Bytes, Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.too_large {
+            return Poll::Ready(Err(Error::ResponseBodyLimit));
+        }
         loop {
             let this = self.as_mut().project();
             if let Some(chunk) = ready!(this.stream.poll_next(cx)).transpose()? {
@@ -75,6 +79,8 @@ pub trait ResponseExt {
 
     /// Size limited version of `bytes` to work around a reqwest issue. Check [`ResponseExt`] docs for details.
     fn bytes_limited(self) -> Self::BytesFuture;
+    /// Same as [`ResponseExt::bytes_limited`], but with a custom size limit in bytes.
+    fn bytes_limited_to(self, limit: usize) -> Self::BytesFuture;
     /// Size limited version of `text` to work around a reqwest issue. Check [`ResponseExt`] docs for details.
     fn text_limited(self) -> Self::TextFuture;
 }
@@ -84,9 +90,16 @@ impl ResponseExt for Response {
     type TextFuture = TextFuture;
 
     fn bytes_limited(self) -> Self::BytesFuture {
+        self.bytes_limited_to(MAX_BODY_SIZE)
+    }
+
+    fn bytes_limited_to(self, limit: usize) -> Self::BytesFuture {
+        // Abort right away if the announced size is already too large
+        let too_large = self.content_length().is_some_and(|len| len > limit as u64);
         BytesFuture {
             stream: Box::pin(self.bytes_stream()),
-            limit: MAX_BODY_SIZE,
+            limit,
+            too_large,
             aggregator: BytesMut::new(),
         }
     }
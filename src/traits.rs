@@ -1,12 +1,21 @@
 //! Traits which need to be implemented for federated data types
 
-use crate::{config::Data, protocol::public_key::PublicKey};
+use crate::{
+    config::Data,
+    protocol::{
+        endpoints::Endpoints,
+        public_key::{KeyIdStrategy, PublicKey},
+    },
+};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use http::HeaderValue;
 use serde::Deserialize;
-use std::{fmt::Debug, ops::Deref};
+use std::{fmt::Debug, ops::Deref, time::Duration};
 use url::Url;
 
+pub use activitypub_federation_derive::ActivityHandler;
+
 /// Helper for converting between database structs and federated protocol structs.
 ///
 /// ```
@@ -111,11 +120,45 @@ pub trait Object: Sized + Debug {
     /// changes, but not all implementations do this, so `last_refreshed_at` is still necessary.
     ///
     /// The object is refetched if `last_refreshed_at` value is more than 24 hours ago. In debug
-    /// mode this is reduced to 20 seconds.
+    /// mode this is reduced to 20 seconds. The interval can be changed with
+    /// [FederationConfigBuilder::object_refetch_interval](crate::config::FederationConfigBuilder::object_refetch_interval)
+    /// and [Object::refetch_interval].
     fn last_refreshed_at(&self) -> Option<DateTime<Utc>> {
         None
     }
 
+    /// Time after which objects of this type are refetched, see [Object::last_refreshed_at].
+    /// Takes precedence over
+    /// [FederationConfigBuilder::object_refetch_interval](crate::config::FederationConfigBuilder::object_refetch_interval),
+    /// for example to refetch actors more often than posts.
+    fn refetch_interval() -> Option<Duration> {
+        None
+    }
+
+    /// Value of the `ETag` header from the last fetch of this object.
+    ///
+    /// If this or [Object::cached_last_modified] returns `Some`, outdated objects are refetched
+    /// with a conditional request. When the remote instance responds with `304 Not Modified`,
+    /// [Object::mark_refreshed] is called instead of [Object::from_json]. The values can be
+    /// stored in [Object::from_json] with [Data::fetched_response] and
+    /// [FetchObjectResponse::etag](crate::fetch::FetchObjectResponse::etag).
+    fn cached_etag(&self) -> Option<String> {
+        None
+    }
+
+    /// Value of the `Last-Modified` header from the last fetch of this object, see
+    /// [Object::cached_etag].
+    fn cached_last_modified(&self) -> Option<String> {
+        None
+    }
+
+    /// Called when the object was refetched, but the remote instance responded that it was not
+    /// modified since the last fetch. This should update the time which is returned by
+    /// [Object::last_refreshed_at], otherwise the object is refetched on every dereference.
+    async fn mark_refreshed(self, _data: &Data<Self::DataType>) -> Result<Self, Self::Error> {
+        Ok(self)
+    }
+
     /// Try to read the object with given `id` from local database.
     ///
     /// Should return `Ok(None)` if not found.
@@ -201,8 +244,35 @@ pub trait Object: Sized + Debug {
 ///     }
 /// }
 /// ```
+///
+/// Inboxes usually accept several types of activities, which are combined into an enum with
+/// [derive(ActivityHandler)](macro@ActivityHandler). Each variant must contain exactly one
+/// activity, and all of them need to use the same `DataType`. The `Error` type of the first
+/// variant is used for the enum, so errors of the other variants must convert into it.
+///
+/// The derive macro also implements `Deserialize`, by trying each variant in order, and adds a
+/// `kind()` method which returns the name of the variant. If no variant matches, the error
+/// contains the reason for each variant, so there is no need to derive `Deserialize` or to add
+/// `#[serde(untagged)]` for it. Only `Serialize` needs `#[serde(untagged)]`.
+///
+/// ```
+/// # use activitypub_federation::traits::ActivityHandler;
+/// # use activitypub_federation::traits::tests::Follow;
+/// #[derive(ActivityHandler, serde::Serialize)]
+/// #[serde(untagged)]
+/// enum PersonActivities {
+///     Follow(Follow),
+///     // Nested enums are also possible
+///     Group(GroupActivities),
+/// }
+///
+/// #[derive(ActivityHandler, serde::Serialize)]
+/// #[serde(untagged)]
+/// enum GroupActivities {
+///     Follow(Follow),
+/// }
+/// ```
 #[async_trait]
-#[enum_delegate::register]
 pub trait ActivityHandler {
     /// App data type passed to handlers. Must be identical to
     /// [crate::config::FederationConfigBuilder::app_data] type.
@@ -227,10 +297,44 @@ pub trait ActivityHandler {
     /// Should perform validation and possibly write action to the database. In case the activity
     /// has a nested `object` field, must call `object.from_json` handler.
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error>;
+
+    /// Called instead of [ActivityHandler::receive] for activities which were delivered to a
+    /// shared inbox with `receive_shared_activity`. `recipients` contains the local actors which
+    /// are addressed in the `to`, `cc`, `bto`, `bcc` or `audience` fields, so that the activity
+    /// can be handled for each of them.
+    ///
+    /// The default implementation ignores the recipients and calls [ActivityHandler::receive].
+    async fn receive_for(
+        self,
+        recipients: Vec<Url>,
+        data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Sized + Send,
+    {
+        let _ = recipients;
+        self.receive(data).await
+    }
+
+    /// Returns false if activities with the given `type` are not handled by this type. The inbox
+    /// then ignores them without verifying, and reports
+    /// [ReceiveOutcome::UnknownTypeIgnored](crate::ReceiveOutcome::UnknownTypeIgnored) instead of
+    /// failing to parse the activity.
+    ///
+    /// The default implementation returns true, so that all activities are parsed. Enums with
+    /// [derive(ActivityHandler)](macro@ActivityHandler) handle a type if any variant handles it.
+    fn handles_kind(kind: &str) -> bool
+    where
+        Self: Sized,
+    {
+        let _ = kind;
+        true
+    }
 }
 
 /// Trait to allow retrieving common Actor data.
-pub trait Actor: Object + Send + 'static {
+#[async_trait]
+pub trait Actor: Object + Send + Sync + 'static {
     /// `id` field of the actor
     fn id(&self) -> Url;
 
@@ -254,6 +358,14 @@ pub trait Actor: Object + Send + 'static {
         PublicKey::new(self.id(), self.public_key_pem().to_string())
     }
 
+    /// Generates a public key struct for use in the actor json representation, with key id
+    /// generated by `strategy`. Use this instead of [Actor::public_key] if a custom
+    /// [FederationConfigBuilder::key_id_strategy](crate::config::FederationConfigBuilder::key_id_strategy)
+    /// is configured, so that the advertised key id matches outgoing signatures.
+    fn public_key_with_strategy(&self, strategy: &KeyIdStrategy) -> PublicKey {
+        PublicKey::new_with_strategy(self.id(), self.public_key_pem().to_string(), strategy)
+    }
+
     /// The actor's shared inbox, if any
     fn shared_inbox(&self) -> Option<Url> {
         None
@@ -263,6 +375,59 @@ pub trait Actor: Object + Send + 'static {
     fn shared_inbox_or_inbox(&self) -> Url {
         self.shared_inbox().unwrap_or_else(|| self.inbox())
     }
+
+    /// Generates the `endpoints` field for use in the actor json representation, or `None` if
+    /// there is no shared inbox.
+    ///
+    /// ```
+    /// # use activitypub_federation::traits::{Actor, tests::DB_USER};
+    /// // Usually embedded in the actor json as
+    /// // `#[serde(skip_serializing_if = "Option::is_none")] endpoints: Option<Endpoints>`
+    /// assert_eq!(DB_USER.endpoints(), None);
+    /// ```
+    fn endpoints(&self) -> Option<Endpoints> {
+        self.shared_inbox().map(|shared_inbox| Endpoints {
+            shared_inbox: Some(shared_inbox),
+        })
+    }
+
+    /// Followers collection of this actor. Activities which are addressed to it are sent with a
+    /// `Collection-Synchronization` header from [Actor::collection_synchronization]. Returns
+    /// `None` by default, so that no header is sent.
+    fn followers(&self) -> Option<Url> {
+        None
+    }
+
+    /// Value of the `Collection-Synchronization` header which is sent along with activities of
+    /// this actor to `inbox`, so that the receiving instance can detect if its list of followers
+    /// is out of sync. Build it with
+    /// [build_collection_synchronization_header](crate::protocol::collection_synchronization::build_collection_synchronization_header)
+    /// from the followers which are on the host of `inbox`.
+    ///
+    /// Only called if the activity is addressed to [Actor::followers], and the header is only
+    /// sent if it is for that collection. Returns `None` by default.
+    async fn collection_synchronization(
+        &self,
+        _inbox: &Url,
+        _data: &Data<Self::DataType>,
+    ) -> Option<HeaderValue> {
+        None
+    }
+
+    /// Type of this actor in the `type` field, such as `Person` or `Group`. If set,
+    /// [webfinger_resolve_actor](crate::fetch::webfinger::webfinger_resolve_actor) prefers
+    /// webfinger links with the same type, which matters for instances where a user and a
+    /// community can have the same name. Returns `None` by default.
+    fn actor_type() -> Option<&'static str> {
+        None
+    }
+
+    /// Other ids of this actor from the `alsoKnownAs` field, which need to be set on the new
+    /// account before an old account can be moved to it. See
+    /// [verify_account_move](crate::protocol::helpers::verify_account_move).
+    fn also_known_as(&self) -> Vec<Url> {
+        vec![]
+    }
 }
 
 /// Allow for boxing of enum variants
@@ -289,6 +454,14 @@ where
     async fn receive(self, data: &Data<Self::DataType>) -> Result<(), Self::Error> {
         (*self).receive(data).await
     }
+
+    async fn receive_for(
+        self,
+        recipients: Vec<Url>,
+        data: &Data<Self::DataType>,
+    ) -> Result<(), Self::Error> {
+        (*self).receive_for(recipients, data).await
+    }
 }
 
 /// Trait for federating collections
@@ -338,12 +511,26 @@ pub trait Collection: Sized {
 #[doc(hidden)]
 #[allow(clippy::unwrap_used)]
 pub mod tests {
-    use super::{async_trait, ActivityHandler, Actor, Data, Debug, Object, PublicKey, Url};
+    use super::{
+        async_trait,
+        ActivityHandler,
+        Actor,
+        Data,
+        Debug,
+        Endpoints,
+        HeaderValue,
+        Object,
+        PublicKey,
+        Url,
+    };
     use crate::{
         error::Error,
         fetch::object_id::ObjectId,
         http_signatures::{generate_actor_keypair, Keypair},
-        protocol::verification::verify_domains_match,
+        protocol::{
+            collection_synchronization::build_collection_synchronization_header,
+            verification::verify_domains_match,
+        },
     };
     use activitystreams_kinds::{activity::FollowType, actor::PersonType};
     use once_cell::sync::Lazy;
@@ -376,16 +563,22 @@ pub mod tests {
         pub id: ObjectId<DbUser>,
         pub inbox: Url,
         pub public_key: PublicKey,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub endpoints: Option<Endpoints>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub also_known_as: Vec<Url>,
     }
     #[derive(Debug, Clone)]
     pub struct DbUser {
         pub name: String,
         pub federation_id: Url,
         pub inbox: Url,
+        pub shared_inbox: Option<Url>,
         pub public_key: String,
         #[allow(dead_code)]
         private_key: Option<String>,
         pub followers: Vec<Url>,
+        pub also_known_as: Vec<Url>,
         pub local: bool,
     }
 
@@ -395,9 +588,11 @@ pub mod tests {
         name: String::new(),
         federation_id: "https://localhost/123".parse().unwrap(),
         inbox: "https://localhost/123/inbox".parse().unwrap(),
+        shared_inbox: None,
         public_key: DB_USER_KEYPAIR.public_key.clone(),
         private_key: Some(DB_USER_KEYPAIR.private_key.clone()),
         followers: vec![],
+        also_known_as: vec![],
         local: false,
     });
 
@@ -421,6 +616,8 @@ pub mod tests {
                 id: self.federation_id.clone().into(),
                 inbox: self.inbox.clone(),
                 public_key: self.public_key(),
+                endpoints: self.endpoints(),
+                also_known_as: self.also_known_as.clone(),
             })
         }
 
@@ -441,14 +638,17 @@ pub mod tests {
                 name: json.preferred_username,
                 federation_id: json.id.into(),
                 inbox: json.inbox,
+                shared_inbox: json.endpoints.and_then(|e| e.shared_inbox),
                 public_key: json.public_key.public_key_pem,
                 private_key: None,
                 followers: vec![],
+                also_known_as: json.also_known_as,
                 local: false,
             })
         }
     }
 
+    #[async_trait]
     impl Actor for DbUser {
         fn id(&self) -> Url {
             self.federation_id.clone()
@@ -465,6 +665,33 @@ pub mod tests {
         fn inbox(&self) -> Url {
             self.inbox.clone()
         }
+
+        fn shared_inbox(&self) -> Option<Url> {
+            self.shared_inbox.clone()
+        }
+
+        fn also_known_as(&self) -> Vec<Url> {
+            self.also_known_as.clone()
+        }
+
+        fn followers(&self) -> Option<Url> {
+            format!("{}/followers", self.federation_id).parse().ok()
+        }
+
+        async fn collection_synchronization(
+            &self,
+            _inbox: &Url,
+            _data: &Data<Self::DataType>,
+        ) -> Option<HeaderValue> {
+            let url = format!("{}/followers_synchronization", self.federation_id)
+                .parse()
+                .ok()?;
+            Some(build_collection_synchronization_header(
+                &[],
+                &self.followers()?,
+                &url,
+            ))
+        }
     }
 
     #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -534,4 +761,80 @@ pub mod tests {
             todo!()
         }
     }
+
+    /// Request data for `example.com` in debug mode, so that objects can be fetched from local
+    /// test servers like [serve_json]
+    #[cfg(test)]
+    pub async fn debug_data() -> Data<DbConnection> {
+        crate::config::FederationConfig::builder()
+            .domain("example.com")
+            .app_data(DbConnection)
+            .debug(true)
+            .build()
+            .await
+            .unwrap()
+            .to_request_data()
+    }
+
+    /// Serves the router on a random port of localhost. The router is created from the base url
+    /// of the server like `http://localhost:1234/`, which is returned.
+    #[cfg(test)]
+    pub async fn serve_router(app: impl FnOnce(&Url) -> axum::Router) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let base = Url::parse(&format!("http://localhost:{port}/")).unwrap();
+        let app = app(&base);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base
+    }
+
+    /// Serves each json value under its path with the ActivityPub content type, see
+    /// [serve_router]
+    #[cfg(test)]
+    pub async fn serve_json(
+        routes: impl FnOnce(&Url) -> Vec<(&'static str, serde_json::Value)>,
+    ) -> Url {
+        use axum::{http::header::CONTENT_TYPE, routing::get, Router};
+
+        serve_router(|base| {
+            routes(base)
+                .into_iter()
+                .fold(Router::new(), |app, (path, json)| {
+                    let json = json.to_string();
+                    let handler = move || {
+                        let json = json.clone();
+                        async move { ([(CONTENT_TYPE, crate::FEDERATION_CONTENT_TYPE)], json) }
+                    };
+                    app.route(path, get(handler))
+                })
+        })
+        .await
+    }
+
+    /// Serves objects with the requested url as `id`, each response is delayed by `delay`.
+    /// Returns the port and the number of requests.
+    #[cfg(test)]
+    pub async fn serve_counting(
+        delay: std::time::Duration,
+    ) -> (u16, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use axum::{extract::Path, http::header::CONTENT_TYPE, routing::get, Router};
+        use std::sync::{atomic::Ordering, Arc};
+
+        let requests = Arc::<std::sync::atomic::AtomicUsize>::default();
+        let counter = requests.clone();
+        let base = serve_router(|base| {
+            let base = base.clone();
+            let handler = move |Path(path): Path<String>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let json = format!(r#"{{"id":"{base}{path}"}}"#);
+                async move {
+                    tokio::time::sleep(delay).await;
+                    ([(CONTENT_TYPE, crate::FEDERATION_CONTENT_TYPE)], json)
+                }
+            };
+            Router::new().route("/*path", get(handler))
+        })
+        .await;
+        (base.port().unwrap(), requests)
+    }
 }
@@ -0,0 +1,102 @@
+//! Normalization of urls, so that different spellings of the same id refer to the same object
+//!
+//! Activities from different instances may refer to the same object as
+//! `https://Example.com/users/alice`, `https://example.com:443/users/alice` or
+//! `https://example.com/users/alice#main-key`. [ObjectId](crate::fetch::object_id::ObjectId) and
+//! [CollectionId](crate::fetch::collection_id::CollectionId) normalize their urls with
+//! [normalize], so that these are all treated as the same id.
+
+use ::url::Url;
+use serde::{Deserialize, Deserializer};
+
+/// Normalizes the url of an id. The host is converted to lowercase, the default port of the
+/// scheme is removed and the fragment is removed. Path and query are case sensitive, so they are
+/// left unchanged.
+///
+/// ```
+/// # use activitypub_federation::url::normalize;
+/// # use url::Url;
+/// let url = Url::parse("https://Example.COM:443/Users/Alice?Page=1#main-key")?;
+/// assert_eq!(
+///     normalize(url).as_str(),
+///     "https://example.com/Users/Alice?Page=1"
+/// );
+/// # Ok::<(), url::ParseError>(())
+/// ```
+pub fn normalize(mut url: Url) -> Url {
+    url.set_fragment(None);
+    // For http and https, the host is already lowercased and the default port removed while
+    // parsing. Other schemes keep the host as it was written.
+    if let Some(host) = url
+        .host_str()
+        .filter(|host| host.bytes().any(|b| b.is_ascii_uppercase()))
+    {
+        let host = host.to_ascii_lowercase();
+        url.set_host(Some(&host)).ok();
+    }
+    url
+}
+
+/// Deserializes a url and normalizes it, for the ids in [ObjectId](crate::fetch::object_id::ObjectId)
+/// and [CollectionId](crate::fetch::collection_id::CollectionId)
+pub(crate) fn deserialize_normalized<'de, D>(deserializer: D) -> Result<Box<Url>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Url::deserialize(deserializer).map(|url| Box::new(normalize(url)))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str) -> String {
+        normalize(Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(
+            normalized("https://Example.com/users/Foo"),
+            "https://example.com/users/Foo"
+        );
+        assert_eq!(
+            normalized("https://EXAMPLE.com/Users?Name=Foo"),
+            "https://example.com/Users?Name=Foo"
+        );
+    }
+
+    #[test]
+    fn test_normalize_default_port() {
+        assert_eq!(
+            normalized("https://example.com:443/users/foo"),
+            "https://example.com/users/foo"
+        );
+        assert_eq!(
+            normalized("http://example.com:80/users/foo"),
+            "http://example.com/users/foo"
+        );
+        // Other ports are kept
+        assert_eq!(
+            normalized("https://example.com:80/users/foo"),
+            "https://example.com:80/users/foo"
+        );
+        assert_eq!(
+            normalized("http://localhost:8080/users/foo"),
+            "http://localhost:8080/users/foo"
+        );
+    }
+
+    #[test]
+    fn test_normalize_fragment() {
+        assert_eq!(
+            normalized("https://example.com/users/foo#main-key"),
+            "https://example.com/users/foo"
+        );
+        assert_eq!(
+            normalized("https://example.com/users/foo#"),
+            "https://example.com/users/foo"
+        );
+    }
+}
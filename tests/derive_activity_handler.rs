@@ -0,0 +1,155 @@
+#![allow(clippy::unwrap_used)]
+
+use activitypub_federation::{
+    config::{Data, FederationConfig},
+    kinds::activity::LikeType,
+    traits::{
+        tests::{DbConnection, Follow},
+        ActivityHandler,
+    },
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Like {
+    actor: Url,
+    object: Url,
+    #[serde(rename = "type")]
+    kind: LikeType,
+    id: Url,
+}
+
+#[async_trait]
+impl ActivityHandler for Like {
+    type DataType = DbConnection;
+    type Error = anyhow::Error;
+
+    fn id(&self) -> &Url {
+        &self.id
+    }
+
+    fn actor(&self) -> &Url {
+        &self.actor
+    }
+
+    async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn receive(self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        Err(anyhow!("liked {}", self.object))
+    }
+
+    fn handles_kind(kind: &str) -> bool {
+        kind == "Like"
+    }
+}
+
+/// Error type is taken from `Like`, errors of `Follow` are converted
+#[derive(ActivityHandler, Serialize, Debug)]
+#[serde(untagged)]
+enum GroupActivities {
+    Like(Like),
+    Follow(Follow),
+}
+
+#[derive(ActivityHandler, Serialize, Debug)]
+#[serde(untagged)]
+enum InboxActivities {
+    Group(GroupActivities),
+    Like(Like),
+}
+
+#[derive(ActivityHandler, Serialize, Debug)]
+#[serde(untagged)]
+enum LikeActivities {
+    Like(Like),
+}
+
+fn follow_json() -> Value {
+    json!({
+        "actor": "https://example.com/u/alice",
+        "object": "https://example.org/u/bob",
+        "type": "Follow",
+        "id": "https://example.com/activity/1"
+    })
+}
+
+fn like_json() -> Value {
+    json!({
+        "actor": "https://example.com/u/alice",
+        "object": "https://example.org/post/1",
+        "type": "Like",
+        "id": "https://example.com/activity/2"
+    })
+}
+
+async fn data() -> Data<DbConnection> {
+    FederationConfig::builder()
+        .domain("example.com")
+        .app_data(DbConnection)
+        .build()
+        .await
+        .unwrap()
+        .to_request_data()
+}
+
+#[tokio::test]
+async fn test_derive_dispatch() {
+    let data = data().await;
+
+    let follow: GroupActivities = serde_json::from_value(follow_json()).unwrap();
+    assert_eq!(follow.kind(), "Follow");
+    assert_eq!(follow.id().as_str(), "https://example.com/activity/1");
+    assert_eq!(follow.actor().as_str(), "https://example.com/u/alice");
+    follow.verify(&data).await.unwrap();
+    follow.receive(&data).await.unwrap();
+
+    let like: GroupActivities = serde_json::from_value(like_json()).unwrap();
+    assert_eq!(like.kind(), "Like");
+    assert_eq!(like.id().as_str(), "https://example.com/activity/2");
+    let recipients = vec![Url::parse("https://example.com/u/carol").unwrap()];
+    let error = like.receive_for(recipients, &data).await.unwrap_err();
+    assert_eq!(error.to_string(), "liked https://example.org/post/1");
+}
+
+#[test]
+fn test_derive_nested() {
+    let activity: InboxActivities = serde_json::from_value(like_json()).unwrap();
+    assert_eq!(activity.kind(), "Group");
+    assert!(matches!(
+        activity,
+        InboxActivities::Group(GroupActivities::Like(_))
+    ));
+    // Serialize is unchanged and doesn't add the variant name
+    assert_eq!(serde_json::to_value(&activity).unwrap(), like_json());
+}
+
+#[test]
+fn test_derive_deserialize_error() {
+    let mut json = follow_json();
+    json["object"] = json!(1);
+    let error = serde_json::from_value::<GroupActivities>(json)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        "data did not match any variant of GroupActivities, \
+        Like: object: invalid type: integer `1`, expected a string representing an URL; \
+        Follow: object: invalid type: integer `1`, expected a string representing an URL"
+    );
+}
+
+#[test]
+fn test_derive_handles_kind() {
+    assert!(LikeActivities::handles_kind("Like"));
+    assert!(!LikeActivities::handles_kind("Flag"));
+    // Follow uses the default implementation, which handles all types
+    assert!(GroupActivities::handles_kind("Flag"));
+    assert!(InboxActivities::handles_kind("Like"));
+}
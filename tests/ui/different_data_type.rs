@@ -0,0 +1,40 @@
+use activitypub_federation::{
+    config::Data,
+    traits::{tests::Follow, ActivityHandler},
+};
+use url::Url;
+
+#[derive(serde::Deserialize)]
+struct Announce {
+    id: Url,
+}
+
+#[async_trait::async_trait]
+impl ActivityHandler for Announce {
+    type DataType = String;
+    type Error = activitypub_federation::error::Error;
+
+    fn id(&self) -> &Url {
+        &self.id
+    }
+
+    fn actor(&self) -> &Url {
+        &self.id
+    }
+
+    async fn verify(&self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn receive(self, _: &Data<Self::DataType>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[derive(ActivityHandler)]
+enum PersonActivities {
+    Follow(Follow),
+    Announce(Announce),
+}
+
+fn main() {}
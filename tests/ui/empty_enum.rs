@@ -0,0 +1,6 @@
+use activitypub_federation::traits::ActivityHandler;
+
+#[derive(ActivityHandler)]
+enum PersonActivities {}
+
+fn main() {}
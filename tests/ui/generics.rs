@@ -0,0 +1,8 @@
+use activitypub_federation::traits::ActivityHandler;
+
+#[derive(ActivityHandler)]
+enum PersonActivities<T> {
+    Activity(T),
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use activitypub_federation::traits::{tests::Follow, ActivityHandler};
+
+#[derive(ActivityHandler)]
+enum PersonActivities {
+    Follow { follow: Follow },
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use activitypub_federation::traits::{tests::Follow, ActivityHandler};
+
+#[derive(ActivityHandler)]
+struct PersonActivities {
+    follow: Follow,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use activitypub_federation::traits::{tests::Follow, ActivityHandler};
+
+#[derive(ActivityHandler)]
+enum PersonActivities {
+    Follow(Follow),
+    Undo,
+}
+
+fn main() {}